@@ -0,0 +1,41 @@
+//! Applying one keystroke to documents of growing size.
+//!
+//! ```text
+//! cargo bench --bench apply
+//! ```
+
+use coedit::{
+    document::{Doc, apply_ops},
+    types::OpKind,
+};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
+
+fn keystroke(c: &mut Criterion) {
+    let mut group = c.benchmark_group("keystroke");
+    for kib in [1, 10, 100] {
+        let mut doc = Doc {
+            content: "é".repeat(kib * 512),
+            ..Default::default()
+        };
+        let middle = kib * 256;
+        // Typing and erasing a character keeps the document the same size.
+        let ops = [
+            OpKind::Insert {
+                pos: middle,
+                text: "a".into(),
+            },
+            OpKind::Delete {
+                pos: middle,
+                len: 1,
+            },
+        ];
+        group.bench_function(BenchmarkId::from_parameter(format!("{kib}KiB")), |b| {
+            b.iter(|| apply_ops(black_box(&mut doc), black_box(&ops)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, keystroke);
+criterion_main!(benches);
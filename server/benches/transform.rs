@@ -0,0 +1,109 @@
+//! Rebasing an edit made `n` revisions ago, through the revision index and
+//! by replaying each revision.
+//!
+//! ```text
+//! cargo bench --bench transform
+//! ```
+
+use coedit::{
+    document::{Doc, apply_ops, shapes, transform_ops, transform_pair},
+    types::{Edit, OpKind},
+};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
+
+/// `revs` single-op revisions typed and deleted around the document, like a
+/// few busy collaborators.
+fn history(revs: usize) -> Doc {
+    let mut doc = Doc {
+        content: "x".repeat(2_000),
+        ..Default::default()
+    };
+    let mut rng = fastrand::Rng::with_seed(7);
+    for _ in 0..revs {
+        let len = doc.content.chars().count();
+        let op = if rng.u8(..4) == 0 && len > 0 {
+            OpKind::Delete {
+                pos: rng.usize(..len),
+                len: 1,
+            }
+        } else {
+            OpKind::Insert {
+                pos: rng.usize(..=len),
+                text: "ab".into(),
+            }
+        };
+        let ops = [op];
+        apply_ops(&mut doc, &ops);
+        doc.log.push(shapes(&ops));
+        doc.rev += 1;
+    }
+    doc
+}
+
+fn edit(ops: Vec<OpKind>) -> Edit {
+    Edit {
+        base_rev: 0,
+        ops,
+        client_id: None,
+        op_id: None,
+        cursor_before: None,
+        cursor_after: None,
+        ts: None,
+        group_id: None,
+        user_id: None,
+    }
+}
+
+fn replay(doc: &Doc, edit: &Edit) -> Vec<OpKind> {
+    doc.log.iter().fold(edit.ops.clone(), |ops, prev| {
+        transform_pair(&ops, prev, true).0
+    })
+}
+
+fn applied(doc: &Doc, ops: &[OpKind]) -> String {
+    let mut doc = Doc {
+        content: doc.content.clone(),
+        ..Default::default()
+    };
+    apply_ops(&mut doc, ops);
+    doc.content
+}
+
+fn rebase(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rebase_from_rev_0");
+    for revs in [100, 1_000, 10_000] {
+        let doc = history(revs);
+        let cases = [
+            (
+                "insert",
+                edit(vec![OpKind::Insert {
+                    pos: 1_000,
+                    text: "z".into(),
+                }]),
+            ),
+            ("delete", edit(vec![OpKind::Delete { pos: 900, len: 200 }])),
+        ];
+        for (name, edit) in &cases {
+            // The first call builds the index; the server pays that once.
+            assert_eq!(
+                applied(&doc, &transform_ops(&doc, edit)),
+                applied(&doc, &replay(&doc, edit))
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("{name}/index"), revs),
+                edit,
+                |b, e| b.iter(|| transform_ops(black_box(&doc), black_box(e))),
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("{name}/replay"), revs),
+                edit,
+                |b, e| b.iter(|| replay(black_box(&doc), black_box(e))),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, rebase);
+criterion_main!(benches);
@@ -0,0 +1,12 @@
+//! Drives `ot_sim` with arbitrary interleavings of typing, sending, server
+//! processing and delivery, and fails on the first divergence.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(divergence) = coedit::ot_sim::run_bytes(data) {
+        panic!("{}", divergence);
+    }
+});
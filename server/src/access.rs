@@ -0,0 +1,184 @@
+//! Whose activity a requester may see. Presence lookups, the by-client index
+//! and workspace rosters all ask [`Viewer::can_see`], so labels and cursors
+//! from a document never reach someone who could not open it, and finer
+//! access rules only have to change here.
+
+use axum::http::HeaderMap;
+
+use crate::{
+    auth::{extract_password_from_headers, is_admin, is_authorized},
+    state::{AppState, get_existing_doc},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Viewer {
+    /// Holds the admin token.
+    Admin,
+    /// Authorized for this document, by its password or by the connection
+    /// that joined it.
+    Member(String),
+    /// Sees only documents that need no credentials.
+    Guest,
+}
+
+impl Viewer {
+    /// The admin when the request carries the admin token, a member of
+    /// `slug` when `password` or Basic auth opens it, a guest otherwise.
+    /// `slug` must already be loaded to count as opened.
+    pub fn from_request(
+        state: &AppState,
+        headers: &HeaderMap,
+        slug: Option<&str>,
+        password: Option<&str>,
+    ) -> Self {
+        if is_admin(headers, state.admin_token.as_deref()) {
+            return Viewer::Admin;
+        }
+        let Some(slug) = slug else {
+            return Viewer::Guest;
+        };
+        let provided = password
+            .map(str::to_string)
+            .or_else(|| extract_password_from_headers(headers, slug));
+        let opens = state
+            .docs
+            .read()
+            .get(slug)
+            .is_some_and(|doc| is_authorized(&doc.read(), provided.as_deref()));
+        if opens {
+            Viewer::Member(slug.to_string())
+        } else {
+            Viewer::Guest
+        }
+    }
+
+    pub fn can_see(&self, state: &AppState, slug: &str) -> bool {
+        match self {
+            Viewer::Admin => true,
+            Viewer::Member(own) if own == slug => true,
+            _ => is_public(state, slug),
+        }
+    }
+
+    /// [`can_see`](Self::can_see) for documents listed from an index, which
+    /// are loaded first so that their password counts.
+    pub async fn can_open(&self, state: &AppState, slug: &str) -> bool {
+        if *self == Viewer::Admin {
+            return true;
+        }
+        matches!(get_existing_doc(state, slug).await, Ok(Some(_))) && self.can_see(state, slug)
+    }
+}
+
+/// Loaded and open without a password. Documents that are not loaded have
+/// nobody in them, so there is nothing to show.
+fn is_public(state: &AppState, slug: &str) -> bool {
+    state
+        .docs
+        .read()
+        .get(slug)
+        .is_some_and(|doc| is_authorized(&doc.read(), None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        document::Doc, presence::register_presence, presence::workspace_roster,
+        storage::hash_password,
+    };
+    use axum::http::{HeaderValue, header::AUTHORIZATION};
+    use parking_lot::RwLock;
+    use std::{fs, sync::Arc};
+    use uuid::Uuid;
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("access-{}", Uuid::new_v4()));
+        let wal = base.join("wal");
+        let snap = base.join("snapshots");
+        fs::create_dir_all(&wal).unwrap();
+        fs::create_dir_all(&snap).unwrap();
+        let mut state = AppState::new(wal, snap, 1_000, 100, true, vec![]);
+        state.admin_token = Some("admin".into());
+        let locked = |password: &str| Doc {
+            password_hash: Some(hash_password(password)),
+            ..Default::default()
+        };
+        let inherited = Doc {
+            inherited_password_hash: Some(hash_password("ws")),
+            ..Default::default()
+        };
+        for (slug, doc) in [
+            ("team/open", Doc::default()),
+            ("team/a", locked("a")),
+            ("team/b", locked("b")),
+            ("team/inherited", inherited),
+        ] {
+            state
+                .docs
+                .write()
+                .insert(slug.into(), Arc::new(RwLock::new(doc)));
+        }
+        state
+    }
+
+    #[test]
+    fn guests_only_see_open_documents() {
+        let state = mk_state();
+        let guest = Viewer::from_request(&state, &HeaderMap::new(), Some("team/a"), None);
+        assert_eq!(guest, Viewer::Guest);
+        assert!(guest.can_see(&state, "team/open"));
+        for hidden in ["team/a", "team/inherited", "team/unloaded"] {
+            assert!(!guest.can_see(&state, hidden), "{hidden} leaked");
+        }
+        let wrong = Viewer::from_request(&state, &HeaderMap::new(), Some("team/a"), Some("b"));
+        assert!(!wrong.can_see(&state, "team/a"));
+    }
+
+    #[test]
+    fn a_password_opens_only_its_own_document() {
+        let state = mk_state();
+        let member = Viewer::from_request(&state, &HeaderMap::new(), Some("team/a"), Some("a"));
+        assert_eq!(member, Viewer::Member("team/a".into()));
+        assert!(member.can_see(&state, "team/a"));
+        assert!(!member.can_see(&state, "team/b"));
+        assert!(!member.can_see(&state, "team/inherited"));
+    }
+
+    #[test]
+    fn the_admin_sees_everything() {
+        let state = mk_state();
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer admin"));
+        let admin = Viewer::from_request(&state, &headers, None, None);
+        assert_eq!(admin, Viewer::Admin);
+        assert!(
+            ["team/a", "team/b", "team/inherited"]
+                .iter()
+                .all(|slug| admin.can_see(&state, slug))
+        );
+    }
+
+    #[test]
+    fn rosters_do_not_name_people_in_hidden_documents() {
+        let state = mk_state();
+        let (ann, bo) = (Uuid::new_v4(), Uuid::new_v4());
+        register_presence(&state, "team/a", ann, None, Some("Ann".into()), None, 0);
+        register_presence(&state, "team/b", bo, None, Some("Bo".into()), None, 0);
+        register_presence(&state, "team/open", bo, None, Some("Bo".into()), None, 0);
+
+        let member = Viewer::Member("team/a".into());
+        let roster = workspace_roster(&state, "team", None, |slug| member.can_see(&state, slug));
+        let seen: Vec<_> = roster
+            .iter()
+            .map(|e| (e.label.as_str(), e.slugs.clone()))
+            .collect();
+        assert_eq!(
+            seen,
+            vec![
+                ("Ann", vec!["team/a".to_string()]),
+                ("Bo", vec!["team/open".to_string()]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! Webhooks about documents in trouble: one that grew past a size, one
+//! whose edits collide more often than a rate per hour, or one with edits
+//! that no flush has written out for some minutes. The thresholds and the
+//! webhook come from `ALERT_*` and can be overridden per document in its
+//! settings. An alert is posted when a document crosses a threshold and
+//! once more when it is back under it, not on every check in between.
+
+use std::{collections::BTreeSet, time::Duration};
+
+use serde::Serialize;
+use tokio::{sync::watch, time::sleep};
+use tracing::{info, warn};
+
+use crate::{
+    document::Doc,
+    state::{AppState, now_millis},
+    types::DocSettings,
+    webhook::post_json,
+};
+
+pub const DEFAULT_ALERT_INTERVAL_MS: u64 = 60_000;
+const HOUR_MS: u64 = 60 * 60 * 1000;
+const MINUTE_MS: u64 = 60 * 1000;
+
+/// Server-wide thresholds; each is off while unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AlertPolicy {
+    pub webhook_url: Option<String>,
+    pub max_bytes: Option<u64>,
+    pub max_conflicts_per_hour: Option<u64>,
+    pub max_unflushed_minutes: Option<u64>,
+}
+
+impl AlertPolicy {
+    /// This policy with what `settings` overrides.
+    pub fn for_doc(&self, settings: &DocSettings) -> Self {
+        Self {
+            webhook_url: settings
+                .alert_webhook_url
+                .clone()
+                .or_else(|| self.webhook_url.clone()),
+            max_bytes: settings.alert_max_bytes.or(self.max_bytes),
+            max_conflicts_per_hour: settings
+                .alert_max_conflicts_per_hour
+                .or(self.max_conflicts_per_hour),
+            max_unflushed_minutes: settings
+                .alert_max_unflushed_minutes
+                .or(self.max_unflushed_minutes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    Size,
+    ConflictRate,
+    FlushStalled,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+/// The body of an alert webhook.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct AlertEvent {
+    pub slug: String,
+    pub alert: AlertKind,
+    pub status: AlertStatus,
+    /// Bytes, conflicts in the last hour or minutes without a flush.
+    pub value: u64,
+    pub threshold: u64,
+    pub at: u64,
+}
+
+/// What the checks remember about one document between runs.
+#[derive(Debug, Default)]
+pub struct DocAlerts {
+    firing: BTreeSet<AlertKind>,
+    /// The snapshot revision unflushed edits were first seen on, and when.
+    /// A flush that gets through moves the revision.
+    unflushed_since: Option<(u64, u64)>,
+}
+
+/// Alerts for `doc` that changed since the last check.
+fn check_doc(
+    slug: &str,
+    policy: &AlertPolicy,
+    doc: &Doc,
+    conflicts_last_hour: u64,
+    tracked: &mut DocAlerts,
+    now: u64,
+) -> Vec<AlertEvent> {
+    tracked.unflushed_since = match tracked.unflushed_since {
+        _ if doc.since_flush == 0 => None,
+        Some((rev, since)) if rev == doc.meta.snapshot_rev => Some((rev, since)),
+        _ => Some((doc.meta.snapshot_rev, now)),
+    };
+    let unflushed_minutes = tracked
+        .unflushed_since
+        .map_or(0, |(_, since)| now.saturating_sub(since) / MINUTE_MS);
+    let checks = [
+        (AlertKind::Size, doc.content.len() as u64, policy.max_bytes),
+        (
+            AlertKind::ConflictRate,
+            conflicts_last_hour,
+            policy.max_conflicts_per_hour,
+        ),
+        (
+            AlertKind::FlushStalled,
+            unflushed_minutes,
+            policy.max_unflushed_minutes,
+        ),
+    ];
+    let mut events = Vec::new();
+    for (alert, value, threshold) in checks {
+        let over = threshold.is_some_and(|t| match alert {
+            // Stalling for exactly the limit is already too long.
+            AlertKind::FlushStalled => tracked.unflushed_since.is_some() && value >= t,
+            _ => value > t,
+        });
+        let status = match (over, tracked.firing.contains(&alert)) {
+            (true, false) => {
+                tracked.firing.insert(alert);
+                AlertStatus::Firing
+            }
+            (false, true) => {
+                tracked.firing.remove(&alert);
+                AlertStatus::Resolved
+            }
+            _ => continue,
+        };
+        events.push(AlertEvent {
+            slug: slug.to_string(),
+            alert,
+            status,
+            value,
+            threshold: threshold.unwrap_or(0),
+            at: now,
+        });
+    }
+    events
+}
+
+/// Checks every loaded document, returning the alerts to post and where.
+pub fn check_alerts(state: &AppState, now: u64) -> Vec<(String, AlertEvent)> {
+    let docs: Vec<_> = state
+        .docs
+        .read()
+        .iter()
+        .map(|(slug, doc)| (slug.clone(), doc.clone()))
+        .collect();
+    let mut tracked = state.alerts.lock();
+    tracked.retain(|slug, _| docs.iter().any(|(s, _)| s == slug));
+    let mut out = Vec::new();
+    for (slug, doc_arc) in docs {
+        let conflicts_last_hour = state.conflicts.read().get(&slug).map_or(0, |events| {
+            events.iter().filter(|e| e.at + HOUR_MS > now).count() as u64
+        });
+        let d = doc_arc.read();
+        let policy = state.alert_policy.for_doc(&d.meta.settings);
+        let Some(url) = policy.webhook_url.clone() else {
+            tracked.remove(&slug);
+            continue;
+        };
+        let entry = tracked.entry(slug.clone()).or_default();
+        for event in check_doc(&slug, &policy, &d, conflicts_last_hour, entry, now) {
+            out.push((url.clone(), event));
+        }
+    }
+    out
+}
+
+/// Checks the documents every `alert_interval_ms` until `shutdown` flips
+/// to `true`.
+pub async fn run_alert_loop(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    let interval = Duration::from_millis(state.alert_interval_ms.max(1_000));
+    loop {
+        tokio::select! {
+            _ = sleep(interval) => {
+                for (url, event) in check_alerts(&state, now_millis()) {
+                    match post_json(&url, &event).await {
+                        Ok(()) => info!(slug = %event.slug, alert = ?event.alert, status = ?event.status, "sent alert"),
+                        Err(err) => warn!(slug = %event.slug, "alert delivery failed: {:#}", err),
+                    }
+                }
+            }
+            changed = shutdown.changed() => {
+                if changed.is_err() || *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        conflicts::ConflictEvent,
+        state::{apply_edit, get_or_load_doc},
+        storage::flush_snapshot_force,
+        types::{Edit, OpKind},
+    };
+    use uuid::Uuid;
+
+    fn insert(base_rev: u64, text: &str) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: Some(Uuid::new_v4()),
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn thresholds_fire_once_and_resolve() {
+        let base = std::env::temp_dir().join(format!("alerts-{}", Uuid::new_v4()));
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        state.alert_policy = AlertPolicy {
+            webhook_url: Some("http://alerts.example/hook".into()),
+            max_bytes: Some(4),
+            max_unflushed_minutes: Some(5),
+            ..Default::default()
+        };
+        apply_edit(&state, "notes", insert(0, "hello"))
+            .await
+            .unwrap();
+        let doc = get_or_load_doc(&state, "quiet").await.unwrap();
+        doc.write().meta.settings.alert_webhook_url = Some("http://other.example/".into());
+        doc.write().meta.settings.alert_max_conflicts_per_hour = Some(1);
+        state.conflicts.write().insert(
+            "quiet".into(),
+            (0..2)
+                .map(|_| ConflictEvent {
+                    at: 1_000,
+                    heading: None,
+                    start_line: 1,
+                    end_line: 20,
+                })
+                .collect(),
+        );
+
+        let kinds = |alerts: Vec<(String, AlertEvent)>| {
+            let mut kinds: Vec<_> = alerts
+                .into_iter()
+                .map(|(url, e)| (url, e.slug, e.alert, e.status))
+                .collect();
+            kinds.sort_by(|a, b| a.1.cmp(&b.1));
+            kinds
+        };
+        assert_eq!(
+            kinds(check_alerts(&state, 2_000)),
+            vec![
+                (
+                    "http://alerts.example/hook".into(),
+                    "notes".into(),
+                    AlertKind::Size,
+                    AlertStatus::Firing
+                ),
+                (
+                    "http://other.example/".into(),
+                    "quiet".into(),
+                    AlertKind::ConflictRate,
+                    AlertStatus::Firing
+                ),
+            ]
+        );
+        // Still over, already reported.
+        assert!(check_alerts(&state, 3_000).is_empty());
+
+        let stalled = check_alerts(&state, 2_000 + 5 * MINUTE_MS);
+        assert_eq!(
+            kinds(stalled),
+            vec![(
+                "http://alerts.example/hook".into(),
+                "notes".into(),
+                AlertKind::FlushStalled,
+                AlertStatus::Firing
+            )]
+        );
+
+        assert!(flush_snapshot_force(&state, "notes").await.unwrap());
+        let later = kinds(check_alerts(&state, 1_000 + HOUR_MS + 6 * MINUTE_MS));
+        assert_eq!(
+            later,
+            vec![
+                (
+                    "http://alerts.example/hook".into(),
+                    "notes".into(),
+                    AlertKind::FlushStalled,
+                    AlertStatus::Resolved
+                ),
+                (
+                    "http://other.example/".into(),
+                    "quiet".into(),
+                    AlertKind::ConflictRate,
+                    AlertStatus::Resolved
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,543 @@
+//! Analyzers that look a document over after each flush or when asked to
+//! with `POST /api/diagnostics`: a spellchecker, a checker for links to
+//! documents that do not exist and patterns to point out. They are listed
+//! in the JSON file named by `ANALYZERS_FILE`, and embedders can add their
+//! own [`Analyzer`]s to [`AppState::analyzers`]. What they find is sent to
+//! the document's sessions as `diagnostics` and kept on the document,
+//! moved along with every edit after it.
+
+use std::{collections::HashSet, fs, ops::Range, path::Path, sync::Arc};
+
+use anyhow::Context;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    document::{OpShape, ShapedOp, map_through_delete},
+    links::link_targets,
+    state::{AppState, broadcast, get_or_load_doc},
+    storage::{blocking, doc_exists_on_disk},
+    toc::fenced,
+    types::{ContentType, Diagnostic, ServerMsg, Severity},
+};
+
+/// What an analyzer gets to look at.
+pub struct Analyzed<'a> {
+    pub state: &'a AppState,
+    pub slug: &'a str,
+    pub content: &'a str,
+    pub content_type: &'a ContentType,
+}
+
+/// A check that points at ranges of a document. Runs on the blocking pool,
+/// so it may read files.
+pub trait Analyzer: Send + Sync {
+    fn analyze(&self, doc: &Analyzed<'_>) -> Vec<Diagnostic>;
+}
+
+/// One entry of `ANALYZERS_FILE`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "analyzer", rename_all = "snake_case")]
+pub enum AnalyzerConfig {
+    /// Words of Markdown prose that are in neither the word list file
+    /// `dictionary` (one word per line) nor `words`.
+    Spellcheck {
+        #[serde(default)]
+        dictionary: Option<String>,
+        #[serde(default)]
+        words: Vec<String>,
+    },
+    /// Links of Markdown documents to documents that do not exist.
+    Links,
+    /// Each match of `pattern`, reported as `name`.
+    Pattern {
+        name: String,
+        pattern: String,
+        message: String,
+        #[serde(default = "default_pattern_severity")]
+        severity: Severity,
+    },
+}
+
+fn default_pattern_severity() -> Severity {
+    Severity::Warning
+}
+
+impl AnalyzerConfig {
+    pub fn build(&self) -> anyhow::Result<Arc<dyn Analyzer>> {
+        Ok(match self {
+            Self::Spellcheck { dictionary, words } => {
+                let mut known: HashSet<String> = words.iter().map(|w| w.to_lowercase()).collect();
+                if let Some(path) = dictionary {
+                    let list = fs::read_to_string(path)
+                        .with_context(|| format!("failed to read dictionary {}", path))?;
+                    known.extend(list.lines().map(|w| w.trim().to_lowercase()));
+                }
+                Arc::new(Spellcheck { known })
+            }
+            Self::Links => Arc::new(BrokenLinks),
+            Self::Pattern {
+                name,
+                pattern,
+                message,
+                severity,
+            } => Arc::new(PatternLint {
+                name: name.clone(),
+                regex: Regex::new(pattern)
+                    .with_context(|| format!("invalid pattern for '{}'", name))?,
+                message: message.clone(),
+                severity: *severity,
+            }),
+        })
+    }
+}
+
+/// Reads and builds the analyzers listed in `path`.
+pub fn load_analyzers(path: &Path) -> anyhow::Result<Vec<Arc<dyn Analyzer>>> {
+    let raw =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let configs: Vec<AnalyzerConfig> =
+        serde_json::from_str(&raw).with_context(|| format!("invalid {}", path.display()))?;
+    configs.iter().map(AnalyzerConfig::build).collect()
+}
+
+/// A diagnostic for the byte range `bytes` of `content`.
+fn diagnostic(
+    analyzer: &str,
+    content: &str,
+    bytes: Range<usize>,
+    severity: Severity,
+    message: String,
+) -> Diagnostic {
+    let start = content[..bytes.start].chars().count();
+    Diagnostic {
+        analyzer: analyzer.to_string(),
+        start,
+        end: start + content[bytes].chars().count(),
+        severity,
+        message,
+    }
+}
+
+struct Spellcheck {
+    known: HashSet<String>,
+}
+
+impl Spellcheck {
+    fn is_known(&self, word: &str) -> bool {
+        // Acronyms and names in capitals are left alone.
+        !word.chars().any(char::is_lowercase)
+            || self.known.contains(word)
+            || self.known.contains(&word.to_lowercase())
+    }
+
+    fn check_prose(&self, content: &str, text: &str, offset: usize, found: &mut Vec<Diagnostic>) {
+        let mut chunk_start = 0;
+        for chunk in text.split_inclusive(char::is_whitespace) {
+            let at = offset + chunk_start;
+            chunk_start += chunk.len();
+            // Addresses are not words.
+            if chunk.contains("://") || chunk.contains('@') {
+                continue;
+            }
+            let mut word_start = None;
+            for (i, c) in chunk.char_indices().chain([(chunk.len(), ' ')]) {
+                let inside = is_latin_letter(c)
+                    || (c == '\''
+                        && word_start.is_some()
+                        && chunk[i + 1..].starts_with(is_latin_letter));
+                match (inside, word_start) {
+                    (true, None) => word_start = Some(i),
+                    (false, Some(from)) => {
+                        word_start = None;
+                        let word = &chunk[from..i];
+                        if word.chars().count() > 1 && !self.is_known(word) {
+                            found.push(diagnostic(
+                                "spellcheck",
+                                content,
+                                at + from..at + i,
+                                Severity::Info,
+                                format!("'{}' is not in the dictionary", word),
+                            ));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn is_latin_letter(c: char) -> bool {
+    c.is_ascii_alphabetic() || (c.is_alphabetic() && ('\u{c0}'..='\u{24f}').contains(&c))
+}
+
+impl Analyzer for Spellcheck {
+    fn analyze(&self, doc: &Analyzed<'_>) -> Vec<Diagnostic> {
+        if *doc.content_type != ContentType::Markdown {
+            return Vec::new();
+        }
+        let mut found = Vec::new();
+        let mut fence = None;
+        let mut offset = 0;
+        for line in doc.content.split_inclusive('\n') {
+            let start = offset;
+            offset += line.len();
+            if fenced(&mut fence, line.trim_start()) {
+                continue;
+            }
+            let mut at = start;
+            // Odd pieces are inside code spans.
+            for (i, text) in line.split('`').enumerate() {
+                if i % 2 == 0 {
+                    self.check_prose(doc.content, text, at, &mut found);
+                }
+                at += text.len() + 1;
+            }
+        }
+        found
+    }
+}
+
+struct BrokenLinks;
+
+impl Analyzer for BrokenLinks {
+    fn analyze(&self, doc: &Analyzed<'_>) -> Vec<Diagnostic> {
+        if *doc.content_type != ContentType::Markdown {
+            return Vec::new();
+        }
+        link_targets(doc.slug, doc.content)
+            .into_iter()
+            .filter(|(_, target)| {
+                !doc.state.docs.read().contains_key(target)
+                    && !doc_exists_on_disk(doc.state, target).unwrap_or(false)
+            })
+            .map(|(bytes, target)| {
+                diagnostic(
+                    "links",
+                    doc.content,
+                    bytes,
+                    Severity::Warning,
+                    format!("there is no document '{}'", target),
+                )
+            })
+            .collect()
+    }
+}
+
+struct PatternLint {
+    name: String,
+    regex: Regex,
+    message: String,
+    severity: Severity,
+}
+
+impl Analyzer for PatternLint {
+    fn analyze(&self, doc: &Analyzed<'_>) -> Vec<Diagnostic> {
+        self.regex
+            .find_iter(doc.content)
+            .filter(|m| !m.is_empty())
+            .map(|m| {
+                diagnostic(
+                    &self.name,
+                    doc.content,
+                    m.range(),
+                    self.severity,
+                    self.message.clone(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Moves `diagnostic` past `ops`, or drops it once its text is gone. Text
+/// typed at either edge stays outside the range.
+fn shift_diagnostic<T: ShapedOp>(mut diagnostic: Diagnostic, ops: &[T]) -> Option<Diagnostic> {
+    for op in ops {
+        match op.shape() {
+            OpShape::Insert { pos, chars } => {
+                if pos <= diagnostic.start {
+                    diagnostic.start += chars;
+                }
+                if pos < diagnostic.end {
+                    diagnostic.end += chars;
+                }
+            }
+            OpShape::Delete { pos, len } => {
+                diagnostic.start = map_through_delete(diagnostic.start, pos, len);
+                diagnostic.end = map_through_delete(diagnostic.end, pos, len);
+                if diagnostic.end <= diagnostic.start {
+                    return None;
+                }
+            }
+        }
+    }
+    Some(diagnostic)
+}
+
+/// Moves the diagnostics along with applied `ops`.
+pub fn shift_diagnostics<T: ShapedOp>(diagnostics: &mut Vec<Diagnostic>, ops: &[T]) {
+    let shifted = std::mem::take(diagnostics)
+        .into_iter()
+        .filter_map(|d| shift_diagnostic(d, ops));
+    diagnostics.extend(shifted);
+}
+
+/// Runs every analyzer on `content`, which `slug` had at `rev`, and keeps
+/// what they found on the document, moved up to its current revision.
+/// Sessions hear about it when it differs from what they had. Returns the
+/// current revision and diagnostics.
+pub async fn analyze_content(
+    state: &AppState,
+    slug: &str,
+    rev: u64,
+    content: String,
+    content_type: ContentType,
+) -> anyhow::Result<(u64, Vec<Diagnostic>)> {
+    let found = {
+        let (state, slug) = (state.clone(), slug.to_string());
+        blocking(move || {
+            let doc = Analyzed {
+                state: &state,
+                slug: &slug,
+                content: &content,
+                content_type: &content_type,
+            };
+            let mut found: Vec<Diagnostic> = state
+                .analyzers
+                .iter()
+                .flat_map(|analyzer| analyzer.analyze(&doc))
+                .collect();
+            found.sort_by_key(|d| (d.start, d.end));
+            Ok(found)
+        })
+        .await?
+    };
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let (current, diagnostics) = {
+        let mut d = doc_arc.write();
+        // A run over newer content finished first.
+        if rev < d.diagnosed_rev {
+            return Ok((d.rev, d.diagnostics.clone()));
+        }
+        let mut diagnostics = found;
+        for ops in d.log.get(rev as usize..).unwrap_or_default() {
+            shift_diagnostics(&mut diagnostics, ops);
+        }
+        d.diagnosed_rev = rev;
+        if diagnostics == d.diagnostics {
+            return Ok((d.rev, diagnostics));
+        }
+        d.diagnostics = diagnostics.clone();
+        (d.rev, diagnostics)
+    };
+    broadcast(
+        state,
+        slug,
+        ServerMsg::Diagnostics {
+            slug: slug.to_string(),
+            rev: current,
+            diagnostics: diagnostics.clone(),
+        },
+    );
+    Ok((current, diagnostics))
+}
+
+/// Runs the analyzers on what `slug` holds now.
+pub async fn analyze_doc(state: &AppState, slug: &str) -> anyhow::Result<(u64, Vec<Diagnostic>)> {
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let (rev, content, content_type) = {
+        let d = doc_arc.read();
+        (
+            d.rev,
+            d.content.clone(),
+            d.meta.content_type.clone().unwrap_or_default(),
+        )
+    };
+    analyze_content(state, slug, rev, content, content_type).await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiagnosticsResp {
+    pub slug: String,
+    pub rev: u64,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state::apply_edit,
+        types::{Edit, OpKind},
+    };
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    fn edit(base_rev: u64, ops: Vec<OpKind>) -> Edit {
+        Edit {
+            base_rev,
+            ops,
+            client_id: Some(Uuid::new_v4()),
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    fn ranges(diagnostics: &[Diagnostic]) -> Vec<(&str, usize, usize)> {
+        diagnostics
+            .iter()
+            .map(|d| (d.analyzer.as_str(), d.start, d.end))
+            .collect()
+    }
+
+    #[test]
+    fn configs_parse_and_check_prose() {
+        let configs: Vec<AnalyzerConfig> = serde_json::from_str(
+            r#"[{"analyzer":"spellcheck","words":["the","plan","is","ok","at"]},
+                {"analyzer":"pattern","name":"todo","pattern":"TODO","message":"left to do"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            configs[1],
+            AnalyzerConfig::Pattern {
+                name: "todo".into(),
+                pattern: "TODO".into(),
+                message: "left to do".into(),
+                severity: Severity::Warning,
+            }
+        );
+        let spellcheck = configs[0].build().unwrap();
+        let base = std::env::temp_dir().join(format!("analysis-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let content =
+            "The plän is ok, TODO teh `code` at https://x.example\n```\nnot cheked\n```\n";
+        let found = spellcheck.analyze(&Analyzed {
+            state: &state,
+            slug: "notes",
+            content,
+            content_type: &ContentType::Markdown,
+        });
+        assert_eq!(
+            ranges(&found),
+            vec![("spellcheck", 4, 8), ("spellcheck", 21, 24)]
+        );
+        assert_eq!(found[1].message, "'teh' is not in the dictionary");
+        assert!(
+            AnalyzerConfig::Pattern {
+                name: "bad".into(),
+                pattern: "(".into(),
+                message: String::new(),
+                severity: Severity::Info,
+            }
+            .build()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn ranges_follow_edits_and_go_with_their_text() {
+        let d = Diagnostic {
+            analyzer: "links".into(),
+            start: 4,
+            end: 8,
+            severity: Severity::Warning,
+            message: String::new(),
+        };
+        let insert = |pos, text: &str| OpKind::Insert {
+            pos,
+            text: text.into(),
+        };
+        let moved = |ops: &[OpKind]| shift_diagnostic(d.clone(), ops).map(|d| (d.start, d.end));
+        assert_eq!(moved(&[insert(4, "ab")]), Some((6, 10)));
+        assert_eq!(moved(&[insert(6, "ab")]), Some((4, 10)));
+        assert_eq!(moved(&[insert(8, "ab")]), Some((4, 8)));
+        assert_eq!(moved(&[OpKind::Delete { pos: 2, len: 4 }]), Some((2, 4)));
+        assert_eq!(moved(&[OpKind::Delete { pos: 3, len: 6 }]), None);
+    }
+
+    #[tokio::test]
+    async fn findings_are_broadcast_and_kept_up_with_edits() {
+        let base = std::env::temp_dir().join(format!("analysis-doc-{}", Uuid::new_v4()));
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        state.analyzers = vec![AnalyzerConfig::Links.build().unwrap()];
+        apply_edit(
+            &state,
+            "notes",
+            edit(
+                0,
+                vec![OpKind::Insert {
+                    pos: 0,
+                    text: "See [[missing]] and [[notes]].\n".into(),
+                }],
+            ),
+        )
+        .await
+        .unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state
+            .subs
+            .write()
+            .entry("notes".into())
+            .or_default()
+            .push(tx.into());
+
+        let (rev, found) = analyze_doc(&state, "notes").await.unwrap();
+        assert_eq!((rev, ranges(&found)), (1, vec![("links", 4, 15)]));
+        match rx.try_recv().unwrap() {
+            ServerMsg::Diagnostics {
+                rev, diagnostics, ..
+            } => assert_eq!((rev, diagnostics), (1, found.clone())),
+            other => panic!("expected diagnostics, got {:?}", other),
+        }
+        // The same findings again are not sent again.
+        analyze_doc(&state, "notes").await.unwrap();
+        assert!(rx.try_recv().is_err());
+
+        apply_edit(
+            &state,
+            "notes",
+            edit(
+                1,
+                vec![OpKind::Insert {
+                    pos: 0,
+                    text: "> ".into(),
+                }],
+            ),
+        )
+        .await
+        .unwrap();
+        let doc = get_or_load_doc(&state, "notes").await.unwrap();
+        assert_eq!(ranges(&doc.read().diagnostics), vec![("links", 6, 17)]);
+
+        // Findings on an older revision are moved up to the current one.
+        analyze_content(
+            &state,
+            "notes",
+            1,
+            "See [[missing]] and [[notes]].\n".into(),
+            ContentType::Markdown,
+        )
+        .await
+        .unwrap();
+        assert_eq!(ranges(&doc.read().diagnostics), vec![("links", 6, 17)]);
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    expiry::{forget_expiry, record_expiry},
+    links::{forget_links, record_links},
+    quota::record_bytes,
+    state::{AppState, Rejection, broadcast, doc_exists, get_or_load_doc, now_millis, unload_doc},
+    storage::{
+        compressed_path, flush_snapshot_force, persist_meta, read_snapshot, slug_to_rel_path,
+        snapshot_path, wal_path,
+    },
+    tags::{forget_tags, record_tags},
+    types::ServerMsg,
+};
+
+const COMPRESSED_EXT: &str = "zst";
+
+fn archived_path(state: &AppState, live: &Path, compress: bool) -> anyhow::Result<PathBuf> {
+    let rel = live
+        .strip_prefix(&state.snap_dir)
+        .map(|rel| Path::new("snapshots").join(rel))
+        .or_else(|_| {
+            live.strip_prefix(&state.wal_dir)
+                .map(|rel| Path::new("wal").join(rel))
+        })?;
+    let mut path = state.archive_dir.join(rel);
+    if compress {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(COMPRESSED_EXT);
+        path.set_file_name(name);
+    }
+    Ok(path)
+}
+
+/// Live bytes of a storage file, merging the plain file with its compressed
+/// sibling. Returns the decoded content and the on-disk size it occupied.
+fn read_live(live: &Path) -> anyhow::Result<Option<(Vec<u8>, u64)>> {
+    let compressed = compressed_path(live);
+    if !live.exists() && !compressed.exists() {
+        return Ok(None);
+    }
+    let mut data = Vec::new();
+    let mut on_disk = 0u64;
+    if live.exists() {
+        let plain = fs::read(live)?;
+        on_disk += plain.len() as u64;
+        data.extend(plain);
+        fs::remove_file(live)?;
+    }
+    if compressed.exists() {
+        let raw = fs::read(&compressed)?;
+        on_disk += raw.len() as u64;
+        data.extend(zstd::decode_all(raw.as_slice())?);
+        fs::remove_file(&compressed)?;
+    }
+    Ok(Some((data, on_disk)))
+}
+
+fn move_to_archive(
+    state: &AppState,
+    slug: &str,
+    live: &Path,
+    compress: bool,
+) -> anyhow::Result<()> {
+    let Some((data, on_disk)) = read_live(live)? else {
+        return Ok(());
+    };
+    let target = archived_path(state, live, compress)?;
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if compress {
+        fs::write(&target, zstd::encode_all(data.as_slice(), 0)?)?;
+    } else {
+        fs::write(&target, &data)?;
+    }
+    record_bytes(state, slug, -(on_disk as i64));
+    Ok(())
+}
+
+fn restore_from_archive(state: &AppState, slug: &str, live: &Path) -> anyhow::Result<()> {
+    let compressed = archived_path(state, live, true)?;
+    let plain = archived_path(state, live, false)?;
+    let (source, data) = if compressed.exists() {
+        let data = zstd::decode_all(fs::read(&compressed)?.as_slice())?;
+        (compressed, data)
+    } else if plain.exists() {
+        let data = fs::read(&plain)?;
+        (plain, data)
+    } else {
+        return Ok(());
+    };
+    if let Some(parent) = live.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let written = if state.compress_storage {
+        let encoded = zstd::encode_all(data.as_slice(), 0)?;
+        fs::write(compressed_path(live), &encoded)?;
+        encoded.len()
+    } else {
+        fs::write(live, &data)?;
+        data.len()
+    };
+    fs::remove_file(source)?;
+    record_bytes(state, slug, written as i64);
+    Ok(())
+}
+
+/// The decoded archived WAL of `slug` and the file it was read from.
+pub fn read_archived_wal(
+    state: &AppState,
+    slug: &str,
+) -> anyhow::Result<Option<(PathBuf, String)>> {
+    let live = wal_path(state, slug)?;
+    for compress in [true, false] {
+        let path = archived_path(state, &live, compress)?;
+        if !path.exists() {
+            continue;
+        }
+        let raw = fs::read(&path)?;
+        let data = if compress {
+            zstd::decode_all(raw.as_slice())?
+        } else {
+            raw
+        };
+        return Ok(Some((path, String::from_utf8(data)?)));
+    }
+    Ok(None)
+}
+
+/// Replaces an archived WAL found by [`read_archived_wal`], keeping its
+/// encoding. Archived bytes are not counted against quotas.
+pub fn rewrite_archived_wal(path: &Path, data: &str) -> anyhow::Result<()> {
+    let encoded = if path.extension().is_some_and(|ext| ext == COMPRESSED_EXT) {
+        zstd::encode_all(data.as_bytes(), 0)?
+    } else {
+        data.as_bytes().to_vec()
+    };
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, encoded)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Flushes the document, moves its snapshot and WAL into the cold archive
+/// tier and drops it from memory. The metadata sidecar stays in place so the
+/// document keeps answering as archived.
+pub async fn archive_doc(state: &AppState, slug: &str, compress: bool) -> anyhow::Result<()> {
+    slug_to_rel_path(slug)?;
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    if doc_arc.read().meta.archived_at.is_some() {
+        return Err(Rejection::new("archived", "document is already archived").into());
+    }
+    flush_snapshot_force(state, slug).await?;
+    let meta = {
+        let mut d = doc_arc.write();
+        d.meta.archived_at = Some(now_millis());
+        d.meta.clone()
+    };
+    persist_meta(state, slug, &meta)?;
+    move_to_archive(state, slug, &snapshot_path(state, slug)?, compress)?;
+    move_to_archive(state, slug, &wal_path(state, slug)?, compress)?;
+    forget_links(state, slug);
+    forget_tags(state, slug);
+    forget_expiry(state, slug);
+    unload_doc(state, slug, "archived");
+    broadcast(
+        state,
+        slug,
+        ServerMsg::Error {
+            slug: slug.to_string(),
+            code: "archived".to_string(),
+            message: "document was archived".to_string(),
+            op_id: None,
+        },
+    );
+    Ok(())
+}
+
+pub async fn restore_doc(state: &AppState, slug: &str) -> anyhow::Result<()> {
+    // Loading an unknown slug would leave an empty document behind.
+    if !doc_exists(state, slug)? {
+        return Err(Rejection::new("not_found", "document not found").into());
+    }
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let mut meta = doc_arc.read().meta.clone();
+    if meta.archived_at.take().is_none() {
+        return Err(Rejection::new("not_archived", "document is not archived").into());
+    }
+    restore_from_archive(state, slug, &snapshot_path(state, slug)?)?;
+    restore_from_archive(state, slug, &wal_path(state, slug)?)?;
+    persist_meta(state, slug, &meta)?;
+    if let Some(content) = read_snapshot(state, slug)? {
+        let content_type = meta.content_type.clone().unwrap_or_default();
+        record_links(state, slug, &content_type, &content);
+    }
+    if let Some(tags) = &meta.tags {
+        record_tags(state, slug, tags);
+    }
+    if meta.settings.expires_at.is_some() {
+        record_expiry(state, slug, meta.settings.expires_at);
+    }
+    unload_doc(state, slug, "restored");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::apply_edit;
+    use crate::types::{Edit, OpKind};
+    use uuid::Uuid;
+
+    fn mk_state(tmp: &Path) -> AppState {
+        let wal_dir = tmp.join("wal");
+        let snap_dir = tmp.join("snapshots");
+        fs::create_dir_all(&wal_dir).unwrap();
+        fs::create_dir_all(&snap_dir).unwrap();
+        AppState::new(wal_dir, snap_dir, 10_000, 1_000, true, Vec::new())
+    }
+
+    fn insert(text: &str) -> Edit {
+        Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn archive_and_restore_roundtrip() {
+        let base = std::env::temp_dir().join(format!("archive-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "team/old";
+        apply_edit(&state, slug, insert("keep me")).await.unwrap();
+
+        archive_doc(&state, slug, true).await.unwrap();
+        assert!(!snapshot_path(&state, slug).unwrap().exists());
+        assert!(!wal_path(&state, slug).unwrap().exists());
+        assert!(base.join("archive/snapshots/team/old.md.zst").exists());
+
+        let err = apply_edit(&state, slug, insert("nope")).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, "archived");
+
+        restore_doc(&state, slug).await.unwrap();
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        let d = doc.read();
+        assert_eq!(d.content, "keep me");
+        assert_eq!(d.rev, 1);
+        assert!(d.meta.archived_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn archive_moves_compressed_live_storage() {
+        let base = std::env::temp_dir().join(format!("archive-zstd-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.compress_storage = true;
+        let slug = "team/zipped";
+        apply_edit(&state, slug, insert("packed")).await.unwrap();
+
+        archive_doc(&state, slug, false).await.unwrap();
+        let wal = wal_path(&state, slug).unwrap();
+        assert!(!compressed_path(&wal).exists());
+        assert!(base.join("archive/wal/team/zipped.jsonl").exists());
+
+        restore_doc(&state, slug).await.unwrap();
+        assert!(compressed_path(&wal).exists());
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!(doc.read().content, "packed");
+    }
+
+    #[tokio::test]
+    async fn restore_rejects_live_documents() {
+        let base = std::env::temp_dir().join(format!("archive-live-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        apply_edit(&state, "live", insert("hi")).await.unwrap();
+        let err = restore_doc(&state, "live").await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Rejection>().unwrap().code,
+            "not_archived"
+        );
+
+        let err = restore_doc(&state, "missing").await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, "not_found");
+        assert!(!state.docs.read().contains_key("missing"));
+        assert!(!doc_exists(&state, "missing").unwrap());
+    }
+}
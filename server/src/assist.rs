@@ -0,0 +1,301 @@
+//! Suggestions from an outside assistant service, for autocomplete or
+//! rewriting. A session sends `AssistRequest` for a range of a document
+//! that turned the `assist` setting on; the server posts the document and
+//! range to `ASSIST_URL` and hands the `text` it answers with to that
+//! session alone as `AssistResult`. Nothing is applied: taking the
+//! suggestion is an ordinary edit. Which model or provider answers is up
+//! to the service.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    state::{AppState, Rejection, get_or_load_doc, now_millis},
+    types::{ContentType, ServerMsg, TextRange},
+    webhook::post_json_reply,
+};
+
+pub const DEFAULT_ASSIST_PER_MINUTE: u32 = 10;
+const MINUTE_MS: u64 = 60 * 1000;
+const ASSIST_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_PROMPT_KIND_CHARS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssistConfig {
+    /// Where requests are POSTed; http:// only, like webhooks.
+    pub url: String,
+    /// Requests one session may make per minute.
+    pub per_minute: u32,
+}
+
+/// Recent requests per session, for the per-minute limit.
+#[derive(Debug, Default)]
+pub struct AssistLimiter {
+    recent: Mutex<HashMap<Uuid, VecDeque<u64>>>,
+}
+
+impl AssistLimiter {
+    /// Counts a request from `client_id` unless it already made `limit` in
+    /// the minute before `now`.
+    pub fn try_acquire(&self, client_id: Uuid, limit: u32, now: u64) -> bool {
+        let mut recent = self.recent.lock();
+        recent.retain(|_, times| {
+            while times.front().is_some_and(|t| t + MINUTE_MS <= now) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = recent.entry(client_id).or_default();
+        if times.len() >= limit as usize {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+/// The body POSTed to the service.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct AssistQuery {
+    pub slug: String,
+    pub rev: u64,
+    pub prompt_kind: String,
+    pub range: TextRange,
+    pub content: String,
+    pub content_type: ContentType,
+}
+
+/// What the service answers with.
+#[derive(Debug, Clone, Deserialize)]
+struct AssistReply {
+    text: String,
+}
+
+/// Checks a request and sends the document off, answering on `tx` once the
+/// service does. Returns as soon as the request is on its way.
+pub async fn request_assist(
+    state: &AppState,
+    slug: &str,
+    client_id: Uuid,
+    range: TextRange,
+    prompt_kind: String,
+    request_id: Option<Uuid>,
+    tx: mpsc::UnboundedSender<ServerMsg>,
+) -> anyhow::Result<()> {
+    let Some(config) = state.assist.clone() else {
+        return Err(Rejection::new("assist_disabled", "no assistant service is configured").into());
+    };
+    if prompt_kind.is_empty() || prompt_kind.chars().count() > MAX_PROMPT_KIND_CHARS {
+        return Err(Rejection::new(
+            "invalid_prompt_kind",
+            format!("prompt_kind must be 1 to {MAX_PROMPT_KIND_CHARS} characters"),
+        )
+        .into());
+    }
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let query = {
+        let d = doc_arc.read();
+        if d.meta.settings.assist != Some(true) {
+            return Err(Rejection::new(
+                "assist_disabled",
+                "the assistant is not turned on for this document",
+            )
+            .into());
+        }
+        let len = d.content.chars().count();
+        if range.start > range.end || range.end > len {
+            return Err(Rejection::new(
+                "invalid_range",
+                format!("{}..{} is outside 0..{}", range.start, range.end, len),
+            )
+            .into());
+        }
+        AssistQuery {
+            slug: slug.to_string(),
+            rev: d.rev,
+            prompt_kind,
+            range,
+            content: d.content.clone(),
+            content_type: d.meta.content_type.clone().unwrap_or_default(),
+        }
+    };
+    if !state
+        .assist_limiter
+        .try_acquire(client_id, config.per_minute, now_millis())
+    {
+        return Err(Rejection::new(
+            "rate_limited",
+            format!("at most {} assistant requests a minute", config.per_minute),
+        )
+        .into());
+    }
+    tokio::spawn(async move {
+        let reply: anyhow::Result<AssistReply> =
+            post_json_reply(&config.url, &query, ASSIST_TIMEOUT).await;
+        let msg = match reply {
+            Ok(reply) => ServerMsg::AssistResult {
+                slug: query.slug,
+                rev: query.rev,
+                range: query.range,
+                text: reply.text,
+                request_id,
+            },
+            Err(err) => {
+                warn!(slug = %query.slug, "assistant request failed: {:#}", err);
+                ServerMsg::Error {
+                    slug: query.slug,
+                    code: "assist_failed".into(),
+                    message: "the assistant service did not answer".into(),
+                    op_id: request_id,
+                }
+            }
+        };
+        let _ = tx.send(msg);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state::apply_edit,
+        types::{Edit, OpKind},
+    };
+    use axum::{Json, Router, routing::post};
+
+    fn state() -> AppState {
+        let base = std::env::temp_dir().join(format!("assist-{}", Uuid::new_v4()));
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        )
+    }
+
+    fn rejection_code(result: anyhow::Result<()>) -> &'static str {
+        result.unwrap_err().downcast::<Rejection>().unwrap().code
+    }
+
+    #[test]
+    fn limiter_slides_over_a_minute() {
+        let limiter = AssistLimiter::default();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        assert!(limiter.try_acquire(a, 2, 0));
+        assert!(limiter.try_acquire(a, 2, 1_000));
+        assert!(!limiter.try_acquire(a, 2, 2_000));
+        assert!(limiter.try_acquire(b, 2, 2_000));
+        assert!(limiter.try_acquire(a, 2, MINUTE_MS));
+        assert!(!limiter.try_acquire(a, 2, MINUTE_MS + 500));
+    }
+
+    #[tokio::test]
+    async fn suggestions_come_back_only_when_opted_in() {
+        let service = Router::new().route(
+            "/assist",
+            post(|Json(query): Json<serde_json::Value>| async move {
+                let content = query["content"].as_str().unwrap();
+                let start = query["range"]["start"].as_u64().unwrap() as usize;
+                let end = query["range"]["end"].as_u64().unwrap() as usize;
+                let picked: String = content.chars().skip(start).take(end - start).collect();
+                Json(serde_json::json!({
+                    "text": format!("{}:{}", query["prompt_kind"].as_str().unwrap(), picked.to_uppercase()),
+                }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        let mut state = state();
+        let client = Uuid::new_v4();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let range = (6, 11);
+        let ask = |state: AppState, kind: &str, (start, end)| {
+            let tx = tx.clone();
+            let kind = kind.to_string();
+            let range = TextRange { start, end };
+            async move { request_assist(&state, "notes", client, range, kind, None, tx).await }
+        };
+        assert_eq!(
+            rejection_code(ask(state.clone(), "complete", range).await),
+            "assist_disabled"
+        );
+
+        state.assist = Some(AssistConfig {
+            url: format!("http://{addr}/assist"),
+            per_minute: 2,
+        });
+        apply_edit(
+            &state,
+            "notes",
+            Edit {
+                base_rev: 0,
+                ops: vec![OpKind::Insert {
+                    pos: 0,
+                    text: "hello world".into(),
+                }],
+                client_id: Some(client),
+                op_id: Some(Uuid::new_v4()),
+                cursor_before: None,
+                cursor_after: None,
+                ts: None,
+                group_id: None,
+                user_id: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            rejection_code(ask(state.clone(), "complete", range).await),
+            "assist_disabled"
+        );
+
+        let doc = get_or_load_doc(&state, "notes").await.unwrap();
+        doc.write().meta.settings.assist = Some(true);
+        let outside = (6, 12);
+        assert_eq!(
+            rejection_code(ask(state.clone(), "complete", outside).await),
+            "invalid_range"
+        );
+        assert_eq!(
+            rejection_code(ask(state.clone(), "", range).await),
+            "invalid_prompt_kind"
+        );
+
+        ask(state.clone(), "shout", range).await.unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            msg,
+            ServerMsg::AssistResult {
+                slug: "notes".into(),
+                rev: 1,
+                range: TextRange { start: 6, end: 11 },
+                text: "shout:WORLD".into(),
+                request_id: None,
+            }
+        );
+        // The content is untouched.
+        assert_eq!(doc.read().content, "hello world");
+
+        ask(state.clone(), "shout", range).await.unwrap();
+        assert_eq!(
+            rejection_code(ask(state.clone(), "shout", range).await),
+            "rate_limited"
+        );
+    }
+}
@@ -18,6 +18,35 @@ pub fn extract_password_from_headers(headers: &HeaderMap, slug: &str) -> Option<
     Some(pass)
 }
 
+/// The password of Basic auth whatever the user name, for clients such as
+/// WebDAV mounts that send one set of credentials for every document.
+pub fn basic_password(headers: &HeaderMap) -> Option<String> {
+    basic_credentials(headers).map(|(_, pass)| pass)
+}
+
+/// The user name and password of Basic auth.
+pub fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let header = headers.get(AUTHORIZATION)?.to_str().ok()?.trim();
+    let (scheme, payload) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    parse_basic_payload(payload)
+}
+
+pub fn is_admin(headers: &HeaderMap, admin_token: Option<&str>) -> bool {
+    let Some(expected) = admin_token else {
+        return false;
+    };
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().split_once(' '))
+        .is_some_and(|(scheme, token)| {
+            scheme.eq_ignore_ascii_case("bearer") && token.trim() == expected
+        })
+}
+
 pub fn extract_password_from_token(token: &str, slug: &str) -> Option<String> {
     let (user, pass) = parse_basic_payload(token)?;
     if user != slug {
@@ -35,15 +64,31 @@ fn parse_basic_payload(encoded: &str) -> Option<(String, String)> {
     }
 }
 
+/// The hash a password has to match to open `doc`: its own, else the one it
+/// inherits from its workspace.
+pub fn required_password_hash(doc: &Doc) -> Option<&str> {
+    doc.password_hash
+        .as_deref()
+        .or(doc.inherited_password_hash.as_deref())
+}
+
 pub fn is_authorized(doc: &Doc, provided: Option<&str>) -> bool {
-    match (&doc.password_hash, provided) {
+    match (required_password_hash(doc), provided) {
         (None, _) => true,
-        (Some(expected), Some(actual)) => hash_password(actual) == *expected,
+        (Some(expected), Some(actual)) => hash_password(actual) == expected,
         (Some(_), None) => false,
     }
 }
 
+pub fn is_owner(doc: &Doc, owner_token: Option<&str>) -> bool {
+    match (&doc.meta.owner_hash, owner_token) {
+        (Some(expected), Some(token)) => hash_password(token) == *expected,
+        _ => false,
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::field_reassign_with_default)]
 mod tests {
     use super::*;
     use axum::http::{HeaderMap, HeaderValue};
@@ -70,6 +115,17 @@ mod tests {
         assert!(extract_password_from_headers(&headers, "doc-slug").is_none());
     }
 
+    #[test]
+    fn is_admin_matches_bearer_token() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer s3cret"));
+
+        assert!(is_admin(&headers, Some("s3cret")));
+        assert!(!is_admin(&headers, Some("other")));
+        assert!(!is_admin(&headers, None));
+        assert!(!is_admin(&HeaderMap::new(), Some("s3cret")));
+    }
+
     #[test]
     fn is_authorized_checks_password_hash() {
         let mut doc = Doc::default();
@@ -80,6 +136,31 @@ mod tests {
         assert!(!is_authorized(&doc, None));
     }
 
+    #[test]
+    fn is_authorized_falls_back_to_inherited_password() {
+        let mut doc = Doc {
+            inherited_password_hash: Some(hash_password("team")),
+            ..Default::default()
+        };
+        assert!(is_authorized(&doc, Some("team")));
+        assert!(!is_authorized(&doc, None));
+
+        doc.password_hash = Some(hash_password("own"));
+        assert!(is_authorized(&doc, Some("own")));
+        assert!(!is_authorized(&doc, Some("team")));
+    }
+
+    #[test]
+    fn is_owner_requires_matching_token() {
+        let mut doc = Doc::default();
+        assert!(!is_owner(&doc, Some("anything")));
+
+        doc.meta.owner_hash = Some(hash_password("owner-token"));
+        assert!(is_owner(&doc, Some("owner-token")));
+        assert!(!is_owner(&doc, Some("other")));
+        assert!(!is_owner(&doc, None));
+    }
+
     #[test]
     fn extract_password_from_token_validates_slug() {
         let token = BASE64.encode("doc-slug:secret");
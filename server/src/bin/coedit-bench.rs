@@ -0,0 +1,377 @@
+//! Load generator for the coedit websocket protocol.
+//!
+//! Spins up simulated clients that join documents, type at a steady rate,
+//! move their cursors and optionally reconnect, then reports throughput and
+//! edit round-trip latency (send -> own `applied` broadcast).
+//!
+//! ```text
+//! cargo run --release --bin coedit-bench -- --url ws://localhost:9000/api/ws \
+//!     --clients 50 --docs 5 --duration 30 --rate 10
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use serde_json::{Value, json};
+use tokio::time::{MissedTickBehavior, interval, sleep_until};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+struct Config {
+    url: String,
+    clients: usize,
+    docs: usize,
+    duration: Duration,
+    rate: f64,
+    cursor_every: u32,
+    reconnect_every: Option<Duration>,
+    password: Option<String>,
+    prefix: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            url: "ws://localhost:9000/api/ws".into(),
+            clients: 8,
+            docs: 1,
+            duration: Duration::from_secs(10),
+            rate: 5.0,
+            cursor_every: 3,
+            reconnect_every: None,
+            password: None,
+            prefix: "bench".into(),
+        }
+    }
+}
+
+const USAGE: &str = "usage: coedit-bench [--url ws://host/api/ws] [--clients N] [--docs N] \
+[--duration SECS] [--rate EDITS_PER_SEC] [--cursor-every N] [--reconnect-every SECS] \
+[--password PW] [--prefix SLUG_PREFIX]";
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut cfg = Config::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        if flag == "-h" || flag == "--help" {
+            return Err(USAGE.into());
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}\n{}", flag, USAGE))?;
+        match flag.as_str() {
+            "--url" => cfg.url = value.clone(),
+            "--clients" => cfg.clients = parse(&flag, &value)?,
+            "--docs" => cfg.docs = parse::<usize>(&flag, &value)?.max(1),
+            "--duration" => cfg.duration = Duration::from_secs(parse(&flag, &value)?),
+            "--rate" => cfg.rate = parse(&flag, &value)?,
+            "--cursor-every" => cfg.cursor_every = parse(&flag, &value)?,
+            "--reconnect-every" => {
+                let secs: u64 = parse(&flag, &value)?;
+                cfg.reconnect_every = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "--password" => cfg.password = Some(value.clone()),
+            "--prefix" => cfg.prefix = value.clone(),
+            _ => return Err(format!("unknown flag {}\n{}", flag, USAGE)),
+        }
+    }
+    if cfg.rate <= 0.0 {
+        return Err("--rate must be positive".into());
+    }
+    Ok(cfg)
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    sent: u64,
+    acked: u64,
+    rejected: u64,
+    broadcasts: u64,
+    cursors: u64,
+    connects: u64,
+    failures: u64,
+    latencies_us: Vec<u64>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.sent += other.sent;
+        self.acked += other.acked;
+        self.rejected += other.rejected;
+        self.broadcasts += other.broadcasts;
+        self.cursors += other.cursors;
+        self.connects += other.connects;
+        self.failures += other.failures;
+        self.latencies_us.extend(other.latencies_us);
+    }
+}
+
+fn percentile_ms(sorted: &[u64], pct: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let idx = (sorted.len() * pct).div_ceil(100).saturating_sub(1);
+    sorted[idx.min(sorted.len() - 1)] as f64 / 1000.0
+}
+
+fn ws_url(cfg: &Config, slug: &str) -> String {
+    let sep = if cfg.url.contains('?') { '&' } else { '?' };
+    let mut url = format!("{}{}slug={}", cfg.url, sep, slug);
+    if let Some(pw) = &cfg.password {
+        url.push_str("&password=");
+        url.push_str(pw);
+    }
+    url
+}
+
+/// What the client knows of the document: the server-ordered content at
+/// `rev`. Local edits are not applied until the server echoes them, so
+/// positions are always valid against `rev`.
+struct Replica {
+    rev: u64,
+    chars: usize,
+}
+
+impl Replica {
+    fn apply(&mut self, ops: &[Value]) {
+        for op in ops {
+            match op["type"].as_str() {
+                Some("insert") => {
+                    self.chars += op["text"].as_str().map_or(0, |t| t.chars().count());
+                }
+                Some("delete") => {
+                    let len = op["len"].as_u64().unwrap_or(0) as usize;
+                    self.chars = self.chars.saturating_sub(len);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn next_edit(replica: &Replica) -> Value {
+    let rng = fastrand::u32(0..100);
+    if replica.chars > 0 && rng < 15 {
+        let pos = fastrand::usize(0..replica.chars);
+        json!({ "type": "delete", "pos": pos, "len": 1 })
+    } else {
+        let text = match rng {
+            15..=24 => " ".to_string(),
+            25..=27 => "\n".to_string(),
+            _ => fastrand::alphanumeric().to_string(),
+        };
+        // Typists mostly append near where they were.
+        let pos = if fastrand::u32(0..10) < 8 {
+            replica.chars
+        } else {
+            fastrand::usize(0..=replica.chars)
+        };
+        json!({ "type": "insert", "pos": pos, "text": text })
+    }
+}
+
+async fn run_session(
+    cfg: &Config,
+    slug: &str,
+    client_id: Uuid,
+    label: &str,
+    until: Instant,
+    report: &mut Report,
+) -> anyhow::Result<()> {
+    let (ws, _) = connect_async(ws_url(cfg, slug)).await?;
+    report.connects += 1;
+    let (mut tx, mut rx) = ws.split();
+    let join = json!({
+        "type": "join",
+        "session_id": slug,
+        "client_id": client_id,
+        "label": label,
+        "password": cfg.password,
+        "version": 1,
+        "capabilities": ["errors"],
+    });
+    tx.send(Message::Text(join.to_string())).await?;
+
+    let mut replica = None;
+    while replica.is_none() {
+        let Some(msg) = rx.next().await else {
+            anyhow::bail!("connection closed before snapshot");
+        };
+        if let Message::Text(text) = msg? {
+            let value: Value = serde_json::from_str(&text)?;
+            if value["type"] == "snapshot" {
+                replica = Some(Replica {
+                    rev: value["rev"].as_u64().unwrap_or(0),
+                    chars: value["content"].as_str().map_or(0, |c| c.chars().count()),
+                });
+            }
+        }
+    }
+    let mut replica = replica.unwrap_or(Replica { rev: 0, chars: 0 });
+
+    let mut pending: HashMap<Uuid, Instant> = HashMap::new();
+    let mut ticker = interval(Duration::from_secs_f64(1.0 / cfg.rate));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut ticks = 0u32;
+    let deadline = tokio::time::Instant::from_std(until);
+    loop {
+        tokio::select! {
+            _ = sleep_until(deadline) => break,
+            _ = ticker.tick() => {
+                ticks += 1;
+                let op_id = Uuid::new_v4();
+                let edit = json!({
+                    "type": "edit",
+                    "slug": slug,
+                    "edit": {
+                        "base_rev": replica.rev,
+                        "ops": [next_edit(&replica)],
+                        "client_id": client_id,
+                        "op_id": op_id,
+                    },
+                });
+                tx.send(Message::Text(edit.to_string())).await?;
+                pending.insert(op_id, Instant::now());
+                report.sent += 1;
+                if cfg.cursor_every > 0 && ticks.is_multiple_of(cfg.cursor_every) {
+                    let cursor = json!({
+                        "type": "cursor",
+                        "slug": slug,
+                        "cursor": { "position": fastrand::usize(0..=replica.chars) },
+                        "op_id": Uuid::new_v4(),
+                    });
+                    tx.send(Message::Text(cursor.to_string())).await?;
+                    report.cursors += 1;
+                }
+            }
+            msg = rx.next() => {
+                let Some(msg) = msg else {
+                    anyhow::bail!("server closed the connection");
+                };
+                let Message::Text(text) = msg? else { continue };
+                let value: Value = serde_json::from_str(&text)?;
+                match value["type"].as_str() {
+                    Some("applied") => {
+                        report.broadcasts += 1;
+                        replica.rev = value["rev"].as_u64().unwrap_or(replica.rev);
+                        if let Some(ops) = value["ops"].as_array() {
+                            replica.apply(ops);
+                        }
+                        let own = value["op_id"]
+                            .as_str()
+                            .and_then(|id| id.parse::<Uuid>().ok())
+                            .and_then(|id| pending.remove(&id));
+                        if let Some(sent_at) = own {
+                            report.acked += 1;
+                            report.latencies_us.push(sent_at.elapsed().as_micros() as u64);
+                        }
+                    }
+                    Some("error") => {
+                        report.rejected += 1;
+                        if let Some(id) = value["op_id"].as_str().and_then(|id| id.parse().ok()) {
+                            pending.remove(&id);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    let _ = tx.send(Message::Close(None)).await;
+    Ok(())
+}
+
+async fn run_client(cfg: Arc<Config>, index: usize, until: Instant) -> Report {
+    let slug = format!("{}/doc-{}", cfg.prefix, index % cfg.docs);
+    let label = format!("bench-{}", index);
+    let client_id = Uuid::new_v4();
+    let mut report = Report::default();
+    // Stagger joins so the server isn't hit by a thundering herd.
+    tokio::time::sleep(Duration::from_millis(fastrand::u64(0..250))).await;
+    while Instant::now() < until {
+        let session_end = match cfg.reconnect_every {
+            Some(every) => until.min(Instant::now() + every),
+            None => until,
+        };
+        if let Err(err) =
+            run_session(&cfg, &slug, client_id, &label, session_end, &mut report).await
+        {
+            report.failures += 1;
+            eprintln!("client {}: {:#}", index, err);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+    report
+}
+
+#[tokio::main]
+async fn main() {
+    let cfg = match parse_args() {
+        Ok(cfg) => Arc::new(cfg),
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(2);
+        }
+    };
+    println!(
+        "coedit-bench: {} clients on {} docs for {:?} at {} edits/s each -> {}",
+        cfg.clients, cfg.docs, cfg.duration, cfg.rate, cfg.url
+    );
+    let started = Instant::now();
+    let until = started + cfg.duration;
+    let total = Arc::new(Mutex::new(Report::default()));
+    let handles: Vec<_> = (0..cfg.clients)
+        .map(|i| {
+            let cfg = cfg.clone();
+            let total = total.clone();
+            tokio::spawn(async move {
+                let report = run_client(cfg, i, until).await;
+                total.lock().merge(report);
+            })
+        })
+        .collect();
+    for handle in handles {
+        let _ = handle.await;
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    let mut report = std::mem::take(&mut *total.lock());
+    report.latencies_us.sort_unstable();
+    let lat = &report.latencies_us;
+    println!("elapsed        {:.1}s", elapsed);
+    println!(
+        "connections    {} ({} failed)",
+        report.connects, report.failures
+    );
+    println!(
+        "edits          sent {} / acked {} / rejected {} ({:.1} acked/s)",
+        report.sent,
+        report.acked,
+        report.rejected,
+        report.acked as f64 / elapsed
+    );
+    println!(
+        "broadcasts     {} received ({:.1}/s)",
+        report.broadcasts,
+        report.broadcasts as f64 / elapsed
+    );
+    println!("cursor moves   {}", report.cursors);
+    println!(
+        "edit latency   p50 {:.2}ms  p90 {:.2}ms  p99 {:.2}ms  max {:.2}ms",
+        percentile_ms(lat, 50),
+        percentile_ms(lat, 90),
+        percentile_ms(lat, 99),
+        lat.last().copied().unwrap_or(0) as f64 / 1000.0
+    );
+}
@@ -0,0 +1,218 @@
+//! Maintenance commands that work directly on a data directory.
+//!
+//! The server caches loaded documents, so run commands that write (`rebuild`,
+//! `set-password`, `clear-password`) while it is stopped.
+//!
+//! ```text
+//! cargo run --bin coedit-cli -- --data-dir ./vault list
+//! cargo run --bin coedit-cli -- verify
+//! ```
+
+use std::{fs, path::PathBuf, process::ExitCode};
+
+use anyhow::{Context, bail};
+use coedit::{
+    AppState,
+    integrity::{WalEdit, verify_doc, wal_entries},
+    state::get_or_load_doc,
+    storage::{
+        collect_slugs_with_extension, doc_exists_on_disk, flush_snapshot_force, hash_password,
+        load_password_hash, persist_password_hash,
+    },
+    types::OpKind,
+};
+
+const USAGE: &str = "usage: coedit-cli [--data-dir DIR] <command>
+
+commands:
+  list                            documents with revision, size and flags
+  dump <slug> [--out FILE]        current content (snapshot + WAL)
+  replay <slug>                   the WAL's edits, one per line
+  rebuild [<slug>...]             fold WALs into snapshots (all by default)
+  set-password <slug> <password>
+  clear-password <slug>
+  verify [<slug>...]              check snapshots, metadata and WALs (all by default)
+
+DATA_DIR and STORAGE_COMPRESSION are read like the server does.";
+
+fn open_state(data_dir: PathBuf) -> anyhow::Result<AppState> {
+    let wal_dir = data_dir.join("wal");
+    let snap_dir = data_dir.join("snapshots");
+    if !wal_dir.is_dir() && !snap_dir.is_dir() {
+        bail!(
+            "{} does not look like a coedit data dir",
+            data_dir.display()
+        );
+    }
+    let mut state = AppState::new(wal_dir, snap_dir, 1500, 200, false, Vec::new());
+    state.archive_dir = data_dir.join("archive");
+    state.compress_storage = std::env::var("STORAGE_COMPRESSION")
+        .map(|v| v.trim().eq_ignore_ascii_case("zstd"))
+        .unwrap_or(false);
+    Ok(state)
+}
+
+fn all_slugs(state: &AppState) -> anyhow::Result<Vec<String>> {
+    let mut slugs = collect_slugs_with_extension(&state.snap_dir, "md", false)?;
+    for slug in collect_slugs_with_extension(&state.wal_dir, "jsonl", false)? {
+        if !slugs.contains(&slug) {
+            slugs.push(slug);
+        }
+    }
+    slugs.sort();
+    Ok(slugs)
+}
+
+fn slugs_or_all(state: &AppState, args: &[String]) -> anyhow::Result<Vec<String>> {
+    if args.is_empty() {
+        all_slugs(state)
+    } else {
+        Ok(args.to_vec())
+    }
+}
+
+fn require_doc(state: &AppState, slug: &str) -> anyhow::Result<()> {
+    if !doc_exists_on_disk(state, slug)? {
+        bail!("no document '{}'", slug);
+    }
+    Ok(())
+}
+
+fn describe_op(op: &OpKind) -> String {
+    match op {
+        OpKind::Insert { pos, text } => format!("insert@{} {:?}", pos, text),
+        OpKind::Delete { pos, len } => format!("delete@{}+{}", pos, len),
+    }
+}
+
+async fn run(state: &AppState, command: &str, args: &[String]) -> anyhow::Result<bool> {
+    match (command, args) {
+        ("list", []) => {
+            for slug in all_slugs(state)? {
+                let doc = get_or_load_doc(state, &slug).await?;
+                let d = doc.read();
+                let mut flags = Vec::new();
+                if load_password_hash(state, &slug)?.is_some() {
+                    flags.push("password");
+                }
+                if d.meta.owner_hash.is_some() {
+                    flags.push("owned");
+                }
+                if d.since_flush > 0 {
+                    flags.push("unflushed");
+                }
+                println!(
+                    "{}\trev {}\t{} chars\t{}",
+                    slug,
+                    d.rev,
+                    d.content.chars().count(),
+                    flags.join(",")
+                );
+            }
+        }
+        ("dump", [slug, rest @ ..]) => {
+            require_doc(state, slug)?;
+            let content = get_or_load_doc(state, slug).await?.read().content.clone();
+            match rest {
+                [] => print!("{}", content),
+                [flag, path] if flag == "--out" => {
+                    fs::write(path, content).with_context(|| format!("failed to write {}", path))?
+                }
+                _ => bail!("{}", USAGE),
+            }
+        }
+        ("replay", [slug]) => {
+            require_doc(state, slug)?;
+            for entry in wal_entries(state, slug)? {
+                match entry {
+                    Ok(WalEdit {
+                        ts,
+                        edit,
+                        compacted,
+                    }) => {
+                        let ops: Vec<String> = edit.ops.iter().map(describe_op).collect();
+                        println!(
+                            "{}\tbase {}{}\t{}\t{}",
+                            ts,
+                            edit.base_rev,
+                            if compacted { " (compacted)" } else { "" },
+                            edit.client_id.map(|c| c.to_string()).unwrap_or_default(),
+                            ops.join(" ")
+                        );
+                    }
+                    Err((line, err)) => println!("line {}: unparsable: {}", line, err),
+                }
+            }
+        }
+        ("rebuild", slugs) => {
+            let mut rebuilt = 0usize;
+            for slug in slugs_or_all(state, slugs)? {
+                if flush_snapshot_force(state, &slug).await? {
+                    rebuilt += 1;
+                    println!("{}: snapshot rewritten", slug);
+                }
+            }
+            println!("{} snapshot(s) rebuilt", rebuilt);
+        }
+        ("set-password", [slug, password]) => {
+            require_doc(state, slug)?;
+            if password.is_empty() {
+                bail!("password must not be empty; use clear-password");
+            }
+            persist_password_hash(state, slug, Some(&hash_password(password)))?;
+            println!("{}: password set", slug);
+        }
+        ("clear-password", [slug]) => {
+            require_doc(state, slug)?;
+            persist_password_hash(state, slug, None)?;
+            println!("{}: password cleared", slug);
+        }
+        ("verify", slugs) => {
+            let mut ok = true;
+            for slug in slugs_or_all(state, slugs)? {
+                let report = verify_doc(state, &slug);
+                if report.is_ok() {
+                    println!("{}: ok (rev {})", slug, report.rev);
+                } else {
+                    ok = false;
+                    for problem in &report.problems {
+                        println!("{}: {}", slug, problem);
+                    }
+                }
+            }
+            return Ok(ok);
+        }
+        _ => bail!("{}", USAGE),
+    }
+    Ok(true)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut data_dir = PathBuf::from(std::env::var("DATA_DIR").unwrap_or_else(|_| "/vault".into()));
+    if args.first().map(String::as_str) == Some("--data-dir") {
+        if args.len() < 2 {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+        data_dir = PathBuf::from(args.remove(1));
+        args.remove(0);
+    }
+    let Some((command, rest)) = args.split_first() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    let result = match open_state(data_dir) {
+        Ok(state) => run(&state, command, rest).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
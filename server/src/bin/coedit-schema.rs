@@ -0,0 +1,25 @@
+//! Prints the websocket protocol as JSON Schema or TypeScript.
+//!
+//! ```text
+//! cargo run --bin coedit-schema -- ts > ../web/app/lib/protocol.gen.ts
+//! cargo run --bin coedit-schema -- json > protocol.schema.json
+//! ```
+
+use coedit::schema::{json_schema, typescript};
+
+fn main() {
+    match std::env::args().nth(1).as_deref() {
+        Some("ts") => print!("{}", typescript()),
+        Some("json") => match serde_json::to_string_pretty(&json_schema()) {
+            Ok(text) => println!("{}", text),
+            Err(err) => {
+                eprintln!("failed to serialize schema: {}", err);
+                std::process::exit(1);
+            }
+        },
+        _ => {
+            eprintln!("usage: coedit-schema <ts|json>");
+            std::process::exit(2);
+        }
+    }
+}
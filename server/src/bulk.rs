@@ -0,0 +1,342 @@
+//! Admin operations over many documents at once, selected by slug prefix or
+//! glob. They run as [jobs](crate::jobs), since a pass over a large instance
+//! can take minutes.
+
+use std::collections::BTreeSet;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use crate::{
+    cluster::owns,
+    doc_settings::update_doc_settings,
+    history::{HistoryArchive, export_history},
+    jobs::{Job, JobHandle, spawn_job},
+    replace::{ReplaceSpec, replace_in_doc},
+    state::{AppState, Rejection, unload_doc},
+    storage::{flush_snapshot_force, hash_password, list_all_slugs},
+    workspace::{load_workspace, save_workspace, workspace_of},
+};
+
+/// Documents a bulk operation applies to. Both filters must match when both
+/// are given; at least one is required.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BulkSelector {
+    pub prefix: Option<String>,
+    /// `*` and `?` stay within one path segment, `**` crosses them.
+    pub glob: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BulkAction {
+    Flush,
+    Lock,
+    Unlock,
+    Export,
+    /// Sets the default password of every workspace with a selected document.
+    WorkspacePassword {
+        password: Option<String>,
+    },
+    Replace {
+        find: String,
+        #[serde(default)]
+        replace: String,
+        #[serde(default)]
+        regex: bool,
+        #[serde(default)]
+        case_insensitive: bool,
+    },
+}
+
+impl BulkAction {
+    /// The job kind the action runs as.
+    fn kind(&self) -> &'static str {
+        match self {
+            BulkAction::Flush => "bulk_flush",
+            BulkAction::Lock => "bulk_lock",
+            BulkAction::Unlock => "bulk_unlock",
+            BulkAction::Export => "bulk_export",
+            BulkAction::WorkspacePassword { .. } => "bulk_workspace_password",
+            BulkAction::Replace { .. } => "bulk_replace",
+        }
+    }
+}
+
+/// Whether `slug` matches `pattern`.
+pub fn glob_matches(pattern: &str, slug: &str) -> bool {
+    fn go(p: &[char], s: &[char]) -> bool {
+        match p {
+            [] => s.is_empty(),
+            ['*', '*', rest @ ..] => (0..=s.len()).any(|i| go(rest, &s[i..])),
+            ['*', rest @ ..] => {
+                let segment = s.iter().position(|c| *c == '/').unwrap_or(s.len());
+                (0..=segment).any(|i| go(rest, &s[i..]))
+            }
+            ['?', rest @ ..] => s.first().is_some_and(|c| *c != '/') && go(rest, &s[1..]),
+            [c, rest @ ..] => s.first() == Some(c) && go(rest, &s[1..]),
+        }
+    }
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = slug.chars().collect();
+    go(&p, &s)
+}
+
+impl BulkSelector {
+    pub fn matches(&self, slug: &str) -> bool {
+        self.prefix.as_deref().is_none_or(|p| slug.starts_with(p))
+            && self.glob.as_deref().is_none_or(|g| glob_matches(g, slug))
+    }
+}
+
+/// What one job works through.
+fn targets(
+    state: &AppState,
+    selector: &BulkSelector,
+    action: &BulkAction,
+) -> anyhow::Result<Vec<String>> {
+    let slugs = list_all_slugs(state)?
+        .into_iter()
+        .filter(|slug| selector.matches(slug) && owns(state, slug));
+    Ok(match action {
+        BulkAction::WorkspacePassword { .. } => slugs
+            .filter_map(|slug| workspace_of(&slug).map(str::to_string))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect(),
+        _ => slugs.collect(),
+    })
+}
+
+/// Checks the request and starts the job. `export` jobs leave the history
+/// archives as their result.
+pub fn start_bulk_job(
+    state: &AppState,
+    selector: BulkSelector,
+    action: BulkAction,
+) -> anyhow::Result<Job> {
+    if selector.prefix.is_none() && selector.glob.is_none() {
+        return Err(Rejection::new("invalid_selector", "prefix or glob required").into());
+    }
+    if let BulkAction::Replace {
+        find,
+        replace,
+        regex,
+        case_insensitive,
+    } = &action
+    {
+        ReplaceSpec {
+            find: find.clone(),
+            replace: replace.clone(),
+            regex: *regex,
+            case_insensitive: *case_insensitive,
+        }
+        .compile()?;
+    }
+    let targets = targets(state, &selector, &action)?;
+    let owned = state.clone();
+    Ok(spawn_job(
+        state,
+        action.kind(),
+        targets.len(),
+        |job| async move { run_bulk(&owned, &job, &action, targets).await },
+    ))
+}
+
+enum Outcome {
+    Done,
+    Replaced(usize),
+    Exported(Box<HistoryArchive>),
+}
+
+async fn run_bulk(
+    state: &AppState,
+    job: &JobHandle,
+    action: &BulkAction,
+    targets: Vec<String>,
+) -> anyhow::Result<Option<Value>> {
+    let mut exports = Vec::new();
+    for target in targets {
+        if job.is_cancelled() {
+            break;
+        }
+        match run_one(state, action, &target).await {
+            Ok(Outcome::Done) => {}
+            Ok(Outcome::Replaced(n)) => job.add_count("matches", n as u64),
+            Ok(Outcome::Exported(archive)) => exports.push(*archive),
+            Err(err) => {
+                warn!(job = %job.id(), %target, "{} failed: {:#}", action.kind(), err);
+                job.advance(Some(target));
+                continue;
+            }
+        }
+        job.advance(None);
+    }
+    Ok(match action {
+        BulkAction::Export => Some(serde_json::to_value(exports)?),
+        _ => None,
+    })
+}
+
+async fn run_one(state: &AppState, action: &BulkAction, target: &str) -> anyhow::Result<Outcome> {
+    if let BulkAction::WorkspacePassword { password } = action {
+        let mut settings = load_workspace(state, target)?;
+        settings.default_password_hash = password
+            .as_deref()
+            .filter(|p| !p.is_empty())
+            .map(hash_password);
+        save_workspace(state, target, &settings)?;
+        return Ok(Outcome::Done);
+    }
+    let loaded = state.docs.read().contains_key(target);
+    let outcome = match action {
+        BulkAction::Flush => {
+            flush_snapshot_force(state, target).await?;
+            Outcome::Done
+        }
+        BulkAction::Lock | BulkAction::Unlock => {
+            let value = match action {
+                BulkAction::Lock => Value::Bool(true),
+                _ => Value::Null,
+            };
+            let patch = Map::from_iter([("read_only".to_string(), value)]);
+            update_doc_settings(state, target, patch).await?;
+            Outcome::Done
+        }
+        BulkAction::Export => Outcome::Exported(Box::new(export_history(state, target)?)),
+        BulkAction::Replace {
+            find,
+            replace,
+            regex,
+            case_insensitive,
+        } => {
+            let spec = ReplaceSpec {
+                find: find.clone(),
+                replace: replace.clone(),
+                regex: *regex,
+                case_insensitive: *case_insensitive,
+            };
+            Outcome::Replaced(
+                replace_in_doc(state, target, &spec, None, None, None)
+                    .await?
+                    .matches,
+            )
+        }
+        BulkAction::WorkspacePassword { .. } => unreachable!("handled above"),
+    };
+    // Documents the job loaded itself do not stay in memory.
+    if !loaded {
+        flush_snapshot_force(state, target).await?;
+        unload_doc(state, target, "bulk");
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        jobs::{JobStatus, job_result, job_status},
+        state::{apply_edit, get_or_load_doc},
+        types::{Edit, OpKind},
+    };
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("srvtest-bulk-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            u64::MAX,
+            1_000,
+            true,
+            vec![],
+        )
+    }
+
+    fn insert(text: &str) -> Edit {
+        Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    async fn wait(state: &AppState, job: &Job) -> Job {
+        for _ in 0..200 {
+            let status = job_status(state, &job.id).unwrap();
+            if status.status == JobStatus::Done {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("job did not finish");
+    }
+
+    #[test]
+    fn globs_keep_single_stars_within_a_segment() {
+        assert!(glob_matches("team/*", "team/a"));
+        assert!(!glob_matches("team/*", "team/a/b"));
+        assert!(glob_matches("team/**", "team/a/b"));
+        assert!(glob_matches("*/notes-?", "x/notes-1"));
+        assert!(!glob_matches("*/notes-?", "x/notes-12"));
+    }
+
+    #[tokio::test]
+    async fn jobs_replace_then_lock_the_selected_documents() {
+        let state = mk_state();
+        for slug in ["team/a", "team/b", "other/c"] {
+            apply_edit(&state, slug, insert("draft v1")).await.unwrap();
+        }
+        let selector = || BulkSelector {
+            prefix: Some("team/".into()),
+            glob: None,
+        };
+
+        let replace = BulkAction::Replace {
+            find: "v1".into(),
+            replace: "v2".into(),
+            regex: false,
+            case_insensitive: false,
+        };
+        let job = start_bulk_job(&state, selector(), replace).unwrap();
+        assert_eq!(job.total, 2);
+        let done = wait(&state, &job).await;
+        assert_eq!((done.done, done.counts["matches"]), (2, 2));
+        let doc = get_or_load_doc(&state, "team/a").await.unwrap();
+        assert_eq!(doc.read().content, "draft v2");
+        let other = get_or_load_doc(&state, "other/c").await.unwrap();
+        assert_eq!(other.read().content, "draft v1");
+
+        let lock = start_bulk_job(&state, selector(), BulkAction::Lock).unwrap();
+        wait(&state, &lock).await;
+        let err = apply_edit(&state, "team/b", insert("x")).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, "read_only");
+
+        let export = start_bulk_job(&state, selector(), BulkAction::Export).unwrap();
+        wait(&state, &export).await;
+        let result = job_result(&state, &export.id).unwrap();
+        let archives: Vec<HistoryArchive> = serde_json::from_value((*result).clone()).unwrap();
+        assert_eq!(archives.len(), 2);
+        assert_eq!(archives[1].content, "draft v2");
+
+        let none = start_bulk_job(&state, BulkSelector::default(), BulkAction::Flush);
+        assert_eq!(
+            none.unwrap_err().downcast_ref::<Rejection>().unwrap().code,
+            "invalid_selector"
+        );
+    }
+}
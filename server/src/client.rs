@@ -0,0 +1,673 @@
+//! Async websocket client for bots, tools and tests, speaking the same
+//! `types.rs` messages as the server.
+//!
+//! Local edits apply immediately and are sent one batch at a time; remote
+//! edits that arrive in between are transformed against the unacknowledged
+//! ones, so callers always edit against [`Client::content`] and never deal
+//! with revisions themselves.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{Context, anyhow};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use futures::{SinkExt, StreamExt};
+use parking_lot::Mutex;
+use tokio::{
+    sync::{Notify, broadcast, mpsc},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        Message,
+        client::IntoClientRequest,
+        http::{HeaderValue, header::AUTHORIZATION},
+    },
+};
+use uuid::Uuid;
+
+use crate::{
+    document::{Doc, apply_ops, content_hash, transform_pair},
+    protocol::PROTOCOL_VERSION,
+    types::{ClientMsg, CursorState, Edit, OpKind, PresenceState, ServerMsg},
+};
+
+/// Features this client handles; it works with char ops on the whole
+/// document, so it leaves out `line_ops` and `viewport`.
+const CLIENT_CAPABILITIES: &[&str] = &[
+    "errors",
+    "owner_grant",
+    "resync",
+    "state_hash",
+    "warnings",
+    "watch",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    pub client_id: Option<Uuid>,
+    pub label: Option<String>,
+    pub color: Option<String>,
+    pub password: Option<String>,
+    /// A one-time ticket from `POST /api/ws-ticket`, for servers that only
+    /// admit sockets holding one.
+    pub ticket: Option<String>,
+}
+
+/// Things other participants did, in server order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// A remote edit, already transformed against local unsent changes.
+    RemoteEdit {
+        client_id: Option<Uuid>,
+        ops: Vec<OpKind>,
+    },
+    Presence {
+        added: Vec<PresenceState>,
+        updated: Vec<PresenceState>,
+        removed: Vec<Uuid>,
+    },
+    /// The server refused an edit; local changes were rolled back to the
+    /// last server state.
+    Rejected {
+        code: String,
+        message: String,
+    },
+    /// The document broke a content rule that does not block edits.
+    Warning {
+        code: String,
+        message: String,
+    },
+    Closed,
+}
+
+#[derive(Debug, Default)]
+struct Replica {
+    /// Server content at `rev`, without local changes.
+    server: Doc,
+    /// `server` plus `pending` and `buffer`.
+    local: Doc,
+    pending: Option<(Uuid, Vec<OpKind>)>,
+    buffer: Vec<OpKind>,
+    presence: HashMap<Uuid, PresenceState>,
+    closed: bool,
+}
+
+struct Shared {
+    slug: String,
+    client_id: Uuid,
+    replica: Mutex<Replica>,
+    outgoing: mpsc::UnboundedSender<ClientMsg>,
+    events: broadcast::Sender<ClientEvent>,
+    changed: Notify,
+}
+
+impl Shared {
+    /// Sends the buffered ops if nothing is in flight.
+    fn flush(&self, replica: &mut Replica) {
+        if replica.pending.is_some() || replica.buffer.is_empty() {
+            return;
+        }
+        let ops = std::mem::take(&mut replica.buffer);
+        let op_id = Uuid::new_v4();
+        let _ = self.outgoing.send(ClientMsg::Edit {
+            slug: self.slug.clone(),
+            edit: Edit {
+                base_rev: replica.server.rev,
+                ops: ops.clone(),
+                client_id: Some(self.client_id),
+                op_id: Some(op_id),
+                cursor_before: None,
+                cursor_after: None,
+                ts: None,
+                group_id: None,
+                user_id: None,
+            },
+        });
+        replica.pending = Some((op_id, ops));
+    }
+
+    fn handle(&self, msg: ServerMsg) {
+        let mut replica = self.replica.lock();
+        match msg {
+            ServerMsg::Applied {
+                rev,
+                ops,
+                client_id,
+                op_id,
+                hash,
+                ..
+            } => {
+                apply_ops(&mut replica.server, &ops);
+                replica.server.rev = rev;
+                if let Some(expected) = hash {
+                    let ours = content_hash(&replica.server.content);
+                    if ours != expected {
+                        // Edits keep flowing until the snapshot replaces
+                        // both copies.
+                        let _ = self.outgoing.send(ClientMsg::StateMismatch {
+                            slug: self.slug.clone(),
+                            rev,
+                            hash: Some(ours),
+                        });
+                    }
+                }
+                let own = matches!(
+                    (&replica.pending, op_id),
+                    (Some((pending_id, _)), Some(id)) if *pending_id == id
+                );
+                if own {
+                    replica.pending = None;
+                    self.flush(&mut replica);
+                } else if !ops.is_empty() {
+                    let mut incoming = ops;
+                    if let Some((id, pending)) = replica.pending.take() {
+                        let (pending, rest) = transform_pair(&pending, &incoming, true);
+                        replica.pending = Some((id, pending));
+                        incoming = rest;
+                    }
+                    let (buffer, rest) = transform_pair(&replica.buffer, &incoming, true);
+                    replica.buffer = buffer;
+                    apply_ops(&mut replica.local, &rest);
+                    let _ = self.events.send(ClientEvent::RemoteEdit {
+                        client_id,
+                        ops: rest,
+                    });
+                }
+                replica.local.rev = rev;
+            }
+            ServerMsg::PresenceSnapshot { clients, .. } => {
+                replica.presence = clients.into_iter().map(|p| (p.client_id, p)).collect();
+            }
+            ServerMsg::PresenceDiff {
+                added,
+                updated,
+                removed,
+                ..
+            } => {
+                for p in added.iter().chain(updated.iter()) {
+                    replica.presence.insert(p.client_id, p.clone());
+                }
+                for id in &removed {
+                    replica.presence.remove(id);
+                }
+                let _ = self.events.send(ClientEvent::Presence {
+                    added,
+                    updated,
+                    removed,
+                });
+            }
+            ServerMsg::CompatSnapshot { rev, content, .. }
+            | ServerMsg::Resync { rev, content, .. } => {
+                replica.server = Doc {
+                    rev,
+                    content,
+                    ..Default::default()
+                };
+                replica.local = Doc {
+                    rev,
+                    content: replica.server.content.clone(),
+                    ..Default::default()
+                };
+                replica.pending = None;
+                replica.buffer.clear();
+            }
+            ServerMsg::Error {
+                code,
+                message,
+                op_id,
+                ..
+            } => {
+                let ours = matches!(
+                    (&replica.pending, op_id),
+                    (Some((pending_id, _)), Some(id)) if *pending_id == id
+                );
+                if ours {
+                    replica.pending = None;
+                    replica.buffer.clear();
+                    replica.local = Doc {
+                        rev: replica.server.rev,
+                        content: replica.server.content.clone(),
+                        ..Default::default()
+                    };
+                }
+                let _ = self.events.send(ClientEvent::Rejected { code, message });
+            }
+            ServerMsg::Warning { code, message, .. } => {
+                let _ = self.events.send(ClientEvent::Warning { code, message });
+            }
+            _ => return,
+        }
+        drop(replica);
+        self.changed.notify_waiters();
+    }
+}
+
+/// A joined document. Dropping it closes the connection.
+pub struct Client {
+    shared: Arc<Shared>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Client {
+    /// Connects to `url` (the server's `/api/ws` endpoint), joins `slug` and
+    /// waits for the initial snapshot.
+    pub async fn connect(url: &str, slug: &str, opts: ConnectOptions) -> anyhow::Result<Self> {
+        let sep = if url.contains('?') { '&' } else { '?' };
+        let mut target = format!("{}{}slug={}", url, sep, query_escape(slug));
+        if let Some(ticket) = &opts.ticket {
+            target.push_str(&format!("&ticket={}", query_escape(ticket)));
+        }
+        // The server checks the password before the upgrade, so it goes
+        // along as Basic auth; a user name cannot hold ':', so slugs that do
+        // send it in the query instead.
+        let mut basic = None;
+        if let Some(password) = &opts.password {
+            if slug.contains(':') {
+                target.push_str(&format!("&password={}", query_escape(password)));
+            } else {
+                basic = Some(BASE64.encode(format!("{}:{}", slug, password)));
+            }
+        }
+        let mut request = target
+            .into_client_request()
+            .with_context(|| format!("invalid websocket URL {}", url))?;
+        if let Some(basic) = basic {
+            request.headers_mut().insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Basic {basic}"))?,
+            );
+        }
+        let (ws, _) = connect_async(request)
+            .await
+            .with_context(|| format!("failed to connect to {}", url))?;
+        let (mut sink, mut stream) = ws.split();
+
+        let client_id = opts.client_id.unwrap_or_else(Uuid::new_v4);
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<ClientMsg>();
+        let (events, _) = broadcast::channel(256);
+        let shared = Arc::new(Shared {
+            slug: slug.to_string(),
+            client_id,
+            replica: Mutex::new(Replica::default()),
+            outgoing,
+            events,
+            changed: Notify::new(),
+        });
+
+        let writer = tokio::spawn(async move {
+            while let Some(msg) = outgoing_rx.recv().await {
+                let Ok(text) = serde_json::to_string(&msg) else {
+                    continue;
+                };
+                if sink.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            let _ = sink.send(Message::Close(None)).await;
+        });
+
+        let (ready_tx, ready_rx) = tokio::sync::oneshot::channel::<anyhow::Result<()>>();
+        let reader_shared = shared.clone();
+        let reader = tokio::spawn(async move {
+            let mut ready_tx = Some(ready_tx);
+            while let Some(Ok(frame)) = stream.next().await {
+                let Message::Text(text) = frame else { continue };
+                let Ok(msg) = serde_json::from_str::<ServerMsg>(&text) else {
+                    continue;
+                };
+                let joined = matches!(msg, ServerMsg::CompatSnapshot { .. });
+                let refused = match &msg {
+                    ServerMsg::Error { code, message, .. } if ready_tx.is_some() => {
+                        Some(anyhow!("{}: {}", code, message))
+                    }
+                    _ => None,
+                };
+                reader_shared.handle(msg);
+                if joined && let Some(tx) = ready_tx.take() {
+                    let _ = tx.send(Ok(()));
+                }
+                if let Some(err) = refused
+                    && let Some(tx) = ready_tx.take()
+                {
+                    let _ = tx.send(Err(err));
+                }
+            }
+            reader_shared.replica.lock().closed = true;
+            let _ = reader_shared.events.send(ClientEvent::Closed);
+            reader_shared.changed.notify_waiters();
+        });
+
+        let _ = shared.outgoing.send(ClientMsg::Join {
+            session_id: slug.to_string(),
+            client_id,
+            label: opts.label,
+            color: opts.color,
+            password: opts.password,
+            token: None,
+            version: Some(PROTOCOL_VERSION),
+            capabilities: CLIENT_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            user_id: None,
+            known: None,
+        });
+
+        let client = Self {
+            shared,
+            tasks: vec![writer, reader],
+        };
+        match tokio::time::timeout(Duration::from_secs(10), ready_rx).await {
+            Ok(Ok(Ok(()))) => Ok(client),
+            Ok(Ok(Err(err))) => Err(err),
+            Ok(Err(_)) => Err(anyhow!("connection closed before the document was joined")),
+            Err(_) => Err(anyhow!("timed out waiting for the document snapshot")),
+        }
+    }
+
+    pub fn client_id(&self) -> Uuid {
+        self.shared.client_id
+    }
+
+    /// Local view of the document, including edits not yet acknowledged.
+    pub fn content(&self) -> String {
+        self.shared.replica.lock().local.content.clone()
+    }
+
+    /// Last revision confirmed by the server.
+    pub fn rev(&self) -> u64 {
+        self.shared.replica.lock().server.rev
+    }
+
+    pub fn presence(&self) -> Vec<PresenceState> {
+        self.shared
+            .replica
+            .lock()
+            .presence
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub fn events(&self) -> broadcast::Receiver<ClientEvent> {
+        self.shared.events.subscribe()
+    }
+
+    /// Applies `ops` (positions relative to [`Client::content`]) locally and
+    /// queues them for the server.
+    pub fn apply_edit(&self, ops: Vec<OpKind>) -> anyhow::Result<()> {
+        let mut replica = self.shared.replica.lock();
+        if replica.closed {
+            return Err(anyhow!("connection closed"));
+        }
+        apply_ops(&mut replica.local, &ops);
+        replica.buffer.extend(ops);
+        self.shared.flush(&mut replica);
+        Ok(())
+    }
+
+    pub fn insert(&self, pos: usize, text: &str) -> anyhow::Result<()> {
+        self.apply_edit(vec![OpKind::Insert {
+            pos,
+            text: text.to_string(),
+        }])
+    }
+
+    pub fn delete(&self, pos: usize, len: usize) -> anyhow::Result<()> {
+        self.apply_edit(vec![OpKind::Delete { pos, len }])
+    }
+
+    pub fn set_cursor(&self, cursor: CursorState) {
+        let _ = self.shared.outgoing.send(ClientMsg::Cursor {
+            slug: self.shared.slug.clone(),
+            cursor,
+            op_id: Some(Uuid::new_v4()),
+            ts: None,
+        });
+    }
+
+    /// Waits until every local edit has been acknowledged.
+    pub async fn synced(&self) -> anyhow::Result<()> {
+        loop {
+            let notified = self.shared.changed.notified();
+            {
+                let replica = self.shared.replica.lock();
+                if replica.pending.is_none() && replica.buffer.is_empty() {
+                    return Ok(());
+                }
+                if replica.closed {
+                    return Err(anyhow!("connection closed with unsent edits"));
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Waits until the server revision reaches at least `rev`.
+    pub async fn wait_for_rev(&self, rev: u64) -> anyhow::Result<()> {
+        loop {
+            let notified = self.shared.changed.notified();
+            {
+                let replica = self.shared.replica.lock();
+                if replica.server.rev >= rev {
+                    return Ok(());
+                }
+                if replica.closed {
+                    return Err(anyhow!("connection closed at rev {}", replica.server.rev));
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Percent-encodes `value` for a query string.
+fn query_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    out
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AppState, build_router,
+        types::{DocEvent, WalLine},
+    };
+    use std::{fs, path::Path};
+
+    fn mk_state(tmp: &Path) -> AppState {
+        let wal_dir = tmp.join("wal");
+        let snap_dir = tmp.join("snapshots");
+        fs::create_dir_all(&wal_dir).unwrap();
+        fs::create_dir_all(&snap_dir).unwrap();
+        AppState::new(wal_dir, snap_dir, 10_000, 1_000, true, Vec::new())
+    }
+
+    async fn serve(state: &AppState) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = build_router(state);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("ws://{}/api/ws", addr)
+    }
+
+    #[tokio::test]
+    async fn concurrent_clients_rebase_and_converge() {
+        let base = std::env::temp_dir().join(format!("client-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        let url = serve(&state).await;
+
+        let a = Client::connect(&url, "shared", ConnectOptions::default())
+            .await
+            .unwrap();
+        let b = Client::connect(
+            &url,
+            "shared",
+            ConnectOptions {
+                label: Some("bot".into()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let mut b_events = b.events();
+
+        for i in 0..20 {
+            a.insert(0, "a").unwrap();
+            b.insert(b.content().chars().count(), "b").unwrap();
+            if i % 5 == 0 {
+                a.delete(0, 1).unwrap();
+            }
+        }
+        a.synced().await.unwrap();
+        b.synced().await.unwrap();
+        let rev = a.rev().max(b.rev());
+        a.wait_for_rev(rev).await.unwrap();
+        b.wait_for_rev(rev).await.unwrap();
+
+        assert_eq!(a.content(), b.content());
+        let doc = crate::state::get_or_load_doc(&state, "shared")
+            .await
+            .unwrap();
+        assert_eq!(doc.read().content, a.content());
+        assert_eq!(a.content().matches('b').count(), 20);
+        assert!(
+            a.presence()
+                .iter()
+                .any(|p| p.label.as_deref() == Some("bot"))
+        );
+        assert!(matches!(
+            b_events.try_recv(),
+            Ok(ClientEvent::RemoteEdit { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn hash_mismatch_brings_a_fresh_snapshot() {
+        let base = std::env::temp_dir().join(format!("client-hash-{}", Uuid::new_v4()));
+        let mut state = mk_state(&base);
+        state.hash_interval = 1;
+        let url = serve(&state).await;
+        let a = Client::connect(&url, "hashed", ConnectOptions::default())
+            .await
+            .unwrap();
+        a.insert(0, "abc").unwrap();
+        a.synced().await.unwrap();
+
+        // Change the server copy behind the client's back.
+        let doc = crate::state::get_or_load_doc(&state, "hashed")
+            .await
+            .unwrap();
+        doc.write().content.push('!');
+        a.insert(0, ">").unwrap();
+        a.synced().await.unwrap();
+        for _ in 0..100 {
+            if a.content() == ">abc!" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(a.content(), ">abc!");
+        assert_eq!(a.rev(), 2);
+    }
+
+    #[tokio::test]
+    async fn cursors_stay_out_of_the_wal() {
+        let base = std::env::temp_dir().join(format!("client-cursor-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        let url = serve(&state).await;
+        let a = Client::connect(&url, "ephemeral", ConnectOptions::default())
+            .await
+            .unwrap();
+        a.set_cursor(CursorState {
+            position: 0,
+            anchor: None,
+            selection_direction: None,
+        });
+        a.insert(0, "hi").unwrap();
+        a.synced().await.unwrap();
+
+        let wal = crate::storage::read_wal(&state, "ephemeral")
+            .unwrap()
+            .unwrap();
+        let events: Vec<WalLine> = wal
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0],
+            WalLine::V2(entry) if matches!(entry.event, DocEvent::Edit { .. })
+        ));
+        let presence = state.presence.read();
+        assert!(
+            presence["ephemeral"]
+                .clients
+                .values()
+                .any(|p| p.cursor.is_some())
+        );
+    }
+
+    #[tokio::test]
+    async fn connects_to_protected_documents_with_odd_slugs() {
+        let base = std::env::temp_dir().join(format!("client-protected-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        let url = serve(&state).await;
+        for slug in ["team/locked doc", "a:b&c=d"] {
+            let doc = crate::state::get_or_load_doc(&state, slug).await.unwrap();
+            doc.write().password_hash = Some(crate::storage::hash_password("hunter2"));
+            drop(doc);
+
+            assert!(
+                Client::connect(&url, slug, ConnectOptions::default())
+                    .await
+                    .is_err()
+            );
+            let wrong = ConnectOptions {
+                password: Some("wrong".into()),
+                ..Default::default()
+            };
+            assert!(Client::connect(&url, slug, wrong).await.is_err());
+            let client = Client::connect(
+                &url,
+                slug,
+                ConnectOptions {
+                    password: Some("hunter2".into()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            client.insert(0, "ok").unwrap();
+            client.synced().await.unwrap();
+            assert_eq!(client.content(), "ok");
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_reports_refused_join() {
+        let base = std::env::temp_dir().join(format!("client-refused-{}", Uuid::new_v4()));
+        let mut state = mk_state(&base);
+        state.invite_only = true;
+        let url = serve(&state).await;
+        assert!(
+            Client::connect(&url, "missing", ConnectOptions::default())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,558 @@
+//! Document ownership across several nodes sharing one data directory. Every
+//! slug is owned by exactly one live node, picked on a consistent-hash ring,
+//! so OT stays single-writer: requests that name a document are proxied to
+//! its owner (WebSocket upgrades included), and a node refuses edits to
+//! documents it does not own.
+//!
+//! Nodes check each other's `/api/health`. A node that fails
+//! [`FAILURES_BEFORE_DOWN`] checks in a row drops off the ring and its
+//! documents fall to the next node, which replays them from the shared WAL on
+//! first use. When a node comes back, the documents it owns again are handed
+//! back: the interim owner unloads them and closes their sessions with a
+//! `moved` error, and the clients reconnect through any node. A node cut off
+//! from every peer keeps serving everything, so network partitions need an
+//! external fence.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Query, Request, State},
+    http::{HeaderValue, StatusCode, Uri, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{io::copy_bidirectional, net::TcpStream, sync::watch, time::timeout};
+use tracing::{info, warn};
+
+use crate::{
+    handlers::http::doc_path_slug,
+    state::{AppState, broadcast, unload_doc},
+    types::ServerMsg,
+};
+
+pub const DEFAULT_CLUSTER_HEALTH_MS: u64 = 2_000;
+/// Failed health checks in a row before a node is taken off the ring.
+pub const FAILURES_BEFORE_DOWN: u32 = 3;
+/// Points per node on the ring; more points spread slugs more evenly.
+const VNODES: usize = 64;
+/// Set on proxied requests so the receiving node serves them itself.
+pub const FORWARDED_HEADER: &str = "x-coedit-forwarded-by";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+/// JSON bodies are buffered to find their `slug`; the largest one accepted.
+const FORWARD_BODY_LIMIT: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClusterNode {
+    pub id: String,
+    /// Base URL of the node's router (`http://` only).
+    pub url: String,
+}
+
+/// Parses `id=url` pairs separated by commas.
+pub fn parse_nodes(spec: &str) -> anyhow::Result<Vec<ClusterNode>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (id, url) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("cluster node '{}' is not id=url", entry))?;
+            Ok(ClusterNode {
+                id: id.trim().to_string(),
+                url: url.trim().trim_end_matches('/').to_string(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct Cluster {
+    pub self_id: String,
+    pub nodes: Vec<ClusterNode>,
+    pub health_ms: u64,
+    /// Ring points and the index of the node each belongs to, sorted.
+    ring: Vec<(u64, usize)>,
+    down: RwLock<HashSet<String>>,
+    failures: Mutex<HashMap<String, u32>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeView {
+    #[serde(flatten)]
+    pub node: ClusterNode,
+    pub up: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClusterView {
+    pub self_id: String,
+    pub nodes: Vec<NodeView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+fn ring_hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("sha256 is 32 bytes"))
+}
+
+impl Cluster {
+    pub fn new(self_id: String, nodes: Vec<ClusterNode>, health_ms: u64) -> anyhow::Result<Self> {
+        if !nodes.iter().any(|node| node.id == self_id) {
+            anyhow::bail!("cluster node id '{}' is not in the node list", self_id);
+        }
+        let mut ids = HashSet::new();
+        if let Some(dup) = nodes.iter().find(|node| !ids.insert(&node.id)) {
+            anyhow::bail!("cluster node id '{}' is listed twice", dup.id);
+        }
+        let mut ring: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, node)| {
+                (0..VNODES).map(move |v| (ring_hash(&format!("{}#{}", node.id, v)), idx))
+            })
+            .collect();
+        ring.sort_unstable();
+        Ok(Self {
+            self_id,
+            nodes,
+            health_ms,
+            ring,
+            down: RwLock::new(HashSet::new()),
+            failures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The live node owning `slug`: the first one clockwise from its hash.
+    pub fn owner(&self, slug: &str) -> &ClusterNode {
+        let down = self.down.read();
+        let start = self
+            .ring
+            .partition_point(|(point, _)| *point < ring_hash(slug));
+        self.ring
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(self.ring.len())
+            .map(|(_, idx)| &self.nodes[*idx])
+            .find(|node| !down.contains(&node.id))
+            .unwrap_or_else(|| self.local_node())
+    }
+
+    pub fn is_local(&self, slug: &str) -> bool {
+        self.owner(slug).id == self.self_id
+    }
+
+    fn local_node(&self) -> &ClusterNode {
+        self.nodes
+            .iter()
+            .find(|node| node.id == self.self_id)
+            .expect("checked in Cluster::new")
+    }
+
+    pub fn is_up(&self, id: &str) -> bool {
+        !self.down.read().contains(id)
+    }
+
+    /// Records one health check of `id`. Returns whether the node went down
+    /// or came back.
+    pub fn record_health(&self, id: &str, ok: bool) -> bool {
+        if id == self.self_id {
+            return false;
+        }
+        let mut failures = self.failures.lock();
+        let count = failures.entry(id.to_string()).or_default();
+        *count = if ok { 0 } else { count.saturating_add(1) };
+        let mut down = self.down.write();
+        if ok {
+            down.remove(id)
+        } else if *count >= FAILURES_BEFORE_DOWN {
+            down.insert(id.to_string())
+        } else {
+            false
+        }
+    }
+
+    pub fn view(&self, slug: Option<&str>) -> ClusterView {
+        ClusterView {
+            self_id: self.self_id.clone(),
+            nodes: self
+                .nodes
+                .iter()
+                .map(|node| NodeView {
+                    node: node.clone(),
+                    up: self.is_up(&node.id),
+                })
+                .collect(),
+            owner: slug.map(|slug| self.owner(slug).id.clone()),
+        }
+    }
+}
+
+/// Whether this node may write `slug`. Always true outside a cluster.
+pub fn owns(state: &AppState, slug: &str) -> bool {
+    state
+        .cluster
+        .as_ref()
+        .is_none_or(|cluster| cluster.is_local(slug))
+}
+
+#[derive(Deserialize)]
+struct SlugField {
+    slug: Option<String>,
+}
+
+/// The document a request is about: `?slug=`, the `/api/docs/{slug}/...`
+/// path, or the `slug` of a JSON body.
+async fn request_slug(req: Request) -> Result<(Option<String>, Request), Response> {
+    if let Ok(Query(SlugField { slug: Some(slug) })) = Query::try_from_uri(req.uri()) {
+        return Ok((Some(slug), req));
+    }
+    if let Some(path) = req.uri().path().strip_prefix("/api/docs/") {
+        let slug = doc_path_slug(path).map(str::to_string);
+        return Ok((slug, req));
+    }
+    let json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !json {
+        return Ok((None, req));
+    }
+    let (parts, body) = req.into_parts();
+    let bytes = to_bytes(body, FORWARD_BODY_LIMIT)
+        .await
+        .map_err(|_| (StatusCode::PAYLOAD_TOO_LARGE, "request body too large").into_response())?;
+    let slug = serde_json::from_slice::<SlugField>(&bytes)
+        .ok()
+        .and_then(|field| field.slug);
+    Ok((slug, Request::from_parts(parts, Body::from(bytes))))
+}
+
+/// Middleware sending requests about a document to the node that owns it.
+pub async fn forward_to_owner(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(cluster) = state.cluster.clone() else {
+        return next.run(req).await;
+    };
+    if req.headers().contains_key(FORWARDED_HEADER) || req.uri().path().starts_with("/api/admin/") {
+        return next.run(req).await;
+    }
+    let (slug, req) = match request_slug(req).await {
+        Ok(found) => found,
+        Err(resp) => return resp,
+    };
+    let Some(slug) = slug else {
+        return next.run(req).await;
+    };
+    let owner = cluster.owner(&slug).clone();
+    if owner.id == cluster.self_id {
+        return next.run(req).await;
+    }
+    match proxy(&owner, &cluster.self_id, req).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            warn!(%slug, node = %owner.id, "forwarding to the owner failed: {:#}", err);
+            (StatusCode::BAD_GATEWAY, "document owner is unreachable").into_response()
+        }
+    }
+}
+
+/// Sends `req` to `base_url` over HTTP/1.1, keeping the connection open for
+/// an upgrade.
+async fn send(base_url: &str, mut req: Request) -> anyhow::Result<hyper::Response<Incoming>> {
+    let base: Uri = base_url.parse()?;
+    if base.scheme_str() != Some("http") {
+        anyhow::bail!("only http:// cluster nodes are supported: {}", base_url);
+    }
+    let host = base
+        .host()
+        .ok_or_else(|| anyhow::anyhow!("cluster node url has no host: {}", base_url))?;
+    let addr = format!("{}:{}", host, base.port_u16().unwrap_or(80));
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    *req.uri_mut() = format!("{}{}", base.path().trim_end_matches('/'), path).parse()?;
+    let authority = base.authority().map(|a| a.as_str()).unwrap_or(host);
+    req.headers_mut()
+        .insert(header::HOST, HeaderValue::from_str(authority)?);
+
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await??;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(conn.with_upgrades());
+    Ok(sender.send_request(req).await?)
+}
+
+async fn proxy(owner: &ClusterNode, self_id: &str, mut req: Request) -> anyhow::Result<Response> {
+    req.headers_mut()
+        .insert(FORWARDED_HEADER, HeaderValue::from_str(self_id)?);
+    let upgrade = req
+        .headers()
+        .contains_key(header::UPGRADE)
+        .then(|| hyper::upgrade::on(&mut req));
+    let mut resp = send(&owner.url, req).await?;
+    if resp.status() == StatusCode::SWITCHING_PROTOCOLS
+        && let Some(downstream) = upgrade
+    {
+        let upstream = hyper::upgrade::on(&mut resp);
+        tokio::spawn(async move {
+            match (downstream.await, upstream.await) {
+                (Ok(down), Ok(up)) => {
+                    let _ =
+                        copy_bidirectional(&mut TokioIo::new(down), &mut TokioIo::new(up)).await;
+                }
+                (Err(err), _) | (_, Err(err)) => warn!("proxied upgrade failed: {:#}", err),
+            }
+        });
+    }
+    Ok(resp.map(Body::new))
+}
+
+async fn check_health(node: &ClusterNode) -> bool {
+    let req = Request::get("/api/health")
+        .body(Body::empty())
+        .expect("static request");
+    matches!(
+        timeout(HEALTH_TIMEOUT, send(&node.url, req)).await,
+        Ok(Ok(resp)) if resp.status().is_success()
+    )
+}
+
+/// Gives up documents this node no longer owns. Every edit is already in the
+/// WAL, so they are only unloaded; their sessions get `moved` and close.
+/// Returns how many were handed off.
+pub fn hand_off(state: &AppState) -> usize {
+    let Some(cluster) = &state.cluster else {
+        return 0;
+    };
+    let loaded: Vec<String> = state.docs.read().keys().cloned().collect();
+    let mut moved = 0;
+    for slug in loaded {
+        let owner = cluster.owner(&slug);
+        if owner.id == cluster.self_id {
+            continue;
+        }
+        unload_doc(state, &slug, "moved");
+        broadcast(
+            state,
+            &slug,
+            ServerMsg::Error {
+                slug: slug.clone(),
+                code: "moved".to_string(),
+                message: format!("document moved to node {}", owner.id),
+                op_id: None,
+            },
+        );
+        moved += 1;
+    }
+    moved
+}
+
+/// Checks the other nodes until `shutdown` flips to `true`, handing off
+/// documents whenever the ring changes.
+pub async fn run_cluster_health(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    let Some(cluster) = state.cluster.clone() else {
+        return;
+    };
+    let interval = Duration::from_millis(cluster.health_ms.max(100));
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {
+                let mut changed = false;
+                for node in cluster.nodes.iter().filter(|n| n.id != cluster.self_id) {
+                    let ok = check_health(node).await;
+                    if cluster.record_health(&node.id, ok) {
+                        changed = true;
+                        if ok {
+                            info!(node = %node.id, "cluster node is back");
+                        } else {
+                            warn!(node = %node.id, "cluster node is down; taking over its documents");
+                        }
+                    }
+                }
+                if changed {
+                    let moved = hand_off(&state);
+                    info!(moved, "cluster ring changed");
+                }
+            }
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build_router,
+        state::{apply_edit, get_or_load_doc},
+        types::{Edit, OpKind},
+    };
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    fn mk_state(base: &std::path::Path) -> AppState {
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            1_000,
+            100,
+            true,
+            vec![],
+        )
+    }
+
+    fn insert(base_rev: u64, text: &str) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    fn nodes(urls: &[&str]) -> Vec<ClusterNode> {
+        urls.iter()
+            .enumerate()
+            .map(|(idx, url)| ClusterNode {
+                id: format!("n{}", idx),
+                url: url.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ring_spreads_slugs_and_skips_down_nodes() {
+        let cluster =
+            Cluster::new("n0".into(), nodes(&["http://a", "http://b", "http://c"]), 1).unwrap();
+        let slugs: Vec<String> = (0..300).map(|i| format!("doc-{}", i)).collect();
+        let owners: Vec<String> = slugs.iter().map(|s| cluster.owner(s).id.clone()).collect();
+        for id in ["n0", "n1", "n2"] {
+            assert!(
+                owners.iter().filter(|o| *o == id).count() > 50,
+                "{} owns too few",
+                id
+            );
+        }
+
+        for _ in 0..FAILURES_BEFORE_DOWN - 1 {
+            assert!(!cluster.record_health("n1", false));
+        }
+        assert!(cluster.record_health("n1", false));
+        for (slug, before) in slugs.iter().zip(&owners) {
+            let after = &cluster.owner(slug).id;
+            assert_ne!(after, "n1");
+            // Only the failed node's documents move.
+            if before != "n1" {
+                assert_eq!(after, before);
+            }
+        }
+        assert!(cluster.record_health("n1", true));
+        let back: Vec<String> = slugs.iter().map(|s| cluster.owner(s).id.clone()).collect();
+        assert_eq!(back, owners);
+
+        assert!(Cluster::new("zz".into(), nodes(&["http://a"]), 1).is_err());
+        assert_eq!(
+            parse_nodes("a=http://x:1/, b = http://y").unwrap()[0].url,
+            "http://x:1"
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_reach_the_owner_and_ownership_moves_on_failure() {
+        let base = std::env::temp_dir().join(format!("srvtest-cluster-{}", Uuid::new_v4()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let members = nodes(&[&url, &url]);
+
+        let mut remote = mk_state(&base);
+        remote.cluster = Some(Arc::new(
+            Cluster::new("n1".into(), members.clone(), 1).unwrap(),
+        ));
+        let app = build_router(&remote);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let mut local = mk_state(&base);
+        local.cluster = Some(Arc::new(Cluster::new("n0".into(), members, 1).unwrap()));
+        let cluster = local.cluster.clone().unwrap();
+
+        let slug = (0..)
+            .map(|i| format!("doc-{}", i))
+            .find(|slug| cluster.owner(slug).id == "n1")
+            .unwrap();
+        let err = apply_edit(&local, &slug, insert(0, "x")).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<crate::state::Rejection>().unwrap().code,
+            "not_owner"
+        );
+        apply_edit(&remote, &slug, insert(0, "remote"))
+            .await
+            .unwrap();
+
+        let resp = tower::util::ServiceExt::oneshot(
+            build_router(&local),
+            Request::get(format!("/api/snapshot?slug={}", slug))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let snap: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(snap["content"], "remote");
+        assert!(!local.docs.read().contains_key(&slug));
+
+        // The owner goes away: this node takes over from the shared WAL.
+        for _ in 0..FAILURES_BEFORE_DOWN {
+            cluster.record_health("n1", false);
+        }
+        apply_edit(&local, &slug, insert(1, "taken "))
+            .await
+            .unwrap();
+        let doc = get_or_load_doc(&local, &slug).await.unwrap();
+        assert_eq!(doc.read().content, "taken remote");
+
+        // And hands the document back when it returns.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        local
+            .subs
+            .write()
+            .entry(slug.clone())
+            .or_default()
+            .push(tx.into());
+        cluster.record_health("n1", true);
+        assert_eq!(hand_off(&local), 1);
+        assert!(!local.docs.read().contains_key(&slug));
+        let Some(ServerMsg::Error { code, .. }) = rx.recv().await else {
+            panic!("session was not told about the move");
+        };
+        assert_eq!(code, "moved");
+    }
+}
@@ -0,0 +1,339 @@
+//! Where concurrent edits collide. An edit made against an older revision
+//! conflicts when its ops touch text that an edit it is rebased across
+//! touched too; ops that merely shift past each other do not count.
+//! Conflicts are kept in memory for a week per document and summarized by
+//! `GET /api/conflicts` per section, or per block of lines outside Markdown
+//! headings, and per time bucket.
+
+use std::collections::{HashMap, VecDeque};
+
+use serde::Serialize;
+
+use crate::{
+    document::{Doc, OpShape, ShapedOp, map_through_delete, transform_pair},
+    retention::DAY_MS,
+    sections::sections,
+    state::AppState,
+    types::{ContentType, Edit},
+};
+
+const MAX_EVENTS: usize = 1_000;
+const KEEP_MS: u64 = 7 * DAY_MS;
+const LINES_PER_BLOCK: usize = 20;
+/// Buckets one summary may have.
+pub const MAX_BUCKETS: u64 = 1_000;
+pub const DEFAULT_WINDOW_MS: u64 = DAY_MS;
+pub const DEFAULT_BUCKET_MS: u64 = 60 * 60 * 1000;
+
+/// One conflict, placed in the section or block of lines it hit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictEvent {
+    pub at: u64,
+    pub heading: Option<String>,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ConflictRange {
+    /// The Markdown section; absent for a block of lines.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heading: Option<String>,
+    /// 1-based and inclusive, as of the latest conflict.
+    pub start_line: usize,
+    pub end_line: usize,
+    pub conflicts: u64,
+    pub last_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ConflictBucket {
+    pub start: u64,
+    pub conflicts: u64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ConflictsResp {
+    pub slug: String,
+    pub since: u64,
+    pub until: u64,
+    pub total: u64,
+    /// Most fought over first.
+    pub ranges: Vec<ConflictRange>,
+    /// Every bucket of the window, oldest first.
+    pub buckets: Vec<ConflictBucket>,
+}
+
+/// Moves a position written after `ops` back to before them. Inside
+/// inserted text it lands where the text went in; at a deletion `low`
+/// picks its start, otherwise its end.
+fn unmap<T: ShapedOp>(pos: usize, ops: &[T], low: bool) -> usize {
+    ops.iter().rev().fold(pos, |pos, op| match op.shape() {
+        OpShape::Insert { pos: o, chars: n } => {
+            if pos <= o {
+                pos
+            } else if pos >= o + n {
+                pos - n
+            } else {
+                o
+            }
+        }
+        OpShape::Delete { pos: o, len } => {
+            if pos < o || (pos == o && low) {
+                pos
+            } else {
+                pos + len
+            }
+        }
+    })
+}
+
+/// The span `ops` touch in the text they apply to, both ends included.
+fn footprint<T: ShapedOp>(ops: &[T]) -> Option<(usize, usize)> {
+    ops.iter()
+        .enumerate()
+        .map(|(idx, op)| {
+            let (start, end) = match op.shape() {
+                OpShape::Insert { pos, .. } => (pos, pos),
+                OpShape::Delete { pos, len } => (pos, pos + len),
+            };
+            (
+                unmap(start, &ops[..idx], true),
+                unmap(end, &ops[..idx], false),
+            )
+        })
+        .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1)))
+}
+
+fn map_forward(pos: usize, ops: &[OpShape]) -> usize {
+    ops.iter().fold(pos, |pos, op| match *op {
+        OpShape::Insert { pos: o, chars } if o < pos => pos + chars,
+        OpShape::Insert { .. } => pos,
+        OpShape::Delete { pos: o, len } => map_through_delete(pos, o, len),
+    })
+}
+
+/// Where in the current text `edit` collided with the edits it is rebased
+/// across, if it did.
+pub fn conflict_span(doc: &Doc, edit: &Edit) -> Option<(usize, usize)> {
+    let mut ops = edit.ops.clone();
+    let mut span: Option<(usize, usize)> = None;
+    for prev in doc.log.get(edit.base_rev as usize..).unwrap_or_default() {
+        if let (Some(a), Some(b)) = (footprint(&ops), footprint(prev))
+            && a.0 <= b.1
+            && b.0 <= a.1
+        {
+            let hit = (a.0.min(b.0), a.1.max(b.1));
+            span = Some(span.map_or(hit, |s| (s.0.min(hit.0), s.1.max(hit.1))));
+        }
+        span = span.map(|(start, end)| (map_forward(start, prev), map_forward(end, prev)));
+        ops = transform_pair(&ops, prev, true).0;
+    }
+    span
+}
+
+/// 1-based line of the character at `pos`.
+fn line_of(content: &str, pos: usize) -> usize {
+    1 + content.chars().take(pos).filter(|c| *c == '\n').count()
+}
+
+/// Places a conflict at `span` of `doc`'s current text and keeps it.
+pub fn record_conflict(state: &AppState, slug: &str, doc: &Doc, span: (usize, usize), now: u64) {
+    let markdown = doc.meta.content_type.clone().unwrap_or_default() == ContentType::Markdown;
+    let section = markdown
+        .then(|| sections(&doc.content))
+        .into_iter()
+        .flatten()
+        .filter(|(start, end, _)| *start <= span.0 && span.0 < *end)
+        .max_by_key(|(start, ..)| *start);
+    let event = match section {
+        Some((start, end, heading)) => ConflictEvent {
+            at: now,
+            heading: Some(heading),
+            start_line: line_of(&doc.content, start),
+            end_line: line_of(&doc.content, end.saturating_sub(1).max(start)),
+        },
+        None => {
+            let block = (line_of(&doc.content, span.0) - 1) / LINES_PER_BLOCK;
+            ConflictEvent {
+                at: now,
+                heading: None,
+                start_line: block * LINES_PER_BLOCK + 1,
+                end_line: (block + 1) * LINES_PER_BLOCK,
+            }
+        }
+    };
+    let mut conflicts = state.conflicts.write();
+    let events = conflicts.entry(slug.to_string()).or_default();
+    while events
+        .front()
+        .is_some_and(|e| e.at + KEEP_MS <= now || events.len() >= MAX_EVENTS)
+    {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
+
+/// Conflicts of `slug` in the `window_ms` before `now`. Sections are told
+/// apart by heading, blocks by their lines.
+pub fn summarize(
+    state: &AppState,
+    slug: &str,
+    now: u64,
+    window_ms: u64,
+    bucket_ms: u64,
+) -> ConflictsResp {
+    let since = now.saturating_sub(window_ms);
+    let mut ranges: HashMap<(Option<String>, usize), ConflictRange> = HashMap::new();
+    let mut buckets: Vec<ConflictBucket> = (0..window_ms.div_ceil(bucket_ms))
+        .map(|idx| ConflictBucket {
+            start: since + idx * bucket_ms,
+            conflicts: 0,
+        })
+        .collect();
+    let mut total = 0;
+    let conflicts = state.conflicts.read();
+    let events = conflicts
+        .get(slug)
+        .map(VecDeque::iter)
+        .into_iter()
+        .flatten();
+    for event in events.filter(|e| e.at > since && e.at <= now) {
+        total += 1;
+        if let Some(bucket) = buckets.get_mut(((event.at - since - 1) / bucket_ms) as usize) {
+            bucket.conflicts += 1;
+        }
+        let key = match &event.heading {
+            Some(heading) => (Some(heading.clone()), 0),
+            None => (None, event.start_line),
+        };
+        let range = ranges.entry(key).or_insert_with(|| ConflictRange {
+            heading: event.heading.clone(),
+            start_line: event.start_line,
+            end_line: event.end_line,
+            conflicts: 0,
+            last_at: event.at,
+        });
+        range.conflicts += 1;
+        if event.at >= range.last_at {
+            (range.start_line, range.end_line, range.last_at) =
+                (event.start_line, event.end_line, event.at);
+        }
+    }
+    let mut ranges: Vec<ConflictRange> = ranges.into_values().collect();
+    ranges.sort_by(|a, b| {
+        b.conflicts
+            .cmp(&a.conflicts)
+            .then(a.start_line.cmp(&b.start_line))
+    });
+    ConflictsResp {
+        slug: slug.to_string(),
+        since,
+        until: now,
+        total,
+        ranges,
+        buckets,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    use crate::types::OpKind;
+
+    use crate::state::{apply_edit, now_millis};
+
+    fn edit(base_rev: u64, ops: Vec<OpKind>) -> Edit {
+        Edit {
+            base_rev,
+            ops,
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    fn insert(pos: usize, text: &str) -> OpKind {
+        OpKind::Insert {
+            pos,
+            text: text.into(),
+        }
+    }
+
+    #[test]
+    fn footprints_are_in_the_original_text() {
+        let ops = [insert(2, "abc"), OpKind::Delete { pos: 7, len: 2 }];
+        assert_eq!(footprint(&ops), Some((2, 6)));
+        assert_eq!(
+            footprint(&[OpKind::Delete { pos: 1, len: 3 }]),
+            Some((1, 4))
+        );
+        assert_eq!(footprint::<OpKind>(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn counts_overlapping_concurrent_edits_per_section() {
+        let base = std::env::temp_dir().join(format!("conflicts-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let text = "# Intro\nhello\n# Plan\nsteps here\n";
+        apply_edit(&state, "spec", edit(0, vec![insert(0, text)]))
+            .await
+            .unwrap();
+        let plan = text.find("steps").unwrap();
+
+        // Both rewrite the same word.
+        apply_edit(
+            &state,
+            "spec",
+            edit(1, vec![OpKind::Delete { pos: plan, len: 5 }]),
+        )
+        .await
+        .unwrap();
+        apply_edit(&state, "spec", edit(1, vec![insert(plan + 2, "x")]))
+            .await
+            .unwrap();
+        // Far apart: only shifted.
+        apply_edit(&state, "spec", edit(1, vec![insert(3, "y")]))
+            .await
+            .unwrap();
+        // Sequential edits never conflict.
+        apply_edit(&state, "spec", edit(4, vec![insert(plan, "z")]))
+            .await
+            .unwrap();
+
+        let now = now_millis();
+        let summary = summarize(&state, "spec", now, DEFAULT_WINDOW_MS, DEFAULT_BUCKET_MS);
+        assert_eq!(summary.total, 1);
+        assert_eq!(summary.ranges.len(), 1);
+        let range = &summary.ranges[0];
+        assert_eq!(range.heading.as_deref(), Some("Plan"));
+        assert_eq!(
+            (range.start_line, range.end_line, range.conflicts),
+            (3, 4, 1)
+        );
+        assert_eq!(summary.buckets.len(), 24);
+        assert_eq!(summary.buckets.last().unwrap().conflicts, 1);
+
+        let later = summarize(
+            &state,
+            "spec",
+            now + DAY_MS,
+            DEFAULT_WINDOW_MS,
+            DEFAULT_WINDOW_MS,
+        );
+        assert_eq!((later.total, later.buckets.len()), (0, 1));
+    }
+}
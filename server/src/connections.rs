@@ -0,0 +1,202 @@
+//! Live WebSocket sessions, for tracking down the one socket that holds a
+//! document back. Each session registers itself while it runs and keeps
+//! its counters here; `GET /api/admin/connections` lists them, most
+//! backed up first, and `POST /api/admin/connections/{id}/disconnect` drops
+//! one without waiting for its queue to drain.
+
+use std::{
+    cmp::Reverse,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::Notify;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::state::AppState;
+
+/// One running session.
+#[derive(Debug)]
+pub struct Connection {
+    pub id: Uuid,
+    pub slug: String,
+    pub connected_at: u64,
+    /// Set once the session said `Hello`.
+    client_id: Mutex<Option<Uuid>>,
+    rtt_ms: Mutex<Option<u64>>,
+    queued: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    last_sent_at: AtomicU64,
+    last_received_at: AtomicU64,
+    kick: Notify,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: Uuid,
+    pub slug: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<Uuid>,
+    pub connected_at: u64,
+    /// Messages waiting to be written to the socket, as of when the session
+    /// last picked one up.
+    pub queued: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// 0 until a frame went that way.
+    pub last_sent_at: u64,
+    pub last_received_at: u64,
+}
+
+impl Connection {
+    pub fn set_client(&self, client_id: Option<Uuid>) {
+        *self.client_id.lock() = client_id;
+    }
+
+    pub fn set_rtt(&self, rtt_ms: u64) {
+        *self.rtt_ms.lock() = Some(rtt_ms);
+    }
+
+    pub fn set_queued(&self, queued: usize) {
+        self.queued.store(queued as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, bytes: usize, now: u64) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_sent_at.store(now, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, bytes: usize, now: u64) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_received_at.store(now, Ordering::Relaxed);
+    }
+
+    /// Resolves once an admin asked for the session to be dropped, even if
+    /// they asked before this was awaited.
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            slug: self.slug.clone(),
+            client_id: *self.client_id.lock(),
+            connected_at: self.connected_at,
+            queued: self.queued.load(Ordering::Relaxed),
+            rtt_ms: *self.rtt_ms.lock(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            last_sent_at: self.last_sent_at.load(Ordering::Relaxed),
+            last_received_at: self.last_received_at.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub fn register_connection(state: &AppState, slug: &str, now: u64) -> Arc<Connection> {
+    let conn = Arc::new(Connection {
+        id: Uuid::new_v4(),
+        slug: slug.to_string(),
+        connected_at: now,
+        client_id: Mutex::new(None),
+        rtt_ms: Mutex::new(None),
+        queued: AtomicU64::new(0),
+        bytes_sent: AtomicU64::new(0),
+        bytes_received: AtomicU64::new(0),
+        last_sent_at: AtomicU64::new(0),
+        last_received_at: AtomicU64::new(0),
+        kick: Notify::new(),
+    });
+    state.connections.write().insert(conn.id, conn.clone());
+    conn
+}
+
+pub fn unregister_connection(state: &AppState, id: &Uuid) {
+    state.connections.write().remove(id);
+}
+
+/// Sessions on `slug`, or on every document, with the most queued first.
+pub fn list_connections(state: &AppState, slug: Option<&str>) -> Vec<ConnectionInfo> {
+    let mut conns: Vec<ConnectionInfo> = state
+        .connections
+        .read()
+        .values()
+        .filter(|conn| slug.is_none_or(|slug| conn.slug == slug))
+        .map(|conn| conn.info())
+        .collect();
+    conns.sort_by_key(|conn| (Reverse(conn.queued), conn.connected_at));
+    conns
+}
+
+/// Asks the session `id` to drop its socket. Returns it as it was, or
+/// `None` when no such session runs.
+pub fn disconnect(state: &AppState, id: &Uuid) -> Option<ConnectionInfo> {
+    let conn = state.connections.read().get(id).cloned()?;
+    conn.kick.notify_one();
+    info!(
+        event = "ws_kicked",
+        slug = %conn.slug,
+        connection = %conn.id,
+        "admin disconnected a websocket session"
+    );
+    Some(conn.info())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lists_backed_up_sessions_first_and_kicks_them() {
+        let base = std::env::temp_dir().join(format!("connections-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let quiet = register_connection(&state, "spec", 1);
+        let stuck = register_connection(&state, "spec", 2);
+        register_connection(&state, "other", 3);
+        stuck.set_queued(40);
+        stuck.set_rtt(900);
+        stuck.set_client(Some(Uuid::new_v4()));
+        stuck.record_sent(100, 5);
+        stuck.record_sent(20, 6);
+        quiet.record_received(7, 8);
+
+        let listed = list_connections(&state, Some("spec"));
+        assert_eq!(
+            listed.iter().map(|c| c.id).collect::<Vec<_>>(),
+            vec![stuck.id, quiet.id]
+        );
+        assert_eq!(
+            (listed[0].queued, listed[0].rtt_ms, listed[0].bytes_sent),
+            (40, Some(900), 120)
+        );
+        assert_eq!(listed[0].last_sent_at, 6);
+        assert_eq!(
+            (listed[1].bytes_received, listed[1].last_received_at),
+            (7, 8)
+        );
+        assert_eq!(list_connections(&state, None).len(), 3);
+
+        // The kick is kept until the session waits for it.
+        assert!(disconnect(&state, &stuck.id).is_some());
+        stuck.kicked().await;
+        unregister_connection(&state, &stuck.id);
+        assert!(disconnect(&state, &stuck.id).is_none());
+        assert_eq!(list_connections(&state, Some("spec")).len(), 1);
+    }
+}
@@ -0,0 +1,150 @@
+//! Per-document content types: how a document is rendered and which checks
+//! its content has to pass.
+
+use crate::{
+    state::{AppState, Rejection, get_or_load_doc},
+    storage::persist_meta,
+    types::ContentType,
+};
+
+const MAX_LANG_LEN: usize = 32;
+
+/// Rejects code languages that could not be used as a highlighter name.
+pub fn check_content_type(content_type: &ContentType) -> Result<(), Rejection> {
+    if let ContentType::Code { lang } = content_type {
+        let valid = !lang.is_empty()
+            && lang.len() <= MAX_LANG_LEN
+            && lang.chars().all(|c| {
+                c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '+' | '#' | '-' | '_')
+            });
+        if !valid {
+            return Err(Rejection::new(
+                "invalid_content_type",
+                format!("invalid code language '{}'", lang),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Describes why `content` is not valid for `content_type`. Empty documents
+/// always pass so a fresh document can be typed before anything is written.
+pub fn content_problem(content_type: &ContentType, content: &str) -> Option<String> {
+    match content_type {
+        ContentType::Json if !content.trim().is_empty() => {
+            serde_json::from_str::<serde_json::Value>(content)
+                .err()
+                .map(|err| format!("invalid JSON: {}", err))
+        }
+        _ => None,
+    }
+}
+
+pub struct Rendered {
+    pub mime: &'static str,
+    pub body: String,
+}
+
+/// Renders `content` for display. Markdown is returned as-is for the web
+/// renderer; everything else becomes an HTML code block, with JSON
+/// pretty-printed when it parses.
+pub fn render(content_type: &ContentType, content: &str) -> Rendered {
+    let (lang, text) = match content_type {
+        ContentType::Markdown => {
+            return Rendered {
+                mime: "text/markdown; charset=utf-8",
+                body: content.to_string(),
+            };
+        }
+        ContentType::Plaintext => (None, content.to_string()),
+        ContentType::Json => {
+            let pretty = serde_json::from_str::<serde_json::Value>(content)
+                .ok()
+                .and_then(|value| serde_json::to_string_pretty(&value).ok());
+            (Some("json"), pretty.unwrap_or_else(|| content.to_string()))
+        }
+        ContentType::Code { lang } => (Some(lang.as_str()), content.to_string()),
+    };
+    let body = match lang {
+        Some(lang) => format!(
+            "<pre><code class=\"language-{}\">{}</code></pre>",
+            lang,
+            escape_html(&text)
+        ),
+        None => format!("<pre>{}</pre>", escape_html(&text)),
+    };
+    Rendered {
+        mime: "text/html; charset=utf-8",
+        body,
+    }
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Changes the content type of `slug` and persists it with the metadata.
+pub async fn set_content_type(
+    state: &AppState,
+    slug: &str,
+    content_type: ContentType,
+) -> anyhow::Result<()> {
+    check_content_type(&content_type)?;
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let meta = {
+        let mut d = doc_arc.write();
+        d.meta.content_type = Some(content_type);
+        d.meta.clone()
+    };
+    persist_meta(state, slug, &meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(lang: &str) -> ContentType {
+        ContentType::Code { lang: lang.into() }
+    }
+
+    #[test]
+    fn code_languages_are_restricted() {
+        assert!(check_content_type(&code("rust")).is_ok());
+        assert!(check_content_type(&code("c++")).is_ok());
+        assert!(check_content_type(&code("")).is_err());
+        assert!(check_content_type(&code("x\" onclick=\"")).is_err());
+        assert!(check_content_type(&ContentType::Json).is_ok());
+    }
+
+    #[test]
+    fn json_documents_are_checked_and_pretty_printed() {
+        assert!(content_problem(&ContentType::Json, "").is_none());
+        assert!(content_problem(&ContentType::Json, "{\"a\": [1]}").is_none());
+        assert!(content_problem(&ContentType::Json, "{\"a\": ").is_some());
+        assert!(content_problem(&ContentType::Markdown, "{\"a\": ").is_none());
+
+        let rendered = render(&ContentType::Json, "{\"a\":\"<b>\"}");
+        assert_eq!(rendered.mime, "text/html; charset=utf-8");
+        assert_eq!(
+            rendered.body,
+            "<pre><code class=\"language-json\">{\n  &quot;a&quot;: &quot;&lt;b&gt;&quot;\n}</code></pre>"
+        );
+        let rendered = render(&ContentType::Markdown, "# <title>");
+        assert_eq!(rendered.body, "# <title>");
+        assert_eq!(
+            render(&code("rust"), "a && b").body,
+            "<pre><code class=\"language-rust\">a &amp;&amp; b</code></pre>"
+        );
+    }
+}
@@ -0,0 +1,596 @@
+//! Documents as a WebDAV share under `/dav/`, so the instance can be mounted
+//! as a network drive. `team/plan` is the file `/dav/team/plan.md` and the
+//! folders are the prefixes of slugs. `GET` reads a document, `PUT` applies
+//! the difference between the document and the new file as one edit, the
+//! way [`crate::ingest`] does, creating the document when needed under the
+//! same checks as `POST /api/docs` (the owner token comes back in
+//! `X-Coedit-Owner-Token`), and `PROPFIND` lists a folder. A protected
+//! document opens with its password as the Basic auth password, whatever the
+//! user name; the admin token works as a bearer token.
+
+use std::collections::BTreeSet;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{HeaderMap, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    auth::{basic_password, is_admin, is_authorized},
+    diff::diff_ops,
+    document::{Doc, content_hash},
+    handlers::http::{admit_creation, create_document, rejection_status, snapshot_etag},
+    state::{AppState, Rejection, apply_edit, doc_exists, get_existing_doc, get_or_load_doc},
+    storage::{list_all_slugs, load_meta, load_password_hash, snapshot_path},
+    types::Edit,
+    workspace::workspace_settings_for,
+};
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, PROPFIND";
+/// Carries the owner token of a document a `PUT` created.
+pub const OWNER_TOKEN_HEADER: &str = "x-coedit-owner-token";
+const MARKDOWN: &str = "text/markdown; charset=utf-8";
+
+/// What a path under `/dav/` names.
+#[derive(Debug, PartialEq, Eq)]
+enum Target {
+    Doc(String),
+    /// Slug prefix without the trailing `/`; empty for the root.
+    Folder(String),
+}
+
+fn target(path: &str) -> Target {
+    let path = path.trim_matches('/');
+    match path.strip_suffix(".md") {
+        Some(slug) if !slug.is_empty() && !slug.ends_with('/') => Target::Doc(slug.to_string()),
+        _ => Target::Folder(path.to_string()),
+    }
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"coedit\"")],
+        "unauthorized",
+    )
+        .into_response()
+}
+
+/// `/dav` and everything under it.
+pub async fn dav(
+    State(state): State<AppState>,
+    method: Method,
+    path: Option<Path<String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let target = target(&path.map(|Path(p)| p).unwrap_or_default());
+    match (method.as_str(), target) {
+        ("OPTIONS", _) => (
+            StatusCode::OK,
+            [
+                (header::ALLOW, ALLOW),
+                (header::HeaderName::from_static("dav"), "1"),
+            ],
+        )
+            .into_response(),
+        ("GET" | "HEAD", Target::Doc(slug)) => {
+            get(&state, &slug, &headers, method == Method::HEAD).await
+        }
+        ("PUT", Target::Doc(slug)) => put(&state, &slug, &headers, body).await,
+        ("PROPFIND", target) => propfind(&state, target, &headers).await,
+        _ => (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, ALLOW)]).into_response(),
+    }
+}
+
+/// The document for someone who may open it.
+async fn open(
+    state: &AppState,
+    slug: &str,
+    headers: &HeaderMap,
+) -> Result<Option<(String, u64)>, Response> {
+    let doc = match get_existing_doc(state, slug).await {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return Ok(None),
+        Err(_) => return Err(StatusCode::NOT_FOUND.into_response()),
+    };
+    let d = doc.read();
+    if !is_admin(headers, state.admin_token.as_deref())
+        && !is_authorized(&d, basic_password(headers).as_deref())
+    {
+        return Err(unauthorized());
+    }
+    if d.meta.archived_at.is_some() {
+        return Ok(None);
+    }
+    Ok(Some((d.content.clone(), d.rev)))
+}
+
+async fn get(state: &AppState, slug: &str, headers: &HeaderMap, head: bool) -> Response {
+    let (content, rev) = match open(state, slug, headers).await {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(resp) => return resp,
+    };
+    let headers = [
+        (header::CONTENT_TYPE, MARKDOWN.to_string()),
+        (header::ETAG, snapshot_etag(rev, content_hash(&content))),
+        (header::CONTENT_LENGTH, content.len().to_string()),
+        (header::CACHE_CONTROL, "private, no-cache".to_string()),
+    ];
+    let body = if head {
+        Body::empty()
+    } else {
+        Body::from(content)
+    };
+    (headers, body).into_response()
+}
+
+async fn put(state: &AppState, slug: &str, headers: &HeaderMap, body: Bytes) -> Response {
+    let Ok(new) = String::from_utf8(body.to_vec()) else {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "not UTF-8 text").into_response();
+    };
+    let mut created = match doc_exists(state, slug) {
+        Ok(exists) => !exists,
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let mut owner_token = None;
+    if created {
+        // Created empty, so the content lands as an edit like any other.
+        if let Err((status, message)) = admit_creation(state, slug, headers, None, new.len() as u64)
+        {
+            return refused(status, message);
+        }
+        match create_document(state, slug, String::new(), None, None, None).await {
+            Ok(token) => owner_token = token,
+            // Another request created it in the meantime.
+            Err(err) if err.downcast_ref::<Rejection>().map(|r| r.code) == Some("exists") => {
+                created = false;
+            }
+            Err(err) => return rejected(slug, err),
+        }
+    }
+    let doc = match get_or_load_doc(state, slug).await {
+        Ok(doc) => doc,
+        Err(err) => return rejected(slug, err),
+    };
+    let admin = is_admin(headers, state.admin_token.as_deref());
+    let (base_rev, ops) = {
+        let d = doc.read();
+        if !admin && !is_authorized(&d, basic_password(headers).as_deref()) {
+            return unauthorized();
+        }
+        (d.rev, diff_ops(&d.content, &new))
+    };
+    if !ops.is_empty() {
+        let edit = Edit {
+            base_rev,
+            ops,
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: Some(Uuid::new_v4()),
+            user_id: None,
+        };
+        if let Err(err) = apply_edit(state, slug, edit).await {
+            return rejected(slug, err);
+        }
+    }
+    if !created {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    match owner_token {
+        Some(token) => (StatusCode::CREATED, [(OWNER_TOKEN_HEADER, token)]).into_response(),
+        None => StatusCode::CREATED.into_response(),
+    }
+}
+
+/// A refusal, asking for credentials when they are what is missing.
+fn refused(status: StatusCode, message: &'static str) -> Response {
+    match status {
+        StatusCode::UNAUTHORIZED => unauthorized(),
+        status => (status, message).into_response(),
+    }
+}
+
+fn rejected(slug: &str, err: anyhow::Error) -> Response {
+    match rejection_status(&err) {
+        Some((status, message)) => refused(status, message),
+        None => {
+            error!(%slug, "WebDAV write failed: {:#}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Percent-encodes everything but unreserved characters and `/`.
+fn href(path: &str) -> String {
+    let mut out = String::from("/dav/");
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn folder_entry(prefix: &str) -> String {
+    let name = prefix.rsplit('/').next().unwrap_or_default();
+    let path = if prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", prefix)
+    };
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}</D:displayname><D:resourcetype><D:collection/></D:resourcetype>\
+         </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(&href(&path)),
+        escape(name)
+    )
+}
+
+/// What a listing says about a document; the length and ETag are left out
+/// when only its stored metadata was read.
+struct Listed {
+    len: Option<u64>,
+    etag: Option<String>,
+}
+
+impl Listed {
+    fn of(content: &str, rev: u64) -> Self {
+        Self {
+            len: Some(content.len() as u64),
+            etag: Some(snapshot_etag(rev, content_hash(content))),
+        }
+    }
+}
+
+fn doc_entry(slug: &str, listed: &Listed) -> String {
+    let name = slug.rsplit('/').next().unwrap_or_default();
+    let len = listed.len.map_or(String::new(), |len| {
+        format!("<D:getcontentlength>{}</D:getcontentlength>", len)
+    });
+    let etag = listed.etag.as_deref().map_or(String::new(), |etag| {
+        format!("<D:getetag>{}</D:getetag>", escape(etag))
+    });
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}.md</D:displayname><D:resourcetype/>{}\
+         <D:getcontenttype>text/markdown</D:getcontenttype>{}\
+         </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(&href(&format!("{}.md", slug))),
+        escape(name),
+        len,
+        etag
+    )
+}
+
+/// A document of a folder listing, for someone who may open it. One that is
+/// not in memory is described from its stored files instead of being loaded.
+fn list_doc(state: &AppState, slug: &str, headers: &HeaderMap) -> Option<Listed> {
+    let admin = is_admin(headers, state.admin_token.as_deref());
+    let password = basic_password(headers);
+    let loaded = state.docs.read().get(slug).cloned();
+    if let Some(doc) = loaded {
+        let d = doc.read();
+        if (!admin && !is_authorized(&d, password.as_deref())) || d.meta.archived_at.is_some() {
+            return None;
+        }
+        return Some(Listed::of(&d.content, d.rev));
+    }
+    let meta = load_meta(state, slug).ok()?.unwrap_or_default();
+    let stored = Doc {
+        password_hash: load_password_hash(state, slug).ok()?,
+        inherited_password_hash: workspace_settings_for(state, slug)
+            .ok()?
+            .and_then(|ws| ws.default_password_hash),
+        ..Default::default()
+    };
+    if (!admin && !is_authorized(&stored, password.as_deref())) || meta.archived_at.is_some() {
+        return None;
+    }
+    // A compressed snapshot's size says nothing about the text.
+    let len = if state.compress_storage {
+        None
+    } else {
+        snapshot_path(state, slug)
+            .ok()
+            .and_then(|path| std::fs::metadata(path).ok())
+            .map(|m| m.len())
+    };
+    Some(Listed { len, etag: None })
+}
+
+async fn propfind(state: &AppState, target: Target, headers: &HeaderMap) -> Response {
+    let mut entries = Vec::new();
+    match target {
+        Target::Doc(slug) => match open(state, &slug, headers).await {
+            Ok(Some((content, rev))) => entries.push(doc_entry(&slug, &Listed::of(&content, rev))),
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(resp) => return resp,
+        },
+        Target::Folder(prefix) => {
+            let slugs = match list_all_slugs(state) {
+                Ok(slugs) => slugs,
+                Err(err) => {
+                    error!("listing documents for WebDAV failed: {:#}", err);
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            };
+            let under = |slug: &str| -> Option<String> {
+                match prefix.as_str() {
+                    "" => Some(slug.to_string()),
+                    prefix => slug
+                        .strip_prefix(prefix)
+                        .and_then(|rest| rest.strip_prefix('/'))
+                        .map(str::to_string),
+                }
+            };
+            let children: Vec<String> = slugs.iter().filter_map(|slug| under(slug)).collect();
+            if !prefix.is_empty() && children.is_empty() {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            entries.push(folder_entry(&prefix));
+            let depth = headers
+                .get("depth")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("1");
+            if depth.trim() != "0" {
+                let join = |name: &str| match prefix.as_str() {
+                    "" => name.to_string(),
+                    prefix => format!("{}/{}", prefix, name),
+                };
+                let mut folders = BTreeSet::new();
+                for child in &children {
+                    match child.split_once('/') {
+                        Some((folder, _)) => {
+                            folders.insert(join(folder));
+                        }
+                        None => {
+                            let slug = join(child);
+                            if let Some(listed) = list_doc(state, &slug, headers) {
+                                entries.push(doc_entry(&slug, &listed));
+                            }
+                        }
+                    }
+                }
+                entries.extend(folders.iter().map(|folder| folder_entry(folder)));
+            }
+        }
+    }
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+        entries.concat()
+    );
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        xml,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build_router,
+        storage::{flush_snapshot_force, hash_password, persist_password_hash},
+        workspace::{WorkspaceSettings, save_workspace},
+    };
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    fn request(method: &str, uri: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("depth", "1")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn text(resp: Response) -> String {
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn paths_name_documents_and_folders() {
+        assert_eq!(target("team/plan.md"), Target::Doc("team/plan".into()));
+        assert_eq!(target("team/"), Target::Folder("team".into()));
+        assert_eq!(target(""), Target::Folder("".into()));
+        assert_eq!(target(".md"), Target::Folder(".md".into()));
+    }
+
+    #[tokio::test]
+    async fn files_are_read_written_and_listed() {
+        let base = std::env::temp_dir().join(format!("dav-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let app = build_router(&state);
+
+        let put = app
+            .clone()
+            .oneshot(request("PUT", "/dav/team/plan.md", "one\ntwo\n"))
+            .await
+            .unwrap();
+        assert_eq!(put.status(), StatusCode::CREATED);
+        let put = app
+            .clone()
+            .oneshot(request("PUT", "/dav/team/plan.md", "one\n2\n"))
+            .await
+            .unwrap();
+        assert_eq!(put.status(), StatusCode::NO_CONTENT);
+        let doc = get_or_load_doc(&state, "team/plan").await.unwrap();
+        // Only the changed line moved.
+        assert_eq!(
+            (doc.read().content.as_str(), doc.read().rev),
+            ("one\n2\n", 2)
+        );
+
+        let got = app
+            .clone()
+            .oneshot(request("GET", "/dav/team/plan.md", ""))
+            .await
+            .unwrap();
+        assert_eq!(got.headers()[header::CONTENT_TYPE], MARKDOWN);
+        assert_eq!(text(got).await, "one\n2\n");
+
+        // A protected document is neither listed nor readable without
+        // its password.
+        app.clone()
+            .oneshot(request("PUT", "/dav/team/secret.md", "psst"))
+            .await
+            .unwrap();
+        let secret = get_or_load_doc(&state, "team/secret").await.unwrap();
+        let hash = hash_password("pw");
+        persist_password_hash(&state, "team/secret", Some(&hash)).unwrap();
+        secret.write().password_hash = Some(hash);
+        let listed = app
+            .clone()
+            .oneshot(request("PROPFIND", "/dav/", ""))
+            .await
+            .unwrap();
+        assert_eq!(listed.status(), StatusCode::MULTI_STATUS);
+        let listed = text(listed).await;
+        assert!(listed.contains("<D:href>/dav/team/</D:href>"));
+        let listed = app
+            .clone()
+            .oneshot(request("PROPFIND", "/dav/team/", ""))
+            .await
+            .unwrap();
+        let listed = text(listed).await;
+        assert!(listed.contains("<D:href>/dav/team/plan.md</D:href>"));
+        assert!(!listed.contains("secret"));
+        let denied = app
+            .clone()
+            .oneshot(request("GET", "/dav/team/secret.md", ""))
+            .await
+            .unwrap();
+        assert_eq!(denied.status(), StatusCode::UNAUTHORIZED);
+        let mut with_password = request("GET", "/dav/team/secret.md", "");
+        with_password
+            .headers_mut()
+            .insert(header::AUTHORIZATION, "Basic YW55b25lOnB3".parse().unwrap());
+        assert_eq!(
+            text(app.oneshot(with_password).await.unwrap()).await,
+            "psst"
+        );
+    }
+
+    #[tokio::test]
+    async fn new_files_pass_the_document_creation_checks() {
+        let base = std::env::temp_dir().join(format!("dav-create-{}", Uuid::new_v4()));
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        state.invite_only = true;
+        state.admin_token = Some("s3cret".into());
+        let locked = WorkspaceSettings {
+            require_password: true,
+            ..Default::default()
+        };
+        save_workspace(&state, "locked", &locked).unwrap();
+        let small = WorkspaceSettings {
+            quota_bytes: Some(2),
+            ..Default::default()
+        };
+        save_workspace(&state, "small", &small).unwrap();
+        let app = build_router(&state);
+        let put = |uri: &str, admin: bool| {
+            let mut req = request("PUT", uri, "some text");
+            if admin {
+                req.headers_mut()
+                    .insert(header::AUTHORIZATION, "Bearer s3cret".parse().unwrap());
+            }
+            app.clone().oneshot(req)
+        };
+
+        let anonymous = put("/dav/notes.md", false).await.unwrap();
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+        assert!(!doc_exists(&state, "notes").unwrap());
+        let created = put("/dav/notes.md", true).await.unwrap();
+        assert_eq!(created.status(), StatusCode::CREATED);
+        assert!(created.headers().contains_key(OWNER_TOKEN_HEADER));
+        assert_eq!(
+            put("/dav/locked/plan.md", true).await.unwrap().status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            put("/dav/small/plan.md", true).await.unwrap().status(),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+    }
+
+    #[tokio::test]
+    async fn folder_listings_leave_documents_unloaded() {
+        let base = std::env::temp_dir().join(format!("dav-list-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let app = build_router(&state);
+        for (uri, body) in [
+            ("/dav/team/plan.md", "plan"),
+            ("/dav/team/secret.md", "psst"),
+        ] {
+            app.clone()
+                .oneshot(request("PUT", uri, body))
+                .await
+                .unwrap();
+        }
+        persist_password_hash(&state, "team/secret", Some(&hash_password("pw"))).unwrap();
+        for slug in ["team/plan", "team/secret"] {
+            flush_snapshot_force(&state, slug).await.unwrap();
+        }
+        state.docs.write().clear();
+
+        let listed = app
+            .oneshot(request("PROPFIND", "/dav/team/", ""))
+            .await
+            .unwrap();
+        let listed = text(listed).await;
+        assert!(listed.contains(
+            "<D:href>/dav/team/plan.md</D:href><D:propstat><D:prop>\
+             <D:displayname>plan.md</D:displayname><D:resourcetype/>\
+             <D:getcontentlength>4</D:getcontentlength>"
+        ));
+        assert!(!listed.contains("secret"));
+        assert!(state.docs.read().is_empty());
+    }
+}
@@ -0,0 +1,227 @@
+//! Degraded mode for when the WAL cannot be written, e.g. because the disk
+//! filled up or the volume was remounted read-only. Edits keep being applied
+//! and their log entries wait in memory, in order, up to a cap; past it edits
+//! are refused. A background loop retries the writes and leaves degraded
+//! mode once they all went through.
+
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::{sync::watch, time::sleep};
+use tracing::{error, info};
+
+use crate::{
+    state::{AppState, Rejection, broadcast},
+    storage::wal_append_event,
+    types::{DocEvent, ServerMsg},
+};
+
+/// WAL entries held in memory before edits are refused.
+pub const DEFAULT_WAL_BUFFER_CAP: usize = 10_000;
+/// How often held entries are retried.
+pub const WAL_RETRY_MS: u64 = 2_000;
+
+struct PendingWrite {
+    slug: String,
+    event: DocEvent,
+    ts: u64,
+}
+
+#[derive(Default)]
+pub struct WalHealth {
+    degraded: AtomicBool,
+    pending: Mutex<VecDeque<PendingWrite>>,
+}
+
+impl WalHealth {
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Acquire)
+    }
+
+    /// Log entries waiting to be written.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().len()
+    }
+}
+
+/// Refuses an edit before it is applied when the buffer is full.
+pub fn check_wal_capacity(state: &AppState) -> Result<(), Rejection> {
+    let health = &state.wal_health;
+    if health.is_degraded() && health.pending() >= state.wal_buffer_cap {
+        return Err(Rejection::new(
+            "degraded",
+            "the server cannot save edits right now; try again later",
+        ));
+    }
+    Ok(())
+}
+
+/// Appends `event` to the WAL of `slug`, or holds it in memory while the WAL
+/// cannot be written. Only fails for events that could not be serialized.
+pub fn append_or_hold(
+    state: &AppState,
+    slug: &str,
+    event: &DocEvent,
+    ts: u64,
+) -> anyhow::Result<()> {
+    let health = &state.wal_health;
+    if !health.is_degraded() {
+        match wal_append_event(state, slug, event, ts) {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                error!(%slug, "WAL write failed, holding edits in memory: {:#}", err);
+                if !health.degraded.swap(true, Ordering::AcqRel) {
+                    announce(state, true);
+                }
+            }
+        }
+    }
+    health.pending.lock().push_back(PendingWrite {
+        slug: slug.to_string(),
+        event: event.clone(),
+        ts,
+    });
+    Ok(())
+}
+
+/// Writes held entries in order until one fails. Leaves degraded mode and
+/// returns `true` once none are left.
+pub fn retry_pending(state: &AppState) -> bool {
+    let health = &state.wal_health;
+    let mut pending = health.pending.lock();
+    while let Some(write) = pending.front() {
+        if let Err(err) = wal_append_event(state, &write.slug, &write.event, write.ts) {
+            error!(pending = pending.len(), "WAL still not writable: {:#}", err);
+            return false;
+        }
+        pending.pop_front();
+    }
+    if health.degraded.swap(false, Ordering::AcqRel) {
+        info!("WAL writable again, leaving degraded mode");
+        drop(pending);
+        announce(state, false);
+    }
+    true
+}
+
+pub async fn run_wal_recovery(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = sleep(Duration::from_millis(WAL_RETRY_MS)) => {
+                if state.wal_health.is_degraded() {
+                    retry_pending(&state);
+                }
+            }
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sent to sessions that join while the server is degraded.
+pub fn degraded_notice(state: &AppState, slug: &str) -> Option<ServerMsg> {
+    state.wal_health.is_degraded().then(|| notice(slug, true))
+}
+
+fn notice(slug: &str, degraded: bool) -> ServerMsg {
+    ServerMsg::Degraded {
+        slug: slug.to_string(),
+        degraded,
+    }
+}
+
+fn announce(state: &AppState, degraded: bool) {
+    let slugs: Vec<String> = state.subs.read().keys().cloned().collect();
+    for slug in slugs {
+        broadcast(state, &slug, notice(&slug, degraded));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state::{apply_edit, get_or_load_doc},
+        storage::wal_path,
+        types::{Edit, OpKind},
+    };
+    use std::fs;
+    use uuid::Uuid;
+
+    fn insert(base_rev: u64, text: &str) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn holds_edits_while_the_wal_is_unwritable_and_recovers() {
+        let base = std::env::temp_dir().join(format!("degraded-{}", Uuid::new_v4()));
+        fs::create_dir_all(base.join("snapshots")).unwrap();
+        fs::create_dir_all(base.join("wal")).unwrap();
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000_000,
+            true,
+            Vec::new(),
+        );
+        state.wal_buffer_cap = 2;
+        let slug = "doc";
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        state.subs.write().insert(slug.into(), vec![tx.into()]);
+        // A file where the WAL directory should be makes every append fail.
+        fs::remove_dir(base.join("wal")).unwrap();
+        fs::write(base.join("wal"), "").unwrap();
+
+        apply_edit(&state, slug, insert(0, "a")).await.unwrap();
+        apply_edit(&state, slug, insert(1, "b")).await.unwrap();
+        assert!(state.wal_health.is_degraded());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMsg::Degraded { degraded: true, .. })
+        ));
+        let err = apply_edit(&state, slug, insert(2, "c")).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, "degraded");
+        assert!(!retry_pending(&state));
+
+        fs::remove_file(base.join("wal")).unwrap();
+        fs::create_dir_all(base.join("wal")).unwrap();
+        assert!(retry_pending(&state));
+        assert!(!state.wal_health.is_degraded());
+        assert!(
+            std::iter::from_fn(|| rx.try_recv().ok()).any(|msg| matches!(
+                msg,
+                ServerMsg::Degraded {
+                    degraded: false,
+                    ..
+                }
+            ))
+        );
+        let wal = fs::read_to_string(wal_path(&state, slug).unwrap()).unwrap();
+        assert_eq!(wal.lines().count(), 2);
+
+        apply_edit(&state, slug, insert(2, "c")).await.unwrap();
+        assert_eq!(doc.read().content, "cba");
+    }
+}
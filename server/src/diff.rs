@@ -0,0 +1,207 @@
+//! Differences between two versions of a text: the runs of a sequence that
+//! stayed and the ones that changed, and from those the ops that turn one
+//! version of a document into the other.
+
+use std::ops::Range;
+
+use crate::types::OpKind;
+
+/// Item pairs a diff compares at most; changes spanning more are reported
+/// as a single changed run.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// A stretch of `a` and the stretch of `b` it corresponds to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Run {
+    Same(Range<usize>, Range<usize>),
+    /// Either side may be empty.
+    Changed(Range<usize>, Range<usize>),
+}
+
+/// `a` and `b` split into alternating runs that are the same in both and
+/// runs that changed, keeping as many items the same as possible.
+pub fn diff_runs<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Run> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (n, m) = (a.len() - prefix - suffix, b.len() - prefix - suffix);
+    let (a_mid, b_mid) = (&a[prefix..prefix + n], &b[prefix..prefix + m]);
+
+    // Pairs of equal items, as offsets into the middle.
+    let mut pairs = Vec::new();
+    if n.saturating_mul(m) <= MAX_DIFF_CELLS {
+        // Longest common subsequence, from the back.
+        let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i * (m + 1) + j] = if a_mid[i] == b_mid[j] {
+                    lcs[(i + 1) * (m + 1) + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n && j < m {
+            if a_mid[i] == b_mid[j] {
+                pairs.push((i, j));
+                (i, j) = (i + 1, j + 1);
+            } else if lcs[i * (m + 1) + j + 1] >= lcs[(i + 1) * (m + 1) + j] {
+                j += 1;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    let mut runs: Vec<Run> = Vec::new();
+    let mut push = |run: Run| {
+        if let (Some(Run::Same(x, y)), Run::Same(a, b)) = (runs.last_mut(), &run)
+            && x.end == a.start
+        {
+            (x.end, y.end) = (a.end, b.end);
+        } else if !matches!(&run, Run::Changed(a, b) if a.is_empty() && b.is_empty()) {
+            runs.push(run);
+        }
+    };
+    push(Run::Same(0..prefix, 0..prefix));
+    let (mut i, mut j) = (prefix, prefix);
+    for (x, y) in pairs {
+        let (x, y) = (prefix + x, prefix + y);
+        push(Run::Changed(i..x, j..y));
+        push(Run::Same(x..x + 1, y..y + 1));
+        (i, j) = (x + 1, y + 1);
+    }
+    push(Run::Changed(i..prefix + n, j..prefix + m));
+    push(Run::Same(prefix + n..a.len(), prefix + m..b.len()));
+    runs.retain(|run| !matches!(run, Run::Same(a, _) if a.is_empty()));
+    runs
+}
+
+/// Ops that turn `old` into `new`, applied in order. Unchanged lines are
+/// kept, and each changed block only loses and gains what differs in it.
+pub fn diff_ops(old: &str, new: &str) -> Vec<OpKind> {
+    let a: Vec<&str> = old.split_inclusive('\n').collect();
+    let b: Vec<&str> = new.split_inclusive('\n').collect();
+    let mut ops = Vec::new();
+    let mut pos = 0;
+    for run in diff_runs(&a, &b) {
+        let (from, to) = match run {
+            Run::Same(x, _) => {
+                pos += a[x].iter().map(|l| l.chars().count()).sum::<usize>();
+                continue;
+            }
+            Run::Changed(x, y) => (a[x].concat(), b[y].concat()),
+        };
+        let head = from
+            .chars()
+            .zip(to.chars())
+            .take_while(|(x, y)| x == y)
+            .count();
+        let tail = from
+            .chars()
+            .rev()
+            .zip(to.chars().rev())
+            .take(from.chars().count().min(to.chars().count()) - head)
+            .take_while(|(x, y)| x == y)
+            .count();
+        let deleted = from.chars().count() - head - tail;
+        let inserted: String = to
+            .chars()
+            .skip(head)
+            .take(to.chars().count() - head - tail)
+            .collect();
+        pos += head;
+        if deleted > 0 {
+            ops.push(OpKind::Delete { pos, len: deleted });
+        }
+        let n = inserted.chars().count();
+        if n > 0 {
+            ops.push(OpKind::Insert {
+                pos,
+                text: inserted,
+            });
+        }
+        pos += n + tail;
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Doc, apply_ops};
+    use proptest::prelude::*;
+
+    fn applied(old: &str, ops: &[OpKind]) -> String {
+        let mut doc = Doc {
+            content: old.to_string(),
+            ..Default::default()
+        };
+        apply_ops(&mut doc, ops);
+        doc.content
+    }
+
+    #[test]
+    fn only_changed_parts_are_touched() {
+        let old = "# Plan\nfirst\nsecond\nthird\n";
+        let new = "# Plan\nfirst!\nsecond\nthird\nfourth\n";
+        assert_eq!(
+            diff_ops(old, new),
+            vec![
+                OpKind::Insert {
+                    pos: 12,
+                    text: "!".into()
+                },
+                OpKind::Insert {
+                    pos: 27,
+                    text: "fourth\n".into()
+                },
+            ]
+        );
+        assert!(diff_ops(old, old).is_empty());
+        assert_eq!(
+            diff_runs(&[1, 2, 3, 4], &[1, 5, 3]),
+            vec![
+                Run::Same(0..1, 0..1),
+                Run::Changed(1..2, 1..2),
+                Run::Same(2..3, 2..3),
+                Run::Changed(3..4, 3..3),
+            ]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn diffs_turn_old_into_new(
+            old in "([ab\u{e9}]{0,3}\n?){0,8}",
+            new in "([ab\u{e9}]{0,3}\n?){0,8}",
+        ) {
+            prop_assert_eq!(applied(&old, &diff_ops(&old, &new)), new);
+        }
+
+        #[test]
+        fn runs_cover_both_sides(
+            a in proptest::collection::vec(0u8..3, 0..10),
+            b in proptest::collection::vec(0u8..3, 0..10),
+        ) {
+            let (mut i, mut j) = (0, 0);
+            for run in diff_runs(&a, &b) {
+                let (x, y) = match run {
+                    Run::Same(x, y) => {
+                        prop_assert_eq!(&a[x.clone()], &b[y.clone()]);
+                        (x, y)
+                    }
+                    Run::Changed(x, y) => (x, y),
+                };
+                prop_assert_eq!((x.start, y.start), (i, j));
+                (i, j) = (x.end, y.end);
+            }
+            prop_assert_eq!((i, j), (a.len(), b.len()));
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::watch,
+    time::sleep,
+};
+use tracing::{error, info};
+
+use crate::{
+    state::{AppState, now_millis},
+    types::{Edit, OpKind},
+    webhook::post_json,
+};
+
+#[derive(Debug, Clone)]
+pub enum DigestTarget {
+    Webhook(String),
+    /// Plain SMTP relay (no TLS/AUTH), e.g. a local MTA on `localhost:25`.
+    Smtp {
+        addr: String,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct DocDigest {
+    pub slug: String,
+    pub edits: u64,
+    pub inserted_chars: u64,
+    pub deleted_chars: u64,
+    pub contributors: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestSummary {
+    pub window_start: u64,
+    pub window_end: u64,
+    pub docs: Vec<DocDigest>,
+}
+
+pub fn contributor_name(state: &AppState, slug: &str, edit: &Edit) -> Option<String> {
+    let client_id = edit.client_id?;
+    let label = state
+        .presence
+        .read()
+        .get(slug)
+        .and_then(|p| p.clients.get(&client_id))
+        .and_then(|p| p.label.clone());
+    Some(label.unwrap_or_else(|| client_id.to_string()))
+}
+
+/// Adds an applied edit to the pending digest window. No-op unless a digest
+/// target is configured.
+pub fn record_change(state: &AppState, slug: &str, edit: &Edit) {
+    if state.digest_target.is_none() {
+        return;
+    }
+    let contributor = contributor_name(state, slug, edit);
+    state
+        .digest_pending
+        .write()
+        .entry(slug.to_string())
+        .or_insert_with(|| DocDigest {
+            slug: slug.to_string(),
+            ..Default::default()
+        })
+        .add(edit, contributor);
+}
+
+impl DocDigest {
+    /// Counts `edit`, made by `contributor` when known.
+    pub fn add(&mut self, edit: &Edit, contributor: Option<String>) {
+        self.edits += 1;
+        for op in &edit.ops {
+            match op {
+                OpKind::Insert { text, .. } => self.inserted_chars += text.chars().count() as u64,
+                OpKind::Delete { len, .. } => self.deleted_chars += *len as u64,
+            }
+        }
+        if let Some(name) = contributor {
+            self.contributors.insert(name);
+        }
+    }
+}
+
+/// Drains the pending window, or returns `None` when nothing changed.
+pub fn take_summary(state: &AppState, window_start: u64) -> Option<DigestSummary> {
+    let mut docs: Vec<DocDigest> = std::mem::take(&mut *state.digest_pending.write())
+        .into_values()
+        .collect();
+    if docs.is_empty() {
+        return None;
+    }
+    docs.sort_by(|a, b| a.slug.cmp(&b.slug));
+    Some(DigestSummary {
+        window_start,
+        window_end: now_millis(),
+        docs,
+    })
+}
+
+pub fn render_text(summary: &DigestSummary) -> String {
+    let mut out = format!("{} document(s) changed:\n\n", summary.docs.len());
+    for doc in &summary.docs {
+        out.push_str(&format!(
+            "- {}: {} edit(s), +{} / -{} chars",
+            doc.slug, doc.edits, doc.inserted_chars, doc.deleted_chars
+        ));
+        if !doc.contributors.is_empty() {
+            let names: Vec<&str> = doc.contributors.iter().map(String::as_str).collect();
+            out.push_str(&format!(" by {}", names.join(", ")));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+async fn smtp_expect<R>(reader: &mut R, code: &str) -> anyhow::Result<()>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            anyhow::bail!("smtp connection closed while waiting for {}", code);
+        }
+        if !line.starts_with(code) {
+            anyhow::bail!("unexpected smtp reply: {}", line.trim_end());
+        }
+        // Multi-line replies use "250-" for every line but the last.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+async fn send_smtp(addr: &str, from: &str, to: &[String], body: &str) -> anyhow::Result<()> {
+    let stream = TcpStream::connect(addr).await?;
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    smtp_expect(&mut reader, "220").await?;
+    let mut command = async |line: String, code: &str| -> anyhow::Result<()> {
+        write.write_all(line.as_bytes()).await?;
+        smtp_expect(&mut reader, code).await
+    };
+    command("EHLO coedit\r\n".into(), "250").await?;
+    command(format!("MAIL FROM:<{}>\r\n", from), "250").await?;
+    for rcpt in to {
+        command(format!("RCPT TO:<{}>\r\n", rcpt), "250").await?;
+    }
+    command("DATA\r\n".into(), "354").await?;
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: coedit digest\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        from,
+        to.join(", ")
+    );
+    for line in body.lines() {
+        // Dot-stuffing so a line starting with "." doesn't end the message.
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    command(message, "250").await?;
+    command("QUIT\r\n".into(), "221").await?;
+    Ok(())
+}
+
+pub async fn deliver(target: &DigestTarget, summary: &DigestSummary) -> anyhow::Result<()> {
+    match target {
+        DigestTarget::Webhook(url) => {
+            post_json(url, summary).await?;
+        }
+        DigestTarget::Smtp { addr, from, to } => {
+            send_smtp(addr, from, to, &render_text(summary)).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn run_digest_loop(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    let Some(target) = state.digest_target.clone() else {
+        return;
+    };
+    let interval = Duration::from_millis(state.digest_interval_ms.max(1_000));
+    let mut window_start = now_millis();
+    loop {
+        tokio::select! {
+            _ = sleep(interval) => {
+                let Some(summary) = take_summary(&state, window_start) else {
+                    continue;
+                };
+                window_start = summary.window_end;
+                match deliver(&target, &summary).await {
+                    Ok(()) => info!(docs = summary.docs.len(), "sent change digest"),
+                    Err(err) => error!("digest delivery failed: {:#}", err),
+                }
+            }
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+
+    fn mk_state(tmp: &Path) -> AppState {
+        let wal_dir = tmp.join("wal");
+        let snap_dir = tmp.join("snapshots");
+        fs::create_dir_all(&wal_dir).unwrap();
+        fs::create_dir_all(&snap_dir).unwrap();
+        AppState::new(wal_dir, snap_dir, 1_000, 128, true, Vec::new())
+    }
+
+    fn edit(client_id: Option<Uuid>, ops: Vec<OpKind>) -> Edit {
+        Edit {
+            base_rev: 0,
+            ops,
+            client_id,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[test]
+    fn record_change_aggregates_per_slug() {
+        let base = std::env::temp_dir().join(format!("digest-{}", Uuid::new_v4()));
+        let mut state = mk_state(&base);
+        let client = Uuid::new_v4();
+        record_change(&state, "a", &edit(Some(client), vec![]));
+        assert!(state.digest_pending.read().is_empty());
+
+        state.digest_target = Some(DigestTarget::Webhook("http://localhost".into()));
+        crate::presence::register_presence(&state, "a", client, None, Some("Ann".into()), None, 0);
+        let insert = OpKind::Insert {
+            pos: 0,
+            text: "héllo".into(),
+        };
+        record_change(&state, "a", &edit(Some(client), vec![insert]));
+        record_change(
+            &state,
+            "a",
+            &edit(None, vec![OpKind::Delete { pos: 0, len: 2 }]),
+        );
+        record_change(&state, "b", &edit(None, vec![]));
+
+        let summary = take_summary(&state, 0).unwrap();
+        assert_eq!(summary.docs.len(), 2);
+        let a = &summary.docs[0];
+        assert_eq!((a.edits, a.inserted_chars, a.deleted_chars), (2, 5, 2));
+        assert_eq!(a.contributors.iter().collect::<Vec<_>>(), vec!["Ann"]);
+        assert!(render_text(&summary).contains("- a: 2 edit(s), +5 / -2 chars by Ann"));
+        assert!(take_summary(&state, 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn send_smtp_speaks_the_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut reader = BufReader::new(read);
+            write.write_all(b"220 ready\r\n").await.unwrap();
+            let mut transcript = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                transcript.push_str(&line);
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-hi\r\n250 OK\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go\r\n"
+                } else if line.starts_with("QUIT") {
+                    write.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                write.write_all(reply).await.unwrap();
+            }
+            transcript
+        });
+
+        send_smtp(&addr, "bot@x", &["me@x".into()], "hello\n.dot")
+            .await
+            .unwrap();
+        let transcript = server.await.unwrap();
+        assert!(transcript.contains("RCPT TO:<me@x>\r\n"));
+        assert!(transcript.contains("\r\nhello\r\n..dot\r\n.\r\n"));
+    }
+}
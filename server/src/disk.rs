@@ -0,0 +1,159 @@
+//! Free-space watchdog for the data directory. Once free space drops below
+//! `min_free_bytes`, new documents and large edits are refused up front with
+//! `disk_low` instead of failing halfway through a write.
+
+use std::{path::Path, time::Duration};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::{sync::watch, time::sleep};
+use tracing::info;
+
+use crate::{
+    metrics::{record_disk_low, record_disk_refusal},
+    state::{AppState, Rejection},
+};
+
+pub const DEFAULT_MIN_FREE_BYTES: u64 = 256 * 1024 * 1024;
+pub const DISK_CHECK_MS: u64 = 30_000;
+/// Edits inserting at least this many bytes are refused while space is low.
+pub const LOW_DISK_EDIT_BYTES: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct DiskStatus {
+    /// `None` before the first check and where it cannot be measured.
+    pub free_bytes: Option<u64>,
+    pub min_free_bytes: u64,
+    pub low: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DiskWatch {
+    status: Mutex<DiskStatus>,
+}
+
+impl DiskWatch {
+    pub fn status(&self) -> DiskStatus {
+        self.status.lock().clone()
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.status.lock().low
+    }
+}
+
+/// Bytes available to unprivileged writers on the filesystem of `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read on success.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Measures the WAL and snapshot directories and records whether the
+/// tighter of the two is below the threshold.
+pub fn check_disk(state: &AppState) -> DiskStatus {
+    let free_bytes = [&state.wal_dir, &state.snap_dir]
+        .into_iter()
+        .filter_map(|dir| free_space(dir))
+        .min();
+    let min_free_bytes = state.min_free_bytes;
+    let low = min_free_bytes > 0 && free_bytes.is_some_and(|free| free < min_free_bytes);
+    let status = DiskStatus {
+        free_bytes,
+        min_free_bytes,
+        low,
+    };
+    let was_low = std::mem::replace(&mut *state.disk.status.lock(), status.clone()).low;
+    if low && !was_low {
+        record_disk_low(free_bytes.unwrap_or(0), min_free_bytes);
+    } else if was_low && !low {
+        info!(
+            event = "disk_recovered",
+            free_bytes, "free disk space back above the threshold"
+        );
+    }
+    status
+}
+
+/// Refuses to create a document, or to write `bytes`, while space is low.
+pub fn admit_write(
+    state: &AppState,
+    slug: &str,
+    bytes: u64,
+    creating: bool,
+) -> Result<(), Rejection> {
+    if !state.disk.is_low() || !creating && bytes < LOW_DISK_EDIT_BYTES {
+        return Ok(());
+    }
+    record_disk_refusal(state, slug, bytes, creating);
+    Err(Rejection::new(
+        "disk_low",
+        "the server is low on disk space; only small edits to existing documents are accepted",
+    ))
+}
+
+pub async fn run_disk_watchdog(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        check_disk(&state);
+        tokio::select! {
+            _ = sleep(Duration::from_millis(DISK_CHECK_MS)) => {}
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_disk_refuses_creation_and_large_edits() {
+        let dir = std::env::temp_dir();
+        let mut state = AppState::new(
+            dir.clone(),
+            dir.clone(),
+            10_000,
+            1_000_000,
+            true,
+            Vec::new(),
+        );
+        let status = check_disk(&state);
+        assert!(!status.low);
+        assert!(admit_write(&state, "doc", LOW_DISK_EDIT_BYTES, true).is_ok());
+
+        state.min_free_bytes = u64::MAX;
+        let status = check_disk(&state);
+        if cfg!(unix) {
+            assert!(status.free_bytes.is_some());
+            assert!(status.low);
+            assert!(admit_write(&state, "doc", 10, false).is_ok());
+            let refused = admit_write(&state, "doc", LOW_DISK_EDIT_BYTES, false).unwrap_err();
+            assert_eq!(refused.code, "disk_low");
+            assert!(admit_write(&state, "doc", 0, true).is_err());
+            assert_eq!(state.metrics.snapshot().disk_refusals, 2);
+        }
+
+        state.min_free_bytes = 0;
+        assert!(!check_disk(&state).low);
+        assert!(admit_write(&state, "doc", 0, true).is_ok());
+    }
+}
@@ -0,0 +1,157 @@
+//! Per-document settings stored in the metadata sidecar. Anything left unset
+//! falls back to the server-wide value.
+
+use serde_json::{Map, Value};
+
+use crate::{
+    expiry::{is_expired, record_expiry},
+    state::{AppState, Rejection, get_or_load_doc, now_millis, publish_access_change},
+    storage::persist_meta,
+    types::{DocMeta, DocSettings},
+};
+
+pub fn flush_idle_ms(state: &AppState, meta: &DocMeta) -> u64 {
+    meta.settings
+        .flush_idle_ms
+        .unwrap_or_else(|| state.live.read().flush_idle_ms)
+}
+
+pub fn flush_max_ops(state: &AppState, meta: &DocMeta) -> usize {
+    meta.settings
+        .flush_max_ops
+        .unwrap_or_else(|| state.live.read().flush_max_ops)
+}
+
+/// Applies a JSON merge patch to `current`: keys with a value replace the
+/// setting, `null` clears it and missing keys are left alone.
+pub fn patch_settings(
+    current: &DocSettings,
+    patch: Map<String, Value>,
+) -> Result<DocSettings, Rejection> {
+    let invalid = |message: String| Rejection::new("invalid_settings", message);
+    let Value::Object(mut merged) =
+        serde_json::to_value(current).map_err(|err| invalid(err.to_string()))?
+    else {
+        return Err(invalid("settings are not an object".into()));
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            merged.remove(&key);
+        } else {
+            merged.insert(key, value);
+        }
+    }
+    let settings: DocSettings =
+        serde_json::from_value(Value::Object(merged)).map_err(|err| invalid(err.to_string()))?;
+    if settings.flush_max_ops == Some(0) {
+        return Err(invalid("flush_max_ops must be at least 1".into()));
+    }
+    if let Some(url) = &settings.alert_webhook_url
+        && !url.starts_with("http://")
+    {
+        return Err(invalid("alert_webhook_url must be an http:// URL".into()));
+    }
+    Ok(settings)
+}
+
+/// Patches the settings of `slug` and persists them with the metadata.
+pub async fn update_doc_settings(
+    state: &AppState,
+    slug: &str,
+    patch: Map<String, Value>,
+) -> anyhow::Result<DocSettings> {
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let now = now_millis();
+    let writable = |meta: &DocMeta| meta.settings.read_only != Some(true) && !is_expired(meta, now);
+    let (meta, lock_changed, expiry_changed) = {
+        let mut d = doc_arc.write();
+        let settings = patch_settings(&d.meta.settings, patch)?;
+        let was_writable = writable(&d.meta);
+        let expiry_changed = settings.expires_at != d.meta.settings.expires_at;
+        d.meta.settings = settings;
+        (
+            d.meta.clone(),
+            was_writable != writable(&d.meta),
+            expiry_changed,
+        )
+    };
+    persist_meta(state, slug, &meta)?;
+    if expiry_changed {
+        record_expiry(state, slug, meta.settings.expires_at);
+    }
+    if lock_changed {
+        publish_access_change(state, slug, &doc_arc, None);
+    }
+    Ok(meta.settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patch(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn patches_merge_and_clear() {
+        let current = DocSettings {
+            flush_idle_ms: Some(100),
+            max_bytes: Some(10),
+            ..Default::default()
+        };
+        let next = patch_settings(
+            &current,
+            patch(json!({"flush_max_ops": 5, "max_bytes": null})),
+        )
+        .unwrap();
+        assert_eq!(
+            next,
+            DocSettings {
+                flush_idle_ms: Some(100),
+                flush_max_ops: Some(5),
+                ..Default::default()
+            }
+        );
+        assert!(patch_settings(&current, patch(json!({"flush_max_ops": 0}))).is_err());
+        assert!(patch_settings(&current, patch(json!({"flush_ms": 1}))).is_err());
+        assert!(patch_settings(&current, patch(json!({"max_bytes": "big"}))).is_err());
+        let hook = json!({"alert_webhook_url": "https://example.com/"});
+        assert!(patch_settings(&current, patch(hook)).is_err());
+    }
+
+    #[tokio::test]
+    async fn locking_publishes_an_access_change() {
+        let base = std::env::temp_dir().join(format!("doc-settings-{}", uuid::Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000_000,
+            true,
+            Vec::new(),
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        state.subs.write().insert("doc".into(), vec![tx.into()]);
+
+        update_doc_settings(&state, "doc", patch(json!({"flush_max_ops": 5})))
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+        update_doc_settings(&state, "doc", patch(json!({"read_only": true})))
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(crate::types::ServerMsg::AccessChanged {
+                version: 1,
+                writable: false,
+                ..
+            })
+        ));
+    }
+}
@@ -1,13 +1,67 @@
-use crate::types::{Edit, OpKind};
+use std::collections::HashMap;
+
+use parking_lot::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    lines::LineLog,
+    rev_index::{INDEX_MIN_REVS, RevIndex},
+    types::{
+        CursorState, Diagnostic, DocMeta, DocStats, Edit, OpKind, SectionClaim, VersionVector,
+    },
+    validation::Violation,
+};
 
 #[derive(Debug, Default)]
 pub struct Doc {
     pub rev: u64,
     pub content: String,
-    pub log: Vec<Vec<OpKind>>,
+    /// Applied ops per revision, as far as rebasing needs them. The text
+    /// they inserted lives on in `content`, the WAL and snapshots.
+    pub log: Vec<Vec<OpShape>>,
     pub since_flush: usize,
     pub password_hash: Option<String>,
+    pub inherited_password_hash: Option<String>,
     pub last_edit_ts: u64,
+    pub meta: DocMeta,
+    pub line_log: Option<LineLog>,
+    /// Violations reported by the last flush, so repeats are not re-sent.
+    pub flush_violations: Vec<Violation>,
+    /// Applied edits per client, including those already in the snapshot.
+    pub versions: VersionVector,
+    /// Moves whenever who may open or edit the document changes; sessions
+    /// re-check their access when it does.
+    pub access_version: u64,
+    /// Live section claims by client; see [`crate::sections`].
+    pub sections: HashMap<Uuid, SectionClaim>,
+    /// What the analyzers last found, as of `rev`; see [`crate::analysis`].
+    pub diagnostics: Vec<Diagnostic>,
+    /// The revision the kept diagnostics were found on.
+    pub diagnosed_rev: u64,
+    /// Built on the first long rebase and kept up with `log` after that;
+    /// clear it when rewriting logged revisions.
+    pub rev_index: Mutex<RevIndex>,
+    /// Set by [`crate::trash::delete_doc`] so an edit that got hold of the
+    /// document before it was unloaded does not write its WAL back.
+    pub deleted: bool,
+}
+
+/// Counts one applied edit from `client_id`. Edits the server makes on its
+/// own behalf carry no client and are not counted.
+pub fn bump_version(versions: &mut VersionVector, client_id: Option<Uuid>) {
+    if let Some(id) = client_id {
+        *versions.entry(id).or_default() += 1;
+    }
+}
+
+/// Moves a replay past revisions whose ops were purged or compacted. They
+/// keep their numbers but log nothing, so edits must not be rebased across
+/// them.
+pub fn skip_purged(doc: &mut Doc, rev: u64) {
+    if doc.rev < rev {
+        doc.rev = rev;
+        doc.log.resize(rev as usize, Vec::new());
+    }
 }
 
 pub fn transform_ops(doc: &Doc, edit: &Edit) -> Vec<OpKind> {
@@ -17,102 +71,428 @@ pub fn transform_ops(doc: &Doc, edit: &Edit) -> Vec<OpKind> {
     }
     let from = edit.base_rev as usize;
     let to = doc.rev as usize;
+    // Revisions past the end of the log have no ops to rebase across.
+    let logged = to.min(doc.log.len());
+    if logged.saturating_sub(from) >= INDEX_MIN_REVS
+        && let Some(ops) = doc
+            .rev_index
+            .lock()
+            .transform(&doc.log[..logged], from, &ops)
+    {
+        return ops;
+    }
     for i in from..to {
         if let Some(prev_ops) = doc.log.get(i) {
-            ops = transform_against(&ops, prev_ops);
+            ops = transform_pair(&ops, prev_ops, true).0;
         }
     }
     ops
 }
 
-fn transform_against(ops: &[OpKind], prev: &[OpKind]) -> Vec<OpKind> {
-    let mut res = ops.to_vec();
-    for p in prev {
-        res = res.into_iter().map(|o| transform_op(o, p)).collect();
+/// An applied op without its text: where it went and how many chars it
+/// inserted or deleted, which is all rebasing across it takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpShape {
+    Insert { pos: usize, chars: usize },
+    Delete { pos: usize, len: usize },
+}
+
+/// Ops that can be rebased: [`OpKind`] as sent by clients, or [`OpShape`]
+/// as kept in the log.
+pub trait ShapedOp: Clone {
+    fn shape(&self) -> OpShape;
+    /// This insert, moved to `pos`.
+    fn moved_to(&self, pos: usize) -> Self;
+    fn delete(pos: usize, len: usize) -> Self;
+}
+
+impl ShapedOp for OpKind {
+    fn shape(&self) -> OpShape {
+        match self {
+            OpKind::Insert { pos, text } => OpShape::Insert {
+                pos: *pos,
+                chars: text.chars().count(),
+            },
+            OpKind::Delete { pos, len } => OpShape::Delete {
+                pos: *pos,
+                len: *len,
+            },
+        }
+    }
+
+    fn moved_to(&self, pos: usize) -> Self {
+        match self {
+            OpKind::Insert { text, .. } => OpKind::Insert {
+                pos,
+                text: text.clone(),
+            },
+            OpKind::Delete { len, .. } => OpKind::Delete { pos, len: *len },
+        }
+    }
+
+    fn delete(pos: usize, len: usize) -> Self {
+        OpKind::Delete { pos, len }
     }
-    res
 }
 
-fn transform_op(op: OpKind, other: &OpKind) -> OpKind {
-    match (op, other) {
-        (OpKind::Insert { mut pos, text }, OpKind::Insert { pos: o, text: t }) => {
-            if pos > *o {
-                pos += t.chars().count();
-            }
-            OpKind::Insert { pos, text }
+impl ShapedOp for OpShape {
+    fn shape(&self) -> OpShape {
+        *self
+    }
+
+    fn moved_to(&self, pos: usize) -> Self {
+        match *self {
+            OpShape::Insert { chars, .. } => OpShape::Insert { pos, chars },
+            OpShape::Delete { len, .. } => OpShape::Delete { pos, len },
+        }
+    }
+
+    fn delete(pos: usize, len: usize) -> Self {
+        OpShape::Delete { pos, len }
+    }
+}
+
+/// What the log keeps of `ops`.
+pub fn shapes(ops: &[OpKind]) -> Vec<OpShape> {
+    ops.iter().map(ShapedOp::shape).collect()
+}
+
+/// Transforms two op sequences made against the same document state so each
+/// can be applied after the other: `apply(a); apply(b')` and
+/// `apply(b); apply(a')` give the same content. `a_wins` decides which insert
+/// ends up first when both insert at the same position. The server passes
+/// `true` for the incoming edit, which places it before text that was already
+/// applied there.
+pub fn transform_pair<A: ShapedOp, B: ShapedOp>(
+    a: &[A],
+    b: &[B],
+    a_wins: bool,
+) -> (Vec<A>, Vec<B>) {
+    match (a, b) {
+        ([], _) | (_, []) => (a.to_vec(), b.to_vec()),
+        ([x], [y]) => (
+            transform_op(x, y.shape(), a_wins),
+            transform_op(y, x.shape(), !a_wins),
+        ),
+        ([x, rest @ ..], _) if !rest.is_empty() => {
+            let (x2, b1) = transform_pair(std::slice::from_ref(x), b, a_wins);
+            let (rest2, b2) = transform_pair(rest, &b1, a_wins);
+            ([x2, rest2].concat(), b2)
         }
-        (OpKind::Insert { mut pos, text }, OpKind::Delete { pos: o, len }) => {
-            if pos > *o {
-                pos = pos.saturating_sub(*len);
+        (_, [y, rest @ ..]) => {
+            let (a1, y2) = transform_pair(a, std::slice::from_ref(y), a_wins);
+            let (a2, rest2) = transform_pair(&a1, rest, a_wins);
+            (a2, [y2, rest2].concat())
+        }
+    }
+}
+
+/// Maps a position through a deletion of `[start, start + len)`.
+pub(crate) fn map_through_delete(pos: usize, start: usize, len: usize) -> usize {
+    if pos <= start {
+        pos
+    } else if pos >= start.saturating_add(len) {
+        pos - len
+    } else {
+        start
+    }
+}
+
+/// Rewrites `op` so it applies after `other`. A delete whose range straddles
+/// an insert is split in two so the inserted text survives; a delete that
+/// `other` already covered vanishes.
+fn transform_op<T: ShapedOp>(op: &T, other: OpShape, op_wins: bool) -> Vec<T> {
+    match (op.shape(), other) {
+        (OpShape::Insert { pos, .. }, OpShape::Insert { pos: o, chars }) => {
+            let mut pos = pos;
+            if pos > o || (pos == o && !op_wins) {
+                pos = pos.saturating_add(chars);
             }
-            OpKind::Insert { pos, text }
+            vec![op.moved_to(pos)]
+        }
+        (OpShape::Insert { pos, .. }, OpShape::Delete { pos: o, len }) => {
+            vec![op.moved_to(map_through_delete(pos, o, len))]
         }
-        (OpKind::Delete { mut pos, len }, OpKind::Insert { pos: o, text: t }) => {
-            if pos >= *o {
-                pos += t.chars().count();
+        (OpShape::Delete { pos, len }, OpShape::Insert { pos: o, chars: t }) => {
+            if o <= pos {
+                vec![T::delete(pos.saturating_add(t), len)]
+            } else if o >= pos.saturating_add(len) {
+                vec![T::delete(pos, len)]
+            } else {
+                vec![
+                    T::delete(pos, o - pos),
+                    T::delete(pos.saturating_add(t), len - (o - pos)),
+                ]
             }
-            OpKind::Delete { pos, len }
         }
-        (OpKind::Delete { mut pos, len }, OpKind::Delete { pos: o, len: l }) => {
-            if pos >= *o {
-                pos = pos.saturating_sub(*l);
+        (OpShape::Delete { pos, len }, OpShape::Delete { pos: o, len: l }) => {
+            let start = map_through_delete(pos, o, l);
+            let end = map_through_delete(pos.saturating_add(len), o, l);
+            if end > start {
+                vec![T::delete(start, end - start)]
+            } else {
+                vec![]
             }
-            OpKind::Delete { pos, len }
         }
     }
 }
 
-pub fn apply_ops(doc: &mut Doc, ops: &[OpKind]) {
+/// Maps a position through `ops`. A position at an insert stays in front of
+/// the inserted text.
+fn map_position(pos: usize, ops: &[OpShape]) -> usize {
+    ops.iter().fold(pos, |pos, op| match *op {
+        OpShape::Insert { pos: o, chars } if o < pos => pos.saturating_add(chars),
+        OpShape::Insert { .. } => pos,
+        OpShape::Delete { pos: o, len } => map_through_delete(pos, o, len),
+    })
+}
+
+fn map_cursor(cursor: &CursorState, ops: &[OpShape]) -> CursorState {
+    CursorState {
+        position: map_position(cursor.position, ops),
+        anchor: cursor.anchor.map(|anchor| map_position(anchor, ops)),
+        selection_direction: cursor.selection_direction.clone(),
+    }
+}
+
+fn cursor_fits(cursor: &CursorState, len: usize) -> bool {
+    cursor.position <= len && cursor.anchor.is_none_or(|anchor| anchor <= len)
+}
+
+/// Moves `edit.cursor_after`, which the client computed on top of
+/// `base_rev`, past the revisions `edit` was rebased across. Call before the
+/// edit itself is logged.
+pub fn rebase_cursor(doc: &Doc, edit: &Edit, cursor: &CursorState) -> CursorState {
+    let mut ops = edit.ops.clone();
+    let mut cursor = cursor.clone();
+    for prev in doc.log.get(edit.base_rev as usize..).unwrap_or_default() {
+        let (rebased, prev_after) = transform_pair(&ops, prev, true);
+        cursor = map_cursor(&cursor, &prev_after);
+        ops = rebased;
+    }
+    cursor
+}
+
+/// Checks `edit` against the server's copy before its rebased `ops` are
+/// applied. Ops that reach past the end of the document, or cursors that
+/// could not exist on either side of the edit, mean the client's state no
+/// longer matches ours.
+pub fn check_consistency(doc: &Doc, edit: &Edit, ops: &[OpKind]) -> Result<(), String> {
+    if edit.base_rev > doc.rev {
+        return Err(format!(
+            "base revision {} is ahead of {}",
+            edit.base_rev, doc.rev
+        ));
+    }
+    let mut len = doc.content.chars().count();
+    if let Some(cursor) = &edit.cursor_before {
+        let concurrent = doc.log.get(edit.base_rev as usize..).unwrap_or_default();
+        let cursor = concurrent
+            .iter()
+            .fold(cursor.clone(), |cursor, ops| map_cursor(&cursor, ops));
+        if !cursor_fits(&cursor, len) {
+            return Err("cursor_before is outside the document".into());
+        }
+    }
     for op in ops {
         match op {
-            OpKind::Insert { pos, .. } if *pos > doc.content.chars().count() => {
-                continue;
-            }
-            _ => {}
+            OpKind::Insert { pos, text } if *pos <= len => len += text.chars().count(),
+            OpKind::Delete { pos, len: n } if pos.saturating_add(*n) <= len => len -= n,
+            _ => return Err(format!("{:?} does not fit a document of {} chars", op, len)),
         }
-        apply_single_op(doc, op);
     }
+    if let Some(cursor) = &edit.cursor_after
+        && !cursor_fits(&rebase_cursor(doc, edit, cursor), len)
+    {
+        return Err("cursor_after is outside the document".into());
+    }
+    Ok(())
 }
 
-fn apply_single_op(doc: &mut Doc, op: &OpKind) {
-    match op {
-        OpKind::Insert { pos, text } => {
-            let mut s = String::new();
-            let mut it = doc.content.chars();
-            for _ in 0..*pos {
-                if let Some(c) = it.next() {
-                    s.push(c);
-                }
+/// An op refused by [`check_ops_strict`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidOp {
+    /// Index of the op within the edit.
+    pub index: usize,
+    pub reason: &'static str,
+    /// Length in chars of the document the op was checked against.
+    pub doc_len: usize,
+}
+
+impl std::fmt::Display for InvalidOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "op {} is invalid ({}) for a document of {} chars",
+            self.index, self.reason, self.doc_len
+        )
+    }
+}
+
+/// Checks the ops of `edit` as the client wrote them, against the document
+/// at `base_rev`, so a refusal names the op and the length the client
+/// actually had. Empty inserts and deletes, which otherwise pass as no-ops,
+/// are refused as well.
+pub fn check_ops_strict(doc: &Doc, edit: &Edit) -> Result<(), InvalidOp> {
+    let current = doc.content.chars().count() as isize;
+    let concurrent = doc.log.get(edit.base_rev as usize..).unwrap_or_default();
+    let mut len = concurrent
+        .iter()
+        .flatten()
+        .fold(current, |len, op| match *op {
+            OpShape::Insert { chars, .. } => len - chars as isize,
+            OpShape::Delete { len: n, .. } => len + n as isize,
+        })
+        .max(0) as usize;
+    for (index, op) in edit.ops.iter().enumerate() {
+        let reason = match op {
+            OpKind::Insert { text, .. } if text.is_empty() => "empty_insert",
+            OpKind::Insert { pos, .. } if *pos > len => "insert_out_of_range",
+            OpKind::Insert { text, .. } => {
+                len += text.chars().count();
+                continue;
             }
-            s.push_str(text);
-            for c in it {
-                s.push(c);
+            OpKind::Delete { len: 0, .. } => "empty_delete",
+            OpKind::Delete { pos, len: n } if pos.saturating_add(*n) > len => "delete_out_of_range",
+            OpKind::Delete { len: n, .. } => {
+                len -= n;
+                continue;
             }
-            doc.content = s;
-        }
-        OpKind::Delete { pos, len } => {
-            let mut s = String::new();
-            let mut it = doc.content.chars();
-            for _ in 0..*pos {
-                if let Some(c) = it.next() {
-                    s.push(c);
-                }
+        };
+        return Err(InvalidOp {
+            index,
+            reason,
+            doc_len: len,
+        });
+    }
+    Ok(())
+}
+
+/// 32-bit FNV-1a over the UTF-8 bytes of `content`, cheap enough for
+/// clients to recompute after every `Applied` that carries one.
+pub fn content_hash(content: &str) -> u32 {
+    content.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+pub fn doc_stats(content: &str) -> DocStats {
+    DocStats {
+        chars: content.chars().count(),
+        words: content.split_whitespace().count(),
+        lines: content.matches('\n').count() + 1,
+    }
+}
+
+/// Applies `ops` in place. Inserts past the end are skipped and deletes are
+/// cut short at it.
+pub fn apply_ops(doc: &mut Doc, ops: &[OpKind]) {
+    // Text before the last op's position is unchanged, so an op further on
+    // finds its offset from there instead of from the start.
+    let mut mark = (0, 0);
+    for op in ops {
+        let pos = match op {
+            OpKind::Insert { pos, .. } | OpKind::Delete { pos, .. } => *pos,
+        };
+        if pos < mark.0 {
+            mark = (0, 0);
+        }
+        let Some(start) = byte_offset(&doc.content[mark.1..], pos - mark.0).map(|at| mark.1 + at)
+        else {
+            continue;
+        };
+        match op {
+            OpKind::Insert { text, .. } => doc.content.insert_str(start, text),
+            OpKind::Delete { len, .. } => {
+                let end = byte_offset(&doc.content[start..], *len)
+                    .map_or(doc.content.len(), |at| start + at);
+                doc.content.replace_range(start..end, "");
+            }
+        }
+        mark = (pos, start);
+    }
+}
+
+/// Byte offset of the char at `chars`, which may be the end of `text` but
+/// not past it.
+fn byte_offset(text: &str, chars: usize) -> Option<usize> {
+    text.char_indices()
+        .map(|(idx, _)| idx)
+        .chain(std::iter::once(text.len()))
+        .nth(chars)
+}
+
+/// Folds ops applied one after another into fewer ops with the same effect,
+/// e.g. a word typed a character at a time into one insert.
+pub fn compose_ops(ops: impl IntoIterator<Item = OpKind>) -> Vec<OpKind> {
+    let mut out: Vec<OpKind> = Vec::new();
+    for op in ops {
+        let Some(last) = out.last_mut() else {
+            out.push(op);
+            continue;
+        };
+        if !merge_op(last, &op) {
+            out.push(op);
+        } else if matches!(last, OpKind::Insert { text, .. } if text.is_empty()) {
+            out.pop();
+        }
+    }
+    out
+}
+
+fn char_offset(text: &str, chars: usize) -> usize {
+    text.char_indices()
+        .nth(chars)
+        .map_or(text.len(), |(idx, _)| idx)
+}
+
+/// Merges `next` into `last` when it only touches what `last` inserted or
+/// deleted, and returns whether it did.
+fn merge_op(last: &mut OpKind, next: &OpKind) -> bool {
+    match (last, next) {
+        (
+            OpKind::Insert { pos, text },
+            OpKind::Insert {
+                pos: at,
+                text: more,
+            },
+        ) => {
+            if *at < *pos || *at - *pos > text.chars().count() {
+                return false;
             }
-            for _ in 0..*len {
-                let _ = it.next();
+            text.insert_str(char_offset(text, *at - *pos), more);
+            true
+        }
+        (OpKind::Insert { pos, text }, OpKind::Delete { pos: at, len }) => {
+            if *at < *pos || (*at - *pos).saturating_add(*len) > text.chars().count() {
+                return false;
             }
-            for c in it {
-                s.push(c);
+            let start = char_offset(text, *at - *pos);
+            let end = char_offset(text, *at - *pos + *len);
+            text.replace_range(start..end, "");
+            true
+        }
+        (OpKind::Delete { pos, len }, OpKind::Delete { pos: at, len: more }) => {
+            if *at == *pos {
+                *len = len.saturating_add(*more);
+            } else if at.checked_add(*more) == Some(*pos) {
+                *pos = *at;
+                *len = len.saturating_add(*more);
+            } else {
+                return false;
             }
-            doc.content = s;
+            true
         }
+        _ => false,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ot_sim::{Simulation, Step, run_bytes};
     use crate::types::{Edit, OpKind};
+    use proptest::prelude::*;
 
     #[test]
     fn transform_ops_accounts_for_previous_inserts() {
@@ -123,7 +503,7 @@ mod tests {
         let doc = Doc {
             rev: 1,
             content: "abc".into(),
-            log: vec![vec![prior]],
+            log: vec![shapes(&[prior])],
             ..Default::default()
         };
         let edit = Edit {
@@ -137,6 +517,8 @@ mod tests {
             cursor_before: None,
             cursor_after: None,
             ts: None,
+            group_id: None,
+            user_id: None,
         };
 
         let transformed = transform_ops(&doc, &edit);
@@ -170,4 +552,410 @@ mod tests {
 
         assert_eq!(doc.content, "abXYef");
     }
+
+    #[test]
+    fn transform_splits_delete_around_concurrent_insert() {
+        let mut doc = Doc {
+            rev: 1,
+            content: "abXcd".into(),
+            log: vec![shapes(&[OpKind::Insert {
+                pos: 2,
+                text: "X".into(),
+            }])],
+            ..Default::default()
+        };
+        let edit = Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Delete { pos: 1, len: 2 }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        let ops = transform_ops(&doc, &edit);
+        apply_ops(&mut doc, &ops);
+        assert_eq!(doc.content, "aXd");
+    }
+
+    #[test]
+    fn transform_pair_converges_on_overlapping_deletes() {
+        let a = vec![OpKind::Delete { pos: 1, len: 3 }];
+        let b = vec![OpKind::Delete { pos: 2, len: 3 }];
+        let (a2, b2) = transform_pair(&a, &b, true);
+        let run = |first: &[OpKind], second: &[OpKind]| {
+            let mut doc = Doc {
+                content: "abcdefg".into(),
+                ..Default::default()
+            };
+            apply_ops(&mut doc, first);
+            apply_ops(&mut doc, second);
+            doc.content
+        };
+        assert_eq!(run(&a, &b2), "afg");
+        assert_eq!(run(&b, &a2), "afg");
+    }
+
+    #[test]
+    fn transform_pair_orders_inserts_at_one_position_by_winner() {
+        let insert = |text: &str| {
+            vec![OpKind::Insert {
+                pos: 1,
+                text: text.into(),
+            }]
+        };
+        let (a, b) = (insert("A"), insert("B"));
+        for (a_wins, expected) in [(true, "xABy"), (false, "xBAy")] {
+            let (a2, b2) = transform_pair(&a, &b, a_wins);
+            let run = |first: &[OpKind], second: &[OpKind]| {
+                let mut doc = Doc {
+                    content: "xy".into(),
+                    ..Default::default()
+                };
+                apply_ops(&mut doc, first);
+                apply_ops(&mut doc, second);
+                doc.content
+            };
+            assert_eq!(run(&a, &b2), expected);
+            assert_eq!(run(&b, &a2), expected);
+        }
+    }
+
+    #[test]
+    fn multi_op_edits_are_transformed_op_by_op() {
+        // The second op is positioned after the first one was applied, so a
+        // concurrent insert between them must be mapped through it.
+        let mut doc = Doc {
+            rev: 1,
+            content: "abZcdef".into(),
+            log: vec![shapes(&[OpKind::Insert {
+                pos: 2,
+                text: "Z".into(),
+            }])],
+            ..Default::default()
+        };
+        let edit = Edit {
+            base_rev: 0,
+            ops: vec![
+                OpKind::Delete { pos: 0, len: 2 },
+                OpKind::Insert {
+                    pos: 2,
+                    text: "X".into(),
+                },
+            ],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        let ops = transform_ops(&doc, &edit);
+        apply_ops(&mut doc, &ops);
+        assert_eq!(doc.content, "ZcdXef");
+    }
+
+    #[test]
+    fn apply_ops_ignores_positions_past_the_end() {
+        let mut doc = Doc {
+            content: "abc".into(),
+            ..Default::default()
+        };
+        apply_ops(
+            &mut doc,
+            &[
+                OpKind::Insert {
+                    pos: usize::MAX,
+                    text: "X".into(),
+                },
+                OpKind::Delete {
+                    pos: 1,
+                    len: usize::MAX,
+                },
+            ],
+        );
+        assert_eq!(doc.content, "a");
+    }
+
+    #[test]
+    fn consistency_check_catches_ops_past_the_end() {
+        let doc = Doc {
+            rev: 1,
+            content: "abc".into(),
+            log: vec![shapes(&[OpKind::Insert {
+                pos: 0,
+                text: "abc".into(),
+            }])],
+            ..Default::default()
+        };
+        let edit = |ops: Vec<OpKind>| Edit {
+            base_rev: 1,
+            ops,
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        let fits = |ops: Vec<OpKind>| {
+            let edit = edit(ops);
+            check_consistency(&doc, &edit, &transform_ops(&doc, &edit)).is_ok()
+        };
+        assert!(fits(vec![OpKind::Delete { pos: 1, len: 2 }]));
+        assert!(!fits(vec![OpKind::Delete { pos: 1, len: 3 }]));
+        assert!(fits(vec![
+            OpKind::Insert {
+                pos: 3,
+                text: "de".into()
+            },
+            OpKind::Delete { pos: 4, len: 1 },
+        ]));
+        assert!(!fits(vec![OpKind::Insert {
+            pos: 4,
+            text: "x".into()
+        }]));
+    }
+
+    #[test]
+    fn strict_check_uses_the_document_the_client_saw() {
+        let doc = Doc {
+            rev: 2,
+            content: "abcdefgh".into(),
+            log: vec![
+                shapes(&[OpKind::Insert {
+                    pos: 0,
+                    text: "abc".into(),
+                }]),
+                shapes(&[OpKind::Insert {
+                    pos: 3,
+                    text: "defgh".into(),
+                }]),
+            ],
+            ..Default::default()
+        };
+        let edit = |ops: Vec<OpKind>| Edit {
+            base_rev: 1,
+            ops,
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        // Reported against "abc", not the text it was rebased onto.
+        let late = edit(vec![OpKind::Delete { pos: 2, len: 5 }]);
+        assert_eq!(
+            check_ops_strict(&doc, &late),
+            Err(InvalidOp {
+                index: 0,
+                reason: "delete_out_of_range",
+                doc_len: 3,
+            })
+        );
+        let empty = edit(vec![
+            OpKind::Delete { pos: 0, len: 1 },
+            OpKind::Insert {
+                pos: 0,
+                text: String::new(),
+            },
+        ]);
+        assert_eq!(
+            check_ops_strict(&doc, &empty).unwrap_err().reason,
+            "empty_insert"
+        );
+        assert!(
+            check_ops_strict(
+                &doc,
+                &edit(vec![OpKind::Insert {
+                    pos: 3,
+                    text: "!".into()
+                }])
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn content_hash_is_fnv1a() {
+        assert_eq!(content_hash(""), 0x811c_9dc5);
+        assert_eq!(content_hash("a"), 0xe40c_292c);
+        assert_ne!(content_hash("ab"), content_hash("ba"));
+    }
+
+    #[test]
+    fn doc_stats_counts_chars_words_and_lines() {
+        assert_eq!(
+            doc_stats(""),
+            DocStats {
+                chars: 0,
+                words: 0,
+                lines: 1
+            }
+        );
+        assert_eq!(
+            doc_stats("# Café\n\nsome  words\there\n"),
+            DocStats {
+                chars: 25,
+                words: 5,
+                lines: 4
+            }
+        );
+    }
+
+    fn arb_op() -> impl Strategy<Value = OpKind> {
+        prop_oneof![
+            (any::<usize>(), "[a-z\\u{e9}\\u{1F600}]{0,4}")
+                .prop_map(|(pos, text)| OpKind::Insert { pos, text }),
+            (any::<usize>(), any::<usize>()).prop_map(|(pos, len)| OpKind::Delete { pos, len }),
+            (0..16usize, 0..16usize).prop_map(|(pos, len)| OpKind::Delete { pos, len }),
+        ]
+    }
+
+    fn arb_step() -> impl Strategy<Value = Step> {
+        prop_oneof![
+            4 => (0..4usize, any::<bool>(), any::<usize>(), any::<usize>(), "[ab\\u{e9}\\u{1F600}]{1,3}")
+                .prop_map(|(client, insert, pos, len, text)| Step::Type {
+                    client,
+                    insert,
+                    pos,
+                    len,
+                    text,
+                }),
+            2 => (0..4usize).prop_map(|client| Step::Send { client }),
+            2 => (0..4usize).prop_map(|client| Step::Receive { client }),
+            2 => (0..4usize).prop_map(|client| Step::Deliver { client }),
+        ]
+    }
+
+    #[test]
+    fn compose_ops_folds_typing_and_backspacing() {
+        let typed = (0..5).map(|i| OpKind::Insert {
+            pos: 3 + i,
+            text: "héllo".chars().nth(i).unwrap().to_string(),
+        });
+        let fixed = [
+            OpKind::Delete { pos: 7, len: 1 },
+            OpKind::Delete { pos: 6, len: 1 },
+            OpKind::Insert {
+                pos: 6,
+                text: "p!".into(),
+            },
+        ];
+        assert_eq!(
+            compose_ops(typed.chain(fixed)),
+            vec![OpKind::Insert {
+                pos: 3,
+                text: "hélp!".into()
+            }]
+        );
+        let deletes = [
+            OpKind::Delete { pos: 4, len: 1 },
+            OpKind::Delete { pos: 3, len: 1 },
+            OpKind::Delete { pos: 3, len: 2 },
+            OpKind::Insert {
+                pos: 0,
+                text: "x".into(),
+            },
+            OpKind::Delete { pos: 0, len: 1 },
+        ];
+        assert_eq!(
+            compose_ops(deletes),
+            vec![OpKind::Delete { pos: 3, len: 4 }]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn composed_ops_apply_the_same(
+            content in "[a-z\\u{e9}]{0,12}",
+            ops in prop::collection::vec(arb_op(), 0..8),
+        ) {
+            let mut step_by_step = Doc { content: content.clone(), ..Default::default() };
+            let mut composed = Doc { content, ..Default::default() };
+            apply_ops(&mut step_by_step, &ops);
+            apply_ops(&mut composed, &compose_ops(ops));
+            prop_assert_eq!(step_by_step.content, composed.content);
+        }
+
+        #[test]
+        fn applies_like_a_char_buffer(
+            content in "[a-z\\u{e9}\\u{1F600}]{0,12}",
+            ops in prop::collection::vec(
+                prop_oneof![
+                    arb_op(),
+                    (0..16usize, "[a-z\\u{e9}]{1,3}").prop_map(|(pos, text)| OpKind::Insert { pos, text }),
+                ],
+                0..8,
+            ),
+        ) {
+            let mut chars: Vec<char> = content.chars().collect();
+            for op in &ops {
+                match op {
+                    OpKind::Insert { pos, text } if *pos <= chars.len() => {
+                        chars.splice(*pos..*pos, text.chars());
+                    }
+                    OpKind::Insert { .. } => {}
+                    OpKind::Delete { pos, len } => {
+                        let start = (*pos).min(chars.len());
+                        chars.drain(start..start.saturating_add(*len).min(chars.len()));
+                    }
+                }
+            }
+            let mut doc = Doc { content, ..Default::default() };
+            apply_ops(&mut doc, &ops);
+            prop_assert_eq!(doc.content, chars.into_iter().collect::<String>());
+        }
+
+        #[test]
+        fn concurrent_clients_converge(
+            clients in 1..5usize,
+            initial in "[xyz]{0,6}",
+            steps in prop::collection::vec(arb_step(), 0..80),
+        ) {
+            let sim = Simulation::run(clients, &initial, &steps);
+            if let Some(divergence) = sim.divergence() {
+                prop_assert!(false, "{}", divergence);
+            }
+        }
+
+        #[test]
+        fn arbitrary_edits_never_panic(
+            content in "[a-z\\u{e9}]{0,12}",
+            log in prop::collection::vec(prop::collection::vec(arb_op(), 0..3), 0..4),
+            ops in prop::collection::vec(arb_op(), 0..4),
+            base_rev in 0..6u64,
+        ) {
+            let mut doc = Doc {
+                rev: log.len() as u64,
+                content,
+                log: log.iter().map(|ops| shapes(ops)).collect(),
+                ..Default::default()
+            };
+            let edit = Edit {
+                base_rev,
+                ops,
+                client_id: None,
+                op_id: None,
+                cursor_before: None,
+                cursor_after: None,
+                ts: None,
+                group_id: None,
+                user_id: None,
+            };
+            let transformed = transform_ops(&doc, &edit);
+            apply_ops(&mut doc, &transformed);
+        }
+
+        #[test]
+        fn fuzz_entry_handles_arbitrary_bytes(data in prop::collection::vec(any::<u8>(), 0..256)) {
+            prop_assert_eq!(run_bytes(&data), None);
+        }
+    }
 }
@@ -0,0 +1,618 @@
+//! Erasure of one client's identifying data on request: authorship in WAL
+//! edits, including archived and trashed ones, its counts in version
+//! vectors, its presence records and labels. Document content is kept.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs,
+};
+
+use serde::Serialize;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    archive::{read_archived_wal, rewrite_archived_wal},
+    presence::unindex_client,
+    retention::parse_wal,
+    state::{AppState, broadcast, get_or_load_doc, now_millis, unload_doc},
+    storage::{
+        collect_slugs_with_extension, flush_lock, load_meta, persist_meta, read_wal, rewrite_wal,
+        wal_lock,
+    },
+    trash::{TrashEntry, list_trash, read_trashed_wals},
+    types::{DocEvent, DocMeta, ServerMsg, VersionVector, WalEntryV2},
+};
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DocErasure {
+    pub slug: String,
+    pub archived: bool,
+    /// Set for a document in the trash, which holds it under this id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trash_id: Option<Uuid>,
+    /// Edits that lost their client id.
+    pub edits: u64,
+    /// Cursor and IME events removed or anonymized.
+    pub presence_events: u64,
+    /// Whether the client's count was dropped from the version vector.
+    pub versions: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErasureReport {
+    pub client_id: Uuid,
+    pub dry_run: bool,
+    pub ran_at: u64,
+    pub docs: Vec<DocErasure>,
+    /// Live presence entries dropped.
+    pub presence_records: u64,
+    /// Names dropped from pending digests.
+    pub digest_entries: u64,
+    /// Documents the run could not process; see the server log.
+    pub failed: Vec<String>,
+}
+
+/// Strips `client_id` from `entries` and returns how many edits and presence
+/// events it touched.
+fn erase_entries(entries: &mut Vec<WalEntryV2>, client_id: Uuid) -> (u64, u64) {
+    let edit_ids: HashSet<Uuid> = entries
+        .iter()
+        .filter_map(|entry| match &entry.event {
+            DocEvent::Edit { edit } | DocEvent::Compacted { edit } => edit.op_id,
+            _ => None,
+        })
+        .collect();
+    let (mut edits, mut presence) = (0, 0);
+    entries.retain_mut(|entry| match &mut entry.event {
+        DocEvent::Edit { edit } | DocEvent::Compacted { edit }
+            if edit.client_id == Some(client_id) =>
+        {
+            edit.client_id = None;
+            edit.user_id = None;
+            edits += 1;
+            true
+        }
+        DocEvent::Cursor {
+            client_id: cid,
+            op_id,
+            ..
+        }
+        | DocEvent::Ime {
+            client_id: cid,
+            op_id,
+            ..
+        } if *cid == client_id => {
+            presence += 1;
+            // Replay dedups edits against presence op ids, so an event
+            // sharing one with an edit has to stay.
+            if op_id.is_some_and(|id| edit_ids.contains(&id)) {
+                *cid = Uuid::nil();
+                true
+            } else {
+                false
+            }
+        }
+        _ => true,
+    });
+    (edits, presence)
+}
+
+fn render_wal(entries: &[WalEntryV2]) -> anyhow::Result<String> {
+    let mut data = String::new();
+    for entry in entries {
+        data.push_str(&serde_json::to_string(entry)?);
+        data.push('\n');
+    }
+    Ok(data)
+}
+
+fn doc_report(slug: &str, archived: bool, (edits, presence_events): (u64, u64)) -> DocErasure {
+    DocErasure {
+        slug: slug.to_string(),
+        archived,
+        trash_id: None,
+        edits,
+        presence_events,
+        versions: false,
+    }
+}
+
+async fn erase_live_doc(
+    state: &AppState,
+    slug: &str,
+    client_id: Uuid,
+    dry_run: bool,
+) -> anyhow::Result<Option<DocErasure>> {
+    let Some(data) = read_wal(state, slug)? else {
+        return Ok(None);
+    };
+    if !data.contains(&client_id.to_string()) {
+        return Ok(None);
+    }
+    let mut entries = parse_wal(&data)?;
+    let counts = erase_entries(&mut entries, client_id);
+    if dry_run || counts == (0, 0) {
+        return Ok((counts != (0, 0)).then(|| doc_report(slug, false, counts)));
+    }
+
+    let loaded = state.docs.read().contains_key(slug);
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let counts = {
+        // Edits wait while the WAL is rewritten, and so do their appends.
+        let wal = wal_lock(state, slug);
+        let _wal = wal.lock();
+        let _d = doc_arc.write();
+        let mut entries = parse_wal(&read_wal(state, slug)?.unwrap_or_default())?;
+        let counts = erase_entries(&mut entries, client_id);
+        rewrite_wal(state, slug, &render_wal(&entries)?)?;
+        counts
+    };
+    if !loaded {
+        unload_doc(state, slug, "erasure");
+    }
+    Ok(Some(doc_report(slug, false, counts)))
+}
+
+/// Drops `client_id` from the version vector of `slug`, in memory and in
+/// its metadata. Returns whether it was counted there.
+async fn erase_live_versions(
+    state: &AppState,
+    slug: &str,
+    client_id: Uuid,
+    dry_run: bool,
+) -> anyhow::Result<bool> {
+    let counted = |versions: &VersionVector| versions.contains_key(&client_id);
+    let loaded = state.docs.read().get(slug).cloned();
+    let found = match &loaded {
+        Some(doc) => {
+            let d = doc.read();
+            counted(&d.versions) || counted(&d.meta.versions)
+        }
+        None => load_meta(state, slug)?.is_some_and(|meta| counted(&meta.versions)),
+    };
+    if !found || dry_run {
+        return Ok(found);
+    }
+    // A flush copies the vector into the metadata; one already under way
+    // would put the client back.
+    let flush = flush_lock(state, slug);
+    let _flushing = flush.lock().await;
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let meta = {
+        let mut d = doc_arc.write();
+        d.versions.remove(&client_id);
+        d.meta.versions.remove(&client_id);
+        d.meta.clone()
+    };
+    persist_meta(state, slug, &meta)?;
+    if loaded.is_none() {
+        unload_doc(state, slug, "erasure");
+    }
+    Ok(true)
+}
+
+/// Documents with a version vector: those with metadata on disk, archived
+/// ones included, and those only in memory.
+fn versioned_slugs(state: &AppState) -> anyhow::Result<Vec<String>> {
+    let mut slugs: BTreeSet<String> = collect_slugs_with_extension(&state.snap_dir, "json", false)?
+        .into_iter()
+        .filter_map(|slug| slug.strip_suffix(".meta").map(str::to_string))
+        .collect();
+    slugs.extend(state.docs.read().keys().cloned());
+    Ok(slugs.into_iter().collect())
+}
+
+/// Drops `client_id` from the metadata of trashed documents. Returns the
+/// entries it was counted in.
+fn erase_trashed_versions(
+    state: &AppState,
+    client_id: Uuid,
+    dry_run: bool,
+) -> anyhow::Result<Vec<TrashEntry>> {
+    let mut erased = Vec::new();
+    for entry in list_trash(state)? {
+        let path = state.trash_dir.join(entry.id.to_string()).join("meta.json");
+        let Some(data) = fs::read(&path).map(Some).or_else(|err| {
+            if err.kind() == std::io::ErrorKind::NotFound {
+                Ok(None)
+            } else {
+                Err(err)
+            }
+        })?
+        else {
+            continue;
+        };
+        let mut meta: DocMeta = serde_json::from_slice(&data)?;
+        if meta.versions.remove(&client_id).is_none() {
+            continue;
+        }
+        if !dry_run {
+            fs::write(&path, serde_json::to_vec_pretty(&meta)?)?;
+        }
+        erased.push(entry);
+    }
+    Ok(erased)
+}
+
+/// Marks the report of `slug` as having had its version vector erased,
+/// adding one if nothing else in it was.
+fn note_versions(report: &mut ErasureReport, slug: &str, trash_id: Option<Uuid>, archived: bool) {
+    match report
+        .docs
+        .iter_mut()
+        .find(|doc| doc.slug == slug && doc.trash_id == trash_id)
+    {
+        Some(doc) => doc.versions = true,
+        None => report.docs.push(DocErasure {
+            trash_id,
+            versions: true,
+            ..doc_report(slug, archived, (0, 0))
+        }),
+    }
+}
+
+fn erase_archived_doc(
+    state: &AppState,
+    slug: &str,
+    client_id: Uuid,
+    dry_run: bool,
+) -> anyhow::Result<Option<DocErasure>> {
+    let Some((path, data)) = read_archived_wal(state, slug)? else {
+        return Ok(None);
+    };
+    if !data.contains(&client_id.to_string()) {
+        return Ok(None);
+    }
+    let mut entries = parse_wal(&data)?;
+    let counts = erase_entries(&mut entries, client_id);
+    if counts == (0, 0) {
+        return Ok(None);
+    }
+    if !dry_run {
+        rewrite_archived_wal(&path, &render_wal(&entries)?)?;
+    }
+    Ok(Some(doc_report(slug, true, counts)))
+}
+
+fn erase_trashed_docs(
+    state: &AppState,
+    client_id: Uuid,
+    dry_run: bool,
+    report: &mut ErasureReport,
+) -> anyhow::Result<()> {
+    for (entry, path, data) in read_trashed_wals(state)? {
+        if !data.contains(&client_id.to_string()) {
+            continue;
+        }
+        let erased = parse_wal(&data).and_then(|mut entries| {
+            let counts = erase_entries(&mut entries, client_id);
+            if counts != (0, 0) && !dry_run {
+                rewrite_archived_wal(&path, &render_wal(&entries)?)?;
+            }
+            Ok(counts)
+        });
+        match erased {
+            Ok((0, 0)) => {}
+            Ok(counts) => report.docs.push(DocErasure {
+                trash_id: Some(entry.id),
+                ..doc_report(&entry.slug, false, counts)
+            }),
+            Err(err) => {
+                error!(slug = %entry.slug, id = %entry.id, "erasure failed: {:#}", err);
+                report.failed.push(entry.slug);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drops the client from live presence and pending digests. Returns the
+/// number of presence records and digest names removed.
+fn erase_memory(state: &AppState, client_id: Uuid, dry_run: bool) -> (u64, u64) {
+    let mut names: HashSet<String> = HashSet::from([client_id.to_string()]);
+    let mut slugs = Vec::new();
+    {
+        let mut presence = state.presence.write();
+        for (slug, doc) in presence.iter_mut() {
+            let found = if dry_run {
+                doc.clients.get(&client_id).cloned()
+            } else {
+                doc.clients.remove(&client_id)
+            };
+            if let Some(found) = found {
+                names.extend(found.label);
+                slugs.push(slug.clone());
+            }
+        }
+    }
+    let mut digest_entries = 0;
+    for digest in state.digest_pending.write().values_mut() {
+        let before = digest.contributors.len();
+        if dry_run {
+            digest_entries += digest
+                .contributors
+                .iter()
+                .filter(|name| names.contains(*name))
+                .count() as u64;
+        } else {
+            digest.contributors.retain(|name| !names.contains(name));
+            digest_entries += (before - digest.contributors.len()) as u64;
+        }
+    }
+    if !dry_run {
+        unindex_client(state, &client_id, None);
+        for slug in &slugs {
+            broadcast(
+                state,
+                slug,
+                ServerMsg::PresenceDiff {
+                    slug: slug.clone(),
+                    added: Vec::new(),
+                    updated: Vec::new(),
+                    removed: vec![client_id],
+                },
+            );
+        }
+    }
+    (slugs.len() as u64, digest_entries)
+}
+
+/// Removes `client_id`'s identifying data from every live, archived and
+/// trashed WAL and from memory. With `dry_run` nothing is changed and the report lists
+/// what would be rewritten. A client that is still connected shows up again
+/// on its next presence update.
+pub async fn erase_client(
+    state: &AppState,
+    client_id: Uuid,
+    dry_run: bool,
+) -> anyhow::Result<ErasureReport> {
+    let mut report = ErasureReport {
+        client_id,
+        dry_run,
+        ran_at: now_millis(),
+        docs: Vec::new(),
+        presence_records: 0,
+        digest_entries: 0,
+        failed: Vec::new(),
+    };
+    let mut slugs = collect_slugs_with_extension(&state.wal_dir, "jsonl", true)?;
+    slugs.sort();
+    for slug in slugs {
+        match erase_live_doc(state, &slug, client_id, dry_run).await {
+            Ok(Some(doc)) => report.docs.push(doc),
+            Ok(None) => {}
+            Err(err) => {
+                error!(%slug, "erasure failed: {:#}", err);
+                report.failed.push(slug);
+            }
+        }
+    }
+    let mut archived = collect_slugs_with_extension(&state.archive_dir.join("wal"), "jsonl", true)?;
+    archived.sort();
+    for slug in archived {
+        match erase_archived_doc(state, &slug, client_id, dry_run) {
+            Ok(Some(doc)) => report.docs.push(doc),
+            Ok(None) => {}
+            Err(err) => {
+                error!(%slug, "erasure failed: {:#}", err);
+                report.failed.push(slug);
+            }
+        }
+    }
+    erase_trashed_docs(state, client_id, dry_run, &mut report)?;
+    for slug in versioned_slugs(state)? {
+        match erase_live_versions(state, &slug, client_id, dry_run).await {
+            Ok(false) => {}
+            Ok(true) => {
+                let archived = report
+                    .docs
+                    .iter()
+                    .any(|doc| doc.slug == slug && doc.archived);
+                note_versions(&mut report, &slug, None, archived);
+            }
+            Err(err) => {
+                error!(%slug, "erasure failed: {:#}", err);
+                report.failed.push(slug);
+            }
+        }
+    }
+    for entry in erase_trashed_versions(state, client_id, dry_run)? {
+        note_versions(&mut report, &entry.slug, Some(entry.id), false);
+    }
+    (report.presence_records, report.digest_entries) = erase_memory(state, client_id, dry_run);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::{
+        archive::archive_doc,
+        digest::DocDigest,
+        history::export_history,
+        presence::register_presence,
+        state::apply_edit,
+        storage::{flush_snapshot_force, wal_append_event},
+        trash::delete_doc,
+        types::{CursorState, Edit, OpKind},
+    };
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("erasure-{}", Uuid::new_v4()));
+        let wal = base.join("wal");
+        let snap = base.join("snapshots");
+        fs::create_dir_all(&wal).unwrap();
+        fs::create_dir_all(&snap).unwrap();
+        AppState::new(wal, snap, 1_000, 100, true, vec![])
+    }
+
+    fn insert(base_rev: u64, pos: usize, text: &str, client_id: Uuid) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: Some(client_id),
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    fn cursor(position: usize) -> CursorState {
+        CursorState {
+            position,
+            anchor: None,
+            selection_direction: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn strips_client_from_wals_presence_and_digests() {
+        let state = mk_state();
+        let (gone, other) = (Uuid::new_v4(), Uuid::new_v4());
+        apply_edit(&state, "notes", insert(0, 0, "hello", gone))
+            .await
+            .unwrap();
+        apply_edit(&state, "notes", insert(1, 5, "!", other))
+            .await
+            .unwrap();
+        let cursor = DocEvent::Cursor {
+            client_id: gone,
+            op_id: None,
+            cursor: cursor(1),
+        };
+        wal_append_event(&state, "notes", &cursor, now_millis()).unwrap();
+        apply_edit(&state, "old", insert(0, 0, "bye", gone))
+            .await
+            .unwrap();
+        archive_doc(&state, "old", true).await.unwrap();
+        apply_edit(&state, "binned", insert(0, 0, "gone", gone))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "binned").await.unwrap();
+        delete_doc(&state, "binned", "unused").unwrap();
+        let trash_id = list_trash(&state).unwrap()[0].id;
+        // Flushed, and with its WAL gone, only the version vector names it.
+        apply_edit(&state, "kept", insert(0, 0, "mine", gone))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "kept").await.unwrap();
+        unload_doc(&state, "kept", "test");
+        fs::remove_file(crate::storage::wal_path(&state, "kept").unwrap()).unwrap();
+        register_presence(&state, "notes", gone, None, Some("Ann".into()), None, 0);
+        state.digest_pending.write().insert(
+            "notes".into(),
+            DocDigest {
+                slug: "notes".into(),
+                contributors: ["Ann".to_string(), "Bob".to_string()].into(),
+                ..Default::default()
+            },
+        );
+
+        let dry = erase_client(&state, gone, true).await.unwrap();
+        assert_eq!(
+            dry.docs,
+            vec![
+                DocErasure {
+                    versions: true,
+                    ..doc_report("notes", false, (1, 1))
+                },
+                DocErasure {
+                    versions: true,
+                    ..doc_report("old", true, (1, 0))
+                },
+                DocErasure {
+                    trash_id: Some(trash_id),
+                    versions: true,
+                    ..doc_report("binned", false, (1, 0))
+                },
+                DocErasure {
+                    versions: true,
+                    ..doc_report("kept", false, (0, 0))
+                },
+            ]
+        );
+        assert_eq!((dry.presence_records, dry.digest_entries), (1, 1));
+        assert!(
+            read_wal(&state, "notes")
+                .unwrap()
+                .unwrap()
+                .contains(&gone.to_string())
+        );
+
+        let report = erase_client(&state, gone, false).await.unwrap();
+        assert_eq!(report.docs, dry.docs);
+        assert!(report.failed.is_empty());
+        let wal = read_wal(&state, "notes").unwrap().unwrap();
+        assert!(!wal.contains(&gone.to_string()));
+        assert!(wal.contains(&other.to_string()));
+        let (_, archived) = read_archived_wal(&state, "old").unwrap().unwrap();
+        assert!(!archived.contains(&gone.to_string()));
+        assert!(archived.contains("bye"));
+        let (_, _, trashed) = read_trashed_wals(&state).unwrap().remove(0);
+        assert!(!trashed.contains(&gone.to_string()));
+        assert!(!state.presence.read()["notes"].clients.contains_key(&gone));
+        let notes = get_or_load_doc(&state, "notes").await.unwrap();
+        assert!(!notes.read().versions.contains_key(&gone));
+        assert!(notes.read().versions.contains_key(&other));
+        for slug in ["notes", "old", "kept"] {
+            let meta = load_meta(&state, slug).unwrap().unwrap();
+            assert!(!meta.versions.contains_key(&gone), "{slug}");
+        }
+        assert!(!state.docs.read().contains_key("kept"));
+        let trashed_meta = fs::read(state.trash_dir.join(trash_id.to_string()).join("meta.json"));
+        assert!(
+            !String::from_utf8(trashed_meta.unwrap())
+                .unwrap()
+                .contains(&gone.to_string())
+        );
+        let contributors = state.digest_pending.read()["notes"].contributors.clone();
+        assert_eq!(contributors.into_iter().collect::<Vec<_>>(), vec!["Bob"]);
+
+        // Content survives a reload and the history no longer names the client.
+        unload_doc(&state, "notes", "test");
+        let doc = get_or_load_doc(&state, "notes").await.unwrap();
+        assert_eq!(doc.read().content, "hello!");
+        flush_snapshot_force(&state, "notes").await.unwrap();
+        let history = export_history(&state, "notes").unwrap();
+        assert!(history.edits.iter().all(|e| e.client_id != Some(gone)));
+
+        let again = erase_client(&state, gone, false).await.unwrap();
+        assert!(again.docs.is_empty());
+    }
+
+    #[test]
+    fn presence_events_sharing_an_edit_op_id_are_anonymized() {
+        let (gone, op_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut edit = insert(0, 0, "x", gone);
+        edit.op_id = Some(op_id);
+        let entry = |event| WalEntryV2 {
+            version: 2,
+            ts: 0,
+            server_ts: None,
+            event,
+        };
+        let mut entries = vec![
+            entry(DocEvent::Cursor {
+                client_id: gone,
+                op_id: Some(op_id),
+                cursor: cursor(0),
+            }),
+            entry(DocEvent::Edit { edit }),
+        ];
+        assert_eq!(erase_entries(&mut entries, gone), (1, 1));
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(
+            entries[0].event,
+            DocEvent::Cursor { client_id, .. } if client_id.is_nil()
+        ));
+    }
+}
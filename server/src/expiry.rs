@@ -0,0 +1,313 @@
+//! Self-destructing documents. Once the `expires_at` setting passes, edits
+//! are refused, and after [`EXPIRY_GRACE_MS`] the document is deleted into
+//! the [trash](crate::trash). Sessions get `Expiring` notices counting down
+//! to it. Due times are read from disk on first use and kept current by
+//! [`record_expiry`] afterwards, like the tag index.
+
+use std::{collections::HashMap, time::Duration};
+
+use tokio::{sync::watch, time::sleep};
+use tracing::{error, info};
+
+use crate::{
+    cluster::owns,
+    state::{AppState, broadcast, now_millis, publish_access_change},
+    storage::{list_all_slugs, load_meta},
+    trash::delete_doc,
+    types::{DocMeta, ServerMsg},
+};
+
+/// How long an expired document stays readable before it is deleted.
+pub const EXPIRY_GRACE_MS: u64 = 60_000;
+/// Time left at which sessions are told again, largest first. The last one
+/// is the lock itself.
+const NOTICES_MS: [u64; 5] = [60 * 60_000, 10 * 60_000, 60_000, 10_000, 0];
+const TICK_MS: u64 = 1_000;
+
+#[derive(Debug, Default)]
+pub struct ExpiryIndex {
+    due: HashMap<String, u64>,
+    /// The entry of [`NOTICES_MS`] each document was last told about.
+    noticed: HashMap<String, u64>,
+}
+
+/// Whether `meta` says the document expired by `now`.
+pub fn is_expired(meta: &DocMeta, now: u64) -> bool {
+    meta.settings.expires_at.is_some_and(|at| now >= at)
+}
+
+/// The notice for a document expiring at `expires_at`, or for a cancelled
+/// expiry.
+pub fn expiry_notice(slug: &str, expires_at: Option<u64>, now: u64) -> ServerMsg {
+    ServerMsg::Expiring {
+        slug: slug.to_string(),
+        expires_at,
+        remaining_ms: expires_at.map(|at| at.saturating_sub(now)),
+    }
+}
+
+/// The smallest notice threshold that `remaining` has reached.
+fn threshold(remaining: u64) -> Option<u64> {
+    NOTICES_MS.iter().rev().copied().find(|t| remaining <= *t)
+}
+
+fn scan_disk(state: &AppState) -> anyhow::Result<ExpiryIndex> {
+    let mut index = ExpiryIndex::default();
+    for slug in list_all_slugs(state)? {
+        let meta = match state.docs.read().get(&slug) {
+            Some(doc) => doc.read().meta.clone(),
+            None => load_meta(state, &slug)?.unwrap_or_default(),
+        };
+        if let (Some(at), None) = (meta.settings.expires_at, meta.archived_at) {
+            index.due.insert(slug, at);
+        }
+    }
+    Ok(index)
+}
+
+fn with_index<T>(state: &AppState, f: impl FnOnce(&mut ExpiryIndex) -> T) -> anyhow::Result<T> {
+    if state.expiries.read().is_none() {
+        let index = scan_disk(state)?;
+        state.expiries.write().get_or_insert(index);
+    }
+    let mut expiries = state.expiries.write();
+    Ok(f(expiries.as_mut().expect("expiry index was just built")))
+}
+
+/// Updates the index after the expiry of `slug` was set, changed or
+/// cleared, and tells its sessions.
+pub fn record_expiry(state: &AppState, slug: &str, expires_at: Option<u64>) {
+    let now = now_millis();
+    if let Some(index) = state.expiries.write().as_mut() {
+        match expires_at {
+            Some(at) => {
+                index.due.insert(slug.to_string(), at);
+                // Notices already passed are not worth repeating.
+                match threshold(at.saturating_sub(now)) {
+                    Some(t) => index.noticed.insert(slug.to_string(), t),
+                    None => index.noticed.remove(slug),
+                };
+            }
+            None => {
+                index.due.remove(slug);
+                index.noticed.remove(slug);
+            }
+        }
+    }
+    broadcast(state, slug, expiry_notice(slug, expires_at, now));
+}
+
+/// Drops `slug` from the index, for documents deleted or archived by other
+/// means.
+pub fn forget_expiry(state: &AppState, slug: &str) {
+    if let Some(index) = state.expiries.write().as_mut() {
+        index.due.remove(slug);
+        index.noticed.remove(slug);
+    }
+}
+
+/// Sends the notices that came due by `now`, locks documents that expired
+/// and deletes those past the grace. Returns the deleted slugs.
+pub fn expire_due(state: &AppState, now: u64) -> anyhow::Result<Vec<String>> {
+    let mut notices = Vec::new();
+    let mut overdue = Vec::new();
+    with_index(state, |index| {
+        for (slug, at) in &index.due {
+            if !owns(state, slug) {
+                continue;
+            }
+            if now >= at.saturating_add(EXPIRY_GRACE_MS) {
+                overdue.push(slug.clone());
+                continue;
+            }
+            let Some(t) = threshold(at.saturating_sub(now)) else {
+                continue;
+            };
+            if index.noticed.get(slug) != Some(&t) {
+                index.noticed.insert(slug.clone(), t);
+                notices.push((slug.clone(), *at, t == 0));
+            }
+        }
+    })?;
+    for (slug, at, locked) in notices {
+        broadcast(state, &slug, expiry_notice(&slug, Some(at), now));
+        let loaded = state.docs.read().get(&slug).cloned();
+        if locked && let Some(doc) = loaded {
+            publish_access_change(state, &slug, &doc, None);
+        }
+    }
+    let mut deleted = Vec::new();
+    for slug in overdue {
+        forget_expiry(state, &slug);
+        delete_doc(state, &slug, "expired")?;
+        deleted.push(slug);
+    }
+    Ok(deleted)
+}
+
+pub async fn run_expiry_loop(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = sleep(Duration::from_millis(TICK_MS)) => {
+                match expire_due(&state, now_millis()) {
+                    Ok(deleted) if !deleted.is_empty() => {
+                        info!(docs = deleted.len(), "deleted expired documents");
+                    }
+                    Ok(_) => {}
+                    Err(err) => error!("expiring documents failed: {:#}", err),
+                }
+            }
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use crate::{
+        doc_settings::update_doc_settings,
+        state::{Rejection, apply_edit},
+        storage::doc_exists_on_disk,
+        trash::list_trash,
+        types::{Edit, OpKind},
+    };
+
+    fn insert(base_rev: u64, text: &str) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    fn expire_at(at: Option<u64>) -> serde_json::Map<String, serde_json::Value> {
+        match json!({ "expires_at": at }) {
+            serde_json::Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    fn remaining(msg: ServerMsg) -> Option<u64> {
+        match msg {
+            ServerMsg::Expiring { remaining_ms, .. } => remaining_ms,
+            other => panic!("expected a notice, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_down_locks_and_deletes_into_the_trash() {
+        let base = std::env::temp_dir().join(format!("expiry-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        apply_edit(&state, "pad", insert(0, "short-lived"))
+            .await
+            .unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        state.subs.write().insert("pad".into(), vec![tx.into()]);
+
+        let at = now_millis() + 2 * 60 * 60_000;
+        update_doc_settings(&state, "pad", expire_at(Some(at)))
+            .await
+            .unwrap();
+        assert!(remaining(rx.try_recv().unwrap()).unwrap() > 60 * 60_000);
+        expire_due(&state, at - 2 * 60 * 60_000).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        // Notices are sent once per threshold.
+        expire_due(&state, at - 30 * 60_000).unwrap();
+        assert_eq!(remaining(rx.try_recv().unwrap()), Some(30 * 60_000));
+        expire_due(&state, at - 20 * 60_000).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        assert!(expire_due(&state, at).unwrap().is_empty());
+        assert_eq!(remaining(rx.try_recv().unwrap()), Some(0));
+        assert!(matches!(rx.try_recv(), Ok(ServerMsg::AccessChanged { .. })));
+
+        // Moving the time into the past locks right away.
+        let at = now_millis();
+        update_doc_settings(&state, "pad", expire_at(Some(at)))
+            .await
+            .unwrap();
+        assert_eq!(remaining(rx.try_recv().unwrap()), Some(0));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMsg::AccessChanged {
+                writable: false,
+                ..
+            })
+        ));
+        let err = apply_edit(&state, "pad", insert(1, "more"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, "expired");
+        expire_due(&state, at + 1).unwrap();
+        assert!(rx.try_recv().is_err());
+
+        let deleted = expire_due(&state, at + EXPIRY_GRACE_MS).unwrap();
+        assert_eq!(deleted, vec!["pad".to_string()]);
+        assert!(!doc_exists_on_disk(&state, "pad").unwrap());
+        assert_eq!(list_trash(&state).unwrap()[0].reason, "expired");
+        assert!(
+            expire_due(&state, at + 2 * EXPIRY_GRACE_MS)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_expiry_is_announced_and_dropped() {
+        let base = std::env::temp_dir().join(format!("expiry-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let at = now_millis() + 60_000;
+        update_doc_settings(&state, "pad", expire_at(Some(at)))
+            .await
+            .unwrap();
+        // Built from disk, after the setting was stored.
+        assert!(expire_due(&state, at).unwrap().is_empty());
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        state.subs.write().insert("pad".into(), vec![tx.into()]);
+        update_doc_settings(&state, "pad", expire_at(None))
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMsg::Expiring {
+                expires_at: None,
+                remaining_ms: None,
+                ..
+            })
+        ));
+        assert!(expire_due(&state, at + EXPIRY_GRACE_MS).unwrap().is_empty());
+        assert!(doc_exists_on_disk(&state, "pad").unwrap());
+    }
+}
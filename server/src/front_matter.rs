@@ -0,0 +1,330 @@
+//! Front-matter at the top of Markdown documents, YAML between `---` lines
+//! or TOML between `+++` lines. Only flat keys are read: scalars, one-line
+//! lists and, in YAML, `- item` lists; nested tables are skipped. The result
+//! is kept in the metadata sidecar on every flush.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    mentions::extract_mentions,
+    state::AppState,
+    storage::{load_meta, read_snapshot},
+    tags::{extract_tags, normalize_tag},
+    types::{ContentType, DocMeta},
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FrontMatter {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Normalized like `#tags` in the text.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// From `authors`, or `author` when there is one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<String>,
+    /// Every other key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, Value>,
+}
+
+/// The front-matter of `content` and the byte offset where the body starts.
+/// A block that is never closed is not front-matter.
+pub fn split_front_matter(content: &str) -> (Option<FrontMatter>, usize) {
+    let mut lines = content.split_inclusive('\n');
+    let Some(first) = lines.next() else {
+        return (None, 0);
+    };
+    let (toml, close) = match first.trim_end() {
+        "---" => (false, &["---", "..."][..]),
+        "+++" => (true, &["+++"][..]),
+        _ => return (None, 0),
+    };
+    let mut offset = first.len();
+    let mut block = Vec::new();
+    for line in lines {
+        offset += line.len();
+        if close.contains(&line.trim_end()) {
+            let fields = if toml {
+                parse_toml(&block)
+            } else {
+                parse_yaml(&block)
+            };
+            return (Some(typed(fields)), offset);
+        }
+        block.push(line.trim_end_matches(['\n', '\r']));
+    }
+    (None, 0)
+}
+
+fn typed(mut fields: BTreeMap<String, Value>) -> FrontMatter {
+    let strings = |value: Option<Value>| -> Vec<String> {
+        match value {
+            Some(Value::Array(items)) => items.iter().filter_map(scalar_string).collect(),
+            Some(value) => scalar_string(&value).into_iter().collect(),
+            None => Vec::new(),
+        }
+    };
+    let title = fields.remove("title").as_ref().and_then(scalar_string);
+    let mut tags: Vec<String> = match fields.remove("tags") {
+        // `tags: a, b` is as common as a list.
+        Some(Value::String(list)) => list.split(',').filter_map(normalize_tag).collect(),
+        other => strings(other)
+            .iter()
+            .filter_map(|tag| normalize_tag(tag))
+            .collect(),
+    };
+    tags.sort();
+    tags.dedup();
+    let mut authors = strings(fields.remove("authors"));
+    if authors.is_empty() {
+        authors = strings(fields.remove("author"));
+    }
+    FrontMatter {
+        title,
+        tags,
+        authors,
+        fields,
+    }
+}
+
+fn scalar_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn parse_yaml(block: &[&str]) -> BTreeMap<String, Value> {
+    let mut fields = BTreeMap::new();
+    let mut list: Option<(String, Vec<Value>)> = None;
+    for line in block {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        if let Some(item) = line.trim_start().strip_prefix("- ")
+            && line.starts_with([' ', '-'])
+            && let Some((_, items)) = &mut list
+        {
+            items.push(scalar(item));
+            continue;
+        }
+        if let Some((key, items)) = list.take() {
+            fields.insert(key, list_value(items));
+        }
+        // Indented lines belong to a nested map.
+        if line.starts_with([' ', '\t']) {
+            continue;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let key = key.trim().trim_matches(['"', '\'']).to_string();
+        if value.trim().is_empty() {
+            list = Some((key, Vec::new()));
+        } else {
+            fields.insert(key, value_of(value));
+        }
+    }
+    if let Some((key, items)) = list {
+        fields.insert(key, list_value(items));
+    }
+    fields
+}
+
+/// A key with nothing under it is null, not an empty list.
+fn list_value(items: Vec<Value>) -> Value {
+    if items.is_empty() {
+        Value::Null
+    } else {
+        Value::Array(items)
+    }
+}
+
+fn parse_toml(block: &[&str]) -> BTreeMap<String, Value> {
+    let mut fields = BTreeMap::new();
+    for line in block {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Keys after a `[table]` header belong to it.
+        if line.starts_with('[') {
+            break;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().trim_matches(['"', '\'']).to_string();
+        fields.insert(key, value_of(value));
+    }
+    fields
+}
+
+/// A scalar or a one-line `[a, b]` list.
+fn value_of(raw: &str) -> Value {
+    let raw = strip_comment(raw.trim());
+    match raw.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+        Some(inner) => Value::Array(
+            split_items(inner)
+                .into_iter()
+                .filter(|item| !item.trim().is_empty())
+                .map(scalar)
+                .collect(),
+        ),
+        None => scalar(raw),
+    }
+}
+
+fn scalar(raw: &str) -> Value {
+    let raw = strip_comment(raw.trim());
+    if let Some(inner) = raw
+        .strip_prefix('"')
+        .and_then(|r| r.strip_suffix('"'))
+        .or_else(|| raw.strip_prefix('\'').and_then(|r| r.strip_suffix('\'')))
+    {
+        return Value::String(inner.replace("\\\"", "\"").replace("\\\\", "\\"));
+    }
+    match raw {
+        "" | "~" | "null" => return Value::Null,
+        "true" => return Value::Bool(true),
+        "false" => return Value::Bool(false),
+        _ => {}
+    }
+    if let Ok(n) = raw.parse::<i64>() {
+        return Value::from(n);
+    }
+    if let Some(n) = raw
+        .parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+    {
+        return Value::Number(n);
+    }
+    Value::String(raw.to_string())
+}
+
+/// Drops a ` # comment` outside quotes.
+fn strip_comment(raw: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (idx, c) in raw.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '#') if prev.is_whitespace() => return raw[..idx].trim_end(),
+            _ => {}
+        }
+        prev = c;
+    }
+    raw
+}
+
+/// Splits on commas outside quotes.
+fn split_items(inner: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (idx, c) in inner.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, ',') => {
+                items.push(&inner[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&inner[start..]);
+    items
+}
+
+/// The front-matter of `slug` as of its last flush.
+pub fn stored_front_matter(state: &AppState, slug: &str) -> anyhow::Result<Option<FrontMatter>> {
+    let loaded = state.docs.read().get(slug).cloned();
+    let meta = match loaded {
+        Some(doc) => doc.read().meta.clone(),
+        None => load_meta(state, slug)?.unwrap_or_default(),
+    };
+    Ok(match meta.tags {
+        Some(_) => meta.front_matter,
+        // Flushed before front-matter was kept in the sidecar.
+        None if meta.content_type.unwrap_or_default() == ContentType::Markdown => {
+            read_snapshot(state, slug)?.and_then(|content| split_front_matter(&content).0)
+        }
+        None => None,
+    })
+}
+
+/// Refreshes what the sidecar keeps about `content`: its front-matter,
+/// tags and mentions. Only Markdown documents have the first two.
+pub fn update_derived_meta(meta: &mut DocMeta, content: &str) {
+    let content_type = meta.content_type.clone().unwrap_or_default();
+    meta.mentions = Some(
+        extract_mentions(content, &content_type)
+            .into_keys()
+            .collect(),
+    );
+    if content_type == ContentType::Markdown {
+        meta.front_matter = split_front_matter(content).0;
+        meta.tags = Some(extract_tags(content).into_iter().collect());
+    } else {
+        meta.front_matter = None;
+        meta.tags = Some(Vec::new());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_yaml_front_matter() {
+        let content = "---\n\
+            title: \"Q3: plan\" # shown in lists\n\
+            tags: [Roadmap, 'q3']\n\
+            authors:\n  - ann\n  - bob\n\
+            draft: true\n\
+            priority: 2\n\
+            owner:\n  name: carl\n\
+            empty:\n\
+            ---\n# Body";
+        let (front, body) = split_front_matter(content);
+        let front = front.unwrap();
+        assert_eq!(&content[body..], "# Body");
+        assert_eq!(front.title.as_deref(), Some("Q3: plan"));
+        assert_eq!(front.tags, vec!["q3", "roadmap"]);
+        assert_eq!(front.authors, vec!["ann", "bob"]);
+        assert_eq!(
+            front.fields,
+            BTreeMap::from([
+                ("draft".to_string(), json!(true)),
+                ("empty".to_string(), Value::Null),
+                ("owner".to_string(), Value::Null),
+                ("priority".to_string(), json!(2)),
+            ])
+        );
+    }
+
+    #[test]
+    fn reads_toml_front_matter_and_skips_unclosed_blocks() {
+        let content = "+++\ntitle = 'Notes'\nauthor = \"ann\"\ntags = [\"a\", \"b, c\"]\n\
+            weight = 1.5\n[extra]\nkey = 1\n+++\nbody";
+        let front = split_front_matter(content).0.unwrap();
+        assert_eq!(front.title.as_deref(), Some("Notes"));
+        assert_eq!(front.authors, vec!["ann"]);
+        assert_eq!(front.tags, vec!["a"]);
+        assert_eq!(front.fields["weight"], json!(1.5));
+        assert!(!front.fields.contains_key("key"));
+
+        assert_eq!(split_front_matter("---\ntitle: x\nbody"), (None, 0));
+        assert_eq!(split_front_matter("text\n---\n"), (None, 0));
+    }
+}
@@ -0,0 +1,309 @@
+//! Snapshots kept in a Git repository. With `GIT_SNAPSHOTS` the snapshot
+//! directory is a work tree, and every flush commits the document's file:
+//! the author is whoever edited it since the last commit, with everyone
+//! else as `Co-authored-by` trailers, and the message sums up what they
+//! did. Contributors appear under a pseudonym rather than their label, so
+//! history pushed elsewhere keeps nothing erasure would have to reach. `git log`, `git blame` and the rest work on the directory as on
+//! any repository. With `GIT_REMOTE` the commits are pushed there every
+//! `GIT_PUSH_INTERVAL_SECS`, as an off-site backup.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::bail;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use tokio::{sync::watch, time::sleep};
+use tracing::{info, warn};
+
+use crate::{digest::DocDigest, state::AppState, storage::blocking, types::Edit};
+
+pub const DEFAULT_GIT_PUSH_INTERVAL_SECS: u64 = 300;
+const COMMITTER: &str = "coedit";
+const COMMITTER_EMAIL: &str = "coedit@localhost";
+/// Only snapshots are tracked, not the metadata next to them.
+const GITIGNORE: &str = "*\n!*/\n!*.md\n!.gitignore\n";
+
+#[derive(Debug)]
+pub struct GitStore {
+    pub dir: PathBuf,
+    pub remote: Option<String>,
+    /// What happened to each document since its last commit.
+    pending: Mutex<HashMap<String, DocDigest>>,
+    /// Git takes a lock on the index; one command at a time avoids failing
+    /// on it.
+    lock: Mutex<()>,
+    unpushed: AtomicBool,
+}
+
+impl GitStore {
+    /// Uses the repository at `dir`, creating it when there is none.
+    pub fn open(dir: PathBuf, remote: Option<String>) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let store = Self {
+            dir,
+            pending: Default::default(),
+            lock: Default::default(),
+            // Whatever the last run committed may not have gone out.
+            unpushed: AtomicBool::new(remote.is_some()),
+            remote,
+        };
+        if !store.dir.join(".git").exists() {
+            store.git(["init", "-q"])?;
+            info!(dir = %store.dir.display(), "created a git repository for snapshots");
+        }
+        let ignore = store.dir.join(".gitignore");
+        if !ignore.exists() {
+            fs::write(&ignore, GITIGNORE)?;
+        }
+        Ok(store)
+    }
+
+    fn run<I, S>(&self, args: I) -> anyhow::Result<Output>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        Ok(Command::new("git")
+            .arg("-C")
+            .arg(&self.dir)
+            .args(["-c", &format!("user.name={}", COMMITTER)])
+            .args(["-c", &format!("user.email={}", COMMITTER_EMAIL)])
+            .args(args)
+            .output()?)
+    }
+
+    fn git<I, S>(&self, args: I) -> anyhow::Result<Output>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let output = self.run(args)?;
+        if !output.status.success() {
+            bail!(
+                "git failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output)
+    }
+
+    /// Commits the snapshot of `slug` at `path`. Returns whether there was
+    /// anything to commit.
+    pub fn commit(&self, slug: &str, path: &Path) -> anyhow::Result<bool> {
+        let rel = path.strip_prefix(&self.dir)?;
+        let _git = self.lock.lock();
+        self.git([OsStr::new("add"), OsStr::new("--"), rel.as_os_str()])?;
+        let unchanged = self
+            .run([
+                OsStr::new("diff"),
+                OsStr::new("--cached"),
+                OsStr::new("--quiet"),
+                OsStr::new("--"),
+                rel.as_os_str(),
+            ])?
+            .status
+            .success();
+        if unchanged {
+            return Ok(false);
+        }
+        let activity = self.pending.lock().remove(slug).unwrap_or_default();
+        let (author, message) = commit_message(slug, &activity);
+        self.git([
+            OsStr::new("commit"),
+            OsStr::new("-q"),
+            OsStr::new("--author"),
+            OsStr::new(&author),
+            OsStr::new("-m"),
+            OsStr::new(&message),
+            OsStr::new("--"),
+            rel.as_os_str(),
+        ])?;
+        self.unpushed.store(true, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Pushes the commits made since the last push, if any.
+    pub fn push(&self) -> anyhow::Result<bool> {
+        let Some(remote) = &self.remote else {
+            return Ok(false);
+        };
+        if !self.unpushed.swap(false, Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let _git = self.lock.lock();
+        if let Err(err) = self.git(["push", "-q", remote.as_str(), "HEAD"]) {
+            self.unpushed.store(true, Ordering::Relaxed);
+            return Err(err);
+        }
+        Ok(true)
+    }
+}
+
+/// Keeps `edit` for the message of the next commit of `slug`.
+pub fn record_activity(state: &AppState, slug: &str, edit: &Edit) {
+    let Some(git) = &state.git else {
+        return;
+    };
+    let contributor = pseudonym(edit);
+    git.pending
+        .lock()
+        .entry(slug.to_string())
+        .or_default()
+        .add(edit, contributor);
+}
+
+/// The stable name commits carry for the client that made `edit`.
+fn pseudonym(edit: &Edit) -> Option<String> {
+    let client_id = edit.client_id?;
+    let digest = hex::encode(Sha256::digest(client_id.as_bytes()));
+    Some(format!("contributor-{}", &digest[..12]))
+}
+
+fn signature(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !matches!(c, '<' | '>' | '\n' | '\r'))
+        .collect();
+    format!("{} <{}>", name.trim(), COMMITTER_EMAIL)
+}
+
+/// The author and message of a commit made after `activity`.
+fn commit_message(slug: &str, activity: &DocDigest) -> (String, String) {
+    let mut names = activity.contributors.iter();
+    let author = signature(names.next().map_or(COMMITTER, String::as_str));
+    let mut message = match activity.edits {
+        0 => format!("Update {}", slug),
+        edits => format!(
+            "Update {}: {} edit(s), +{} / -{} chars",
+            slug, edits, activity.inserted_chars, activity.deleted_chars
+        ),
+    };
+    let co_authors: Vec<String> = names
+        .map(|name| format!("Co-authored-by: {}", signature(name)))
+        .collect();
+    if !co_authors.is_empty() {
+        message.push_str("\n\n");
+        message.push_str(&co_authors.join("\n"));
+    }
+    (author, message)
+}
+
+/// Pushes to the remote every `interval_ms` until `shutdown` flips to
+/// `true`, and once more then.
+pub async fn run_git_push_loop(
+    state: AppState,
+    interval_ms: u64,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let Some(git) = state.git.clone() else {
+        return;
+    };
+    loop {
+        let stop = tokio::select! {
+            _ = sleep(Duration::from_millis(interval_ms.max(1_000))) => false,
+            changed = shutdown.changed() => changed.is_err() || *shutdown.borrow(),
+        };
+        let pushing = git.clone();
+        match blocking(move || pushing.push()).await {
+            Ok(true) => info!(remote = git.remote.as_deref(), "pushed snapshots"),
+            Ok(false) => {}
+            Err(err) => warn!("pushing snapshots failed: {:#}", err),
+        }
+        if stop {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        presence::register_presence,
+        state::{apply_edit, now_millis},
+        storage::flush_snapshot_force,
+        types::OpKind,
+    };
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn flushes_commit_with_their_authors() {
+        let base = std::env::temp_dir().join(format!("git-{}", Uuid::new_v4()));
+        let remote = base.join("remote.git");
+        let init = Command::new("git")
+            .args(["init", "-q", "--bare"])
+            .arg(&remote)
+            .status()
+            .unwrap();
+        assert!(init.success());
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let git =
+            GitStore::open(base.join("snapshots"), Some(remote.display().to_string())).unwrap();
+        state.git = Some(Arc::new(git));
+
+        let (ann, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        for (client, name) in [(ann, "Ann"), (bob, "Bob")] {
+            register_presence(&state, "notes", client, None, Some(name.into()), None, 0);
+        }
+        let edit = |base_rev: u64, client_id: Uuid, text: &str| Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: Some(client_id),
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: Some(now_millis()),
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(&state, "notes", edit(0, ann, "hello"))
+            .await
+            .unwrap();
+        apply_edit(&state, "notes", edit(1, bob, "> "))
+            .await
+            .unwrap();
+        assert!(flush_snapshot_force(&state, "notes").await.unwrap());
+
+        let git = state.git.clone().unwrap();
+        let log = git.git(["log", "--format=%an%n%B"]).unwrap();
+        let log = String::from_utf8(log.stdout).unwrap();
+        let name = |client_id: Uuid| pseudonym(&edit(0, client_id, "")).unwrap();
+        let mut names = [name(ann), name(bob)];
+        names.sort();
+        assert!(log.starts_with(&format!(
+            "{}\nUpdate notes: 2 edit(s), +7 / -0 chars\n",
+            names[0]
+        )));
+        assert!(log.contains(&format!("Co-authored-by: {} <coedit@localhost>", names[1])));
+        assert!(!log.contains("Ann") && !log.contains("Bob"));
+
+        assert!(git.push().unwrap());
+        assert!(!git.push().unwrap());
+        let pushed = Command::new("git")
+            .arg("-C")
+            .arg(&remote)
+            .args(["log", "--oneline"])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&pushed.stdout).lines().count(), 1);
+    }
+}
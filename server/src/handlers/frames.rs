@@ -0,0 +1,130 @@
+use axum::extract::ws::Message;
+
+use crate::{document::content_hash, types::ServerMsg};
+
+/// Largest piece of snapshot content sent in one `snapshot_chunk`.
+pub const SNAPSHOT_CHUNK_BYTES: usize = 256 * 1024;
+
+/// Splits a `snapshot` whose content is over `chunk_bytes` into
+/// `snapshot_chunk`s followed by the `snapshot` itself with its content
+/// left out. Anything else passes through.
+pub fn split_snapshot(msg: ServerMsg, chunk_bytes: usize) -> Vec<ServerMsg> {
+    let (session_id, rev, content, presence, versions) = match msg {
+        ServerMsg::CompatSnapshot {
+            session_id,
+            rev,
+            content,
+            presence,
+            versions,
+            chunked: false,
+        } if content.len() > chunk_bytes => (session_id, rev, content, presence, versions),
+        msg => return vec![msg],
+    };
+    let total = content.len();
+    let checksum = content_hash(&content);
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < total {
+        let mut end = (offset + chunk_bytes).min(total);
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        // A chunk size below one character still has to make progress.
+        if end == offset {
+            end = offset + content[offset..].chars().next().map_or(1, char::len_utf8);
+        }
+        out.push(ServerMsg::SnapshotChunk {
+            session_id: session_id.clone(),
+            rev,
+            offset,
+            total,
+            data: content[offset..end].to_string(),
+            checksum: (end == total).then_some(checksum),
+        });
+        offset = end;
+    }
+    out.push(ServerMsg::CompatSnapshot {
+        session_id,
+        rev,
+        content: String::new(),
+        presence,
+        versions,
+        chunked: true,
+    });
+    out
+}
+
+/// The websocket frame for serialized `text`: zstd in a binary frame when
+/// it reaches `threshold`, plain text otherwise. A threshold of 0 never
+/// compresses.
+pub fn frame(text: String, threshold: usize) -> Message {
+    if threshold == 0 || text.len() < threshold {
+        return Message::Text(text);
+    }
+    match zstd::encode_all(text.as_bytes(), 0) {
+        Ok(bytes) => Message::Binary(bytes),
+        Err(_) => Message::Text(text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(content: &str) -> ServerMsg {
+        ServerMsg::CompatSnapshot {
+            session_id: "doc".into(),
+            rev: 3,
+            content: content.into(),
+            presence: None,
+            versions: Default::default(),
+            chunked: false,
+        }
+    }
+
+    #[test]
+    fn large_snapshots_are_chunked_on_char_boundaries() {
+        assert_eq!(
+            split_snapshot(snapshot("short"), 16),
+            vec![snapshot("short")]
+        );
+
+        let content = "あいうえおabc";
+        let msgs = split_snapshot(snapshot(content), 4);
+        let mut rebuilt = String::new();
+        for msg in &msgs[..msgs.len() - 1] {
+            let ServerMsg::SnapshotChunk {
+                offset,
+                total,
+                data,
+                checksum,
+                ..
+            } = msg
+            else {
+                panic!("expected a chunk, got {:?}", msg);
+            };
+            assert_eq!((*offset, *total), (rebuilt.len(), content.len()));
+            rebuilt.push_str(data);
+            assert_eq!(checksum.is_some(), rebuilt.len() == content.len());
+            if let Some(sum) = checksum {
+                assert_eq!(*sum, content_hash(content));
+            }
+        }
+        assert_eq!(rebuilt, content);
+        assert!(matches!(
+            msgs.last(),
+            Some(ServerMsg::CompatSnapshot { content, chunked: true, .. }) if content.is_empty()
+        ));
+    }
+
+    #[test]
+    fn only_frames_over_the_threshold_are_compressed() {
+        let text = "x".repeat(100);
+        assert_eq!(frame(text.clone(), 0), Message::Text(text.clone()));
+        assert_eq!(frame(text.clone(), 101), Message::Text(text.clone()));
+        let Message::Binary(bytes) = frame(text.clone(), 100) else {
+            panic!("frame was not compressed");
+        };
+        assert_eq!(zstd::decode_all(bytes.as_slice()).unwrap(), text.as_bytes());
+    }
+}
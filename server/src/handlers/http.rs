@@ -1,29 +1,302 @@
+use std::collections::BTreeMap;
+
 use axum::{
     Json,
-    extract::{Query, State},
-    http::{HeaderMap, StatusCode},
+    body::Bytes,
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
 };
-use serde::Deserialize;
-use tracing::error;
+use futures::{Stream, StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, warn};
+use uuid::Uuid;
 
 use crate::{
-    auth::{extract_password_from_headers, is_authorized},
-    state::{AppState, get_or_load_doc},
-    storage::{hash_password, persist_password_hash},
-    types::SnapshotResp,
+    access::Viewer,
+    analysis::{DiagnosticsResp, analyze_doc},
+    archive::{archive_doc, restore_doc},
+    auth::{basic_credentials, extract_password_from_headers, is_admin, is_authorized, is_owner},
+    bulk::{BulkAction, BulkSelector, start_bulk_job},
+    cluster::ClusterView,
+    conflicts::{ConflictsResp, DEFAULT_BUCKET_MS, DEFAULT_WINDOW_MS, MAX_BUCKETS, summarize},
+    connections::{ConnectionInfo, disconnect, list_connections},
+    content_type::{check_content_type, render as render_content, set_content_type},
+    disk::{DiskStatus, admit_write},
+    doc_settings::update_doc_settings,
+    document::content_hash,
+    erasure::{ErasureReport, erase_client},
+    expiry::record_expiry,
+    front_matter::{FrontMatter, stored_front_matter},
+    history::{HistoryArchive, export_history, import_history},
+    jobs::{Job, JobStatus, cancel_job, job_result, job_status, list_jobs, spawn_job},
+    links::{LinksResp, doc_links},
+    merge::{MergeReport, merge_docs},
+    metrics::LifecycleStats,
+    notifications::{NotificationsResp, clear_notifications, notifications_for},
+    presence::{PRESENCE_CACHE_SECS, client_presence, presence_list},
+    quota::{check_quota, workspace_usage},
+    reload::{ReloadReport, reload_config},
+    replace::{ReplaceReport, ReplaceSpec, replace_in_doc},
+    replay::{DEFAULT_MAX_GAP_MS, ReplayItem, spawn_replay},
+    retention::{RetentionReport, run_retention, run_retention_job},
+    review::{ReviewView, contents_at, review_html},
+    seen::unread_revisions,
+    state::{
+        AppState, OwnerClaim, Rejection, change_password, claim_ownership, create_new_doc,
+        get_existing_doc, get_or_load_doc, now_millis,
+    },
+    storage::{
+        blocking, hash_password, load_meta, load_password_hash, persist_meta,
+        persist_password_hash, slug_to_rel_path, write_snapshot,
+    },
+    tags::{
+        TagCount, TaggedDocsResp, TagsResp, all_tags, normalize_tag, tagged_docs as docs_with_tag,
+    },
+    ticket::{WsTicket, issue_ticket},
+    toc::{TocResp, toc as heading_tree},
+    transform::{TransformReport, transform_doc},
+    trash::{TrashEntry, TrashResp, list_trash, restore_trashed},
+    types::{ContentType, PresenceState, SnapshotResp, TextRange},
+    validation::ValidationRule,
+    workspace::{
+        WorkspaceSettings, list_workspace_docs, load_workspace, save_workspace, workspace_of,
+        workspace_settings_for,
+    },
 };
 
+#[derive(Deserialize)]
+struct SlugParam {
+    slug: Option<String>,
+}
+
+/// Logs every request turned away with 401 or 403 as an `auth_failed` event.
+pub async fn log_auth_failures(req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let slug = Query::<SlugParam>::try_from_uri(req.uri())
+        .ok()
+        .and_then(|Query(param)| param.slug);
+    let resp = next.run(req).await;
+    let status = resp.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        warn!(
+            event = "auth_failed",
+            slug,
+            %method,
+            %path,
+            status = status.as_u16(),
+            "request refused"
+        );
+    }
+    resp
+}
+
 #[derive(Deserialize)]
 pub struct SnapshotQuery {
     pub slug: String,
     pub password: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct ConflictsQuery {
+    pub slug: String,
+    pub password: Option<String>,
+    pub window_ms: Option<u64>,
+    pub bucket_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct DocQuery {
+    pub password: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct PasswordUpdateReq {
     pub slug: String,
     pub current_password: Option<String>,
     pub new_password: Option<String>,
+    pub owner_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveReq {
+    pub slug: String,
+    pub owner_token: Option<String>,
+    pub compress: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct OwnerClaimReq {
+    pub slug: String,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateDocReq {
+    pub slug: String,
+    pub password: Option<String>,
+    pub content: Option<String>,
+    pub content_type: Option<ContentType>,
+    /// See [`crate::expiry`]; must be in the future.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ContentTypeReq {
+    pub content_type: ContentType,
+    pub owner_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ErasureReq {
+    pub client_id: Uuid,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NotificationsQuery {
+    pub user_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct ReadNotificationsReq {
+    pub user_id: Uuid,
+    /// Only the entry of this document; all of them when left out.
+    #[serde(default)]
+    pub slug: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TrashRestoreReq {
+    pub id: Uuid,
+    /// Restore under another slug, when the old one was taken since.
+    #[serde(default)]
+    pub slug: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MergeReq {
+    pub source: String,
+    pub target: String,
+    pub source_password: Option<String>,
+    pub target_password: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ReplaceReq {
+    pub slug: String,
+    pub password: Option<String>,
+    pub find: String,
+    #[serde(default)]
+    pub replace: String,
+    #[serde(default)]
+    pub regex: bool,
+    #[serde(default)]
+    pub case_insensitive: bool,
+}
+
+#[derive(Deserialize)]
+pub struct TransformReq {
+    pub slug: String,
+    pub password: Option<String>,
+    pub transform: String,
+    /// The whole document when absent.
+    pub range: Option<TextRange>,
+    #[serde(default)]
+    pub preview: bool,
+}
+
+#[derive(Deserialize)]
+pub struct BulkReq {
+    #[serde(flatten)]
+    pub selector: BulkSelector,
+    #[serde(flatten)]
+    pub action: BulkAction,
+}
+
+#[derive(Deserialize)]
+pub struct WorkspaceUpdateReq {
+    #[serde(default)]
+    pub require_password: bool,
+    pub default_password: Option<String>,
+    #[serde(default)]
+    pub members: Vec<String>,
+    pub quota_bytes: Option<u64>,
+    #[serde(default)]
+    pub validation: Vec<ValidationRule>,
+}
+
+#[derive(Serialize)]
+pub struct WorkspaceResp {
+    pub workspace: String,
+    pub require_password: bool,
+    pub has_default_password: bool,
+    pub members: Vec<String>,
+    pub quota_bytes: Option<u64>,
+    pub validation: Vec<ValidationRule>,
+}
+
+impl WorkspaceResp {
+    fn new(workspace: String, settings: WorkspaceSettings) -> Self {
+        Self {
+            workspace,
+            require_password: settings.require_password,
+            has_default_password: settings.default_password_hash.is_some(),
+            members: settings.members,
+            quota_bytes: settings.quota_bytes,
+            validation: settings.validation,
+        }
+    }
+}
+
+/// Documents with a password of their own are left out unless the admin
+/// asks.
+#[derive(Serialize)]
+pub struct WorkspaceDocsResp {
+    pub workspace: String,
+    pub docs: Vec<String>,
+    pub content_types: BTreeMap<String, ContentType>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub front_matter: BTreeMap<String, FrontMatter>,
+    /// Revisions `user_id` has not seen, when the query names one.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub unread: BTreeMap<String, u64>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct WorkspaceDocsQuery {
+    pub user_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct WorkspaceUsage {
+    pub workspace: String,
+    pub used_bytes: u64,
+    pub quota_bytes: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct StatsResp {
+    pub loaded_docs: usize,
+    pub lifecycle: LifecycleStats,
+    pub workspaces: Vec<WorkspaceUsage>,
+    pub disk: DiskStatus,
+}
+
+#[derive(Serialize)]
+pub struct OwnerClaimResp {
+    pub slug: String,
+    pub owner_token: String,
 }
 
 pub async fn health() -> &'static str {
@@ -37,96 +310,2307 @@ pub async fn update_password(
     let slug = req.slug;
     let current = req.current_password.unwrap_or_default();
     let new_password = req.new_password.unwrap_or_default();
-    let doc = get_or_load_doc(&state, &slug).await.map_err(|err| {
+    let doc = get_existing_doc(&state, &slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug".to_string())
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found".to_string()))?;
+    let owner_token = req.owner_token.as_deref();
+    if let Err(err) = change_password(
+        &state,
+        &slug,
+        &doc,
+        &current,
+        &new_password,
+        owner_token,
+        None,
+    ) {
+        return Err(match err.downcast_ref::<Rejection>() {
+            Some(rejection) => {
+                let status = match rejection.code {
+                    "password_required" => StatusCode::BAD_REQUEST,
+                    "invalid_password" => StatusCode::UNAUTHORIZED,
+                    _ => StatusCode::FORBIDDEN,
+                };
+                (status, rejection.message.clone())
+            }
+            None => {
+                error!("failed to update password for '{}': {:#}", slug, err);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to update password".to_string(),
+                )
+            }
+        });
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Checks that whoever sent `headers` may create `slug` with `bytes` of
+/// content protected by `password`, whichever way the document is created:
+/// the workspace's password requirement and members, invite-only mode,
+/// free disk space and the workspace quota.
+pub(crate) fn admit_creation(
+    state: &AppState,
+    slug: &str,
+    headers: &HeaderMap,
+    password: Option<&str>,
+    bytes: u64,
+) -> Result<(), (StatusCode, &'static str)> {
+    if let Err(err) = slug_to_rel_path(slug) {
         error!("invalid slug '{}': {:#}", slug, err);
-        (StatusCode::BAD_REQUEST, "invalid slug".to_string())
-    })?;
-    let new_hash = {
-        let mut d = doc.write();
-        if let Some(expected) = d.password_hash.clone() {
-            if hash_password(&current) != expected {
-                return Err((
-                    StatusCode::UNAUTHORIZED,
-                    "invalid current password".to_string(),
-                ));
-            }
-        } else if !current.is_empty() {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                "invalid current password".to_string(),
-            ));
-        }
-        let new_hash_opt = if new_password.is_empty() {
-            None
-        } else {
-            Some(hash_password(&new_password))
-        };
-        d.password_hash = new_hash_opt.clone();
-        new_hash_opt
-    };
-    if let Err(err) = persist_password_hash(&state, &slug, new_hash.as_deref()) {
-        error!("failed to persist password: {:#}", err);
+        return Err((StatusCode::BAD_REQUEST, "invalid slug"));
+    }
+    let admin = is_admin(headers, state.admin_token.as_deref());
+    if let Ok(Some(ws)) = workspace_settings_for(state, slug) {
+        if password.is_none() && ws.require_password && ws.default_password_hash.is_none() {
+            return Err((StatusCode::BAD_REQUEST, "workspace requires a password"));
+        }
+        let credentials = basic_credentials(headers);
+        let (user, pass) = credentials
+            .as_ref()
+            .map(|(user, pass)| (Some(user.as_str()), Some(pass.as_str())))
+            .unwrap_or_default();
+        if !ws.members.is_empty() && !ws.admits(user, pass) && !admin {
+            return Err((StatusCode::UNAUTHORIZED, "workspace members only"));
+        }
+    }
+    if state.invite_only && !admin {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    if admit_write(state, slug, bytes, true).is_err() {
         return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "failed to persist password".to_string(),
+            StatusCode::INSUFFICIENT_STORAGE,
+            "server is low on disk space",
         ));
     }
-    Ok(StatusCode::NO_CONTENT)
+    if let Err(rejection) = check_quota(state, slug, bytes) {
+        error!(%slug, "document creation refused: {}", rejection);
+        return Err((StatusCode::INSUFFICIENT_STORAGE, "workspace quota exceeded"));
+    }
+    Ok(())
 }
 
-pub async fn get_snapshot(
+/// Creates `slug` once [`admit_creation`] let it through, and makes its
+/// creator the owner. Returns the owner token, or `None` when the document
+/// somehow got an owner first.
+pub(crate) async fn create_document(
+    state: &AppState,
+    slug: &str,
+    content: String,
+    password: Option<&str>,
+    content_type: Option<ContentType>,
+    expires_at: Option<u64>,
+) -> anyhow::Result<Option<String>> {
+    let password_hash = password.map(hash_password);
+    let doc = create_new_doc(state, slug, |d| {
+        d.content = content.clone();
+        d.password_hash = password_hash.clone();
+        d.meta.content_type = content_type;
+        d.meta.settings.expires_at = expires_at;
+    })?;
+    let meta = doc.read().meta.clone();
+    write_snapshot(state, slug, &content)?;
+    persist_password_hash(state, slug, password_hash.as_deref())?;
+    persist_meta(state, slug, &meta)?;
+    if expires_at.is_some() {
+        record_expiry(state, slug, expires_at);
+    }
+    claim_ownership(state, slug, OwnerClaim::Unowned).await
+}
+
+pub async fn create_doc(
     State(state): State<AppState>,
-    Query(q): Query<SnapshotQuery>,
     headers: HeaderMap,
-) -> Result<Json<SnapshotResp>, (StatusCode, &'static str)> {
-    let SnapshotQuery { slug, password } = q;
-    let doc = get_or_load_doc(&state, &slug).await.map_err(|err| {
-        error!("invalid slug '{}': {:#}", slug, err);
-        (StatusCode::BAD_REQUEST, "invalid slug")
-    })?;
-    let provided = password.or_else(|| extract_password_from_headers(&headers, &slug));
+    Json(req): Json<CreateDocReq>,
+) -> Result<(StatusCode, Json<OwnerClaimResp>), (StatusCode, &'static str)> {
+    let CreateDocReq {
+        slug,
+        password,
+        content,
+        content_type,
+        expires_at,
+    } = req;
+    if let Some(content_type) = &content_type
+        && check_content_type(content_type).is_err()
     {
-        let d = doc.read();
-        if !is_authorized(&d, provided.as_deref()) {
-            return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+        return Err((StatusCode::BAD_REQUEST, "invalid content type"));
+    }
+    if expires_at.is_some_and(|at| at <= now_millis()) {
+        return Err((StatusCode::BAD_REQUEST, "expiry must be in the future"));
+    }
+    let password = password.filter(|p| !p.is_empty());
+    let bytes = content.as_deref().map(str::len).unwrap_or(0) as u64;
+    admit_creation(&state, &slug, &headers, password.as_deref(), bytes)?;
+    let persisted = create_document(
+        &state,
+        &slug,
+        content.unwrap_or_default(),
+        password.as_deref(),
+        content_type,
+        expires_at,
+    )
+    .await;
+    match persisted {
+        Ok(Some(owner_token)) => Ok((
+            StatusCode::CREATED,
+            Json(OwnerClaimResp { slug, owner_token }),
+        )),
+        Ok(None) => Err((StatusCode::CONFLICT, "document already has an owner")),
+        Err(err) => match err.downcast_ref::<Rejection>().map(|r| r.code) {
+            Some("exists") => Err((StatusCode::CONFLICT, "document already exists")),
+            Some("password_required") => {
+                Err((StatusCode::BAD_REQUEST, "workspace requires a password"))
+            }
+            _ => {
+                error!("failed to create document '{}': {:#}", slug, err);
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "failed to create document",
+                ))
+            }
+        },
+    }
+}
+
+pub async fn claim_owner(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<OwnerClaimReq>,
+) -> Result<Json<OwnerClaimResp>, (StatusCode, &'static str)> {
+    let OwnerClaimReq { slug, password } = req;
+    let doc = get_existing_doc(&state, &slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    let provided = password.or_else(|| extract_password_from_headers(&headers, &slug));
+    if !is_authorized(&doc.read(), provided.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+    }
+    match claim_ownership(&state, &slug, OwnerClaim::Unowned).await {
+        Ok(Some(owner_token)) => Ok(Json(OwnerClaimResp { slug, owner_token })),
+        Ok(None) => Err((StatusCode::CONFLICT, "document already has an owner")),
+        Err(err) => {
+            error!("failed to persist owner for '{}': {:#}", slug, err);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to persist owner"))
         }
-        Ok(Json(SnapshotResp {
-            slug,
-            rev: d.rev,
-            content: d.content.clone(),
-        }))
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::document::Doc;
-    use crate::state::AppState;
-    use axum::extract::State as StateExtractor;
-    use axum::http::HeaderValue;
-    use base64::Engine;
-    use parking_lot::RwLock;
-    use std::fs;
-    use std::sync::Arc;
-    use uuid::Uuid;
+/// Reports what the configured retention policy would do right now.
+pub async fn retention_dry_run(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<RetentionReport>, (StatusCode, &'static str)> {
+    run_retention_now(&state, &headers, true).await
+}
+
+#[derive(Deserialize)]
+pub struct RetentionRunQuery {
+    /// Run as a job and answer with its id instead of waiting for the report.
+    #[serde(default)]
+    pub background: bool,
+}
+
+/// Applies the configured retention policy immediately.
+pub async fn retention_run(
+    State(state): State<AppState>,
+    Query(q): Query<RetentionRunQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
+    if !q.background {
+        return Ok(run_retention_now(&state, &headers, false)
+            .await?
+            .into_response());
+    }
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    let owned = state.clone();
+    let job = spawn_job(&state, "retention", 0, |job| async move {
+        let report = run_retention_job(&owned, &owned.retention, now_millis(), false, &job).await?;
+        Ok(Some(serde_json::to_value(report)?))
+    });
+    Ok((StatusCode::ACCEPTED, Json(job)).into_response())
+}
+
+async fn run_retention_now(
+    state: &AppState,
+    headers: &HeaderMap,
+    dry_run: bool,
+) -> Result<Json<RetentionReport>, (StatusCode, &'static str)> {
+    if !is_admin(headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    run_retention(state, &state.retention, now_millis(), dry_run)
+        .await
+        .map(Json)
+        .map_err(|err| {
+            error!("retention run failed: {:#}", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "retention run failed")
+        })
+}
+
+/// Re-reads the reloadable settings; live sessions stay connected.
+pub async fn reload(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ReloadReport>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    reload_config(&state).map(Json).map_err(|err| {
+        error!("config reload failed: {:#}", err);
+        (StatusCode::UNPROCESSABLE_ENTITY, "config reload failed")
+    })
+}
+
+/// Starts a bulk operation; poll `/api/admin/jobs/{id}` for its progress.
+pub async fn bulk(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<BulkReq>,
+) -> Result<(StatusCode, Json<Job>), (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    match start_bulk_job(&state, req.selector, req.action) {
+        Ok(job) => Ok((StatusCode::ACCEPTED, Json(job))),
+        Err(err) => match err.downcast_ref::<Rejection>().map(|r| r.code) {
+            Some("invalid_selector") => Err((StatusCode::BAD_REQUEST, "prefix or glob required")),
+            Some("invalid_pattern") => Err((StatusCode::BAD_REQUEST, "invalid pattern")),
+            _ => {
+                error!("bulk job failed to start: {:#}", err);
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "bulk job failed to start",
+                ))
+            }
+        },
+    }
+}
+
+pub async fn jobs(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Job>>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    Ok(Json(list_jobs(&state)))
+}
+
+pub async fn job(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Json<Job>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    job_status(&state, &id)
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "job not found"))
+}
+
+/// What a finished job produced, e.g. the archives of a bulk export.
+pub async fn job_output(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    let job = job_status(&state, &id).ok_or((StatusCode::NOT_FOUND, "job not found"))?;
+    if job.status == JobStatus::Running {
+        return Err((StatusCode::CONFLICT, "job is still running"));
+    }
+    job_result(&state, &id)
+        .map(|result| Json((*result).clone()))
+        .ok_or((StatusCode::NOT_FOUND, "job has no result"))
+}
+
+pub async fn job_cancel(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<Job>), (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    cancel_job(&state, &id)
+        .map(|job| (StatusCode::ACCEPTED, Json(job)))
+        .ok_or((StatusCode::NOT_FOUND, "job not found"))
+}
+
+#[derive(Deserialize)]
+pub struct ClusterQuery {
+    /// Also report which node owns this document.
+    pub slug: Option<String>,
+}
+
+pub async fn cluster(
+    State(state): State<AppState>,
+    Query(q): Query<ClusterQuery>,
+    headers: HeaderMap,
+) -> Result<Json<ClusterView>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    let cluster = state
+        .cluster
+        .as_ref()
+        .ok_or((StatusCode::NOT_FOUND, "not running as a cluster"))?;
+    Ok(Json(cluster.view(q.slug.as_deref())))
+}
+
+#[derive(Deserialize)]
+pub struct ConnectionsQuery {
+    pub slug: Option<String>,
+}
+
+/// WebSocket sessions on this node, the most backed up first.
+pub async fn connections(
+    State(state): State<AppState>,
+    Query(q): Query<ConnectionsQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ConnectionInfo>>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    Ok(Json(list_connections(&state, q.slug.as_deref())))
+}
+
+pub async fn disconnect_connection(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<ConnectionInfo>), (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    disconnect(&state, &id)
+        .map(|conn| (StatusCode::ACCEPTED, Json(conn)))
+        .ok_or((StatusCode::NOT_FOUND, "connection not found"))
+}
+
+/// Strips a client's identifying data from stored WALs and live presence.
+pub async fn erasure(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ErasureReq>,
+) -> Result<Json<ErasureReport>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    erase_client(&state, req.client_id, req.dry_run)
+        .await
+        .map(Json)
+        .map_err(|err| {
+            error!("erasure of '{}' failed: {:#}", req.client_id, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "erasure failed")
+        })
+}
+
+/// What is queued for `user_id` about documents the requester may open.
+pub async fn notifications(
+    State(state): State<AppState>,
+    Query(q): Query<NotificationsQuery>,
+    headers: HeaderMap,
+) -> Json<NotificationsResp> {
+    let viewer = Viewer::from_request(&state, &headers, None, None);
+    let mut notifications = Vec::new();
+    for notification in notifications_for(&state, q.user_id) {
+        if viewer.can_open(&state, &notification.slug).await {
+            notifications.push(notification);
+        }
+    }
+    Json(NotificationsResp { notifications })
+}
+
+pub async fn read_notifications(
+    State(state): State<AppState>,
+    Json(req): Json<ReadNotificationsReq>,
+) -> StatusCode {
+    clear_notifications(&state, req.user_id, req.slug.as_deref(), None);
+    StatusCode::NO_CONTENT
+}
+
+pub async fn trash(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<TrashResp>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    let items = list_trash(&state).map_err(|err| {
+        error!("listing the trash failed: {:#}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "listing the trash failed",
+        )
+    })?;
+    Ok(Json(TrashResp { items }))
+}
+
+pub async fn restore_trash(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<TrashRestoreReq>,
+) -> Result<Json<TrashEntry>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    if let Some(slug) = &req.slug
+        && slug_to_rel_path(slug).is_err()
+    {
+        return Err((StatusCode::BAD_REQUEST, "invalid slug"));
+    }
+    restore_trashed(&state, req.id, req.slug.as_deref())
+        .map(Json)
+        .map_err(|err| match err.downcast_ref::<Rejection>() {
+            Some(rejection) if rejection.code == "not_found" => {
+                (StatusCode::NOT_FOUND, "no such item in the trash")
+            }
+            Some(rejection) if rejection.code == "exists" => {
+                (StatusCode::CONFLICT, "a document with this slug exists")
+            }
+            _ => {
+                error!("restoring '{}' from the trash failed: {:#}", req.id, err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "restore failed")
+            }
+        })
+}
+
+pub async fn stats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<StatsResp>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    let mut names: Vec<String> = std::fs::read_dir(&state.snap_dir)
+        .into_iter()
+        .flatten()
+        .chain(std::fs::read_dir(&state.wal_dir).into_iter().flatten())
+        .flatten()
+        .filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    names.dedup();
+    let mut workspaces = Vec::with_capacity(names.len());
+    for ws in names {
+        let used_bytes = workspace_usage(&state, &ws).map_err(|err| {
+            error!("failed to measure workspace '{}': {:#}", ws, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to collect stats")
+        })?;
+        let quota_bytes = load_workspace(&state, &ws)
+            .ok()
+            .and_then(|settings| settings.quota_bytes);
+        workspaces.push(WorkspaceUsage {
+            workspace: ws,
+            used_bytes,
+            quota_bytes,
+        });
+    }
+    Ok(Json(StatsResp {
+        loaded_docs: state.docs.read().len(),
+        lifecycle: state.metrics.snapshot(),
+        workspaces,
+        disk: state.disk.status(),
+    }))
+}
+
+/// Loads the settings of `ws` for a request that has to be from one of its
+/// members, or the admin.
+fn load_workspace_for(
+    state: &AppState,
+    ws: &str,
+    headers: &HeaderMap,
+) -> Result<WorkspaceSettings, (StatusCode, &'static str)> {
+    let settings = load_workspace(state, ws).map_err(|err| {
+        error!("invalid workspace '{}': {:#}", ws, err);
+        (StatusCode::BAD_REQUEST, "invalid workspace")
+    })?;
+    let credentials = basic_credentials(headers);
+    let (user, password) = credentials
+        .as_ref()
+        .map(|(user, pass)| (Some(user.as_str()), Some(pass.as_str())))
+        .unwrap_or_default();
+    if !settings.admits(user, password) && !is_admin(headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+    }
+    Ok(settings)
+}
+
+pub async fn get_workspace(
+    State(state): State<AppState>,
+    Path(ws): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<WorkspaceResp>, (StatusCode, &'static str)> {
+    let settings = load_workspace_for(&state, &ws, &headers)?;
+    Ok(Json(WorkspaceResp::new(ws, settings)))
+}
+
+pub async fn update_workspace(
+    State(state): State<AppState>,
+    Path(ws): Path<String>,
+    headers: HeaderMap,
+    Json(req): Json<WorkspaceUpdateReq>,
+) -> Result<Json<WorkspaceResp>, (StatusCode, &'static str)> {
+    if !is_admin(&headers, state.admin_token.as_deref()) {
+        return Err((StatusCode::UNAUTHORIZED, "admin token required"));
+    }
+    let settings = WorkspaceSettings {
+        require_password: req.require_password,
+        default_password_hash: req
+            .default_password
+            .filter(|p| !p.is_empty())
+            .map(|p| hash_password(&p)),
+        members: req
+            .members
+            .into_iter()
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty())
+            .collect(),
+        quota_bytes: req.quota_bytes,
+        validation: req.validation,
+    };
+    // Members prove who they are with the default password.
+    if !settings.members.is_empty() && settings.default_password_hash.is_none() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "members need a default password",
+        ));
+    }
+    save_workspace(&state, &ws, &settings).map_err(|err| {
+        error!("failed to save workspace '{}': {:#}", ws, err);
+        (StatusCode::BAD_REQUEST, "invalid workspace")
+    })?;
+    Ok(Json(WorkspaceResp::new(ws, settings)))
+}
+
+pub async fn get_workspace_docs(
+    State(state): State<AppState>,
+    Path(ws): Path<String>,
+    Query(q): Query<WorkspaceDocsQuery>,
+    headers: HeaderMap,
+) -> Result<Json<WorkspaceDocsResp>, (StatusCode, &'static str)> {
+    load_workspace_for(&state, &ws, &headers)?;
+    let docs = list_workspace_docs(&state, &ws).map_err(|err| {
+        error!("failed to list workspace '{}': {:#}", ws, err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to list workspace",
+        )
+    })?;
+    let admin = is_admin(&headers, state.admin_token.as_deref());
+    let mut content_types = BTreeMap::new();
+    let mut front_matter = BTreeMap::new();
+    let mut unread = BTreeMap::new();
+    let mut listed = Vec::with_capacity(docs.len());
+    for slug in docs {
+        let loaded = state.docs.read().get(&slug).cloned();
+        let (content_type, protected) = match loaded {
+            Some(doc) => {
+                let d = doc.read();
+                (d.meta.content_type.clone(), d.password_hash.is_some())
+            }
+            None => (
+                load_meta(&state, &slug)
+                    .ok()
+                    .flatten()
+                    .and_then(|meta| meta.content_type),
+                !matches!(load_password_hash(&state, &slug), Ok(None)),
+            ),
+        };
+        if protected && !admin {
+            continue;
+        }
+        content_types.insert(slug.clone(), content_type.unwrap_or_default());
+        if let Ok(Some(front)) = stored_front_matter(&state, &slug) {
+            front_matter.insert(slug.clone(), front);
+        }
+        if let Some(user_id) = q.user_id
+            && let Ok(count) = unread_revisions(&state, &slug, user_id)
+        {
+            unread.insert(slug.clone(), count);
+        }
+        listed.push(slug);
+    }
+    Ok(Json(WorkspaceDocsResp {
+        workspace: ws,
+        docs: listed,
+        content_types,
+        front_matter,
+        unread,
+    }))
+}
+
+async fn authorize_owner_action(
+    state: &AppState,
+    headers: &HeaderMap,
+    slug: &str,
+    owner_token: Option<&str>,
+) -> Result<(), (StatusCode, &'static str)> {
+    if is_admin(headers, state.admin_token.as_deref()) {
+        return Ok(());
+    }
+    let doc = get_existing_doc(state, slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    if !is_owner(&doc.read(), owner_token) {
+        return Err((StatusCode::FORBIDDEN, "owner credentials required"));
+    }
+    Ok(())
+}
+
+fn archive_status(err: anyhow::Error, slug: &str) -> (StatusCode, &'static str) {
+    match err.downcast_ref::<Rejection>() {
+        Some(rejection) if rejection.code == "archived" => {
+            (StatusCode::CONFLICT, "document is already archived")
+        }
+        Some(rejection) if rejection.code == "not_archived" => {
+            (StatusCode::CONFLICT, "document is not archived")
+        }
+        Some(rejection) if rejection.code == "not_found" => {
+            (StatusCode::NOT_FOUND, "document not found")
+        }
+        _ => {
+            error!("archive operation failed for '{}': {:#}", slug, err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "archive operation failed",
+            )
+        }
+    }
+}
+
+pub async fn archive(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ArchiveReq>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    authorize_owner_action(&state, &headers, &req.slug, req.owner_token.as_deref()).await?;
+    let compress = req.compress.unwrap_or(state.archive_compress);
+    archive_doc(&state, &req.slug, compress)
+        .await
+        .map_err(|err| archive_status(err, &req.slug))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn restore(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ArchiveReq>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    authorize_owner_action(&state, &headers, &req.slug, req.owner_token.as_deref()).await?;
+    restore_doc(&state, &req.slug)
+        .await
+        .map_err(|err| archive_status(err, &req.slug))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_snapshot(
+    State(state): State<AppState>,
+    Query(q): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<Json<SnapshotResp>, (StatusCode, &'static str)> {
+    let SnapshotQuery { slug, password } = q;
+    let doc = get_existing_doc(&state, &slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    let provided = password.or_else(|| extract_password_from_headers(&headers, &slug));
+    {
+        let d = doc.read();
+        if !is_authorized(&d, provided.as_deref()) {
+            return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+        if d.meta.archived_at.is_some() {
+            return Err((StatusCode::GONE, "document is archived"));
+        }
+        Ok(Json(SnapshotResp {
+            slug,
+            rev: d.rev,
+            content: d.content.clone(),
+            content_type: d.meta.content_type.clone().unwrap_or_default(),
+            versions: d.versions.clone(),
+            content_hash: content_hash(&d.content),
+            chars: d.content.chars().count(),
+        }))
+    }
+}
+
+/// Strong validator for a snapshot: the revision alone is not enough, since
+/// a restore or import can reuse one with different content.
+pub fn snapshot_etag(rev: u64, hash: u32) -> String {
+    format!("\"{}-{:08x}\"", rev, hash)
+}
+
+/// `GET /api/snapshot`: [`get_snapshot`] with an `ETag`, answering `304` when
+/// `If-None-Match` already names the current content. Clients that joined
+/// with `lazy_snapshot` fetch the document body here.
+pub async fn snapshot(
+    State(state): State<AppState>,
+    Query(q): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let if_none_match = headers.get(header::IF_NONE_MATCH).cloned();
+    let Json(snapshot) = get_snapshot(State(state), Query(q), headers).await?;
+    let etag = snapshot_etag(snapshot.rev, snapshot.content_hash);
+    let cached = if_none_match
+        .as_ref()
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    // Documents may be password-protected: shared caches must not keep them,
+    // and every use revalidates.
+    let cache = [
+        (header::ETAG, etag),
+        (header::CACHE_CONTROL, "private, no-cache".to_string()),
+    ];
+    if cached {
+        return Ok((StatusCode::NOT_MODIFIED, cache).into_response());
+    }
+    Ok((cache, Json(snapshot)).into_response())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PresenceResp {
+    pub slug: String,
+    pub count: usize,
+    pub clients: Vec<PresenceState>,
+}
+
+/// `GET /api/presence`: who is in a document right now, for badges on
+/// listing pages that should not open a WebSocket per entry.
+pub async fn presence(
+    State(state): State<AppState>,
+    Query(q): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let SnapshotQuery { slug, password } = q;
+    let doc = get_existing_doc(&state, &slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    let viewer = Viewer::from_request(&state, &headers, Some(&slug), password.as_deref());
+    if !viewer.can_see(&state, &slug) {
+        return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+    }
+    if doc.read().meta.archived_at.is_some() {
+        return Err((StatusCode::GONE, "document is archived"));
+    }
+    let clients = presence_list(&state, &slug);
+    let cache = [(
+        header::CACHE_CONTROL,
+        format!("private, max-age={}", PRESENCE_CACHE_SECS),
+    )];
+    Ok((
+        cache,
+        Json(PresenceResp {
+            slug,
+            count: clients.len(),
+            clients,
+        }),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ClientPresenceQuery {
+    pub client_id: Uuid,
+    /// Only documents in this workspace.
+    pub workspace: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClientDoc {
+    pub slug: String,
+    pub presence: PresenceState,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClientPresenceResp {
+    pub client_id: Uuid,
+    pub docs: Vec<ClientDoc>,
+}
+
+/// `GET /api/presence/by-client`: the documents a client is active in.
+/// Password-protected documents are only listed for the admin.
+pub async fn presence_by_client(
+    State(state): State<AppState>,
+    Query(q): Query<ClientPresenceQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let viewer = Viewer::from_request(&state, &headers, None, None);
+    let docs = client_presence(&state, &q.client_id)
+        .into_iter()
+        .filter(|(slug, _)| {
+            q.workspace
+                .as_deref()
+                .is_none_or(|ws| workspace_of(slug) == Some(ws))
+        })
+        .filter(|(slug, _)| viewer.can_see(&state, slug))
+        .map(|(slug, presence)| ClientDoc { slug, presence })
+        .collect();
+    let cache = [(
+        header::CACHE_CONTROL,
+        format!("private, max-age={}", PRESENCE_CACHE_SECS),
+    )];
+    (
+        cache,
+        Json(ClientPresenceResp {
+            client_id: q.client_id,
+            docs,
+        }),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct WsTicketReq {
+    pub slug: String,
+}
+
+/// Trades the credentials in the `Authorization` header for a ticket that
+/// `/api/ws` accepts once.
+pub async fn ws_ticket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<WsTicketReq>,
+) -> Result<Json<WsTicket>, (StatusCode, &'static str)> {
+    let doc = get_existing_doc(&state, &req.slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", req.slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    let provided = extract_password_from_headers(&headers, &req.slug);
+    {
+        let d = doc.read();
+        if !is_admin(&headers, state.admin_token.as_deref())
+            && !is_authorized(&d, provided.as_deref())
+        {
+            return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+        if d.meta.archived_at.is_some() {
+            return Err((StatusCode::GONE, "document is archived"));
+        }
+    }
+    Ok(Json(issue_ticket(&state, &req.slug, now_millis())))
+}
+
+#[derive(Deserialize)]
+pub struct ReplayQuery {
+    pub slug: String,
+    /// Playback rate; 2.0 plays twice as fast as the edits were made.
+    pub speed: Option<f64>,
+    pub max_gap_ms: Option<u64>,
+    pub password: Option<String>,
+}
+
+/// Streams the history of a document as server-sent events: one `start` with
+/// the content playback begins from, an `edit` per revision spaced out by its
+/// original timing, then `end`.
+pub async fn replay(
+    State(state): State<AppState>,
+    Query(q): Query<ReplayQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, (StatusCode, &'static str)> {
+    let speed = q.speed.unwrap_or(1.0);
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err((StatusCode::BAD_REQUEST, "speed must be a positive number"));
+    }
+    let doc = get_existing_doc(&state, &q.slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", q.slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    let provided = q
+        .password
+        .or_else(|| extract_password_from_headers(&headers, &q.slug));
+    {
+        let d = doc.read();
+        if !is_authorized(&d, provided.as_deref())
+            && !is_admin(&headers, state.admin_token.as_deref())
+        {
+            return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+        if d.meta.archived_at.is_some() {
+            return Err((StatusCode::GONE, "document is archived"));
+        }
+    }
+    let slug = q.slug;
+    let items = spawn_replay(
+        state,
+        slug.clone(),
+        speed,
+        q.max_gap_ms.unwrap_or(DEFAULT_MAX_GAP_MS),
+    );
+    let events = ReceiverStream::new(items)
+        .map(move |item| match item {
+            Ok(ReplayItem::Start(start)) => Event::default().event("start").json_data(start),
+            Ok(ReplayItem::Edit(edit)) => Event::default().event("edit").json_data(edit),
+            Err(err) => {
+                error!("replay of '{}' failed: {:#}", slug, err);
+                Ok(Event::default().event("error").data("replay failed"))
+            }
+        })
+        .chain(stream::once(async {
+            Ok(Event::default().event("end").data(""))
+        }));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize)]
+pub struct ReviewQuery {
+    pub slug: String,
+    pub from: u64,
+    /// The current revision when left out.
+    pub to: Option<u64>,
+    #[serde(default)]
+    pub view: ReviewView,
+    pub password: Option<String>,
+}
+
+/// `GET /api/review`: the changes from revision `from` to `to` as an HTML
+/// diff of the rendered content, for reviewing a stretch of history.
+pub async fn review(
+    State(state): State<AppState>,
+    Query(q): Query<ReviewQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let doc = get_existing_doc(&state, &q.slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", q.slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    let provided = q
+        .password
+        .or_else(|| extract_password_from_headers(&headers, &q.slug));
+    let (rev, content_type) = {
+        let d = doc.read();
+        if !is_authorized(&d, provided.as_deref())
+            && !is_admin(&headers, state.admin_token.as_deref())
+        {
+            return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+        if d.meta.archived_at.is_some() {
+            return Err((StatusCode::GONE, "document is archived"));
+        }
+        (d.rev, d.meta.content_type.clone().unwrap_or_default())
+    };
+    let to = q.to.unwrap_or(rev);
+    if q.from > to || to > rev {
+        return Err((StatusCode::BAD_REQUEST, "invalid revision range"));
+    }
+    let (slug, from) = (q.slug, q.from);
+    let contents = {
+        let (state, slug) = (state.clone(), slug.clone());
+        blocking(move || contents_at(&state, &slug, from, to)).await
+    };
+    let (old, new) = contents.map_err(|err| match err.downcast_ref::<Rejection>() {
+        Some(_) => (StatusCode::GONE, "revision is no longer in the history"),
+        None => {
+            error!("review of '{}' failed: {:#}", slug, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "review failed")
+        }
+    })?;
+    let html = review_html(&content_type, &old, &new, q.view);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::CACHE_CONTROL, "private, no-cache"),
+        ],
+        html,
+    )
+        .into_response())
+}
+
+pub async fn render(
+    State(state): State<AppState>,
+    Query(q): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let Json(snapshot) = get_snapshot(State(state), Query(q), headers).await?;
+    let rendered = render_content(&snapshot.content_type, &snapshot.content);
+    Ok(([(header::CONTENT_TYPE, rendered.mime)], rendered.body).into_response())
+}
+
+/// Only Markdown documents have headings; anything else gets an empty tree.
+pub async fn toc(
+    State(state): State<AppState>,
+    Query(q): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<Json<TocResp>, (StatusCode, &'static str)> {
+    let Json(snapshot) = get_snapshot(State(state), Query(q), headers).await?;
+    let headings = match snapshot.content_type {
+        ContentType::Markdown => heading_tree(&snapshot.content),
+        _ => Vec::new(),
+    };
+    Ok(Json(TocResp {
+        slug: snapshot.slug,
+        rev: snapshot.rev,
+        headings,
+    }))
+}
+
+/// Where concurrent edits of a document collided lately. Needs the same
+/// access as `/api/snapshot`.
+pub async fn conflicts(
+    State(state): State<AppState>,
+    Query(q): Query<ConflictsQuery>,
+    headers: HeaderMap,
+) -> Result<Json<ConflictsResp>, (StatusCode, &'static str)> {
+    let window_ms = q.window_ms.unwrap_or(DEFAULT_WINDOW_MS);
+    let bucket_ms = q.bucket_ms.unwrap_or(DEFAULT_BUCKET_MS.min(window_ms));
+    if window_ms == 0 || bucket_ms == 0 || window_ms.div_ceil(bucket_ms) > MAX_BUCKETS {
+        return Err((StatusCode::BAD_REQUEST, "invalid window or bucket size"));
+    }
+    let snapshot = SnapshotQuery {
+        slug: q.slug,
+        password: q.password,
+    };
+    let Json(snapshot) = get_snapshot(State(state.clone()), Query(snapshot), headers).await?;
+    Ok(Json(summarize(
+        &state,
+        &snapshot.slug,
+        now_millis(),
+        window_ms,
+        bucket_ms,
+    )))
+}
+
+/// Incoming links from documents the requester could not open are left out.
+pub async fn links(
+    State(state): State<AppState>,
+    Query(q): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<Json<LinksResp>, (StatusCode, &'static str)> {
+    let password = q.password.clone();
+    let Json(snapshot) = get_snapshot(State(state.clone()), Query(q), headers.clone()).await?;
+    let slug = snapshot.slug;
+    let (outgoing, incoming) = doc_links(&state, &slug).map_err(|err| {
+        error!("failed to index links of '{}': {:#}", slug, err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "failed to index links")
+    })?;
+    let viewer = Viewer::from_request(&state, &headers, Some(&slug), password.as_deref());
+    let mut visible = Vec::new();
+    for source in incoming {
+        if viewer.can_open(&state, &source).await {
+            visible.push(source);
+        }
+    }
+    Ok(Json(LinksResp {
+        slug,
+        outgoing,
+        incoming: visible,
+    }))
+}
+
+/// What the analyzers last found in a document, as of its current
+/// revision. Needs the same access as `/api/snapshot`.
+pub async fn diagnostics(
+    State(state): State<AppState>,
+    Query(q): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<Json<DiagnosticsResp>, (StatusCode, &'static str)> {
+    let Json(snapshot) = get_snapshot(State(state.clone()), Query(q), headers).await?;
+    let doc = get_or_load_doc(&state, &snapshot.slug)
+        .await
+        .map_err(|err| {
+            error!("failed to load '{}': {:#}", snapshot.slug, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to load document")
+        })?;
+    let d = doc.read();
+    Ok(Json(DiagnosticsResp {
+        slug: snapshot.slug,
+        rev: d.rev,
+        diagnostics: d.diagnostics.clone(),
+    }))
+}
+
+/// Runs the analyzers on the document now instead of after the next flush.
+pub async fn run_diagnostics(
+    State(state): State<AppState>,
+    Query(q): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<Json<DiagnosticsResp>, (StatusCode, &'static str)> {
+    let Json(snapshot) = get_snapshot(State(state.clone()), Query(q), headers).await?;
+    let (rev, diagnostics) = analyze_doc(&state, &snapshot.slug).await.map_err(|err| {
+        error!("failed to analyze '{}': {:#}", snapshot.slug, err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to analyze document",
+        )
+    })?;
+    Ok(Json(DiagnosticsResp {
+        slug: snapshot.slug,
+        rev,
+        diagnostics,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct TaggedDocsQuery {
+    pub tag: String,
+}
+
+/// Tags of the documents the requester may open, with how many carry each.
+pub async fn tags(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<TagsResp>, (StatusCode, &'static str)> {
+    let all = all_tags(&state).map_err(|err| {
+        error!("failed to index tags: {:#}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "failed to index tags")
+    })?;
+    let viewer = Viewer::from_request(&state, &headers, None, None);
+    let mut tags = Vec::new();
+    for (tag, slugs) in all {
+        let mut docs = 0;
+        for slug in &slugs {
+            if viewer.can_open(&state, slug).await {
+                docs += 1;
+            }
+        }
+        if docs > 0 {
+            tags.push(TagCount { tag, docs });
+        }
+    }
+    Ok(Json(TagsResp { tags }))
+}
+
+/// `GET /api/docs?tag=...`: documents carrying `tag` that the requester may
+/// open.
+pub async fn tagged_docs(
+    State(state): State<AppState>,
+    Query(q): Query<TaggedDocsQuery>,
+    headers: HeaderMap,
+) -> Result<Json<TaggedDocsResp>, (StatusCode, &'static str)> {
+    let tag = normalize_tag(&q.tag).ok_or((StatusCode::BAD_REQUEST, "invalid tag"))?;
+    let slugs = docs_with_tag(&state, &tag).map_err(|err| {
+        error!("failed to index tags: {:#}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "failed to index tags")
+    })?;
+    let viewer = Viewer::from_request(&state, &headers, None, None);
+    let mut docs = Vec::new();
+    let mut front_matter = BTreeMap::new();
+    for slug in slugs {
+        if viewer.can_open(&state, &slug).await {
+            if let Ok(Some(front)) = stored_front_matter(&state, &slug) {
+                front_matter.insert(slug.clone(), front);
+            }
+            docs.push(slug);
+        }
+    }
+    Ok(Json(TaggedDocsResp {
+        tag,
+        docs,
+        front_matter,
+    }))
+}
+
+pub async fn merge(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<MergeReq>,
+) -> Result<Json<MergeReport>, (StatusCode, &'static str)> {
+    let admin = is_admin(&headers, state.admin_token.as_deref());
+    for (slug, password) in [
+        (&req.source, &req.source_password),
+        (&req.target, &req.target_password),
+    ] {
+        let doc = get_existing_doc(&state, slug)
+            .await
+            .map_err(|err| {
+                error!("invalid slug '{}': {:#}", slug, err);
+                (StatusCode::BAD_REQUEST, "invalid slug")
+            })?
+            .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+        let d = doc.read();
+        if !admin && !is_authorized(&d, password.as_deref()) {
+            return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+        if d.meta.archived_at.is_some() {
+            return Err((StatusCode::GONE, "document is archived"));
+        }
+    }
+    match merge_docs(&state, &req.source, &req.target, req.dry_run).await {
+        Ok(report) => Ok(Json(report)),
+        Err(err) => match err.downcast_ref::<Rejection>().map(|r| r.code) {
+            Some("same_doc") => Err((
+                StatusCode::BAD_REQUEST,
+                "cannot merge a document into itself",
+            )),
+            Some("busy") => Err((StatusCode::CONFLICT, "document changed during the merge")),
+            Some("too_large") => Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "documents without shared history are too large to merge",
+            )),
+            Some("quota_exceeded") => {
+                Err((StatusCode::INSUFFICIENT_STORAGE, "workspace quota exceeded"))
+            }
+            Some("disk_low") => Err((
+                StatusCode::INSUFFICIENT_STORAGE,
+                "server is low on disk space",
+            )),
+            _ => {
+                error!(
+                    "merge of '{}' into '{}' failed: {:#}",
+                    req.source, req.target, err
+                );
+                Err((StatusCode::INTERNAL_SERVER_ERROR, "merge failed"))
+            }
+        },
+    }
+}
+
+/// Status for a [`Rejection`] any write to a document can run into, or
+/// `None` when `err` is not a rejection. Callers match the codes only their
+/// own operation produces first.
+pub(crate) fn rejection_status(err: &anyhow::Error) -> Option<(StatusCode, &'static str)> {
+    let code = err.downcast_ref::<Rejection>()?.code;
+    Some(match code {
+        "password_required" => (StatusCode::BAD_REQUEST, "workspace requires a password"),
+        "exists" => (StatusCode::CONFLICT, "document already exists"),
+        "archived" => (StatusCode::GONE, "document is archived"),
+        "deleted" => (StatusCode::GONE, "document was deleted"),
+        "read_only" => (StatusCode::LOCKED, "document is locked"),
+        "expired" => (StatusCode::LOCKED, "document has expired"),
+        "degraded" => (StatusCode::SERVICE_UNAVAILABLE, "edits are paused"),
+        "quota_exceeded" => (StatusCode::INSUFFICIENT_STORAGE, "workspace quota exceeded"),
+        "disk_low" => (
+            StatusCode::INSUFFICIENT_STORAGE,
+            "server is low on disk space",
+        ),
+        "doc_too_large" => (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "document would exceed its size limit",
+        ),
+        _ => (StatusCode::UNPROCESSABLE_ENTITY, "edit was rejected"),
+    })
+}
+
+/// Replaces every match of `find` in one edit and reports how many there were.
+pub async fn replace(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ReplaceReq>,
+) -> Result<Json<ReplaceReport>, (StatusCode, &'static str)> {
+    let doc = get_existing_doc(&state, &req.slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", req.slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    let provided = req
+        .password
+        .clone()
+        .or_else(|| extract_password_from_headers(&headers, &req.slug));
+    {
+        let d = doc.read();
+        if !is_admin(&headers, state.admin_token.as_deref())
+            && !is_authorized(&d, provided.as_deref())
+        {
+            return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+        if d.meta.archived_at.is_some() {
+            return Err((StatusCode::GONE, "document is archived"));
+        }
+    }
+    let spec = ReplaceSpec {
+        find: req.find,
+        replace: req.replace,
+        regex: req.regex,
+        case_insensitive: req.case_insensitive,
+    };
+    match replace_in_doc(&state, &req.slug, &spec, None, None, None).await {
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(match err.downcast_ref::<Rejection>().map(|r| r.code) {
+            Some("invalid_pattern") => (StatusCode::BAD_REQUEST, "invalid pattern"),
+            _ => rejection_status(&err).unwrap_or_else(|| {
+                error!("replace in '{}' failed: {:#}", req.slug, err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "replace failed")
+            }),
+        }),
+    }
+}
+
+/// Runs a transform over a range of a document and applies it as one edit,
+/// or with `preview` only reports the ops.
+pub async fn transform(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<TransformReq>,
+) -> Result<Json<TransformReport>, (StatusCode, &'static str)> {
+    let doc = get_existing_doc(&state, &req.slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", req.slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    let provided = req
+        .password
+        .clone()
+        .or_else(|| extract_password_from_headers(&headers, &req.slug));
+    {
+        let d = doc.read();
+        if !is_admin(&headers, state.admin_token.as_deref())
+            && !is_authorized(&d, provided.as_deref())
+        {
+            return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+        if d.meta.archived_at.is_some() {
+            return Err((StatusCode::GONE, "document is archived"));
+        }
+    }
+    match transform_doc(&state, &req.slug, &req.transform, req.range, req.preview).await {
+        Ok(report) => Ok(Json(report)),
+        Err(err) => Err(match err.downcast_ref::<Rejection>().map(|r| r.code) {
+            Some("unknown_transform") => (StatusCode::NOT_FOUND, "unknown transform"),
+            Some("invalid_range") => (StatusCode::BAD_REQUEST, "range is outside the document"),
+            Some("transform_failed") => (StatusCode::BAD_GATEWAY, "transform service failed"),
+            _ => rejection_status(&err).unwrap_or_else(|| {
+                error!("transform of '{}' failed: {:#}", req.slug, err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "transform failed")
+            }),
+        }),
+    }
+}
+
+/// `/api/docs/{slug}/...` routes. Slugs contain `/`, so the router hands over
+/// the whole tail and the action is matched on its suffix.
+fn doc_action<'a>(path: &'a str, action: &str) -> Option<&'a str> {
+    path.strip_suffix(action)
+        .and_then(|slug| slug.strip_suffix('/'))
+        .filter(|slug| !slug.is_empty())
+}
+
+const DOC_ACTIONS: &[&str] = &[
+    "history/export",
+    "history/import",
+    "content-type",
+    "settings",
+];
+
+/// The slug of a `/api/docs/{slug}/{action}` path, without the prefix.
+pub(crate) fn doc_path_slug(path: &str) -> Option<&str> {
+    DOC_ACTIONS
+        .iter()
+        .find_map(|action| doc_action(path, action))
+}
+
+pub async fn doc_get(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    Query(q): Query<DocQuery>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
+    if let Some(slug) = doc_action(&path, "history/export") {
+        return export_doc_history(&state, slug, q.password, &headers).await;
+    }
+    Err((StatusCode::NOT_FOUND, "not found"))
+}
+
+pub async fn doc_post(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    Query(q): Query<DocQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, (StatusCode, &'static str)> {
+    if let Some(slug) = doc_action(&path, "history/import") {
+        return import_doc_history(&state, slug, q.password, &headers, &body).await;
+    }
+    if let Some(slug) = doc_action(&path, "content-type") {
+        return update_content_type(&state, slug, &headers, &body).await;
+    }
+    Err((StatusCode::NOT_FOUND, "not found"))
+}
+
+pub async fn doc_patch(
+    State(state): State<AppState>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, (StatusCode, &'static str)> {
+    if let Some(slug) = doc_action(&path, "settings") {
+        return patch_doc_settings(&state, slug, &headers, &body).await;
+    }
+    Err((StatusCode::NOT_FOUND, "not found"))
+}
+
+/// Merges the body into the document's settings; `owner_token` may ride
+/// along for owners without the admin token.
+async fn patch_doc_settings(
+    state: &AppState,
+    slug: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, (StatusCode, &'static str)> {
+    let mut patch: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(body).map_err(|_| (StatusCode::BAD_REQUEST, "invalid request"))?;
+    let owner_token = match patch.remove("owner_token") {
+        Some(serde_json::Value::String(token)) => Some(token),
+        _ => None,
+    };
+    authorize_owner_action(state, headers, slug, owner_token.as_deref()).await?;
+    match update_doc_settings(state, slug, patch).await {
+        Ok(settings) => Ok(Json(settings).into_response()),
+        Err(err) if err.downcast_ref::<Rejection>().is_some() => {
+            Err((StatusCode::UNPROCESSABLE_ENTITY, "invalid settings"))
+        }
+        Err(err) => {
+            error!("failed to update settings of '{}': {:#}", slug, err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to update settings",
+            ))
+        }
+    }
+}
+
+async fn update_content_type(
+    state: &AppState,
+    slug: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, (StatusCode, &'static str)> {
+    let req: ContentTypeReq =
+        serde_json::from_slice(body).map_err(|_| (StatusCode::BAD_REQUEST, "invalid request"))?;
+    authorize_owner_action(state, headers, slug, req.owner_token.as_deref()).await?;
+    match set_content_type(state, slug, req.content_type).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(err) if err.downcast_ref::<Rejection>().is_some() => {
+            Err((StatusCode::BAD_REQUEST, "invalid content type"))
+        }
+        Err(err) => {
+            error!("failed to set content type of '{}': {:#}", slug, err);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to set content type",
+            ))
+        }
+    }
+}
+
+async fn export_doc_history(
+    state: &AppState,
+    slug: &str,
+    password: Option<String>,
+    headers: &HeaderMap,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let doc = get_existing_doc(state, slug)
+        .await
+        .map_err(|err| {
+            error!("invalid slug '{}': {:#}", slug, err);
+            (StatusCode::BAD_REQUEST, "invalid slug")
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "document not found"))?;
+    let provided = password.or_else(|| extract_password_from_headers(headers, slug));
+    {
+        let d = doc.read();
+        if !is_authorized(&d, provided.as_deref())
+            && !is_admin(headers, state.admin_token.as_deref())
+        {
+            return Err((StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+        if d.meta.archived_at.is_some() {
+            return Err((StatusCode::GONE, "document is archived"));
+        }
+    }
+    let archive = export_history(state, slug).map_err(|err| {
+        error!("history export failed for '{}': {:#}", slug, err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "history export failed")
+    })?;
+    let filename = format!(
+        "attachment; filename=\"{}.coedit-history.json\"",
+        slug.replace('/', "-")
+    );
+    Ok(([(header::CONTENT_DISPOSITION, filename)], Json(archive)).into_response())
+}
+
+async fn import_doc_history(
+    state: &AppState,
+    slug: &str,
+    password: Option<String>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, (StatusCode, &'static str)> {
+    let archive: HistoryArchive = serde_json::from_slice(body)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "invalid history archive"))?;
+    let password = password.filter(|p| !p.is_empty());
+    admit_creation(
+        state,
+        slug,
+        headers,
+        password.as_deref(),
+        archive.content.len() as u64,
+    )?;
+    let imported = async {
+        import_history(state, slug, &archive).await?;
+        if let Some(password) = password.as_deref() {
+            let hash = hash_password(password);
+            persist_password_hash(state, slug, Some(&hash))?;
+            get_or_load_doc(state, slug).await?.write().password_hash = Some(hash);
+        }
+        claim_ownership(state, slug, OwnerClaim::Unowned).await
+    }
+    .await;
+    match imported {
+        Ok(Some(owner_token)) => Ok((
+            StatusCode::CREATED,
+            Json(OwnerClaimResp {
+                slug: slug.to_string(),
+                owner_token,
+            }),
+        )
+            .into_response()),
+        Ok(None) => Err((StatusCode::CONFLICT, "document already has an owner")),
+        Err(err) => match err.downcast_ref::<Rejection>().map(|r| r.code) {
+            Some("exists") => Err((StatusCode::CONFLICT, "document already exists")),
+            Some("invalid_history") => Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "history does not replay to its content",
+            )),
+            Some("unsupported_format") => Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "unsupported history format",
+            )),
+            Some("invalid_content_type") => {
+                Err((StatusCode::UNPROCESSABLE_ENTITY, "invalid content type"))
+            }
+            _ => {
+                error!("history import failed for '{}': {:#}", slug, err);
+                Err((StatusCode::INTERNAL_SERVER_ERROR, "history import failed"))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::field_reassign_with_default)]
+mod tests {
+    use super::*;
+    use crate::document::Doc;
+    use crate::state::AppState;
+    use axum::extract::State as StateExtractor;
+    use axum::http::HeaderValue;
+    use base64::Engine;
+    use parking_lot::RwLock;
+    use std::fs;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn mk_state(tmp: &std::path::Path) -> AppState {
+        let wal_dir = tmp.join("wal");
+        let snap_dir = tmp.join("snapshots");
+        fs::create_dir_all(&wal_dir).unwrap();
+        fs::create_dir_all(&snap_dir).unwrap();
+        AppState::new(wal_dir, snap_dir, 1_000, 128, true, Vec::new())
+    }
+
+    #[tokio::test]
+    async fn health_endpoint_returns_ok() {
+        assert_eq!(health().await, "ok");
+    }
+
+    #[tokio::test]
+    async fn ws_tickets_stand_in_for_the_password_once() {
+        let base = std::env::temp_dir().join(format!("http-ticket-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.require_ws_ticket = true;
+        let doc = Doc {
+            password_hash: Some(hash_password("pw")),
+            ..Default::default()
+        };
+        state
+            .docs
+            .write()
+            .insert("locked".into(), Arc::new(RwLock::new(doc)));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = crate::build_router(&state);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let req = || {
+            Json(WsTicketReq {
+                slug: "locked".into(),
+            })
+        };
+        let denied = ws_ticket(StateExtractor(state.clone()), HeaderMap::new(), req()).await;
+        assert!(matches!(denied, Err((StatusCode::UNAUTHORIZED, _))));
+        let mut headers = HeaderMap::new();
+        let token = base64::engine::general_purpose::STANDARD.encode("locked:pw");
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", token)).unwrap(),
+        );
+        let Json(ticket) = ws_ticket(StateExtractor(state.clone()), headers, req())
+            .await
+            .unwrap();
+
+        let url = |query: &str| format!("ws://{}/api/ws?slug=locked&{}", addr, query);
+        let with_ticket = url(&format!("ticket={}", ticket.ticket));
+        assert!(tokio_tungstenite::connect_async(&with_ticket).await.is_ok());
+        assert!(
+            tokio_tungstenite::connect_async(&with_ticket)
+                .await
+                .is_err()
+        );
+        assert!(
+            tokio_tungstenite::connect_async(url("password=pw"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_streams_start_edits_and_end() {
+        use http_body_util::BodyExt;
+        use tower::ServiceExt;
+
+        let base = std::env::temp_dir().join(format!("http-replay-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        for (i, text) in ["a", "b"].into_iter().enumerate() {
+            let edit = crate::types::Edit {
+                base_rev: i as u64,
+                ops: vec![crate::types::OpKind::Insert {
+                    pos: i,
+                    text: text.into(),
+                }],
+                client_id: None,
+                op_id: None,
+                cursor_before: None,
+                cursor_after: None,
+                ts: Some(1_000 + i as u64 * 60_000),
+                group_id: None,
+                user_id: None,
+            };
+            crate::state::apply_edit(&state, "tape", edit)
+                .await
+                .unwrap();
+        }
+        let app = crate::build_router(&state);
+        let resp = app
+            .clone()
+            .oneshot(
+                axum::http::Request::get("/api/replay?slug=tape&speed=1000")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let events: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("event: "))
+            .collect();
+        assert_eq!(events, vec!["start", "edit", "edit", "end"]);
+        assert!(body.contains(r#""rev":2"#));
+
+        let bad = app
+            .oneshot(
+                axum::http::Request::get("/api/replay?slug=tape&speed=0")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(bad.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn get_snapshot_enforces_password() {
+        let base = std::env::temp_dir().join(format!("http-snapshot-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "secure";
+        let mut doc = Doc::default();
+        doc.content = "secret text".into();
+        doc.password_hash = Some(hash_password("pw"));
+        state
+            .docs
+            .write()
+            .insert(slug.into(), Arc::new(RwLock::new(doc)));
+
+        let headers = HeaderMap::new();
+        let result = get_snapshot(
+            StateExtractor(state.clone()),
+            Query(SnapshotQuery {
+                slug: slug.into(),
+                password: None,
+            }),
+            headers,
+        )
+        .await;
+        assert!(matches!(result, Err((StatusCode::UNAUTHORIZED, _))));
+
+        let mut headers = HeaderMap::new();
+        let token = base64::engine::general_purpose::STANDARD.encode("secure:pw");
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", token)).unwrap(),
+        );
+        let ok = get_snapshot(
+            StateExtractor(state.clone()),
+            Query(SnapshotQuery {
+                slug: slug.into(),
+                password: None,
+            }),
+            headers,
+        )
+        .await
+        .expect("authorized");
+
+        assert_eq!(ok.0.slug, "secure");
+        assert_eq!(ok.0.content, "secret text");
+        assert_eq!(ok.0.content_hash, content_hash("secret text"));
+        assert_eq!(ok.0.chars, 11);
+    }
+
+    #[tokio::test]
+    async fn presence_lists_clients_with_a_short_cache() {
+        use http_body_util::BodyExt;
+        use tower::ServiceExt;
+
+        let base = std::env::temp_dir().join(format!("http-presence-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let doc = Doc {
+            password_hash: Some(hash_password("pw")),
+            ..Default::default()
+        };
+        state
+            .docs
+            .write()
+            .insert("room".into(), Arc::new(RwLock::new(doc)));
+        crate::presence::register_presence(&state, "room", Uuid::new_v4(), None, None, None, 1);
+        crate::presence::register_presence(&state, "room", Uuid::new_v4(), None, None, None, 2);
+        let app = crate::build_router(&state);
+        let get = |uri: &str| {
+            app.clone().oneshot(
+                axum::http::Request::get(uri)
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let denied = get("/api/presence?slug=room").await.unwrap();
+        assert_eq!(denied.status(), StatusCode::UNAUTHORIZED);
+        let resp = get("/api/presence?slug=room&password=pw").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "private, max-age=2");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let listed: PresenceResp = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listed.count, 2);
+        assert_eq!(listed.clients.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn presence_by_client_hides_protected_docs_from_non_admins() {
+        let base = std::env::temp_dir().join(format!("http-by-client-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.admin_token = Some("admin".into());
+        let locked = Doc {
+            password_hash: Some(hash_password("pw")),
+            ..Default::default()
+        };
+        state
+            .docs
+            .write()
+            .insert("team/open".into(), Arc::new(RwLock::new(Doc::default())));
+        state
+            .docs
+            .write()
+            .insert("team/locked".into(), Arc::new(RwLock::new(locked)));
+        let client = Uuid::new_v4();
+        for slug in ["team/open", "team/locked"] {
+            crate::presence::register_presence(&state, slug, client, None, None, None, 1);
+        }
+        let listed = |headers: HeaderMap| async {
+            let resp = presence_by_client(
+                StateExtractor(state.clone()),
+                Query(ClientPresenceQuery {
+                    client_id: client,
+                    workspace: Some("team".into()),
+                }),
+                headers,
+            )
+            .await;
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let resp: ClientPresenceResp = serde_json::from_slice(&body).unwrap();
+            resp.docs.into_iter().map(|d| d.slug).collect::<Vec<_>>()
+        };
+
+        assert_eq!(listed(HeaderMap::new()).await, vec!["team/open"]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer admin"),
+        );
+        assert_eq!(listed(headers).await, vec!["team/locked", "team/open"]);
+    }
+
+    #[tokio::test]
+    async fn snapshot_revalidates_with_its_etag() {
+        let base = std::env::temp_dir().join(format!("http-etag-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let doc = Doc {
+            rev: 4,
+            content: "cached".into(),
+            ..Default::default()
+        };
+        state
+            .docs
+            .write()
+            .insert("etag".into(), Arc::new(RwLock::new(doc)));
+        let fetch = |if_none_match: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(tag) = if_none_match {
+                headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(tag).unwrap());
+            }
+            snapshot(
+                StateExtractor(state.clone()),
+                Query(SnapshotQuery {
+                    slug: "etag".into(),
+                    password: None,
+                }),
+                headers,
+            )
+        };
+
+        let fresh = fetch(None).await.unwrap();
+        let etag = snapshot_etag(4, content_hash("cached"));
+        assert_eq!(fresh.status(), StatusCode::OK);
+        assert_eq!(fresh.headers()[header::ETAG], etag.as_str());
+        assert_eq!(
+            fetch(Some(&etag)).await.unwrap().status(),
+            StatusCode::NOT_MODIFIED
+        );
+        let stale = snapshot_etag(3, content_hash("cached"));
+        assert_eq!(fetch(Some(&stale)).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn update_password_validates_current_password() {
+        let base = std::env::temp_dir().join(format!("http-update-password-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "pw-doc";
+        let mut doc = Doc::default();
+        doc.password_hash = Some(hash_password("old"));
+        state
+            .docs
+            .write()
+            .insert(slug.into(), Arc::new(RwLock::new(doc)));
+
+        let resp = update_password(
+            StateExtractor(state.clone()),
+            Json(PasswordUpdateReq {
+                slug: slug.into(),
+                current_password: Some("wrong".into()),
+                new_password: Some("new".into()),
+                owner_token: None,
+            }),
+        )
+        .await;
+        assert!(matches!(resp, Err((StatusCode::UNAUTHORIZED, _))));
+
+        let resp = update_password(
+            StateExtractor(state.clone()),
+            Json(PasswordUpdateReq {
+                slug: slug.into(),
+                current_password: Some("old".into()),
+                new_password: Some("new".into()),
+                owner_token: None,
+            }),
+        )
+        .await
+        .expect("password updated");
+        assert_eq!(resp, StatusCode::NO_CONTENT);
+
+        let doc_arc = state.docs.read().get(slug).unwrap().clone();
+        let guard = doc_arc.read();
+        let expected = hash_password("new");
+        assert_eq!(guard.password_hash.as_deref(), Some(expected.as_str()));
+        drop(guard);
+        let path = crate::storage::password_path(&state, slug).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn update_password_requires_owner_for_initial_password() {
+        let base = std::env::temp_dir().join(format!("http-owner-password-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "owned-doc";
+        let req = |owner_token: Option<String>| {
+            Json(PasswordUpdateReq {
+                slug: slug.into(),
+                current_password: None,
+                new_password: Some("pw".into()),
+                owner_token,
+            })
+        };
+
+        let resp = update_password(StateExtractor(state.clone()), req(None)).await;
+        assert!(matches!(resp, Err((StatusCode::FORBIDDEN, _))));
+
+        let claimed = claim_owner(
+            StateExtractor(state.clone()),
+            HeaderMap::new(),
+            Json(OwnerClaimReq {
+                slug: slug.into(),
+                password: None,
+            }),
+        )
+        .await
+        .expect("claim succeeds");
+
+        let resp = update_password(StateExtractor(state.clone()), req(Some("bogus".into()))).await;
+        assert!(matches!(resp, Err((StatusCode::FORBIDDEN, _))));
+
+        let resp = update_password(
+            StateExtractor(state.clone()),
+            req(Some(claimed.0.owner_token.clone())),
+        )
+        .await
+        .expect("owner may set password");
+        assert_eq!(resp, StatusCode::NO_CONTENT);
+
+        let again = claim_owner(
+            StateExtractor(state.clone()),
+            HeaderMap::new(),
+            Json(OwnerClaimReq {
+                slug: slug.into(),
+                password: Some("pw".into()),
+            }),
+        )
+        .await;
+        assert!(matches!(again, Err((StatusCode::CONFLICT, _))));
+    }
+
+    #[tokio::test]
+    async fn invite_only_mode_requires_explicit_creation() {
+        let base = std::env::temp_dir().join(format!("http-invite-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.invite_only = true;
+        state.admin_token = Some("admin".into());
+        let slug = "invited";
+        let query = || {
+            Query(SnapshotQuery {
+                slug: slug.into(),
+                password: None,
+            })
+        };
+
+        let missing = get_snapshot(StateExtractor(state.clone()), query(), HeaderMap::new()).await;
+        assert!(matches!(missing, Err((StatusCode::NOT_FOUND, _))));
+
+        let req = || {
+            Json(CreateDocReq {
+                slug: slug.into(),
+                password: None,
+                content: Some("welcome".into()),
+                content_type: None,
+                expires_at: None,
+            })
+        };
+        let denied = create_doc(StateExtractor(state.clone()), HeaderMap::new(), req()).await;
+        assert!(matches!(denied, Err((StatusCode::UNAUTHORIZED, _))));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer admin"),
+        );
+        let (status, _) = create_doc(StateExtractor(state.clone()), headers.clone(), req())
+            .await
+            .expect("admin creates doc");
+        assert_eq!(status, StatusCode::CREATED);
+
+        let ok = get_snapshot(StateExtractor(state.clone()), query(), HeaderMap::new())
+            .await
+            .expect("doc now exists");
+        assert_eq!(ok.0.content, "welcome");
+
+        let dup = create_doc(StateExtractor(state.clone()), headers.clone(), req()).await;
+        assert!(matches!(dup, Err((StatusCode::CONFLICT, _))));
+
+        let with_password = Json(CreateDocReq {
+            slug: "sneaky".into(),
+            password: Some("pw".into()),
+            content: None,
+            content_type: None,
+            expires_at: None,
+        });
+        let denied = create_doc(
+            StateExtractor(state.clone()),
+            HeaderMap::new(),
+            with_password,
+        )
+        .await;
+        assert!(matches!(denied, Err((StatusCode::UNAUTHORIZED, _))));
+
+        let racing: Vec<_> = (0..8)
+            .map(|i| {
+                let state = state.clone();
+                let headers = headers.clone();
+                tokio::spawn(async move {
+                    let req = Json(CreateDocReq {
+                        slug: "raced".into(),
+                        password: None,
+                        content: Some(format!("writer {i}")),
+                        content_type: None,
+                        expires_at: None,
+                    });
+                    create_doc(StateExtractor(state), headers, req).await
+                })
+            })
+            .collect();
+        let mut created = 0;
+        for task in racing {
+            match task.await.unwrap() {
+                Ok((StatusCode::CREATED, _)) => created += 1,
+                Err((StatusCode::CONFLICT, _)) => {}
+                other => panic!("unexpected {:?}", other.map(|(status, _)| status)),
+            }
+        }
+        assert_eq!(created, 1);
+    }
+
+    #[tokio::test]
+    async fn workspace_default_password_applies_to_member_docs() {
+        let base = std::env::temp_dir().join(format!("http-workspace-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.admin_token = Some("admin".into());
+        let mut admin = HeaderMap::new();
+        admin.insert(
+            axum::http::header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer admin"),
+        );
+        fs::create_dir_all(state.snap_dir.join("team")).unwrap();
+        fs::write(state.snap_dir.join("team/spec.md"), "spec").unwrap();
+
+        let denied = update_workspace(
+            StateExtractor(state.clone()),
+            Path("team".into()),
+            HeaderMap::new(),
+            Json(WorkspaceUpdateReq {
+                require_password: false,
+                default_password: Some("teampw".into()),
+                members: vec![],
+                quota_bytes: None,
+                validation: vec![],
+            }),
+        )
+        .await;
+        assert!(matches!(denied, Err((StatusCode::UNAUTHORIZED, _))));
+        let nameless = update_workspace(
+            StateExtractor(state.clone()),
+            Path("team".into()),
+            admin.clone(),
+            Json(WorkspaceUpdateReq {
+                require_password: false,
+                default_password: None,
+                members: vec!["alice".into()],
+                quota_bytes: None,
+                validation: vec![],
+            }),
+        )
+        .await;
+        assert!(matches!(
+            nameless,
+            Err((StatusCode::UNPROCESSABLE_ENTITY, _))
+        ));
+
+        let saved = update_workspace(
+            StateExtractor(state.clone()),
+            Path("team".into()),
+            admin.clone(),
+            Json(WorkspaceUpdateReq {
+                require_password: false,
+                default_password: Some("teampw".into()),
+                members: vec![" alice ".into()],
+                quota_bytes: Some(1024),
+                validation: vec![],
+            }),
+        )
+        .await
+        .expect("admin updates workspace");
+        assert!(saved.0.has_default_password);
+        assert_eq!(saved.0.members, vec!["alice".to_string()]);
 
-    fn mk_state(tmp: &std::path::Path) -> AppState {
-        let wal_dir = tmp.join("wal");
-        let snap_dir = tmp.join("snapshots");
-        fs::create_dir_all(&wal_dir).unwrap();
-        fs::create_dir_all(&snap_dir).unwrap();
-        AppState::new(wal_dir, snap_dir, 1_000, 128, true, Vec::new())
+        let fetch = |user: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(user) = user {
+                let basic = base64::engine::general_purpose::STANDARD.encode(user);
+                headers.insert(
+                    axum::http::header::AUTHORIZATION,
+                    HeaderValue::from_str(&format!("Basic {basic}")).unwrap(),
+                );
+            }
+            get_workspace(StateExtractor(state.clone()), Path("team".into()), headers)
+        };
+        assert!(matches!(
+            fetch(None).await,
+            Err((StatusCode::UNAUTHORIZED, _))
+        ));
+        assert!(matches!(
+            fetch(Some("bob:teampw")).await,
+            Err((StatusCode::UNAUTHORIZED, _))
+        ));
+        assert!(matches!(
+            fetch(Some("alice:wrong")).await,
+            Err((StatusCode::UNAUTHORIZED, _))
+        ));
+        assert_eq!(
+            fetch(Some("Alice:teampw")).await.unwrap().0.quota_bytes,
+            Some(1024)
+        );
+        let create = |user: &str| {
+            let basic = base64::engine::general_purpose::STANDARD.encode(user);
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                axum::http::header::AUTHORIZATION,
+                HeaderValue::from_str(&format!("Basic {basic}")).unwrap(),
+            );
+            create_doc(
+                StateExtractor(state.clone()),
+                headers,
+                Json(CreateDocReq {
+                    slug: "team/new".into(),
+                    password: None,
+                    content: None,
+                    content_type: None,
+                    expires_at: None,
+                }),
+            )
+        };
+        assert!(matches!(
+            create("alice:").await,
+            Err((StatusCode::UNAUTHORIZED, _))
+        ));
+        assert_eq!(create("alice:teampw").await.unwrap().0, StatusCode::CREATED);
+
+        let snapshot = |password: Option<&str>| {
+            get_snapshot(
+                StateExtractor(state.clone()),
+                Query(SnapshotQuery {
+                    slug: "team/spec".into(),
+                    password: password.map(str::to_string),
+                }),
+                HeaderMap::new(),
+            )
+        };
+        assert!(matches!(
+            snapshot(None).await,
+            Err((StatusCode::UNAUTHORIZED, _))
+        ));
+        assert_eq!(snapshot(Some("teampw")).await.unwrap().0.content, "spec");
+
+        let listed = get_workspace_docs(
+            StateExtractor(state.clone()),
+            Path("team".into()),
+            Query(WorkspaceDocsQuery::default()),
+            admin,
+        )
+        .await
+        .expect("admin lists docs");
+        assert_eq!(listed.0.docs, vec!["team/new", "team/spec"]);
     }
 
     #[tokio::test]
-    async fn health_endpoint_returns_ok() {
-        assert_eq!(health().await, "ok");
+    async fn archive_requires_owner_and_hides_snapshot() {
+        let base = std::env::temp_dir().join(format!("http-archive-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "stale";
+        let token = claim_ownership(&state, slug, OwnerClaim::NewDocument)
+            .await
+            .unwrap()
+            .unwrap();
+        let req = |owner_token: Option<String>| {
+            Json(ArchiveReq {
+                slug: slug.into(),
+                owner_token,
+                compress: None,
+            })
+        };
+
+        let denied = archive(StateExtractor(state.clone()), HeaderMap::new(), req(None)).await;
+        assert!(matches!(denied, Err((StatusCode::FORBIDDEN, _))));
+        archive(
+            StateExtractor(state.clone()),
+            HeaderMap::new(),
+            req(Some(token.clone())),
+        )
+        .await
+        .expect("owner archives");
+
+        let snapshot = get_snapshot(
+            StateExtractor(state.clone()),
+            Query(SnapshotQuery {
+                slug: slug.into(),
+                password: None,
+            }),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(snapshot, Err((StatusCode::GONE, _))));
+
+        restore(
+            StateExtractor(state.clone()),
+            HeaderMap::new(),
+            req(Some(token)),
+        )
+        .await
+        .expect("owner restores");
     }
 
     #[tokio::test]
-    async fn get_snapshot_enforces_password() {
-        let base = std::env::temp_dir().join(format!("http-snapshot-{}", Uuid::new_v4()));
+    async fn get_snapshot_accepts_query_password() {
+        let base = std::env::temp_dir().join(format!("http-snapshot-q-{}", Uuid::new_v4()));
         fs::create_dir_all(&base).unwrap();
         let state = mk_state(&base);
         let slug = "secure";
@@ -139,28 +2623,11 @@ mod tests {
             .insert(slug.into(), Arc::new(RwLock::new(doc)));
 
         let headers = HeaderMap::new();
-        let result = get_snapshot(
-            StateExtractor(state.clone()),
-            Query(SnapshotQuery {
-                slug: slug.into(),
-                password: None,
-            }),
-            headers,
-        )
-        .await;
-        assert!(matches!(result, Err((StatusCode::UNAUTHORIZED, _))));
-
-        let mut headers = HeaderMap::new();
-        let token = base64::engine::general_purpose::STANDARD.encode("secure:pw");
-        headers.insert(
-            axum::http::header::AUTHORIZATION,
-            HeaderValue::from_str(&format!("Basic {}", token)).unwrap(),
-        );
         let ok = get_snapshot(
             StateExtractor(state.clone()),
             Query(SnapshotQuery {
                 slug: slug.into(),
-                password: None,
+                password: Some("pw".into()),
             }),
             headers,
         )
@@ -172,77 +2639,634 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn update_password_validates_current_password() {
-        let base = std::env::temp_dir().join(format!("http-update-password-{}", Uuid::new_v4()));
+    async fn merge_requires_access_to_both_docs() {
+        let base = std::env::temp_dir().join(format!("http-merge-{}", Uuid::new_v4()));
         fs::create_dir_all(&base).unwrap();
         let state = mk_state(&base);
-        let slug = "pw-doc";
-        let mut doc = Doc::default();
-        doc.password_hash = Some(hash_password("old"));
+        for (slug, content, password) in [("open", "a\n", None), ("locked", "b\n", Some("pw"))] {
+            let doc = Doc {
+                content: content.into(),
+                password_hash: password.map(hash_password),
+                ..Default::default()
+            };
+            state
+                .docs
+                .write()
+                .insert(slug.into(), Arc::new(RwLock::new(doc)));
+        }
+        let req = |password: Option<&str>| {
+            Json(MergeReq {
+                source: "open".into(),
+                target: "locked".into(),
+                source_password: None,
+                target_password: password.map(Into::into),
+                dry_run: true,
+            })
+        };
+
+        let denied = merge(StateExtractor(state.clone()), HeaderMap::new(), req(None)).await;
+        assert_eq!(denied.unwrap_err().0, StatusCode::UNAUTHORIZED);
+
+        let report = merge(
+            StateExtractor(state.clone()),
+            HeaderMap::new(),
+            req(Some("pw")),
+        )
+        .await
+        .expect("authorized");
+        assert!(!report.0.applied);
+        assert_eq!(report.0.conflicts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn replace_needs_access_and_rejects_bad_patterns() {
+        let base = std::env::temp_dir().join(format!("http-replace-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let doc = Doc {
+            content: "colour and colour".into(),
+            password_hash: Some(hash_password("pw")),
+            ..Default::default()
+        };
         state
             .docs
             .write()
-            .insert(slug.into(), Arc::new(RwLock::new(doc)));
+            .insert("locked".into(), Arc::new(RwLock::new(doc)));
+        let req = |password: Option<&str>, find: &str| {
+            Json(ReplaceReq {
+                slug: "locked".into(),
+                password: password.map(Into::into),
+                find: find.into(),
+                replace: "color".into(),
+                regex: true,
+                case_insensitive: false,
+            })
+        };
 
-        let resp = update_password(
+        let denied = replace(
             StateExtractor(state.clone()),
-            Json(PasswordUpdateReq {
-                slug: slug.into(),
-                current_password: Some("wrong".into()),
-                new_password: Some("new".into()),
-            }),
+            HeaderMap::new(),
+            req(None, "colour"),
         )
         .await;
-        assert!(matches!(resp, Err((StatusCode::UNAUTHORIZED, _))));
+        assert_eq!(denied.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        let invalid = replace(
+            StateExtractor(state.clone()),
+            HeaderMap::new(),
+            req(Some("pw"), "colo(u"),
+        )
+        .await;
+        assert_eq!(invalid.unwrap_err().0, StatusCode::BAD_REQUEST);
 
-        let resp = update_password(
+        let report = replace(
             StateExtractor(state.clone()),
-            Json(PasswordUpdateReq {
+            HeaderMap::new(),
+            req(Some("pw"), "colou?r"),
+        )
+        .await
+        .expect("authorized");
+        assert_eq!(report.0.matches, 2);
+        let doc = get_or_load_doc(&state, "locked").await.unwrap();
+        assert_eq!(doc.read().content, "color and color");
+    }
+
+    #[tokio::test]
+    async fn bulk_jobs_are_admin_only_and_pollable() {
+        let base = std::env::temp_dir().join(format!("http-bulk-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.admin_token = Some("admin".into());
+        let req = || {
+            let body = r#"{"glob":"team/*","action":"replace","find":"a","replace":"b"}"#;
+            Json(serde_json::from_str::<BulkReq>(body).unwrap())
+        };
+
+        let denied = bulk(StateExtractor(state.clone()), HeaderMap::new(), req()).await;
+        assert_eq!(denied.unwrap_err().0, StatusCode::UNAUTHORIZED);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer admin"),
+        );
+        let (status, Json(job)) = bulk(StateExtractor(state.clone()), headers.clone(), req())
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(job.kind, "bulk_replace");
+        let polled = super::job(StateExtractor(state.clone()), Path(job.id), headers.clone())
+            .await
+            .unwrap();
+        assert_eq!(polled.0.id, job.id);
+        let cancelled = job_cancel(StateExtractor(state.clone()), Path(job.id), headers.clone())
+            .await
+            .unwrap();
+        assert_eq!(cancelled.0, StatusCode::ACCEPTED);
+        let listed = jobs(StateExtractor(state), headers).await.unwrap();
+        assert_eq!(listed.0.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn toc_lists_markdown_headings_only() {
+        let base = std::env::temp_dir().join(format!("http-toc-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let create = |slug: &str, content_type| {
+            Json(CreateDocReq {
                 slug: slug.into(),
-                current_password: Some("old".into()),
-                new_password: Some("new".into()),
-            }),
+                password: None,
+                content: Some("# Top\n## Sub\n".into()),
+                content_type: Some(content_type),
+                expires_at: None,
+            })
+        };
+        for (slug, content_type) in [
+            ("outline", ContentType::Markdown),
+            ("plain", ContentType::Plaintext),
+        ] {
+            let _ = create_doc(
+                StateExtractor(state.clone()),
+                HeaderMap::new(),
+                create(slug, content_type),
+            )
+            .await
+            .expect("doc created");
+        }
+        let query = |slug: &str| {
+            Query(SnapshotQuery {
+                slug: slug.into(),
+                password: None,
+            })
+        };
+        let resp = toc(
+            StateExtractor(state.clone()),
+            query("outline"),
+            HeaderMap::new(),
         )
         .await
-        .expect("password updated");
-        assert_eq!(resp, StatusCode::NO_CONTENT);
+        .unwrap();
+        assert_eq!(resp.0.headings.len(), 1);
+        let sub = &resp.0.headings[0].children[0];
+        assert_eq!((sub.text.as_str(), sub.start, sub.end), ("Sub", 6, 13));
+        let plain = toc(StateExtractor(state), query("plain"), HeaderMap::new())
+            .await
+            .unwrap();
+        assert!(plain.0.headings.is_empty());
+    }
 
-        let doc_arc = state.docs.read().get(slug).unwrap().clone();
-        let guard = doc_arc.read();
-        let expected = hash_password("new");
-        assert_eq!(guard.password_hash.as_deref(), Some(expected.as_str()));
-        drop(guard);
-        let path = crate::storage::password_path(&state, slug).unwrap();
-        assert_eq!(fs::read_to_string(path).unwrap(), expected);
+    #[tokio::test]
+    async fn links_list_backlinks_the_requester_may_open() {
+        let base = std::env::temp_dir().join(format!("http-links-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        for (slug, password, content) in [
+            ("wiki/home", None, "[[wiki/faq]] and [guide](guide.md)"),
+            ("wiki/secret", Some("pw"), "[[wiki/home]]"),
+            ("wiki/faq", None, ""),
+        ] {
+            let _ = create_doc(
+                StateExtractor(state.clone()),
+                HeaderMap::new(),
+                Json(CreateDocReq {
+                    slug: slug.into(),
+                    password: password.map(str::to_string),
+                    content: Some(content.into()),
+                    content_type: None,
+                    expires_at: None,
+                }),
+            )
+            .await
+            .expect("doc created");
+        }
+        let links_of = |slug: &str, password: Option<&str>| {
+            links(
+                StateExtractor(state.clone()),
+                Query(SnapshotQuery {
+                    slug: slug.into(),
+                    password: password.map(str::to_string),
+                }),
+                HeaderMap::new(),
+            )
+        };
+        let home = links_of("wiki/home", None).await.unwrap().0;
+        assert_eq!(home.outgoing, vec!["wiki/faq", "wiki/guide"]);
+        assert!(home.incoming.is_empty());
+        let faq = links_of("wiki/faq", None).await.unwrap().0;
+        assert_eq!(faq.incoming, vec!["wiki/home"]);
+
+        let edit = crate::types::Edit {
+            base_rev: 0,
+            ops: vec![crate::types::OpKind::Insert {
+                pos: 0,
+                text: "[[wiki/home]] ".into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        crate::state::apply_edit(&state, "wiki/faq", edit)
+            .await
+            .unwrap();
+        crate::storage::flush_snapshot_force(&state, "wiki/faq")
+            .await
+            .unwrap();
+        let home = links_of("wiki/home", None).await.unwrap().0;
+        assert_eq!(home.incoming, vec!["wiki/faq"]);
+        let secret = links_of("wiki/secret", Some("pw")).await.unwrap().0;
+        assert_eq!(secret.outgoing, vec!["wiki/home"]);
     }
 
     #[tokio::test]
-    async fn get_snapshot_accepts_query_password() {
-        let base = std::env::temp_dir().join(format!("http-snapshot-q-{}", Uuid::new_v4()));
+    async fn tags_list_documents_the_requester_may_open() {
+        let base = std::env::temp_dir().join(format!("http-tags-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.admin_token = Some("admin".into());
+        for (slug, password, content) in [
+            ("notes/a", None, "---\ntags: [Plan]\n---\n#idea"),
+            ("notes/b", None, "more #plan"),
+            ("notes/c", Some("pw"), "#plan #secret"),
+        ] {
+            let _ = create_doc(
+                StateExtractor(state.clone()),
+                HeaderMap::new(),
+                Json(CreateDocReq {
+                    slug: slug.into(),
+                    password: password.map(str::to_string),
+                    content: Some(content.into()),
+                    content_type: None,
+                    expires_at: None,
+                }),
+            )
+            .await
+            .expect("doc created");
+        }
+        let listed = tags(StateExtractor(state.clone()), HeaderMap::new())
+            .await
+            .unwrap();
+        let counts: Vec<(&str, usize)> = listed
+            .0
+            .tags
+            .iter()
+            .map(|t| (t.tag.as_str(), t.docs))
+            .collect();
+        assert_eq!(counts, vec![("idea", 1), ("plan", 2)]);
+
+        let query = || {
+            Query(TaggedDocsQuery {
+                tag: "#Plan".into(),
+            })
+        };
+        let plan = tagged_docs(StateExtractor(state.clone()), query(), HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(plan.0.tag, "plan");
+        assert_eq!(plan.0.docs, vec!["notes/a", "notes/b"]);
+        let mut admin = HeaderMap::new();
+        admin.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer admin"),
+        );
+        let plan = tagged_docs(StateExtractor(state.clone()), query(), admin)
+            .await
+            .unwrap();
+        assert_eq!(plan.0.docs, vec!["notes/a", "notes/b", "notes/c"]);
+        let invalid = tagged_docs(
+            StateExtractor(state),
+            Query(TaggedDocsQuery { tag: "a b".into() }),
+            HeaderMap::new(),
+        )
+        .await;
+        assert!(matches!(invalid, Err((StatusCode::BAD_REQUEST, _))));
+    }
+
+    #[tokio::test]
+    async fn workspace_listing_carries_front_matter_from_the_last_flush() {
+        let base = std::env::temp_dir().join(format!("http-front-matter-{}", Uuid::new_v4()));
         fs::create_dir_all(&base).unwrap();
         let state = mk_state(&base);
-        let slug = "secure";
-        let mut doc = Doc::default();
-        doc.content = "secret text".into();
-        doc.password_hash = Some(hash_password("pw"));
-        state
-            .docs
-            .write()
-            .insert(slug.into(), Arc::new(RwLock::new(doc)));
+        for (slug, password) in [("kb/open", None), ("kb/closed", Some("pw"))] {
+            let _ = create_doc(
+                StateExtractor(state.clone()),
+                HeaderMap::new(),
+                Json(CreateDocReq {
+                    slug: slug.into(),
+                    password: password.map(str::to_string),
+                    content: Some("---\ntitle: Draft\n---\nbody".into()),
+                    content_type: None,
+                    expires_at: None,
+                }),
+            )
+            .await
+            .expect("doc created");
+        }
+        let list = || {
+            get_workspace_docs(
+                StateExtractor(state.clone()),
+                Path("kb".into()),
+                Query(WorkspaceDocsQuery::default()),
+                HeaderMap::new(),
+            )
+        };
+        let listed = list().await.unwrap().0;
+        assert_eq!(
+            listed.front_matter["kb/open"].title.as_deref(),
+            Some("Draft")
+        );
+        assert!(!listed.front_matter.contains_key("kb/closed"));
+        assert_eq!(listed.docs, vec!["kb/open".to_string()]);
+        assert!(!listed.content_types.contains_key("kb/closed"));
 
-        let headers = HeaderMap::new();
-        let ok = get_snapshot(
+        let edit = crate::types::Edit {
+            base_rev: 0,
+            ops: vec![
+                crate::types::OpKind::Delete { pos: 11, len: 5 },
+                crate::types::OpKind::Insert {
+                    pos: 11,
+                    text: "Final\nauthor: ann".into(),
+                },
+            ],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        crate::state::apply_edit(&state, "kb/open", edit)
+            .await
+            .unwrap();
+        let unflushed = list().await.unwrap().0;
+        assert_eq!(
+            unflushed.front_matter["kb/open"].title.as_deref(),
+            Some("Draft")
+        );
+        crate::storage::flush_snapshot_force(&state, "kb/open")
+            .await
+            .unwrap();
+        let front = list()
+            .await
+            .unwrap()
+            .0
+            .front_matter
+            .remove("kb/open")
+            .unwrap();
+        assert_eq!(front.title.as_deref(), Some("Final"));
+        assert_eq!(front.authors, vec!["ann"]);
+
+        let ann = Uuid::new_v4();
+        let unread = || async {
+            get_workspace_docs(
+                StateExtractor(state.clone()),
+                Path("kb".into()),
+                Query(WorkspaceDocsQuery { user_id: Some(ann) }),
+                HeaderMap::new(),
+            )
+            .await
+            .unwrap()
+            .0
+            .unread
+        };
+        assert_eq!(unread().await, BTreeMap::from([("kb/open".to_string(), 1)]));
+        crate::seen::mark_seen(&state, "kb/open", ann, 1)
+            .await
+            .unwrap();
+        assert_eq!(unread().await, BTreeMap::from([("kb/open".to_string(), 0)]));
+    }
+
+    #[tokio::test]
+    async fn content_type_drives_snapshot_listing_and_render() {
+        let base = std::env::temp_dir().join(format!("http-content-type-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "team/config";
+        let create = |content_type| {
+            Json(CreateDocReq {
+                slug: slug.into(),
+                password: None,
+                content: Some("{\"a\":1}".into()),
+                content_type: Some(content_type),
+                expires_at: None,
+            })
+        };
+        let invalid = create_doc(
+            StateExtractor(state.clone()),
+            HeaderMap::new(),
+            create(ContentType::Code {
+                lang: "<script>".into(),
+            }),
+        )
+        .await;
+        assert!(matches!(invalid, Err((StatusCode::BAD_REQUEST, _))));
+        let (_, owner) = create_doc(
             StateExtractor(state.clone()),
+            HeaderMap::new(),
+            create(ContentType::Json),
+        )
+        .await
+        .expect("doc created");
+        fs::write(state.snap_dir.join("team/notes.md"), "notes").unwrap();
+
+        let query = || {
             Query(SnapshotQuery {
                 slug: slug.into(),
-                password: Some("pw".into()),
+                password: None,
+            })
+        };
+        let snap = get_snapshot(StateExtractor(state.clone()), query(), HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(snap.0.content_type, ContentType::Json);
+        let listed = get_workspace_docs(
+            StateExtractor(state.clone()),
+            Path("team".into()),
+            Query(WorkspaceDocsQuery::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(listed.0.content_types[slug], ContentType::Json);
+        assert_eq!(listed.0.content_types["team/notes"], ContentType::Markdown);
+
+        let rendered = render(StateExtractor(state.clone()), query(), HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            rendered.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+
+        let update = |owner_token: Option<&str>| {
+            let body = serde_json::json!({
+                "content_type": { "kind": "code", "lang": "rust" },
+                "owner_token": owner_token,
+            });
+            doc_post(
+                StateExtractor(state.clone()),
+                Path(format!("{}/content-type", slug)),
+                Query(DocQuery { password: None }),
+                HeaderMap::new(),
+                Bytes::from(body.to_string()),
+            )
+        };
+        assert!(matches!(
+            update(None).await,
+            Err((StatusCode::FORBIDDEN, _))
+        ));
+        let updated = update(Some(&owner.0.owner_token)).await.unwrap();
+        assert_eq!(updated.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            load_meta(&state, slug).unwrap().unwrap().content_type,
+            Some(ContentType::Code {
+                lang: "rust".into()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn doc_settings_are_patched_and_enforced() {
+        use crate::{
+            state::apply_edit,
+            types::{Edit, OpKind},
+        };
+
+        let base = std::env::temp_dir().join(format!("http-doc-settings-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "capped";
+        let (_, owner) = create_doc(
+            StateExtractor(state.clone()),
+            HeaderMap::new(),
+            Json(CreateDocReq {
+                slug: slug.into(),
+                password: None,
+                content: None,
+                content_type: None,
+                expires_at: None,
             }),
-            headers,
         )
         .await
-        .expect("authorized");
+        .expect("doc created");
+        let patch = |body: serde_json::Value| {
+            doc_patch(
+                StateExtractor(state.clone()),
+                Path(format!("{}/settings", slug)),
+                HeaderMap::new(),
+                Bytes::from(body.to_string()),
+            )
+        };
+        let token = owner.0.owner_token.clone();
+        assert!(matches!(
+            patch(serde_json::json!({"max_bytes": 4})).await,
+            Err((StatusCode::FORBIDDEN, _))
+        ));
+        assert!(matches!(
+            patch(serde_json::json!({"max_bytes": -1, "owner_token": token})).await,
+            Err((StatusCode::UNPROCESSABLE_ENTITY, _))
+        ));
+        let ok = patch(serde_json::json!({
+            "max_bytes": 4,
+            "flush_max_ops": 1,
+            "owner_token": token,
+        }))
+        .await
+        .unwrap();
+        assert_eq!(ok.status(), StatusCode::OK);
+        let settings = load_meta(&state, slug).unwrap().unwrap().settings;
+        assert_eq!(
+            (settings.max_bytes, settings.flush_max_ops),
+            (Some(4), Some(1))
+        );
 
-        assert_eq!(ok.0.slug, "secure");
-        assert_eq!(ok.0.content, "secret text");
+        let insert = |base_rev, text: &str| Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(&state, slug, insert(0, "abcd")).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(state.snap_dir.join("capped.md")).unwrap(),
+            "abcd"
+        );
+        let err = apply_edit(&state, slug, insert(1, "e")).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Rejection>().unwrap().code,
+            "doc_too_large"
+        );
+
+        patch(serde_json::json!({"max_bytes": null, "owner_token": token}))
+            .await
+            .unwrap();
+        apply_edit(&state, slug, insert(1, "e")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn notifications_hide_protected_docs_and_can_be_read() {
+        let base = std::env::temp_dir().join(format!("http-notifications-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let user_id = Uuid::new_v4();
+        for (slug, password) in [("open", None), ("locked", Some("pw"))] {
+            let doc = Doc {
+                content: "text".into(),
+                password_hash: password.map(hash_password),
+                ..Default::default()
+            };
+            state
+                .docs
+                .write()
+                .insert(slug.into(), Arc::new(RwLock::new(doc)));
+            state
+                .notifications
+                .write()
+                .entry(user_id)
+                .or_default()
+                .push(crate::types::Notification {
+                    slug: slug.into(),
+                    since_rev: 0,
+                    rev: 1,
+                    edits: 1,
+                    editors: Vec::new(),
+                    mentions: Vec::new(),
+                    first_at: 0,
+                    last_at: 0,
+                });
+        }
+        let list = || {
+            notifications(
+                StateExtractor(state.clone()),
+                Query(NotificationsQuery { user_id }),
+                HeaderMap::new(),
+            )
+        };
+        let listed = list().await.0.notifications;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].slug, "open");
+
+        let status = read_notifications(
+            StateExtractor(state.clone()),
+            Json(ReadNotificationsReq {
+                user_id,
+                slug: Some("open".into()),
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(list().await.0.notifications.is_empty());
+        assert_eq!(state.notifications.read()[&user_id][0].slug, "locked");
     }
 }
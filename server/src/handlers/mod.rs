@@ -1,2 +1,7 @@
+pub mod frames;
 pub mod http;
+pub mod outbox;
+pub mod poll;
+#[cfg(feature = "webtransport")]
+pub mod webtransport;
 pub mod ws;
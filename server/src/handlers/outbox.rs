@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+use crate::types::ServerMsg;
+
+/// How many serialized messages a connection keeps for `Resync` replays.
+pub const REPLAY_BUFFER: usize = 256;
+
+#[derive(Serialize)]
+struct Sequenced<'a> {
+    seq: u64,
+    #[serde(flatten)]
+    msg: &'a ServerMsg,
+}
+
+/// Per-connection numbering of outgoing messages, with a short history so a
+/// client that noticed a gap can have the missing frames sent again.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    last_seq: u64,
+    sent: VecDeque<(u64, String)>,
+}
+
+impl Outbox {
+    /// Serializes `msg` with the next `seq` and remembers the frame.
+    pub fn encode(&mut self, msg: &ServerMsg) -> serde_json::Result<String> {
+        let seq = self.last_seq + 1;
+        let text = serde_json::to_string(&Sequenced { seq, msg })?;
+        self.last_seq = seq;
+        if self.sent.len() == REPLAY_BUFFER {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((seq, text.clone()));
+        Ok(text)
+    }
+
+    /// Frames sent after `last_seq`, or `None` when some of them have already
+    /// dropped out of the buffer and the client needs a full resync.
+    pub fn replay_after(&self, last_seq: u64) -> Option<Vec<String>> {
+        if last_seq >= self.last_seq {
+            return Some(Vec::new());
+        }
+        let oldest = self.sent.front().map(|(seq, _)| *seq)?;
+        if last_seq + 1 < oldest {
+            return None;
+        }
+        Some(
+            self.sent
+                .iter()
+                .filter(|(seq, _)| *seq > last_seq)
+                .map(|(_, text)| text.clone())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_numbers_frames_and_replays_gaps() {
+        let mut outbox = Outbox::default();
+        let first = outbox.encode(&ServerMsg::Pong { ts: None }).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&first).unwrap();
+        assert_eq!(value["seq"], 1);
+        assert_eq!(value["type"], "pong");
+
+        for _ in 0..REPLAY_BUFFER {
+            outbox.encode(&ServerMsg::Pong { ts: Some(1) }).unwrap();
+        }
+        let last = REPLAY_BUFFER as u64 + 1;
+        assert_eq!(outbox.replay_after(last).unwrap().len(), 0);
+        assert_eq!(outbox.replay_after(last - 2).unwrap().len(), 2);
+        assert_eq!(outbox.replay_after(1).unwrap().len(), REPLAY_BUFFER);
+        assert!(outbox.replay_after(0).is_none());
+    }
+}
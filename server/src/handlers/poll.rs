@@ -0,0 +1,503 @@
+//! The WebSocket protocol over plain HTTP requests, for networks whose
+//! proxies break WebSockets. `POST /api/poll` takes the same query and
+//! credentials as `/api/ws` and opens a session, answering with its token.
+//! `POST /api/poll/:session` carries a JSON array of client messages,
+//! handled exactly as on a socket, and `GET /api/poll/:session` waits up to
+//! `wait_ms` for server messages and returns them, numbered as on a socket
+//! so `resync` works the same. A session nobody polled for
+//! [`POLL_SESSION_TTL_MS`] ends, and so does one `DELETE`d.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Mutex as AsyncMutex, mpsc, watch},
+    time::{Instant, sleep, sleep_until},
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    connections::{Connection, register_connection, unregister_connection},
+    handlers::{
+        outbox::Outbox,
+        ws::{
+            Admitted, ClientMeta, SessionAuth, WsQuery, admit, close_code_after, current_client,
+            handle_client_message, leave_doc, other_edit_form, prepare_batch, resync_fallback,
+        },
+    },
+    protocol::CLOSE_UNAUTHORIZED,
+    state::{AppState, now_millis},
+    subscription::{PresenceLane, Subscriber},
+    types::{ClientMsg, ServerMsg},
+    viewport::ViewportFilter,
+};
+
+/// Longest a poll is held open, below the idle timeouts of common proxies.
+pub const MAX_POLL_WAIT_MS: u64 = 25_000;
+/// How long a session lives without being polled.
+pub const POLL_SESSION_TTL_MS: u64 = 60_000;
+
+pub type PollSessions = Arc<Mutex<HashMap<String, Arc<PollSession>>>>;
+
+/// A session whose messages wait here between polls.
+pub struct PollSession {
+    slug: String,
+    ticketed: bool,
+    conn: Arc<Connection>,
+    client_meta: Arc<Mutex<Option<ClientMeta>>>,
+    auth: Arc<Mutex<SessionAuth>>,
+    tx: mpsc::UnboundedSender<ServerMsg>,
+    resync_tx: mpsc::UnboundedSender<u64>,
+    /// Whether the client said `Hello`; held while its messages are
+    /// handled, which keeps concurrent posts in order.
+    established: AsyncMutex<bool>,
+    inbox: AsyncMutex<Inbox>,
+    lane: Arc<PresenceLane>,
+    last_seen: AtomicU64,
+    ended: watch::Sender<bool>,
+}
+
+struct Inbox {
+    rx: mpsc::UnboundedReceiver<ServerMsg>,
+    resync_rx: mpsc::UnboundedReceiver<u64>,
+    outbox: Outbox,
+    viewport: Option<ViewportFilter>,
+}
+
+impl PollSession {
+    fn touch(&self) {
+        self.last_seen.store(now_millis(), Ordering::Relaxed);
+    }
+
+    fn end(&self) {
+        self.ended.send_replace(true);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PollOpened {
+    pub session: String,
+    pub max_wait_ms: u64,
+    pub ttl_ms: u64,
+}
+
+#[derive(Deserialize)]
+pub struct PollWait {
+    pub wait_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct PollClose {
+    code: u16,
+    reason: &'static str,
+}
+
+/// Opens a session on the document in the query.
+pub async fn open(
+    State(state): State<AppState>,
+    Query(q): Query<WsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Admitted {
+        slug,
+        ticketed,
+        auth,
+    } = match admit(&state, q, &headers).await {
+        Ok(admitted) => admitted,
+        Err(status) => return status.into_response(),
+    };
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (resync_tx, resync_rx) = mpsc::unbounded_channel();
+    let lane = Arc::new(PresenceLane::default());
+    state
+        .subs
+        .write()
+        .entry(slug.clone())
+        .or_default()
+        .push(Subscriber {
+            lane: Some(lane.clone()),
+            ..tx.clone().into()
+        });
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let session = Arc::new(PollSession {
+        conn: register_connection(&state, &slug, now_millis()),
+        slug,
+        ticketed,
+        client_meta: Default::default(),
+        auth,
+        tx,
+        resync_tx,
+        established: AsyncMutex::new(false),
+        inbox: AsyncMutex::new(Inbox {
+            rx,
+            resync_rx,
+            outbox: Outbox::default(),
+            viewport: None,
+        }),
+        lane,
+        last_seen: AtomicU64::new(now_millis()),
+        ended: watch::channel(false).0,
+    });
+    state
+        .poll_sessions
+        .lock()
+        .insert(token.clone(), session.clone());
+    info!(event = "poll_connected", slug = %session.slug, "polling session opened");
+    tokio::spawn(watch_session(state, token.clone(), session));
+    Json(PollOpened {
+        session: token,
+        max_wait_ms: MAX_POLL_WAIT_MS,
+        ttl_ms: POLL_SESSION_TTL_MS,
+    })
+    .into_response()
+}
+
+/// Ends `session` once it is kicked, closed or left idle, and lets go of
+/// what its client held.
+async fn watch_session(state: AppState, token: String, session: Arc<PollSession>) {
+    let mut ended = session.ended.subscribe();
+    loop {
+        let idle_at = session.last_seen.load(Ordering::Relaxed) + POLL_SESSION_TTL_MS;
+        let wait = Duration::from_millis(idle_at.saturating_sub(now_millis()));
+        tokio::select! {
+            _ = session.conn.kicked() => break,
+            _ = ended.wait_for(|ended| *ended) => break,
+            _ = sleep(wait) => {
+                if session.last_seen.load(Ordering::Relaxed) + POLL_SESSION_TTL_MS <= now_millis() {
+                    break;
+                }
+            }
+        }
+    }
+    session.end();
+    state.poll_sessions.lock().remove(&token);
+    unregister_connection(&state, &session.conn.id);
+    let client = current_client(&session.client_meta);
+    info!(
+        event = "poll_disconnected",
+        slug = %session.slug,
+        client_id = client.map(|meta| meta.id.to_string()),
+        "polling session closed"
+    );
+    if let Some(meta) = client {
+        leave_doc(&state, &session.slug, meta.id);
+    }
+}
+
+fn session(state: &AppState, token: &str) -> Option<Arc<PollSession>> {
+    let session = state.poll_sessions.lock().get(token).cloned()?;
+    let ended = *session.ended.borrow();
+    (!ended).then_some(session)
+}
+
+/// Handles a JSON array of client messages in order.
+pub async fn send(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    body: Bytes,
+) -> Response {
+    let Some(session) = session(&state, &token) else {
+        return (StatusCode::NOT_FOUND, "unknown session").into_response();
+    };
+    session.touch();
+    session.conn.record_received(body.len(), now_millis());
+    let msgs: Vec<ClientMsg> = match serde_json::from_slice(&body) {
+        Ok(msgs) => msgs,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    let mut established = session.established.lock().await;
+    for msg in msgs {
+        if let Err(err) = handle_client_message(
+            msg,
+            &mut established,
+            &state,
+            &session.slug,
+            session.ticketed,
+            &session.client_meta,
+            &session.tx,
+            &session.resync_tx,
+            &session.auth,
+        )
+        .await
+        {
+            error!(slug = %session.slug, "handle_client_message error: {:#}", err);
+            session.end();
+            return (StatusCode::BAD_REQUEST, format!("{:#}", err)).into_response();
+        }
+        session
+            .conn
+            .set_client(current_client(&session.client_meta).map(|m| m.id));
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Waits for server messages and returns what arrived, as
+/// `{"messages": [...]}` with `close` set when the session ended with them.
+pub async fn poll(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Query(q): Query<PollWait>,
+) -> Response {
+    let Some(session) = session(&state, &token) else {
+        return (StatusCode::NOT_FOUND, "unknown session").into_response();
+    };
+    session.touch();
+    let wait = Duration::from_millis(q.wait_ms.unwrap_or(MAX_POLL_WAIT_MS).min(MAX_POLL_WAIT_MS));
+    let mut until = Instant::now() + wait;
+    let mut ended = session.ended.subscribe();
+    let mut inbox = session.inbox.lock().await;
+    let Inbox {
+        rx,
+        resync_rx,
+        outbox,
+        viewport,
+    } = &mut *inbox;
+    let mut frames = Vec::new();
+    let mut close = None;
+    if let Some(msg) = viewport.as_mut().and_then(ViewportFilter::take_sync)
+        && let Ok(text) = outbox.encode(&msg)
+    {
+        frames.push(text);
+    }
+    while close.is_none() {
+        if !frames.is_empty() {
+            // Only what is already waiting joins the answer.
+            until = Instant::now();
+        }
+        let auth_deadline = session.auth.lock().deadline;
+        let msgs = tokio::select! {
+            biased;
+            msg = rx.recv() => match msg {
+                Some(msg) => {
+                    let meta = current_client(&session.client_meta);
+                    if other_edit_form(&msg, &session.slug, meta.is_some_and(|m| m.line_ops)) {
+                        continue;
+                    }
+                    ViewportFilter::route(viewport, &session.slug, meta.map(|m| m.id), msg)
+                }
+                None => break,
+            },
+            Some(last_seq) = resync_rx.recv() => {
+                if let Some(replayed) = outbox.replay_after(last_seq) {
+                    frames.extend(replayed);
+                    continue;
+                }
+                let compat = current_client(&session.client_meta).is_some_and(|m| m.compat);
+                match resync_fallback(&state, &session.slug, compat, viewport.as_mut()).await {
+                    Ok(msg) => vec![msg],
+                    Err(err) => {
+                        error!(slug = %session.slug, "failed to build resync: {:#}", err);
+                        continue;
+                    }
+                }
+            }
+            _ = session.lane.ready() => session.lane.drain(),
+            _ = sleep_until(auth_deadline.unwrap_or_else(Instant::now)),
+                if auth_deadline.is_some() =>
+            {
+                if session.auth.lock().deadline.is_some_and(|d| d <= Instant::now()) {
+                    info!(slug = %session.slug, "closing session that did not re-authenticate");
+                    close = Some((CLOSE_UNAUTHORIZED, "authentication expired"));
+                }
+                continue;
+            }
+            // The `Ref` it resolves to must not be held across the awaits
+            // below.
+            _ = async { ended.wait_for(|ended| *ended).await.is_ok() } => break,
+            _ = sleep_until(until) => break,
+        };
+        let meta = current_client(&session.client_meta);
+        let msgs = prepare_batch(
+            &state,
+            &session.slug,
+            meta,
+            &session.auth,
+            viewport.as_mut(),
+            msgs,
+        )
+        .await;
+        for msg in msgs {
+            match outbox.encode(&msg) {
+                Ok(text) => frames.push(text),
+                Err(err) => {
+                    warn!("failed to serialize poll message: {:#}", err);
+                    continue;
+                }
+            }
+            if let Some(closing) = close_code_after(&msg) {
+                close = Some(closing);
+                break;
+            }
+        }
+    }
+    session.conn.set_queued(rx.len() + session.lane.pending());
+    drop(inbox);
+    session.touch();
+    let close = close.map(|(code, reason)| {
+        session.end();
+        format!(
+            ",\"close\":{}",
+            serde_json::to_string(&PollClose { code, reason }).unwrap_or_default()
+        )
+    });
+    let body = format!(
+        "{{\"messages\":[{}]{}}}",
+        frames.join(","),
+        close.unwrap_or_default()
+    );
+    session.conn.record_sent(body.len(), now_millis());
+    ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+/// Ends a session right away.
+pub async fn close(State(state): State<AppState>, Path(token): Path<String>) -> StatusCode {
+    match session(&state, &token) {
+        Some(session) => {
+            session.end();
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build_router,
+        state::apply_edit,
+        types::{Edit, OpKind},
+    };
+    use axum::{Router, body::Body, http::Request};
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    async fn call(app: &Router, method: &str, uri: &str, body: Value) -> (StatusCode, Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn types(polled: &Value) -> Vec<&str> {
+        polled["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["type"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sessions_speak_the_socket_protocol_over_requests() {
+        let base = std::env::temp_dir().join(format!("poll-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let app = build_router(&state);
+        let created = call(
+            &app,
+            "POST",
+            "/api/docs",
+            json!({"slug": "notes", "content": "hello"}),
+        )
+        .await;
+        assert_eq!(created.0, StatusCode::CREATED);
+        let (status, _) = call(&app, "GET", "/api/poll/nope", Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, opened) = call(&app, "POST", "/api/poll?slug=notes", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let uri = format!("/api/poll/{}", opened["session"].as_str().unwrap());
+        let client_id = Uuid::new_v4();
+        let hello = json!([{
+            "type": "hello",
+            "slug": "notes",
+            "client_id": client_id,
+            "label": "Ann",
+            "color": null,
+        }]);
+        assert_eq!(
+            call(&app, "POST", &uri, hello).await.0,
+            StatusCode::NO_CONTENT
+        );
+        let (_, polled) = call(&app, "GET", &format!("{}?wait_ms=0", uri), Value::Null).await;
+        assert_eq!(types(&polled), vec!["presence_snapshot", "presence_diff"]);
+        assert_eq!(polled["messages"][0]["seq"], 1);
+        assert!(
+            state.presence.read()["notes"]
+                .clients
+                .contains_key(&client_id)
+        );
+
+        let edit = Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 5,
+                text: "!".into(),
+            }],
+            client_id: Some(Uuid::new_v4()),
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        let waiting = tokio::spawn({
+            let (app, uri) = (app.clone(), uri.clone());
+            async move { call(&app, "GET", &uri, Value::Null).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        apply_edit(&state, "notes", edit).await.unwrap();
+        let (_, polled) = waiting.await.unwrap();
+        assert_eq!(types(&polled), vec!["applied"]);
+
+        assert_eq!(
+            call(&app, "DELETE", &uri, Value::Null).await.0,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            call(&app, "GET", &uri, Value::Null).await.0,
+            StatusCode::NOT_FOUND
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(state.poll_sessions.lock().is_empty());
+        assert!(
+            state
+                .presence
+                .read()
+                .get("notes")
+                .is_none_or(|p| p.clients.is_empty())
+        );
+    }
+}
@@ -0,0 +1,669 @@
+//! The WebSocket protocol over WebTransport (HTTP/3), so that a lost packet
+//! carrying a cursor does not hold up the edits behind it. Experimental,
+//! and only built with the `webtransport` feature.
+//!
+//! A client opens a session with an extended `CONNECT` to
+//! [`WEBTRANSPORT_PATH`], with the same query and credentials as `/api/ws`.
+//! The server then opens one bidirectional stream in the session that
+//! carries newline-separated JSON both ways, numbered as on a socket. The
+//! presence lane goes out as datagrams instead, unnumbered, when it fits
+//! one; clients may send `Cursor` and the like as datagrams too. The
+//! session ends with a `CLOSE_WEBTRANSPORT_SESSION` capsule carrying the
+//! code a socket would have closed with.
+
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use axum::{
+    extract::Query,
+    http::{Method, Request, Response, StatusCode},
+};
+use bytes::Bytes;
+use h3::{ext::Protocol, server::RequestStream};
+use parking_lot::Mutex;
+use quinn::{Endpoint, crypto::rustls::QuicServerConfig};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, pem::PemObject};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::{Mutex as AsyncMutex, mpsc, watch},
+    time::{Instant, MissedTickBehavior, interval, sleep_until, timeout},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    connections::{Connection, register_connection, unregister_connection},
+    handlers::{
+        outbox::Outbox,
+        ws::{
+            Admitted, ClientMeta, SessionAuth, WebTransportInfo, WsQuery, admit, close_code_after,
+            current_client, handle_client_message, leave_doc, other_edit_form, prepare_batch,
+            resync_fallback,
+        },
+    },
+    protocol::CLOSE_UNAUTHORIZED,
+    state::{AppState, now_millis},
+    subscription::{PresenceLane, Subscriber},
+    types::{ClientMsg, ServerMsg},
+    viewport::{VIEWPORT_SYNC_MS, ViewportFilter},
+};
+
+pub const WEBTRANSPORT_PATH: &str = "/api/webtransport";
+/// Starts a stream that belongs to a session, followed by the session id.
+const WEBTRANSPORT_STREAM: u64 = 0x41;
+const CLOSE_WEBTRANSPORT_SESSION: u64 = 0x2843;
+/// Browsers only accept a certificate pinned by hash for up to two weeks.
+const SELF_SIGNED_DAYS: i64 = 13;
+/// How long a client gets to hang up after its session was closed before
+/// the connection is closed under it.
+const CLOSE_GRACE: Duration = Duration::from_secs(2);
+
+type ConnectStream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Binds the listener to `addr`, with the PEM certificate chain and key in
+/// `cert` or else a fresh self-signed certificate for `localhost`.
+pub fn bind(
+    addr: SocketAddr,
+    cert: Option<(&Path, &Path)>,
+) -> anyhow::Result<(Endpoint, WebTransportInfo)> {
+    let (chain, key, cert_hash) = match cert {
+        Some((cert, key)) => (
+            CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?,
+            PrivateKeyDer::from_pem_file(key)?,
+            None,
+        ),
+        None => {
+            let (cert, key) = self_signed()?;
+            let hash = hex::encode(Sha256::digest(&cert));
+            (vec![cert], key, Some(hash))
+        }
+    };
+    let endpoint = listen(addr, chain, key)?;
+    let info = WebTransportInfo {
+        port: endpoint.local_addr()?.port(),
+        path: WEBTRANSPORT_PATH.to_string(),
+        cert_hash,
+    };
+    Ok((endpoint, info))
+}
+
+fn self_signed() -> anyhow::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let key = rcgen::KeyPair::generate()?;
+    let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()])?;
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::hours(1);
+    params.not_after = now + time::Duration::days(SELF_SIGNED_DAYS);
+    let cert = params.self_signed(&key)?;
+    let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+    Ok((cert.der().clone(), key.into()))
+}
+
+fn listen(
+    addr: SocketAddr,
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> anyhow::Result<Endpoint> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    Ok(Endpoint::server(config, addr)?)
+}
+
+/// Accepts connections on `endpoint` until `shutdown` flips to `true`.
+pub async fn run_webtransport(
+    state: AppState,
+    endpoint: Endpoint,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            changed = shutdown.changed() => {
+                if changed.is_err() || *shutdown.borrow() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_connection(state, incoming).await {
+                warn!("webtransport connection failed: {:#}", err);
+            }
+        });
+    }
+    endpoint.close(0u32.into(), b"shutting down");
+}
+
+async fn serve_connection(state: AppState, incoming: quinn::Incoming) -> anyhow::Result<()> {
+    let raw = incoming.await?;
+    let mut h3_conn = h3::server::builder()
+        .enable_webtransport(true)
+        .enable_extended_connect(true)
+        .enable_datagram(true)
+        .max_webtransport_sessions(1)
+        .build(h3_quinn::Connection::new(raw.clone()))
+        .await?;
+    let mut session: Option<tokio::task::JoinHandle<()>> = None;
+    // Ends once the client or a finished session closes the connection.
+    while let Ok(Some(resolver)) = h3_conn.accept().await {
+        let Ok((req, mut stream)) = resolver.resolve_request().await else {
+            continue;
+        };
+        if session.as_ref().is_some_and(|s| !s.is_finished()) {
+            refuse(&mut stream, StatusCode::TOO_MANY_REQUESTS).await;
+            continue;
+        }
+        session = Some(tokio::spawn(run_session(
+            state.clone(),
+            raw.clone(),
+            req,
+            stream,
+        )));
+    }
+    Ok(())
+}
+
+async fn refuse(stream: &mut ConnectStream, status: StatusCode) {
+    let response = Response::builder().status(status).body(()).unwrap();
+    let _ = stream.send_response(response).await;
+    let _ = stream.finish().await;
+}
+
+/// One client's session; what its stream, its datagrams and its outgoing
+/// messages share.
+struct Session {
+    state: AppState,
+    slug: String,
+    ticketed: bool,
+    raw: quinn::Connection,
+    /// The stream id of the `CONNECT`, which prefixes everything sent in
+    /// the session.
+    session_id: u64,
+    conn: Arc<Connection>,
+    client_meta: Arc<Mutex<Option<ClientMeta>>>,
+    auth: Arc<Mutex<SessionAuth>>,
+    tx: mpsc::UnboundedSender<ServerMsg>,
+    resync_tx: mpsc::UnboundedSender<u64>,
+    /// Whether the client said `Hello`; held while a message is handled,
+    /// whether it came on the stream or as a datagram.
+    established: AsyncMutex<bool>,
+}
+
+impl Session {
+    async fn handle(&self, msg: ClientMsg) -> anyhow::Result<()> {
+        let mut established = self.established.lock().await;
+        handle_client_message(
+            msg,
+            &mut established,
+            &self.state,
+            &self.slug,
+            self.ticketed,
+            &self.client_meta,
+            &self.tx,
+            &self.resync_tx,
+            &self.auth,
+        )
+        .await?;
+        self.conn
+            .set_client(current_client(&self.client_meta).map(|m| m.id));
+        Ok(())
+    }
+}
+
+async fn run_session(
+    state: AppState,
+    raw: quinn::Connection,
+    req: Request<()>,
+    mut stream: ConnectStream,
+) {
+    let is_webtransport = req.method() == Method::CONNECT
+        && req.extensions().get::<Protocol>() == Some(&Protocol::WEB_TRANSPORT);
+    if !is_webtransport || req.uri().path() != WEBTRANSPORT_PATH {
+        return refuse(&mut stream, StatusCode::NOT_FOUND).await;
+    }
+    let Ok(Query(q)) = Query::<WsQuery>::try_from_uri(req.uri()) else {
+        return refuse(&mut stream, StatusCode::BAD_REQUEST).await;
+    };
+    let Admitted {
+        slug,
+        ticketed,
+        auth,
+    } = match admit(&state, q, req.headers()).await {
+        Ok(admitted) => admitted,
+        Err(status) => return refuse(&mut stream, status).await,
+    };
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("sec-webtransport-http3-draft", "draft02")
+        .body(())
+        .unwrap();
+    if stream.send_response(response).await.is_err() {
+        return;
+    }
+    let session_id = stream.id().into_inner();
+    let (mut connect_send, mut connect_recv) = stream.split();
+    let Ok((mut send, recv)) = raw.open_bi().await else {
+        return;
+    };
+    let mut header = Vec::new();
+    put_varint(&mut header, WEBTRANSPORT_STREAM);
+    put_varint(&mut header, session_id);
+    if send.write_all(&header).await.is_err() {
+        return;
+    }
+
+    let connected_at = Instant::now();
+    let conn = register_connection(&state, &slug, now_millis());
+    info!(event = "webtransport_connected", %slug, "webtransport session opened");
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (resync_tx, resync_rx) = mpsc::unbounded_channel();
+    let lane = Arc::new(PresenceLane::default());
+    state
+        .subs
+        .write()
+        .entry(slug.clone())
+        .or_default()
+        .push(Subscriber {
+            lane: Some(lane.clone()),
+            ..tx.clone().into()
+        });
+    let session = Session {
+        state: state.clone(),
+        slug,
+        ticketed,
+        raw,
+        session_id,
+        conn,
+        client_meta: Default::default(),
+        auth,
+        tx,
+        resync_tx,
+        established: AsyncMutex::new(false),
+    };
+
+    let closing = tokio::select! {
+        closing = send_loop(&session, send, rx, resync_rx, &lane) => closing,
+        _ = recv_loop(&session, recv) => None,
+        _ = datagram_loop(&session) => None,
+        // Capsules from the client mean nothing here but its hanging up.
+        _ = async { while let Ok(Some(_)) = connect_recv.recv_data().await {} } => None,
+        _ = session.conn.kicked() => None,
+    };
+    let (code, reason) = closing.unwrap_or((0, ""));
+    let mut capsule = Vec::new();
+    put_varint(&mut capsule, CLOSE_WEBTRANSPORT_SESSION);
+    put_varint(&mut capsule, 4 + reason.len() as u64);
+    capsule.extend_from_slice(&u32::from(code).to_be_bytes());
+    capsule.extend_from_slice(reason.as_bytes());
+    let _ = connect_send.send_data(Bytes::from(capsule)).await;
+    let _ = connect_send.finish().await;
+
+    unregister_connection(&state, &session.conn.id);
+    let client = current_client(&session.client_meta);
+    info!(
+        event = "webtransport_disconnected",
+        slug = %session.slug,
+        client_id = client.map(|meta| meta.id.to_string()),
+        duration_ms = connected_at.elapsed().as_secs_f64() * 1000.0,
+        "webtransport session closed"
+    );
+    if let Some(meta) = client {
+        leave_doc(&state, &session.slug, meta.id);
+    }
+    if timeout(CLOSE_GRACE, session.raw.closed()).await.is_err() {
+        session.raw.close(0u32.into(), b"");
+    }
+}
+
+/// Sends what the session is subscribed to until the client goes away or
+/// a message closes the session, returning the close code it asked for.
+async fn send_loop(
+    session: &Session,
+    mut send: quinn::SendStream,
+    mut rx: mpsc::UnboundedReceiver<ServerMsg>,
+    mut resync_rx: mpsc::UnboundedReceiver<u64>,
+    lane: &PresenceLane,
+) -> Option<(u16, &'static str)> {
+    let mut outbox = Outbox::default();
+    let mut viewport: Option<ViewportFilter> = None;
+    let mut viewport_tick = interval(Duration::from_millis(VIEWPORT_SYNC_MS));
+    viewport_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        session.conn.set_queued(rx.len() + lane.pending());
+        let auth_deadline = session.auth.lock().deadline;
+        let msgs = tokio::select! {
+            biased;
+            msg = rx.recv() => match msg {
+                Some(msg) => {
+                    let meta = current_client(&session.client_meta);
+                    if other_edit_form(&msg, &session.slug, meta.is_some_and(|m| m.line_ops)) {
+                        continue;
+                    }
+                    ViewportFilter::route(&mut viewport, &session.slug, meta.map(|m| m.id), msg)
+                }
+                None => return None,
+            },
+            Some(last_seq) = resync_rx.recv() => {
+                if let Some(frames) = outbox.replay_after(last_seq) {
+                    for text in frames {
+                        if !write_line(session, &mut send, &text).await {
+                            return None;
+                        }
+                    }
+                    continue;
+                }
+                let compat = current_client(&session.client_meta).is_some_and(|m| m.compat);
+                match resync_fallback(&session.state, &session.slug, compat, viewport.as_mut()).await {
+                    Ok(msg) => vec![msg],
+                    Err(err) => {
+                        error!(slug = %session.slug, "failed to build resync: {:#}", err);
+                        continue;
+                    }
+                }
+            }
+            _ = viewport_tick.tick() => {
+                match viewport.as_mut().and_then(ViewportFilter::take_sync) {
+                    Some(msg) => vec![msg],
+                    None => continue,
+                }
+            }
+            // What does not fit a datagram takes the stream.
+            _ = lane.ready() => lane
+                .drain()
+                .into_iter()
+                .filter(|msg| !send_datagram(session, msg))
+                .collect(),
+            _ = sleep_until(auth_deadline.unwrap_or_else(Instant::now)),
+                if auth_deadline.is_some() =>
+            {
+                if session.auth.lock().deadline.is_some_and(|d| d <= Instant::now()) {
+                    info!(slug = %session.slug, "closing session that did not re-authenticate");
+                    return Some((CLOSE_UNAUTHORIZED, "authentication expired"));
+                }
+                continue;
+            }
+        };
+        let meta = current_client(&session.client_meta);
+        let msgs = prepare_batch(
+            &session.state,
+            &session.slug,
+            meta,
+            &session.auth,
+            viewport.as_mut(),
+            msgs,
+        )
+        .await;
+        for msg in msgs {
+            let closing = close_code_after(&msg);
+            match outbox.encode(&msg) {
+                Ok(text) => {
+                    if !write_line(session, &mut send, &text).await {
+                        return None;
+                    }
+                    if closing.is_some() {
+                        return closing;
+                    }
+                }
+                Err(err) => {
+                    warn!("failed to serialize webtransport message: {:#}", err);
+                }
+            }
+        }
+    }
+}
+
+async fn write_line(session: &Session, send: &mut quinn::SendStream, text: &str) -> bool {
+    let mut line = Vec::with_capacity(text.len() + 1);
+    line.extend_from_slice(text.as_bytes());
+    line.push(b'\n');
+    if send.write_all(&line).await.is_err() {
+        return false;
+    }
+    session.conn.record_sent(line.len(), now_millis());
+    true
+}
+
+/// Sends `msg` as a datagram, unless it is too large for one.
+fn send_datagram(session: &Session, msg: &ServerMsg) -> bool {
+    let Ok(json) = serde_json::to_vec(msg) else {
+        return false;
+    };
+    let mut datagram = Vec::with_capacity(json.len() + 8);
+    // Datagrams carry the session id divided by four.
+    put_varint(&mut datagram, session.session_id / 4);
+    datagram.extend_from_slice(&json);
+    let len = datagram.len();
+    if session.raw.max_datagram_size().is_none_or(|max| len > max)
+        || session.raw.send_datagram(datagram.into()).is_err()
+    {
+        return false;
+    }
+    session.conn.record_sent(len, now_millis());
+    true
+}
+
+async fn recv_loop(session: &Session, recv: quinn::RecvStream) {
+    let mut lines = BufReader::new(recv).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        session.conn.record_received(line.len() + 1, now_millis());
+        match serde_json::from_str::<ClientMsg>(&line) {
+            Ok(msg) => {
+                if let Err(err) = session.handle(msg).await {
+                    error!(slug = %session.slug, "handle_client_message error: {:#}", err);
+                    return;
+                }
+            }
+            Err(err) => {
+                warn!("failed to parse webtransport message: {:#}", err);
+            }
+        }
+    }
+}
+
+async fn datagram_loop(session: &Session) {
+    while let Ok(datagram) = session.raw.read_datagram().await {
+        session.conn.record_received(datagram.len(), now_millis());
+        let mut payload = &datagram[..];
+        if get_varint(&mut payload) != Some(session.session_id / 4) {
+            continue;
+        }
+        match serde_json::from_slice::<ClientMsg>(payload) {
+            Ok(msg) => {
+                if let Err(err) = session.handle(msg).await {
+                    error!(slug = %session.slug, "handle_client_message error: {:#}", err);
+                    return;
+                }
+            }
+            Err(err) => {
+                warn!("failed to parse webtransport datagram: {:#}", err);
+            }
+        }
+    }
+}
+
+/// Appends `value` as a QUIC variable-length integer.
+fn put_varint(buf: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => buf.push(value as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Takes a QUIC variable-length integer off the front of `buf`.
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+    let bytes = buf.get(..len)?;
+    let value = bytes[1..].iter().fold(u64::from(first & 0x3f), |value, b| {
+        value << 8 | u64::from(*b)
+    });
+    *buf = &buf[len..];
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::crypto::rustls::QuicClientConfig;
+    use uuid::Uuid;
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [
+            0,
+            0x3f,
+            0x40,
+            0x3fff,
+            0x4000,
+            0x3fff_ffff,
+            0x4000_0000,
+            1 << 61,
+        ] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, value);
+            let mut rest = &buf[..];
+            assert_eq!(get_varint(&mut rest), Some(value));
+            assert!(rest.is_empty());
+        }
+        assert_eq!(get_varint(&mut &[0x40][..]), None);
+    }
+
+    #[tokio::test]
+    async fn sessions_speak_the_socket_protocol() {
+        let base = std::env::temp_dir().join(format!("webtransport-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let (cert, key) = self_signed().unwrap();
+        let endpoint = listen("127.0.0.1:0".parse().unwrap(), vec![cert.clone()], key).unwrap();
+        let port = endpoint.local_addr().unwrap().port();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        tokio::spawn(run_webtransport(state.clone(), endpoint, shutdown_rx));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls).unwrap(),
+        )));
+        let raw = client
+            .connect(([127, 0, 0, 1], port).into(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        // The h3 driver is never polled: it would refuse the server's stream.
+        let (_driver, mut requests) = h3::client::builder()
+            .enable_extended_connect(true)
+            .enable_datagram(true)
+            .build::<_, _, Bytes>(h3_quinn::Connection::new(raw.clone()))
+            .await
+            .unwrap();
+
+        let mut req = Request::builder()
+            .method(Method::CONNECT)
+            .uri(format!(
+                "https://localhost:{}/api/elsewhere?slug=notes",
+                port
+            ))
+            .body(())
+            .unwrap();
+        req.extensions_mut().insert(Protocol::WEB_TRANSPORT);
+        let mut refused = requests.send_request(req).await.unwrap();
+        assert_eq!(
+            refused.recv_response().await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let mut req = Request::builder()
+            .method(Method::CONNECT)
+            .uri(format!(
+                "https://localhost:{}{}?slug=notes",
+                port, WEBTRANSPORT_PATH
+            ))
+            .body(())
+            .unwrap();
+        req.extensions_mut().insert(Protocol::WEB_TRANSPORT);
+        let mut connect = requests.send_request(req).await.unwrap();
+        assert_eq!(
+            connect.recv_response().await.unwrap().status(),
+            StatusCode::OK
+        );
+        let session_id = connect.id().into_inner();
+
+        let (mut send, recv) = raw.accept_bi().await.unwrap();
+        let mut recv = BufReader::new(recv);
+        let mut header = [0u8; 3];
+        tokio::io::AsyncReadExt::read_exact(&mut recv, &mut header)
+            .await
+            .unwrap();
+        let mut rest = &header[..];
+        assert_eq!(get_varint(&mut rest), Some(WEBTRANSPORT_STREAM));
+        assert_eq!(get_varint(&mut rest), Some(session_id));
+
+        let client_id = Uuid::new_v4();
+        let hello = serde_json::json!({
+            "type": "hello",
+            "slug": "notes",
+            "client_id": client_id,
+            "label": "Ann",
+            "color": null,
+        });
+        send.write_all(format!("{}\n", hello).as_bytes())
+            .await
+            .unwrap();
+        let mut line = String::new();
+        recv.read_line(&mut line).await.unwrap();
+        let first: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(first["seq"], 1);
+
+        let cursor = serde_json::json!({
+            "type": "cursor",
+            "slug": "notes",
+            "cursor": {"position": 0},
+        });
+        let mut datagram = Vec::new();
+        put_varint(&mut datagram, session_id / 4);
+        datagram.extend_from_slice(cursor.to_string().as_bytes());
+        raw.send_datagram(datagram.into()).unwrap();
+        let echoed = timeout(Duration::from_secs(5), raw.read_datagram())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut payload = &echoed[..];
+        assert_eq!(get_varint(&mut payload), Some(session_id / 4));
+        let presence: serde_json::Value = serde_json::from_slice(payload).unwrap();
+        assert!(presence.get("seq").is_none());
+        assert_eq!(state.connections.read().len(), 1);
+
+        connect.finish().await.unwrap();
+        timeout(Duration::from_secs(5), async {
+            while !state.connections.read().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let _ = shutdown_tx.send(true);
+    }
+}
@@ -1,36 +1,98 @@
 use axum::{
+    Json,
     extract::{
         Query, State, WebSocketUpgrade,
-        ws::{Message, WebSocket},
+        ws::{CloseFrame, Message, WebSocket},
     },
-    http::{HeaderMap, StatusCode},
+    http::{HeaderMap, StatusCode, header},
     response::IntoResponse,
 };
-use futures::{SinkExt, StreamExt};
-use parking_lot::Mutex;
-use serde::Deserialize;
-use std::sync::Arc;
-use tokio::sync::mpsc;
-use tracing::{error, warn};
+use futures::{SinkExt, StreamExt, stream::SplitSink};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc,
+    time::{Instant, MissedTickBehavior, interval, interval_at, sleep_until},
+};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
 use anyhow::anyhow;
 
 use crate::{
-    auth::{extract_password_from_headers, extract_password_from_token, is_authorized},
+    access::Viewer,
+    assist::request_assist,
+    auth::{
+        extract_password_from_headers, extract_password_from_token, is_authorized,
+        required_password_hash,
+    },
+    connections::{Connection, register_connection, unregister_connection},
+    degraded::degraded_notice,
+    document::{Doc, content_hash, doc_stats},
+    expiry::expiry_notice,
+    handlers::{
+        frames::{SNAPSHOT_CHUNK_BYTES, frame, split_snapshot},
+        outbox::Outbox,
+    },
+    lines::{join_line_log, leave_line_log},
+    metrics::record_rtt,
+    notifications::set_following,
+    origin::origin_allowed,
     presence::{
-        register_presence, remove_presence, touch_presence, update_presence_cursor,
-        update_presence_ime, update_presence_profile,
+        ProfileUpdate, register_presence, remove_presence, set_presence_section, smooth_rtt,
+        touch_presence, update_presence_cursor, update_presence_ime, update_presence_profile,
+        update_presence_rtt, workspace_roster,
+    },
+    protocol::{
+        CLOSE_DOC_GONE, CLOSE_DRAINING, CLOSE_MOVED, CLOSE_PROTOCOL_ERROR, CLOSE_UNAUTHORIZED,
+        PROTOCOL_VERSION, ProtocolInfo, negotiate,
     },
-    state::{AppState, apply_edit, broadcast, get_or_load_doc, now_millis, remember_op_id},
-    storage::wal_append_event,
-    types::{ClientMsg, CompatOpContext, CursorState, DocEvent, Edit, ImeEvent, OpKind, ServerMsg},
+    replace::{ReplaceSpec, replace_in_doc},
+    replica::allowed_on_replica,
+    sections::{claim_section, release_section},
+    seen::mark_seen,
+    state::{
+        AppState, DIVERGED, INVALID_OP, OwnerClaim, Rejection, add_watcher, apply_edit,
+        apply_line_edit, broadcast, change_password, claim_ownership, clamp_client_ts,
+        get_existing_doc, get_or_load_doc, now_millis, remember_op_id, remove_watcher,
+        set_subscription,
+    },
+    subscription::{MessageClass, PresenceLane, Subscriber},
+    ticket::redeem_ticket,
+    types::{
+        ClientMsg, CompatOpContext, CursorState, Edit, ImeEvent, KnownState, LineEdit, OpKind,
+        SectionClaim, ServerMsg, ViewportUnit,
+    },
+    viewport::{VIEWPORT_SYNC_MS, ViewportFilter, resolve_window, viewport_message},
+    workspace::workspace_of,
 };
 
+/// How often every session is sent a ping frame to time its round trip.
+const RTT_PROBE_MS: u64 = 10_000;
+
 #[derive(Clone, Copy)]
-struct ClientMeta {
-    id: Uuid,
-    compat: bool,
+pub(super) struct ClientMeta {
+    pub(super) id: Uuid,
+    /// Named users' stable identity, stamped on their edits.
+    user_id: Option<Uuid>,
+    pub(super) compat: bool,
+    /// Gets `LineApplied` instead of `Applied` for its document.
+    pub(super) line_ops: bool,
+    /// Gets large snapshots as `SnapshotChunk`s.
+    snapshot_chunks: bool,
+    /// Gets frames over `ws_compress_threshold` zstd-compressed.
+    compression: bool,
+}
+
+/// The password hash a session was let in with, the access version it was
+/// last checked against and, once the hash stopped opening the document,
+/// when the session is closed unless it shows the current one.
+#[derive(Default)]
+pub(super) struct SessionAuth {
+    credential: Option<String>,
+    version: u64,
+    pub(super) deadline: Option<Instant>,
 }
 
 #[derive(Deserialize)]
@@ -38,6 +100,54 @@ pub struct WsQuery {
     pub slug: String,
     pub token: Option<String>,
     pub password: Option<String>,
+    /// From `POST /api/ws-ticket`; stands in for the password.
+    pub ticket: Option<String>,
+}
+
+/// Timing a client should line its own heartbeats up with.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WsConfig {
+    /// How often an idle socket gets a ping frame; 0 when the server sends
+    /// none.
+    pub ping_interval_ms: u64,
+    /// Whether a requested `Sec-WebSocket-Protocol` is echoed back.
+    pub echo_protocol: bool,
+    pub protocol_version: u32,
+    /// Set when the experimental WebTransport listener runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webtransport: Option<WebTransportInfo>,
+}
+
+/// Where a client finds the WebTransport listener.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WebTransportInfo {
+    pub port: u16,
+    pub path: String,
+    /// Hex SHA-256 of the listener's self-signed certificate, for
+    /// `serverCertificateHashes`; absent with a configured certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_hash: Option<String>,
+}
+
+pub async fn ws_config(State(state): State<AppState>) -> Json<WsConfig> {
+    Json(WsConfig {
+        ping_interval_ms: state.ws_ping_interval_ms,
+        echo_protocol: state.ws_echo_protocol,
+        protocol_version: PROTOCOL_VERSION,
+        webtransport: state.webtransport.clone(),
+    })
+}
+
+/// The first subprotocol in `Sec-WebSocket-Protocol`, if any.
+fn requested_protocol(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(str::trim)
+        .find(|p| !p.is_empty())
+        .map(str::to_string)
 }
 
 pub async fn ws_handler(
@@ -46,72 +156,235 @@ pub async fn ws_handler(
     headers: HeaderMap,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
+    let admitted = match admit(&state, q, &headers).await {
+        Ok(admitted) => admitted,
+        Err(status) => return status.into_response(),
+    };
+    // Browsers drop the connection when a requested subprotocol is not
+    // confirmed, and the server has no subprotocol of its own.
+    let ws = match requested_protocol(&headers).filter(|_| state.ws_echo_protocol) {
+        Some(protocol) => ws.protocols([protocol]),
+        None => ws,
+    };
+    ws.on_upgrade(move |socket| handle_ws(state, admitted, socket))
+}
+
+/// A session let in by `admit`.
+pub(super) struct Admitted {
+    pub(super) slug: String,
+    /// Whether it came in on a ticket.
+    pub(super) ticketed: bool,
+    /// The credential it starts out with: the hash its password or ticket
+    /// was checked against.
+    pub(super) auth: Arc<Mutex<SessionAuth>>,
+}
+
+/// Checks the origin and credentials of a new session.
+pub(super) async fn admit(
+    state: &AppState,
+    q: WsQuery,
+    headers: &HeaderMap,
+) -> Result<Admitted, StatusCode> {
     if !state.app_env_dev
-        && !state.allowed_origins.is_empty()
         && let Some(origin) = headers.get("origin").and_then(|v| v.to_str().ok())
-        && !state
-            .allowed_origins
-            .iter()
-            .any(|allowed| origin.starts_with(allowed))
+        && !origin_allowed(&state.live.read().allowed_origins, origin)
     {
-        return StatusCode::FORBIDDEN.into_response();
+        return Err(StatusCode::FORBIDDEN);
     }
     let WsQuery {
         slug,
         token,
         password,
+        ticket,
     } = q;
-    let header_pw = extract_password_from_headers(&headers, &slug);
-    let mut provided = password;
-    if provided.is_none() {
-        provided = header_pw;
-    }
-    if provided.is_none() {
-        provided = token
-            .as_deref()
-            .and_then(|t| extract_password_from_token(t, &slug));
-    }
-    let doc = match get_or_load_doc(&state, &slug).await {
-        Ok(doc) => doc,
+    let ticketed = match ticket.as_deref() {
+        Some(ticket) if redeem_ticket(state, ticket, &slug, now_millis()) => true,
+        Some(_) => return Err(StatusCode::UNAUTHORIZED),
+        None => false,
+    };
+    let mut provided = None;
+    if !state.require_ws_ticket {
+        provided = password
+            .or_else(|| extract_password_from_headers(headers, &slug))
+            .or_else(|| {
+                token
+                    .as_deref()
+                    .and_then(|t| extract_password_from_token(t, &slug))
+            });
+    }
+    let doc = match get_existing_doc(state, &slug).await {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        // Opening it would create it without the password its workspace
+        // requires.
+        Err(err)
+            if err.downcast_ref::<Rejection>().map(|r| r.code) == Some("password_required") =>
+        {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
         Err(err) => {
             error!("invalid slug '{}': {:#}", slug, err);
-            return StatusCode::BAD_REQUEST.into_response();
+            return Err(StatusCode::BAD_REQUEST);
         }
     };
-    {
-        let d = doc.read();
-        if !is_authorized(&d, provided.as_deref()) {
-            return StatusCode::UNAUTHORIZED.into_response();
-        }
+    let d = doc.read();
+    if !ticketed && !is_authorized(&d, provided.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    if d.meta.archived_at.is_some() {
+        return Err(StatusCode::GONE);
     }
-    ws.on_upgrade(move |socket| handle_ws(state, slug, socket))
+    let auth = SessionAuth {
+        credential: required_password_hash(&d).map(str::to_string),
+        version: d.access_version,
+        deadline: None,
+    };
+    drop(d);
+    Ok(Admitted {
+        slug,
+        ticketed,
+        auth: Arc::new(Mutex::new(auth)),
+    })
 }
 
-async fn handle_ws(state: AppState, slug: String, socket: WebSocket) {
+async fn handle_ws(state: AppState, admitted: Admitted, socket: WebSocket) {
+    let Admitted {
+        slug,
+        ticketed,
+        auth,
+    } = admitted;
     let (mut sender, mut receiver) = socket.split();
-    if let Err(err) = get_or_load_doc(&state, &slug).await {
-        error!("invalid slug '{}': {:#}", slug, err);
-        return;
-    }
+    let connected_at = Instant::now();
+    let conn = register_connection(&state, &slug, now_millis());
+    info!(event = "ws_connected", %slug, "websocket connected");
 
     let (tx, mut rx) = mpsc::unbounded_channel::<ServerMsg>();
+    let lane = Arc::new(PresenceLane::default());
     {
         let mut subs = state.subs.write();
-        subs.entry(slug.clone()).or_default().push(tx.clone());
+        subs.entry(slug.clone()).or_default().push(Subscriber {
+            lane: Some(lane.clone()),
+            ..tx.clone().into()
+        });
     }
     let tx_self = tx.clone();
     let client_id_store = Arc::new(Mutex::new(None::<ClientMeta>));
+    let (resync_tx, mut resync_rx) = mpsc::unbounded_channel::<u64>();
 
+    let st_send = state.clone();
+    let slug_send = slug.clone();
+    let client_meta_send = client_id_store.clone();
+    let auth_send = auth.clone();
+    let conn_send = conn.clone();
     let mut send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            match serde_json::to_string(&msg) {
-                Ok(text) => {
-                    if sender.send(Message::Text(text)).await.is_err() {
-                        break;
+        let mut outbox = Outbox::default();
+        let mut viewport: Option<ViewportFilter> = None;
+        let mut viewport_tick = interval(Duration::from_millis(VIEWPORT_SYNC_MS));
+        viewport_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Keeps proxies that close idle sockets from cutting quiet sessions.
+        let ping_every = Duration::from_millis(st_send.ws_ping_interval_ms.max(1));
+        let mut ping_tick = interval_at(Instant::now() + ping_every, ping_every);
+        ping_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Measures busy sessions too, which never go idle long enough to ping.
+        let rtt_every = Duration::from_millis(RTT_PROBE_MS);
+        let mut rtt_tick = interval_at(Instant::now() + rtt_every, rtt_every);
+        rtt_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            conn_send.set_queued(rx.len() + lane.pending());
+            let auth_deadline = auth_send.lock().deadline;
+            // Edits and replies go out before anything waiting in the
+            // presence lane.
+            let msgs = tokio::select! {
+                biased;
+                msg = rx.recv() => match msg {
+                    Some(msg) => {
+                        let meta = current_client(&client_meta_send);
+                        if other_edit_form(&msg, &slug_send, meta.is_some_and(|m| m.line_ops)) {
+                            continue;
+                        }
+                        ViewportFilter::route(&mut viewport, &slug_send, meta.map(|m| m.id), msg)
+                    }
+                    None => break,
+                },
+                Some(last_seq) = resync_rx.recv() => {
+                    if let Some(frames) = outbox.replay_after(last_seq) {
+                        let compress_at =
+                            compress_threshold(&st_send, current_client(&client_meta_send));
+                        for text in frames {
+                            if !send_counted(&mut sender, &conn_send, frame(text, compress_at)).await {
+                                return;
+                            }
+                        }
+                        continue;
+                    }
+                    let compat = current_client(&client_meta_send).is_some_and(|m| m.compat);
+                    match resync_fallback(&st_send, &slug_send, compat, viewport.as_mut()).await {
+                        Ok(msg) => vec![msg],
+                        Err(err) => {
+                            error!(slug = %slug_send, "failed to build resync: {:#}", err);
+                            continue;
+                        }
                     }
                 }
-                Err(err) => {
-                    warn!("failed to serialize ws message: {:#}", err);
+                _ = viewport_tick.tick() => {
+                    match viewport.as_mut().and_then(ViewportFilter::take_sync) {
+                        Some(msg) => vec![msg],
+                        None => continue,
+                    }
+                }
+                _ = lane.ready() => lane.drain(),
+                _ = ping_tick.tick(), if st_send.ws_ping_interval_ms > 0 => {
+                    if !send_counted(&mut sender, &conn_send, rtt_probe()).await {
+                        return;
+                    }
+                    continue;
+                }
+                _ = rtt_tick.tick() => {
+                    if !send_counted(&mut sender, &conn_send, rtt_probe()).await {
+                        return;
+                    }
+                    continue;
+                }
+                _ = sleep_until(auth_deadline.unwrap_or_else(Instant::now)),
+                    if auth_deadline.is_some() =>
+                {
+                    if auth_send.lock().deadline.is_some_and(|d| d <= Instant::now()) {
+                        info!(slug = %slug_send, "closing session that did not re-authenticate");
+                        let _ = sender
+                            .send(close_frame(CLOSE_UNAUTHORIZED, "authentication expired"))
+                            .await;
+                        return;
+                    }
+                    continue;
+                }
+            };
+            ping_tick.reset();
+            let meta = current_client(&client_meta_send);
+            let msgs = prepare_batch(
+                &st_send,
+                &slug_send,
+                meta,
+                &auth_send,
+                viewport.as_mut(),
+                msgs,
+            )
+            .await;
+            let compress_at = compress_threshold(&st_send, meta);
+            for msg in msgs {
+                let closing = close_code_after(&msg);
+                match outbox.encode(&msg) {
+                    Ok(text) => {
+                        if !send_counted(&mut sender, &conn_send, frame(text, compress_at)).await {
+                            return;
+                        }
+                        if let Some((code, reason)) = closing {
+                            let _ = sender.send(close_frame(code, reason)).await;
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        warn!("failed to serialize ws message: {:#}", err);
+                    }
                 }
             }
         }
@@ -121,9 +394,13 @@ async fn handle_ws(state: AppState, slug: String, socket: WebSocket) {
     let slug_cl = slug.clone();
     let client_id_for_task = client_id_store.clone();
     let tx_for_task = tx_self.clone();
+    let auth_recv = auth.clone();
+    let conn_recv = conn.clone();
     let mut recv_task = tokio::spawn(async move {
         let mut established = false;
+        let mut rtt = None;
         while let Some(Ok(msg)) = receiver.next().await {
+            conn_recv.record_received(message_len(&msg), now_millis());
             match msg {
                 Message::Text(t) => match serde_json::from_str::<ClientMsg>(&t) {
                     Ok(client_msg) => {
@@ -132,19 +409,46 @@ async fn handle_ws(state: AppState, slug: String, socket: WebSocket) {
                             &mut established,
                             &st,
                             &slug_cl,
+                            ticketed,
                             &client_id_for_task,
                             &tx_for_task,
+                            &resync_tx,
+                            &auth_recv,
                         )
                         .await
                         {
                             error!(slug = %slug_cl, "handle_client_message error: {:#}", err);
                             break;
                         }
+                        conn_recv.set_client(current_client(&client_id_for_task).map(|m| m.id));
                     }
                     Err(err) => {
                         warn!("failed to parse ws message: {:#}", err);
                     }
                 },
+                Message::Pong(payload) => {
+                    if let Some(meta) = current_client(&client_id_for_task)
+                        && let Some(sample) = rtt_sample(&payload, now_millis())
+                    {
+                        record_rtt(&st, &slug_cl, Some(meta.id), sample);
+                        let smoothed = smooth_rtt(rtt, sample);
+                        rtt = Some(smoothed);
+                        conn_recv.set_rtt(smoothed);
+                        if let Some(updated) = update_presence_rtt(&st, &slug_cl, meta.id, smoothed)
+                        {
+                            broadcast(
+                                &st,
+                                &slug_cl,
+                                ServerMsg::PresenceDiff {
+                                    slug: slug_cl.clone(),
+                                    added: vec![],
+                                    updated: vec![updated],
+                                    removed: vec![],
+                                },
+                            );
+                        }
+                    }
+                }
                 Message::Close(_) => break,
                 _ => {}
             }
@@ -154,15 +458,40 @@ async fn handle_ws(state: AppState, slug: String, socket: WebSocket) {
     tokio::select! {
         _ = (&mut send_task) => {}
         _ = (&mut recv_task) => {}
+        // A stuck socket may never get to a close frame; dropping both
+        // halves closes it.
+        _ = conn.kicked() => {
+            send_task.abort();
+            recv_task.abort();
+        }
     }
-    if let Some(meta) = *client_id_store.lock()
-        && let Some(removed) = remove_presence(&state, &slug, &meta.id)
-    {
+    unregister_connection(&state, &conn.id);
+    let client = *client_id_store.lock();
+    info!(
+        event = "ws_disconnected",
+        %slug,
+        client_id = client.map(|meta| meta.id.to_string()),
+        duration_ms = connected_at.elapsed().as_secs_f64() * 1000.0,
+        "websocket disconnected"
+    );
+    if let Some(meta) = client {
+        leave_doc(&state, &slug, meta.id);
+    }
+}
+
+/// Gives up what a client held in `slug` once its session ended.
+pub(super) fn leave_doc(state: &AppState, slug: &str, client_id: Uuid) {
+    release_section(state, slug, client_id);
+    let doc = state.docs.read().get(slug).cloned();
+    if let Some(doc) = doc {
+        leave_line_log(&mut doc.write(), client_id);
+    }
+    if let Some(removed) = remove_presence(state, slug, &client_id) {
         broadcast(
-            &state,
-            &slug,
+            state,
+            slug,
             ServerMsg::PresenceDiff {
-                slug: slug.clone(),
+                slug: slug.to_string(),
                 added: vec![],
                 updated: vec![],
                 removed: vec![removed.client_id],
@@ -171,33 +500,100 @@ async fn handle_ws(state: AppState, slug: String, socket: WebSocket) {
     }
 }
 
-async fn handle_client_message(
+/// Readies messages bound for a session: a stalled viewport is rebuilt, an
+/// access change re-checks its credential and large snapshots are split
+/// for clients that asked for chunks.
+pub(super) async fn prepare_batch(
+    state: &AppState,
+    slug: &str,
+    meta: Option<ClientMeta>,
+    auth: &Mutex<SessionAuth>,
+    viewport: Option<&mut ViewportFilter>,
+    mut msgs: Vec<ServerMsg>,
+) -> Vec<ServerMsg> {
+    if let Some(filter) = viewport.filter(|f| f.is_stalled())
+        && let Ok(doc) = get_or_load_doc(state, slug).await
+    {
+        msgs = vec![filter.rebuild(&doc.read())];
+    }
+    let changed_by = msgs.iter().find_map(|msg| match msg {
+        ServerMsg::AccessChanged { client_id, .. } => Some(*client_id),
+        _ => None,
+    });
+    if let Some(changed_by) = changed_by
+        && let Ok(doc) = get_or_load_doc(state, slug).await
+    {
+        let changed_here = changed_by.is_some() && changed_by == meta.map(|m| m.id);
+        msgs.extend(recheck_auth(state, slug, &doc.read(), auth, changed_here));
+    }
+    if meta.is_some_and(|m| m.snapshot_chunks) {
+        msgs = msgs
+            .into_iter()
+            .flat_map(|msg| split_snapshot(msg, SNAPSHOT_CHUNK_BYTES))
+            .collect();
+    }
+    msgs
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn handle_client_message(
     msg: ClientMsg,
     established: &mut bool,
     state: &AppState,
     slug: &str,
+    ticketed: bool,
     client_meta: &Arc<Mutex<Option<ClientMeta>>>,
     tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+    resync_tx: &mpsc::UnboundedSender<u64>,
+    auth: &Mutex<SessionAuth>,
 ) -> anyhow::Result<()> {
     use ClientMsg::*;
 
+    if state.replica.is_some() && !allowed_on_replica(&msg) {
+        let _ = tx_for_task.send(ServerMsg::Error {
+            slug: slug.to_string(),
+            code: "read_replica".into(),
+            message: "this server is a read-only replica; only watching is possible".into(),
+            op_id: None,
+        });
+        return Ok(());
+    }
+    if auth.lock().deadline.is_some()
+        && let Some(op_id) = refused_until_reauth(&msg)
+    {
+        let _ = tx_for_task.send(ServerMsg::Error {
+            slug: slug.to_string(),
+            code: "auth_required".into(),
+            message: "the document password changed; authenticate again to edit".into(),
+            op_id,
+        });
+        return Ok(());
+    }
     match msg {
         Hello {
             slug: hello_slug,
             client_id,
             label,
             color,
-        } => handle_hello(
-            established,
-            state,
-            slug,
-            client_meta,
-            tx_for_task,
-            hello_slug,
-            client_id,
-            label,
-            color,
-        ),
+            version,
+            capabilities,
+            subscribe,
+            user_id,
+            known,
+        } => {
+            let protocol = negotiate_or_refuse(slug, tx_for_task, version, &capabilities)?;
+            let hello = HelloReq {
+                slug: hello_slug,
+                client_id,
+                user_id,
+                label,
+                color,
+                protocol,
+                subscribe,
+                known,
+            };
+            handle_hello(established, state, slug, client_meta, tx_for_task, hello).await
+        }
         Join {
             session_id,
             client_id,
@@ -205,7 +601,12 @@ async fn handle_client_message(
             color,
             password,
             token,
+            version,
+            capabilities,
+            user_id,
+            known,
         } => {
+            let protocol = negotiate_or_refuse(slug, tx_for_task, version, &capabilities)?;
             handle_compat_join(
                 state,
                 slug,
@@ -214,10 +615,14 @@ async fn handle_client_message(
                 established,
                 session_id,
                 client_id,
+                user_id,
                 label,
                 color,
+                ticketed,
                 password,
                 token,
+                protocol,
+                known,
             )
             .await
         }
@@ -227,13 +632,28 @@ async fn handle_client_message(
             context,
         } => {
             *established = true;
-            handle_compat_op(state, slug, client_meta, session_id, operation, context).await
+            handle_compat_op(
+                state,
+                slug,
+                client_meta,
+                tx_for_task,
+                session_id,
+                operation,
+                context,
+            )
+            .await
         }
         Edit { slug: _, edit } => {
             if !*established {
                 return Ok(());
             }
-            handle_edit(state, slug, client_meta, edit).await
+            handle_edit(state, slug, client_meta, tx_for_task, edit).await
+        }
+        LineEdit { slug: _, edit } => {
+            if !*established {
+                return Ok(());
+            }
+            handle_line_edit(state, slug, client_meta, tx_for_task, edit).await
         }
         Cursor {
             slug: _,
@@ -261,11 +681,19 @@ async fn handle_client_message(
             slug: profile_slug,
             label,
             color,
+            avatar_url,
+            status,
         } => {
             if !*established {
                 return Ok(());
             }
-            handle_profile(state, slug, client_meta, profile_slug, label, color)
+            let update = ProfileUpdate {
+                label,
+                color,
+                avatar_url,
+                status,
+            };
+            handle_profile(state, slug, client_meta, profile_slug, update)
         }
         Ping { ts } => {
             if !*established {
@@ -281,9 +709,447 @@ async fn handle_client_message(
             handle_pong(state, slug, client_meta);
             Ok(())
         }
+        Watch {
+            slug: watch_slug,
+            password,
+            ticket,
+        } => {
+            handle_watch(state, slug, tx_for_task, watch_slug, password, ticket).await;
+            Ok(())
+        }
+        Unwatch { slug: watch_slug } => {
+            remove_watcher(state, &watch_slug, tx_for_task);
+            Ok(())
+        }
+        Resync { last_seq } => {
+            let _ = resync_tx.send(last_seq);
+            Ok(())
+        }
+        StateMismatch {
+            slug: mismatch_slug,
+            rev,
+            hash,
+        } => {
+            if !*established || mismatch_slug != slug {
+                return Ok(());
+            }
+            warn!(%slug, rev, ?hash, "client reported a content hash mismatch");
+            let _ = tx_for_task.send(resync_message(state, slug, true).await?);
+            Ok(())
+        }
+        SetViewport {
+            slug: viewport_slug,
+            start,
+            end,
+            unit,
+        } => {
+            if !*established || viewport_slug != slug {
+                return Ok(());
+            }
+            handle_set_viewport(state, slug, tx_for_task, start, end, unit).await
+        }
+        ClearViewport {
+            slug: viewport_slug,
+        } => {
+            if !*established || viewport_slug != slug {
+                return Ok(());
+            }
+            let _ = tx_for_task.send(resync_message(state, slug, false).await?);
+            Ok(())
+        }
+        Stats { slug: stats_slug } => {
+            if !*established || stats_slug != slug {
+                return Ok(());
+            }
+            handle_stats(state, slug, tx_for_task).await
+        }
+        SeenUpTo {
+            slug: seen_slug,
+            rev,
+        } => {
+            if !*established || seen_slug != slug {
+                return Ok(());
+            }
+            let user_id = client_meta.lock().and_then(|meta| meta.user_id);
+            if let Some(user_id) = user_id {
+                mark_seen(state, slug, user_id, rev).await?;
+            }
+            Ok(())
+        }
+        ClaimSection {
+            slug: claim_slug,
+            start,
+        } => {
+            if !*established || claim_slug != slug {
+                return Ok(());
+            }
+            let Some(meta) = current_client(client_meta) else {
+                return Ok(());
+            };
+            let result = claim_section(state, slug, meta.id, start, now_millis())
+                .await
+                .map(|claim| show_section(state, slug, meta.id, Some(claim)));
+            report_rejection(result, slug, None, tx_for_task)
+        }
+        ReleaseSection { slug: claim_slug } => {
+            if claim_slug != slug {
+                return Ok(());
+            }
+            if let Some(meta) = current_client(client_meta)
+                && release_section(state, slug, meta.id)
+            {
+                show_section(state, slug, meta.id, None);
+            }
+            Ok(())
+        }
+        Follow {
+            slug: follow_slug,
+            follow,
+        } => {
+            if !*established || follow_slug != slug {
+                return Ok(());
+            }
+            let user_id = client_meta.lock().and_then(|meta| meta.user_id);
+            if let Some(user_id) = user_id {
+                set_following(state, slug, user_id, follow).await?;
+            }
+            Ok(())
+        }
+        Replace {
+            slug: _,
+            find,
+            replace,
+            regex,
+            case_insensitive,
+            op_id,
+        } => {
+            if !*established {
+                return Ok(());
+            }
+            let spec = ReplaceSpec {
+                find,
+                replace,
+                regex,
+                case_insensitive,
+            };
+            handle_replace(state, slug, client_meta, tx_for_task, spec, op_id).await
+        }
+        AssistRequest {
+            slug: _,
+            range,
+            prompt_kind,
+            request_id,
+        } => {
+            if !*established {
+                return Ok(());
+            }
+            let Some(meta) = current_client(client_meta) else {
+                return Ok(());
+            };
+            let result = request_assist(
+                state,
+                slug,
+                meta.id,
+                range,
+                prompt_kind,
+                request_id,
+                tx_for_task.clone(),
+            )
+            .await;
+            report_rejection(result, slug, request_id, tx_for_task)
+        }
+        Roster { workspace, label } => {
+            if !*established {
+                return Ok(());
+            }
+            handle_roster(state, slug, tx_for_task, workspace, label);
+            Ok(())
+        }
+        SetPassword {
+            current,
+            new,
+            owner_token,
+        } => {
+            if !*established {
+                return Ok(());
+            }
+            handle_set_password(
+                state,
+                slug,
+                client_meta,
+                tx_for_task,
+                current,
+                new,
+                owner_token,
+            )
+            .await
+        }
+        Authenticate { password, ticket } => {
+            handle_authenticate(state, slug, tx_for_task, auth, password, ticket).await
+        }
+    }
+}
+
+/// Messages a session waiting to re-authenticate may not send, with the op
+/// id their refusal is reported under.
+fn refused_until_reauth(msg: &ClientMsg) -> Option<Option<Uuid>> {
+    match msg {
+        ClientMsg::Edit { edit, .. } => Some(edit.op_id),
+        ClientMsg::LineEdit { edit, .. } => Some(edit.op_id),
+        ClientMsg::Replace { op_id, .. } => Some(*op_id),
+        ClientMsg::AssistRequest { request_id, .. } => Some(*request_id),
+        ClientMsg::CompatOp { .. }
+        | ClientMsg::SetPassword { .. }
+        | ClientMsg::ClaimSection { .. }
+        | ClientMsg::ReleaseSection { .. }
+        | ClientMsg::Follow { .. }
+        | ClientMsg::SeenUpTo { .. }
+        | ClientMsg::Watch { .. } => Some(None),
+        _ => None,
+    }
+}
+
+/// Re-checks the session's credential once the document's access version
+/// moved; the session that made the change keeps up with it. Returns the
+/// challenge to send when the credential no longer opens the document.
+fn recheck_auth(
+    state: &AppState,
+    slug: &str,
+    doc: &Doc,
+    auth: &Mutex<SessionAuth>,
+    changed_here: bool,
+) -> Option<ServerMsg> {
+    let mut auth = auth.lock();
+    if auth.version == doc.access_version {
+        return None;
+    }
+    auth.version = doc.access_version;
+    let required = required_password_hash(doc);
+    if required.is_none() || changed_here || auth.credential.as_deref() == required {
+        auth.credential = required.map(str::to_string);
+        auth.deadline = None;
+        return None;
+    }
+    if auth.deadline.is_some() {
+        return None;
+    }
+    auth.deadline = Some(Instant::now() + Duration::from_millis(state.reauth_grace_ms));
+    Some(ServerMsg::AuthRequired {
+        slug: slug.to_string(),
+        grace_ms: state.reauth_grace_ms,
+    })
+}
+
+async fn handle_authenticate(
+    state: &AppState,
+    slug: &str,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+    auth: &Mutex<SessionAuth>,
+    password: Option<String>,
+    ticket: Option<String>,
+) -> anyhow::Result<()> {
+    let doc = get_or_load_doc(state, slug).await?;
+    let accepted = match ticket.as_deref() {
+        Some(ticket) => redeem_ticket(state, ticket, slug, now_millis()),
+        None => !state.require_ws_ticket && is_authorized(&doc.read(), password.as_deref()),
+    };
+    if !accepted {
+        let _ = tx_for_task.send(ServerMsg::Error {
+            slug: slug.to_string(),
+            code: "unauthorized".into(),
+            message: "invalid password".into(),
+            op_id: None,
+        });
+        return Ok(());
+    }
+    {
+        let mut auth = auth.lock();
+        auth.credential = required_password_hash(&doc.read()).map(str::to_string);
+        auth.deadline = None;
+    }
+    let _ = tx_for_task.send(ServerMsg::Authenticated {
+        slug: slug.to_string(),
+    });
+    Ok(())
+}
+
+/// The session was let in with the password of its time, so `current` (or
+/// the owner token) is checked again against the document as it is now.
+async fn handle_set_password(
+    state: &AppState,
+    slug: &str,
+    client_meta: &Arc<Mutex<Option<ClientMeta>>>,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+    current: Option<String>,
+    new: Option<String>,
+    owner_token: Option<String>,
+) -> anyhow::Result<()> {
+    let Some(meta) = current_client(client_meta) else {
+        return Ok(());
+    };
+    let doc = get_or_load_doc(state, slug).await?;
+    let result = change_password(
+        state,
+        slug,
+        &doc,
+        current.as_deref().unwrap_or_default(),
+        new.as_deref().unwrap_or_default(),
+        owner_token.as_deref(),
+        Some(meta.id),
+    );
+    if result.is_ok() {
+        info!(%slug, client_id = %meta.id, "password changed over websocket");
+    }
+    report_rejection(result, slug, None, tx_for_task)
+}
+
+fn handle_roster(
+    state: &AppState,
+    slug: &str,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+    workspace: Option<String>,
+    label: Option<String>,
+) {
+    let Some(workspace) = workspace.or_else(|| workspace_of(slug).map(str::to_string)) else {
+        let _ = tx_for_task.send(ServerMsg::Error {
+            slug: slug.to_string(),
+            code: "no_workspace".into(),
+            message: "this document is not in a workspace".into(),
+            op_id: None,
+        });
+        return;
+    };
+    let viewer = Viewer::Member(slug.to_string());
+    let members = workspace_roster(state, &workspace, label.as_deref(), |other| {
+        viewer.can_see(state, other)
+    });
+    let _ = tx_for_task.send(ServerMsg::Roster {
+        slug: slug.to_string(),
+        workspace,
+        members,
+    });
+}
+
+async fn handle_stats(
+    state: &AppState,
+    slug: &str,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+) -> anyhow::Result<()> {
+    let doc = get_or_load_doc(state, slug).await?;
+    let msg = {
+        let d = doc.read();
+        ServerMsg::Stats {
+            slug: slug.to_string(),
+            rev: d.rev,
+            stats: doc_stats(&d.content),
+        }
+    };
+    let _ = tx_for_task.send(msg);
+    Ok(())
+}
+
+async fn handle_set_viewport(
+    state: &AppState,
+    slug: &str,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+    start: usize,
+    end: usize,
+    unit: ViewportUnit,
+) -> anyhow::Result<()> {
+    let doc = get_or_load_doc(state, slug).await?;
+    let msg = {
+        let d = doc.read();
+        let (start, end) = resolve_window(&d.content, unit, start, end);
+        viewport_message(slug, &d, start, end)
+    };
+    let _ = tx_for_task.send(msg);
+    Ok(())
+}
+
+async fn resync_message(state: &AppState, slug: &str, compat: bool) -> anyhow::Result<ServerMsg> {
+    let doc = get_or_load_doc(state, slug).await?;
+    let d = doc.read();
+    Ok(if compat {
+        ServerMsg::CompatSnapshot {
+            session_id: slug.to_string(),
+            rev: d.rev,
+            content: d.content.clone(),
+            presence: None,
+            versions: d.versions.clone(),
+            chunked: false,
+        }
+    } else {
+        ServerMsg::Resync {
+            slug: slug.to_string(),
+            rev: d.rev,
+            content: d.content.clone(),
+        }
+    })
+}
+
+/// What a client gets when its missed frames are gone: the whole document,
+/// or just its window when it set a viewport.
+pub(super) async fn resync_fallback(
+    state: &AppState,
+    slug: &str,
+    compat: bool,
+    viewport: Option<&mut ViewportFilter>,
+) -> anyhow::Result<ServerMsg> {
+    match viewport {
+        Some(filter) => {
+            let doc = get_or_load_doc(state, slug).await?;
+            let d = doc.read();
+            Ok(filter.rebuild(&d))
+        }
+        None => resync_message(state, slug, compat).await,
     }
 }
 
+async fn handle_watch(
+    state: &AppState,
+    slug: &str,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+    watch_slug: String,
+    password: Option<String>,
+    ticket: Option<String>,
+) {
+    let refuse = |code: &str, message: &str| {
+        let _ = tx_for_task.send(ServerMsg::Error {
+            slug: watch_slug.clone(),
+            code: code.to_string(),
+            message: message.to_string(),
+            op_id: None,
+        });
+    };
+    let doc = match get_existing_doc(state, &watch_slug).await {
+        Ok(Some(doc)) => doc,
+        Ok(None) => return refuse("not_found", "document does not exist"),
+        Err(_) => return refuse("invalid_slug", "invalid document slug"),
+    };
+    let (rev, credential) = {
+        let d = doc.read();
+        let accepted = match ticket.as_deref() {
+            Some(ticket) => redeem_ticket(state, ticket, &watch_slug, now_millis()),
+            None => !state.require_ws_ticket && is_authorized(&d, password.as_deref()),
+        };
+        if !accepted {
+            return refuse("unauthorized", "password required to watch this document");
+        }
+        if d.meta.archived_at.is_some() {
+            return refuse("archived", "document is archived");
+        }
+        (d.rev, required_password_hash(&d).map(str::to_string))
+    };
+    // The socket's own document already reaches it through `subs`.
+    if watch_slug != slug {
+        add_watcher(state, &watch_slug, tx_for_task, credential);
+    }
+    let _ = tx_for_task.send(ServerMsg::Watching {
+        slug: watch_slug,
+        rev,
+    });
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn handle_compat_join(
     state: &AppState,
@@ -293,10 +1159,14 @@ async fn handle_compat_join(
     established: &mut bool,
     session_id: String,
     client_id: Uuid,
+    user_id: Option<Uuid>,
     label: Option<String>,
     color: Option<String>,
+    ticketed: bool,
     password: Option<String>,
     token: Option<String>,
+    protocol: Option<ProtocolInfo>,
+    known: Option<KnownState>,
 ) -> anyhow::Result<()> {
     if session_id != slug {
         warn!(expected = %slug, received = %session_id, "compat join slug mismatch");
@@ -304,16 +1174,18 @@ async fn handle_compat_join(
     }
 
     let doc = get_or_load_doc(state, slug).await?;
-    let mut provided = password;
-    if provided.is_none()
-        && let Some(tk) = token.as_deref()
-    {
-        provided = extract_password_from_token(tk, slug);
+    let mut provided = None;
+    if !state.require_ws_ticket {
+        provided = password.or_else(|| {
+            token
+                .as_deref()
+                .and_then(|tk| extract_password_from_token(tk, slug))
+        });
     }
 
     {
         let guard = doc.read();
-        if !is_authorized(&guard, provided.as_deref()) {
+        if !ticketed && !is_authorized(&guard, provided.as_deref()) {
             return Err(anyhow!("unauthorized compat join request"));
         }
     }
@@ -322,16 +1194,23 @@ async fn handle_compat_join(
         let mut guard = client_meta.lock();
         *guard = Some(ClientMeta {
             id: client_id,
+            user_id,
             compat: true,
+            line_ops: use_line_ops(&doc, protocol.as_ref(), client_id),
+            snapshot_chunks: negotiated(protocol.as_ref(), "snapshot_chunks"),
+            compression: negotiated(protocol.as_ref(), "compression"),
         });
     }
 
+    let lazy = negotiated(protocol.as_ref(), "lazy_snapshot");
     let now = now_millis();
-    let (presence_snapshot, added) = register_presence(state, slug, client_id, label, color, now);
+    let (presence_snapshot, added) =
+        register_presence(state, slug, client_id, user_id, label, color, now);
     if tx_for_task
         .send(ServerMsg::PresenceSnapshot {
             slug: slug.to_string(),
             clients: presence_snapshot.clone(),
+            protocol,
         })
         .is_err()
     {
@@ -349,22 +1228,83 @@ async fn handle_compat_join(
         },
     );
 
+    send_owner_grant(state, slug, tx_for_task).await;
+    send_expiry(&doc, slug, now, tx_for_task);
+
     let doc_guard = doc.read();
-    let _ = tx_for_task.send(ServerMsg::CompatSnapshot {
-        session_id: slug.to_string(),
-        rev: doc_guard.rev,
-        content: doc_guard.content.clone(),
-        presence: Some(presence_snapshot),
-    });
+    let snapshot = if is_current(&doc_guard, known) {
+        ServerMsg::SnapshotCurrent {
+            session_id: slug.to_string(),
+            rev: doc_guard.rev,
+            presence: Some(presence_snapshot),
+            versions: doc_guard.versions.clone(),
+        }
+    } else if lazy {
+        ServerMsg::SnapshotRef {
+            session_id: slug.to_string(),
+            rev: doc_guard.rev,
+            content_hash: content_hash(&doc_guard.content),
+            length: doc_guard.content.len(),
+            presence: Some(presence_snapshot),
+            versions: doc_guard.versions.clone(),
+        }
+    } else {
+        ServerMsg::CompatSnapshot {
+            session_id: slug.to_string(),
+            rev: doc_guard.rev,
+            content: doc_guard.content.clone(),
+            presence: Some(presence_snapshot),
+            versions: doc_guard.versions.clone(),
+            chunked: false,
+        }
+    };
+    let _ = tx_for_task.send(snapshot);
 
     *established = true;
     Ok(())
 }
 
+/// Whether the copy a client kept is the document as it stands.
+fn is_current(doc: &Doc, known: Option<KnownState>) -> bool {
+    known.is_some_and(|known| {
+        known.rev == doc.rev && known.content_hash == content_hash(&doc.content)
+    })
+}
+
+async fn send_owner_grant(
+    state: &AppState,
+    slug: &str,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+) {
+    match claim_ownership(state, slug, OwnerClaim::NewDocument).await {
+        Ok(Some(owner_token)) => {
+            let _ = tx_for_task.send(ServerMsg::OwnerGranted {
+                slug: slug.to_string(),
+                owner_token,
+            });
+        }
+        Ok(None) => {}
+        Err(err) => error!(%slug, "failed to record document owner: {:#}", err),
+    }
+}
+
+fn send_expiry(
+    doc: &RwLock<Doc>,
+    slug: &str,
+    now: u64,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+) {
+    let expires_at = doc.read().meta.settings.expires_at;
+    if expires_at.is_some() {
+        let _ = tx_for_task.send(expiry_notice(slug, expires_at, now));
+    }
+}
+
 async fn handle_compat_op(
     state: &AppState,
     slug: &str,
     client_meta: &Arc<Mutex<Option<ClientMeta>>>,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
     session_id: String,
     operation: OpKind,
     context: CompatOpContext,
@@ -382,7 +1322,7 @@ async fn handle_compat_op(
         ts,
     } = context;
 
-    let effective_client_id = {
+    let (effective_client_id, user_id) = {
         let mut guard = client_meta.lock();
         match *guard {
             Some(mut meta) => {
@@ -390,15 +1330,19 @@ async fn handle_compat_op(
                     meta.compat = true;
                     *guard = Some(meta);
                 }
-                meta.id
+                (meta.id, meta.user_id)
             }
             None => {
                 let cid = ctx_client_id.ok_or_else(|| anyhow!("compat op missing client id"))?;
                 *guard = Some(ClientMeta {
                     id: cid,
+                    user_id: None,
                     compat: true,
+                    line_ops: false,
+                    snapshot_chunks: false,
+                    compression: false,
                 });
-                cid
+                (cid, None)
             }
         }
     };
@@ -413,48 +1357,243 @@ async fn handle_compat_op(
         op_id,
         cursor_before: None,
         cursor_after: selection.map(CursorState::from),
-        ts: ts.or(Some(now)),
+        ts,
+        group_id: None,
+        user_id,
     };
 
-    apply_edit(state, slug, edit).await?;
+    let result = apply_edit(state, slug, edit).await;
+    report_edit_result(state, slug, true, result, op_id, tx_for_task).await
+}
+
+/// Negotiates the protocol for a `Hello`/`Join`. An unsupported version is
+/// reported to the client and then closes the connection.
+fn negotiate_or_refuse(
+    slug: &str,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+    version: Option<u32>,
+    capabilities: &[String],
+) -> anyhow::Result<Option<ProtocolInfo>> {
+    negotiate(version, capabilities).map_err(|rejection| {
+        let _ = tx_for_task.send(ServerMsg::Error {
+            slug: slug.to_string(),
+            code: rejection.code.to_string(),
+            message: rejection.message.clone(),
+            op_id: None,
+        });
+        rejection.into()
+    })
+}
+
+/// Turns a [`Rejection`] into an `Error` message for the client instead of
+/// tearing down the connection; other failures are propagated.
+fn report_rejection(
+    result: anyhow::Result<()>,
+    slug: &str,
+    op_id: Option<Uuid>,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+) -> anyhow::Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => match err.downcast::<Rejection>() {
+            Ok(rejection) => {
+                let msg = match rejection.invalid_op {
+                    Some(invalid) => ServerMsg::InvalidOp {
+                        slug: slug.to_string(),
+                        op_id,
+                        index: invalid.index,
+                        reason: invalid.reason.to_string(),
+                        doc_len: invalid.doc_len,
+                        message: rejection.message,
+                        resync: true,
+                    },
+                    None => ServerMsg::Error {
+                        slug: slug.to_string(),
+                        code: rejection.code.to_string(),
+                        message: rejection.message,
+                        op_id,
+                    },
+                };
+                let _ = tx_for_task.send(msg);
+                Ok(())
+            }
+            Err(err) => Err(err),
+        },
+    }
+}
+
+/// Like [`report_rejection`], but a client whose edit showed it diverged or
+/// sent invalid ops also gets the full document to start over from.
+async fn report_edit_result(
+    state: &AppState,
+    slug: &str,
+    compat: bool,
+    result: anyhow::Result<()>,
+    op_id: Option<Uuid>,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+) -> anyhow::Result<()> {
+    let diverged = result.as_ref().is_err_and(|err| {
+        err.downcast_ref::<Rejection>()
+            .is_some_and(|rejection| [DIVERGED, INVALID_OP].contains(&rejection.code))
+    });
+    report_rejection(result, slug, op_id, tx_for_task)?;
+    if diverged {
+        let _ = tx_for_task.send(resync_message(state, slug, compat).await?);
+    }
     Ok(())
 }
 
-fn current_client(meta: &Arc<Mutex<Option<ClientMeta>>>) -> Option<ClientMeta> {
+/// The close code and reason a session is ended with right after `msg`, for
+/// errors it cannot carry on from.
+pub(super) fn close_code_after(msg: &ServerMsg) -> Option<(u16, &'static str)> {
+    let ServerMsg::Error { code, .. } = msg else {
+        return None;
+    };
+    match code.as_str() {
+        // The client reconnects and gets forwarded to the new node.
+        "moved" | "not_owner" => Some((CLOSE_MOVED, "document moved")),
+        "archived" | "deleted" => Some((CLOSE_DOC_GONE, "document gone")),
+        "draining" => Some((CLOSE_DRAINING, "server shutting down")),
+        "unsupported_version" => Some((CLOSE_PROTOCOL_ERROR, "unsupported protocol")),
+        _ => None,
+    }
+}
+
+fn close_frame(code: u16, reason: &'static str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
+
+/// Sends `frame`, counting it for `/api/admin/connections`. Returns `false`
+/// once the socket is gone.
+async fn send_counted(
+    sender: &mut SplitSink<WebSocket, Message>,
+    conn: &Connection,
+    frame: Message,
+) -> bool {
+    let len = message_len(&frame);
+    if sender.send(frame).await.is_err() {
+        return false;
+    }
+    conn.record_sent(len, now_millis());
+    true
+}
+
+fn message_len(msg: &Message) -> usize {
+    match msg {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(_) => 0,
+    }
+}
+
+/// A ping frame carrying the time it was sent; clients echo it in their pong.
+fn rtt_probe() -> Message {
+    Message::Ping(now_millis().to_be_bytes().to_vec())
+}
+
+/// The round trip of the probe `payload` echoes, if it echoes one.
+fn rtt_sample(payload: &[u8], now: u64) -> Option<u64> {
+    let sent = u64::from_be_bytes(payload.try_into().ok()?);
+    (sent <= now).then(|| now - sent)
+}
+
+pub(super) fn current_client(meta: &Arc<Mutex<Option<ClientMeta>>>) -> Option<ClientMeta> {
     *meta.lock()
 }
 
-fn handle_hello(
+/// Size from which frames to this session are compressed; 0 when it did
+/// not negotiate `compression`.
+fn compress_threshold(state: &AppState, meta: Option<ClientMeta>) -> usize {
+    if meta.is_some_and(|m| m.compression) {
+        state.ws_compress_threshold
+    } else {
+        0
+    }
+}
+
+fn negotiated(protocol: Option<&ProtocolInfo>, capability: &str) -> bool {
+    protocol.is_some_and(|p| p.capabilities.iter().any(|c| c == capability))
+}
+
+/// Joins the document's line log when the session negotiated `line_ops`.
+fn use_line_ops(doc: &RwLock<Doc>, protocol: Option<&ProtocolInfo>, client_id: Uuid) -> bool {
+    let wanted = negotiated(protocol, "line_ops");
+    if wanted {
+        join_line_log(&mut doc.write(), client_id);
+    }
+    wanted
+}
+
+/// Whether `msg` is the form of an edit broadcast this session did not ask
+/// for: line sessions get `LineApplied`, everyone else `Applied`.
+pub(super) fn other_edit_form(msg: &ServerMsg, slug: &str, line_ops: bool) -> bool {
+    match msg {
+        ServerMsg::Applied { slug: s, .. } => line_ops && s == slug,
+        ServerMsg::LineApplied { .. } => !line_ops,
+        _ => false,
+    }
+}
+
+/// A `Hello` once its protocol version was negotiated.
+struct HelloReq {
+    slug: String,
+    client_id: Uuid,
+    user_id: Option<Uuid>,
+    label: Option<String>,
+    color: Option<String>,
+    protocol: Option<ProtocolInfo>,
+    subscribe: Option<Vec<MessageClass>>,
+    known: Option<KnownState>,
+}
+
+async fn handle_hello(
     established: &mut bool,
     state: &AppState,
     slug: &str,
     client_meta: &Arc<Mutex<Option<ClientMeta>>>,
     tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
-    hello_slug: String,
-    client_id: Uuid,
-    label: Option<String>,
-    color: Option<String>,
+    hello: HelloReq,
 ) -> anyhow::Result<()> {
     if *established {
         return Ok(());
     }
+    let HelloReq {
+        slug: hello_slug,
+        client_id,
+        user_id,
+        label,
+        color,
+        protocol,
+        subscribe,
+        known,
+    } = hello;
     if hello_slug != slug {
         warn!(expected = %slug, received = %hello_slug, "hello slug mismatch");
         return Err(anyhow!("hello slug mismatch"));
     }
+    let doc = get_or_load_doc(state, slug).await?;
     {
         let mut guard = client_meta.lock();
         *guard = Some(ClientMeta {
             id: client_id,
+            user_id,
             compat: false,
+            line_ops: use_line_ops(&doc, protocol.as_ref(), client_id),
+            snapshot_chunks: negotiated(protocol.as_ref(), "snapshot_chunks"),
+            compression: negotiated(protocol.as_ref(), "compression"),
         });
     }
+    set_subscription(state, slug, tx_for_task, client_id, subscribe);
     let now = now_millis();
-    let (snapshot, added) = register_presence(state, slug, client_id, label, color, now);
+    let (snapshot, added) = register_presence(state, slug, client_id, user_id, label, color, now);
     if tx_for_task
         .send(ServerMsg::PresenceSnapshot {
             slug: slug.to_string(),
             clients: snapshot,
+            protocol,
         })
         .is_err()
     {
@@ -470,6 +1609,29 @@ fn handle_hello(
             removed: vec![],
         },
     );
+    send_owner_grant(state, slug, tx_for_task).await;
+    send_expiry(&doc, slug, now, tx_for_task);
+    if known.is_some() {
+        let d = doc.read();
+        let msg = if is_current(&d, known) {
+            ServerMsg::SnapshotCurrent {
+                session_id: slug.to_string(),
+                rev: d.rev,
+                presence: None,
+                versions: d.versions.clone(),
+            }
+        } else {
+            ServerMsg::Resync {
+                slug: slug.to_string(),
+                rev: d.rev,
+                content: d.content.clone(),
+            }
+        };
+        let _ = tx_for_task.send(msg);
+    }
+    if let Some(notice) = degraded_notice(state, slug) {
+        let _ = tx_for_task.send(notice);
+    }
     *established = true;
     Ok(())
 }
@@ -478,21 +1640,73 @@ async fn handle_edit(
     state: &AppState,
     slug: &str,
     client_meta: &Arc<Mutex<Option<ClientMeta>>>,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
     mut edit: Edit,
 ) -> anyhow::Result<()> {
-    let cid = match current_client(client_meta) {
-        Some(meta) => meta.id,
-        None => return Ok(()),
+    let Some(meta) = current_client(client_meta) else {
+        return Ok(());
+    };
+    let cid = meta.id;
+    let now = now_millis();
+    touch_presence(state, slug, &cid, now);
+    if edit.client_id.is_none() {
+        edit.client_id = Some(cid);
+    }
+    edit.user_id = meta.user_id;
+    let op_id = edit.op_id;
+    let result = apply_edit(state, slug, edit).await;
+    report_edit_result(state, slug, meta.compat, result, op_id, tx_for_task).await
+}
+
+async fn handle_line_edit(
+    state: &AppState,
+    slug: &str,
+    client_meta: &Arc<Mutex<Option<ClientMeta>>>,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+    mut edit: LineEdit,
+) -> anyhow::Result<()> {
+    let Some(meta) = current_client(client_meta) else {
+        return Ok(());
     };
+    let cid = meta.id;
     let now = now_millis();
     touch_presence(state, slug, &cid, now);
     if edit.client_id.is_none() {
         edit.client_id = Some(cid);
     }
-    if edit.ts.is_none() {
-        edit.ts = Some(now);
+    edit.user_id = meta.user_id;
+    let op_id = edit.op_id;
+    let result = apply_line_edit(state, slug, edit).await;
+    report_edit_result(state, slug, meta.compat, result, op_id, tx_for_task).await
+}
+
+async fn handle_replace(
+    state: &AppState,
+    slug: &str,
+    client_meta: &Arc<Mutex<Option<ClientMeta>>>,
+    tx_for_task: &mpsc::UnboundedSender<ServerMsg>,
+    spec: ReplaceSpec,
+    op_id: Option<Uuid>,
+) -> anyhow::Result<()> {
+    let Some(meta) = current_client(client_meta) else {
+        return Ok(());
+    };
+    touch_presence(state, slug, &meta.id, now_millis());
+    match replace_in_doc(state, slug, &spec, Some(meta.id), meta.user_id, op_id).await {
+        Ok(report) => {
+            let _ = tx_for_task.send(ServerMsg::Replaced {
+                slug: slug.to_string(),
+                rev: report.rev,
+                matches: report.matches,
+                op_id,
+                group_id: report.group_id,
+            });
+            Ok(())
+        }
+        Err(err) => {
+            report_edit_result(state, slug, meta.compat, Err(err), op_id, tx_for_task).await
+        }
     }
-    apply_edit(state, slug, edit).await
 }
 
 fn handle_cursor(
@@ -506,22 +1720,13 @@ fn handle_cursor(
     if let Some(meta) = current_client(client_meta) {
         let cid = meta.id;
         let server_now = now_millis();
-        let ts_value = ts.unwrap_or(server_now);
+        let ts_value = clamp_client_ts(state, slug, Some(cid), ts, server_now);
         if let Some(updated) = update_presence_cursor(state, slug, cid, cursor.clone(), server_now)
         {
-            let mut should_append = true;
+            // Presence is ephemeral and never reaches the WAL; the op id is
+            // still remembered so a retry is recognised.
             if let Some(id) = op_id {
-                should_append = remember_op_id(state, slug, id);
-            }
-            if should_append {
-                let event = DocEvent::Cursor {
-                    client_id: cid,
-                    op_id,
-                    cursor: cursor.clone(),
-                };
-                if let Err(err) = wal_append_event(state, slug, &event, ts_value) {
-                    error!("failed to append cursor event: {:#}", err);
-                }
+                remember_op_id(state, slug, id);
             }
             broadcast(
                 state,
@@ -560,21 +1765,10 @@ fn handle_ime(
     if let Some(meta) = current_client(client_meta) {
         let cid = meta.id;
         let server_now = now_millis();
-        let ts_value = ts.unwrap_or(server_now);
+        let ts_value = clamp_client_ts(state, slug, Some(cid), ts, server_now);
         if let Some(updated) = update_presence_ime(state, slug, cid, &ime, server_now) {
-            let mut should_append = true;
             if let Some(id) = op_id {
-                should_append = remember_op_id(state, slug, id);
-            }
-            if should_append {
-                let event = DocEvent::Ime {
-                    client_id: cid,
-                    op_id,
-                    ime: ime.clone(),
-                };
-                if let Err(err) = wal_append_event(state, slug, &event, ts_value) {
-                    error!("failed to append ime event: {:#}", err);
-                }
+                remember_op_id(state, slug, id);
             }
             broadcast(
                 state,
@@ -607,8 +1801,7 @@ fn handle_profile(
     slug: &str,
     client_meta: &Arc<Mutex<Option<ClientMeta>>>,
     profile_slug: String,
-    label: Option<String>,
-    color: Option<String>,
+    update: ProfileUpdate,
 ) -> anyhow::Result<()> {
     if profile_slug != slug {
         warn!(expected = %slug, received = %profile_slug, "profile slug mismatch");
@@ -617,7 +1810,7 @@ fn handle_profile(
     if let Some(meta) = current_client(client_meta) {
         let cid = meta.id;
         let now = now_millis();
-        if let Some(updated) = update_presence_profile(state, slug, cid, label, color, now) {
+        if let Some(updated) = update_presence_profile(state, slug, cid, update, now) {
             broadcast(
                 state,
                 slug,
@@ -633,6 +1826,23 @@ fn handle_profile(
     Ok(())
 }
 
+/// Puts a claimed section into the presence of `client_id`, or takes it
+/// out, and tells everyone.
+fn show_section(state: &AppState, slug: &str, client_id: Uuid, section: Option<SectionClaim>) {
+    if let Some(updated) = set_presence_section(state, slug, client_id, section) {
+        broadcast(
+            state,
+            slug,
+            ServerMsg::PresenceDiff {
+                slug: slug.to_string(),
+                added: vec![],
+                updated: vec![updated],
+                removed: vec![],
+            },
+        );
+    }
+}
+
 fn handle_ping(
     state: &AppState,
     slug: &str,
@@ -651,3 +1861,275 @@ fn handle_pong(state: &AppState, slug: &str, client_meta: &Arc<Mutex<Option<Clie
         touch_presence(state, slug, &meta.id, now_millis());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::hash_password;
+
+    #[test]
+    fn stale_credentials_are_challenged_once() {
+        let state = AppState::new(
+            "wal".into(),
+            "snapshots".into(),
+            10_000,
+            1_000_000,
+            true,
+            Vec::new(),
+        );
+        let mut doc = Doc {
+            password_hash: Some(hash_password("old")),
+            ..Default::default()
+        };
+        let auth = Mutex::new(SessionAuth {
+            credential: doc.password_hash.clone(),
+            ..Default::default()
+        });
+        let change = |doc: &mut Doc, password: Option<&str>| {
+            doc.password_hash = password.map(hash_password);
+            doc.access_version += 1;
+        };
+
+        change(&mut doc, Some("new"));
+        assert!(recheck_auth(&state, "doc", &doc, &auth, true).is_none());
+        assert_eq!(auth.lock().credential, doc.password_hash);
+
+        change(&mut doc, Some("newer"));
+        let challenge = recheck_auth(&state, "doc", &doc, &auth, false);
+        assert!(matches!(
+            challenge,
+            Some(ServerMsg::AuthRequired { grace_ms, .. }) if grace_ms == state.reauth_grace_ms
+        ));
+        assert!(auth.lock().deadline.is_some());
+        assert!(recheck_auth(&state, "doc", &doc, &auth, false).is_none());
+
+        change(&mut doc, None);
+        assert!(recheck_auth(&state, "doc", &doc, &auth, false).is_none());
+        assert!(auth.lock().deadline.is_none());
+    }
+
+    #[tokio::test]
+    async fn sessions_keep_the_credential_they_were_admitted_with() {
+        let base = std::env::temp_dir().join(format!("ws-admit-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000_000,
+            true,
+            Vec::new(),
+        );
+        let doc = get_or_load_doc(&state, "doc").await.unwrap();
+        doc.write().password_hash = Some(hash_password("old"));
+        let q = WsQuery {
+            slug: "doc".into(),
+            token: None,
+            password: Some("old".into()),
+            ticket: None,
+        };
+        let admitted = admit(&state, q, &HeaderMap::new()).await.unwrap();
+
+        // The password changes before the socket is upgraded.
+        {
+            let mut d = doc.write();
+            d.password_hash = Some(hash_password("new"));
+            d.access_version += 1;
+        }
+        let auth = admitted.auth.lock();
+        assert_eq!(auth.credential, Some(hash_password("old")));
+        assert_eq!(auth.version, 0);
+        drop(auth);
+        assert!(matches!(
+            recheck_auth(&state, "doc", &doc.read(), &admitted.auth, false),
+            Some(ServerMsg::AuthRequired { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn watch_takes_only_tickets_when_the_server_requires_them() {
+        let base = std::env::temp_dir().join(format!("ws-watch-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000_000,
+            true,
+            Vec::new(),
+        );
+        state.require_ws_ticket = true;
+        let doc = get_or_load_doc(&state, "other").await.unwrap();
+        doc.write().password_hash = Some(hash_password("pw"));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        handle_watch(&state, "doc", &tx, "other".into(), Some("pw".into()), None).await;
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            ServerMsg::Error { ref code, .. } if code == "unauthorized"
+        ));
+
+        let ticket = crate::ticket::issue_ticket(&state, "other", now_millis()).ticket;
+        handle_watch(&state, "doc", &tx, "other".into(), None, Some(ticket)).await;
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            ServerMsg::Watching { ref slug, .. } if slug == "other"
+        ));
+    }
+
+    #[tokio::test]
+    async fn join_with_a_current_copy_skips_the_content() {
+        let base = std::env::temp_dir().join(format!("ws-known-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000_000,
+            true,
+            Vec::new(),
+        );
+        let doc = get_or_load_doc(&state, "doc").await.unwrap();
+        {
+            let mut d = doc.write();
+            d.content = "cached".into();
+            d.rev = 4;
+        }
+        let state = &state;
+        let join = |known| async move {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            handle_compat_join(
+                state,
+                "doc",
+                &Arc::new(Mutex::new(None)),
+                &tx,
+                &mut false,
+                "doc".into(),
+                Uuid::new_v4(),
+                None,
+                None,
+                None,
+                true,
+                None,
+                None,
+                None,
+                known,
+            )
+            .await
+            .unwrap();
+            std::iter::from_fn(|| rx.try_recv().ok()).last().unwrap()
+        };
+
+        let current = KnownState {
+            rev: 4,
+            content_hash: content_hash("cached"),
+        };
+        assert!(matches!(
+            join(Some(current)).await,
+            ServerMsg::SnapshotCurrent { rev: 4, .. }
+        ));
+        let stale = KnownState { rev: 3, ..current };
+        assert!(matches!(
+            join(Some(stale)).await,
+            ServerMsg::CompatSnapshot { rev: 4, ref content, .. } if content == "cached"
+        ));
+        let diverged = KnownState {
+            content_hash: content_hash("other"),
+            ..current
+        };
+        assert!(matches!(
+            join(Some(diverged)).await,
+            ServerMsg::CompatSnapshot { .. }
+        ));
+    }
+
+    #[test]
+    fn unchanged_access_version_skips_the_check() {
+        let state = AppState::new(
+            "wal".into(),
+            "snapshots".into(),
+            10_000,
+            1_000_000,
+            true,
+            Vec::new(),
+        );
+        let doc = Doc {
+            password_hash: Some(hash_password("pw")),
+            access_version: 3,
+            ..Default::default()
+        };
+        let auth = Mutex::new(SessionAuth {
+            credential: None,
+            version: 3,
+            deadline: None,
+        });
+        assert!(recheck_auth(&state, "doc", &doc, &auth, false).is_none());
+        auth.lock().version = 2;
+        assert!(recheck_auth(&state, "doc", &doc, &auth, false).is_some());
+    }
+
+    #[test]
+    fn fatal_errors_close_with_their_code() {
+        let error = |code: &str| ServerMsg::Error {
+            slug: "doc".into(),
+            code: code.into(),
+            message: String::new(),
+            op_id: None,
+        };
+        assert_eq!(
+            close_code_after(&error("moved")).map(|c| c.0),
+            Some(CLOSE_MOVED)
+        );
+        assert_eq!(
+            close_code_after(&error("deleted")).map(|c| c.0),
+            Some(CLOSE_DOC_GONE)
+        );
+        assert_eq!(
+            close_code_after(&error("draining")).map(|c| c.0),
+            Some(CLOSE_DRAINING)
+        );
+        assert_eq!(close_code_after(&error("read_only")), None);
+        assert_eq!(close_code_after(&ServerMsg::Pong { ts: None }), None);
+    }
+
+    #[test]
+    fn pongs_echoing_a_probe_time_the_round_trip() {
+        let Message::Ping(payload) = rtt_probe() else {
+            panic!("expected a ping frame");
+        };
+        let sent = u64::from_be_bytes(payload.as_slice().try_into().unwrap());
+        assert_eq!(rtt_sample(&payload, sent + 42), Some(42));
+        assert_eq!(rtt_sample(&payload, sent - 1), None);
+        // Unsolicited pongs carry nothing to time.
+        assert_eq!(rtt_sample(&[], sent), None);
+    }
+
+    #[test]
+    fn edits_wait_for_reauthentication() {
+        let op_id = Uuid::new_v4();
+        let msg = ClientMsg::Replace {
+            slug: "doc".into(),
+            find: "a".into(),
+            replace: "b".into(),
+            regex: false,
+            case_insensitive: false,
+            op_id: Some(op_id),
+        };
+        assert_eq!(refused_until_reauth(&msg), Some(Some(op_id)));
+        let claim = ClientMsg::ClaimSection {
+            slug: "doc".into(),
+            start: 0,
+        };
+        assert_eq!(refused_until_reauth(&claim), Some(None));
+        let watch = ClientMsg::Watch {
+            slug: "other".into(),
+            password: None,
+            ticket: None,
+        };
+        assert_eq!(refused_until_reauth(&watch), Some(None));
+        assert_eq!(refused_until_reauth(&ClientMsg::Pong), None);
+    }
+}
@@ -0,0 +1,365 @@
+//! Portable export and import of a document together with its edit history,
+//! for moving a single document between instances.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    content_type::check_content_type,
+    document::{Doc, apply_ops},
+    quota::record_bytes,
+    state::{AppState, Rejection, doc_exists, get_or_load_doc, now_millis},
+    storage::{load_meta, persist_meta, read_snapshot, read_wal, wal_append_event, write_snapshot},
+    types::{ContentType, DocEvent, DocMeta, Edit, OpKind},
+    wal_replay::{Replayed, Replayer},
+};
+
+pub const HISTORY_FORMAT: &str = "coedit-history";
+pub const HISTORY_VERSION: u32 = 1;
+
+/// One revision, with ops already transformed into server order so they
+/// apply in sequence without rebasing.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HistoryEdit {
+    pub rev: u64,
+    pub ts: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
+    /// Revisions right before `rev` that history compaction folded into
+    /// this one; they have no edits of their own.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub folded: u64,
+    pub ops: Vec<OpKind>,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Edits up to `snapshot_rev` are history only: their effect is already in
+/// `snapshot`, and replaying the rest on top of it must give `content`.
+/// Passwords and owner tokens are not exported.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HistoryArchive {
+    pub format: String,
+    pub version: u32,
+    pub slug: String,
+    pub exported_at: u64,
+    pub snapshot_rev: u64,
+    /// Revisions up to here were purged and have no edits in the archive.
+    #[serde(default)]
+    pub history_start: u64,
+    /// Edits up to here carry scrubbed text.
+    #[serde(default)]
+    pub scrubbed_rev: u64,
+    pub snapshot: String,
+    pub rev: u64,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<ContentType>,
+    pub edits: Vec<HistoryEdit>,
+}
+
+impl HistoryArchive {
+    pub fn validate(&self) -> Result<(), Rejection> {
+        if self.format != HISTORY_FORMAT || self.version != HISTORY_VERSION {
+            return Err(Rejection::new(
+                "unsupported_format",
+                format!(
+                    "expected {} v{}, got {} v{}",
+                    HISTORY_FORMAT, HISTORY_VERSION, self.format, self.version
+                ),
+            ));
+        }
+        if let Some(content_type) = &self.content_type {
+            check_content_type(content_type)?;
+        }
+        let invalid = |message: String| Err(Rejection::new("invalid_history", message));
+        let revs = self.edits.iter().fold(0u64, |revs, edit| {
+            revs.saturating_add(edit.folded).saturating_add(1)
+        });
+        if self.history_start.saturating_add(revs) != self.rev
+            || self.snapshot_rev > self.rev
+            || self.history_start > self.snapshot_rev
+            || self.scrubbed_rev > self.snapshot_rev
+        {
+            return invalid(format!(
+                "{} edits after rev {} do not add up to rev {} (snapshot at rev {})",
+                self.edits.len(),
+                self.history_start,
+                self.rev,
+                self.snapshot_rev
+            ));
+        }
+        let mut doc = Doc {
+            content: self.snapshot.clone(),
+            ..Default::default()
+        };
+        let mut seen = HashSet::new();
+        let mut rev = self.history_start;
+        for edit in &self.edits {
+            rev = rev.saturating_add(edit.folded).saturating_add(1);
+            if edit.rev != rev {
+                return invalid(format!("edit {} has rev {}", rev, edit.rev));
+            }
+            if edit.ops.is_empty() {
+                return invalid(format!("rev {} has no ops", edit.rev));
+            }
+            if let Some(id) = edit.op_id
+                && !seen.insert(id)
+            {
+                return invalid(format!("rev {} repeats op_id {}", edit.rev, id));
+            }
+            if edit.rev > self.snapshot_rev {
+                apply_ops(&mut doc, &edit.ops);
+            }
+        }
+        if doc.content != self.content {
+            return invalid("edits do not replay to the exported content".into());
+        }
+        Ok(())
+    }
+}
+
+/// Builds the archive from what is on disk, replaying the WAL the same way
+/// the loader does.
+pub fn export_history(state: &AppState, slug: &str) -> anyhow::Result<HistoryArchive> {
+    let meta = load_meta(state, slug)?.unwrap_or_default();
+    let snapshot = read_snapshot(state, slug)?.unwrap_or_default();
+    let doc = Doc {
+        content: snapshot.clone(),
+        ..Default::default()
+    };
+    let data = read_wal(state, slug)?.unwrap_or_default();
+    let mut replayer = Replayer::new(doc, meta.snapshot_rev);
+    let mut edits = Vec::new();
+    for replayed in replayer.replay_lines(&data) {
+        let Replayed { ts, edit, step, .. } = match replayed {
+            Ok(replayed) => replayed,
+            Err((line, err)) => {
+                anyhow::bail!("WAL line {} of '{}' is corrupt: {}", line, slug, err)
+            }
+        };
+        edits.push(HistoryEdit {
+            rev: step.rev,
+            ts,
+            client_id: edit.client_id,
+            op_id: edit.op_id,
+            group_id: edit.group_id,
+            user_id: edit.user_id,
+            folded: step.folded,
+            ops: step.ops,
+        });
+    }
+    let Replayer {
+        doc,
+        purged: history_start,
+        ..
+    } = replayer;
+    if doc.rev < meta.snapshot_rev {
+        anyhow::bail!(
+            "WAL of '{}' ends at rev {} before the snapshot's rev {}",
+            slug,
+            doc.rev,
+            meta.snapshot_rev
+        );
+    }
+    Ok(HistoryArchive {
+        format: HISTORY_FORMAT.to_string(),
+        version: HISTORY_VERSION,
+        slug: slug.to_string(),
+        exported_at: now_millis(),
+        snapshot_rev: meta.snapshot_rev,
+        history_start,
+        scrubbed_rev: meta.scrubbed_rev,
+        snapshot,
+        rev: doc.rev,
+        content: doc.content,
+        content_type: meta.content_type,
+        edits,
+    })
+}
+
+/// Recreates `slug` from `archive`. The slug must not exist yet.
+pub async fn import_history(
+    state: &AppState,
+    slug: &str,
+    archive: &HistoryArchive,
+) -> anyhow::Result<()> {
+    archive.validate()?;
+    if doc_exists(state, slug)? {
+        return Err(Rejection::new("exists", "document already exists").into());
+    }
+    if archive.history_start > 0 {
+        let event = DocEvent::Purged {
+            rev: archive.history_start,
+        };
+        wal_append_event(state, slug, &event, archive.exported_at)?;
+    }
+    for edit in &archive.edits {
+        let wal_edit = Edit {
+            base_rev: edit.rev - 1,
+            ops: edit.ops.clone(),
+            client_id: edit.client_id,
+            op_id: edit.op_id,
+            cursor_before: None,
+            cursor_after: None,
+            ts: Some(edit.ts),
+            group_id: edit.group_id,
+            user_id: edit.user_id,
+        };
+        let event = if edit.folded > 0 {
+            DocEvent::Compacted { edit: wal_edit }
+        } else {
+            DocEvent::Edit { edit: wal_edit }
+        };
+        wal_append_event(state, slug, &event, edit.ts)?;
+    }
+    let delta = write_snapshot(state, slug, &archive.snapshot)?;
+    record_bytes(state, slug, delta);
+    let meta = DocMeta {
+        snapshot_rev: archive.snapshot_rev,
+        content_type: archive.content_type.clone(),
+        scrubbed_rev: archive.scrubbed_rev,
+        ..Default::default()
+    };
+    persist_meta(state, slug, &meta)?;
+
+    let doc = get_or_load_doc(state, slug).await?;
+    let d = doc.read();
+    if d.rev != archive.rev || d.content != archive.content {
+        anyhow::bail!(
+            "imported '{}' loads as rev {} instead of rev {}",
+            slug,
+            d.rev,
+            archive.rev
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::apply_edit;
+    use crate::storage::flush_snapshot_force;
+    use std::{fs, path::Path};
+
+    fn mk_state(tmp: &Path) -> AppState {
+        let wal_dir = tmp.join("wal");
+        let snap_dir = tmp.join("snapshots");
+        fs::create_dir_all(&wal_dir).unwrap();
+        fs::create_dir_all(&snap_dir).unwrap();
+        AppState::new(wal_dir, snap_dir, 10_000, 1_000, true, Vec::new())
+    }
+
+    fn edit(base_rev: u64, op: OpKind) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![op],
+            client_id: Some(Uuid::new_v4()),
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn export_then_import_on_another_instance_keeps_history() {
+        let id = Uuid::new_v4();
+        let source = mk_state(&std::env::temp_dir().join(format!("history-src-{}", id)));
+        let mut target = mk_state(&std::env::temp_dir().join(format!("history-dst-{}", id)));
+        target.compress_storage = true;
+
+        let insert = |pos, text: &str| OpKind::Insert {
+            pos,
+            text: text.into(),
+        };
+        apply_edit(&source, "team/a", edit(0, insert(0, "hello")))
+            .await
+            .unwrap();
+        // Concurrent with the first edit: gets transformed on the server.
+        apply_edit(&source, "team/a", edit(0, insert(0, ">> ")))
+            .await
+            .unwrap();
+        flush_snapshot_force(&source, "team/a").await.unwrap();
+        let (group, user) = (Uuid::new_v4(), Uuid::new_v4());
+        apply_edit(
+            &source,
+            "team/a",
+            Edit {
+                group_id: Some(group),
+                user_id: Some(user),
+                ..edit(2, OpKind::Delete { pos: 0, len: 3 })
+            },
+        )
+        .await
+        .unwrap();
+
+        let archive = export_history(&source, "team/a").unwrap();
+        assert_eq!(archive.rev, 3);
+        assert_eq!(archive.snapshot_rev, 2);
+        assert_eq!(archive.content, "hello");
+        assert_eq!(archive.edits[1].ops, vec![insert(0, ">> ")]);
+        assert_eq!(archive.edits[2].group_id, Some(group));
+        assert_eq!(archive.edits[2].user_id, Some(user));
+
+        let json = serde_json::to_string(&archive).unwrap();
+        let parsed: HistoryArchive = serde_json::from_str(&json).unwrap();
+        import_history(&target, "moved", &parsed).await.unwrap();
+
+        let doc = get_or_load_doc(&target, "moved").await.unwrap();
+        assert_eq!(doc.read().content, "hello");
+        assert_eq!(doc.read().rev, 3);
+        assert_eq!(doc.read().log.len(), 3);
+        let again = export_history(&target, "moved").unwrap();
+        assert_eq!(again.edits, archive.edits);
+    }
+
+    #[tokio::test]
+    async fn import_rejects_tampered_or_conflicting_archives() {
+        let base = std::env::temp_dir().join(format!("history-bad-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        apply_edit(
+            &state,
+            "src",
+            edit(
+                0,
+                OpKind::Insert {
+                    pos: 0,
+                    text: "x".into(),
+                },
+            ),
+        )
+        .await
+        .unwrap();
+        let archive = export_history(&state, "src").unwrap();
+
+        let err = import_history(&state, "src", &archive).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, "exists");
+
+        let mut tampered = archive.clone();
+        tampered.content = "y".into();
+        let err = import_history(&state, "copy", &tampered).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Rejection>().unwrap().code,
+            "invalid_history"
+        );
+
+        let mut future = archive;
+        future.version = HISTORY_VERSION + 1;
+        assert_eq!(future.validate().unwrap_err().code, "unsupported_format");
+        assert!(!doc_exists(&state, "copy").unwrap());
+    }
+}
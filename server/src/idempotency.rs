@@ -0,0 +1,376 @@
+//! `Idempotency-Key` support for mutating HTTP requests. The first request
+//! with a key runs; its response is kept in a bounded cache and replayed to
+//! retries with the same key and credentials, the way `recent_ops` drops
+//! repeated edits.
+
+use std::collections::{HashMap, VecDeque};
+
+use axum::{
+    body::{Body, Bytes, HttpBody, to_bytes},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::{
+    state::{AppState, now_millis},
+    tenants::API_KEY_HEADER,
+};
+
+pub const IDEMPOTENCY_HEADER: &str = "idempotency-key";
+/// Set on responses replayed from the cache.
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+pub const IDEMPOTENCY_CAP: usize = 1024;
+pub const IDEMPOTENCY_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+const MAX_KEY_LEN: usize = 255;
+/// Matches axum's default body limit for the handlers behind it.
+const MAX_REQUEST_BYTES: usize = 2 * 1024 * 1024;
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+struct Stored {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    at: u64,
+}
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Running {
+        fingerprint: [u8; 32],
+    },
+    Done {
+        fingerprint: [u8; 32],
+        stored: Stored,
+    },
+}
+
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    entries: HashMap<String, Entry>,
+    order: VecDeque<String>,
+    cap: usize,
+}
+
+pub type IdempotencyStore = Arc<Mutex<IdempotencyCache>>;
+
+impl IdempotencyCache {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            cap,
+        }
+    }
+
+    fn insert(&mut self, key: String, entry: Entry) {
+        if self.entries.insert(key.clone(), entry).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.cap {
+            if let Some(old) = self.order.pop_front() {
+                self.entries.remove(&old);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if self.entries.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new(IDEMPOTENCY_CAP)
+    }
+}
+
+/// Forgets a running request whose response never got stored, so a retry
+/// can run it again.
+struct RunningGuard {
+    store: IdempotencyStore,
+    key: Option<String>,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.store.lock().remove(&key);
+        }
+    }
+}
+
+fn replay(stored: &Stored) -> Response {
+    let mut resp = (stored.status, stored.body.clone()).into_response();
+    resp.headers_mut().extend(stored.headers.clone());
+    resp.headers_mut()
+        .insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+    resp
+}
+
+/// Middleware applying `Idempotency-Key` to every request that is not a
+/// `GET`, `HEAD` or `OPTIONS`.
+pub async fn idempotent(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+    let Some(key) = req.headers().get(IDEMPOTENCY_HEADER) else {
+        return next.run(req).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.trim().is_empty() && key.len() <= MAX_KEY_LEN => key.trim().to_string(),
+        _ => return (StatusCode::BAD_REQUEST, "invalid idempotency key").into_response(),
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let cache_key = format!(
+        "{} {} {} {}",
+        req.method(),
+        path,
+        key,
+        credential_digest(req.headers())
+    );
+
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_REQUEST_BYTES).await else {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "request body too large").into_response();
+    };
+    let fingerprint: [u8; 32] = Sha256::digest(&bytes).into();
+    {
+        let mut cache = state.idempotency.lock();
+        match cache.entries.get(&cache_key) {
+            Some(Entry::Done { stored, .. })
+                if now_millis().saturating_sub(stored.at) > IDEMPOTENCY_TTL_MS =>
+            {
+                cache.remove(&cache_key);
+            }
+            Some(Entry::Done {
+                fingerprint: seen,
+                stored,
+            }) => {
+                if *seen != fingerprint {
+                    return reused_key();
+                }
+                return replay(stored);
+            }
+            Some(Entry::Running { fingerprint: seen }) => {
+                if *seen != fingerprint {
+                    return reused_key();
+                }
+                return (
+                    StatusCode::CONFLICT,
+                    "a request with this idempotency key is still running",
+                )
+                    .into_response();
+            }
+            None => {}
+        }
+        cache.insert(cache_key.clone(), Entry::Running { fingerprint });
+    }
+    let mut guard = RunningGuard {
+        store: state.idempotency.clone(),
+        key: Some(cache_key.clone()),
+    };
+
+    let resp = next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await;
+    if !worth_keeping(resp.status()) {
+        return resp;
+    }
+    // Streams and large bodies go out as they are; a retry runs again.
+    let size = resp.body().size_hint().upper();
+    if size.is_none_or(|size| size > MAX_RESPONSE_BYTES as u64) {
+        return resp;
+    }
+    let (parts, body) = resp.into_parts();
+    let Ok(body) = to_bytes(body, MAX_RESPONSE_BYTES).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "failed to read response").into_response();
+    };
+    let mut headers = HeaderMap::new();
+    for name in [header::CONTENT_TYPE, header::LOCATION] {
+        if let Some(value) = parts.headers.get(&name) {
+            headers.insert(name, value.clone());
+        }
+    }
+    let stored = Stored {
+        status: parts.status,
+        headers,
+        body: body.clone(),
+        at: now_millis(),
+    };
+    guard.key = None;
+    state.idempotency.lock().insert(
+        cache_key,
+        Entry::Done {
+            fingerprint,
+            stored,
+        },
+    );
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Hashes the credentials a request carries, so a key only replays to the
+/// caller that first sent it.
+fn credential_digest(headers: &HeaderMap) -> String {
+    let mut hasher = Sha256::new();
+    for name in [header::AUTHORIZATION.as_str(), API_KEY_HEADER] {
+        hasher.update(name);
+        if let Some(value) = headers.get(name) {
+            hasher.update(b"=");
+            hasher.update(value.as_bytes());
+        }
+        hasher.update(b"\n");
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Refusals that a retry with other credentials or later may get past, and
+/// server errors, run again instead of being replayed.
+fn worth_keeping(status: StatusCode) -> bool {
+    !status.is_server_error()
+        && !matches!(
+            status,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+        )
+}
+
+fn reused_key() -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "idempotency key was used for a different request",
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_router;
+    use tower::util::ServiceExt;
+    use uuid::Uuid;
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("srvtest-idem-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            1_000,
+            100,
+            true,
+            vec![],
+        )
+    }
+
+    fn create(key: &str, slug: &str) -> Request {
+        Request::post("/api/docs")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(IDEMPOTENCY_HEADER, key)
+            .body(Body::from(format!(r#"{{"slug":"{}"}}"#, slug)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_with_a_key_get_the_first_response() {
+        let state = mk_state();
+        let app = build_router(&state);
+
+        let first = app.clone().oneshot(create("k1", "idem/a")).await.unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        assert!(!first.headers().contains_key(REPLAYED_HEADER));
+        let retry = app.clone().oneshot(create("k1", "idem/a")).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers()[REPLAYED_HEADER], "true");
+
+        // Without the key the document already exists.
+        let mut plain = create("k1", "idem/a");
+        plain.headers_mut().remove(IDEMPOTENCY_HEADER);
+        assert_eq!(
+            app.clone().oneshot(plain).await.unwrap().status(),
+            StatusCode::CONFLICT
+        );
+        let reused = app.clone().oneshot(create("k1", "idem/b")).await.unwrap();
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let long = "x".repeat(MAX_KEY_LEN + 1);
+        let invalid = app.oneshot(create(&long, "idem/c")).await.unwrap();
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn keys_replay_only_to_the_same_credentials() {
+        let mut state = mk_state();
+        state.admin_token = Some("s3cret".into());
+        let app = build_router(&state);
+        let with_auth = |auth: Option<&str>| {
+            let mut req = create("k1", "idem/auth");
+            if let Some(auth) = auth {
+                req.headers_mut()
+                    .insert(header::AUTHORIZATION, auth.parse().unwrap());
+            }
+            req
+        };
+
+        let first = app.clone().oneshot(with_auth(None)).await.unwrap();
+        assert_eq!(first.status(), StatusCode::CREATED);
+        // Another caller reusing the key runs its own request.
+        let other = app
+            .clone()
+            .oneshot(with_auth(Some("Bearer s3cret")))
+            .await
+            .unwrap();
+        assert!(!other.headers().contains_key(REPLAYED_HEADER));
+        assert_eq!(other.status(), StatusCode::CONFLICT);
+
+        // A refusal is not replayed to the retry that brings the token.
+        let reload = |auth: Option<&str>| {
+            let mut req = Request::post("/api/admin/reload")
+                .header(IDEMPOTENCY_HEADER, "k2")
+                .body(Body::empty())
+                .unwrap();
+            if let Some(auth) = auth {
+                req.headers_mut()
+                    .insert(header::AUTHORIZATION, auth.parse().unwrap());
+            }
+            req
+        };
+        let refused = app.clone().oneshot(reload(None)).await.unwrap();
+        assert_eq!(refused.status(), StatusCode::UNAUTHORIZED);
+        let retry = app.oneshot(reload(Some("Bearer s3cret"))).await.unwrap();
+        assert_ne!(retry.status(), StatusCode::UNAUTHORIZED);
+        assert!(!retry.headers().contains_key(REPLAYED_HEADER));
+        assert!(!worth_keeping(StatusCode::UNAUTHORIZED));
+        assert!(!worth_keeping(StatusCode::FORBIDDEN));
+        assert!(!worth_keeping(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!worth_keeping(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(worth_keeping(StatusCode::CONFLICT));
+    }
+
+    #[test]
+    fn cache_drops_the_oldest_keys_first() {
+        let mut cache = IdempotencyCache::new(2);
+        for key in ["a", "b", "c"] {
+            cache.insert(
+                key.into(),
+                Entry::Running {
+                    fingerprint: [0; 32],
+                },
+            );
+        }
+        assert!(!cache.entries.contains_key("a"));
+        assert_eq!(cache.order, ["b", "c"]);
+        cache.remove("b");
+        assert_eq!(cache.order, ["c"]);
+    }
+}
@@ -0,0 +1,428 @@
+//! Documents kept in step with files edited outside coedit. Every `.md` file
+//! under `INGEST_DIR` is the document named by its path without the
+//! extension (`team/plan.md` is `team/plan`). The directory is polled; when a
+//! file changes, what changed in it is applied as one server edit, line by
+//! line, and rebased over whatever collaborators typed since the file was
+//! last applied, so neither side's changes are lost.
+//!
+//! Files present at startup only create documents that do not exist yet, so
+//! a restart does not revert edits made in coedit. After a restart, or when
+//! the document's history no longer reaches back far enough, the next change
+//! to a file makes the document match it as a whole.
+//!
+//! With `MIRROR_DIR` instead, the sync goes both ways: every snapshot is
+//! also written to the document's file there, and a file watcher picks up
+//! saves from local editors without waiting for the next poll.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::{Mutex, RwLock};
+use tokio::{
+    sync::{mpsc, watch},
+    time::sleep,
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    diff::diff_ops,
+    document::Doc,
+    state::{AppState, apply_edit, doc_exists, get_or_load_doc},
+    storage::{collect_slugs_with_extension, slug_to_rel_path},
+    types::{Edit, OpKind},
+};
+
+pub const DEFAULT_INGEST_INTERVAL_MS: u64 = 2_000;
+/// How long after a watcher event the scan waits.
+const WATCH_SETTLE: Duration = Duration::from_millis(100);
+
+type Stamp = (Option<SystemTime>, u64);
+
+fn stamp(meta: &fs::Metadata) -> Stamp {
+    (meta.modified().ok(), meta.len())
+}
+
+/// What a scan last saw of a file.
+#[derive(Debug, Clone)]
+struct Seen {
+    stamp: Stamp,
+    /// The file as last applied or written and the revision that left the
+    /// document matching it, while that is known.
+    synced: Option<(String, u64)>,
+}
+
+/// Files under one directory and what scans last saw of them.
+#[derive(Debug, Default)]
+pub struct IngestDir {
+    pub dir: PathBuf,
+    /// Whether snapshots are written back to the files as well; see
+    /// [`IngestDir::write_mirror`].
+    pub mirror: bool,
+    seen: Mutex<HashMap<String, Seen>>,
+    started: AtomicBool,
+}
+
+impl IngestDir {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            ..Default::default()
+        }
+    }
+
+    /// Two-way: documents are also written to their files.
+    pub fn mirror(dir: PathBuf) -> Self {
+        Self {
+            mirror: true,
+            ..Self::new(dir)
+        }
+    }
+
+    fn file_path(&self, slug: &str) -> anyhow::Result<PathBuf> {
+        let mut path = self.dir.join(slug_to_rel_path(slug)?).into_os_string();
+        path.push(".md");
+        Ok(path.into())
+    }
+
+    /// Applies the files that changed since the last scan. Returns the
+    /// documents that were edited or created.
+    pub async fn scan(&self, state: &AppState) -> anyhow::Result<Vec<String>> {
+        let first = !self.started.swap(true, Ordering::Relaxed);
+        let mut changed = Vec::new();
+        let slugs = collect_slugs_with_extension(&self.dir, "md", false)?;
+        self.seen.lock().retain(|slug, _| slugs.contains(slug));
+        for slug in slugs {
+            let path = self.file_path(&slug)?;
+            let Ok(meta) = fs::metadata(&path) else {
+                continue;
+            };
+            let stamp = stamp(&meta);
+            let known = self.seen.lock().get(&slug).cloned();
+            let synced = match known {
+                Some(seen) if seen.stamp == stamp => continue,
+                Some(seen) => seen.synced,
+                None if first && doc_exists(state, &slug).unwrap_or(true) => {
+                    self.seen.lock().insert(
+                        slug,
+                        Seen {
+                            stamp,
+                            synced: None,
+                        },
+                    );
+                    continue;
+                }
+                None => None,
+            };
+            match ingest_file(state, &slug, &path, synced).await {
+                Ok((edited, content, rev)) => {
+                    self.seen.lock().insert(
+                        slug.clone(),
+                        Seen {
+                            stamp,
+                            synced: rev.map(|rev| (content.clone(), rev)),
+                        },
+                    );
+                    if self.mirror {
+                        self.write_merged(state, &slug, &content).await;
+                    }
+                    if edited {
+                        changed.push(slug);
+                    }
+                }
+                // Left as it was, so the next scan tries again.
+                Err(err) => warn!(%slug, "ingesting {} failed: {:#}", path.display(), err),
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Writes the document back when it no longer matches its file after
+    /// the file was applied, because collaborators' edits were kept.
+    async fn write_merged(&self, state: &AppState, slug: &str, file: &str) {
+        let Some(doc) = state.docs.read().get(slug).cloned() else {
+            return;
+        };
+        let (content, rev) = {
+            let d = doc.read();
+            (d.content.clone(), d.rev)
+        };
+        if content == file {
+            return;
+        }
+        if let Err(err) = self.write_mirror(slug, &content, rev) {
+            warn!(%slug, "writing the mirrored file failed: {:#}", err);
+        }
+    }
+
+    /// Writes `content`, the document at `rev`, to its file. A file changed
+    /// outside coedit and not applied yet is left alone, so the change is
+    /// not lost; the next scan applies it and writes the result. Returns
+    /// whether the file was written.
+    pub fn write_mirror(&self, slug: &str, content: &str, rev: u64) -> anyhow::Result<bool> {
+        let path = self.file_path(slug)?;
+        let mut seen = self.seen.lock();
+        let on_disk = fs::metadata(&path).ok().map(|meta| stamp(&meta));
+        if on_disk.is_some() && on_disk != seen.get(slug).map(|seen| seen.stamp) {
+            return Ok(false);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Editors watching the file never see it half written.
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &path)?;
+        seen.insert(
+            slug.to_string(),
+            Seen {
+                stamp: stamp(&fs::metadata(&path)?),
+                synced: Some((content.to_string(), rev)),
+            },
+        );
+        Ok(true)
+    }
+}
+
+/// Brings `slug` in line with the file at `path`. With the file as it was
+/// last applied, only what changed in the file since is applied, on top of
+/// the edits made in coedit meanwhile; without it, the document is made to
+/// match the file. Returns whether the document changed, the file, and the
+/// revision that matches it when known.
+async fn ingest_file(
+    state: &AppState,
+    slug: &str,
+    path: &Path,
+    synced: Option<(String, u64)>,
+) -> anyhow::Result<(bool, String, Option<u64>)> {
+    let content = fs::read_to_string(path)?;
+    let doc = get_or_load_doc(state, slug).await?;
+    if let Some((before, rev)) = synced {
+        let ops = diff_ops(&before, &content);
+        if ops.is_empty() {
+            return Ok((false, content, Some(rev)));
+        }
+        match apply_file_edit(state, slug, &doc, rev, ops).await {
+            Ok(rev) => return Ok((true, content, rev)),
+            // Too old to rebase over what happened since.
+            Err(err) => debug!(%slug, "falling back to the whole file: {:#}", err),
+        }
+    }
+    let (base_rev, ops) = {
+        let d = doc.read();
+        (d.rev, diff_ops(&d.content, &content))
+    };
+    if ops.is_empty() {
+        return Ok((false, content, Some(base_rev)));
+    }
+    let rev = apply_file_edit(state, slug, &doc, base_rev, ops).await?;
+    Ok((true, content, rev))
+}
+
+/// Applies `ops` made against `base_rev`. Returns the revision they made,
+/// unless other edits landed alongside so it is not known which.
+async fn apply_file_edit(
+    state: &AppState,
+    slug: &str,
+    doc: &Arc<RwLock<Doc>>,
+    base_rev: u64,
+    ops: Vec<OpKind>,
+) -> anyhow::Result<Option<u64>> {
+    let before = doc.read().rev;
+    apply_edit(
+        state,
+        slug,
+        Edit {
+            base_rev,
+            ops,
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: Some(Uuid::new_v4()),
+            user_id: None,
+        },
+    )
+    .await?;
+    let after = doc.read().rev;
+    Ok((before == base_rev && after == base_rev + 1).then_some(after))
+}
+
+/// Sends a unit whenever something under `dir` changes. `None` when the
+/// platform offers no watcher; the interval alone drives scans then.
+fn watch_dir(dir: &Path) -> Option<(RecommendedWatcher, mpsc::Receiver<()>)> {
+    let (tx, rx) = mpsc::channel(1);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok() {
+            // A scan is pending already when the channel is full.
+            let _ = tx.try_send(());
+        }
+    })
+    .inspect_err(|err| warn!("file watcher unavailable: {}", err))
+    .ok()?;
+    watcher
+        .watch(dir, RecursiveMode::Recursive)
+        .inspect_err(|err| warn!(dir = %dir.display(), "watching failed: {}", err))
+        .ok()?;
+    Some((watcher, rx))
+}
+
+/// Scans `ingest.dir` whenever the watcher reports a change there, and
+/// every `interval_ms` in case it missed one, until `shutdown` flips to
+/// `true`.
+pub async fn run_ingest_loop(
+    state: AppState,
+    ingest: Arc<IngestDir>,
+    interval_ms: u64,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    if let Err(err) = fs::create_dir_all(&ingest.dir) {
+        error!(dir = %ingest.dir.display(), "cannot create the ingest directory: {}", err);
+    }
+    let (_watcher, mut events) = match watch_dir(&ingest.dir) {
+        Some((watcher, events)) => (Some(watcher), Some(events)),
+        None => (None, None),
+    };
+    loop {
+        match ingest.scan(&state).await {
+            Ok(changed) if !changed.is_empty() => {
+                info!(docs = changed.len(), "ingested external changes");
+            }
+            Ok(_) => {}
+            Err(err) => error!(dir = %ingest.dir.display(), "ingest scan failed: {:#}", err),
+        }
+        let event = async {
+            let Some(events) = events.as_mut() else {
+                return std::future::pending().await;
+            };
+            match events.recv().await {
+                // Let an editor finish saving.
+                Some(()) => sleep(WATCH_SETTLE).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = event => {}
+            _ = sleep(Duration::from_millis(interval_ms.max(50))) => {}
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::flush_snapshot_force;
+
+    #[tokio::test]
+    async fn files_changed_outside_are_applied_as_edits() {
+        let base = std::env::temp_dir().join(format!("ingest-{}", Uuid::new_v4()));
+        let dir = base.join("ingest");
+        fs::create_dir_all(dir.join("team")).unwrap();
+        fs::write(dir.join("team/plan.md"), "one\ntwo\n").unwrap();
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let ingest = IngestDir::new(dir.clone());
+        assert_eq!(ingest.scan(&state).await.unwrap(), vec!["team/plan"]);
+        assert!(ingest.scan(&state).await.unwrap().is_empty());
+
+        // A collaborator types while the file changes elsewhere.
+        let doc = get_or_load_doc(&state, "team/plan").await.unwrap();
+        let typed = Edit {
+            base_rev: 1,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: "> ".into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(&state, "team/plan", typed).await.unwrap();
+        fs::write(dir.join("team/plan.md"), "one\n2\nthree\n").unwrap();
+        assert_eq!(ingest.scan(&state).await.unwrap(), vec!["team/plan"]);
+        assert_eq!(doc.read().content, "> one\n2\nthree\n");
+
+        // A restart does not revert what was typed since.
+        let restarted = IngestDir::new(dir);
+        assert!(restarted.scan(&state).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn mirrored_files_follow_both_ways() {
+        let base = std::env::temp_dir().join(format!("mirror-{}", Uuid::new_v4()));
+        let dir = base.join("notes");
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let mirror = Arc::new(IngestDir::mirror(dir.clone()));
+        state.mirror = Some(mirror.clone());
+        let edit = |base_rev: u64, pos: usize, text: &str| Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(&state, "daily", edit(0, 0, "a\nb\n"))
+            .await
+            .unwrap();
+        assert!(flush_snapshot_force(&state, "daily").await.unwrap());
+        assert_eq!(fs::read_to_string(dir.join("daily.md")).unwrap(), "a\nb\n");
+        // Its own write is not read back as a change.
+        assert!(mirror.scan(&state).await.unwrap().is_empty());
+
+        // Typed in the browser and saved in an editor at the same time.
+        apply_edit(&state, "daily", edit(1, 0, "# ")).await.unwrap();
+        fs::write(dir.join("daily.md"), "a\nb\nc\n").unwrap();
+        // The pending change to the file is not overwritten.
+        assert!(flush_snapshot_force(&state, "daily").await.unwrap());
+        assert_eq!(
+            fs::read_to_string(dir.join("daily.md")).unwrap(),
+            "a\nb\nc\n"
+        );
+        assert_eq!(mirror.scan(&state).await.unwrap(), vec!["daily"]);
+        let merged = "# a\nb\nc\n";
+        let doc = get_or_load_doc(&state, "daily").await.unwrap();
+        assert_eq!(doc.read().content, merged);
+        assert_eq!(fs::read_to_string(dir.join("daily.md")).unwrap(), merged);
+        assert!(mirror.scan(&state).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+//! Offline consistency checks over a document's files on disk.
+
+use crate::{
+    document::Doc,
+    state::AppState,
+    storage::{load_meta, load_password_hash, read_snapshot, read_wal},
+    types::{DocEvent, Edit},
+    wal_replay::{Replayer, decode_line},
+};
+
+#[derive(Debug, Default)]
+pub struct DocReport {
+    pub slug: String,
+    /// Revision the WAL replays to.
+    pub rev: u64,
+    pub snapshot_rev: u64,
+    pub wal_entries: usize,
+    pub problems: Vec<String>,
+}
+
+impl DocReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// An edit read back from the WAL.
+#[derive(Debug, Clone)]
+pub struct WalEdit {
+    pub ts: u64,
+    pub edit: Edit,
+    /// Written by history compaction: the revisions up to `edit.base_rev`
+    /// log nothing and this edit carries their ops.
+    pub compacted: bool,
+}
+
+/// A WAL edit, or the line number and parse error of a line that could not
+/// be read.
+pub type WalEntry = Result<WalEdit, (usize, String)>;
+
+/// Decoded WAL edits, oldest first, as written: duplicates included.
+pub fn wal_entries(state: &AppState, slug: &str) -> anyhow::Result<Vec<WalEntry>> {
+    let Some(data) = read_wal(state, slug)? else {
+        return Ok(Vec::new());
+    };
+    let mut out = Vec::new();
+    for (idx, line) in data.lines().enumerate() {
+        match decode_line(idx, line) {
+            Some(Ok(entry)) => match entry.event {
+                DocEvent::Edit { edit } => out.push(Ok(WalEdit {
+                    ts: entry.ts,
+                    edit,
+                    compacted: false,
+                })),
+                DocEvent::Compacted { edit } => out.push(Ok(WalEdit {
+                    ts: entry.ts,
+                    edit,
+                    compacted: true,
+                })),
+                DocEvent::Purged { .. } | DocEvent::Cursor { .. } | DocEvent::Ime { .. } => {}
+            },
+            Some(Err(err)) => out.push(Err(err)),
+            None => {}
+        }
+    }
+    Ok(out)
+}
+
+/// Checks that the snapshot, metadata, password file and WAL of `slug` can be
+/// read and agree with each other. Does not touch loaded documents.
+pub fn verify_doc(state: &AppState, slug: &str) -> DocReport {
+    let mut report = DocReport {
+        slug: slug.to_string(),
+        ..Default::default()
+    };
+    let meta = match load_meta(state, slug) {
+        Ok(meta) => meta.unwrap_or_default(),
+        Err(err) => {
+            report
+                .problems
+                .push(format!("unreadable metadata: {:#}", err));
+            Default::default()
+        }
+    };
+    report.snapshot_rev = meta.snapshot_rev;
+    let snapshot = match read_snapshot(state, slug) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            report
+                .problems
+                .push(format!("unreadable snapshot: {:#}", err));
+            None
+        }
+    };
+    if let Ok(Some(hash)) = load_password_hash(state, slug)
+        && (hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()))
+    {
+        report
+            .problems
+            .push("password file is not a sha256 hex digest".into());
+    }
+    let data = match read_wal(state, slug) {
+        Ok(data) => data.unwrap_or_default(),
+        Err(err) => {
+            report.problems.push(format!("unreadable WAL: {:#}", err));
+            String::new()
+        }
+    };
+
+    // Same replay as the loader. If it never reaches `snapshot_rev`, the WAL
+    // lost edits the snapshot claims to contain.
+    let doc = Doc {
+        content: snapshot.unwrap_or_default(),
+        ..Default::default()
+    };
+    let mut replayer = Replayer::new(doc, meta.snapshot_rev);
+    for replayed in replayer.replay_lines(&data) {
+        if let Err((line, err)) = replayed {
+            report
+                .problems
+                .push(format!("WAL line {} does not parse: {}", line, err));
+        }
+    }
+    let Replayer {
+        doc,
+        purged: start,
+        edits,
+        ..
+    } = replayer;
+    report.wal_entries = edits;
+    report.rev = doc.rev;
+    if start > meta.snapshot_rev {
+        report.problems.push(format!(
+            "WAL was purged up to rev {} but the snapshot is at rev {}",
+            start, meta.snapshot_rev
+        ));
+    }
+    if doc.rev < meta.snapshot_rev {
+        report.problems.push(format!(
+            "snapshot claims rev {} but the WAL only replays to rev {}",
+            meta.snapshot_rev, doc.rev
+        ));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::apply_edit;
+    use crate::storage::{flush_snapshot_force, persist_meta, persist_password_hash, wal_path};
+    use crate::types::OpKind;
+    use std::fs;
+    use std::path::Path;
+    use uuid::Uuid;
+
+    fn mk_state(tmp: &Path) -> AppState {
+        let wal_dir = tmp.join("wal");
+        let snap_dir = tmp.join("snapshots");
+        fs::create_dir_all(&wal_dir).unwrap();
+        fs::create_dir_all(&snap_dir).unwrap();
+        AppState::new(wal_dir, snap_dir, 10_000, 1_000, true, Vec::new())
+    }
+
+    fn insert(base_rev: u64, pos: usize, text: &str) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn healthy_doc_verifies_clean() {
+        let base = std::env::temp_dir().join(format!("integrity-ok-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        apply_edit(&state, "a", insert(0, 0, "hello"))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "a").await.unwrap();
+        apply_edit(&state, "a", insert(1, 5, "!")).await.unwrap();
+
+        let report = verify_doc(&state, "a");
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.rev, 2);
+        assert_eq!(report.snapshot_rev, 1);
+        assert_eq!(report.wal_entries, 2);
+    }
+
+    #[tokio::test]
+    async fn reports_lost_wal_and_garbage() {
+        let base = std::env::temp_dir().join(format!("integrity-bad-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        apply_edit(&state, "b", insert(0, 0, "x")).await.unwrap();
+        fs::write(wal_path(&state, "b").unwrap(), "not json\n").unwrap();
+        let meta = crate::types::DocMeta {
+            snapshot_rev: 3,
+            ..Default::default()
+        };
+        persist_meta(&state, "b", &meta).unwrap();
+        persist_password_hash(&state, "b", Some("plaintext")).unwrap();
+
+        let report = verify_doc(&state, "b");
+        assert_eq!(report.problems.len(), 3, "{:?}", report.problems);
+        assert!(report.problems[0].contains("sha256"));
+        assert!(report.problems[1].contains("line 1"));
+        assert!(report.problems[2].contains("claims rev 3"));
+    }
+}
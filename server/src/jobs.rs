@@ -0,0 +1,287 @@
+//! Background jobs for admin operations that can outlast an HTTP request:
+//! the handler starts the job and answers with its id, and progress, the
+//! result and cancellation go through `/api/admin/jobs/{id}`.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::state::{AppState, now_millis};
+
+/// Finished jobs kept for polling; older ones are dropped first.
+const MAX_FINISHED_JOBS: usize = 64;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: &'static str,
+    pub status: JobStatus,
+    /// Items the job works through; `done` counts the ones it has finished.
+    pub total: usize,
+    pub done: usize,
+    /// Items that could not be processed; see the server log.
+    pub failed: Vec<String>,
+    /// Job-specific tallies, such as matches replaced.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub counts: BTreeMap<&'static str, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub started_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct JobEntry {
+    job: Job,
+    cancel: Arc<AtomicBool>,
+    result: Option<Arc<Value>>,
+}
+
+pub type JobStore = Arc<Mutex<HashMap<Uuid, JobEntry>>>;
+
+/// What a running job uses to report progress and notice cancellation.
+#[derive(Clone)]
+pub struct JobHandle {
+    id: Uuid,
+    store: JobStore,
+    cancel: Arc<AtomicBool>,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Jobs check this between items and stop early when it is set.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Job)) {
+        if let Some(entry) = self.store.lock().get_mut(&self.id) {
+            f(&mut entry.job);
+        }
+    }
+
+    pub fn set_total(&self, total: usize) {
+        self.update(|job| job.total = total);
+    }
+
+    /// Marks one item finished, recording it as failed when `failed` is set.
+    pub fn advance(&self, failed: Option<String>) {
+        self.update(|job| {
+            job.done += 1;
+            job.failed.extend(failed);
+        });
+    }
+
+    pub fn add_count(&self, key: &'static str, n: u64) {
+        self.update(|job| *job.counts.entry(key).or_default() += n);
+    }
+}
+
+/// Registers a job and runs `work` in the background. Its `Ok` value is kept
+/// as the job's result.
+pub fn spawn_job<F, Fut>(state: &AppState, kind: &'static str, total: usize, work: F) -> Job
+where
+    F: FnOnce(JobHandle) -> Fut,
+    Fut: Future<Output = anyhow::Result<Option<Value>>> + Send + 'static,
+{
+    let job = Job {
+        id: Uuid::new_v4(),
+        kind,
+        status: JobStatus::Running,
+        total,
+        done: 0,
+        failed: Vec::new(),
+        counts: BTreeMap::new(),
+        error: None,
+        started_at: now_millis(),
+        finished_at: None,
+    };
+    let cancel = Arc::new(AtomicBool::new(false));
+    {
+        let mut jobs = state.jobs.lock();
+        prune_finished(&mut jobs);
+        jobs.insert(
+            job.id,
+            JobEntry {
+                job: job.clone(),
+                cancel: cancel.clone(),
+                result: None,
+            },
+        );
+    }
+    let handle = JobHandle {
+        id: job.id,
+        store: state.jobs.clone(),
+        cancel,
+    };
+    info!(job = %job.id, kind, total, "job started");
+    let fut = work(handle.clone());
+    tokio::spawn(async move {
+        let outcome = fut.await;
+        let mut jobs = handle.store.lock();
+        let Some(entry) = jobs.get_mut(&handle.id) else {
+            return;
+        };
+        let job = &mut entry.job;
+        job.finished_at = Some(now_millis());
+        job.status = match outcome {
+            Err(err) => {
+                warn!(job = %job.id, kind = job.kind, "job failed: {:#}", err);
+                job.error = Some(format!("{:#}", err));
+                JobStatus::Failed
+            }
+            Ok(_) if handle.is_cancelled() => JobStatus::Cancelled,
+            Ok(result) => {
+                entry.result = result.map(Arc::new);
+                JobStatus::Done
+            }
+        };
+        info!(
+            job = %job.id,
+            kind = job.kind,
+            status = ?job.status,
+            failed = job.failed.len(),
+            "job finished"
+        );
+    });
+    job
+}
+
+fn prune_finished(jobs: &mut HashMap<Uuid, JobEntry>) {
+    let mut finished: Vec<(u64, Uuid)> = jobs
+        .values()
+        .filter_map(|e| e.job.finished_at.map(|at| (at, e.job.id)))
+        .collect();
+    if finished.len() < MAX_FINISHED_JOBS {
+        return;
+    }
+    finished.sort();
+    for (_, id) in &finished[..=finished.len() - MAX_FINISHED_JOBS] {
+        jobs.remove(id);
+    }
+}
+
+pub fn job_status(state: &AppState, id: &Uuid) -> Option<Job> {
+    state.jobs.lock().get(id).map(|entry| entry.job.clone())
+}
+
+/// Every known job, newest first.
+pub fn list_jobs(state: &AppState) -> Vec<Job> {
+    let mut jobs: Vec<Job> = state
+        .jobs
+        .lock()
+        .values()
+        .map(|entry| entry.job.clone())
+        .collect();
+    jobs.sort_by_key(|job| Reverse(job.started_at));
+    jobs
+}
+
+/// The result of a job that finished with one.
+pub fn job_result(state: &AppState, id: &Uuid) -> Option<Arc<Value>> {
+    state
+        .jobs
+        .lock()
+        .get(id)
+        .and_then(|entry| entry.result.clone())
+}
+
+/// Asks a running job to stop. Items already processed stay processed.
+pub fn cancel_job(state: &AppState, id: &Uuid) -> Option<Job> {
+    let jobs = state.jobs.lock();
+    let entry = jobs.get(id)?;
+    if entry.job.status == JobStatus::Running {
+        entry.cancel.store(true, Ordering::Relaxed);
+    }
+    Some(entry.job.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("srvtest-jobs-{}", Uuid::new_v4()));
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            1_000,
+            100,
+            true,
+            vec![],
+        )
+    }
+
+    async fn wait(state: &AppState, id: &Uuid) -> Job {
+        for _ in 0..200 {
+            let job = job_status(state, id).unwrap();
+            if job.status != JobStatus::Running {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("job did not finish");
+    }
+
+    #[tokio::test]
+    async fn jobs_report_progress_results_and_cancellation() {
+        let state = mk_state();
+        let job = spawn_job(&state, "count", 3, |handle| async move {
+            for idx in 0..3 {
+                handle.advance((idx == 1).then(|| "one".to_string()));
+                handle.add_count("seen", 1);
+            }
+            Ok(Some(Value::from(3)))
+        });
+        let done = wait(&state, &job.id).await;
+        assert_eq!(done.status, JobStatus::Done);
+        assert_eq!((done.done, done.failed), (3, vec!["one".to_string()]));
+        assert_eq!(done.counts["seen"], 3);
+        assert_eq!(*job_result(&state, &job.id).unwrap(), Value::from(3));
+
+        let (release, wait_release) = tokio::sync::oneshot::channel::<()>();
+        let slow = spawn_job(&state, "slow", 1, |handle| async move {
+            let _ = wait_release.await;
+            assert!(handle.is_cancelled());
+            Ok(None)
+        });
+        assert_eq!(
+            cancel_job(&state, &slow.id).unwrap().status,
+            JobStatus::Running
+        );
+        release.send(()).unwrap();
+        assert_eq!(wait(&state, &slow.id).await.status, JobStatus::Cancelled);
+
+        let failing = spawn_job(&state, "fail", 0, |_| async { anyhow::bail!("boom") });
+        let failed = wait(&state, &failing.id).await;
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.error.as_deref(), Some("boom"));
+        assert_eq!(list_jobs(&state).len(), 3);
+    }
+}
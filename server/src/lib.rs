@@ -0,0 +1,438 @@
+//! The coedit server as a library. Other axum services can mount
+//! [`build_router`] under their own router, or drive documents directly
+//! through [`state`], [`document`] and [`storage`].
+
+pub mod access;
+pub mod alerts;
+pub mod analysis;
+pub mod archive;
+pub mod assist;
+pub mod auth;
+pub mod bulk;
+pub mod client;
+pub mod cluster;
+pub mod conflicts;
+pub mod connections;
+pub mod content_type;
+pub mod dav;
+pub mod degraded;
+pub mod diff;
+pub mod digest;
+pub mod disk;
+pub mod doc_settings;
+pub mod document;
+pub mod erasure;
+pub mod expiry;
+pub mod front_matter;
+pub mod git;
+pub mod handlers;
+pub mod history;
+pub mod idempotency;
+pub mod ingest;
+pub mod integrity;
+pub mod jobs;
+pub mod lines;
+pub mod links;
+pub mod listener;
+pub mod mentions;
+pub mod merge;
+pub mod metrics;
+pub mod notifications;
+pub mod origin;
+#[cfg(any(test, fuzzing))]
+pub mod ot_sim;
+pub mod presence;
+pub mod protocol;
+pub mod quota;
+pub mod reload;
+pub mod replace;
+pub mod replay;
+pub mod replica;
+pub mod retention;
+pub mod rev_index;
+pub mod review;
+pub mod schema;
+pub mod sections;
+pub mod seen;
+pub mod state;
+pub mod storage;
+pub mod subscription;
+pub mod tags;
+pub mod tenants;
+pub mod ticket;
+pub mod toc;
+pub mod transform;
+pub mod trash;
+pub mod types;
+pub mod validation;
+pub mod viewport;
+pub mod wal_replay;
+pub mod webhook;
+pub mod workspace;
+
+use std::time::Duration;
+
+use axum::{
+    Router, middleware,
+    routing::{any, get, post},
+};
+use tokio::{sync::watch, time::sleep};
+use tracing::error;
+
+use crate::{
+    handlers::{http, poll, ws},
+    state::broadcast,
+    storage::{flush_all_wals_to_snapshots, flush_snapshot_force, flush_snapshot_if_needed},
+    types::ServerMsg,
+};
+
+pub use crate::state::AppState;
+
+pub fn build_router(state: &AppState) -> Router {
+    Router::new()
+        .route("/api/snapshot", get(http::snapshot))
+        .route("/api/render", get(http::render))
+        .route("/api/review", get(http::review))
+        .route("/api/toc", get(http::toc))
+        .route("/api/links", get(http::links))
+        .route(
+            "/api/diagnostics",
+            get(http::diagnostics).post(http::run_diagnostics),
+        )
+        .route("/api/conflicts", get(http::conflicts))
+        .route("/api/presence", get(http::presence))
+        .route("/api/presence/by-client", get(http::presence_by_client))
+        .route("/api/replay", get(http::replay))
+        .route("/api/password", post(http::update_password))
+        .route("/api/owner", post(http::claim_owner))
+        .route("/api/archive", post(http::archive))
+        .route("/api/archive/restore", post(http::restore))
+        .route("/api/docs", get(http::tagged_docs).post(http::create_doc))
+        .route("/api/tags", get(http::tags))
+        .route("/api/merge", post(http::merge))
+        .route("/api/replace", post(http::replace))
+        .route("/api/transform", post(http::transform))
+        .route(
+            "/api/docs/*path",
+            get(http::doc_get)
+                .post(http::doc_post)
+                .patch(http::doc_patch),
+        )
+        .route(
+            "/api/workspaces/:ws",
+            get(http::get_workspace).put(http::update_workspace),
+        )
+        .route("/api/workspaces/:ws/docs", get(http::get_workspace_docs))
+        .route("/api/health", get(http::health))
+        .route("/api/stats", get(http::stats))
+        .route(
+            "/api/retention",
+            get(http::retention_dry_run).post(http::retention_run),
+        )
+        .route("/api/erasure", post(http::erasure))
+        .route("/api/notifications", get(http::notifications))
+        .route("/api/notifications/read", post(http::read_notifications))
+        .route("/api/trash", get(http::trash))
+        .route("/api/trash/restore", post(http::restore_trash))
+        .route("/api/admin/reload", post(http::reload))
+        .route("/api/admin/bulk", post(http::bulk))
+        .route("/api/admin/jobs", get(http::jobs))
+        .route("/api/admin/jobs/:id", get(http::job))
+        .route("/api/admin/jobs/:id/result", get(http::job_output))
+        .route("/api/admin/jobs/:id/cancel", post(http::job_cancel))
+        .route("/api/admin/cluster", get(http::cluster))
+        .route("/api/admin/connections", get(http::connections))
+        .route(
+            "/api/admin/connections/:id/disconnect",
+            post(http::disconnect_connection),
+        )
+        .route("/api/ws-ticket", post(http::ws_ticket))
+        .route("/api/ws", get(ws::ws_handler))
+        .route("/api/ws-config", get(ws::ws_config))
+        .route("/api/poll", post(poll::open))
+        .route(
+            "/api/poll/:session",
+            get(poll::poll).post(poll::send).delete(poll::close),
+        )
+        .route("/dav", any(dav::dav))
+        .route("/dav/", any(dav::dav))
+        .route("/dav/*path", any(dav::dav))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            idempotency::idempotent,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            cluster::forward_to_owner,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            replica::refuse_writes,
+        ))
+        .layer(middleware::from_fn(http::log_auth_failures))
+        .with_state(state.clone())
+}
+
+/// Flushes idle or busy documents until `shutdown` flips to `true`.
+pub async fn run_periodic_snapshot_flush(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        // Re-read every round so a config reload takes effect.
+        let interval = Duration::from_millis(state.live.read().flush_idle_ms.max(50));
+        tokio::select! {
+            _ = sleep(interval) => {
+                let slugs: Vec<String> = state.docs.read().keys().cloned().collect();
+                for slug in slugs {
+                    if let Err(err) = flush_snapshot_if_needed(&state, &slug).await {
+                        error!(%slug, "periodic flush failed: {:#}", err);
+                    }
+                }
+            }
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Tells every open session the server is going away; their sockets close
+/// with [`CLOSE_DRAINING`](protocol::CLOSE_DRAINING) once it is delivered.
+pub fn drain_sessions(state: &AppState) {
+    let slugs: Vec<String> = state.subs.read().keys().cloned().collect();
+    for slug in slugs {
+        broadcast(
+            state,
+            &slug,
+            ServerMsg::Error {
+                slug: slug.clone(),
+                code: "draining".to_string(),
+                message: "server is shutting down".to_string(),
+                op_id: None,
+            },
+        );
+    }
+}
+
+/// Flushes every loaded document and any WAL left on disk. Returns the
+/// number of loaded and WAL-only documents written.
+pub async fn finalize_shutdown(state: &AppState) -> anyhow::Result<(usize, usize)> {
+    if state.wal_health.is_degraded() && !degraded::retry_pending(state) {
+        error!(
+            pending = state.wal_health.pending(),
+            "shutting down with WAL entries that could not be written"
+        );
+    }
+    let loaded = flush_loaded_docs(state).await?;
+    let wal = flush_all_wals_to_snapshots(state).await?;
+    Ok((loaded, wal))
+}
+
+async fn flush_loaded_docs(state: &AppState) -> anyhow::Result<usize> {
+    let slugs: Vec<String> = state.docs.read().keys().cloned().collect();
+    let mut flushed = 0usize;
+    for slug in slugs {
+        if flush_snapshot_force(state, &slug).await? {
+            flushed += 1;
+        }
+    }
+    Ok(flushed)
+}
+
+#[cfg(test)]
+#[allow(clippy::field_reassign_with_default)]
+mod tests {
+    use super::*;
+    use crate::document::Doc;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use parking_lot::RwLock;
+    use std::fs;
+    use std::sync::Arc;
+    use tower::util::ServiceExt;
+    use uuid::Uuid;
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("main-tests-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let wal = base.join("wal");
+        let snap = base.join("snapshots");
+        fs::create_dir_all(&wal).unwrap();
+        fs::create_dir_all(&snap).unwrap();
+        AppState::new(wal, snap, 1_000, 128, true, Vec::new())
+    }
+
+    #[tokio::test]
+    async fn router_can_be_nested_in_a_host_app() {
+        let state = mk_state();
+        let app = Router::new().nest("/coedit", build_router(&state));
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/coedit/api/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn router_serves_health_endpoint() {
+        let state = mk_state();
+        let app = build_router(&state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn router_advertises_ws_heartbeat() {
+        let mut state = mk_state();
+        state.ws_ping_interval_ms = 20_000;
+        let app = build_router(&state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/ws-config")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let config: handlers::ws::WsConfig = serde_json::from_slice(&body).unwrap();
+        assert_eq!(config.ping_interval_ms, 20_000);
+        assert!(!config.echo_protocol);
+    }
+
+    #[tokio::test]
+    async fn router_enforces_snapshot_auth() {
+        let state = mk_state();
+        let slug = "secure";
+        let mut doc = Doc::default();
+        doc.password_hash = Some(crate::storage::hash_password("pw"));
+        doc.content = "secret".into();
+        state
+            .docs
+            .write()
+            .insert(slug.into(), Arc::new(RwLock::new(doc)));
+
+        let app = build_router(&state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/snapshot?slug=secure")
+                    .method("GET")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn flush_loaded_docs_writes_pending_content() {
+        let state = mk_state();
+        let slug = "flush-me";
+        let mut doc = Doc::default();
+        doc.content = "shutdown".into();
+        doc.rev = 1;
+        doc.since_flush = 1;
+        state
+            .docs
+            .write()
+            .insert(slug.into(), Arc::new(RwLock::new(doc)));
+
+        let flushed = flush_loaded_docs(&state).await.unwrap();
+        assert_eq!(flushed, 1);
+
+        let snap = crate::storage::snapshot_path(&state, slug).unwrap();
+        assert_eq!(fs::read_to_string(snap).unwrap(), "shutdown");
+    }
+
+    #[tokio::test]
+    async fn router_moves_doc_history_between_instances() {
+        let source = mk_state();
+        let target = mk_state();
+        let edit = crate::types::Edit {
+            base_rev: 0,
+            ops: vec![crate::types::OpKind::Insert {
+                pos: 0,
+                text: "moved".into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        crate::state::apply_edit(&source, "team/doc", edit)
+            .await
+            .unwrap();
+
+        let exported = build_router(&source)
+            .oneshot(
+                Request::builder()
+                    .uri("/api/docs/team/doc/history/export")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(exported.status(), StatusCode::OK);
+        let archive = axum::body::to_bytes(exported.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        let import = |slug: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(format!("/api/docs/{}/history/import", slug))
+                .body(Body::from(archive.clone()))
+                .unwrap()
+        };
+        let app = build_router(&target);
+        let created = app.clone().oneshot(import("other/doc")).await.unwrap();
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let again = app.clone().oneshot(import("other/doc")).await.unwrap();
+        assert_eq!(again.status(), StatusCode::CONFLICT);
+        let unknown = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/docs/other/doc/nope")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+
+        let doc = crate::state::get_or_load_doc(&target, "other/doc")
+            .await
+            .unwrap();
+        assert_eq!(doc.read().content, "moved");
+        assert_eq!(doc.read().rev, 1);
+    }
+}
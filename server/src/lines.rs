@@ -0,0 +1,668 @@
+//! Line-based ops for log and code documents. Sessions that negotiate
+//! `line_ops` send [`LineOp`]s, which are transformed against the other edits
+//! line by line and only turned into char ops when applied. While such a
+//! session is around, the document keeps the edits those sessions may still
+//! rebase across in line form as well.
+//!
+//! Lines are the `\n`-separated parts of the content, so a document always
+//! has at least one. Deleting every line therefore leaves an empty one
+//! behind, and concurrent edits that together empty the document are the one
+//! case the line transform does not model exactly.
+
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::{
+    document::{Doc, apply_ops},
+    state::Rejection,
+    types::{Edit, LineEdit, LineOp, OpKind},
+};
+
+/// Line forms of the edits since `from_rev`, the oldest revision a line
+/// session may still base an edit on.
+#[derive(Debug, Default)]
+pub struct LineLog {
+    pub from_rev: u64,
+    entries: Vec<Vec<LineOp>>,
+    /// Line sessions and the revision each last based an edit on, or joined
+    /// at.
+    sessions: HashMap<Uuid, u64>,
+}
+
+impl LineLog {
+    /// Forgets the entries no session can rebase across any more.
+    fn trim(&mut self) {
+        let Some(oldest) = self.sessions.values().min().copied() else {
+            return;
+        };
+        if oldest > self.from_rev {
+            let drop = ((oldest - self.from_rev) as usize).min(self.entries.len());
+            self.entries.drain(..drop);
+            self.from_rev += drop as u64;
+        }
+    }
+}
+
+/// Registers a line session on `doc`, starting its line log if it is the
+/// first.
+pub fn join_line_log(doc: &mut Doc, client_id: Uuid) {
+    let rev = doc.rev;
+    let log = doc.line_log.get_or_insert_with(|| LineLog {
+        from_rev: rev,
+        ..Default::default()
+    });
+    log.sessions.insert(client_id, rev);
+}
+
+/// Unregisters a line session. The log goes away with the last one.
+pub fn leave_line_log(doc: &mut Doc, client_id: Uuid) {
+    let Some(log) = doc.line_log.as_mut() else {
+        return;
+    };
+    if log.sessions.remove(&client_id).is_none() {
+        return;
+    }
+    if log.sessions.is_empty() {
+        doc.line_log = None;
+    } else {
+        log.trim();
+    }
+}
+
+/// Replaces `delete` lines starting at `line` with `insert`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Splice {
+    line: usize,
+    delete: usize,
+    insert: Vec<String>,
+}
+
+impl Splice {
+    fn end(&self) -> usize {
+        self.line + self.delete
+    }
+
+    fn is_noop(&self) -> bool {
+        self.delete == 0 && self.insert.is_empty()
+    }
+
+    /// `self` moved past `other`, which lies entirely before it.
+    fn after(&self, other: &Splice) -> Splice {
+        Splice {
+            line: self.line + other.insert.len() - other.delete,
+            ..self.clone()
+        }
+    }
+}
+
+impl From<&LineOp> for Splice {
+    fn from(op: &LineOp) -> Self {
+        match op {
+            LineOp::InsertLines { line, lines } => Splice {
+                line: *line,
+                delete: 0,
+                insert: lines.clone(),
+            },
+            LineOp::DeleteLines { line, count } => Splice {
+                line: *line,
+                delete: *count,
+                insert: Vec::new(),
+            },
+            LineOp::ReplaceLine { line, text } => Splice {
+                line: *line,
+                delete: 1,
+                insert: vec![text.clone()],
+            },
+        }
+    }
+}
+
+fn to_line_ops(splices: Vec<Splice>) -> Vec<LineOp> {
+    let mut ops = Vec::new();
+    for s in splices {
+        if s.delete == 1 && s.insert.len() == 1 {
+            ops.push(LineOp::ReplaceLine {
+                line: s.line,
+                text: s.insert.into_iter().next().unwrap_or_default(),
+            });
+            continue;
+        }
+        // Insert first so the delete never has to remove every line, which
+        // would leave an empty one behind.
+        let inserted = s.insert.len();
+        if inserted > 0 {
+            ops.push(LineOp::InsertLines {
+                line: s.line,
+                lines: s.insert,
+            });
+        }
+        if s.delete > 0 {
+            ops.push(LineOp::DeleteLines {
+                line: s.line + inserted,
+                count: s.delete,
+            });
+        }
+    }
+    ops
+}
+
+fn transform_splice(a: &Splice, b: &Splice, a_wins: bool) -> (Vec<Splice>, Vec<Splice>) {
+    let (a0, a1, b0, b1) = (a.line, a.end(), b.line, b.end());
+    let either_deletes = a.delete > 0 || b.delete > 0;
+    if a1 < b0 || (a1 == b0 && (either_deletes || a_wins)) {
+        return (vec![a.clone()], vec![b.after(a)]);
+    }
+    if b1 < a0 || (b1 == a0 && (either_deletes || !a_wins)) {
+        return (vec![a.after(b)], vec![b.clone()]);
+    }
+    let keep = |v: Vec<Splice>| v.into_iter().filter(|s| !s.is_noop()).collect::<Vec<_>>();
+    // A pure insert inside the other's deleted range survives, placed where
+    // that range starts.
+    if a.delete == 0 {
+        let b2 = vec![
+            Splice {
+                line: b0,
+                delete: a0 - b0,
+                insert: Vec::new(),
+            },
+            Splice {
+                line: b0 + a.insert.len(),
+                delete: b1 - a0,
+                insert: b.insert.clone(),
+            },
+        ];
+        let a2 = Splice {
+            line: b0,
+            ..a.clone()
+        };
+        return (vec![a2], keep(b2));
+    }
+    if b.delete == 0 {
+        let (b2, a2) = transform_splice(b, a, !a_wins);
+        return (a2, b2);
+    }
+    // Overlapping rewrites: the winner replaces every line either of them
+    // touched, the loser's text is dropped.
+    let (w, l) = if a_wins { (a, b) } else { (b, a) };
+    let start = a0.min(b0);
+    let overlap = a1.min(b1) - a0.max(b0);
+    let w2 = Splice {
+        line: start,
+        delete: w.delete - overlap + l.insert.len(),
+        insert: w.insert.clone(),
+    };
+    let mut l2 = Vec::new();
+    if l.line < w.line {
+        l2.push(Splice {
+            line: l.line,
+            delete: w.line - l.line,
+            insert: Vec::new(),
+        });
+    }
+    if l.end() > w.end() {
+        l2.push(Splice {
+            line: start + w.insert.len(),
+            delete: l.end() - w.end(),
+            insert: Vec::new(),
+        });
+    }
+    if a_wins {
+        (vec![w2], l2)
+    } else {
+        (l2, vec![w2])
+    }
+}
+
+fn transform_splices(a: &[Splice], b: &[Splice], a_wins: bool) -> (Vec<Splice>, Vec<Splice>) {
+    match (a, b) {
+        ([], _) | (_, []) => (a.to_vec(), b.to_vec()),
+        ([x], [y]) => transform_splice(x, y, a_wins),
+        ([x, rest @ ..], _) if !rest.is_empty() => {
+            let (x2, b1) = transform_splices(std::slice::from_ref(x), b, a_wins);
+            let (rest2, b2) = transform_splices(rest, &b1, a_wins);
+            ([x2, rest2].concat(), b2)
+        }
+        (_, [y, rest @ ..]) => {
+            let (a1, y2) = transform_splices(a, std::slice::from_ref(y), a_wins);
+            let (a2, rest2) = transform_splices(&a1, rest, a_wins);
+            (a2, [y2, rest2].concat())
+        }
+    }
+}
+
+/// Line counterpart of [`crate::document::transform_pair`].
+pub fn transform_line_ops(a: &[LineOp], b: &[LineOp], a_wins: bool) -> (Vec<LineOp>, Vec<LineOp>) {
+    let a: Vec<Splice> = a.iter().map(Splice::from).collect();
+    let b: Vec<Splice> = b.iter().map(Splice::from).collect();
+    let (a2, b2) = transform_splices(&a, &b, a_wins);
+    (to_line_ops(a2), to_line_ops(b2))
+}
+
+/// Turns line ops into char ops against `content`. Line numbers past the end
+/// are clamped like out-of-range char positions.
+pub fn line_ops_to_char_ops(content: &str, ops: &[LineOp]) -> Vec<OpKind> {
+    let mut lens: Vec<usize> = content.split('\n').map(|l| l.chars().count()).collect();
+    let offset = |lens: &[usize], line: usize| lens[..line].iter().sum::<usize>() + line;
+    let mut out = Vec::new();
+    for op in ops {
+        let s = Splice::from(op);
+        let n = lens.len();
+        let line = s.line.min(n);
+        let delete = s.delete.min(n - line);
+        let total = offset(&lens, n) - 1;
+        let text = s.insert.join("\n");
+        let new_lens = s.insert.iter().map(|l| l.chars().count());
+        if delete > 0 && line + delete < n {
+            let start = offset(&lens, line);
+            out.push(OpKind::Delete {
+                pos: start,
+                len: offset(&lens, line + delete) - start,
+            });
+            if !s.insert.is_empty() {
+                out.push(OpKind::Insert {
+                    pos: start,
+                    text: text + "\n",
+                });
+            }
+            lens.splice(line..line + delete, new_lens);
+        } else if delete > 0 && line > 0 {
+            // Through the last line: take the newline before it instead.
+            let start = offset(&lens, line) - 1;
+            out.push(OpKind::Delete {
+                pos: start,
+                len: total - start,
+            });
+            if !s.insert.is_empty() {
+                out.push(OpKind::Insert {
+                    pos: start,
+                    text: format!("\n{}", text),
+                });
+            }
+            lens.splice(line.., new_lens);
+        } else if delete > 0 {
+            // Every line: the document is left with one empty line at least.
+            out.push(OpKind::Delete { pos: 0, len: total });
+            if !s.insert.is_empty() {
+                out.push(OpKind::Insert { pos: 0, text });
+            }
+            lens = s.insert.iter().map(|l| l.chars().count()).collect();
+            if lens.is_empty() {
+                lens.push(0);
+            }
+        } else if s.insert.is_empty() {
+            continue;
+        } else if line < n {
+            out.push(OpKind::Insert {
+                pos: offset(&lens, line),
+                text: text + "\n",
+            });
+            lens.splice(line..line, new_lens);
+        } else {
+            out.push(OpKind::Insert {
+                pos: total,
+                text: format!("\n{}", text),
+            });
+            lens.extend(new_lens);
+        }
+    }
+    out.retain(|op| !matches!(op, OpKind::Delete { len: 0, .. }));
+    out
+}
+
+/// The line form of one char op applied to `content`.
+fn char_op_to_splice(content: &str, op: &OpKind) -> Option<Splice> {
+    let (pos, del, text) = match op {
+        OpKind::Insert { pos, text } => (*pos, 0, text.as_str()),
+        OpKind::Delete { pos, len } if *len > 0 => (*pos, *len, ""),
+        _ => return None,
+    };
+    // Byte offsets of the op's range, found without decoding the rest.
+    let mut offsets = content
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(content.len()));
+    let start = offsets.nth(pos)?;
+    if del > 0 && start == content.len() {
+        return None;
+    }
+    let end = match del {
+        0 => start,
+        del => offsets.nth(del - 1).unwrap_or(content.len()),
+    };
+    let head = &content[..start];
+    let line = head.matches('\n').count();
+    let line_start = head.rfind('\n').map_or(0, |i| i + 1);
+    let line_end = content[end..].find('\n').map_or(content.len(), |i| end + i);
+    let spanned = content[start..end].matches('\n').count();
+    let mut rewritten = String::with_capacity(line_end - line_start + text.len());
+    rewritten.push_str(&content[line_start..start]);
+    rewritten.push_str(text);
+    rewritten.push_str(&content[end..line_end]);
+    Some(Splice {
+        line,
+        delete: spanned + 1,
+        insert: rewritten.split('\n').map(str::to_string).collect(),
+    })
+}
+
+/// Applies `ops` to `doc` and, while the document keeps a line log, records
+/// and returns their line form.
+pub fn apply_ops_tracking_lines(doc: &mut Doc, ops: &[OpKind]) -> Option<Vec<LineOp>> {
+    if doc.line_log.is_none() {
+        apply_ops(doc, ops);
+        return None;
+    }
+    let mut splices = Vec::new();
+    for op in ops {
+        splices.extend(char_op_to_splice(&doc.content, op));
+        apply_ops(doc, std::slice::from_ref(op));
+    }
+    let line_ops = to_line_ops(splices);
+    if let Some(log) = doc.line_log.as_mut() {
+        log.entries.push(line_ops.clone());
+    }
+    Some(line_ops)
+}
+
+/// Rebases a line edit onto the current revision and converts it into a char
+/// edit made against that revision. A line session's edit also moves its
+/// base forward, letting the log drop what no session still needs.
+pub fn line_edit_to_edit(doc: &mut Doc, edit: LineEdit) -> Result<Edit, Rejection> {
+    let Some(log) = doc.line_log.as_mut() else {
+        return Err(Rejection::new(
+            "line_ops_unavailable",
+            "line ops were not negotiated for this document",
+        ));
+    };
+    if edit.base_rev < log.from_rev || edit.base_rev > doc.rev {
+        return Err(Rejection::new(
+            "stale_base",
+            format!(
+                "line edits must be based on rev {} to {}",
+                log.from_rev, doc.rev
+            ),
+        ));
+    }
+    let multiline = edit.ops.iter().any(|op| match op {
+        LineOp::InsertLines { lines, .. } => lines.iter().any(|l| l.contains('\n')),
+        LineOp::ReplaceLine { text, .. } => text.contains('\n'),
+        LineOp::DeleteLines { .. } => false,
+    });
+    if multiline {
+        return Err(Rejection::new(
+            "invalid_op",
+            "line text must not contain newlines",
+        ));
+    }
+    let mut ops = edit.ops;
+    for applied in &log.entries[(edit.base_rev - log.from_rev) as usize..] {
+        ops = transform_line_ops(&ops, applied, true).0;
+    }
+    if let Some(base) = edit.client_id.and_then(|id| log.sessions.get_mut(&id)) {
+        *base = edit.base_rev.max(*base);
+        log.trim();
+    }
+    Ok(Edit {
+        base_rev: doc.rev,
+        ops: line_ops_to_char_ops(&doc.content, &ops),
+        client_id: edit.client_id,
+        op_id: edit.op_id,
+        cursor_before: None,
+        cursor_after: None,
+        ts: edit.ts,
+        group_id: edit.group_id,
+        user_id: edit.user_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use uuid::Uuid;
+
+    fn apply_lines(content: &str, ops: &[LineOp]) -> String {
+        let mut doc = Doc {
+            content: content.into(),
+            ..Default::default()
+        };
+        apply_ops(&mut doc, &line_ops_to_char_ops(content, ops));
+        doc.content
+    }
+
+    fn line_op() -> impl Strategy<Value = LineOp> {
+        let text = "[a-c]{0,2}";
+        prop_oneof![
+            (0usize..6, prop::collection::vec(text, 1..3))
+                .prop_map(|(line, lines)| LineOp::InsertLines { line, lines }),
+            (0usize..6, 1usize..3).prop_map(|(line, count)| LineOp::DeleteLines { line, count }),
+            (0usize..6, text).prop_map(|(line, text)| LineOp::ReplaceLine { line, text }),
+        ]
+    }
+
+    #[test]
+    fn converts_line_ops_to_char_ops() {
+        let doc = "one\ntwo\nthree";
+        let replace = LineOp::ReplaceLine {
+            line: 1,
+            text: "2".into(),
+        };
+        assert_eq!(apply_lines(doc, &[replace]), "one\n2\nthree");
+        let insert = LineOp::InsertLines {
+            line: 3,
+            lines: vec!["four".into()],
+        };
+        assert_eq!(apply_lines(doc, &[insert]), "one\ntwo\nthree\nfour");
+        let delete_tail = LineOp::DeleteLines { line: 1, count: 9 };
+        assert_eq!(apply_lines(doc, &[delete_tail]), "one");
+        let delete_head = LineOp::DeleteLines { line: 0, count: 1 };
+        assert_eq!(apply_lines(doc, &[delete_head]), "two\nthree");
+    }
+
+    #[test]
+    fn char_edits_are_logged_as_lines() {
+        let mut doc = Doc {
+            content: "one\ntwo\nthree".into(),
+            ..Default::default()
+        };
+        join_line_log(&mut doc, Uuid::nil());
+        let ops = [
+            OpKind::Insert {
+                pos: 4,
+                text: "2\n".into(),
+            },
+            OpKind::Delete { pos: 2, len: 3 },
+        ];
+        let before = doc.content.clone();
+        let line_ops = apply_ops_tracking_lines(&mut doc, &ops).unwrap();
+        assert_eq!(doc.content, "on\ntwo\nthree");
+        assert_eq!(apply_lines(&before, &line_ops), doc.content);
+    }
+
+    #[test]
+    fn line_edits_are_rebased_over_char_edits() {
+        let mut doc = Doc {
+            content: "a\nb\nc".into(),
+            ..Default::default()
+        };
+        join_line_log(&mut doc, Uuid::nil());
+        // A char session adds a line at the top after the line session read
+        // rev 0.
+        let top = [OpKind::Insert {
+            pos: 0,
+            text: "new\n".into(),
+        }];
+        apply_ops_tracking_lines(&mut doc, &top);
+        doc.rev += 1;
+        let edit = LineEdit {
+            base_rev: 0,
+            ops: vec![LineOp::ReplaceLine {
+                line: 1,
+                text: "B".into(),
+            }],
+            client_id: None,
+            op_id: None,
+            ts: None,
+            group_id: Some(Uuid::nil()),
+            user_id: None,
+        };
+        let edit = line_edit_to_edit(&mut doc, edit).unwrap();
+        assert_eq!(edit.base_rev, 1);
+        assert_eq!(edit.group_id, Some(Uuid::nil()));
+        apply_ops(&mut doc, &edit.ops);
+        assert_eq!(doc.content, "new\na\nB\nc");
+
+        let stale = LineEdit {
+            base_rev: 0,
+            ops: vec![],
+            client_id: None,
+            op_id: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        let mut fresh = Doc {
+            rev: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            line_edit_to_edit(&mut fresh, stale).unwrap_err().code,
+            "line_ops_unavailable"
+        );
+    }
+
+    #[test]
+    fn line_log_keeps_only_what_sessions_can_rebase_across() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut doc = Doc {
+            content: "é\nb".into(),
+            ..Default::default()
+        };
+        join_line_log(&mut doc, a);
+        for _ in 0..3 {
+            let line_ops = apply_ops_tracking_lines(
+                &mut doc,
+                &[OpKind::Insert {
+                    pos: 1,
+                    text: "ü".into(),
+                }],
+            );
+            assert_eq!(
+                line_ops.unwrap(),
+                [LineOp::ReplaceLine {
+                    line: 0,
+                    text: doc.content.split('\n').next().unwrap().into(),
+                }]
+            );
+            doc.rev += 1;
+        }
+        join_line_log(&mut doc, b);
+        let edit = |client_id, base_rev| LineEdit {
+            base_rev,
+            ops: vec![LineOp::ReplaceLine {
+                line: 1,
+                text: "B".into(),
+            }],
+            client_id: Some(client_id),
+            op_id: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        line_edit_to_edit(&mut doc, edit(a, 2)).unwrap();
+        let log = doc.line_log.as_ref().unwrap();
+        assert_eq!((log.from_rev, log.entries.len()), (2, 1));
+
+        leave_line_log(&mut doc, a);
+        let log = doc.line_log.as_ref().unwrap();
+        assert_eq!((log.from_rev, log.entries.len()), (3, 0));
+        assert_eq!(
+            line_edit_to_edit(&mut doc, edit(b, 2)).unwrap_err().code,
+            "stale_base"
+        );
+        leave_line_log(&mut doc, b);
+        assert!(doc.line_log.is_none());
+    }
+
+    fn line_count(content: &str) -> usize {
+        content.split('\n').count()
+    }
+
+    /// Applies `ops` one by one, or `None` if one of them deletes every line,
+    /// which still leaves an empty one behind.
+    fn apply_nonempty(content: &str, ops: &[LineOp]) -> Option<String> {
+        let mut content = content.to_string();
+        for op in ops {
+            let s = Splice::from(op);
+            if s.line == 0 && s.delete >= line_count(&content) && s.insert.is_empty() {
+                return None;
+            }
+            content = apply_lines(&content, std::slice::from_ref(op));
+        }
+        Some(content)
+    }
+
+    fn in_range(content: &str, ops: Vec<LineOp>) -> Vec<LineOp> {
+        let mut n = line_count(content);
+        ops.into_iter()
+            .map(|op| {
+                let op = match op {
+                    LineOp::InsertLines { line, lines } => LineOp::InsertLines {
+                        line: line % (n + 1),
+                        lines,
+                    },
+                    LineOp::DeleteLines { line, count } => LineOp::DeleteLines {
+                        line: line % n,
+                        count: count.min(n - line % n),
+                    },
+                    LineOp::ReplaceLine { line, text } => LineOp::ReplaceLine {
+                        line: line % n,
+                        text,
+                    },
+                };
+                let s = Splice::from(&op);
+                n = (n + s.insert.len() - s.delete).max(1);
+                op
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn concurrent_line_ops_converge(
+            doc in prop::collection::vec("[x-z]{0,2}", 3..8),
+            a in prop::collection::vec(line_op(), 1..3),
+            b in prop::collection::vec(line_op(), 1..3),
+            a_wins: bool,
+        ) {
+            let doc = doc.join("\n");
+            // Positions past the end are clamped on conversion but not by the
+            // transform, so keep the generated ops in range.
+            let (a, b) = (in_range(&doc, a), in_range(&doc, b));
+            let (a2, b2) = transform_line_ops(&a, &b, a_wins);
+            let ab = apply_nonempty(&doc, &a).and_then(|d| apply_nonempty(&d, &b2));
+            let ba = apply_nonempty(&doc, &b).and_then(|d| apply_nonempty(&d, &a2));
+            prop_assume!(ab.is_some() && ba.is_some());
+            prop_assert_eq!(ab, ba);
+        }
+
+        #[test]
+        fn char_edits_replay_as_lines(
+            doc in "[ab\n]{0,8}",
+            pos in 0usize..10,
+            len in 0usize..4,
+            text in "[c\n]{0,3}",
+        ) {
+            let mut tracked = Doc {
+                content: doc.clone(),
+                ..Default::default()
+            };
+            join_line_log(&mut tracked, Uuid::nil());
+            let ops = [OpKind::Delete { pos, len }, OpKind::Insert { pos, text }];
+            let line_ops = apply_ops_tracking_lines(&mut tracked, &ops).unwrap();
+            let replayed = apply_lines(&doc, &line_ops);
+            prop_assert_eq!(replayed, tracked.content);
+        }
+    }
+}
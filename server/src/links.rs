@@ -0,0 +1,250 @@
+//! Links between documents: wiki-style `[[slug]]` and relative Markdown
+//! links, read from each snapshot as it is flushed. The backlink index is
+//! built from disk on first use and kept up to date by [`record_links`]
+//! afterwards, like workspace usage.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Range,
+};
+
+use serde::Serialize;
+
+use crate::{
+    state::AppState,
+    storage::{collect_slugs_with_extension, load_meta, read_snapshot, slug_to_rel_path},
+    toc::fenced,
+    types::ContentType,
+};
+
+#[derive(Debug, Default)]
+pub struct LinkIndex {
+    outgoing: HashMap<String, BTreeSet<String>>,
+    incoming: HashMap<String, BTreeSet<String>>,
+}
+
+impl LinkIndex {
+    fn set(&mut self, slug: &str, targets: BTreeSet<String>) {
+        self.remove(slug);
+        for target in &targets {
+            self.incoming
+                .entry(target.clone())
+                .or_default()
+                .insert(slug.to_string());
+        }
+        if !targets.is_empty() {
+            self.outgoing.insert(slug.to_string(), targets);
+        }
+    }
+
+    fn remove(&mut self, slug: &str) {
+        for target in self.outgoing.remove(slug).unwrap_or_default() {
+            if let Some(sources) = self.incoming.get_mut(&target) {
+                sources.remove(slug);
+                if sources.is_empty() {
+                    self.incoming.remove(&target);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct LinksResp {
+    pub slug: String,
+    /// Documents this one links to, whether or not they exist yet.
+    pub outgoing: Vec<String>,
+    /// Documents linking here that the requester may open.
+    pub incoming: Vec<String>,
+}
+
+/// The documents `content` of `slug` links to. `[[slug]]` names a document
+/// from the root; `[[slug|label]]` and `[[slug#heading]]` work too. Markdown
+/// link targets without a scheme resolve against the folder of `slug`, or
+/// the root when they start with `/`, with any `.md` dropped. Code is
+/// skipped.
+pub fn extract_links(slug: &str, content: &str) -> BTreeSet<String> {
+    link_targets(slug, content)
+        .into_iter()
+        .map(|(_, target)| target)
+        .collect()
+}
+
+/// Every link of [`extract_links`] in order, with the byte range of the
+/// whole `[[...]]` or of the target inside `(...)`.
+pub(crate) fn link_targets(slug: &str, content: &str) -> Vec<(Range<usize>, String)> {
+    let mut links = Vec::new();
+    let mut fence = None;
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if fenced(&mut fence, line.trim_start()) {
+            continue;
+        }
+        // Odd pieces are inside code spans.
+        let mut at = start;
+        for (i, text) in line.split('`').enumerate() {
+            if i % 2 == 0 {
+                wiki_links(text, at, &mut links);
+                markdown_links(slug, text, at, &mut links);
+            }
+            at += text.len() + 1;
+        }
+    }
+    links.retain(|(_, target)| target != slug);
+    links
+}
+
+fn wiki_links(text: &str, offset: usize, links: &mut Vec<(Range<usize>, String)>) {
+    let mut at = 0;
+    while let Some(open) = text[at..].find("[[") {
+        let start = at + open;
+        let Some(close) = text[start + 2..].find("]]") else {
+            return;
+        };
+        let inner = &text[start + 2..start + 2 + close];
+        at = start + 2 + close + 2;
+        let target = inner.split(['|', '#']).next().unwrap_or_default();
+        if let Some(target) = normalize(target.trim().trim_start_matches('/')) {
+            links.push((offset + start..offset + at, target));
+        }
+    }
+}
+
+fn markdown_links(slug: &str, text: &str, offset: usize, links: &mut Vec<(Range<usize>, String)>) {
+    let mut at = 0;
+    while let Some(open) = text[at..].find("](") {
+        let start = at + open + 2;
+        let Some(close) = text[start..].find(')') else {
+            return;
+        };
+        // Drop an optional title.
+        let target = text[start..start + close]
+            .split_whitespace()
+            .next()
+            .unwrap_or_default();
+        at = start + close + 1;
+        let target = target.trim_start_matches('<').trim_end_matches('>');
+        if let Some(target) = resolve(slug, target) {
+            links.push((offset + start..offset + start + close, target));
+        }
+    }
+}
+
+fn resolve(slug: &str, target: &str) -> Option<String> {
+    let has_scheme = target
+        .split_once(':')
+        .is_some_and(|(scheme, _)| !scheme.is_empty() && !scheme.contains('/'));
+    if has_scheme || target.starts_with("//") {
+        return None;
+    }
+    let path = target.split(['#', '?']).next().unwrap_or_default();
+    if path.is_empty() {
+        return None;
+    }
+    let path = path.strip_suffix(".md").unwrap_or(path);
+    let mut parts: Vec<&str> = Vec::new();
+    if !path.starts_with('/') {
+        // Relative links start from the folder, not the document.
+        parts.extend(slug.split('/'));
+        parts.pop();
+    }
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    normalize(&parts.join("/"))
+}
+
+fn normalize(target: &str) -> Option<String> {
+    let target = target.trim_end_matches('/');
+    slug_to_rel_path(target).ok()?;
+    Some(target.to_string())
+}
+
+fn scan_disk(state: &AppState) -> anyhow::Result<LinkIndex> {
+    let mut index = LinkIndex::default();
+    for slug in collect_slugs_with_extension(&state.snap_dir, "md", false)? {
+        let content_type = load_meta(state, &slug)?.and_then(|meta| meta.content_type);
+        if content_type.unwrap_or_default() != ContentType::Markdown {
+            continue;
+        }
+        if let Some(content) = read_snapshot(state, &slug)? {
+            index.set(&slug, extract_links(&slug, &content));
+        }
+    }
+    Ok(index)
+}
+
+/// Outgoing and incoming links of `slug` as of the last flushes, sorted.
+pub fn doc_links(state: &AppState, slug: &str) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    if state.links.read().is_none() {
+        let index = scan_disk(state)?;
+        state.links.write().get_or_insert(index);
+    }
+    let links = state.links.read();
+    let index = links.as_ref().expect("link index was just built");
+    let list = |map: &HashMap<String, BTreeSet<String>>| {
+        map.get(slug)
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default()
+    };
+    Ok((list(&index.outgoing), list(&index.incoming)))
+}
+
+/// Updates the index with a flushed snapshot. Only Markdown documents link.
+pub fn record_links(state: &AppState, slug: &str, content_type: &ContentType, content: &str) {
+    if let Some(index) = state.links.write().as_mut() {
+        let targets = match content_type {
+            ContentType::Markdown => extract_links(slug, content),
+            _ => BTreeSet::new(),
+        };
+        index.set(slug, targets);
+    }
+}
+
+/// Drops the links of a document that was deleted or archived.
+pub fn forget_links(state: &AppState, slug: &str) {
+    if let Some(index) = state.links.write().as_mut() {
+        index.remove(slug);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_wiki_and_relative_markdown_links() {
+        let content = "See [[ideas]], [[team/plan|the plan]] and [[team/plan#goals]].\n\
+            [Sibling](notes.md#top) [up](../readme \"Readme\") [root](/faq)\n\
+            [web](https://example.com) [mail](mailto:a@b.c) [anchor](#here)\n\
+            [escape](../../../etc) `[[in code]]`\n\
+            ```\n[[fenced]]\n```\n[[team/spec]]";
+        let links: Vec<String> = extract_links("team/spec", content).into_iter().collect();
+        assert_eq!(
+            links,
+            vec!["faq", "ideas", "readme", "team/notes", "team/plan"]
+        );
+    }
+
+    #[test]
+    fn index_tracks_backlinks_across_updates() {
+        let mut index = LinkIndex::default();
+        index.set("a", ["b".to_string(), "c".to_string()].into());
+        index.set("d", ["b".to_string()].into());
+        index.set("a", ["c".to_string()].into());
+        assert_eq!(index.incoming["b"], BTreeSet::from(["d".to_string()]));
+        index.remove("d");
+        assert!(!index.incoming.contains_key("b"));
+        assert_eq!(index.incoming["c"], BTreeSet::from(["a".to_string()]));
+    }
+}
@@ -0,0 +1,97 @@
+//! How the server gets its listening socket for zero-downtime restarts:
+//! either a socket handed over by systemd socket activation (`LISTEN_FDS`),
+//! or a fresh one bound with `SO_REUSEPORT` so the next process can listen
+//! on the same port while the old one drains. The next process serves only
+//! once the old one let go of the data directory, see `lock_data_dir`.
+
+use std::net::SocketAddr;
+
+use tokio::net::{TcpListener, TcpSocket};
+
+/// Where the listener came from, for the startup log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerSource {
+    Inherited,
+    ReusePort,
+    Bound,
+}
+
+/// The first descriptor systemd passes, see `sd_listen_fds(3)`.
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::fd::RawFd = 3;
+
+/// Takes over the socket systemd passed to this process, if any.
+#[cfg(unix)]
+fn inherited_listener() -> anyhow::Result<Option<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.trim().parse::<u32>().ok())
+        == Some(std::process::id());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.trim().parse::<u32>().ok())
+        .unwrap_or(0);
+    if !for_us || fds == 0 {
+        return Ok(None);
+    }
+    // SAFETY: with LISTEN_PID naming this process, systemd transferred
+    // ownership of the descriptors starting at LISTEN_FDS_START to us.
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    // Fails unless the descriptor really is a bound socket.
+    listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+fn inherited_listener() -> anyhow::Result<Option<std::net::TcpListener>> {
+    Ok(None)
+}
+
+fn reuse_port_listener(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(1024)?)
+}
+
+/// An inherited socket wins over `addr`; otherwise binds `addr`, sharing the
+/// port with other processes when `reuse_port` is set.
+pub async fn bind_listener(
+    addr: SocketAddr,
+    reuse_port: bool,
+) -> anyhow::Result<(TcpListener, ListenerSource)> {
+    if let Some(listener) = inherited_listener()? {
+        return Ok((TcpListener::from_std(listener)?, ListenerSource::Inherited));
+    }
+    if reuse_port {
+        return Ok((reuse_port_listener(addr)?, ListenerSource::ReusePort));
+    }
+    Ok((TcpListener::bind(addr).await?, ListenerSource::Bound))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn reuse_port_lets_a_second_process_bind() {
+        let (first, source) = bind_listener("127.0.0.1:0".parse().unwrap(), true)
+            .await
+            .unwrap();
+        assert_eq!(source, ListenerSource::ReusePort);
+        let addr = first.local_addr().unwrap();
+        let (second, _) = bind_listener(addr, true).await.unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+        assert!(bind_listener(addr, false).await.is_err());
+    }
+}
@@ -1,45 +1,84 @@
-mod auth;
-mod document;
-mod handlers;
-mod presence;
-mod state;
-mod storage;
-mod types;
-
-use std::{fs, path::Path, time::Duration};
-
-use axum::{
-    Router,
-    routing::{get, post},
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
+
 #[cfg(unix)]
 use tokio::signal::unix::{SignalKind, signal as unix_signal};
 use tokio::{
     signal,
     sync::{oneshot, watch},
-    time::sleep,
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::{
+    EnvFilter, Layer, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt,
 };
-use tracing::{error, info};
 
-use crate::{
-    handlers::{http, ws},
-    state::AppState,
-    storage::{flush_all_wals_to_snapshots, flush_snapshot_force, flush_snapshot_if_needed},
+use coedit::{
+    AppState,
+    alerts::{AlertPolicy, run_alert_loop},
+    analysis::load_analyzers,
+    assist::{AssistConfig, DEFAULT_ASSIST_PER_MINUTE},
+    build_router,
+    cluster::{Cluster, DEFAULT_CLUSTER_HEALTH_MS, parse_nodes, run_cluster_health},
+    degraded::run_wal_recovery,
+    digest::{DigestTarget, run_digest_loop},
+    disk::run_disk_watchdog,
+    drain_sessions,
+    expiry::run_expiry_loop,
+    finalize_shutdown,
+    git::{DEFAULT_GIT_PUSH_INTERVAL_SECS, GitStore, run_git_push_loop},
+    ingest::{DEFAULT_INGEST_INTERVAL_MS, IngestDir, run_ingest_loop},
+    listener::bind_listener,
+    reload::{ConfigVars, live_config, reload_config},
+    replica::{DEFAULT_REPLICA_REFRESH_MS, ReplicaConfig, run_replica_refresh},
+    retention::{RetentionPolicy, run_retention_loop},
+    run_periodic_snapshot_flush,
+    storage::{flush_all_wals_to_snapshots, lock_data_dir, migrate_password_hashes},
+    tenants::{TenantRouters, load_tenants, tenant_state, with_tenants},
+    transform::load_transforms,
+    trash::run_trash_purge_loop,
+    workspace::migrate_workspace_secrets,
 };
 
-fn build_router(state: &AppState) -> Router {
-    Router::new()
-        .route("/api/snapshot", get(http::get_snapshot))
-        .route("/api/password", post(http::update_password))
-        .route("/api/health", get(http::health))
-        .route("/api/ws", get(ws::ws_handler))
-        .with_state(state.clone())
+#[cfg(feature = "webtransport")]
+use coedit::handlers::webtransport::{bind as bind_webtransport, run_webtransport};
+
+fn main() -> anyhow::Result<()> {
+    runtime()?.block_on(serve())
+}
+
+/// Worker and blocking-pool threads come from `WORKER_THREADS` and
+/// `MAX_BLOCKING_THREADS`, or tokio's defaults when unset.
+fn runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = env_u64("WORKER_THREADS").filter(|n| *n > 0) {
+        builder.worker_threads(threads as usize);
+    }
+    if let Some(threads) = env_u64("MAX_BLOCKING_THREADS").filter(|n| *n > 0) {
+        builder.max_blocking_threads(threads as usize);
+    }
+    builder.build()
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+async fn serve() -> anyhow::Result<()> {
+    let (filter, filter_handle) = reload::Layer::new(EnvFilter::from_default_env());
+    let json_logs = std::env::var("LOG_FORMAT").is_ok_and(|v| v.eq_ignore_ascii_case("json"));
+    let format = if json_logs {
+        fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .boxed()
+    } else {
+        fmt::layer().boxed()
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(format)
         .init();
 
     let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "/vault".to_string());
@@ -48,101 +87,404 @@ async fn main() -> anyhow::Result<()> {
     fs::create_dir_all(&wal_dir)?;
     fs::create_dir_all(&snap_dir)?;
 
-    let flush_idle_ms = std::env::var("FLUSH_IDLE_MS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(1500);
-    let flush_max_ops = std::env::var("FLUSH_MAX_OPS")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(200);
+    let config_file = std::env::var("CONFIG_FILE").ok().map(PathBuf::from);
+    let vars = ConfigVars::load(config_file.as_deref())?;
+    let live = live_config(&vars);
     let app_env_dev = std::env::var("APP_ENV").unwrap_or_else(|_| "dev".into()) == "dev";
-    let app_domain = std::env::var("APP_DOMAIN").ok();
-    let allowed_origins = std::env::var("APP_ALLOWED_ORIGINS")
-        .ok()
-        .map(|raw| {
-            raw.split(',')
-                .map(|s| s.trim())
-                .filter(|s| !s.is_empty())
-                .map(|s| s.to_string())
-                .collect::<Vec<_>>()
-        })
-        .filter(|list| !list.is_empty())
-        .or_else(|| {
-            app_domain
-                .as_ref()
-                .map(|domain| vec![format!("https://{}", domain)])
-        })
-        .unwrap_or_default();
 
-    let state = AppState::new(
+    let mut state = AppState::new(
         wal_dir,
         snap_dir,
-        flush_idle_ms,
-        flush_max_ops,
+        live.flush_idle_ms,
+        live.flush_max_ops,
         app_env_dev,
-        allowed_origins,
+        live.allowed_origins,
     );
+    state.config_file = config_file;
+    state.log_filter = Some(Arc::new(move |directives: &str| {
+        filter_handle.reload(EnvFilter::try_new(directives)?)?;
+        Ok(())
+    }));
+    if let Some(directives) = vars.get("RUST_LOG")
+        && let Some(apply) = &state.log_filter
+        && let Err(err) = apply(&directives)
+    {
+        error!("invalid RUST_LOG '{}': {:#}", directives, err);
+    }
+    state.invite_only = env_flag("REQUIRE_PASSWORD_ON_CREATE");
+    state.require_ws_ticket = env_flag("REQUIRE_WS_TICKET");
+    state.strict_ops = env_flag("STRICT_OPS");
+    if let Ok(path) = std::env::var("ANALYZERS_FILE")
+        && !path.trim().is_empty()
+    {
+        state.analyzers = load_analyzers(Path::new(path.trim()))?;
+        info!(
+            analyzers = state.analyzers.len(),
+            "analyzing flushed documents"
+        );
+    }
+    if let Ok(path) = std::env::var("TRANSFORMS_FILE")
+        && !path.trim().is_empty()
+    {
+        state.transforms = load_transforms(Path::new(path.trim()))?;
+        info!(transforms = state.transforms.len(), "loaded transforms");
+    }
+    if let Ok(url) = std::env::var("ASSIST_URL")
+        && !url.trim().is_empty()
+    {
+        let url = url.trim().to_string();
+        if !url.starts_with("http://") {
+            anyhow::bail!("ASSIST_URL must be an http:// URL");
+        }
+        let per_minute = env_u64("ASSIST_RATE_PER_MINUTE")
+            .map_or(DEFAULT_ASSIST_PER_MINUTE, |n| n.min(u32::MAX as u64) as u32);
+        info!(url = %url, per_minute, "assistant requests enabled");
+        state.assist = Some(AssistConfig { url, per_minute });
+    }
+    if let Some(skew) = env_u64("MAX_CLOCK_SKEW_MS") {
+        state.max_clock_skew_ms = skew;
+    }
+    if let Some(threshold) = env_u64("WS_COMPRESS_THRESHOLD") {
+        state.ws_compress_threshold = threshold as usize;
+    }
+    if let Some(interval) = env_u64("WS_PING_INTERVAL_MS") {
+        state.ws_ping_interval_ms = interval;
+    }
+    state.ws_echo_protocol = env_flag("WS_ECHO_PROTOCOL");
+    if let Some(grace) = env_u64("REAUTH_GRACE_MS") {
+        state.reauth_grace_ms = grace;
+    }
+    if let Some(cap) = env_u64("WAL_BUFFER_CAP") {
+        state.wal_buffer_cap = cap as usize;
+    }
+    if let Some(mb) = env_u64("MIN_FREE_DISK_MB") {
+        state.min_free_bytes = mb * 1024 * 1024;
+    }
+    state.archive_dir = Path::new(&data_dir).join("archive");
+    state.secrets_dir = Path::new(&data_dir).join("secrets");
+    state.trash_dir = Path::new(&data_dir).join(".trash");
+    if let Some(days) = env_u64("TRASH_RETENTION_DAYS") {
+        state.trash_retention_days = days;
+    }
+    state.archive_compress = std::env::var("ARCHIVE_COMPRESS")
+        .map(|_| env_flag("ARCHIVE_COMPRESS"))
+        .unwrap_or(true);
+    state.compress_storage = std::env::var("STORAGE_COMPRESSION")
+        .map(|v| v.trim().eq_ignore_ascii_case("zstd"))
+        .unwrap_or(false);
+    state.admin_token = std::env::var("ADMIN_TOKEN")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    state.digest_target = digest_target_from_env();
+    if let Some(secs) = std::env::var("DIGEST_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+    {
+        state.digest_interval_ms = secs.saturating_mul(1000);
+    }
+    state.alert_policy = AlertPolicy {
+        webhook_url: std::env::var("ALERT_WEBHOOK_URL")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty()),
+        max_bytes: env_u64("ALERT_MAX_BYTES"),
+        max_conflicts_per_hour: env_u64("ALERT_MAX_CONFLICTS_PER_HOUR"),
+        max_unflushed_minutes: env_u64("ALERT_MAX_UNFLUSHED_MINUTES"),
+    };
+    if let Some(secs) = env_u64("ALERT_INTERVAL_SECS") {
+        state.alert_interval_ms = secs.saturating_mul(1000);
+    }
+    state.retention = RetentionPolicy {
+        purge_history_days: env_u64("RETENTION_PURGE_HISTORY_DAYS"),
+        scrub_wal: env_flag("RETENTION_SCRUB_WAL"),
+        compact_history_days: env_u64("RETENTION_COMPACT_HISTORY_DAYS"),
+        delete_unused_months: env_u64("RETENTION_DELETE_UNUSED_MONTHS"),
+    };
+    if let Some(interval) = env_u64("CONTENT_HASH_INTERVAL") {
+        state.hash_interval = interval;
+    }
+    if let Some(interval) = env_u64("DOC_STATS_INTERVAL") {
+        state.stats_interval = interval;
+    }
+    if let Some(secs) = env_u64("RETENTION_INTERVAL_SECS") {
+        state.retention_interval_ms = secs.saturating_mul(1000);
+    }
+    if env_flag("READ_REPLICA") {
+        state.replica = Some(ReplicaConfig {
+            primary_url: std::env::var("PRIMARY_URL")
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty()),
+            refresh_ms: env_u64("REPLICA_REFRESH_MS").unwrap_or(DEFAULT_REPLICA_REFRESH_MS),
+        });
+    }
+    if let Ok(spec) = std::env::var("CLUSTER_NODES")
+        && !spec.trim().is_empty()
+    {
+        let self_id = std::env::var("CLUSTER_NODE_ID")
+            .map_err(|_| anyhow::anyhow!("CLUSTER_NODE_ID is required with CLUSTER_NODES"))?;
+        let cluster = Cluster::new(
+            self_id.trim().to_string(),
+            parse_nodes(&spec)?,
+            env_u64("CLUSTER_HEALTH_MS").unwrap_or(DEFAULT_CLUSTER_HEALTH_MS),
+        )?;
+        info!(node = %cluster.self_id, nodes = cluster.nodes.len(), "running as a cluster node");
+        state.cluster = Some(Arc::new(cluster));
+    }
+    if state.invite_only && state.admin_token.is_none() {
+        warn!("invite-only mode without ADMIN_TOKEN: no new documents can be created");
+    }
+    if std::env::var_os("WAL_PRESENCE_EVENTS").is_some() {
+        warn!("WAL_PRESENCE_EVENTS is ignored: presence is no longer written to the WAL");
+    }
 
-    let hydrated = flush_all_wals_to_snapshots(&state).await?;
-    info!(
-        slugs = hydrated,
-        "replayed pending WAL entries into snapshots"
-    );
+    // Bound before the data directory is ours, so connections queue up
+    // while a process draining with REUSE_PORT still writes it.
+    let reuse_port = env_flag("REUSE_PORT");
+    let addr = "0.0.0.0:9000".parse()?;
+    let (listener, source) = bind_listener(addr, reuse_port).await?;
+    // A replica only reads the directory; cluster nodes sharing it each
+    // write their own documents.
+    let lock_name = match &state.cluster {
+        _ if state.replica.is_some() => None,
+        Some(cluster) => Some(format!(".lock-{}", cluster.self_id)),
+        None => Some(".lock".to_string()),
+    };
+    let _data_lock = match lock_name {
+        Some(name) => Some(lock_data_dir(Path::new(&data_dir).join(name), reuse_port).await?),
+        None => None,
+    };
+
+    if env_flag("GIT_SNAPSHOTS") && state.replica.is_none() {
+        if state.compress_storage {
+            anyhow::bail!("GIT_SNAPSHOTS cannot be combined with STORAGE_COMPRESSION");
+        }
+        let remote = std::env::var("GIT_REMOTE")
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty());
+        state.git = Some(Arc::new(GitStore::open(state.snap_dir.clone(), remote)?));
+    }
+    let env_dir = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let ingest = match (env_dir("INGEST_DIR"), env_dir("MIRROR_DIR")) {
+        (Some(_), Some(_)) => anyhow::bail!("set either INGEST_DIR or MIRROR_DIR, not both"),
+        (Some(dir), None) => Some(Arc::new(IngestDir::new(dir.into()))),
+        (None, Some(dir)) => Some(Arc::new(IngestDir::mirror(dir.into()))),
+        (None, None) => None,
+    };
+    if state.replica.is_none() {
+        state.mirror = ingest.clone().filter(|ingest| ingest.mirror);
+    }
+
+    let tenants = match std::env::var("TENANTS_FILE") {
+        Ok(path) if !path.trim().is_empty() => load_tenants(Path::new(path.trim()))?,
+        _ => Vec::new(),
+    };
+    if !tenants.is_empty() && state.cluster.is_some() {
+        anyhow::bail!("TENANTS_FILE cannot be combined with CLUSTER_NODES");
+    }
+    let tenants = tenants
+        .into_iter()
+        .map(|tenant| {
+            let tenant_state = tenant_state(&state, Path::new(&data_dir), &tenant)?;
+            Ok((tenant, tenant_state))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    state.tenants = Arc::new(tenants.iter().map(|(_, s)| s.clone()).collect());
+    if !tenants.is_empty() {
+        info!(tenants = tenants.len(), "serving tenants");
+    }
 
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
-    let periodic_handle = tokio::spawn(run_periodic_snapshot_flush(state.clone(), shutdown_rx));
+    if state.replica.is_some() {
+        info!("running as a read-only replica");
+    } else {
+        tokio::spawn(run_cluster_health(state.clone(), shutdown_rx.clone()));
+        tokio::spawn(run_disk_watchdog(state.clone(), shutdown_rx.clone()));
+    }
+    let mut states = vec![("", state.clone())];
+    states.extend(tenants.iter().map(|(t, s)| (t.id.as_str(), s.clone())));
+    let mut periodic_handles = Vec::with_capacity(states.len());
+    for (_, state) in &states {
+        periodic_handles.push(start_storage_loops(state, shutdown_rx.clone()).await?);
+    }
+    if let Some(git) = &state.git
+        && git.remote.is_some()
+    {
+        let interval = env_u64("GIT_PUSH_INTERVAL_SECS").unwrap_or(DEFAULT_GIT_PUSH_INTERVAL_SECS);
+        tokio::spawn(run_git_push_loop(
+            state.clone(),
+            interval.saturating_mul(1000),
+            shutdown_rx.clone(),
+        ));
+    }
+    if let Some(ingest) = &ingest
+        && state.replica.is_none()
+    {
+        let interval = env_u64("INGEST_INTERVAL_MS").unwrap_or(DEFAULT_INGEST_INTERVAL_MS);
+        info!(
+            dir = %ingest.dir.display(),
+            mirror = ingest.mirror,
+            interval,
+            "syncing documents with files"
+        );
+        tokio::spawn(run_ingest_loop(
+            state.clone(),
+            ingest.clone(),
+            interval,
+            shutdown_rx.clone(),
+        ));
+    }
+
+    // Only the instance's own documents; tenants stay on WebSockets.
+    #[cfg(feature = "webtransport")]
+    if let Ok(addr) = std::env::var("WEBTRANSPORT_ADDR") {
+        let cert = std::env::var("WEBTRANSPORT_CERT")
+            .ok()
+            .zip(std::env::var("WEBTRANSPORT_KEY").ok());
+        let (endpoint, webtransport) = bind_webtransport(
+            addr.parse()?,
+            cert.as_ref().map(|(c, k)| (Path::new(c), Path::new(k))),
+        )?;
+        info!(
+            cert_hash = webtransport.cert_hash,
+            "serving webtransport on {}",
+            endpoint.local_addr()?
+        );
+        state.webtransport = Some(webtransport);
+        tokio::spawn(run_webtransport(
+            state.clone(),
+            endpoint,
+            shutdown_rx.clone(),
+        ));
+    }
+    #[cfg(not(feature = "webtransport"))]
+    if std::env::var_os("WEBTRANSPORT_ADDR").is_some() {
+        tracing::warn!("WEBTRANSPORT_ADDR is ignored: built without the webtransport feature");
+    }
 
     let (signal_tx, signal_rx) = oneshot::channel();
     tokio::spawn(listen_for_shutdown_signal(shutdown_tx.clone(), signal_tx));
+    #[cfg(unix)]
+    tokio::spawn(listen_for_reload_signal(state.clone()));
 
-    let app = build_router(&state);
+    let mut app = build_router(&state);
+    if !tenants.is_empty() {
+        app = with_tenants(app, TenantRouters::new(&tenants));
+    }
 
-    let addr = "0.0.0.0:9000";
-    info!("listening on {}", addr);
-    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(?source, "listening on {}", listener.local_addr()?);
+    let draining: Vec<AppState> = states.iter().map(|(_, s)| s.clone()).collect();
     axum::serve(listener, app)
         .with_graceful_shutdown(async move {
             let _ = signal_rx.await;
+            draining.iter().for_each(drain_sessions);
         })
         .await?;
 
     let _ = shutdown_tx.send(true);
 
-    if let Err(err) = periodic_handle.await {
-        error!("periodic flush task aborted: {:#}", err);
+    for handle in periodic_handles {
+        if let Err(err) = handle.await {
+            error!("periodic flush task aborted: {:#}", err);
+        }
+    }
+    if state.replica.is_some() {
+        return Ok(());
     }
 
-    match finalize_shutdown(&state).await {
-        Ok((loaded, wal)) => {
-            info!(loaded, wal, "flushed snapshots before shutdown");
-        }
-        Err(err) => {
-            error!("shutdown flush failed: {:#}", err);
+    for (tenant, state) in &states {
+        match finalize_shutdown(state).await {
+            Ok((loaded, wal)) => {
+                info!(tenant, loaded, wal, "flushed snapshots before shutdown");
+            }
+            Err(err) => {
+                error!(tenant, "shutdown flush failed: {:#}", err);
+            }
         }
     }
     Ok(())
 }
 
-async fn run_periodic_snapshot_flush(state: AppState, mut shutdown: watch::Receiver<bool>) {
-    let interval = Duration::from_millis(state.flush_idle_ms.max(50));
-    loop {
-        tokio::select! {
-            _ = sleep(interval) => {
-                let slugs: Vec<String> = state.docs.read().keys().cloned().collect();
-                for slug in slugs {
-                    if let Err(err) = flush_snapshot_if_needed(&state, &slug).await {
-                        error!(%slug, "periodic flush failed: {:#}", err);
-                    }
-                }
-            }
-            changed = shutdown.changed() => {
-                if changed.is_ok() && *shutdown.borrow() {
-                    break;
-                }
-            }
-        }
+/// Starts what keeps the documents of `state` written out and tidied up, or
+/// refreshed from the primary on a replica. Returns the task that flushes or
+/// refreshes them, which ends on shutdown.
+async fn start_storage_loops(
+    state: &AppState,
+    shutdown_rx: watch::Receiver<bool>,
+) -> anyhow::Result<JoinHandle<()>> {
+    // A replica never writes to the replicated data directory.
+    if state.replica.is_some() {
+        return Ok(tokio::spawn(run_replica_refresh(
+            state.clone(),
+            shutdown_rx,
+        )));
     }
+    let moved = migrate_password_hashes(state)? + migrate_workspace_secrets(state)?;
+    if moved > 0 {
+        info!(
+            files = moved,
+            "moved password hashes to the secrets directory"
+        );
+    }
+    let hydrated = flush_all_wals_to_snapshots(state).await?;
+    info!(
+        slugs = hydrated,
+        "replayed pending WAL entries into snapshots"
+    );
+    tokio::spawn(run_digest_loop(state.clone(), shutdown_rx.clone()));
+    tokio::spawn(run_alert_loop(state.clone(), shutdown_rx.clone()));
+    tokio::spawn(run_retention_loop(state.clone(), shutdown_rx.clone()));
+    tokio::spawn(run_trash_purge_loop(state.clone(), shutdown_rx.clone()));
+    tokio::spawn(run_expiry_loop(state.clone(), shutdown_rx.clone()));
+    tokio::spawn(run_wal_recovery(state.clone(), shutdown_rx.clone()));
+    Ok(tokio::spawn(run_periodic_snapshot_flush(
+        state.clone(),
+        shutdown_rx,
+    )))
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| {
+            matches!(
+                v.trim().to_ascii_lowercase().as_str(),
+                "1" | "true" | "yes" | "on"
+            )
+        })
+        .unwrap_or(false)
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+}
+
+fn digest_target_from_env() -> Option<DigestTarget> {
+    let non_empty = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    if let Some(url) = non_empty("DIGEST_WEBHOOK_URL") {
+        return Some(DigestTarget::Webhook(url));
+    }
+    let addr = non_empty("DIGEST_SMTP_ADDR")?;
+    let to: Vec<String> = non_empty("DIGEST_SMTP_TO")?
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+    let from = non_empty("DIGEST_SMTP_FROM").unwrap_or_else(|| "coedit@localhost".to_string());
+    Some(DigestTarget::Smtp { addr, from, to })
 }
 
 #[cfg(unix)]
@@ -169,6 +511,24 @@ async fn listen_for_shutdown_signal(
     let _ = signal_tx.send(());
 }
 
+/// Reloads the live settings on every SIGHUP.
+#[cfg(unix)]
+async fn listen_for_reload_signal(state: AppState) {
+    let mut sighup = match unix_signal(SignalKind::hangup()) {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!("failed to install SIGHUP handler: {:#}", err);
+            return;
+        }
+    };
+    while sighup.recv().await.is_some() {
+        match reload_config(&state) {
+            Ok(report) => info!(changed = ?report.changed, "reloaded configuration"),
+            Err(err) => error!("config reload failed: {:#}", err),
+        }
+    }
+}
+
 #[cfg(not(unix))]
 async fn listen_for_shutdown_signal(
     shutdown_tx: watch::Sender<bool>,
@@ -181,109 +541,3 @@ async fn listen_for_shutdown_signal(
     let _ = shutdown_tx.send(true);
     let _ = signal_tx.send(());
 }
-
-async fn finalize_shutdown(state: &AppState) -> anyhow::Result<(usize, usize)> {
-    let loaded = flush_loaded_docs(state).await?;
-    let wal = flush_all_wals_to_snapshots(state).await?;
-    Ok((loaded, wal))
-}
-
-async fn flush_loaded_docs(state: &AppState) -> anyhow::Result<usize> {
-    let slugs: Vec<String> = state.docs.read().keys().cloned().collect();
-    let mut flushed = 0usize;
-    for slug in slugs {
-        if flush_snapshot_force(state, &slug).await? {
-            flushed += 1;
-        }
-    }
-    Ok(flushed)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::document::Doc;
-    use axum::{
-        body::Body,
-        http::{Request, StatusCode},
-    };
-    use parking_lot::RwLock;
-    use std::fs;
-    use std::sync::Arc;
-    use tower::util::ServiceExt;
-    use uuid::Uuid;
-
-    fn mk_state() -> AppState {
-        let base = std::env::temp_dir().join(format!("main-tests-{}", Uuid::new_v4()));
-        fs::create_dir_all(&base).unwrap();
-        let wal = base.join("wal");
-        let snap = base.join("snapshots");
-        fs::create_dir_all(&wal).unwrap();
-        fs::create_dir_all(&snap).unwrap();
-        AppState::new(wal, snap, 1_000, 128, true, Vec::new())
-    }
-
-    #[tokio::test]
-    async fn router_serves_health_endpoint() {
-        let state = mk_state();
-        let app = build_router(&state);
-
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/api/health")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::OK);
-    }
-
-    #[tokio::test]
-    async fn router_enforces_snapshot_auth() {
-        let state = mk_state();
-        let slug = "secure";
-        let mut doc = Doc::default();
-        doc.password_hash = Some(crate::storage::hash_password("pw"));
-        doc.content = "secret".into();
-        state
-            .docs
-            .write()
-            .insert(slug.into(), Arc::new(RwLock::new(doc)));
-
-        let app = build_router(&state);
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/api/snapshot?slug=secure")
-                    .method("GET")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
-    }
-
-    #[tokio::test]
-    async fn flush_loaded_docs_writes_pending_content() {
-        let state = mk_state();
-        let slug = "flush-me";
-        let mut doc = Doc::default();
-        doc.content = "shutdown".into();
-        doc.rev = 1;
-        doc.since_flush = 1;
-        state
-            .docs
-            .write()
-            .insert(slug.into(), Arc::new(RwLock::new(doc)));
-
-        let flushed = flush_loaded_docs(&state).await.unwrap();
-        assert_eq!(flushed, 1);
-
-        let snap = crate::storage::snapshot_path(&state, slug).unwrap();
-        assert_eq!(fs::read_to_string(snap).unwrap(), "shutdown");
-    }
-}
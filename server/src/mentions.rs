@@ -0,0 +1,273 @@
+//! `@name` mentions. Every flush keeps the names mentioned in the snapshot
+//! in the metadata sidecar, and names that were not in the previous one go
+//! to the [notification](crate::notifications) queue of whoever they name.
+//! Names resolve through the labels named users joined with since the
+//! server started; in a workspace with `members`, only members can be
+//! mentioned.
+
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+use crate::{
+    notifications::notify_mention,
+    state::{AppState, now_millis},
+    toc::fenced,
+    types::{ContentType, DocMeta},
+    workspace::workspace_settings_for,
+};
+
+const MAX_NAME_CHARS: usize = 64;
+const EXCERPT_CHARS: usize = 120;
+
+/// How names are compared: trimmed and lowercased, with spaces as `_` so
+/// `@ann_lee` reaches "Ann Lee".
+pub fn mention_key(name: &str) -> Option<String> {
+    let key: String = name
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+        .to_lowercase();
+    (!key.is_empty() && key.chars().count() <= MAX_NAME_CHARS).then_some(key)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// The line quoted in a notification.
+fn excerpt(line: &str) -> String {
+    let line = line.trim();
+    match line.char_indices().nth(EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+/// Names mentioned in `content` with the first line mentioning each. An `@`
+/// only starts a mention at the start of a word, so addresses are not
+/// mentions; in Markdown, code is skipped. Other content types have none.
+pub fn extract_mentions(content: &str, content_type: &ContentType) -> BTreeMap<String, String> {
+    let markdown = match content_type {
+        ContentType::Markdown => true,
+        ContentType::Plaintext => false,
+        _ => return BTreeMap::new(),
+    };
+    let mut mentions = BTreeMap::new();
+    let mut fence = None;
+    for line in content.lines() {
+        if markdown && fenced(&mut fence, line.trim_start()) {
+            continue;
+        }
+        // Odd pieces are inside code spans.
+        let pieces: Vec<&str> = if markdown {
+            line.split('`').step_by(2).collect()
+        } else {
+            vec![line]
+        };
+        for text in pieces {
+            let mut prev = ' ';
+            for (idx, c) in text.char_indices() {
+                if c == '@' && !is_name_char(prev) && prev != '@' {
+                    let rest = &text[idx + 1..];
+                    let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+                    // A sentence may end right after the name.
+                    if let Some(name) = mention_key(rest[..end].trim_end_matches(['.', '-'])) {
+                        mentions.entry(name).or_insert_with(|| excerpt(line));
+                    }
+                }
+                prev = c;
+            }
+        }
+    }
+    mentions
+}
+
+/// Remembers that `label` names `user_id`, for resolving mentions.
+pub fn record_name(state: &AppState, user_id: Uuid, label: &str) {
+    if let Some(key) = mention_key(label) {
+        state.mention_names.write().insert(key, user_id);
+    }
+}
+
+/// Queues notifications for the names the flush at `meta.snapshot_rev`
+/// found and the one before did not. `before` is `None` when that one was
+/// flushed before mentions were kept; every name would look new then, so
+/// nothing is sent.
+pub fn notify_mentions(
+    state: &AppState,
+    slug: &str,
+    before: Option<&[String]>,
+    meta: &DocMeta,
+    content: &str,
+) {
+    let Some(before) = before else {
+        return;
+    };
+    let fresh: Vec<&String> = meta
+        .mentions
+        .iter()
+        .flatten()
+        .filter(|name| !before.contains(name))
+        .collect();
+    if fresh.is_empty() {
+        return;
+    }
+    let members: Option<Vec<String>> = workspace_settings_for(state, slug)
+        .ok()
+        .flatten()
+        .map(|ws| ws.members.iter().filter_map(|m| mention_key(m)).collect())
+        .filter(|members: &Vec<String>| !members.is_empty());
+    let mut excerpts = extract_mentions(content, &meta.content_type.clone().unwrap_or_default());
+    let now = now_millis();
+    for name in fresh {
+        if members
+            .as_ref()
+            .is_some_and(|members| !members.contains(name))
+        {
+            continue;
+        }
+        let Some(user_id) = state.mention_names.read().get(name).copied() else {
+            continue;
+        };
+        let excerpt = excerpts.remove(name).unwrap_or_default();
+        notify_mention(state, slug, user_id, meta.snapshot_rev, excerpt, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        notifications::{clear_notifications, notifications_for},
+        presence::register_presence,
+        state::apply_edit,
+        storage::flush_snapshot_force,
+        types::{Edit, OpKind},
+        workspace::{WorkspaceSettings, save_workspace},
+    };
+
+    fn insert(base_rev: u64, pos: usize, text: &str) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[test]
+    fn finds_mentions_outside_code_and_addresses() {
+        let content = "Ask @Ann. and @bob-smith, cc ann@example.com\n\
+            `@code` @@x\n```\n@fenced\n```\n@Ann again";
+        let mentions = extract_mentions(content, &ContentType::Markdown);
+        assert_eq!(
+            mentions.keys().collect::<Vec<_>>(),
+            vec!["ann", "bob-smith"]
+        );
+        assert_eq!(
+            mentions["ann"],
+            "Ask @Ann. and @bob-smith, cc ann@example.com"
+        );
+        let plain = extract_mentions("`@code`", &ContentType::Plaintext);
+        assert!(plain.contains_key("code"));
+        assert!(extract_mentions("@x", &ContentType::Json).is_empty());
+        assert_eq!(mention_key(" Ann  Lee ").as_deref(), Some("ann_lee"));
+    }
+
+    #[tokio::test]
+    async fn new_mentions_notify_named_members_once() {
+        let base = std::env::temp_dir().join(format!("mentions-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let (ann, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        register_presence(
+            &state,
+            "team/x",
+            Uuid::new_v4(),
+            Some(ann),
+            Some("Ann".into()),
+            None,
+            0,
+        );
+        register_presence(
+            &state,
+            "other",
+            Uuid::new_v4(),
+            Some(bob),
+            Some("Bob".into()),
+            None,
+            0,
+        );
+        save_workspace(
+            &state,
+            "team",
+            &WorkspaceSettings {
+                members: vec!["ann".into()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        apply_edit(&state, "team/spec", insert(0, 0, "hi @ann\n"))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "team/spec").await.unwrap();
+        assert_eq!(notifications_for(&state, ann)[0].mentions, vec!["hi @ann"]);
+        clear_notifications(&state, ann, None, None);
+
+        apply_edit(&state, "team/spec", insert(1, 0, "@bob @ANN: review\n"))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "team/spec").await.unwrap();
+        assert!(notifications_for(&state, ann).is_empty());
+        // Bob is not a member of the workspace.
+        assert!(notifications_for(&state, bob).is_empty());
+
+        // Once the mention is gone, it is new again.
+        let len = state.docs.read()["team/spec"]
+            .read()
+            .content
+            .chars()
+            .count();
+        apply_edit(
+            &state,
+            "team/spec",
+            Edit {
+                ops: vec![OpKind::Delete { pos: 0, len }],
+                ..insert(2, 0, "")
+            },
+        )
+        .await
+        .unwrap();
+        flush_snapshot_force(&state, "team/spec").await.unwrap();
+        apply_edit(&state, "team/spec", insert(3, 0, "thanks @Ann\n"))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "team/spec").await.unwrap();
+        let queued = notifications_for(&state, ann);
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].mentions, vec!["thanks @Ann"]);
+        assert_eq!((queued[0].rev, queued[0].edits), (4, 0));
+
+        apply_edit(&state, "other", insert(0, 0, "@bob\n"))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "other").await.unwrap();
+        assert_eq!(notifications_for(&state, bob)[0].mentions, vec!["@bob"]);
+    }
+}
@@ -0,0 +1,528 @@
+//! Merging one document into another, e.g. a fork back into its original.
+//!
+//! Documents that share history (a fork made with the history export/import
+//! endpoints keeps the original's op ids) are merged with OT: everything the
+//! source did after the common revision is transformed over what the target
+//! did and applied as one edit, so nothing is lost. Regions both sides
+//! touched are reported as conflicts for review.
+//!
+//! Unrelated documents fall back to a line-based two-way merge. Without a
+//! common ancestor, lines present on only one side are kept and blocks that
+//! differ on both sides get git-style conflict markers.
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    document::{Doc, apply_ops, transform_pair},
+    history::{HistoryArchive, export_history},
+    state::{AppState, Rejection, apply_edit, get_or_load_doc},
+    types::{Edit, OpKind},
+};
+
+/// Upper bound on `target_lines * source_lines` for the two-way merge table.
+const MAX_DIFF_CELLS: usize = 25_000_000;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+    Ot,
+    TwoWay,
+}
+
+/// A region both sides changed. Offsets are chars into the target content
+/// as it was before the merge.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct MergeConflict {
+    pub target_start: usize,
+    pub target_end: usize,
+    pub target_text: String,
+    pub source_text: String,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct MergeReport {
+    pub strategy: MergeStrategy,
+    /// Last revision both documents share, for OT merges.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub common_rev: Option<u64>,
+    pub rev: u64,
+    pub content: String,
+    pub conflicts: Vec<MergeConflict>,
+    pub applied: bool,
+}
+
+/// Merges `source` into `target`. With `dry_run` only the report is built.
+pub async fn merge_docs(
+    state: &AppState,
+    source: &str,
+    target: &str,
+    dry_run: bool,
+) -> anyhow::Result<MergeReport> {
+    if source == target {
+        return Err(Rejection::new("same_doc", "cannot merge a document into itself").into());
+    }
+    // History comes from disk; the in-memory documents are authoritative and
+    // every applied edit is in the WAL, so the two must agree.
+    let mut src = export_history(state, source)?;
+    let mut tgt = export_history(state, target)?;
+    for (slug, history) in [(source, &mut src), (target, &mut tgt)] {
+        let doc = get_or_load_doc(state, slug).await?;
+        let d = doc.read();
+        if d.rev != history.rev {
+            return Err(Rejection::new("busy", "document changed during the merge").into());
+        }
+        history.content = d.content.clone();
+    }
+
+    let common = common_prefix(&src, &tgt);
+    // Scrubbed edits no longer carry their text, so they cannot be replayed
+    // onto the target.
+    let replayable = |h: &HistoryArchive| h.scrubbed_rev <= common as u64;
+    let (strategy, ops, conflicts) = if common > 0 && replayable(&src) && replayable(&tgt) {
+        let (ops, conflicts) = ot_merge(&src, &tgt, common);
+        (MergeStrategy::Ot, ops, conflicts)
+    } else {
+        let (merged, conflicts) = two_way_merge(&tgt.content, &src.content)?;
+        (
+            MergeStrategy::TwoWay,
+            replace_ops(&tgt.content, &merged),
+            conflicts,
+        )
+    };
+
+    let mut doc = Doc {
+        content: tgt.content.clone(),
+        ..Default::default()
+    };
+    apply_ops(&mut doc, &ops);
+    let mut report = MergeReport {
+        strategy,
+        common_rev: (strategy == MergeStrategy::Ot).then_some(common as u64),
+        rev: tgt.rev,
+        content: doc.content,
+        conflicts,
+        applied: false,
+    };
+    if dry_run || ops.is_empty() {
+        return Ok(report);
+    }
+    let edit = Edit {
+        base_rev: tgt.rev,
+        ops,
+        client_id: None,
+        op_id: Some(Uuid::new_v4()),
+        cursor_before: None,
+        cursor_after: None,
+        ts: None,
+        group_id: None,
+        user_id: None,
+    };
+    apply_edit(state, target, edit).await?;
+    let doc = get_or_load_doc(state, target).await?;
+    let d = doc.read();
+    report.rev = d.rev;
+    report.content = d.content.clone();
+    report.applied = true;
+    Ok(report)
+}
+
+/// Number of leading revisions both histories got from the same edits.
+/// Purged histories share nothing that can be checked.
+fn common_prefix(a: &HistoryArchive, b: &HistoryArchive) -> usize {
+    if a.history_start > 0 || b.history_start > 0 {
+        return 0;
+    }
+    a.edits
+        .iter()
+        .zip(&b.edits)
+        .take_while(|(x, y)| x.op_id.is_some() && x.op_id == y.op_id && x.ops == y.ops)
+        .count()
+}
+
+fn ot_merge(
+    src: &HistoryArchive,
+    tgt: &HistoryArchive,
+    common: usize,
+) -> (Vec<OpKind>, Vec<MergeConflict>) {
+    let since = |h: &HistoryArchive| -> Vec<OpKind> {
+        h.edits[common..]
+            .iter()
+            .flat_map(|e| e.ops.iter().cloned())
+            .collect()
+    };
+    let src_ops = since(src);
+    let tgt_ops = since(tgt);
+    let (merged, _) = transform_pair(&src_ops, &tgt_ops, true);
+
+    // Length at the common revision, recovered from the target's content.
+    let delta: isize = tgt_ops
+        .iter()
+        .map(|op| match op {
+            OpKind::Insert { text, .. } => text.chars().count() as isize,
+            OpKind::Delete { len, .. } => -(*len as isize),
+        })
+        .sum();
+    let base_len = (tgt.content.chars().count() as isize - delta).max(0) as usize;
+    let src_changes = changes(base_len, &origins(base_len, &src_ops));
+    let tgt_changes = changes(base_len, &origins(base_len, &tgt_ops));
+    let conflicts = overlapping(&src_changes, &tgt_changes)
+        .into_iter()
+        .map(|(s, t): (CharRange, CharRange)| MergeConflict {
+            target_start: t.0,
+            target_end: t.1,
+            target_text: slice_chars(&tgt.content, t.0, t.1),
+            source_text: slice_chars(&src.content, s.0, s.1),
+        })
+        .collect();
+    (merged, conflicts)
+}
+
+/// For every char after `ops`, the index it had in the base text, or `None`
+/// for inserted chars. Mirrors `apply_ops`' handling of out-of-range ops.
+fn origins(base_len: usize, ops: &[OpKind]) -> Vec<Option<usize>> {
+    let mut origin: Vec<Option<usize>> = (0..base_len).map(Some).collect();
+    for op in ops {
+        match op {
+            OpKind::Insert { pos, text } => {
+                if *pos <= origin.len() {
+                    let n = text.chars().count();
+                    origin.splice(*pos..*pos, std::iter::repeat_n(None, n));
+                }
+            }
+            OpKind::Delete { pos, len } => {
+                let start = (*pos).min(origin.len());
+                let end = pos.saturating_add(*len).min(origin.len());
+                origin.drain(start..end);
+            }
+        }
+    }
+    origin
+}
+
+/// One side's change: base range `[start, end)` replaced by chars
+/// `[from, to)` of that side's content.
+#[derive(Debug, Clone, Copy)]
+struct Change {
+    start: usize,
+    end: usize,
+    from: usize,
+    to: usize,
+}
+
+impl Change {
+    fn overlaps(&self, other: &Change) -> bool {
+        // Half-open overlap also catches an insert strictly inside the other
+        // side's replaced range; inserts at the same point compete too.
+        let inserts = |c: &Change| c.to > c.from;
+        (self.start < other.end && other.start < self.end)
+            || (self.start == other.start && inserts(self) && inserts(other))
+    }
+}
+
+fn changes(base_len: usize, origin: &[Option<usize>]) -> Vec<Change> {
+    let mut out = Vec::new();
+    let mut expected = 0usize;
+    let mut current: Option<Change> = None;
+    for (f, o) in origin.iter().enumerate() {
+        let open = |expected| Change {
+            start: expected,
+            end: expected,
+            from: f,
+            to: f,
+        };
+        match o {
+            None => current.get_or_insert_with(|| open(expected)).to = f + 1,
+            Some(i) => {
+                if *i > expected {
+                    current.get_or_insert_with(|| open(expected)).end = *i;
+                }
+                out.extend(current.take());
+                expected = i + 1;
+            }
+        }
+    }
+    if expected < base_len || current.is_some() {
+        let f = origin.len();
+        let mut c = current.unwrap_or(Change {
+            start: expected,
+            end: expected,
+            from: f,
+            to: f,
+        });
+        c.end = base_len.max(expected);
+        out.push(c);
+    }
+    out
+}
+
+type CharRange = (usize, usize);
+
+/// Clusters of overlapping changes, as `(source, target)` char ranges into
+/// each side's content.
+fn overlapping(src: &[Change], tgt: &[Change]) -> Vec<(CharRange, CharRange)> {
+    let mut out = Vec::new();
+    // Base range of the cluster being built, and its ranges on each side.
+    let mut open: Option<(CharRange, (CharRange, CharRange))> = None;
+    for s in src {
+        for t in tgt.iter().filter(|t| s.overlaps(t)) {
+            let (start, end) = (s.start.min(t.start), s.end.max(t.end));
+            match &mut open {
+                Some((base, (sr, tr))) if start < base.1 || start == base.0 => {
+                    base.1 = base.1.max(end);
+                    *sr = (sr.0.min(s.from), sr.1.max(s.to));
+                    *tr = (tr.0.min(t.from), tr.1.max(t.to));
+                }
+                _ => {
+                    out.extend(open.take().map(|(_, ranges)| ranges));
+                    open = Some(((start, end), ((s.from, s.to), (t.from, t.to))));
+                }
+            }
+        }
+    }
+    out.extend(open.map(|(_, ranges)| ranges));
+    out
+}
+
+fn slice_chars(s: &str, start: usize, end: usize) -> String {
+    s.chars()
+        .skip(start)
+        .take(end.saturating_sub(start))
+        .collect()
+}
+
+/// Ops turning `from` into `to`: one delete and one insert around the common
+/// prefix and suffix.
+pub(crate) fn replace_ops(from: &str, to: &str) -> Vec<OpKind> {
+    let a: Vec<char> = from.chars().collect();
+    let b: Vec<char> = to.chars().collect();
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let mut ops = Vec::new();
+    let removed = a.len() - prefix - suffix;
+    if removed > 0 {
+        ops.push(OpKind::Delete {
+            pos: prefix,
+            len: removed,
+        });
+    }
+    let inserted: String = b[prefix..b.len() - suffix].iter().collect();
+    if !inserted.is_empty() {
+        ops.push(OpKind::Insert {
+            pos: prefix,
+            text: inserted,
+        });
+    }
+    ops
+}
+
+fn two_way_merge(target: &str, source: &str) -> anyhow::Result<(String, Vec<MergeConflict>)> {
+    let t: Vec<&str> = target.split_inclusive('\n').collect();
+    let s: Vec<&str> = source.split_inclusive('\n').collect();
+    if t.len().saturating_mul(s.len()) > MAX_DIFF_CELLS {
+        return Err(Rejection::new(
+            "too_large",
+            "documents without shared history are too large to diff",
+        )
+        .into());
+    }
+    // lcs[i][j]: longest common subsequence of t[i..] and s[j..].
+    let width = s.len() + 1;
+    let mut lcs = vec![0u32; (t.len() + 1) * width];
+    for i in (0..t.len()).rev() {
+        for j in (0..s.len()).rev() {
+            lcs[i * width + j] = if t[i] == s[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut merged = String::new();
+    let mut conflicts = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut offset = 0usize; // chars of `target` consumed so far
+    let mut t_block: Vec<&str> = Vec::new();
+    let mut s_block: Vec<&str> = Vec::new();
+    let mut flush = |merged: &mut String,
+                     t_block: &mut Vec<&str>,
+                     s_block: &mut Vec<&str>,
+                     offset: &mut usize| {
+        let t_text = t_block.concat();
+        let s_text = s_block.concat();
+        let t_len = t_text.chars().count();
+        if !t_text.is_empty() && !s_text.is_empty() {
+            let line_end = |text: &str| if text.ends_with('\n') { "" } else { "\n" };
+            merged.push_str("<<<<<<< target\n");
+            merged.push_str(&t_text);
+            merged.push_str(line_end(&t_text));
+            merged.push_str("=======\n");
+            merged.push_str(&s_text);
+            merged.push_str(line_end(&s_text));
+            merged.push_str(">>>>>>> source\n");
+            conflicts.push(MergeConflict {
+                target_start: *offset,
+                target_end: *offset + t_len,
+                target_text: t_text,
+                source_text: s_text,
+            });
+        } else {
+            merged.push_str(&t_text);
+            merged.push_str(&s_text);
+        }
+        *offset += t_len;
+        t_block.clear();
+        s_block.clear();
+    };
+    while i < t.len() || j < s.len() {
+        if i < t.len() && j < s.len() && t[i] == s[j] {
+            flush(&mut merged, &mut t_block, &mut s_block, &mut offset);
+            merged.push_str(t[i]);
+            offset += t[i].chars().count();
+            i += 1;
+            j += 1;
+        } else if j == s.len()
+            || (i < t.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1])
+        {
+            t_block.push(t[i]);
+            i += 1;
+        } else {
+            s_block.push(s[j]);
+            j += 1;
+        }
+    }
+    flush(&mut merged, &mut t_block, &mut s_block, &mut offset);
+    Ok((merged, conflicts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::import_history;
+    use std::{fs, path::Path};
+
+    fn mk_state(tmp: &Path) -> AppState {
+        let wal_dir = tmp.join("wal");
+        let snap_dir = tmp.join("snapshots");
+        fs::create_dir_all(&wal_dir).unwrap();
+        fs::create_dir_all(&snap_dir).unwrap();
+        AppState::new(wal_dir, snap_dir, 10_000, 1_000, true, Vec::new())
+    }
+
+    async fn edit(state: &AppState, slug: &str, ops: Vec<OpKind>) {
+        let rev = get_or_load_doc(state, slug).await.unwrap().read().rev;
+        let edit = Edit {
+            base_rev: rev,
+            ops,
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(state, slug, edit).await.unwrap();
+    }
+
+    fn ins(pos: usize, text: &str) -> OpKind {
+        OpKind::Insert {
+            pos,
+            text: text.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn fork_merges_back_with_ot_and_reports_overlaps() {
+        let base = std::env::temp_dir().join(format!("merge-ot-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        edit(&state, "orig", vec![ins(0, "alpha beta gamma")]).await;
+        let archive = export_history(&state, "orig").unwrap();
+        import_history(&state, "fork", &archive).await.unwrap();
+
+        // Disjoint edits on each side, plus both rewriting "beta".
+        edit(&state, "orig", vec![ins(16, "!")]).await;
+        edit(
+            &state,
+            "orig",
+            vec![OpKind::Delete { pos: 6, len: 4 }, ins(6, "BETA")],
+        )
+        .await;
+        edit(&state, "fork", vec![ins(0, "> ")]).await;
+        edit(
+            &state,
+            "fork",
+            vec![OpKind::Delete { pos: 8, len: 4 }, ins(8, "b")],
+        )
+        .await;
+
+        let dry = merge_docs(&state, "fork", "orig", true).await.unwrap();
+        assert_eq!(dry.strategy, MergeStrategy::Ot);
+        assert_eq!(dry.common_rev, Some(1));
+        assert!(!dry.applied);
+        assert_eq!(
+            get_or_load_doc(&state, "orig")
+                .await
+                .unwrap()
+                .read()
+                .content,
+            "alpha BETA gamma!"
+        );
+        assert_eq!(dry.conflicts.len(), 1);
+        assert_eq!(dry.conflicts[0].target_text, "BETA");
+        assert_eq!(dry.conflicts[0].source_text, "b");
+
+        let report = merge_docs(&state, "fork", "orig", false).await.unwrap();
+        assert!(report.applied);
+        assert_eq!(report.content, dry.content);
+        assert_eq!(report.content, "> alpha bBETA gamma!");
+        assert_eq!(report.rev, 4);
+    }
+
+    #[tokio::test]
+    async fn unrelated_docs_get_a_two_way_merge_with_markers() {
+        let base = std::env::temp_dir().join(format!("merge-2way-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        edit(&state, "a", vec![ins(0, "title\nsame\nmine\nend\n")]).await;
+        edit(
+            &state,
+            "b",
+            vec![ins(0, "title\nsame\ntheirs\nend\nextra\n")],
+        )
+        .await;
+
+        let report = merge_docs(&state, "b", "a", false).await.unwrap();
+        assert_eq!(report.strategy, MergeStrategy::TwoWay);
+        assert_eq!(
+            report.content,
+            "title\nsame\n<<<<<<< target\nmine\n=======\ntheirs\n>>>>>>> source\nend\nextra\n"
+        );
+        assert_eq!(
+            report.conflicts,
+            vec![MergeConflict {
+                target_start: 11,
+                target_end: 16,
+                target_text: "mine\n".into(),
+                source_text: "theirs\n".into(),
+            }]
+        );
+        assert_eq!(
+            get_or_load_doc(&state, "a").await.unwrap().read().content,
+            report.content
+        );
+    }
+
+    #[tokio::test]
+    async fn merging_into_itself_is_refused() {
+        let base = std::env::temp_dir().join(format!("merge-self-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        let err = merge_docs(&state, "x", "x", false).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, "same_doc");
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{document::InvalidOp, state::AppState};
+
+/// Number of recent load latencies kept for percentile estimates.
+const LATENCY_SAMPLES: usize = 1024;
+/// Round trips slower than this are counted and logged.
+pub const SLOW_RTT_MS: u64 = 1_000;
+
+/// Counters for the in-memory document lifecycle: load from disk, WAL
+/// hydration, snapshot flush and unload, plus edits refused as invalid or
+/// for lack of disk space, client timestamps that were not trusted and the
+/// round-trip times of WebSocket sessions.
+#[derive(Debug, Default)]
+pub struct LifecycleMetrics {
+    loads: AtomicU64,
+    hydrated_edits: AtomicU64,
+    flushes: AtomicU64,
+    flushed_edits: AtomicU64,
+    unloads: AtomicU64,
+    invalid_ops: AtomicU64,
+    clock_skew: AtomicU64,
+    disk_refusals: AtomicU64,
+    load_micros: Mutex<VecDeque<u64>>,
+    slow_rtt: AtomicU64,
+    rtt_micros: Mutex<VecDeque<u64>>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LifecycleStats {
+    pub loads: u64,
+    pub hydrated_edits: u64,
+    pub flushes: u64,
+    pub flushed_edits: u64,
+    pub unloads: u64,
+    /// Edits refused by strict op validation.
+    pub invalid_ops: u64,
+    /// Client timestamps replaced because the client clock was too far off.
+    pub clock_skew: u64,
+    /// Writes refused because free disk space was below the threshold.
+    pub disk_refusals: u64,
+    pub load_ms_p50: f64,
+    pub load_ms_p90: f64,
+    pub load_ms_p99: f64,
+    /// Round trips slower than [`SLOW_RTT_MS`].
+    pub slow_rtt: u64,
+    pub rtt_ms_p50: f64,
+    pub rtt_ms_p90: f64,
+    pub rtt_ms_p99: f64,
+}
+
+fn duration_ms(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1000.0
+}
+
+fn percentile_ms(sorted: &[u64], pct: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let idx = (sorted.len() * pct).div_ceil(100).saturating_sub(1);
+    sorted[idx.min(sorted.len() - 1)] as f64 / 1000.0
+}
+
+impl LifecycleMetrics {
+    pub fn snapshot(&self) -> LifecycleStats {
+        let mut samples: Vec<u64> = self.load_micros.lock().iter().copied().collect();
+        samples.sort_unstable();
+        let mut rtt: Vec<u64> = self.rtt_micros.lock().iter().copied().collect();
+        rtt.sort_unstable();
+        LifecycleStats {
+            loads: self.loads.load(Ordering::Relaxed),
+            hydrated_edits: self.hydrated_edits.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            flushed_edits: self.flushed_edits.load(Ordering::Relaxed),
+            unloads: self.unloads.load(Ordering::Relaxed),
+            invalid_ops: self.invalid_ops.load(Ordering::Relaxed),
+            clock_skew: self.clock_skew.load(Ordering::Relaxed),
+            disk_refusals: self.disk_refusals.load(Ordering::Relaxed),
+            load_ms_p50: percentile_ms(&samples, 50),
+            load_ms_p90: percentile_ms(&samples, 90),
+            load_ms_p99: percentile_ms(&samples, 99),
+            slow_rtt: self.slow_rtt.load(Ordering::Relaxed),
+            rtt_ms_p50: percentile_ms(&rtt, 50),
+            rtt_ms_p90: percentile_ms(&rtt, 90),
+            rtt_ms_p99: percentile_ms(&rtt, 99),
+        }
+    }
+}
+
+fn push_sample(samples: &Mutex<VecDeque<u64>>, micros: u64) {
+    let mut samples = samples.lock();
+    if samples.len() == LATENCY_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(micros);
+}
+
+pub fn record_load(state: &AppState, slug: &str, elapsed: Duration, wal_edits: usize) {
+    let m = &state.metrics;
+    m.loads.fetch_add(1, Ordering::Relaxed);
+    m.hydrated_edits
+        .fetch_add(wal_edits as u64, Ordering::Relaxed);
+    push_sample(&m.load_micros, elapsed.as_micros() as u64);
+    info!(
+        event = "doc_loaded",
+        %slug,
+        duration_ms = duration_ms(elapsed),
+        wal_edits,
+        "document loaded"
+    );
+}
+
+pub fn record_flush(
+    state: &AppState,
+    slug: &str,
+    rev: u64,
+    edits: usize,
+    bytes: usize,
+    elapsed: Duration,
+) {
+    let m = &state.metrics;
+    m.flushes.fetch_add(1, Ordering::Relaxed);
+    m.flushed_edits.fetch_add(edits as u64, Ordering::Relaxed);
+    info!(
+        event = "flush",
+        %slug,
+        rev,
+        edits,
+        bytes,
+        duration_ms = duration_ms(elapsed),
+        "snapshot flushed"
+    );
+}
+
+pub fn record_edit(slug: &str, client_id: Option<Uuid>, rev: u64, elapsed: Duration) {
+    info!(
+        event = "edit_applied",
+        %slug,
+        client_id = client_id.map(|id| id.to_string()),
+        rev,
+        duration_ms = duration_ms(elapsed),
+        "edit applied"
+    );
+}
+
+pub fn record_unload(state: &AppState, slug: &str, reason: &str) {
+    state.metrics.unloads.fetch_add(1, Ordering::Relaxed);
+    info!(event = "doc_unloaded", %slug, reason, "document unloaded");
+}
+
+pub fn record_invalid_op(state: &AppState, slug: &str, client_id: Option<Uuid>, op: &InvalidOp) {
+    state.metrics.invalid_ops.fetch_add(1, Ordering::Relaxed);
+    warn!(
+        event = "op_rejected",
+        %slug,
+        client_id = client_id.map(|id| id.to_string()),
+        index = op.index,
+        reason = op.reason,
+        doc_len = op.doc_len,
+        "edit refused by strict op validation"
+    );
+}
+
+pub fn record_clock_skew(state: &AppState, slug: &str, client_id: Option<Uuid>, skew_ms: i64) {
+    state.metrics.clock_skew.fetch_add(1, Ordering::Relaxed);
+    warn!(
+        event = "clock_skew",
+        %slug,
+        client_id = client_id.map(|id| id.to_string()),
+        skew_ms,
+        "client timestamp replaced by server time"
+    );
+}
+
+pub fn record_rtt(state: &AppState, slug: &str, client_id: Option<Uuid>, rtt_ms: u64) {
+    push_sample(&state.metrics.rtt_micros, rtt_ms * 1000);
+    if rtt_ms > SLOW_RTT_MS {
+        state.metrics.slow_rtt.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            event = "slow_client",
+            %slug,
+            client_id = client_id.map(|id| id.to_string()),
+            rtt_ms,
+            "websocket round trip is slow"
+        );
+    }
+}
+
+pub fn record_disk_low(free_bytes: u64, min_free_bytes: u64) {
+    warn!(
+        event = "disk_low",
+        free_bytes,
+        min_free_bytes,
+        "free disk space below the threshold, refusing new documents and large edits"
+    );
+}
+
+pub fn record_disk_refusal(state: &AppState, slug: &str, bytes: u64, creating: bool) {
+    state.metrics.disk_refusals.fetch_add(1, Ordering::Relaxed);
+    warn!(
+        event = "disk_refused",
+        %slug,
+        bytes,
+        creating,
+        "write refused for lack of disk space"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_ms_picks_nearest_rank() {
+        assert_eq!(percentile_ms(&[], 50), 0.0);
+        let samples: Vec<u64> = (1..=100).map(|v| v * 1000).collect();
+        assert_eq!(percentile_ms(&samples, 50), 50.0);
+        assert_eq!(percentile_ms(&samples, 99), 99.0);
+        assert_eq!(percentile_ms(&[7000], 90), 7.0);
+    }
+}
@@ -0,0 +1,327 @@
+//! Notifications for followed documents. Named users follow a document over
+//! the WebSocket, which keeps them in its metadata sidecar. Changes made while
+//! a follower is not on the document are queued for them, one entry per
+//! document that grows until it is read, and a new entry is pushed to any
+//! socket they have open elsewhere. The queue lives in memory, like pending
+//! digests.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    digest::contributor_name,
+    state::{AppState, get_or_load_doc},
+    storage::persist_meta,
+    types::{Edit, Notification, ServerMsg},
+};
+
+/// Entries kept per user; the oldest go first.
+pub const MAX_NOTIFICATIONS: usize = 100;
+/// Mentions quoted per entry; the oldest go first.
+const MAX_MENTIONS: usize = 10;
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct NotificationsResp {
+    pub notifications: Vec<Notification>,
+}
+
+/// Adds or removes `user_id` from the followers of `slug`. Returns whether
+/// anything changed. Unfollowing drops what was queued for the document.
+pub async fn set_following(
+    state: &AppState,
+    slug: &str,
+    user_id: Uuid,
+    follow: bool,
+) -> anyhow::Result<bool> {
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let meta = {
+        let mut d = doc_arc.write();
+        let changed = if follow {
+            d.meta.followers.insert(user_id)
+        } else {
+            d.meta.followers.remove(&user_id)
+        };
+        if !changed {
+            return Ok(false);
+        }
+        d.meta.clone()
+    };
+    persist_meta(state, slug, &meta)?;
+    if !follow {
+        clear_notifications(state, user_id, Some(slug), None);
+    }
+    Ok(true)
+}
+
+/// Whether `user_id` has a session on `slug`.
+fn is_on_doc(state: &AppState, slug: &str, user_id: Uuid) -> bool {
+    state.presence.read().get(slug).is_some_and(|presence| {
+        presence
+            .clients
+            .values()
+            .any(|client| client.user_id == Some(user_id))
+    })
+}
+
+/// Sends `msg` to every socket `user_id` has open.
+fn push_to_user(state: &AppState, user_id: Uuid, msg: ServerMsg) {
+    let sessions: HashMap<String, HashSet<Uuid>> = state
+        .presence
+        .read()
+        .iter()
+        .filter_map(|(slug, presence)| {
+            let clients: HashSet<Uuid> = presence
+                .clients
+                .values()
+                .filter(|client| client.user_id == Some(user_id))
+                .map(|client| client.client_id)
+                .collect();
+            (!clients.is_empty()).then(|| (slug.clone(), clients))
+        })
+        .collect();
+    let subs = state.subs.read();
+    for (slug, clients) in sessions {
+        for sub in subs.get(&slug).into_iter().flatten() {
+            if sub.client_id.is_some_and(|id| clients.contains(&id)) {
+                let _ = sub.tx.send(msg.clone());
+            }
+        }
+    }
+}
+
+/// Updates the entry of `slug` queued for `user_id`, starting one at `rev`
+/// when there is none. Returns the entry when it is new or `update` says it
+/// is worth pushing again.
+fn queue(
+    state: &AppState,
+    user_id: Uuid,
+    slug: &str,
+    rev: u64,
+    ts: u64,
+    update: impl FnOnce(&mut Notification) -> bool,
+) -> Option<Notification> {
+    let mut queues = state.notifications.write();
+    let queue = queues.entry(user_id).or_default();
+    let (idx, created) = match queue.iter().position(|n| n.slug == slug) {
+        Some(idx) => (idx, false),
+        None => {
+            if queue.len() >= MAX_NOTIFICATIONS {
+                queue.remove(0);
+            }
+            queue.push(Notification {
+                slug: slug.to_string(),
+                since_rev: rev,
+                rev,
+                edits: 0,
+                editors: Vec::new(),
+                mentions: Vec::new(),
+                first_at: ts,
+                last_at: ts,
+            });
+            (queue.len() - 1, true)
+        }
+    };
+    let entry = &mut queue[idx];
+    entry.rev = entry.rev.max(rev);
+    entry.last_at = ts;
+    let ping = update(entry);
+    (created || ping).then(|| entry.clone())
+}
+
+/// Queues an applied edit for the followers of `slug` who are not on it,
+/// other than its author.
+pub fn notify_followers(
+    state: &AppState,
+    slug: &str,
+    followers: &BTreeSet<Uuid>,
+    edit: &Edit,
+    rev: u64,
+    ts: u64,
+) {
+    if followers.is_empty() {
+        return;
+    }
+    let editor = contributor_name(state, slug, edit);
+    for &user_id in followers {
+        if edit.user_id == Some(user_id) || is_on_doc(state, slug, user_id) {
+            continue;
+        }
+        let pushed = queue(state, user_id, slug, rev, ts, |entry| {
+            entry.since_rev = entry.since_rev.min(rev - 1);
+            entry.edits += 1;
+            if let Some(name) = &editor
+                && !entry.editors.contains(name)
+            {
+                entry.editors.push(name.clone());
+            }
+            // Only new entries are pushed, so a busy document is one ping.
+            false
+        });
+        if let Some(notification) = pushed {
+            push_to_user(state, user_id, ServerMsg::Notification { notification });
+        }
+    }
+}
+
+/// Queues that `user_id` was mentioned in `slug` as of `rev`, quoting the
+/// line. The first mention in an entry is pushed even when the entry is not
+/// new.
+pub fn notify_mention(
+    state: &AppState,
+    slug: &str,
+    user_id: Uuid,
+    rev: u64,
+    excerpt: String,
+    ts: u64,
+) {
+    let pushed = queue(state, user_id, slug, rev, ts, |entry| {
+        let first = entry.mentions.is_empty();
+        if entry.mentions.len() >= MAX_MENTIONS {
+            entry.mentions.remove(0);
+        }
+        entry.mentions.push(excerpt);
+        first
+    });
+    if let Some(notification) = pushed {
+        push_to_user(state, user_id, ServerMsg::Notification { notification });
+    }
+}
+
+/// What is queued for `user_id`, oldest first.
+pub fn notifications_for(state: &AppState, user_id: Uuid) -> Vec<Notification> {
+    state
+        .notifications
+        .read()
+        .get(&user_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Drops queued entries of `user_id`: those of `slug` or all of them, and
+/// with `up_to` only those it covers. Returns how many were dropped.
+pub fn clear_notifications(
+    state: &AppState,
+    user_id: Uuid,
+    slug: Option<&str>,
+    up_to: Option<u64>,
+) -> usize {
+    let mut queues = state.notifications.write();
+    let Some(queue) = queues.get_mut(&user_id) else {
+        return 0;
+    };
+    let before = queue.len();
+    queue.retain(|n| {
+        slug.is_some_and(|slug| n.slug != slug) || up_to.is_some_and(|rev| n.rev > rev)
+    });
+    let dropped = before - queue.len();
+    if queue.is_empty() {
+        queues.remove(&user_id);
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        presence::register_presence,
+        seen::mark_seen,
+        state::{apply_edit, now_millis},
+        storage::load_meta,
+        types::OpKind,
+    };
+
+    fn insert(base_rev: u64, text: &str, user_id: Option<Uuid>) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id,
+        }
+    }
+
+    #[tokio::test]
+    async fn queues_changes_for_absent_followers_and_pushes_new_entries() {
+        let base = std::env::temp_dir().join(format!("notify-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let (ann, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        apply_edit(&state, "spec", insert(0, "draft", None))
+            .await
+            .unwrap();
+        assert!(set_following(&state, "spec", ann, true).await.unwrap());
+        assert!(!set_following(&state, "spec", ann, true).await.unwrap());
+        assert!(set_following(&state, "spec", bob, true).await.unwrap());
+        assert!(
+            load_meta(&state, "spec")
+                .unwrap()
+                .unwrap()
+                .followers
+                .contains(&ann)
+        );
+
+        // Ann is on another document, Bob on this one.
+        let ann_client = Uuid::new_v4();
+        register_presence(&state, "inbox", ann_client, Some(ann), None, None, 0);
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut sub: crate::subscription::Subscriber = tx.into();
+        sub.client_id = Some(ann_client);
+        state.subs.write().insert("inbox".into(), vec![sub]);
+        register_presence(&state, "spec", Uuid::new_v4(), Some(bob), None, None, 0);
+
+        apply_edit(&state, "spec", insert(1, "a", None))
+            .await
+            .unwrap();
+        apply_edit(&state, "spec", insert(2, "b", None))
+            .await
+            .unwrap();
+        // Her own edits are not news to her.
+        apply_edit(&state, "spec", insert(3, "c", Some(ann)))
+            .await
+            .unwrap();
+        let queued = notifications_for(&state, ann);
+        assert_eq!(queued.len(), 1);
+        assert_eq!(
+            (queued[0].since_rev, queued[0].rev, queued[0].edits),
+            (1, 3, 2)
+        );
+        assert!(queued[0].last_at <= now_millis());
+        match rx.try_recv().unwrap() {
+            ServerMsg::Notification { notification } => assert_eq!(notification.rev, 2),
+            other => panic!("expected a notification, got {other:?}"),
+        }
+        assert!(rx.try_recv().is_err());
+        assert!(notifications_for(&state, bob).is_empty());
+
+        assert_eq!(mark_seen(&state, "spec", ann, 2).await.unwrap(), Some(2));
+        assert_eq!(notifications_for(&state, ann).len(), 1);
+        mark_seen(&state, "spec", ann, 4).await.unwrap();
+        assert!(notifications_for(&state, ann).is_empty());
+
+        apply_edit(&state, "spec", insert(4, "d", None))
+            .await
+            .unwrap();
+        assert!(set_following(&state, "spec", ann, false).await.unwrap());
+        assert!(notifications_for(&state, ann).is_empty());
+        apply_edit(&state, "spec", insert(5, "e", None))
+            .await
+            .unwrap();
+        assert!(notifications_for(&state, ann).is_empty());
+    }
+}
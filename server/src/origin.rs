@@ -0,0 +1,175 @@
+//! Matching of request `Origin` headers against the configured allowlist.
+//! Entries are full origins (`https://example.com`, `http://localhost:5173`)
+//! compared by scheme, host and port; `https://*.example.com` additionally
+//! admits any subdomain of `example.com`, but not `example.com` itself.
+
+use anyhow::{anyhow, bail};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostRule {
+    Exact(String),
+    /// Matches hosts ending in `.{suffix}`.
+    Subdomains(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginRule {
+    scheme: String,
+    host: HostRule,
+    port: u16,
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        _ => None,
+    }
+}
+
+/// Splits `scheme://host[:port]`, lowercasing scheme and host and filling in
+/// the default port. A single trailing `/` is tolerated, any other path is not.
+fn split_origin(raw: &str) -> anyhow::Result<(String, String, u16)> {
+    let raw = raw.trim();
+    let (scheme, rest) = raw
+        .split_once("://")
+        .ok_or_else(|| anyhow!("origin '{}' has no scheme", raw))?;
+    let scheme = scheme.to_ascii_lowercase();
+    let rest = rest.strip_suffix('/').unwrap_or(rest);
+    if rest.is_empty() || rest.contains(['/', '?', '#', '@']) {
+        bail!("origin '{}' must be scheme://host[:port]", raw);
+    }
+    let (host, port) = if let Some(v6) = rest.strip_prefix('[') {
+        let (addr, after) = v6
+            .split_once(']')
+            .ok_or_else(|| anyhow!("origin '{}' has an unterminated IPv6 host", raw))?;
+        (format!("[{}]", addr), after.strip_prefix(':'))
+    } else {
+        match rest.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), Some(port)),
+            None => (rest.to_string(), None),
+        }
+    };
+    if host.is_empty() || host == "[]" {
+        bail!("origin '{}' has an empty host", raw);
+    }
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| anyhow!("origin '{}' has an invalid port", raw))?,
+        None => default_port(&scheme)
+            .ok_or_else(|| anyhow!("origin '{}' needs an explicit port", raw))?,
+    };
+    Ok((scheme, host.to_ascii_lowercase(), port))
+}
+
+impl Origin {
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        let (scheme, host, port) = split_origin(raw)?;
+        if host.contains('*') {
+            bail!("origin '{}' contains a wildcard", raw);
+        }
+        Ok(Self { scheme, host, port })
+    }
+}
+
+impl OriginRule {
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        let (scheme, host, port) = split_origin(raw)?;
+        let host = match host.strip_prefix("*.") {
+            Some(suffix) if !suffix.is_empty() && !suffix.contains('*') => {
+                HostRule::Subdomains(suffix.to_string())
+            }
+            Some(_) => bail!("origin rule '{}' has an empty wildcard domain", raw),
+            None if host.contains('*') => {
+                bail!("origin rule '{}' may only use a leading '*.'", raw)
+            }
+            None => HostRule::Exact(host),
+        };
+        Ok(Self { scheme, host, port })
+    }
+
+    pub fn matches(&self, origin: &Origin) -> bool {
+        if self.scheme != origin.scheme || self.port != origin.port {
+            return false;
+        }
+        match &self.host {
+            HostRule::Exact(host) => *host == origin.host,
+            HostRule::Subdomains(suffix) => origin
+                .host
+                .strip_suffix(suffix.as_str())
+                .and_then(|label| label.strip_suffix('.'))
+                .is_some_and(|label| !label.is_empty()),
+        }
+    }
+}
+
+/// An empty allowlist admits every origin. Entries that do not parse are
+/// skipped, so a malformed list never widens what is allowed.
+pub fn origin_allowed(allowed: &[String], origin: &str) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+    let Ok(origin) = Origin::parse(origin) else {
+        return false;
+    };
+    allowed
+        .iter()
+        .filter_map(|rule| OriginRule::parse(rule).ok())
+        .any(|rule| rule.matches(&origin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(rules: &[&str], origin: &str) -> bool {
+        let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+        origin_allowed(&rules, origin)
+    }
+
+    #[test]
+    fn exact_rules_match_scheme_host_and_port() {
+        let rules = ["https://example.com"];
+        assert!(allowed(&rules, "https://example.com"));
+        assert!(allowed(&rules, "https://EXAMPLE.com:443"));
+        assert!(!allowed(&rules, "https://example.com.evil.com"));
+        assert!(!allowed(&rules, "https://example.community"));
+        assert!(!allowed(&rules, "http://example.com"));
+        assert!(!allowed(&rules, "https://example.com:8443"));
+        assert!(!allowed(&rules, "https://a.example.com"));
+        assert!(!allowed(&rules, "null"));
+        assert!(allowed(
+            &["http://localhost:5173/"],
+            "http://localhost:5173"
+        ));
+        assert!(allowed(&["http://[::1]:8080"], "http://[::1]:8080"));
+    }
+
+    #[test]
+    fn wildcard_rules_match_subdomains_only() {
+        let rules = ["https://*.example.com"];
+        assert!(allowed(&rules, "https://a.example.com"));
+        assert!(allowed(&rules, "https://a.b.example.com"));
+        assert!(!allowed(&rules, "https://example.com"));
+        assert!(!allowed(&rules, "https://evilexample.com"));
+        assert!(!allowed(&rules, "https://a.example.com.evil.com"));
+    }
+
+    #[test]
+    fn malformed_rules_never_match() {
+        assert!(OriginRule::parse("example.com").is_err());
+        assert!(OriginRule::parse("https://a.*.example.com").is_err());
+        assert!(OriginRule::parse("https://*.").is_err());
+        assert!(OriginRule::parse("https://example.com/path").is_err());
+        assert!(!allowed(&["example.com"], "https://example.com"));
+        assert!(allowed(&[], "https://anything.test"));
+    }
+}
@@ -0,0 +1,268 @@
+//! Deterministic simulation of several clients editing one document through
+//! the server's transform path. Every step of the network (local typing,
+//! sending, server processing, delivery of broadcasts) is explicit, so tests
+//! and fuzzers can drive arbitrary interleavings and check that everybody
+//! ends up with the same content.
+
+use std::collections::VecDeque;
+
+use crate::{
+    document::{Doc, apply_ops, shapes, transform_ops, transform_pair},
+    types::{Edit, OpKind},
+};
+
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// A local edit. `pos`/`len` are folded into the client's current
+    /// content so every generated op is valid where it is made.
+    Type {
+        client: usize,
+        insert: bool,
+        pos: usize,
+        len: usize,
+        text: String,
+    },
+    /// The client sends its buffered ops, unless an edit is still in flight.
+    Send { client: usize },
+    /// The server processes the oldest edit queued by the client.
+    Receive { client: usize },
+    /// The client handles the oldest broadcast addressed to it.
+    Deliver { client: usize },
+}
+
+#[derive(Debug, Clone)]
+struct Broadcast {
+    rev: u64,
+    ops: Vec<OpKind>,
+    origin: usize,
+}
+
+/// A client following the usual one-edit-in-flight OT discipline.
+#[derive(Debug, Default)]
+pub struct SimClient {
+    pub doc: Doc,
+    pending: Option<Vec<OpKind>>,
+    buffer: Vec<OpKind>,
+    outbox: VecDeque<Edit>,
+    inbox: VecDeque<Broadcast>,
+}
+
+#[derive(Debug)]
+pub struct Simulation {
+    pub initial: String,
+    pub server: Doc,
+    /// What the server applied per revision; its own log keeps no text.
+    pub applied: Vec<Vec<OpKind>>,
+    pub clients: Vec<SimClient>,
+}
+
+impl Simulation {
+    pub fn new(clients: usize, initial: &str) -> Self {
+        let doc = || Doc {
+            content: initial.to_string(),
+            ..Default::default()
+        };
+        Self {
+            initial: initial.to_string(),
+            server: doc(),
+            applied: Vec::new(),
+            clients: (0..clients.max(1))
+                .map(|_| SimClient {
+                    doc: doc(),
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    /// Runs `steps`, then lets every queued message settle.
+    pub fn run(clients: usize, initial: &str, steps: &[Step]) -> Self {
+        let mut sim = Self::new(clients, initial);
+        for step in steps {
+            sim.step(step);
+        }
+        sim.settle();
+        sim
+    }
+
+    pub fn step(&mut self, step: &Step) {
+        let n = self.clients.len();
+        match step {
+            Step::Type {
+                client,
+                insert,
+                pos,
+                len,
+                text,
+            } => self.local_edit(client % n, *insert, *pos, *len, text),
+            Step::Send { client } => {
+                self.send(client % n);
+            }
+            Step::Receive { client } => {
+                self.receive(client % n);
+            }
+            Step::Deliver { client } => {
+                self.deliver(client % n);
+            }
+        }
+    }
+
+    /// Drains every buffer and queue until nothing moves any more.
+    pub fn settle(&mut self) {
+        loop {
+            let mut progressed = false;
+            for i in 0..self.clients.len() {
+                progressed |= self.send(i);
+                progressed |= self.receive(i);
+                progressed |= self.deliver(i);
+            }
+            if !progressed {
+                break;
+            }
+        }
+    }
+
+    /// Describes the first divergence, if any: a client whose content or
+    /// revision differs from the server, or a server log that does not
+    /// replay to the server content.
+    pub fn divergence(&self) -> Option<String> {
+        for (i, client) in self.clients.iter().enumerate() {
+            if client.doc.content != self.server.content || client.doc.rev != self.server.rev {
+                return Some(format!(
+                    "client {} has rev {} {:?}, server has rev {} {:?}",
+                    i, client.doc.rev, client.doc.content, self.server.rev, self.server.content
+                ));
+            }
+        }
+        let mut replay = Doc {
+            content: self.initial.clone(),
+            ..Default::default()
+        };
+        for ops in &self.applied {
+            apply_ops(&mut replay, ops);
+        }
+        if replay.content != self.server.content {
+            return Some(format!(
+                "log replays to {:?}, server has {:?}",
+                replay.content, self.server.content
+            ));
+        }
+        None
+    }
+
+    fn local_edit(&mut self, client: usize, insert: bool, pos: usize, len: usize, text: &str) {
+        let c = &mut self.clients[client];
+        let chars = c.doc.content.chars().count();
+        let op = if insert {
+            if text.is_empty() {
+                return;
+            }
+            OpKind::Insert {
+                pos: pos % (chars + 1),
+                text: text.to_string(),
+            }
+        } else {
+            if chars == 0 {
+                return;
+            }
+            let pos = pos % chars;
+            OpKind::Delete {
+                pos,
+                len: 1 + len % (chars - pos),
+            }
+        };
+        apply_ops(&mut c.doc, std::slice::from_ref(&op));
+        c.buffer.push(op);
+    }
+
+    fn send(&mut self, client: usize) -> bool {
+        let c = &mut self.clients[client];
+        if c.pending.is_some() || c.buffer.is_empty() {
+            return false;
+        }
+        let ops = std::mem::take(&mut c.buffer);
+        c.outbox.push_back(Edit {
+            base_rev: c.doc.rev,
+            ops: ops.clone(),
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        });
+        c.pending = Some(ops);
+        true
+    }
+
+    /// Mirrors the transform/apply/broadcast part of `state::apply_edit`.
+    fn receive(&mut self, client: usize) -> bool {
+        let Some(edit) = self.clients[client].outbox.pop_front() else {
+            return false;
+        };
+        let ops = transform_ops(&self.server, &edit);
+        if !ops.is_empty() {
+            apply_ops(&mut self.server, &ops);
+            self.server.rev += 1;
+            self.server.log.push(shapes(&ops));
+            self.applied.push(ops.clone());
+        }
+        for c in &mut self.clients {
+            c.inbox.push_back(Broadcast {
+                rev: self.server.rev,
+                ops: ops.clone(),
+                origin: client,
+            });
+        }
+        true
+    }
+
+    fn deliver(&mut self, client: usize) -> bool {
+        let c = &mut self.clients[client];
+        let Some(msg) = c.inbox.pop_front() else {
+            return false;
+        };
+        if msg.origin == client {
+            c.pending = None;
+        } else {
+            let mut incoming = msg.ops;
+            if let Some(pending) = c.pending.take() {
+                let (pending, rest) = transform_pair(&pending, &incoming, true);
+                c.pending = Some(pending);
+                incoming = rest;
+            }
+            let (buffer, rest) = transform_pair(&c.buffer, &incoming, true);
+            c.buffer = buffer;
+            apply_ops(&mut c.doc, &rest);
+        }
+        c.doc.rev = msg.rev;
+        true
+    }
+}
+
+/// Decodes an arbitrary byte string into a scenario and runs it; entry point
+/// for fuzzers. Returns the divergence, if one was found.
+pub fn run_bytes(data: &[u8]) -> Option<String> {
+    let (&clients, rest) = data.split_first()?;
+    let steps: Vec<Step> = rest
+        .chunks(4)
+        .map(|chunk| {
+            let byte = |i: usize| chunk.get(i).copied().unwrap_or(0) as usize;
+            let client = byte(0) >> 2;
+            match byte(0) & 3 {
+                0 => Step::Type {
+                    client,
+                    insert: byte(1) & 1 == 0,
+                    pos: byte(2),
+                    len: byte(3),
+                    text: ["a", "bc", "é", "😀x"][byte(1) >> 1 & 3].to_string(),
+                },
+                1 => Step::Send { client },
+                2 => Step::Receive { client },
+                _ => Step::Deliver { client },
+            }
+        })
+        .collect();
+    Simulation::run(1 + clients as usize % 4, "seed text", &steps).divergence()
+}
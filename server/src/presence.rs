@@ -1,10 +1,37 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use uuid::Uuid;
 
 use crate::{
+    mentions::record_name,
     state::{AppState, DocPresence},
-    types::{CursorState, ImeEvent, ImeSnapshot, PresenceState},
+    types::{CursorState, ImeEvent, ImeSnapshot, PresenceState, RosterEntry, SectionClaim},
+    workspace::workspace_of,
 };
 
+pub const PRESENCE_PALETTE: &[&str] = &[
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#bfef45",
+    "#469990", "#9a6324", "#800000", "#808000", "#000075", "#fabed4", "#ffd8b1", "#dcbeff",
+];
+
+const NAME_ADJECTIVES: &[&str] = &[
+    "Amber", "Azure", "Brave", "Calm", "Clever", "Coral", "Crimson", "Gentle", "Golden", "Jade",
+    "Lucky", "Misty", "Nimble", "Quiet", "Silver", "Swift",
+];
+
+const NAME_ANIMALS: &[&str] = &[
+    "Badger", "Crane", "Falcon", "Fox", "Hare", "Heron", "Lynx", "Marten", "Otter", "Owl", "Panda",
+    "Raven", "Seal", "Stoat", "Tiger", "Wolf",
+];
+
+const MAX_AVATAR_URL_BYTES: usize = 512;
+const MAX_STATUS_CHARS: usize = 80;
+
+/// How long listing pages may reuse a `GET /api/presence` answer.
+pub const PRESENCE_CACHE_SECS: u64 = 2;
+/// How far the smoothed RTT of a session moves before others are told.
+pub const RTT_REPORT_STEP_MS: u64 = 50;
+
 pub fn with_doc_presence<R, F>(state: &AppState, slug: &str, f: F) -> R
 where
     F: FnOnce(&mut DocPresence) -> R,
@@ -18,23 +45,54 @@ pub fn register_presence(
     state: &AppState,
     slug: &str,
     client_id: Uuid,
+    user_id: Option<Uuid>,
     label: Option<String>,
     color: Option<String>,
     now: u64,
 ) -> (Vec<PresenceState>, PresenceState) {
-    with_doc_presence(state, slug, |doc| {
+    let label = sanitize_label(label);
+    if let (Some(user_id), Some(label)) = (user_id, &label) {
+        record_name(state, user_id, label);
+    }
+    let registered = with_doc_presence(state, slug, |doc| {
+        let color = assign_color(doc, client_id, sanitize_color(color));
         let presence = PresenceState {
             client_id,
-            label: sanitize_label(label),
-            color: sanitize_color(color),
+            label: label.or_else(|| Some(anonymous_name(&client_id))),
+            color: Some(color),
             cursor: None,
             ime: None,
             last_seen: now,
+            user_id,
+            avatar_url: None,
+            status: None,
+            section: None,
+            rtt_ms: None,
         };
         doc.clients.insert(client_id, presence.clone());
         let snapshot = doc.clients.values().cloned().collect();
         (snapshot, presence)
-    })
+    });
+    state
+        .client_docs
+        .write()
+        .entry(client_id)
+        .or_default()
+        .insert(slug.to_string());
+    registered
+}
+
+/// Everyone currently in `slug`, oldest client id first so repeated reads
+/// compare equal.
+pub fn presence_list(state: &AppState, slug: &str) -> Vec<PresenceState> {
+    let mut clients: Vec<PresenceState> = state
+        .presence
+        .read()
+        .get(slug)
+        .map(|doc| doc.clients.values().cloned().collect())
+        .unwrap_or_default();
+    clients.sort_by_key(|p| p.client_id);
+    clients
 }
 
 pub fn touch_presence(state: &AppState, slug: &str, client_id: &Uuid, now: u64) {
@@ -111,38 +169,145 @@ pub fn update_presence_ime(
 }
 
 pub fn remove_presence(state: &AppState, slug: &str, client_id: &Uuid) -> Option<PresenceState> {
-    let mut map = state.presence.write();
-    if let std::collections::hash_map::Entry::Occupied(mut entry) = map.entry(slug.to_string()) {
-        let doc = entry.get_mut();
-        let removed = doc.clients.remove(client_id);
-        if doc.clients.is_empty() {
+    let removed = {
+        let mut map = state.presence.write();
+        if let std::collections::hash_map::Entry::Occupied(mut entry) = map.entry(slug.to_string())
+        {
+            let doc = entry.get_mut();
+            let removed = doc.clients.remove(client_id);
+            if doc.clients.is_empty() {
+                entry.remove();
+            }
+            removed
+        } else {
+            None
+        }
+    };
+    unindex_client(state, client_id, Some(slug));
+    removed
+}
+
+/// Drops `slug` from the documents `client_id` is indexed under, or all of
+/// them when `slug` is `None`.
+pub fn unindex_client(state: &AppState, client_id: &Uuid, slug: Option<&str>) {
+    let mut index = state.client_docs.write();
+    if let std::collections::hash_map::Entry::Occupied(mut entry) = index.entry(*client_id) {
+        match slug {
+            Some(slug) => {
+                entry.get_mut().remove(slug);
+            }
+            None => entry.get_mut().clear(),
+        }
+        if entry.get().is_empty() {
             entry.remove();
         }
-        removed
-    } else {
-        None
     }
 }
 
+/// Every document `client_id` is present in, with its presence there.
+pub fn client_presence(state: &AppState, client_id: &Uuid) -> Vec<(String, PresenceState)> {
+    let slugs = state
+        .client_docs
+        .read()
+        .get(client_id)
+        .cloned()
+        .unwrap_or_default();
+    let presence = state.presence.read();
+    slugs
+        .into_iter()
+        .filter_map(|slug| {
+            let found = presence.get(&slug)?.clients.get(client_id)?.clone();
+            Some((slug, found))
+        })
+        .collect()
+}
+
+/// Who is active where in workspace `ws`, one entry per label, limited to
+/// `label` when given. Documents `visible` turns down are left out.
+pub fn workspace_roster(
+    state: &AppState,
+    ws: &str,
+    label: Option<&str>,
+    visible: impl Fn(&str) -> bool,
+) -> Vec<RosterEntry> {
+    let mut present: Vec<(String, Uuid, String)> = Vec::new();
+    for (slug, doc) in state.presence.read().iter() {
+        if workspace_of(slug) != Some(ws) {
+            continue;
+        }
+        for p in doc.clients.values() {
+            let name = p
+                .label
+                .clone()
+                .unwrap_or_else(|| anonymous_name(&p.client_id));
+            if label.is_none_or(|l| l == name) {
+                present.push((name, p.client_id, slug.clone()));
+            }
+        }
+    }
+    let mut by_label: BTreeMap<String, (BTreeSet<Uuid>, BTreeSet<String>)> = BTreeMap::new();
+    for (name, client_id, slug) in present {
+        if !visible(&slug) {
+            continue;
+        }
+        let (clients, slugs) = by_label.entry(name).or_default();
+        clients.insert(client_id);
+        slugs.insert(slug);
+    }
+    by_label
+        .into_iter()
+        .map(|(label, (clients, slugs))| RosterEntry {
+            label,
+            client_ids: clients.into_iter().collect(),
+            slugs: slugs.into_iter().collect(),
+        })
+        .collect()
+}
+
+/// What a `Profile` message asks to change. `None` leaves a field alone; an
+/// empty avatar URL or status clears it.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileUpdate {
+    pub label: Option<String>,
+    pub color: Option<String>,
+    pub avatar_url: Option<String>,
+    pub status: Option<String>,
+}
+
 pub fn update_presence_profile(
     state: &AppState,
     slug: &str,
     client_id: Uuid,
-    label: Option<String>,
-    color: Option<String>,
+    update: ProfileUpdate,
     now: u64,
 ) -> Option<PresenceState> {
+    let ProfileUpdate {
+        label,
+        color,
+        avatar_url,
+        status,
+    } = update;
     with_doc_presence(state, slug, |doc| {
+        let next_color = color
+            .is_some()
+            .then(|| assign_color(doc, client_id, sanitize_color(color)));
         if let Some(p) = doc.clients.get_mut(&client_id) {
             if let Some(label_norm) = sanitize_label(label.clone()) {
+                if let Some(user_id) = p.user_id {
+                    record_name(state, user_id, &label_norm);
+                }
                 p.label = Some(label_norm);
             } else if label.is_some() {
-                p.label = None;
+                p.label = Some(anonymous_name(&client_id));
             }
-            if let Some(color_norm) = sanitize_color(color.clone()) {
+            if let Some(color_norm) = next_color {
                 p.color = Some(color_norm);
-            } else if color.is_some() {
-                p.color = None;
+            }
+            if let Some(url) = avatar_url {
+                p.avatar_url = sanitize_avatar_url(&url);
+            }
+            if let Some(status) = status {
+                p.status = sanitize_status(&status);
             }
             p.last_seen = now;
             Some(p.clone())
@@ -152,6 +317,80 @@ pub fn update_presence_profile(
     })
 }
 
+/// Shows or clears the section claim of `client_id`. Returns the updated
+/// entry for a `PresenceDiff`.
+pub fn set_presence_section(
+    state: &AppState,
+    slug: &str,
+    client_id: Uuid,
+    section: Option<SectionClaim>,
+) -> Option<PresenceState> {
+    with_doc_presence(state, slug, |doc| {
+        let p = doc.clients.get_mut(&client_id)?;
+        if p.section == section {
+            return None;
+        }
+        p.section = section;
+        Some(p.clone())
+    })
+}
+
+/// Folds an RTT sample into the smoothed value, weighing it a quarter.
+pub fn smooth_rtt(prev: Option<u64>, sample_ms: u64) -> u64 {
+    prev.map_or(sample_ms, |prev| (prev * 3 + sample_ms) / 4)
+}
+
+/// Shows the smoothed RTT of `client_id` once it is first measured or has
+/// moved by [`RTT_REPORT_STEP_MS`] from what is shown. Returns the updated
+/// entry for a `PresenceDiff`.
+pub fn update_presence_rtt(
+    state: &AppState,
+    slug: &str,
+    client_id: Uuid,
+    rtt_ms: u64,
+) -> Option<PresenceState> {
+    with_doc_presence(state, slug, |doc| {
+        let p = doc.clients.get_mut(&client_id)?;
+        if p.rtt_ms
+            .is_some_and(|shown| shown.abs_diff(rtt_ms) < RTT_REPORT_STEP_MS)
+        {
+            return None;
+        }
+        p.rtt_ms = Some(rtt_ms);
+        Some(p.clone())
+    })
+}
+
+/// Picks the effective color for `client_id`: the requested color when no
+/// other client in the document holds it, otherwise the first free palette
+/// entry, falling back to a palette slot derived from the client id once the
+/// palette is exhausted.
+fn assign_color(doc: &DocPresence, client_id: Uuid, requested: Option<String>) -> String {
+    let taken: Vec<String> = doc
+        .clients
+        .values()
+        .filter(|p| p.client_id != client_id)
+        .filter_map(|p| p.color.as_deref().map(str::to_ascii_lowercase))
+        .collect();
+    if let Some(color) = requested
+        && !taken.contains(&color.to_ascii_lowercase())
+    {
+        return color;
+    }
+    PRESENCE_PALETTE
+        .iter()
+        .find(|c| !taken.iter().any(|t| t == *c))
+        .unwrap_or(&PRESENCE_PALETTE[client_id.as_u128() as usize % PRESENCE_PALETTE.len()])
+        .to_string()
+}
+
+pub fn anonymous_name(client_id: &Uuid) -> String {
+    let bits = client_id.as_u128();
+    let adjective = NAME_ADJECTIVES[(bits % NAME_ADJECTIVES.len() as u128) as usize];
+    let animal = NAME_ANIMALS[((bits >> 64) % NAME_ANIMALS.len() as u128) as usize];
+    format!("{} {}", adjective, animal)
+}
+
 fn sanitize_label(label: Option<String>) -> Option<String> {
     label
         .map(|l| l.trim().to_string())
@@ -166,6 +405,30 @@ fn sanitize_color(color: Option<String>) -> Option<String> {
         .map(|c| c.chars().take(32).collect())
 }
 
+/// Keeps only plain `http(s)` URLs short enough to pass around in every
+/// diff; anything else, including `javascript:` and `data:`, is dropped.
+fn sanitize_avatar_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    let scheme_ok = ["https://", "http://"].iter().any(|scheme| {
+        url.get(..scheme.len())
+            .is_some_and(|s| s.eq_ignore_ascii_case(scheme))
+    });
+    (scheme_ok
+        && url.len() <= MAX_AVATAR_URL_BYTES
+        && !url.chars().any(|c| c.is_whitespace() || c.is_control()))
+    .then(|| url.to_string())
+}
+
+fn sanitize_status(status: &str) -> Option<String> {
+    let status: String = status
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_STATUS_CHARS)
+        .collect();
+    (!status.is_empty()).then_some(status)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -190,17 +453,110 @@ mod tests {
         let long_label = "   ".to_string() + &"a".repeat(80);
         let long_color = " #123456 ".repeat(5);
         let client = uuid::Uuid::new_v4();
+        let user = uuid::Uuid::new_v4();
 
-        let (_snapshot, presence) =
-            register_presence(&state, slug, client, Some(long_label), Some(long_color), 10);
+        let (_snapshot, presence) = register_presence(
+            &state,
+            slug,
+            client,
+            Some(user),
+            Some(long_label),
+            Some(long_color),
+            10,
+        );
 
         assert_eq!(presence.client_id, client);
+        assert_eq!(presence.user_id, Some(user));
         assert_eq!(presence.label.as_ref().unwrap().len(), 64);
         assert!(presence.label.as_ref().unwrap().starts_with('a'));
         assert_eq!(presence.color.as_ref().unwrap().len(), 32);
         assert_eq!(presence.last_seen, 10);
     }
 
+    #[test]
+    fn presence_list_reads_without_creating_entries() {
+        let base = std::env::temp_dir().join(format!("presence-list-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        assert!(presence_list(&state, "empty").is_empty());
+        assert!(!state.presence.read().contains_key("empty"));
+
+        let a = uuid::Uuid::from_u128(2);
+        let b = uuid::Uuid::from_u128(1);
+        register_presence(&state, "busy", a, None, None, None, 1);
+        register_presence(&state, "busy", b, None, None, None, 2);
+        let ids: Vec<_> = presence_list(&state, "busy")
+            .into_iter()
+            .map(|p| p.client_id)
+            .collect();
+        assert_eq!(ids, vec![b, a]);
+    }
+
+    #[test]
+    fn client_index_follows_joins_and_leaves() {
+        let base = std::env::temp_dir().join(format!("presence-index-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let client = uuid::Uuid::new_v4();
+        register_presence(&state, "team/a", client, None, Some("Ann".into()), None, 1);
+        register_presence(&state, "team/b", client, None, Some("Ann".into()), None, 2);
+        let slugs: Vec<_> = client_presence(&state, &client)
+            .into_iter()
+            .map(|(slug, _)| slug)
+            .collect();
+        assert_eq!(slugs, vec!["team/a", "team/b"]);
+
+        remove_presence(&state, "team/a", &client);
+        assert_eq!(client_presence(&state, &client).len(), 1);
+        remove_presence(&state, "team/b", &client);
+        assert!(!state.client_docs.read().contains_key(&client));
+    }
+
+    #[test]
+    fn workspace_roster_groups_tabs_by_label() {
+        let base = std::env::temp_dir().join(format!("presence-roster-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let (tab1, tab2, other) = (
+            uuid::Uuid::from_u128(1),
+            uuid::Uuid::from_u128(2),
+            uuid::Uuid::from_u128(3),
+        );
+        register_presence(&state, "team/a", tab1, None, Some("Ann".into()), None, 1);
+        register_presence(&state, "team/b", tab2, None, Some("Ann".into()), None, 1);
+        register_presence(
+            &state,
+            "team/secret",
+            other,
+            None,
+            Some("Bo".into()),
+            None,
+            1,
+        );
+        register_presence(
+            &state,
+            "elsewhere/c",
+            other,
+            None,
+            Some("Bo".into()),
+            None,
+            1,
+        );
+
+        let roster = workspace_roster(&state, "team", None, |slug| slug != "team/secret");
+        assert_eq!(
+            roster,
+            vec![RosterEntry {
+                label: "Ann".into(),
+                client_ids: vec![tab1, tab2],
+                slugs: vec!["team/a".into(), "team/b".into()],
+            }]
+        );
+        let only_bo = workspace_roster(&state, "team", Some("Bo"), |_| true);
+        assert_eq!(only_bo.len(), 1);
+        assert_eq!(only_bo[0].slugs, vec!["team/secret"]);
+    }
+
     #[test]
     fn update_presence_cursor_returns_updated_state() {
         let base = std::env::temp_dir().join(format!("presence-cursor-{}", uuid::Uuid::new_v4()));
@@ -208,7 +564,7 @@ mod tests {
         let state = mk_state(&base);
         let slug = "cursor";
         let client = uuid::Uuid::new_v4();
-        register_presence(&state, slug, client, None, None, 5);
+        register_presence(&state, slug, client, None, None, None, 5);
 
         let cursor = CursorState {
             position: 3,
@@ -229,7 +585,7 @@ mod tests {
         let state = mk_state(&base);
         let slug = "remove";
         let client = uuid::Uuid::new_v4();
-        register_presence(&state, slug, client, None, None, 1);
+        register_presence(&state, slug, client, None, None, None, 1);
 
         let removed = remove_presence(&state, slug, &client).expect("presence removed");
         assert_eq!(removed.client_id, client);
@@ -251,6 +607,7 @@ mod tests {
             &state,
             slug,
             client,
+            None,
             Some("label".into()),
             Some("#abc".into()),
             0,
@@ -260,14 +617,123 @@ mod tests {
             &state,
             slug,
             client,
-            Some("   ".into()),
-            Some("".into()),
+            ProfileUpdate {
+                label: Some("   ".into()),
+                color: Some("".into()),
+                avatar_url: Some("javascript:alert(1)".into()),
+                status: Some(" \u{7}".into()),
+            },
             30,
         )
         .expect("presence updated");
 
-        assert_eq!(updated.label, None);
-        assert_eq!(updated.color, None);
+        assert_eq!(updated.label, Some(anonymous_name(&client)));
+        assert_eq!(updated.color.as_deref(), Some(PRESENCE_PALETTE[0]));
+        assert_eq!(updated.avatar_url, None);
+        assert_eq!(updated.status, None);
         assert_eq!(updated.last_seen, 30);
     }
+
+    #[test]
+    fn update_presence_profile_keeps_extended_fields_until_cleared() {
+        let base = std::env::temp_dir().join(format!("presence-avatar-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let client = uuid::Uuid::new_v4();
+        register_presence(&state, "faces", client, None, None, None, 0);
+        let update = |update: ProfileUpdate| {
+            update_presence_profile(&state, "faces", client, update, 1).expect("presence updated")
+        };
+
+        let set = update(ProfileUpdate {
+            avatar_url: Some(" https://example.com/a.png ".into()),
+            status: Some("x".repeat(100)),
+            ..Default::default()
+        });
+        assert_eq!(set.avatar_url.as_deref(), Some("https://example.com/a.png"));
+        assert_eq!(set.status.as_ref().map(|s| s.chars().count()), Some(80));
+
+        let relabeled = update(ProfileUpdate {
+            label: Some("Ann".into()),
+            ..Default::default()
+        });
+        assert_eq!(relabeled.avatar_url, set.avatar_url);
+
+        let cleared = update(ProfileUpdate {
+            avatar_url: Some(String::new()),
+            status: Some(String::new()),
+            ..Default::default()
+        });
+        assert_eq!((cleared.avatar_url, cleared.status), (None, None));
+    }
+
+    #[test]
+    fn register_presence_names_unlabeled_clients_deterministically() {
+        let base = std::env::temp_dir().join(format!("presence-names-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let client = uuid::Uuid::new_v4();
+
+        let (_, first) = register_presence(&state, "names", client, None, None, None, 0);
+        remove_presence(&state, "names", &client);
+        let (_, again) =
+            register_presence(&state, "names", client, None, Some(" ".into()), None, 1);
+
+        let name = first.label.expect("generated label");
+        assert_eq!(again.label.as_deref(), Some(name.as_str()));
+        assert_eq!(name.split(' ').count(), 2);
+    }
+
+    #[test]
+    fn register_presence_assigns_distinct_colors() {
+        let base = std::env::temp_dir().join(format!("presence-colors-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "colors";
+
+        let (_, first) = register_presence(
+            &state,
+            slug,
+            uuid::Uuid::new_v4(),
+            None,
+            None,
+            Some("#ABCDEF".into()),
+            0,
+        );
+        let (_, second) = register_presence(
+            &state,
+            slug,
+            uuid::Uuid::new_v4(),
+            None,
+            None,
+            Some("#abcdef".into()),
+            0,
+        );
+        let (snapshot, third) =
+            register_presence(&state, slug, uuid::Uuid::new_v4(), None, None, None, 0);
+
+        assert_eq!(first.color.as_deref(), Some("#ABCDEF"));
+        assert_eq!(second.color.as_deref(), Some(PRESENCE_PALETTE[0]));
+        assert_eq!(third.color.as_deref(), Some(PRESENCE_PALETTE[1]));
+        assert_eq!(snapshot.len(), 3);
+    }
+
+    #[test]
+    fn rtt_is_smoothed_and_shown_when_it_moves() {
+        let base = std::env::temp_dir().join(format!("presence-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let client = uuid::Uuid::new_v4();
+        register_presence(&state, "rtt", client, None, None, None, 0);
+
+        assert_eq!(smooth_rtt(None, 80), 80);
+        assert_eq!(smooth_rtt(Some(80), 400), 160);
+        let shown = update_presence_rtt(&state, "rtt", client, 80).unwrap();
+        assert_eq!(shown.rtt_ms, Some(80));
+        assert!(update_presence_rtt(&state, "rtt", client, 120).is_none());
+        assert_eq!(presence_list(&state, "rtt")[0].rtt_ms, Some(80));
+        let shown = update_presence_rtt(&state, "rtt", client, 160).unwrap();
+        assert_eq!(shown.rtt_ms, Some(160));
+        assert!(update_presence_rtt(&state, "rtt", uuid::Uuid::new_v4(), 10).is_none());
+    }
 }
@@ -0,0 +1,122 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::state::Rejection;
+
+/// Bumped whenever `ClientMsg`/`ServerMsg` change in a way older clients
+/// cannot ignore.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features a session may use once both sides advertise them.
+pub const SERVER_CAPABILITIES: &[&str] = &[
+    "compression",
+    "conditional_join",
+    "errors",
+    "lazy_snapshot",
+    "line_ops",
+    "owner_grant",
+    "reauth",
+    "resync",
+    "roster",
+    "set_password",
+    "snapshot_chunks",
+    "state_hash",
+    "subscribe",
+    "viewport",
+    "warnings",
+    "watch",
+];
+
+/// Positions in ops and cursors count Unicode scalar values.
+pub const COORDINATE_SYSTEM: &str = "unicode_scalar";
+
+/// WebSocket close code: the session's credential does not open the
+/// document any more. Reconnecting needs a new password.
+pub const CLOSE_UNAUTHORIZED: u16 = 4001;
+/// WebSocket close code: the client sent too much; reconnect after a
+/// backoff.
+pub const CLOSE_RATE_LIMITED: u16 = 4002;
+/// WebSocket close code: the document was deleted or archived.
+pub const CLOSE_DOC_GONE: u16 = 4003;
+/// WebSocket close code: the server is shutting down; reconnect, possibly
+/// to another node.
+pub const CLOSE_DRAINING: u16 = 4004;
+/// WebSocket close code: the document lives on another node now; reconnect
+/// right away and get forwarded there.
+pub const CLOSE_MOVED: u16 = 4005;
+/// WebSocket close code: the client broke the protocol, e.g. with a
+/// version the server no longer speaks.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 4006;
+
+/// Whether a client closed with `code` should reconnect on its own. The
+/// other application codes only repeat until the user does something.
+pub fn close_is_retryable(code: u16) -> bool {
+    matches!(code, CLOSE_RATE_LIMITED | CLOSE_DRAINING | CLOSE_MOVED)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+    pub coordinates: String,
+}
+
+/// Settles on the highest version both sides speak and the capabilities both
+/// advertise. Clients that send no version get `None` and the legacy
+/// message shapes.
+pub fn negotiate(
+    client_version: Option<u32>,
+    client_capabilities: &[String],
+) -> Result<Option<ProtocolInfo>, Rejection> {
+    let Some(client_version) = client_version else {
+        return Ok(None);
+    };
+    if client_version < MIN_PROTOCOL_VERSION {
+        return Err(Rejection::new(
+            "unsupported_version",
+            format!(
+                "protocol version {} is too old; server requires at least {}",
+                client_version, MIN_PROTOCOL_VERSION
+            ),
+        ));
+    }
+    let capabilities = SERVER_CAPABILITIES
+        .iter()
+        .filter(|cap| client_capabilities.iter().any(|c| c == *cap))
+        .map(|cap| cap.to_string())
+        .collect();
+    Ok(Some(ProtocolInfo {
+        version: client_version.min(PROTOCOL_VERSION),
+        capabilities,
+        coordinates: COORDINATE_SYSTEM.to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_transient_closures_are_retryable() {
+        assert!(close_is_retryable(CLOSE_DRAINING));
+        assert!(close_is_retryable(CLOSE_MOVED));
+        assert!(!close_is_retryable(CLOSE_UNAUTHORIZED));
+        assert!(!close_is_retryable(CLOSE_DOC_GONE));
+    }
+
+    #[test]
+    fn negotiate_intersects_capabilities() {
+        assert_eq!(negotiate(None, &["watch".into()]).unwrap(), None);
+
+        let info = negotiate(Some(7), &["watch".into(), "binary_frames".into()])
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.version, PROTOCOL_VERSION);
+        assert_eq!(info.capabilities, vec!["watch".to_string()]);
+        assert_eq!(info.coordinates, COORDINATE_SYSTEM);
+
+        let err = negotiate(Some(0), &[]).unwrap_err();
+        assert_eq!(err.code, "unsupported_version");
+    }
+}
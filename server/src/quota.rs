@@ -0,0 +1,133 @@
+use std::{fs, path::Path};
+
+use crate::{
+    state::{AppState, Rejection},
+    workspace::{load_workspace, workspace_of},
+};
+
+fn dir_size(dir: &Path) -> anyhow::Result<u64> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut total = 0u64;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else {
+            total += meta.len();
+        }
+    }
+    Ok(total)
+}
+
+/// [`workspace_usage`] of this covers every document.
+const ALL_DOCS: &str = "";
+
+/// Cumulative snapshot + WAL bytes stored under a workspace. Computed from
+/// disk on first use and kept up to date by [`record_bytes`] afterwards.
+pub fn workspace_usage(state: &AppState, ws: &str) -> anyhow::Result<u64> {
+    if let Some(used) = state.usage.read().get(ws).copied() {
+        return Ok(used);
+    }
+    let used = dir_size(&state.snap_dir.join(ws))? + dir_size(&state.wal_dir.join(ws))?;
+    Ok(*state.usage.write().entry(ws.to_string()).or_insert(used))
+}
+
+pub fn record_bytes(state: &AppState, slug: &str, delta: i64) {
+    let mut usage = state.usage.write();
+    for key in [Some(ALL_DOCS), workspace_of(slug)].into_iter().flatten() {
+        if let Some(used) = usage.get_mut(key) {
+            *used = used.saturating_add_signed(delta);
+        }
+    }
+}
+
+/// Rejects writes that would push the workspace of `slug`, or all documents
+/// together, past their quota.
+pub fn check_quota(state: &AppState, slug: &str, additional: u64) -> Result<(), Rejection> {
+    if let Some(quota) = state.quota_bytes {
+        let used = workspace_usage(state, ALL_DOCS).unwrap_or(0);
+        if used.saturating_add(additional) > quota {
+            return Err(Rejection::new(
+                "quota_exceeded",
+                format!("documents use {} of {} bytes", used, quota),
+            ));
+        }
+    }
+    let Some(ws) = workspace_of(slug) else {
+        return Ok(());
+    };
+    let Some(quota) = load_workspace(state, ws)
+        .ok()
+        .and_then(|settings| settings.quota_bytes)
+    else {
+        return Ok(());
+    };
+    let used = workspace_usage(state, ws).unwrap_or(0);
+    if used.saturating_add(additional) > quota {
+        return Err(Rejection::new(
+            "quota_exceeded",
+            format!("workspace '{}' uses {} of {} bytes", ws, used, quota),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::{WorkspaceSettings, save_workspace};
+    use uuid::Uuid;
+
+    fn mk_state(tmp: &Path) -> AppState {
+        let wal_dir = tmp.join("wal");
+        let snap_dir = tmp.join("snapshots");
+        fs::create_dir_all(&wal_dir).unwrap();
+        fs::create_dir_all(&snap_dir).unwrap();
+        AppState::new(wal_dir, snap_dir, 1_000, 128, true, Vec::new())
+    }
+
+    #[test]
+    fn check_quota_tracks_recorded_bytes() {
+        let base = std::env::temp_dir().join(format!("quota-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        save_workspace(
+            &state,
+            "team",
+            &WorkspaceSettings {
+                quota_bytes: Some(100),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let settings_bytes = workspace_usage(&state, "team").unwrap();
+        assert!(settings_bytes > 0);
+
+        assert!(check_quota(&state, "team/doc", 100 - settings_bytes).is_ok());
+        record_bytes(&state, "team/doc", 100 - settings_bytes as i64);
+        let err = check_quota(&state, "team/doc", 1).unwrap_err();
+        assert_eq!(err.code, "quota_exceeded");
+
+        record_bytes(&state, "team/doc", -10);
+        assert!(check_quota(&state, "team/doc", 5).is_ok());
+        assert!(check_quota(&state, "loose", u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn check_quota_covers_all_documents() {
+        let base = std::env::temp_dir().join(format!("quota-all-{}", Uuid::new_v4()));
+        fs::create_dir_all(base.join("snapshots/team")).unwrap();
+        fs::write(base.join("snapshots/loose.md"), "0123456789").unwrap();
+        let mut state = mk_state(&base);
+        state.quota_bytes = Some(50);
+
+        assert!(check_quota(&state, "team/doc", 40).is_ok());
+        record_bytes(&state, "team/doc", 30);
+        let err = check_quota(&state, "loose", 11).unwrap_err();
+        assert_eq!(err.code, "quota_exceeded");
+        assert!(check_quota(&state, "other", 10).is_ok());
+    }
+}
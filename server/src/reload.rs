@@ -0,0 +1,245 @@
+//! Settings that are re-read on SIGHUP or `POST /api/admin/reload` without
+//! dropping live sessions: allowed origins, flush tuning and the log filter.
+//! Values come from `CONFIG_FILE` when it sets them, else the environment.
+
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use anyhow::Context;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    origin::OriginRule,
+    state::{AppState, LiveConfig},
+};
+
+pub const DEFAULT_FLUSH_IDLE_MS: u64 = 1500;
+pub const DEFAULT_FLUSH_MAX_OPS: usize = 200;
+
+/// Swaps the active log filter for the given directives.
+pub type LogFilterReloader = Arc<dyn Fn(&str) -> anyhow::Result<()> + Send + Sync>;
+
+/// Configuration lookup that prefers the config file over the environment.
+#[derive(Debug, Default)]
+pub struct ConfigVars {
+    file: HashMap<String, String>,
+}
+
+impl ConfigVars {
+    /// Reads `KEY=VALUE` lines from `path`. Blank lines and `#` comments are
+    /// skipped and values may be quoted.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let mut file = HashMap::new();
+        for (idx, line) in raw.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("{} line {} has no '='", path.display(), idx + 1))?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            file.insert(key.trim().to_string(), value.to_string());
+        }
+        Ok(Self { file })
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.file
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+    }
+
+    fn parse<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name).and_then(|v| v.trim().parse().ok())
+    }
+}
+
+/// Origins come from `APP_ALLOWED_ORIGINS`, falling back to
+/// `https://$APP_DOMAIN`.
+pub fn live_config(vars: &ConfigVars) -> LiveConfig {
+    let allowed_origins = vars
+        .get("APP_ALLOWED_ORIGINS")
+        .map(|raw| {
+            raw.split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+        })
+        .filter(|list| !list.is_empty())
+        .or_else(|| {
+            vars.get("APP_DOMAIN")
+                .map(|domain| vec![format!("https://{}", domain)])
+        })
+        .unwrap_or_default();
+    for rule in &allowed_origins {
+        if let Err(err) = OriginRule::parse(rule) {
+            warn!("ignoring allowed origin: {:#}", err);
+        }
+    }
+    LiveConfig {
+        allowed_origins,
+        flush_idle_ms: vars.parse("FLUSH_IDLE_MS").unwrap_or(DEFAULT_FLUSH_IDLE_MS),
+        flush_max_ops: vars
+            .parse("FLUSH_MAX_OPS")
+            .filter(|ops| *ops > 0)
+            .unwrap_or(DEFAULT_FLUSH_MAX_OPS),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ReloadReport {
+    /// Settings whose value changed.
+    pub changed: Vec<&'static str>,
+    /// The log filter now in effect, when one was applied.
+    pub log_filter: Option<String>,
+}
+
+/// Re-reads the live settings and the log filter, for `state` and the
+/// tenants it serves. Nothing is changed when the config file cannot be read
+/// or the filter does not parse.
+pub fn reload_config(state: &AppState) -> anyhow::Result<ReloadReport> {
+    let vars = ConfigVars::load(state.config_file.as_deref())?;
+    let filter = vars.get("RUST_LOG").filter(|f| !f.trim().is_empty());
+    let log_filter = match (&state.log_filter, filter) {
+        (Some(apply), Some(filter)) => {
+            apply(&filter)?;
+            Some(filter)
+        }
+        _ => None,
+    };
+    let changed = reload_live(state, &vars);
+    for tenant in state.tenants.iter() {
+        reload_live(tenant, &vars);
+    }
+    Ok(ReloadReport {
+        changed,
+        log_filter,
+    })
+}
+
+/// Swaps in the live settings `vars` give `state`. Returns those that
+/// changed.
+fn reload_live(state: &AppState, vars: &ConfigVars) -> Vec<&'static str> {
+    let mut next = live_config(vars);
+    if let Some(origins) = &state.own_origins {
+        next.allowed_origins = origins.clone();
+    }
+    let mut live = state.live.write();
+    let mut changed = Vec::new();
+    if live.allowed_origins != next.allowed_origins {
+        changed.push("allowed_origins");
+    }
+    if live.flush_idle_ms != next.flush_idle_ms {
+        changed.push("flush_idle_ms");
+    }
+    if live.flush_max_ops != next.flush_max_ops {
+        changed.push("flush_max_ops");
+    }
+    *live = next;
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenants::{Tenant, tenant_state};
+    use parking_lot::Mutex;
+    use uuid::Uuid;
+
+    #[test]
+    fn reload_applies_the_config_file() {
+        let base = std::env::temp_dir().join(format!("reload-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            1_000,
+            100,
+            false,
+            vec!["https://old.example".into()],
+        );
+        let config = base.join("coedit.env");
+        fs::write(
+            &config,
+            "# live settings\nAPP_ALLOWED_ORIGINS=\"https://a.example, https://b.example\"\n\
+             FLUSH_IDLE_MS=250\nFLUSH_MAX_OPS=100\nRUST_LOG=coedit=debug\n",
+        )
+        .unwrap();
+        state.config_file = Some(config.clone());
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let seen = applied.clone();
+        state.log_filter = Some(Arc::new(move |filter: &str| {
+            seen.lock().push(filter.to_string());
+            Ok(())
+        }));
+
+        let report = reload_config(&state).unwrap();
+        assert_eq!(report.changed, vec!["allowed_origins", "flush_idle_ms"]);
+        assert_eq!(report.log_filter.as_deref(), Some("coedit=debug"));
+        assert_eq!(*applied.lock(), vec!["coedit=debug"]);
+        let live = state.live.read().clone();
+        assert_eq!(
+            live.allowed_origins,
+            vec!["https://a.example", "https://b.example"]
+        );
+        assert_eq!(live.flush_idle_ms, 250);
+
+        fs::write(&config, "FLUSH_IDLE_MS\n").unwrap();
+        assert!(reload_config(&state).is_err());
+        assert_eq!(state.live.read().flush_idle_ms, 250);
+    }
+
+    #[test]
+    fn tenants_reload_with_the_instance_and_keep_their_origins() {
+        let base = std::env::temp_dir().join(format!("reload-tenants-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            1_000,
+            100,
+            false,
+            vec!["https://old.example".into()],
+        );
+        let config = base.join("coedit.env");
+        fs::write(
+            &config,
+            "APP_ALLOWED_ORIGINS=https://a.example\nFLUSH_IDLE_MS=250\n",
+        )
+        .unwrap();
+        state.config_file = Some(config);
+        let tenant = |id: &str, origins: &[&str]| Tenant {
+            id: id.into(),
+            api_keys: vec![format!("key-{}", id)],
+            quota_bytes: None,
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+        };
+        let own = tenant_state(&state, &base, &tenant("own", &["https://own.example"])).unwrap();
+        let plain = tenant_state(&state, &base, &tenant("plain", &[])).unwrap();
+        state.tenants = Arc::new(vec![own.clone(), plain.clone()]);
+
+        reload_config(&state).unwrap();
+        let live = own.live.read().clone();
+        assert_eq!(live.allowed_origins, vec!["https://own.example"]);
+        assert_eq!(live.flush_idle_ms, 250);
+        assert_eq!(plain.live.read().allowed_origins, vec!["https://a.example"]);
+
+        // Reloading through the tenant's own key reads the same settings.
+        own.live.write().flush_idle_ms = 1;
+        let report = reload_config(&own).unwrap();
+        assert_eq!(report.changed, vec!["flush_idle_ms"]);
+        assert_eq!(own.live.read().allowed_origins, vec!["https://own.example"]);
+    }
+}
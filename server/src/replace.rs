@@ -0,0 +1,261 @@
+//! Server-side find and replace. Every match is rewritten in one edit that
+//! shares a `group_id`, so clients undo it as a unit and a large replace
+//! does not turn into hundreds of separate client ops.
+
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    state::{AppState, Rejection, apply_edit, get_or_load_doc},
+    types::{Edit, OpKind},
+};
+
+/// Upper bound on the compiled size of a user-supplied pattern.
+const MAX_PATTERN_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplaceSpec {
+    pub find: String,
+    pub replace: String,
+    /// `find` is a regular expression and `replace` may use `$1`, `$name`.
+    pub regex: bool,
+    pub case_insensitive: bool,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ReplaceReport {
+    pub matches: usize,
+    pub rev: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<Uuid>,
+}
+
+impl ReplaceSpec {
+    pub(crate) fn compile(&self) -> Result<Regex, Rejection> {
+        if self.find.is_empty() {
+            return Err(Rejection::new("invalid_pattern", "pattern is empty"));
+        }
+        let pattern = if self.regex {
+            self.find.clone()
+        } else {
+            regex::escape(&self.find)
+        };
+        RegexBuilder::new(&pattern)
+            .case_insensitive(self.case_insensitive)
+            .size_limit(MAX_PATTERN_BYTES)
+            .build()
+            .map_err(|err| Rejection::new("invalid_pattern", err.to_string()))
+    }
+}
+
+/// Ops that apply `spec` to `content`, in order, with the number of matches.
+/// Matches whose replacement is identical produce no ops but still count.
+pub fn replace_ops(content: &str, spec: &ReplaceSpec) -> Result<(Vec<OpKind>, usize), Rejection> {
+    let re = spec.compile()?;
+    let mut ops = Vec::new();
+    let mut matches = 0;
+    // Char offset in the original content of the last byte offset seen, and
+    // how far earlier ops moved everything after it.
+    let (mut byte_at, mut char_at) = (0, 0);
+    let mut shift: isize = 0;
+    for caps in re.captures_iter(content) {
+        let m = caps.get(0).expect("group 0 always matches");
+        matches += 1;
+        let replacement = if spec.regex {
+            let mut out = String::new();
+            caps.expand(&spec.replace, &mut out);
+            out
+        } else {
+            spec.replace.clone()
+        };
+        if replacement == m.as_str() {
+            continue;
+        }
+        char_at += content[byte_at..m.start()].chars().count();
+        byte_at = m.start();
+        let pos = (char_at as isize + shift) as usize;
+        let len = m.as_str().chars().count();
+        if len > 0 {
+            ops.push(OpKind::Delete { pos, len });
+        }
+        if !replacement.is_empty() {
+            ops.push(OpKind::Insert {
+                pos,
+                text: replacement.clone(),
+            });
+        }
+        shift += replacement.chars().count() as isize - len as isize;
+    }
+    Ok((ops, matches))
+}
+
+/// Runs `spec` over the current content of `slug` and applies the result as
+/// one edit. Edits that land in between are transformed over it as usual.
+pub async fn replace_in_doc(
+    state: &AppState,
+    slug: &str,
+    spec: &ReplaceSpec,
+    client_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+    op_id: Option<Uuid>,
+) -> anyhow::Result<ReplaceReport> {
+    let doc = get_or_load_doc(state, slug).await?;
+    let (base_rev, content) = {
+        let d = doc.read();
+        (d.rev, d.content.clone())
+    };
+    let (ops, matches) = replace_ops(&content, spec)?;
+    if ops.is_empty() {
+        return Ok(ReplaceReport {
+            matches,
+            rev: base_rev,
+            group_id: None,
+        });
+    }
+    let group_id = Uuid::new_v4();
+    let edit = Edit {
+        base_rev,
+        ops,
+        client_id,
+        op_id: Some(op_id.unwrap_or_else(Uuid::new_v4)),
+        cursor_before: None,
+        cursor_after: None,
+        ts: None,
+        group_id: Some(group_id),
+        user_id,
+    };
+    apply_edit(state, slug, edit).await?;
+    let rev = doc.read().rev;
+    Ok(ReplaceReport {
+        matches,
+        rev,
+        group_id: Some(group_id),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Doc, apply_ops};
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("srvtest-replace-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            1_000,
+            100,
+            true,
+            vec![],
+        )
+    }
+
+    fn run(content: &str, spec: &ReplaceSpec) -> (String, usize) {
+        let (ops, matches) = replace_ops(content, spec).unwrap();
+        let mut doc = Doc {
+            content: content.to_string(),
+            ..Default::default()
+        };
+        apply_ops(&mut doc, &ops);
+        (doc.content, matches)
+    }
+
+    #[test]
+    fn literal_and_regex_replacements_match_the_regex_crate() {
+        let literal = ReplaceSpec {
+            find: "a.b".into(),
+            replace: "ß".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            run("ä a.b axb A.B a.b", &literal),
+            ("ä ß axb A.B ß".into(), 2)
+        );
+
+        let folded = ReplaceSpec {
+            case_insensitive: true,
+            ..literal
+        };
+        assert_eq!(run("a.b A.B", &folded).1, 2);
+
+        let regex = ReplaceSpec {
+            find: r"(\w+)@(\w+)".into(),
+            replace: "$2 at $1".into(),
+            regex: true,
+            ..Default::default()
+        };
+        let content = "mail 日本@example or bob@host";
+        let expected = Regex::new(&regex.find)
+            .unwrap()
+            .replace_all(content, regex.replace.as_str());
+        assert_eq!(run(content, &regex), (expected.into_owned(), 2));
+
+        let empty = ReplaceSpec::default();
+        assert_eq!(
+            replace_ops("x", &empty).unwrap_err().code,
+            "invalid_pattern"
+        );
+        let broken = ReplaceSpec {
+            find: "(".into(),
+            regex: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            replace_ops("x", &broken).unwrap_err().code,
+            "invalid_pattern"
+        );
+    }
+
+    #[tokio::test]
+    async fn replace_applies_one_grouped_edit() {
+        let state = mk_state();
+        apply_edit(
+            &state,
+            "doc",
+            Edit {
+                base_rev: 0,
+                ops: vec![OpKind::Insert {
+                    pos: 0,
+                    text: "foo bar foo".into(),
+                }],
+                client_id: None,
+                op_id: None,
+                cursor_before: None,
+                cursor_after: None,
+                ts: None,
+                group_id: None,
+                user_id: None,
+            },
+        )
+        .await
+        .unwrap();
+        let spec = ReplaceSpec {
+            find: "foo".into(),
+            replace: "baz".into(),
+            ..Default::default()
+        };
+        let report = replace_in_doc(&state, "doc", &spec, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(report.matches, 2);
+        assert_eq!(report.rev, 2);
+        assert!(report.group_id.is_some());
+        let doc = get_or_load_doc(&state, "doc").await.unwrap();
+        assert_eq!(doc.read().content, "baz bar baz");
+
+        let none = replace_in_doc(&state, "doc", &spec, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            none,
+            ReplaceReport {
+                matches: 0,
+                rev: 2,
+                group_id: None,
+            }
+        );
+    }
+}
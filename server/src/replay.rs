@@ -0,0 +1,296 @@
+//! Playback of a document's history: the WAL is read as a stream and its
+//! edits are sent in their original order, paced by their timestamps, so a
+//! client can show how the document was written.
+
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{
+    document::Doc,
+    history::HistoryEdit,
+    state::AppState,
+    storage::{load_meta, read_snapshot, wal_lines},
+    wal_replay::{Replayer, decode_line, edit_of},
+};
+
+/// Longest pause between two edits before speed is applied, so a document
+/// edited over days still plays back in minutes.
+pub const DEFAULT_MAX_GAP_MS: u64 = 2_000;
+
+/// Where playback begins. `rev` is 0 unless retention purged the start of the
+/// history, in which case playback starts from the snapshot.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ReplayStart {
+    pub slug: String,
+    pub rev: u64,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayItem {
+    Start(ReplayStart),
+    Edit(HistoryEdit),
+}
+
+/// Content and revision playback starts from once the WAL head is known.
+/// Edits up to the returned revision only rebuild the log.
+fn replay_start(
+    state: &AppState,
+    slug: &str,
+    snapshot_rev: u64,
+    replayer: &mut Replayer,
+) -> anyhow::Result<ReplayStart> {
+    if replayer.purged > 0 {
+        replayer.doc.content = read_snapshot(state, slug)?.unwrap_or_default();
+        replayer.apply_from = snapshot_rev.max(replayer.purged);
+    }
+    Ok(ReplayStart {
+        slug: slug.to_string(),
+        rev: replayer.apply_from,
+        content: replayer.doc.content.clone(),
+    })
+}
+
+/// Reads the WAL of `slug` and hands each item to `emit`, stopping early
+/// when `emit` returns false. The start always comes before any edit.
+pub fn replay_history(
+    state: &AppState,
+    slug: &str,
+    mut emit: impl FnMut(ReplayItem) -> bool,
+) -> anyhow::Result<()> {
+    let snapshot_rev = load_meta(state, slug)?.unwrap_or_default().snapshot_rev;
+    let mut replayer = Replayer::new(Doc::default(), 0);
+    let mut started = false;
+    for (idx, line) in wal_lines(state, slug)?.enumerate() {
+        let line = line?;
+        let entry = match decode_line(idx, &line) {
+            None => continue,
+            Some(Ok(entry)) => entry,
+            Some(Err((_, err))) => {
+                // The last line may still be in the middle of being appended.
+                warn!(%slug, "skipping unreadable WAL line during replay: {}", err);
+                continue;
+            }
+        };
+        if !started && edit_of(&entry.event).is_some() {
+            let start = replay_start(state, slug, snapshot_rev, &mut replayer)?;
+            if !emit(ReplayItem::Start(start)) {
+                return Ok(());
+            }
+            started = true;
+        }
+        let Some(step) = replayer.replay(&entry) else {
+            continue;
+        };
+        if step.rev <= replayer.apply_from {
+            continue;
+        }
+        let Some(edit) = edit_of(&entry.event) else {
+            unreachable!("only edits make revisions");
+        };
+        let item = ReplayItem::Edit(HistoryEdit {
+            rev: step.rev,
+            ts: entry.ts,
+            client_id: edit.client_id,
+            op_id: edit.op_id,
+            group_id: edit.group_id,
+            user_id: edit.user_id,
+            folded: step.folded,
+            ops: step.ops,
+        });
+        if !emit(item) {
+            return Ok(());
+        }
+    }
+    if !started {
+        emit(ReplayItem::Start(replay_start(
+            state,
+            slug,
+            snapshot_rev,
+            &mut replayer,
+        )?));
+    }
+    Ok(())
+}
+
+/// How long to wait before an edit stamped `ts` when the previous one was
+/// stamped `prev`.
+pub fn replay_delay(prev: u64, ts: u64, speed: f64, max_gap_ms: u64) -> Duration {
+    let gap = ts.saturating_sub(prev).min(max_gap_ms) as f64;
+    Duration::from_secs_f64(gap / speed / 1000.0)
+}
+
+/// Runs [`replay_history`] on a blocking thread and delivers its items with
+/// their original spacing. The stream ends early when the receiver is
+/// dropped.
+pub fn spawn_replay(
+    state: AppState,
+    slug: String,
+    speed: f64,
+    max_gap_ms: u64,
+) -> mpsc::Receiver<anyhow::Result<ReplayItem>> {
+    let (read_tx, mut read_rx) = mpsc::channel::<anyhow::Result<ReplayItem>>(64);
+    let (out_tx, out_rx) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let result = replay_history(&state, &slug, |item| {
+            read_tx.blocking_send(Ok(item)).is_ok()
+        });
+        if let Err(err) = result {
+            let _ = read_tx.blocking_send(Err(err));
+        }
+    });
+    tokio::spawn(async move {
+        let mut prev_ts = None;
+        while let Some(item) = read_rx.recv().await {
+            if let Ok(ReplayItem::Edit(edit)) = &item {
+                if let Some(prev) = prev_ts {
+                    tokio::time::sleep(replay_delay(prev, edit.ts, speed, max_gap_ms)).await;
+                }
+                prev_ts = Some(edit.ts);
+            }
+            if out_tx.send(item).await.is_err() {
+                break;
+            }
+        }
+    });
+    out_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        document::apply_ops,
+        state::apply_edit,
+        storage::{flush_snapshot_force, wal_append_event},
+        types::{DocEvent, Edit, OpKind},
+    };
+    use uuid::Uuid;
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("srvtest-replay-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            1_000,
+            100,
+            true,
+            vec![],
+        );
+        // Edits are stamped with fixed clocks to check pacing.
+        state.max_clock_skew_ms = u64::MAX;
+        state
+    }
+
+    fn insert(base_rev: u64, pos: usize, text: &str, ts: u64) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: Some(ts),
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    fn collect(state: &AppState, slug: &str) -> Vec<ReplayItem> {
+        let mut items = Vec::new();
+        replay_history(state, slug, |item| {
+            items.push(item);
+            true
+        })
+        .unwrap();
+        items
+    }
+
+    #[tokio::test]
+    async fn replays_edits_in_order_from_an_empty_document() {
+        let state = mk_state();
+        apply_edit(&state, "play", insert(0, 0, "world", 1_000))
+            .await
+            .unwrap();
+        // Concurrent with the first edit, so it is rebased on replay too.
+        apply_edit(&state, "play", insert(0, 0, "hello ", 1_500))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "play").await.unwrap();
+
+        let items = collect(&state, "play");
+        assert_eq!(
+            items[0],
+            ReplayItem::Start(ReplayStart {
+                slug: "play".into(),
+                rev: 0,
+                content: String::new(),
+            })
+        );
+        let mut doc = Doc::default();
+        let mut stamps = Vec::new();
+        for item in &items[1..] {
+            let ReplayItem::Edit(edit) = item else {
+                panic!("second start");
+            };
+            apply_ops(&mut doc, &edit.ops);
+            stamps.push((edit.rev, edit.ts));
+        }
+        assert_eq!(doc.content, "hello world");
+        assert_eq!(stamps, vec![(1, 1_000), (2, 1_500)]);
+    }
+
+    #[tokio::test]
+    async fn purged_history_starts_from_the_snapshot() {
+        let state = mk_state();
+        apply_edit(&state, "cut", insert(0, 0, "ab", 1_000))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "cut").await.unwrap();
+        let wal = crate::storage::wal_path(&state, "cut").unwrap();
+        std::fs::remove_file(&wal).unwrap();
+        wal_append_event(&state, "cut", &DocEvent::Purged { rev: 1 }, 1_000).unwrap();
+        wal_append_event(
+            &state,
+            "cut",
+            &DocEvent::Edit {
+                edit: insert(1, 2, "c", 2_000),
+            },
+            2_000,
+        )
+        .unwrap();
+
+        let items = collect(&state, "cut");
+        assert_eq!(items.len(), 2);
+        assert!(matches!(
+            &items[0],
+            ReplayItem::Start(start) if start.rev == 1 && start.content == "ab"
+        ));
+        assert!(matches!(&items[1], ReplayItem::Edit(edit) if edit.rev == 2));
+    }
+
+    #[test]
+    fn delays_scale_with_speed_and_cap_long_pauses() {
+        assert_eq!(
+            replay_delay(1_000, 1_500, 1.0, 2_000),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            replay_delay(1_000, 1_500, 2.0, 2_000),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            replay_delay(0, 86_400_000, 1.0, 2_000),
+            Duration::from_secs(2)
+        );
+        assert_eq!(replay_delay(2_000, 1_000, 1.0, 2_000), Duration::ZERO);
+    }
+}
@@ -0,0 +1,266 @@
+//! Read replica mode. A replica serves snapshots, renders and watch-only
+//! WebSocket sessions from a data directory that is replicated from the
+//! primary (rsync, a shared volume, block replication...). It never writes
+//! to that directory: writes are redirected to the primary or refused, and
+//! loaded documents are re-read from disk periodically, with the changes
+//! pushed to watchers as `Applied`.
+
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::{sync::watch, time::sleep};
+use tracing::{error, warn};
+
+use crate::{
+    merge::replace_ops,
+    state::{AppState, broadcast, now_millis, read_doc, unload_doc},
+    storage::doc_exists_on_disk,
+    types::{ClientMsg, ServerMsg},
+};
+
+pub const DEFAULT_REPLICA_REFRESH_MS: u64 = 2_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaConfig {
+    /// Writes are redirected here with `307`; without it they get `421`.
+    pub primary_url: Option<String>,
+    pub refresh_ms: u64,
+}
+
+/// Requests a replica serves although they are not `GET`s: tickets only live
+/// in this process's memory.
+const REPLICA_POSTS: &[&str] = &["/api/ws-ticket"];
+
+/// Middleware turning writes away from a replica.
+pub async fn refuse_writes(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(replica) = &state.replica else {
+        return next.run(req).await;
+    };
+    let read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if read || REPLICA_POSTS.contains(&req.uri().path()) {
+        return next.run(req).await;
+    }
+    match &replica.primary_url {
+        Some(primary) => {
+            let path = req
+                .uri()
+                .path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or("/");
+            let location = format!("{}{}", primary.trim_end_matches('/'), path);
+            (
+                StatusCode::TEMPORARY_REDIRECT,
+                [(header::LOCATION, location)],
+            )
+                .into_response()
+        }
+        None => (StatusCode::MISDIRECTED_REQUEST, "read-only replica").into_response(),
+    }
+}
+
+/// Messages a replica answers; everything else would join presence or edit.
+pub fn allowed_on_replica(msg: &ClientMsg) -> bool {
+    matches!(
+        msg,
+        ClientMsg::Watch { .. }
+            | ClientMsg::Unwatch { .. }
+            | ClientMsg::Ping { .. }
+            | ClientMsg::Pong
+            | ClientMsg::Resync { .. }
+    )
+}
+
+/// Re-reads every loaded document from disk. Documents that moved on are
+/// swapped in and their watchers get the difference as one `Applied`;
+/// documents gone from disk are dropped. Returns how many changed.
+pub fn refresh_replica(state: &AppState) -> usize {
+    let loaded: Vec<_> = state
+        .docs
+        .read()
+        .iter()
+        .map(|(slug, doc)| (slug.clone(), doc.clone()))
+        .collect();
+    let mut changed = 0;
+    for (slug, doc) in loaded {
+        match doc_exists_on_disk(state, &slug) {
+            Ok(true) => {}
+            Ok(false) => {
+                unload_doc(state, &slug, "replica");
+                continue;
+            }
+            Err(err) => {
+                warn!(%slug, "replica refresh skipped: {:#}", err);
+                continue;
+            }
+        }
+        let (fresh, _) = match read_doc(state, &slug) {
+            Ok(read) => read,
+            Err(err) => {
+                warn!(%slug, "replica refresh failed: {:#}", err);
+                continue;
+            }
+        };
+        let update = {
+            let mut d = doc.write();
+            if d.rev == fresh.rev && d.content == fresh.content && d.meta == fresh.meta {
+                None
+            } else {
+                let ops = replace_ops(&d.content, &fresh.content);
+                let rev = fresh.rev;
+                *d = fresh;
+                Some((rev, ops))
+            }
+        };
+        if let Some((rev, ops)) = update {
+            changed += 1;
+            broadcast(
+                state,
+                &slug,
+                ServerMsg::Applied {
+                    slug: slug.clone(),
+                    rev,
+                    ops,
+                    client_id: None,
+                    op_id: None,
+                    ts: now_millis(),
+                    hash: None,
+                    chars: None,
+                    stats: None,
+                    group_id: None,
+                    user_id: None,
+                },
+            );
+        }
+    }
+    changed
+}
+
+/// Refreshes loaded documents until `shutdown` flips to `true`.
+pub async fn run_replica_refresh(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    let Some(replica) = state.replica.clone() else {
+        return;
+    };
+    let interval = Duration::from_millis(replica.refresh_ms.max(100));
+    loop {
+        tokio::select! {
+            _ = sleep(interval) => {
+                let state = state.clone();
+                if let Err(err) = tokio::task::spawn_blocking(move || refresh_replica(&state)).await {
+                    error!("replica refresh aborted: {:#}", err);
+                }
+            }
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build_router,
+        state::{add_watcher, apply_edit, get_or_load_doc},
+        storage::flush_snapshot_force,
+        types::{Edit, OpKind},
+    };
+    use axum::body::Body;
+    use tokio::sync::mpsc;
+    use tower::util::ServiceExt;
+    use uuid::Uuid;
+
+    fn mk_state(base: &std::path::Path) -> AppState {
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            1_000,
+            100,
+            true,
+            vec![],
+        )
+    }
+
+    fn insert(base_rev: u64, pos: usize, text: &str) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn replicas_follow_the_primary_and_turn_writes_away() {
+        let base = std::env::temp_dir().join(format!("srvtest-replica-{}", Uuid::new_v4()));
+        let primary = mk_state(&base);
+        let mut replica = mk_state(&base);
+        replica.replica = Some(ReplicaConfig {
+            primary_url: Some("https://primary.example/".into()),
+            refresh_ms: DEFAULT_REPLICA_REFRESH_MS,
+        });
+
+        apply_edit(&primary, "doc", insert(0, 0, "hello"))
+            .await
+            .unwrap();
+        flush_snapshot_force(&primary, "doc").await.unwrap();
+        let doc = get_or_load_doc(&replica, "doc").await.unwrap();
+        assert_eq!(doc.read().content, "hello");
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        add_watcher(&replica, "doc", &tx, None);
+
+        // Unflushed edits are already in the replicated WAL.
+        apply_edit(&primary, "doc", insert(1, 5, " world"))
+            .await
+            .unwrap();
+        assert_eq!(refresh_replica(&replica), 1);
+        assert_eq!(doc.read().content, "hello world");
+        let Some(ServerMsg::Applied { rev, ops, .. }) = rx.recv().await else {
+            panic!("watcher missed the refresh");
+        };
+        assert_eq!(rev, 2);
+        assert_eq!(
+            ops,
+            vec![OpKind::Insert {
+                pos: 5,
+                text: " world".into(),
+            }]
+        );
+        assert_eq!(refresh_replica(&replica), 0);
+
+        let app = build_router(&replica);
+        let write = Request::builder()
+            .method(Method::POST)
+            .uri("/api/replace?x=1")
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.clone().oneshot(write).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            resp.headers()[header::LOCATION],
+            "https://primary.example/api/replace?x=1"
+        );
+        let read = Request::builder()
+            .uri("/api/snapshot?slug=doc")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.oneshot(read).await.unwrap().status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,901 @@
+//! Retention rules, run on a schedule and on demand through the admin API:
+//! purging old op history, compacting it into coarser edits, scrubbing
+//! inserted text from snapshotted WAL edits and deleting documents nobody
+//! used for months into the [trash](crate::trash). Archived documents are
+//! left to the archive tier.
+
+use std::{
+    collections::HashSet,
+    fs,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, time::sleep};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    cluster::owns,
+    document::{Doc, compose_ops, shapes},
+    jobs::JobHandle,
+    state::{AppState, get_or_load_doc, now_millis, unload_doc},
+    storage::{
+        compressed_path, flush_snapshot_force, list_all_slugs, load_meta, persist_meta, read_wal,
+        rewrite_wal, snapshot_path, wal_lock,
+    },
+    trash::delete_doc,
+    types::{CURRENT_WAL_VERSION, DocEvent, DocMeta, Edit, ImeEvent, OpKind, WalEntryV2},
+    wal_replay::{Replayer, decode_line},
+};
+
+pub const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+const MONTH_MS: u64 = 30 * DAY_MS;
+const SCRUB_CHAR: char = '*';
+/// Edits by one author within this span are compacted into one.
+pub const COMPACT_WINDOW_MS: u64 = 60_000;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Drop snapshotted edits older than this many days from the WAL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purge_history_days: Option<u64>,
+    /// Overwrite the inserted text of WAL edits once they are snapshotted.
+    #[serde(default)]
+    pub scrub_wal: bool,
+    /// Squash snapshotted edits older than this many days into one edit per
+    /// author and minute. Revisions keep their numbers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compact_history_days: Option<u64>,
+    /// Delete documents without activity for this many 30-day months.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_unused_months: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DocRetention {
+    pub slug: String,
+    pub purged_edits: u64,
+    pub scrubbed_edits: u64,
+    /// Edits squashed into compacted ones.
+    pub compacted_edits: u64,
+    pub deleted: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    pub ran_at: u64,
+    pub docs: Vec<DocRetention>,
+    /// Documents the run could not process; see the server log.
+    pub failed: Vec<String>,
+}
+
+/// The WAL rewrite retention wants for one document.
+#[derive(Debug)]
+struct WalPlan {
+    data: String,
+    history_start: u64,
+    scrubbed_rev: u64,
+    purged: u64,
+    scrubbed: u64,
+    compacted: Vec<Compaction>,
+}
+
+/// Revisions `first_rev..=rev` squashed into `ops` on `rev`.
+#[derive(Debug)]
+struct Compaction {
+    first_rev: u64,
+    rev: u64,
+    ops: Vec<OpKind>,
+}
+
+pub(crate) fn parse_wal(data: &str) -> anyhow::Result<Vec<WalEntryV2>> {
+    let mut entries = Vec::new();
+    for (idx, line) in data.lines().enumerate() {
+        match decode_line(idx, line) {
+            Some(Ok(entry)) => entries.push(entry),
+            Some(Err((line, err))) => anyhow::bail!("WAL line {} does not parse: {}", line, err),
+            None => {}
+        }
+    }
+    Ok(entries)
+}
+
+fn op_id_of(event: &DocEvent) -> Option<Uuid> {
+    match event {
+        DocEvent::Edit { edit } | DocEvent::Compacted { edit } => edit.op_id,
+        DocEvent::Cursor { op_id, .. } | DocEvent::Ime { op_id, .. } => *op_id,
+        DocEvent::Purged { .. } => None,
+    }
+}
+
+fn scrub_text(text: &mut String) {
+    *text = std::iter::repeat_n(SCRUB_CHAR, text.chars().count()).collect();
+}
+
+fn scrub_ops(ops: &mut [OpKind]) {
+    for op in ops {
+        if let OpKind::Insert { text, .. } = op {
+            scrub_text(text);
+        }
+    }
+}
+
+fn scrub_event(event: &mut DocEvent) {
+    match event {
+        DocEvent::Edit { edit } | DocEvent::Compacted { edit } => scrub_ops(&mut edit.ops),
+        DocEvent::Ime {
+            ime: ImeEvent::Update { text, .. } | ImeEvent::Commit { text, .. },
+            ..
+        } => scrub_text(text),
+        _ => {}
+    }
+}
+
+/// Works out which WAL entries `purge_before` drops, which ones get
+/// scrubbed and which ones `compact_before` squashes. Only snapshotted edits
+/// are touched, and neither the cut nor a squash lands where a kept edit
+/// would have to be rebased across revisions that no longer log their ops.
+fn plan_wal(
+    entries: Vec<WalEntryV2>,
+    meta: &DocMeta,
+    purge_before: Option<u64>,
+    scrub: bool,
+    compact_before: Option<u64>,
+) -> anyhow::Result<Option<WalPlan>> {
+    // Number the edits the same way the loader does, keeping the applied
+    // ops whole since squashes are made of them.
+    let mut replayer = Replayer::new(Doc::default(), u64::MAX);
+    let mut applied: Vec<Vec<OpKind>> = Vec::new();
+    let mut revs = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        let rev = replayer.replay(entry).map(|step| {
+            applied.resize(step.rev as usize - 1, Vec::new());
+            applied.push(step.ops);
+            step.rev
+        });
+        revs.push(rev);
+    }
+    let start = replayer.purged;
+    let index_of = |rev: u64| revs.iter().position(|r| *r == Some(rev));
+    let base_rev = |entry: &WalEntryV2| match &entry.event {
+        DocEvent::Edit { edit } | DocEvent::Compacted { edit } => Some(edit.base_rev),
+        _ => None,
+    };
+
+    let mut cut = start;
+    if let Some(before) = purge_before {
+        for (entry, rev) in entries.iter().zip(&revs) {
+            if let Some(rev) = *rev {
+                if rev > meta.snapshot_rev || entry.ts >= before {
+                    break;
+                }
+                cut = rev;
+            }
+        }
+        while cut > start {
+            let idx = index_of(cut).context("purge cut has no WAL entry")?;
+            let min_base = entries[idx + 1..]
+                .iter()
+                .filter_map(base_rev)
+                .min()
+                .unwrap_or(cut);
+            if min_base >= cut {
+                break;
+            }
+            cut = min_base.max(start);
+        }
+    }
+
+    let scrub_to = if scrub { meta.snapshot_rev } else { 0 };
+    let scrub_from = meta.scrubbed_rev.max(cut);
+    let first_kept = if cut > start {
+        index_of(cut).map_or(0, |idx| idx + 1)
+    } else {
+        0
+    };
+    let scrub_end = if scrub_to > scrub_from {
+        index_of(scrub_to).map_or(0, |idx| idx + 1)
+    } else {
+        0
+    };
+
+    // Runs of entries squashed into one, as index ranges.
+    let mut squashes: Vec<std::ops::Range<usize>> = Vec::new();
+    if let Some(before) = compact_before {
+        let mut last_ref = std::collections::HashMap::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            if let Some(base) = base_rev(entry) {
+                last_ref.insert(base, idx);
+            }
+        }
+        let author = |edit: &Edit| (edit.client_id, edit.user_id, edit.group_id);
+        let mut run: Option<std::ops::Range<usize>> = None;
+        for (idx, (entry, rev)) in entries.iter().zip(&revs).enumerate().skip(first_kept) {
+            let (edit, rev) = match (&entry.event, *rev) {
+                (DocEvent::Edit { edit }, Some(rev))
+                    if rev <= meta.snapshot_rev && entry.ts < before =>
+                {
+                    (edit, rev)
+                }
+                _ => {
+                    squashes.extend(run.take().filter(|run| run.len() > 1));
+                    continue;
+                }
+            };
+            let joins = run.as_ref().is_some_and(|run| {
+                let first = &entries[run.start];
+                let DocEvent::Edit { edit: first_edit } = &first.event else {
+                    return false;
+                };
+                // The previous revision becomes internal to the squash.
+                let prev = rev - 1;
+                run.end == idx
+                    && author(first_edit) == author(edit)
+                    && entry.ts.saturating_sub(first.ts) < COMPACT_WINDOW_MS
+                    && (run.start < scrub_end) == (idx < scrub_end)
+                    && last_ref.get(&prev).is_none_or(|last| *last <= idx)
+            });
+            if joins {
+                if let Some(run) = &mut run {
+                    run.end = idx + 1;
+                }
+            } else {
+                squashes.extend(run.replace(idx..idx + 1).filter(|run| run.len() > 1));
+            }
+        }
+        squashes.extend(run.filter(|run| run.len() > 1));
+    }
+
+    if cut == start && scrub_to <= scrub_from && squashes.is_empty() {
+        return Ok(None);
+    }
+
+    let mut dropped: HashSet<Uuid> = entries[..first_kept]
+        .iter()
+        .filter_map(|entry| op_id_of(&entry.event))
+        .collect();
+    for run in &squashes {
+        dropped.extend(
+            entries[run.clone()]
+                .iter()
+                .filter_map(|e| op_id_of(&e.event)),
+        );
+    }
+    let mut scrubbed_ids = HashSet::new();
+    let mut scrubbed = 0;
+    let mut compacted = Vec::new();
+    let mut kept = Vec::new();
+    if cut > start {
+        kept.push(WalEntryV2 {
+            version: CURRENT_WAL_VERSION,
+            ts: entries[first_kept - 1].ts,
+            server_ts: None,
+            event: DocEvent::Purged { rev: cut },
+        });
+    }
+    let mut squashes = squashes.into_iter().peekable();
+    let mut squashed: Option<(std::ops::Range<usize>, Edit)> = None;
+    for (idx, (mut entry, rev)) in entries.into_iter().zip(revs).enumerate().skip(first_kept) {
+        let op_id = op_id_of(&entry.event);
+        if let Some(run) = squashes.next_if(|run| run.start == idx) {
+            let DocEvent::Edit { edit } = &entry.event else {
+                unreachable!("squashes start at edits");
+            };
+            squashed = Some((run, edit.clone()));
+        }
+        let in_squash = squashed.as_ref().is_some_and(|(run, _)| run.contains(&idx));
+        if !in_squash && op_id.is_some_and(|id| dropped.contains(&id)) {
+            continue;
+        }
+        let scrubbing = idx < scrub_end || op_id.is_some_and(|id| scrubbed_ids.contains(&id));
+        if scrubbing {
+            scrub_event(&mut entry.event);
+            if let Some(id) = op_id {
+                scrubbed_ids.insert(id);
+            }
+            if rev.is_some_and(|rev| rev > scrub_from) {
+                scrubbed += 1;
+            }
+        }
+        let Some((run, first)) = squashed.take_if(|(run, _)| run.end == idx + 1) else {
+            if !in_squash {
+                kept.push(entry);
+            }
+            continue;
+        };
+        let rev = rev.context("squashed entry has no revision")?;
+        let first_rev = rev + 1 - run.len() as u64;
+        let mut ops: Vec<OpKind> = applied[first_rev as usize - 1..rev as usize]
+            .iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let composed = compose_ops(ops.clone());
+        if !composed.is_empty() {
+            ops = composed;
+        }
+        if scrubbing {
+            scrub_ops(&mut ops);
+        }
+        compacted.push(Compaction {
+            first_rev,
+            rev,
+            ops: ops.clone(),
+        });
+        kept.push(WalEntryV2 {
+            version: CURRENT_WAL_VERSION,
+            ts: entry.ts,
+            server_ts: entry.server_ts,
+            event: DocEvent::Compacted {
+                edit: Edit {
+                    base_rev: rev - 1,
+                    ops,
+                    client_id: first.client_id,
+                    op_id: None,
+                    cursor_before: None,
+                    cursor_after: None,
+                    ts: Some(entry.ts),
+                    group_id: first.group_id,
+                    user_id: first.user_id,
+                },
+            },
+        });
+    }
+    let mut data = String::new();
+    for entry in &kept {
+        data.push_str(&serde_json::to_string(entry)?);
+        data.push('\n');
+    }
+    Ok(Some(WalPlan {
+        data,
+        history_start: cut,
+        scrubbed_rev: scrub_to.max(meta.scrubbed_rev),
+        purged: cut - start,
+        scrubbed,
+        compacted,
+    }))
+}
+
+fn modified_millis(path: &std::path::Path) -> Option<u64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+/// Latest edit timestamp in the WAL, or the snapshot's mtime without one.
+fn last_activity(state: &AppState, slug: &str, entries: &[WalEntryV2]) -> anyhow::Result<u64> {
+    let wal_ts = entries.iter().map(|entry| entry.ts).max().unwrap_or(0);
+    let snap = snapshot_path(state, slug)?;
+    let snap_ts = modified_millis(&snap)
+        .or_else(|| modified_millis(&compressed_path(&snap)))
+        .unwrap_or(0);
+    Ok(wal_ts.max(snap_ts))
+}
+
+async fn retain_doc(
+    state: &AppState,
+    policy: &RetentionPolicy,
+    slug: &str,
+    now: u64,
+    dry_run: bool,
+) -> anyhow::Result<Option<DocRetention>> {
+    let loaded = state.docs.read().get(slug).cloned();
+    let meta = match &loaded {
+        // A real run flushes loaded documents first.
+        Some(doc) => {
+            let d = doc.read();
+            DocMeta {
+                snapshot_rev: d.rev,
+                ..d.meta.clone()
+            }
+        }
+        None => load_meta(state, slug)?.unwrap_or_default(),
+    };
+    if meta.archived_at.is_some() {
+        return Ok(None);
+    }
+    let purge_days = meta
+        .settings
+        .purge_history_days
+        .or(policy.purge_history_days);
+    if purge_days.is_none()
+        && !policy.scrub_wal
+        && policy.compact_history_days.is_none()
+        && policy.delete_unused_months.is_none()
+    {
+        return Ok(None);
+    }
+    let entries = parse_wal(&read_wal(state, slug)?.unwrap_or_default())?;
+    let report = |plan: &WalPlan| DocRetention {
+        slug: slug.to_string(),
+        purged_edits: plan.purged,
+        scrubbed_edits: plan.scrubbed,
+        compacted_edits: plan
+            .compacted
+            .iter()
+            .map(|squash| squash.rev + 1 - squash.first_rev)
+            .sum(),
+        deleted: false,
+    };
+
+    if let Some(months) = policy.delete_unused_months {
+        let in_use = state.subs.read().get(slug).is_some_and(|s| !s.is_empty());
+        let last = last_activity(state, slug, &entries)?
+            .max(loaded.as_ref().map_or(0, |doc| doc.read().last_edit_ts));
+        if !in_use && now.saturating_sub(last) >= months.saturating_mul(MONTH_MS) {
+            if !dry_run {
+                delete_doc(state, slug, "unused")?;
+            }
+            return Ok(Some(DocRetention {
+                slug: slug.to_string(),
+                purged_edits: 0,
+                scrubbed_edits: 0,
+                compacted_edits: 0,
+                deleted: true,
+            }));
+        }
+    }
+
+    let days_ago = |days: u64| now.saturating_sub(days.saturating_mul(DAY_MS));
+    let purge_before = purge_days.map(days_ago);
+    let compact_before = policy.compact_history_days.map(days_ago);
+    if purge_before.is_none() && !policy.scrub_wal && compact_before.is_none() {
+        return Ok(None);
+    }
+    if dry_run {
+        let plan = plan_wal(
+            entries,
+            &meta,
+            purge_before,
+            policy.scrub_wal,
+            compact_before,
+        )?;
+        return Ok(plan.as_ref().map(report));
+    }
+
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    flush_snapshot_force(state, slug).await?;
+    let plan = {
+        // Edits wait while the WAL is rewritten, and so do their appends.
+        let wal = wal_lock(state, slug);
+        let _wal = wal.lock();
+        let mut d = doc_arc.write();
+        let entries = parse_wal(&read_wal(state, slug)?.unwrap_or_default())?;
+        let plan = plan_wal(
+            entries,
+            &d.meta,
+            purge_before,
+            policy.scrub_wal,
+            compact_before,
+        )?;
+        if let Some(plan) = &plan {
+            if plan.scrubbed_rev != d.meta.scrubbed_rev {
+                d.meta.scrubbed_rev = plan.scrubbed_rev;
+                persist_meta(state, slug, &d.meta)?;
+            }
+            rewrite_wal(state, slug, &plan.data)?;
+            d.rev_index.get_mut().clear();
+            for squash in &plan.compacted {
+                let first = squash.first_rev as usize - 1;
+                for ops in &mut d.log[first..squash.rev as usize - 1] {
+                    *ops = Vec::new();
+                }
+                d.log[squash.rev as usize - 1] = shapes(&squash.ops);
+            }
+            for ops in d.log.iter_mut().take(plan.history_start as usize) {
+                *ops = Vec::new();
+            }
+        }
+        plan
+    };
+    if loaded.is_none() {
+        unload_doc(state, slug, "retention");
+    }
+    Ok(plan.as_ref().map(report))
+}
+
+/// Applies `policy` to every document on disk. With `dry_run` nothing is
+/// changed and the report lists what would happen.
+pub async fn run_retention(
+    state: &AppState,
+    policy: &RetentionPolicy,
+    now: u64,
+    dry_run: bool,
+) -> anyhow::Result<RetentionReport> {
+    retention_pass(state, policy, now, dry_run, None).await
+}
+
+/// [`run_retention`] as a background job: reports progress per document and
+/// stops early when the job is cancelled.
+pub async fn run_retention_job(
+    state: &AppState,
+    policy: &RetentionPolicy,
+    now: u64,
+    dry_run: bool,
+    job: &JobHandle,
+) -> anyhow::Result<RetentionReport> {
+    retention_pass(state, policy, now, dry_run, Some(job)).await
+}
+
+async fn retention_pass(
+    state: &AppState,
+    policy: &RetentionPolicy,
+    now: u64,
+    dry_run: bool,
+    job: Option<&JobHandle>,
+) -> anyhow::Result<RetentionReport> {
+    let slugs: Vec<String> = list_all_slugs(state)?
+        .into_iter()
+        .filter(|slug| owns(state, slug))
+        .collect();
+    if let Some(job) = job {
+        job.set_total(slugs.len());
+    }
+    let mut report = RetentionReport {
+        dry_run,
+        ran_at: now,
+        docs: Vec::new(),
+        failed: Vec::new(),
+    };
+    for slug in slugs {
+        if job.is_some_and(JobHandle::is_cancelled) {
+            break;
+        }
+        let failed = match retain_doc(state, policy, &slug, now, dry_run).await {
+            Ok(Some(doc)) => {
+                report.docs.push(doc);
+                None
+            }
+            Ok(None) => None,
+            Err(err) => {
+                error!(%slug, "retention failed: {:#}", err);
+                report.failed.push(slug.clone());
+                Some(slug)
+            }
+        };
+        if let Some(job) = job {
+            job.advance(failed);
+        }
+    }
+    Ok(report)
+}
+
+/// Runs the configured retention policy, and documents' own history
+/// retention, until `shutdown` flips to `true`.
+pub async fn run_retention_loop(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    let interval = Duration::from_millis(state.retention_interval_ms.max(60_000));
+    loop {
+        tokio::select! {
+            _ = sleep(interval) => {
+                match run_retention(&state, &state.retention, now_millis(), false).await {
+                    Ok(report) => info!(
+                        docs = report.docs.len(),
+                        failed = report.failed.len(),
+                        "applied retention policy"
+                    ),
+                    Err(err) => error!("retention run failed: {:#}", err),
+                }
+            }
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        document::OpShape, history::export_history, integrity::verify_doc, state::apply_edit,
+        storage::doc_exists_on_disk, types::Edit,
+    };
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("retention-{}", Uuid::new_v4()));
+        let wal = base.join("wal");
+        let snap = base.join("snapshots");
+        fs::create_dir_all(&wal).unwrap();
+        fs::create_dir_all(&snap).unwrap();
+        // Edits carry made-up timestamps; only forced flushes should run.
+        let mut state = AppState::new(wal, snap, u64::MAX, 1_000, true, vec![]);
+        state.max_clock_skew_ms = u64::MAX;
+        state
+    }
+
+    fn insert(base_rev: u64, pos: usize, text: &str, ts: u64) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: Some(ts),
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    fn delete(base_rev: u64, pos: usize, len: usize, ts: u64) -> Edit {
+        Edit {
+            ops: vec![OpKind::Delete { pos, len }],
+            ..insert(base_rev, 0, "", ts)
+        }
+    }
+
+    #[tokio::test]
+    async fn purges_and_scrubs_snapshotted_history() {
+        let state = mk_state();
+        let slug = "team/notes";
+        let now = 100 * DAY_MS;
+        apply_edit(&state, slug, insert(0, 0, "secret ", DAY_MS))
+            .await
+            .unwrap();
+        apply_edit(&state, slug, delete(1, 0, 7, DAY_MS))
+            .await
+            .unwrap();
+        apply_edit(&state, slug, insert(2, 0, "kept", now))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, slug).await.unwrap();
+        apply_edit(&state, slug, insert(3, 4, "!", now))
+            .await
+            .unwrap();
+
+        let policy = RetentionPolicy {
+            purge_history_days: Some(30),
+            scrub_wal: true,
+            ..Default::default()
+        };
+        let expected = vec![DocRetention {
+            slug: slug.into(),
+            purged_edits: 2,
+            scrubbed_edits: 2,
+            compacted_edits: 0,
+            deleted: false,
+        }];
+        let dry = run_retention(&state, &policy, now, true).await.unwrap();
+        assert_eq!(dry.docs, expected);
+        assert!(read_wal(&state, slug).unwrap().unwrap().contains("secret"));
+
+        let report = run_retention(&state, &policy, now, false).await.unwrap();
+        assert_eq!(report.docs, expected);
+        assert!(report.failed.is_empty());
+        let wal = read_wal(&state, slug).unwrap().unwrap();
+        assert!(!wal.contains("secret"));
+        assert!(!wal.contains("kept"));
+        assert!(
+            wal.starts_with(r#"{"version":2,"ts":86400000,"event":{"type":"purged","rev":2}}"#)
+        );
+        {
+            let doc = get_or_load_doc(&state, slug).await.unwrap();
+            let d = doc.read();
+            assert_eq!((d.rev, d.content.as_str()), (4, "kept!"));
+            assert!(d.log[0].is_empty());
+            assert_eq!(d.log[2], vec![OpShape::Insert { pos: 0, chars: 4 }]);
+        }
+
+        // A reload replays the rewritten WAL to the same revision.
+        unload_doc(&state, slug, "test");
+        apply_edit(&state, slug, insert(4, 5, "?", now))
+            .await
+            .unwrap();
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!(
+            (doc.read().rev, doc.read().content.clone()),
+            (5, "kept!?".into())
+        );
+        assert!(verify_doc(&state, slug).is_ok());
+        let history = export_history(&state, slug).unwrap();
+        assert_eq!((history.history_start, history.scrubbed_rev), (2, 4));
+        history.validate().unwrap();
+
+        // Only the edit made since then is left to scrub.
+        let again = run_retention(&state, &policy, now, false).await.unwrap();
+        assert_eq!(
+            (again.docs[0].purged_edits, again.docs[0].scrubbed_edits),
+            (0, 1)
+        );
+        let last = run_retention(&state, &policy, now, false).await.unwrap();
+        assert!(last.docs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn purge_stops_before_edits_based_on_old_revisions() {
+        let state = mk_state();
+        let slug = "solo";
+        let now = 100 * DAY_MS;
+        apply_edit(&state, slug, insert(0, 0, "old", DAY_MS))
+            .await
+            .unwrap();
+        apply_edit(&state, slug, insert(1, 3, " older", DAY_MS))
+            .await
+            .unwrap();
+        // A late edit made against rev 1 still needs rev 2 to rebase.
+        apply_edit(&state, slug, insert(1, 0, ">", now))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, slug).await.unwrap();
+
+        let policy = RetentionPolicy {
+            purge_history_days: Some(30),
+            ..Default::default()
+        };
+        let report = run_retention(&state, &policy, now, false).await.unwrap();
+        assert_eq!(report.docs[0].purged_edits, 1);
+        unload_doc(&state, slug, "test");
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!(doc.read().content, ">old older");
+        assert_eq!(doc.read().rev, 3);
+    }
+
+    #[tokio::test]
+    async fn purged_edits_are_still_deduplicated_after_a_restart() {
+        let state = mk_state();
+        let slug = "retry";
+        let now = 100 * DAY_MS;
+        let edit = insert(0, 0, "once", DAY_MS);
+        apply_edit(&state, slug, edit.clone()).await.unwrap();
+        flush_snapshot_force(&state, slug).await.unwrap();
+        let policy = RetentionPolicy {
+            purge_history_days: Some(30),
+            ..Default::default()
+        };
+        let report = run_retention(&state, &policy, now, false).await.unwrap();
+        assert_eq!(report.docs[0].purged_edits, 1);
+        assert!(!read_wal(&state, slug).unwrap().unwrap().contains("once"));
+
+        unload_doc(&state, slug, "restart");
+        apply_edit(&state, slug, edit).await.unwrap();
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!((doc.read().rev, doc.read().content.as_str()), (1, "once"));
+    }
+
+    #[tokio::test]
+    async fn compacts_old_history_per_author_and_minute() {
+        let state = mk_state();
+        let slug = "typed";
+        let now = 100 * DAY_MS;
+        let (alice, bob) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+        let by = |client_id, edit: Edit| Edit { client_id, ..edit };
+        for edit in [
+            by(alice, insert(0, 0, "h", DAY_MS)),
+            by(alice, insert(1, 1, "i", DAY_MS + 1_000)),
+            by(bob, insert(2, 2, "!", DAY_MS + 2_000)),
+            by(alice, insert(3, 3, " x", DAY_MS + 120_000)),
+            by(alice, insert(4, 5, "y", DAY_MS + 121_000)),
+            // Still needs rev 5 on its own to rebase.
+            by(alice, insert(4, 0, ">", now)),
+        ] {
+            apply_edit(&state, slug, edit).await.unwrap();
+        }
+        flush_snapshot_force(&state, slug).await.unwrap();
+        apply_edit(&state, slug, insert(6, 7, ".", now))
+            .await
+            .unwrap();
+
+        let policy = RetentionPolicy {
+            compact_history_days: Some(30),
+            ..Default::default()
+        };
+        let report = run_retention(&state, &policy, now, false).await.unwrap();
+        assert_eq!(report.docs[0].compacted_edits, 2);
+        let wal = read_wal(&state, slug).unwrap().unwrap();
+        assert_eq!(wal.lines().count(), 6);
+        let first = parse_wal(&wal).unwrap().remove(0);
+        let DocEvent::Compacted { edit } = first.event else {
+            panic!("expected a compacted edit, got {:?}", first.event);
+        };
+        assert_eq!(
+            (first.ts, edit.base_rev, edit.client_id),
+            (DAY_MS + 1_000, 1, alice)
+        );
+        assert_eq!(
+            edit.ops,
+            vec![OpKind::Insert {
+                pos: 0,
+                text: "hi".into()
+            }]
+        );
+        {
+            let doc = get_or_load_doc(&state, slug).await.unwrap();
+            let d = doc.read();
+            assert!(d.log[0].is_empty());
+            assert_eq!(d.log[1].len(), 1);
+        }
+
+        unload_doc(&state, slug, "test");
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!(
+            (doc.read().rev, doc.read().content.clone()),
+            (7, ">hi! xy.".into())
+        );
+        assert!(verify_doc(&state, slug).is_ok());
+        let history = export_history(&state, slug).unwrap();
+        history.validate().unwrap();
+        assert_eq!((history.edits[0].rev, history.edits[0].folded), (2, 1));
+        assert_eq!(history.edits[0].client_id, alice);
+        let again = run_retention(&state, &policy, now, false).await.unwrap();
+        assert!(again.docs.is_empty(), "{:?}", again.docs);
+        crate::history::import_history(&state, "typed-copy", &history)
+            .await
+            .unwrap();
+        let copy = get_or_load_doc(&state, "typed-copy").await.unwrap();
+        assert_eq!(copy.read().content, ">hi! xy.");
+    }
+
+    #[tokio::test]
+    async fn deletes_documents_unused_for_months() {
+        let state = mk_state();
+        apply_edit(&state, "stale", insert(0, 0, "bye", DAY_MS))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "stale").await.unwrap();
+        let policy = RetentionPolicy {
+            delete_unused_months: Some(6),
+            ..Default::default()
+        };
+        let soon = now_millis() + 30 * DAY_MS;
+        let report = run_retention(&state, &policy, soon, false).await.unwrap();
+        assert!(report.docs.is_empty());
+
+        let later = now_millis() + 7 * MONTH_MS;
+        let dry = run_retention(&state, &policy, later, true).await.unwrap();
+        assert!(dry.docs[0].deleted);
+        assert!(doc_exists_on_disk(&state, "stale").unwrap());
+        run_retention(&state, &policy, later, false).await.unwrap();
+        assert!(!doc_exists_on_disk(&state, "stale").unwrap());
+        assert!(state.docs.read().get("stale").is_none());
+        let trashed = crate::trash::list_trash(&state).unwrap();
+        assert_eq!(
+            (trashed[0].slug.as_str(), trashed[0].reason.as_str()),
+            ("stale", "unused")
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn edits_appended_during_a_rewrite_survive_a_reload() {
+        let state = mk_state();
+        let slug = "busy";
+        apply_edit(&state, slug, insert(0, 0, "x", DAY_MS))
+            .await
+            .unwrap();
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        let rev = get_or_load_doc(&state, slug).await.unwrap().read().rev;
+                        apply_edit(&state, slug, insert(rev, 0, "x", DAY_MS))
+                            .await
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        let policy = RetentionPolicy {
+            scrub_wal: true,
+            ..Default::default()
+        };
+        while writers.iter().any(|writer| !writer.is_finished()) {
+            run_retention(&state, &policy, 2 * DAY_MS, false)
+                .await
+                .unwrap();
+        }
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        unload_doc(&state, slug, "test");
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!((doc.read().rev, doc.read().content.len()), (401, 401));
+    }
+}
@@ -0,0 +1,407 @@
+//! Rebasing an edit across many revisions without replaying each one. Every
+//! revision's ops are folded into a changeset: a run of retained, inserted
+//! and deleted lengths that says where each character of the older text
+//! went. Changesets of aligned blocks of 2, 4, 8... revisions are composed
+//! as the log grows, so any revision range is covered by O(log n) of them
+//! and a single op is carried across each with one walk.
+//!
+//! The result is the same as [`transform_pair`](crate::document::transform_pair)
+//! against each revision in turn, with the incoming edit winning ties: an
+//! insert lands ahead of text inserted at the same spot, and a delete takes
+//! what is left of the characters it named, split around text inserted
+//! among them. Edits of several ops fall back to the replay.
+
+use crate::{
+    document::{OpShape, ShapedOp},
+    types::OpKind,
+};
+
+/// Rebases spanning fewer revisions replay them; building the index only
+/// pays off for clients that fell far behind.
+pub const INDEX_MIN_REVS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Span {
+    Retain(usize),
+    Insert(usize),
+    Delete(usize),
+}
+
+impl Span {
+    fn len(self) -> usize {
+        match self {
+            Span::Retain(n) | Span::Insert(n) | Span::Delete(n) => n,
+        }
+    }
+
+    fn with_len(self, n: usize) -> Span {
+        match self {
+            Span::Retain(_) => Span::Retain(n),
+            Span::Insert(_) => Span::Insert(n),
+            Span::Delete(_) => Span::Delete(n),
+        }
+    }
+}
+
+/// Spans in the order they apply to the older text; whatever follows the
+/// last one is retained.
+type Changeset = Vec<Span>;
+
+fn push(cs: &mut Changeset, span: Span) {
+    if span.len() == 0 {
+        return;
+    }
+    match (cs.last_mut(), span) {
+        (Some(Span::Retain(n)), Span::Retain(m))
+        | (Some(Span::Insert(n)), Span::Insert(m))
+        | (Some(Span::Delete(n)), Span::Delete(m)) => *n += m,
+        _ => cs.push(span),
+    }
+}
+
+/// `a` followed by `b`. Text `b` inserts where `a` deleted goes after the
+/// deletion, so every position that collapsed there stays ahead of it.
+fn compose(a: &[Span], b: &[Span]) -> Changeset {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    let (mut a_rest, mut b_rest) = (a.iter().copied(), b.iter().copied());
+    let (mut x, mut y) = (a_rest.next(), b_rest.next());
+    loop {
+        match (x, y) {
+            (Some(Span::Delete(n)), _) => {
+                push(&mut out, Span::Delete(n));
+                x = a_rest.next();
+            }
+            (_, Some(Span::Insert(n))) => {
+                push(&mut out, Span::Insert(n));
+                y = b_rest.next();
+            }
+            (None, None) => break,
+            // Past the end of `a` its text is retained, and past the end of
+            // `b` so is whatever `a` left.
+            (None, Some(span)) => {
+                push(&mut out, span);
+                y = b_rest.next();
+            }
+            (Some(span), None) => {
+                push(&mut out, span);
+                x = a_rest.next();
+            }
+            (Some(sa), Some(sb)) => {
+                let n = sa.len().min(sb.len());
+                match (sa, sb) {
+                    (Span::Retain(_), Span::Retain(_)) => push(&mut out, Span::Retain(n)),
+                    (Span::Retain(_), Span::Delete(_)) => push(&mut out, Span::Delete(n)),
+                    (Span::Insert(_), Span::Retain(_)) => push(&mut out, Span::Insert(n)),
+                    // Deleting what `a` inserted leaves no trace.
+                    _ => {}
+                }
+                x = match sa.len() - n {
+                    0 => a_rest.next(),
+                    left => Some(sa.with_len(left)),
+                };
+                y = match sb.len() - n {
+                    0 => b_rest.next(),
+                    left => Some(sb.with_len(left)),
+                };
+            }
+        }
+    }
+    out
+}
+
+fn op_changeset(op: OpShape) -> Changeset {
+    let mut cs = Vec::with_capacity(2);
+    match op {
+        OpShape::Insert { pos, chars } => {
+            push(&mut cs, Span::Retain(pos));
+            push(&mut cs, Span::Insert(chars));
+        }
+        OpShape::Delete { pos, len } => {
+            push(&mut cs, Span::Retain(pos));
+            push(&mut cs, Span::Delete(len));
+        }
+    }
+    cs
+}
+
+fn rev_changeset<T: ShapedOp>(ops: &[T]) -> Changeset {
+    ops.iter()
+        .fold(Vec::new(), |cs, op| compose(&cs, &op_changeset(op.shape())))
+}
+
+/// Where an insert at `pos` of the older text goes, ahead of anything
+/// inserted at the same spot.
+fn map_insert(cs: &[Span], pos: usize) -> usize {
+    let (mut base, mut at) = (0, 0);
+    for span in cs {
+        if base == pos {
+            return at;
+        }
+        match *span {
+            Span::Retain(n) if pos < base + n => return at + (pos - base),
+            Span::Delete(n) if pos < base + n => return at,
+            Span::Retain(n) => (base, at) = (base + n, at + n),
+            Span::Delete(n) => base += n,
+            Span::Insert(n) => at += n,
+        }
+    }
+    at + (pos - base)
+}
+
+fn keep(out: &mut Vec<(usize, usize)>, start: usize, end: usize) {
+    if start >= end {
+        return;
+    }
+    match out.last_mut() {
+        Some(last) if last.1 == start => last.1 = end,
+        _ => out.push((start, end)),
+    }
+}
+
+/// Where what is left of the sorted, disjoint `ranges` of the older text
+/// went. Text inserted among them is not theirs, so it splits them.
+fn map_ranges(cs: &[Span], ranges: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut out = Vec::with_capacity(ranges.len());
+    let (mut base, mut at, mut next) = (0, 0, 0);
+    for span in cs {
+        match *span {
+            Span::Insert(n) => at += n,
+            Span::Delete(n) => base += n,
+            Span::Retain(n) => {
+                let end = base + n;
+                while let Some(&(start, stop)) = ranges.get(next)
+                    && start < end
+                {
+                    keep(
+                        &mut out,
+                        start.max(base) - base + at,
+                        stop.min(end).max(base) - base + at,
+                    );
+                    if stop > end {
+                        break;
+                    }
+                    next += 1;
+                }
+                (base, at) = (end, at + n);
+            }
+        }
+    }
+    for &(start, stop) in ranges.get(next..).unwrap_or_default() {
+        let start = start.max(base);
+        if start < stop {
+            keep(&mut out, start - base + at, stop - base + at);
+        }
+    }
+    out
+}
+
+/// Composed changesets of the log: `levels[k][j]` covers revisions
+/// `j << k` up to `(j + 1) << k`.
+#[derive(Debug, Default)]
+pub struct RevIndex {
+    levels: Vec<Vec<Changeset>>,
+}
+
+impl RevIndex {
+    /// Forgets everything; call after rewriting logged revisions in place.
+    pub fn clear(&mut self) {
+        self.levels.clear();
+    }
+
+    /// Takes in revisions logged since the last call.
+    fn sync<T: ShapedOp>(&mut self, log: &[Vec<T>]) {
+        if self
+            .levels
+            .first()
+            .is_some_and(|leaves| leaves.len() > log.len())
+        {
+            self.clear();
+        }
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        let done = self.levels[0].len();
+        for (rev, ops) in log.iter().enumerate().skip(done) {
+            self.levels[0].push(rev_changeset(ops));
+            // Each finished pair becomes a block one level up.
+            let (mut level, mut idx) = (0, rev);
+            while !idx.is_multiple_of(2) {
+                let block = {
+                    let below = &self.levels[level];
+                    compose(&below[idx - 1], &below[idx])
+                };
+                if self.levels.len() == level + 1 {
+                    self.levels.push(Vec::new());
+                }
+                self.levels[level + 1].push(block);
+                (level, idx) = (level + 1, idx / 2);
+            }
+        }
+    }
+
+    /// The fewest blocks that cover revisions `from..to`, oldest first.
+    fn blocks(&self, from: usize, to: usize) -> Vec<&Changeset> {
+        let mut out = Vec::new();
+        let mut rev = from;
+        while rev < to {
+            let mut level = 0;
+            while level + 1 < self.levels.len() {
+                let size = 1 << (level + 1);
+                if !rev.is_multiple_of(size)
+                    || rev + size > to
+                    || self.levels[level + 1].len() <= rev / size
+                {
+                    break;
+                }
+                level += 1;
+            }
+            out.push(&self.levels[level][rev >> level]);
+            rev += 1 << level;
+        }
+        out
+    }
+
+    /// `ops`, made against revision `from`, rebased onto the end of `log`.
+    /// `None` when they are not a single op with an effect, which the
+    /// caller replays instead.
+    pub fn transform<T: ShapedOp>(
+        &mut self,
+        log: &[Vec<T>],
+        from: usize,
+        ops: &[OpKind],
+    ) -> Option<Vec<OpKind>> {
+        let [op] = ops else {
+            return None;
+        };
+        match op {
+            OpKind::Insert { text, .. } if text.is_empty() => return None,
+            OpKind::Delete { len: 0, .. } => return None,
+            _ => {}
+        }
+        self.sync(log);
+        let blocks = self.blocks(from, log.len());
+        Some(match op {
+            OpKind::Insert { pos, text } => vec![OpKind::Insert {
+                pos: blocks.iter().fold(*pos, |pos, cs| map_insert(cs, pos)),
+                text: text.clone(),
+            }],
+            OpKind::Delete { pos, len } => {
+                let ranges = blocks.iter().fold(vec![(*pos, pos + len)], |ranges, cs| {
+                    map_ranges(cs, &ranges)
+                });
+                // Each range moves back by what the ones before it removed.
+                let mut removed = 0;
+                ranges
+                    .into_iter()
+                    .map(|(start, end)| {
+                        let op = OpKind::Delete {
+                            pos: start - removed,
+                            len: end - start,
+                        };
+                        removed += end - start;
+                        op
+                    })
+                    .collect()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{compose_ops, transform_pair};
+    use proptest::prelude::*;
+
+    fn replay(log: &[Vec<OpKind>], from: usize, ops: &[OpKind]) -> Vec<OpKind> {
+        log[from..]
+            .iter()
+            .fold(ops.to_vec(), |ops, prev| transform_pair(&ops, prev, true).0)
+    }
+
+    fn insert(pos: usize, text: &str) -> OpKind {
+        OpKind::Insert {
+            pos,
+            text: text.into(),
+        }
+    }
+
+    #[test]
+    fn ties_follow_the_order_of_the_history() {
+        // Deleting "e" and then typing "x" there, or the other way round.
+        let delete_then_type = vec![
+            vec![OpKind::Delete { pos: 4, len: 1 }],
+            vec![insert(4, "x")],
+        ];
+        let type_then_delete = vec![
+            vec![insert(4, "x")],
+            vec![OpKind::Delete { pos: 5, len: 1 }],
+        ];
+        for log in [delete_then_type, type_then_delete] {
+            let mut index = RevIndex::default();
+            for op in [
+                insert(5, "c"),
+                insert(4, "c"),
+                OpKind::Delete { pos: 3, len: 3 },
+            ] {
+                let ops = [op];
+                assert_eq!(
+                    index.transform(&log, 0, &ops),
+                    Some(replay(&log, 0, &ops)),
+                    "{log:?} {ops:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_up_with_the_log_and_covers_ranges_with_few_blocks() {
+        let mut log: Vec<Vec<OpKind>> = Vec::new();
+        let mut index = RevIndex::default();
+        for rev in 0..100 {
+            log.push(vec![insert(rev, "a")]);
+            index.sync(&log);
+        }
+        assert_eq!(index.blocks(0, 100).len(), 3);
+        assert_eq!(index.blocks(3, 100).len(), 7);
+        assert_eq!(
+            index.transform(&log, 10, &[insert(10, "b")]),
+            Some(vec![insert(10, "b")])
+        );
+        assert_eq!(
+            index.transform(&log, 10, &[insert(11, "b")]),
+            Some(vec![insert(101, "b")])
+        );
+        // A shorter log means it was rewritten.
+        log.truncate(4);
+        assert_eq!(
+            index.transform(&log, 0, &[insert(2, "b")]),
+            Some(vec![insert(6, "b")])
+        );
+        assert_eq!(index.transform(&log, 0, &[insert(0, "")]), None);
+    }
+
+    fn arb_op() -> impl Strategy<Value = OpKind> {
+        prop_oneof![
+            (0..12usize, "[a-c]{0,3}").prop_map(|(pos, text)| OpKind::Insert { pos, text }),
+            (0..12usize, 0..4usize).prop_map(|(pos, len)| OpKind::Delete { pos, len }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn rebases_like_the_replay(
+            log in prop::collection::vec(prop::collection::vec(arb_op(), 0..3), 0..40),
+            op in arb_op(),
+            from in 0..40usize,
+        ) {
+            let from = from.min(log.len());
+            let ops = [op];
+            let mut index = RevIndex::default();
+            if let Some(indexed) = index.transform(&log, from, &ops) {
+                // The replay may leave a delete in adjacent pieces.
+                prop_assert_eq!(compose_ops(indexed), compose_ops(replay(&log, from, &ops)));
+            }
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Review of what changed between two revisions, as rendered HTML rather
+//! than source lines. Markdown is compared block by block (paragraphs,
+//! headings, list items, code blocks) and each block is rendered on its
+//! own; other content types are compared line by line. Raw HTML in a
+//! document is shown as text, so the review can be embedded as is.
+
+use pulldown_cmark::{Event, Options, Parser, Tag, html};
+use serde::Deserialize;
+
+use crate::{
+    content_type::escape_html,
+    diff::{Run, diff_runs},
+    document::{Doc, apply_ops},
+    replay::{ReplayItem, replay_history},
+    state::{AppState, Rejection},
+    types::ContentType,
+};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewView {
+    /// Removed blocks right before the blocks that replaced them.
+    #[default]
+    Unified,
+    /// A table with the old revision on the left and the new on the right.
+    SideBySide,
+}
+
+/// The content of `slug` at `from` and at `to`, read back from its history.
+pub fn contents_at(
+    state: &AppState,
+    slug: &str,
+    from: u64,
+    to: u64,
+) -> anyhow::Result<(String, String)> {
+    let mut doc = Doc::default();
+    let (mut old, mut new) = (None, None);
+    let mut capture = |doc: &Doc| {
+        if doc.rev == from {
+            old = Some(doc.content.clone());
+        }
+        if doc.rev == to {
+            new = Some(doc.content.clone());
+        }
+    };
+    replay_history(state, slug, |item| {
+        match item {
+            ReplayItem::Start(start) => {
+                doc.rev = start.rev;
+                doc.content = start.content;
+            }
+            ReplayItem::Edit(edit) => {
+                apply_ops(&mut doc, &edit.ops);
+                doc.rev = edit.rev;
+            }
+        }
+        capture(&doc);
+        doc.rev < to
+    })?;
+    match (old, new) {
+        (Some(old), Some(new)) => Ok((old, new)),
+        _ => Err(Rejection::new(
+            "revision_unavailable",
+            "the history no longer has one of these revisions",
+        )
+        .into()),
+    }
+}
+
+/// Top-level blocks of Markdown, as their source, with the items of a
+/// top-level list as blocks of their own.
+fn markdown_blocks(content: &str) -> Vec<&str> {
+    let mut blocks = Vec::new();
+    let mut depth = 0usize;
+    let mut in_list = false;
+    for (event, range) in Parser::new_ext(content, Options::all()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::List(_)) if depth == 0 => {
+                in_list = true;
+                depth += 1;
+            }
+            Event::Start(_) => {
+                if depth == 0 || (depth == 1 && in_list) {
+                    blocks.push(content[range].trim_end());
+                }
+                depth += 1;
+            }
+            Event::End(_) => {
+                depth = depth.saturating_sub(1);
+                in_list &= depth > 0;
+            }
+            // Blocks without an end, such as rules and raw HTML.
+            _ if depth == 0 => blocks.push(content[range].trim_end()),
+            _ => {}
+        }
+    }
+    blocks
+}
+
+fn render_markdown(block: &str) -> String {
+    let events = Parser::new_ext(block, Options::all()).map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+        Event::Start(Tag::HtmlBlock) => Event::Start(Tag::Paragraph),
+        Event::End(pulldown_cmark::TagEnd::HtmlBlock) => {
+            Event::End(pulldown_cmark::TagEnd::Paragraph)
+        }
+        event => event,
+    });
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    out
+}
+
+/// An HTML diff of `old` and `new`, with `same`, `removed` and `added`
+/// classes for styling.
+pub fn review_html(content_type: &ContentType, old: &str, new: &str, view: ReviewView) -> String {
+    let (a, b): (Vec<&str>, Vec<&str>) = match content_type {
+        ContentType::Markdown => (markdown_blocks(old), markdown_blocks(new)),
+        _ => (old.lines().collect(), new.lines().collect()),
+    };
+    let render = |block: &str| match content_type {
+        ContentType::Markdown => render_markdown(block),
+        _ => format!("<pre>{}</pre>", escape_html(block)),
+    };
+    let mut out = String::new();
+    match view {
+        ReviewView::Unified => {
+            out.push_str("<div class=\"coedit-review unified\">\n");
+            for run in diff_runs(&a, &b) {
+                let blocks = match run {
+                    Run::Same(x, _) => a[x].iter().map(|block| ("same", *block)).collect(),
+                    Run::Changed(x, y) => {
+                        let removed = a[x].iter().map(|block| ("removed", *block));
+                        let added = b[y].iter().map(|block| ("added", *block));
+                        removed.chain(added).collect::<Vec<_>>()
+                    }
+                };
+                for (class, block) in blocks {
+                    out.push_str(&format!(
+                        "<div class=\"{}\">{}</div>\n",
+                        class,
+                        render(block)
+                    ));
+                }
+            }
+            out.push_str("</div>\n");
+        }
+        ReviewView::SideBySide => {
+            out.push_str("<table class=\"coedit-review side-by-side\">\n");
+            let cell = |class: &str, block: Option<&&str>| match block {
+                Some(block) => format!("<td class=\"{}\">{}</td>", class, render(block)),
+                None => "<td class=\"empty\"></td>".to_string(),
+            };
+            for run in diff_runs(&a, &b) {
+                match run {
+                    Run::Same(x, _) => {
+                        for block in &a[x] {
+                            let html = render(block);
+                            out.push_str(&format!(
+                                "<tr class=\"same\"><td>{}</td><td>{}</td></tr>\n",
+                                html, html
+                            ));
+                        }
+                    }
+                    Run::Changed(x, y) => {
+                        let (removed, added) = (&a[x], &b[y]);
+                        for i in 0..removed.len().max(added.len()) {
+                            out.push_str(&format!(
+                                "<tr class=\"changed\">{}{}</tr>\n",
+                                cell("removed", removed.get(i)),
+                                cell("added", added.get(i))
+                            ));
+                        }
+                    }
+                }
+            }
+            out.push_str("</table>\n");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state::{apply_edit, get_or_load_doc},
+        types::{Edit, OpKind},
+    };
+    use uuid::Uuid;
+
+    #[test]
+    fn changed_blocks_are_rendered_on_both_sides() {
+        let old = "# Plan\n\nShip it.\n\n- a\n- b\n\n<script>x</script>\n";
+        let new = "# Plan\n\nShip it **today**.\n\n- a\n- c\n\n<script>x</script>\n";
+        let unified = review_html(&ContentType::Markdown, old, new, ReviewView::Unified);
+        assert!(unified.contains("<div class=\"same\"><h1>Plan</h1>\n</div>"));
+        assert!(unified.contains("<div class=\"removed\"><p>Ship it.</p>\n</div>"));
+        assert!(
+            unified.contains("<div class=\"added\"><p>Ship it <strong>today</strong>.</p>\n</div>")
+        );
+        assert!(unified.contains("<div class=\"same\"><ul>\n<li>a</li>\n</ul>\n</div>"));
+        assert!(unified.contains("<div class=\"added\"><ul>\n<li>c</li>\n</ul>\n</div>"));
+        assert!(!unified.contains("<script>"));
+        assert!(unified.contains("&lt;script&gt;"));
+
+        let side = review_html(&ContentType::Markdown, old, new, ReviewView::SideBySide);
+        assert!(side.contains(
+            "<tr class=\"changed\"><td class=\"removed\"><p>Ship it.</p>\n</td>\
+             <td class=\"added\"><p>Ship it <strong>today</strong>.</p>\n</td></tr>"
+        ));
+        let lines = review_html(
+            &ContentType::Plaintext,
+            "a\nb",
+            "a\nc\nd",
+            ReviewView::SideBySide,
+        );
+        assert!(lines.contains("<td class=\"added\"><pre>d</pre></td>"));
+        assert!(lines.contains("<td class=\"empty\"></td>"));
+    }
+
+    #[tokio::test]
+    async fn revisions_are_read_back_from_history() {
+        let base = std::env::temp_dir().join(format!("review-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        for (rev, text) in ["one", " two", " three"].into_iter().enumerate() {
+            let doc = get_or_load_doc(&state, "notes").await.unwrap();
+            let pos = doc.read().content.chars().count();
+            let edit = Edit {
+                base_rev: rev as u64,
+                ops: vec![OpKind::Insert {
+                    pos,
+                    text: text.into(),
+                }],
+                client_id: None,
+                op_id: Some(Uuid::new_v4()),
+                cursor_before: None,
+                cursor_after: None,
+                ts: None,
+                group_id: None,
+                user_id: None,
+            };
+            apply_edit(&state, "notes", edit).await.unwrap();
+        }
+        let (old, new) = contents_at(&state, "notes", 1, 3).unwrap();
+        assert_eq!((old.as_str(), new.as_str()), ("one", "one two three"));
+        assert_eq!(contents_at(&state, "notes", 0, 2).unwrap().0, "");
+        assert!(contents_at(&state, "notes", 1, 4).is_err());
+    }
+}
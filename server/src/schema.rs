@@ -0,0 +1,234 @@
+//! JSON Schema and TypeScript definitions of the websocket protocol, derived
+//! from the serde types in [`crate::types`] so clients can be checked against
+//! what the server actually sends and accepts.
+
+use schemars::generate::SchemaSettings;
+use serde_json::{Map, Value, json};
+
+use crate::types::{ClientMsg, Edit, PresenceState, ServerMsg};
+
+/// Every definition reachable from the wire messages, keyed by type name.
+fn definitions() -> Map<String, Value> {
+    let mut generator = SchemaSettings::draft2020_12().into_generator();
+    generator.subschema_for::<ClientMsg>();
+    generator.subschema_for::<ServerMsg>();
+    generator.subschema_for::<Edit>();
+    generator.subschema_for::<PresenceState>();
+    let mut defs = generator.take_definitions(true);
+    // Outbox adds `seq` to every frame it sends.
+    defs.insert(
+        "ServerFrame".into(),
+        json!({
+            "description": "A `ServerMsg` as sent on the socket, numbered per connection.",
+            "allOf": [
+                { "$ref": "#/$defs/ServerMsg" },
+                {
+                    "type": "object",
+                    "properties": { "seq": { "type": "integer", "format": "uint64", "minimum": 0 } },
+                    "required": ["seq"]
+                }
+            ]
+        }),
+    );
+    defs
+}
+
+pub fn json_schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "coedit websocket protocol",
+        "anyOf": [
+            { "$ref": "#/$defs/ClientMsg" },
+            { "$ref": "#/$defs/ServerFrame" }
+        ],
+        "$defs": definitions(),
+    })
+}
+
+pub fn typescript() -> String {
+    let mut out = String::from(
+        "// Generated from server/src/types.rs by `cargo run --bin coedit-schema -- ts`.\n\
+         // Do not edit by hand.\n",
+    );
+    for (name, schema) in definitions() {
+        out.push('\n');
+        push_doc(&mut out, &schema, "");
+        let body = match union_members(&schema) {
+            Some(members) => members
+                .iter()
+                .map(|m| format!("\n  | {}", ts_type(m, "    ")))
+                .collect(),
+            None => format!(" {}", ts_type(&schema, "")),
+        };
+        out.push_str(&format!("export type {} ={}\n", name, body));
+    }
+    out
+}
+
+fn union_members(schema: &Value) -> Option<&Vec<Value>> {
+    schema
+        .get("oneOf")
+        .or_else(|| schema.get("anyOf"))
+        .and_then(Value::as_array)
+}
+
+fn push_doc(out: &mut String, schema: &Value, indent: &str) {
+    if let Some(desc) = schema.get("description").and_then(Value::as_str) {
+        let desc = desc.split_whitespace().collect::<Vec<_>>().join(" ");
+        out.push_str(&format!("{}/** {} */\n", indent, desc));
+    }
+}
+
+fn literal(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+        other => other.to_string(),
+    }
+}
+
+fn ts_type(schema: &Value, indent: &str) -> String {
+    let Some(obj) = schema.as_object() else {
+        return "unknown".into();
+    };
+    if let Some(target) = obj.get("$ref").and_then(Value::as_str) {
+        return target.rsplit('/').next().unwrap_or(target).to_string();
+    }
+    if let Some(value) = obj.get("const") {
+        return literal(value);
+    }
+    if let Some(values) = obj.get("enum").and_then(Value::as_array) {
+        return values.iter().map(literal).collect::<Vec<_>>().join(" | ");
+    }
+    if let Some(members) = union_members(schema) {
+        return members
+            .iter()
+            .map(|m| ts_type(m, indent))
+            .collect::<Vec<_>>()
+            .join(" | ");
+    }
+    if let Some(parts) = obj.get("allOf").and_then(Value::as_array) {
+        return parts
+            .iter()
+            .map(|p| ts_type(p, indent))
+            .collect::<Vec<_>>()
+            .join(" & ");
+    }
+    match obj.get("type") {
+        Some(Value::String(ty)) => primitive(ty, schema, indent),
+        Some(Value::Array(types)) => types
+            .iter()
+            .filter_map(Value::as_str)
+            .map(|ty| primitive(ty, schema, indent))
+            .collect::<Vec<_>>()
+            .join(" | "),
+        _ => "unknown".into(),
+    }
+}
+
+fn primitive(ty: &str, schema: &Value, indent: &str) -> String {
+    match ty {
+        "string" => "string".into(),
+        "integer" | "number" => "number".into(),
+        "boolean" => "boolean".into(),
+        "null" => "null".into(),
+        "array" => {
+            let item = ts_type(&schema["items"], indent);
+            if item.contains(' ') {
+                format!("({})[]", item)
+            } else {
+                format!("{}[]", item)
+            }
+        }
+        "object" => object(schema, indent),
+        _ => "unknown".into(),
+    }
+}
+
+fn object(schema: &Value, indent: &str) -> String {
+    let Some(props) = schema.get("properties").and_then(Value::as_object) else {
+        return "Record<string, unknown>".into();
+    };
+    let required: Vec<&str> = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|r| r.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let inner = format!("{}  ", indent);
+    // Tag fields (`type`, `phase`) first, the rest alphabetically.
+    let mut props: Vec<_> = props.iter().collect();
+    props.sort_by_key(|(_, prop)| prop.get("const").is_none());
+    let mut out = String::from("{\n");
+    for (key, prop) in props {
+        push_doc(&mut out, prop, &inner);
+        let optional = if required.contains(&key.as_str()) {
+            ""
+        } else {
+            "?"
+        };
+        out.push_str(&format!(
+            "{}{}{}: {}\n",
+            inner,
+            key,
+            optional,
+            ts_type(prop, &inner)
+        ));
+    }
+    out.push_str(indent);
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn schema_covers_every_message_variant() {
+        let schema = json_schema();
+        let defs = schema["$defs"].as_object().unwrap();
+        for name in [
+            "ClientMsg",
+            "ServerMsg",
+            "ServerFrame",
+            "Edit",
+            "PresenceState",
+        ] {
+            assert!(defs.contains_key(name), "missing {}", name);
+        }
+        let tags: Vec<&str> = defs["ServerMsg"]["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|v| v["properties"]["type"]["const"].as_str())
+            .collect();
+        assert!(tags.contains(&"applied"));
+        assert!(tags.contains(&"snapshot"));
+        assert!(tags.contains(&"op_broadcast"));
+    }
+
+    #[test]
+    fn typescript_renders_tagged_unions_and_optional_fields() {
+        let ts = typescript();
+        assert!(ts.contains("export type ClientMsg =\n  | {\n"));
+        assert!(ts.contains("      type: 'edit'\n"));
+        assert!(ts.contains("export type OpKind ="));
+        assert!(ts.contains("  ops: OpKind[]\n"));
+        assert!(ts.contains("  cursor_before?: CursorState | null\n"));
+        assert!(ts.contains("export type ServerFrame = ServerMsg & {\n  seq: number\n}"));
+    }
+
+    #[test]
+    fn checked_in_web_types_are_current() {
+        // Only present in a full checkout; the server container mounts server/ alone.
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../web/app/lib/protocol.gen.ts");
+        let Ok(existing) = std::fs::read_to_string(&path) else {
+            return;
+        };
+        assert!(
+            existing == typescript(),
+            "{} is stale; regenerate with `cargo run --bin coedit-schema -- ts`",
+            path.display()
+        );
+    }
+}
@@ -0,0 +1,288 @@
+//! Soft locks on Markdown sections. A session claims the section under a
+//! heading with `ClaimSection` and others see the claim in presence. Their
+//! edits inside it draw a `section_claimed` warning, or are refused when
+//! `STRICT_OPS` is on. A claim ends when the session releases it or leaves,
+//! or [`SECTION_CLAIM_TTL_MS`] after it was last claimed.
+
+use uuid::Uuid;
+
+use crate::{
+    document::{Doc, map_through_delete},
+    state::{AppState, Rejection, get_or_load_doc},
+    toc::{Heading, toc},
+    types::{ContentType, OpKind, SectionClaim},
+};
+
+pub const SECTION_CLAIM_TTL_MS: u64 = 5 * 60_000;
+pub const SECTION_CLAIMED: &str = "section_claimed";
+
+/// Every heading as `(start, end, text)`, in document order.
+pub(crate) fn sections(content: &str) -> Vec<(usize, usize, String)> {
+    fn flatten(headings: Vec<Heading>, out: &mut Vec<(usize, usize, String)>) {
+        for heading in headings {
+            out.push((heading.start, heading.end, heading.text));
+            flatten(heading.children, out);
+        }
+    }
+    let mut out = Vec::new();
+    flatten(toc(content), &mut out);
+    out
+}
+
+/// Drops claims that lapsed or whose heading is gone, and brings the end
+/// and text of the others up to date.
+fn refresh(doc: &mut Doc, now: u64) {
+    if doc.sections.is_empty() {
+        return;
+    }
+    let headings = sections(&doc.content);
+    doc.sections.retain(|_, claim| {
+        if claim.expires_at <= now {
+            return false;
+        }
+        match headings.iter().find(|(start, ..)| *start == claim.start) {
+            Some((_, end, text)) => {
+                claim.end = *end;
+                claim.heading = text.clone();
+                true
+            }
+            None => false,
+        }
+    });
+}
+
+/// Claims the section under the heading at `start` for `client_id`,
+/// replacing any claim it held.
+pub async fn claim_section(
+    state: &AppState,
+    slug: &str,
+    client_id: Uuid,
+    start: usize,
+    now: u64,
+) -> anyhow::Result<SectionClaim> {
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let mut d = doc_arc.write();
+    if d.meta.content_type.clone().unwrap_or_default() != ContentType::Markdown {
+        return Err(Rejection::new("no_section", "only Markdown documents have sections").into());
+    }
+    let Some((_, end, heading)) = sections(&d.content)
+        .into_iter()
+        .find(|(at, ..)| *at == start)
+    else {
+        return Err(Rejection::new("no_section", "no heading starts there").into());
+    };
+    refresh(&mut d, now);
+    if let Some(other) = d
+        .sections
+        .iter()
+        .find(|(id, claim)| **id != client_id && claim.start < end && start < claim.end)
+        .map(|(_, claim)| claim)
+    {
+        return Err(Rejection::new(
+            SECTION_CLAIMED,
+            format!("\"{}\" is claimed by another session", other.heading),
+        )
+        .into());
+    }
+    let claim = SectionClaim {
+        heading,
+        start,
+        end,
+        expires_at: now + SECTION_CLAIM_TTL_MS,
+    };
+    d.sections.insert(client_id, claim.clone());
+    Ok(claim)
+}
+
+/// Ends the claim of `client_id`, if it held one.
+pub fn release_section(state: &AppState, slug: &str, client_id: Uuid) -> bool {
+    let loaded = state.docs.read().get(slug).cloned();
+    loaded.is_some_and(|doc| doc.write().sections.remove(&client_id).is_some())
+}
+
+/// The first claim of another session that `ops`, about to be applied,
+/// write into. Inserting at the very end of a section belongs to the next
+/// one, except at the end of the document.
+pub fn claimed_by_other(
+    doc: &mut Doc,
+    client_id: Option<Uuid>,
+    ops: &[OpKind],
+    now: u64,
+) -> Option<SectionClaim> {
+    refresh(doc, now);
+    let mut claims: Vec<(SectionClaim, usize, usize)> = doc
+        .sections
+        .iter()
+        .filter(|(id, _)| Some(**id) != client_id)
+        .map(|(_, claim)| (claim.clone(), claim.start, claim.end))
+        .collect();
+    if claims.is_empty() {
+        return None;
+    }
+    let mut len = doc.content.chars().count();
+    for op in ops {
+        for (claim, start, end) in &mut claims {
+            let inside = match op {
+                OpKind::Insert { pos, .. } => {
+                    *start < *pos && (*pos < *end || (*pos == *end && *end == len))
+                }
+                OpKind::Delete { pos, len } => *pos < *end && pos + len > *start,
+            };
+            if inside {
+                return Some(claim.clone());
+            }
+            (*start, *end) = (shift_start(*start, op), shift_end(*end, op));
+        }
+        len = match op {
+            OpKind::Insert { text, .. } => len + text.chars().count(),
+            OpKind::Delete { len: n, .. } => len.saturating_sub(*n),
+        };
+    }
+    None
+}
+
+/// Text inserted right at a heading goes before it.
+fn shift_start(start: usize, op: &OpKind) -> usize {
+    match op {
+        OpKind::Insert { pos, text } if *pos <= start => start + text.chars().count(),
+        OpKind::Insert { .. } => start,
+        OpKind::Delete { pos, len } => map_through_delete(start, *pos, *len),
+    }
+}
+
+fn shift_end(end: usize, op: &OpKind) -> usize {
+    match op {
+        OpKind::Insert { pos, text } if *pos < end => end + text.chars().count(),
+        OpKind::Insert { .. } => end,
+        OpKind::Delete { pos, len } => map_through_delete(end, *pos, *len),
+    }
+}
+
+/// Moves the claims along with applied `ops`; ends are recomputed when they
+/// are next checked.
+pub fn shift_sections(doc: &mut Doc, ops: &[OpKind]) {
+    for claim in doc.sections.values_mut() {
+        for op in ops {
+            claim.start = shift_start(claim.start, op);
+            claim.end = shift_end(claim.end, op);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state::{apply_edit, now_millis},
+        types::{Edit, ServerMsg},
+    };
+
+    const SPEC: &str = "# Spec\nintro\n## API\ncalls\n## UI\nscreens\n";
+
+    fn edit(client_id: Uuid, base_rev: u64, ops: Vec<OpKind>) -> Edit {
+        Edit {
+            base_rev,
+            ops,
+            client_id: Some(client_id),
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    fn insert(pos: usize, text: &str) -> OpKind {
+        OpKind::Insert {
+            pos,
+            text: text.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn claims_warn_or_refuse_edits_inside_and_follow_the_heading() {
+        let base = std::env::temp_dir().join(format!("sections-{}", Uuid::new_v4()));
+        let mut state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let (ann, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        apply_edit(&state, "spec", edit(ann, 0, vec![insert(0, SPEC)]))
+            .await
+            .unwrap();
+        let api = SPEC.find("## API").unwrap();
+        let ui = SPEC.find("## UI").unwrap();
+
+        let now = now_millis();
+        let claim = claim_section(&state, "spec", ann, api, now).await.unwrap();
+        assert_eq!((claim.heading.as_str(), claim.end), ("API", ui));
+        let code = |err: anyhow::Error| err.downcast_ref::<Rejection>().unwrap().code;
+        // The top section contains Ann's.
+        let err = claim_section(&state, "spec", bob, 0, now)
+            .await
+            .unwrap_err();
+        assert_eq!(code(err), SECTION_CLAIMED);
+        let err = claim_section(&state, "spec", bob, 3, now)
+            .await
+            .unwrap_err();
+        assert_eq!(code(err), "no_section");
+        claim_section(&state, "spec", bob, ui, now).await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        state.subs.write().insert("spec".into(), vec![tx.into()]);
+        // Bob's section moves down when Ann adds a line above it.
+        apply_edit(
+            &state,
+            "spec",
+            edit(ann, 1, vec![insert(api + 7, "more\n")]),
+        )
+        .await
+        .unwrap();
+        assert!(
+            rx.try_recv()
+                .is_ok_and(|m| matches!(m, ServerMsg::Applied { .. }))
+        );
+        assert!(rx.try_recv().is_err());
+        apply_edit(&state, "spec", edit(bob, 2, vec![insert(api + 7, "x")]))
+            .await
+            .unwrap();
+        rx.try_recv().unwrap();
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMsg::Warning { code, .. }) if code == SECTION_CLAIMED
+        ));
+
+        state.strict_ops = true;
+        let ui = ui + "more\nx".len();
+        let err = apply_edit(&state, "spec", edit(ann, 3, vec![insert(ui + 6, "y")]))
+            .await
+            .unwrap_err();
+        assert_eq!(code(err), SECTION_CLAIMED);
+        // Appending after the last section is writing into it.
+        let len = state.docs.read()["spec"].read().content.chars().count();
+        let err = apply_edit(&state, "spec", edit(ann, 3, vec![insert(len, "z")]))
+            .await
+            .unwrap_err();
+        assert_eq!(code(err), SECTION_CLAIMED);
+
+        assert!(release_section(&state, "spec", bob));
+        apply_edit(&state, "spec", edit(ann, 3, vec![insert(len, "z")]))
+            .await
+            .unwrap();
+        // Ann's claim holds until it lapses.
+        let err = apply_edit(&state, "spec", edit(bob, 4, vec![insert(api + 7, "w")]))
+            .await
+            .unwrap_err();
+        assert_eq!(code(err), SECTION_CLAIMED);
+        let doc = state.docs.read()["spec"].clone();
+        let later = now + SECTION_CLAIM_TTL_MS;
+        let ops = [insert(api + 7, "w")];
+        assert!(claimed_by_other(&mut doc.write(), Some(bob), &ops, later).is_none());
+        assert!(doc.read().sections.is_empty());
+    }
+}
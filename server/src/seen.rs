@@ -0,0 +1,108 @@
+//! Read receipts: the last revision each named user has seen of a document,
+//! kept in the metadata sidecar so listings can count what is unread.
+
+use uuid::Uuid;
+
+use crate::{
+    notifications::clear_notifications,
+    state::{AppState, get_or_load_doc},
+    storage::{load_meta, persist_meta},
+};
+
+/// Moves the mark of `user_id` on `slug` up to `rev`, capped at the head.
+/// Marks never move back. Returns the mark when it moved.
+pub async fn mark_seen(
+    state: &AppState,
+    slug: &str,
+    user_id: Uuid,
+    rev: u64,
+) -> anyhow::Result<Option<u64>> {
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let (meta, mark) = {
+        let mut d = doc_arc.write();
+        let mark = rev.min(d.rev);
+        if d.meta.seen.get(&user_id).is_some_and(|seen| *seen >= mark) {
+            return Ok(None);
+        }
+        d.meta.seen.insert(user_id, mark);
+        (d.meta.clone(), mark)
+    };
+    persist_meta(state, slug, &meta)?;
+    clear_notifications(state, user_id, Some(slug), Some(mark));
+    Ok(Some(mark))
+}
+
+/// Revisions of `slug` after the mark of `user_id`; all of them when the user
+/// never reported one. Unloaded documents count up to their last flush.
+pub fn unread_revisions(state: &AppState, slug: &str, user_id: Uuid) -> anyhow::Result<u64> {
+    let loaded = state.docs.read().get(slug).cloned();
+    let (rev, meta) = match loaded {
+        Some(doc) => {
+            let d = doc.read();
+            (d.rev, d.meta.clone())
+        }
+        None => {
+            let meta = load_meta(state, slug)?.unwrap_or_default();
+            (meta.snapshot_rev, meta)
+        }
+    };
+    let seen = meta.seen.get(&user_id).copied().unwrap_or(0);
+    Ok(rev.saturating_sub(seen))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state::{apply_edit, unload_doc},
+        storage::flush_snapshot_force,
+        types::{Edit, OpKind},
+    };
+
+    fn insert(base_rev: u64, text: &str) -> Edit {
+        Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn marks_only_move_forward_and_survive_unloading() {
+        let base = std::env::temp_dir().join(format!("seen-{}", Uuid::new_v4()));
+        let state = AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let (ann, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        for rev in 0..3 {
+            apply_edit(&state, "inbox", insert(rev, "x")).await.unwrap();
+        }
+        assert_eq!(mark_seen(&state, "inbox", ann, 9).await.unwrap(), Some(3));
+        assert_eq!(mark_seen(&state, "inbox", ann, 1).await.unwrap(), None);
+        assert_eq!(mark_seen(&state, "inbox", bob, 1).await.unwrap(), Some(1));
+        apply_edit(&state, "inbox", insert(3, "y")).await.unwrap();
+
+        flush_snapshot_force(&state, "inbox").await.unwrap();
+        unload_doc(&state, "inbox", "test");
+        assert_eq!(unread_revisions(&state, "inbox", ann).unwrap(), 1);
+        assert_eq!(unread_revisions(&state, "inbox", bob).unwrap(), 3);
+        assert_eq!(
+            unread_revisions(&state, "inbox", Uuid::new_v4()).unwrap(),
+            4
+        );
+    }
+}
@@ -1,23 +1,65 @@
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    fs,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
     path::PathBuf,
     sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc;
 use tracing::warn;
 use uuid::Uuid;
 
 use crate::{
-    document::{Doc, apply_ops, transform_ops},
+    alerts::{AlertPolicy, DEFAULT_ALERT_INTERVAL_MS, DocAlerts},
+    analysis::{Analyzer, shift_diagnostics},
+    assist::{AssistConfig, AssistLimiter},
+    auth::{is_owner, required_password_hash},
+    cluster::{Cluster, owns},
+    conflicts::{ConflictEvent, conflict_span, record_conflict},
+    connections::Connection,
+    degraded::{DEFAULT_WAL_BUFFER_CAP, WalHealth, append_or_hold, check_wal_capacity},
+    digest::{DigestTarget, DocDigest, record_change},
+    disk::{DEFAULT_MIN_FREE_BYTES, DiskWatch, admit_write},
+    document::{
+        Doc, InvalidOp, apply_ops, bump_version, check_consistency, check_ops_strict, content_hash,
+        doc_stats, rebase_cursor, shapes, transform_ops,
+    },
+    expiry::{ExpiryIndex, is_expired},
+    git::{GitStore, record_activity},
+    handlers::{poll::PollSessions, ws::WebTransportInfo},
+    idempotency::IdempotencyStore,
+    ingest::IngestDir,
+    jobs::JobStore,
+    lines::{apply_ops_tracking_lines, line_edit_to_edit},
+    links::LinkIndex,
+    metrics::{
+        LifecycleMetrics, record_clock_skew, record_edit, record_invalid_op, record_load,
+        record_unload,
+    },
+    notifications::notify_followers,
     presence::update_presence_cursor,
+    quota::check_quota,
+    reload::LogFilterReloader,
+    replica::ReplicaConfig,
+    retention::{DAY_MS, RetentionPolicy},
+    sections::{SECTION_CLAIMED, claimed_by_other, shift_sections},
     storage::{
-        flush_snapshot_if_needed, password_path, slug_to_rel_path, snapshot_path, wal_append_event,
-        wal_path,
+        WalLock, doc_exists_on_disk, flush_snapshot_if_needed, hash_password, load_meta,
+        load_op_ids, load_password_hash, persist_meta, persist_password_hash, read_snapshot,
+        read_wal, slug_to_rel_path, wal_lock,
     },
-    types::{DocEvent, Edit, ServerMsg, WalLine},
+    subscription::{MessageClass, Subscriber},
+    tags::TagIndex,
+    ticket::TicketStore,
+    transform::TransformService,
+    trash::DEFAULT_TRASH_RETENTION_DAYS,
+    types::{DocEvent, DocStats, Edit, LineEdit, LineOp, Notification, OpKind, ServerMsg},
+    validation::{
+        Candidate, RuleAction, RuleStage, ValidationHook, Violation, has_checks, rejection,
+        validate,
+    },
+    wal_replay::Replayer,
+    workspace::{WorkspaceSettings, workspace_settings_for},
 };
 
 #[derive(Debug, Default)]
@@ -25,18 +67,140 @@ pub struct DocPresence {
     pub clients: HashMap<Uuid, crate::types::PresenceState>,
 }
 
+/// Settings that can change while the server runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveConfig {
+    pub allowed_origins: Vec<String>,
+    pub flush_idle_ms: u64,
+    pub flush_max_ops: usize,
+}
+
 #[derive(Clone)]
 pub struct AppState {
     pub docs: Arc<RwLock<HashMap<String, Arc<RwLock<Doc>>>>>,
-    pub subs: Arc<RwLock<HashMap<String, Vec<mpsc::UnboundedSender<ServerMsg>>>>>,
+    pub subs: Arc<RwLock<HashMap<String, Vec<Subscriber>>>>,
+    pub watchers: Arc<RwLock<HashMap<String, Vec<Watcher>>>>,
     pub presence: Arc<RwLock<HashMap<String, DocPresence>>>,
+    /// The documents each client is present in, kept in step with
+    /// `presence`.
+    pub client_docs: Arc<RwLock<HashMap<Uuid, BTreeSet<String>>>>,
     pub wal_dir: PathBuf,
     pub snap_dir: PathBuf,
-    pub flush_idle_ms: u64,
-    pub flush_max_ops: usize,
+    pub archive_dir: PathBuf,
+    /// Where deleted documents wait for [`crate::trash`] to purge them.
+    pub trash_dir: PathBuf,
+    /// Days a deleted document stays restorable; 0 deletes outright.
+    pub trash_retention_days: u64,
+    /// Password hashes, kept out of the snapshot tree so backups and
+    /// exports of it carry no credentials.
+    pub secrets_dir: PathBuf,
+    pub archive_compress: bool,
+    pub compress_storage: bool,
+    pub live: Arc<RwLock<LiveConfig>>,
+    /// Where [`reload_config`](crate::reload::reload_config) reads settings
+    /// from, ahead of the environment.
+    pub config_file: Option<PathBuf>,
+    pub log_filter: Option<LogFilterReloader>,
+    /// A tenant's own allowed origins, kept over `APP_ALLOWED_ORIGINS`
+    /// whenever the live settings are reloaded.
+    pub own_origins: Option<Vec<String>>,
+    /// The tenants served next to the instance, reloaded along with it.
+    pub tenants: Arc<Vec<AppState>>,
     pub app_env_dev: bool,
     pub recent_ops: Arc<RwLock<HashMap<String, RecentOps>>>,
-    pub allowed_origins: Vec<String>,
+    /// One per document; see [`wal_lock`](crate::storage::wal_lock).
+    pub wal_locks: Arc<Mutex<HashMap<String, Arc<WalLock>>>>,
+    pub flush_locks: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    pub invite_only: bool,
+    pub admin_token: Option<String>,
+    pub workspaces: Arc<RwLock<HashMap<String, WorkspaceSettings>>>,
+    /// Bytes all documents together may take on disk, on top of the
+    /// per-workspace quotas; set for tenants, see [`crate::tenants`].
+    pub quota_bytes: Option<u64>,
+    pub usage: Arc<RwLock<HashMap<String, u64>>>,
+    /// Built on first use; see [`crate::links`].
+    pub links: Arc<RwLock<Option<LinkIndex>>>,
+    /// Built on first use; see [`crate::tags`].
+    pub tags: Arc<RwLock<Option<TagIndex>>>,
+    /// Built on first use; see [`crate::expiry`].
+    pub expiries: Arc<RwLock<Option<ExpiryIndex>>>,
+    pub digest_target: Option<DigestTarget>,
+    pub digest_interval_ms: u64,
+    pub digest_pending: Arc<RwLock<HashMap<String, DocDigest>>>,
+    /// Queued per user; see [`crate::notifications`].
+    pub notifications: Arc<RwLock<HashMap<Uuid, Vec<Notification>>>>,
+    /// Running WebSocket sessions; see [`crate::connections`].
+    pub connections: Arc<RwLock<HashMap<Uuid, Arc<Connection>>>>,
+    /// Recent conflicts per document; see [`crate::conflicts`].
+    pub conflicts: Arc<RwLock<HashMap<String, VecDeque<ConflictEvent>>>>,
+    /// Labels of named users, for resolving mentions; see
+    /// [`crate::mentions`].
+    pub mention_names: Arc<RwLock<HashMap<String, Uuid>>>,
+    pub metrics: Arc<LifecycleMetrics>,
+    pub validation_hooks: Vec<Arc<dyn ValidationHook>>,
+    /// Run after each flush; see [`crate::analysis`].
+    pub analyzers: Vec<Arc<dyn Analyzer>>,
+    /// Off while unset; see [`crate::assist`].
+    pub assist: Option<AssistConfig>,
+    pub assist_limiter: Arc<AssistLimiter>,
+    /// Named transforms besides the built-in ones; see [`crate::transform`].
+    pub transforms: Vec<TransformService>,
+    pub retention: RetentionPolicy,
+    pub retention_interval_ms: u64,
+    /// `Applied` carries a content hash every this many revisions; 0 turns
+    /// hashes off.
+    pub hash_interval: u64,
+    /// `Applied` carries [`DocStats`] every this many revisions; 0 leaves
+    /// them to `ClientMsg::Stats`.
+    pub stats_interval: u64,
+    pub ws_tickets: TicketStore,
+    /// Password-protected documents only accept upgrades that present a
+    /// ticket; raw passwords on the WebSocket are ignored.
+    pub require_ws_ticket: bool,
+    pub jobs: JobStore,
+    /// Set when this instance is a read replica of another one.
+    pub replica: Option<ReplicaConfig>,
+    /// Set when documents are spread over several nodes.
+    pub cluster: Option<Arc<Cluster>>,
+    /// Edits whose ops do not fit the document they were written against
+    /// are refused instead of being clamped by the rebase.
+    pub strict_ops: bool,
+    pub idempotency: IdempotencyStore,
+    /// Client timestamps further than this from the server clock are
+    /// replaced by the server time.
+    pub max_clock_skew_ms: u64,
+    /// Frames of at least this many bytes are compressed for sessions that
+    /// negotiated `compression`; 0 turns compression off.
+    pub ws_compress_threshold: usize,
+    /// An idle socket gets an empty ping frame this often; 0 turns pings off.
+    pub ws_ping_interval_ms: u64,
+    /// Accept the first subprotocol a client asks for, for proxies and
+    /// client libraries that insist on one.
+    pub ws_echo_protocol: bool,
+    /// How long a session whose password went stale stays connected,
+    /// read-only, to present the new one.
+    pub reauth_grace_ms: u64,
+    pub wal_health: Arc<WalHealth>,
+    /// WAL entries held in memory while the WAL cannot be written.
+    pub wal_buffer_cap: usize,
+    /// Free space below which new documents and large edits are refused;
+    /// `0` turns the check off.
+    pub min_free_bytes: u64,
+    pub disk: Arc<DiskWatch>,
+    /// Markdown files the documents are written to and read back from; see
+    /// [`crate::ingest`].
+    pub mirror: Option<Arc<IngestDir>>,
+    /// Commits every snapshot; see [`crate::git`].
+    pub git: Option<Arc<GitStore>>,
+    /// Thresholds documents are checked against; see [`crate::alerts`].
+    pub alert_policy: AlertPolicy,
+    pub alert_interval_ms: u64,
+    pub alerts: Arc<Mutex<HashMap<String, DocAlerts>>>,
+    /// Sessions of the HTTP fallback transport; see
+    /// [`crate::handlers::poll`].
+    pub poll_sessions: PollSessions,
+    /// Advertised by `/api/ws-config` while the WebTransport listener runs.
+    pub webtransport: Option<WebTransportInfo>,
 }
 
 impl AppState {
@@ -51,18 +215,117 @@ impl AppState {
         Self {
             docs: Arc::new(RwLock::new(HashMap::new())),
             subs: Arc::new(RwLock::new(HashMap::new())),
+            watchers: Arc::new(RwLock::new(HashMap::new())),
             presence: Arc::new(RwLock::new(HashMap::new())),
+            client_docs: Arc::new(RwLock::new(HashMap::new())),
+            archive_dir: snap_dir.with_file_name("archive"),
+            trash_dir: snap_dir.with_file_name(".trash"),
+            trash_retention_days: DEFAULT_TRASH_RETENTION_DAYS,
+            secrets_dir: snap_dir.with_file_name("secrets"),
+            archive_compress: true,
+            compress_storage: false,
             wal_dir,
             snap_dir,
-            flush_idle_ms,
-            flush_max_ops,
+            live: Arc::new(RwLock::new(LiveConfig {
+                allowed_origins,
+                flush_idle_ms,
+                flush_max_ops,
+            })),
+            config_file: None,
+            log_filter: None,
+            own_origins: None,
+            tenants: Default::default(),
             app_env_dev,
             recent_ops: Arc::new(RwLock::new(HashMap::new())),
-            allowed_origins,
+            wal_locks: Default::default(),
+            flush_locks: Default::default(),
+            invite_only: false,
+            admin_token: None,
+            workspaces: Arc::new(RwLock::new(HashMap::new())),
+            quota_bytes: None,
+            usage: Arc::new(RwLock::new(HashMap::new())),
+            links: Default::default(),
+            tags: Default::default(),
+            expiries: Default::default(),
+            digest_target: None,
+            digest_interval_ms: 24 * 60 * 60 * 1000,
+            digest_pending: Arc::new(RwLock::new(HashMap::new())),
+            notifications: Arc::new(RwLock::new(HashMap::new())),
+            mention_names: Arc::new(RwLock::new(HashMap::new())),
+            conflicts: Arc::new(RwLock::new(HashMap::new())),
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            metrics: Arc::new(LifecycleMetrics::default()),
+            validation_hooks: Vec::new(),
+            analyzers: Vec::new(),
+            assist: None,
+            assist_limiter: Default::default(),
+            transforms: Vec::new(),
+            retention: RetentionPolicy::default(),
+            retention_interval_ms: DAY_MS,
+            hash_interval: DEFAULT_HASH_INTERVAL,
+            stats_interval: 0,
+            ws_tickets: Default::default(),
+            require_ws_ticket: false,
+            jobs: Default::default(),
+            replica: None,
+            cluster: None,
+            strict_ops: false,
+            idempotency: Default::default(),
+            max_clock_skew_ms: DEFAULT_MAX_CLOCK_SKEW_MS,
+            ws_compress_threshold: DEFAULT_WS_COMPRESS_THRESHOLD,
+            ws_ping_interval_ms: DEFAULT_WS_PING_INTERVAL_MS,
+            ws_echo_protocol: false,
+            reauth_grace_ms: DEFAULT_REAUTH_GRACE_MS,
+            wal_health: Default::default(),
+            wal_buffer_cap: DEFAULT_WAL_BUFFER_CAP,
+            min_free_bytes: DEFAULT_MIN_FREE_BYTES,
+            disk: Default::default(),
+            mirror: None,
+            git: None,
+            alert_policy: AlertPolicy::default(),
+            alert_interval_ms: DEFAULT_ALERT_INTERVAL_MS,
+            alerts: Default::default(),
+            poll_sessions: Default::default(),
+            webtransport: None,
         }
     }
 }
 
+/// An edit or request refused for a reason the client should be told about,
+/// as opposed to an internal failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub code: &'static str,
+    pub message: String,
+    /// The offending op, for [`INVALID_OP`] rejections.
+    pub invalid_op: Option<InvalidOp>,
+}
+
+/// Rejection code for edits that show the client's copy no longer matches
+/// the server's; the client is sent a resync.
+pub const DIVERGED: &str = "diverged";
+/// Rejection code for edits refused by strict op validation; the client is
+/// sent a resync as well.
+pub const INVALID_OP: &str = "invalid_op";
+
+impl Rejection {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            invalid_op: None,
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
 #[derive(Default)]
 pub struct RecentOps {
     set: HashSet<Uuid>,
@@ -71,6 +334,12 @@ pub struct RecentOps {
 }
 
 pub const RECENT_OPS_CAP: usize = 4096;
+pub const DEFAULT_HASH_INTERVAL: u64 = 32;
+pub const DEFAULT_MAX_CLOCK_SKEW_MS: u64 = 30_000;
+pub const DEFAULT_WS_COMPRESS_THRESHOLD: usize = 64 * 1024;
+/// Below the 60 s idle timeout common reverse proxies apply.
+pub const DEFAULT_WS_PING_INTERVAL_MS: u64 = 25_000;
+pub const DEFAULT_REAUTH_GRACE_MS: u64 = 30_000;
 
 impl RecentOps {
     pub fn new(cap: usize) -> Self {
@@ -101,6 +370,11 @@ impl RecentOps {
             false
         }
     }
+
+    /// Remembered ids, oldest first.
+    pub fn ids(&self) -> impl Iterator<Item = &Uuid> {
+        self.order.iter()
+    }
 }
 
 pub fn now_millis() -> u64 {
@@ -110,17 +384,114 @@ pub fn now_millis() -> u64 {
         .as_millis() as u64
 }
 
+/// The timestamp to keep for something a client stamped with `client_ts`:
+/// the client's own clock while it agrees with ours, the server time when
+/// it is missing or off by more than `max_clock_skew_ms`.
+pub fn clamp_client_ts(
+    state: &AppState,
+    slug: &str,
+    client_id: Option<Uuid>,
+    client_ts: Option<u64>,
+    server_now: u64,
+) -> u64 {
+    match client_ts {
+        Some(ts) if ts.abs_diff(server_now) <= state.max_clock_skew_ms => ts,
+        Some(ts) => {
+            record_clock_skew(state, slug, client_id, ts as i64 - server_now as i64);
+            server_now
+        }
+        None => server_now,
+    }
+}
+
+/// Sends `msg` to every socket joined to `slug` that subscribed to its
+/// class. Watchers only get `Applied`.
 pub fn broadcast(state: &AppState, slug: &str, msg: ServerMsg) {
-    let mut subs = state.subs.write();
-    if let Some(list) = subs.get_mut(slug) {
-        let mut i = 0;
-        while i < list.len() {
-            let ok = list[i].send(msg.clone()).is_ok();
-            if ok {
-                i += 1;
-            } else {
-                list.remove(i);
-            }
+    if let Some(list) = state.subs.write().get_mut(slug) {
+        list.retain(|sub| !sub.wants(&msg) || sub.send(msg.clone()));
+    }
+    if matches!(msg, ServerMsg::Applied { .. })
+        && let Some(list) = state.watchers.write().get_mut(slug)
+    {
+        list.retain(|w| w.tx.send(msg.clone()).is_ok());
+    }
+}
+
+/// Applies the filter a socket sent with its `Hello`.
+pub fn set_subscription(
+    state: &AppState,
+    slug: &str,
+    tx: &mpsc::UnboundedSender<ServerMsg>,
+    client_id: Uuid,
+    classes: Option<Vec<MessageClass>>,
+) {
+    if let Some(sub) = state
+        .subs
+        .write()
+        .get_mut(slug)
+        .and_then(|list| list.iter_mut().find(|sub| sub.tx.same_channel(tx)))
+    {
+        sub.client_id = Some(client_id);
+        sub.classes = classes;
+    }
+}
+
+/// A socket following another document's `Applied` through `Watch`.
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    pub tx: mpsc::UnboundedSender<ServerMsg>,
+    /// The password hash that opened the document when the watch began,
+    /// checked again whenever its access changes.
+    pub credential: Option<String>,
+}
+
+pub fn add_watcher(
+    state: &AppState,
+    slug: &str,
+    tx: &mpsc::UnboundedSender<ServerMsg>,
+    credential: Option<String>,
+) {
+    let mut watchers = state.watchers.write();
+    let list = watchers.entry(slug.to_string()).or_default();
+    match list.iter_mut().find(|w| w.tx.same_channel(tx)) {
+        Some(watcher) => watcher.credential = credential,
+        None => list.push(Watcher {
+            tx: tx.clone(),
+            credential,
+        }),
+    }
+}
+
+/// Stops the watches of `slug` whose credential no longer opens it, telling
+/// each socket with `unauthorized`. `required` is the hash it takes now.
+pub fn recheck_watchers(state: &AppState, slug: &str, required: Option<&str>) {
+    let mut watchers = state.watchers.write();
+    let Some(list) = watchers.get_mut(slug) else {
+        return;
+    };
+    list.retain(|w| {
+        if required.is_none() || w.credential.as_deref() == required {
+            return true;
+        }
+        let _ = w.tx.send(ServerMsg::Error {
+            slug: slug.to_string(),
+            code: "unauthorized".into(),
+            message: "the document password changed; watch it again".into(),
+            op_id: None,
+        });
+        false
+    });
+    if list.is_empty() {
+        watchers.remove(slug);
+    }
+}
+
+pub fn remove_watcher(state: &AppState, slug: &str, tx: &mpsc::UnboundedSender<ServerMsg>) {
+    let mut watchers = state.watchers.write();
+    if let Some(list) = watchers.get_mut(slug) {
+        list.retain(|w| !w.tx.same_channel(tx));
+        if list.is_empty() {
+            watchers.remove(slug);
         }
     }
 }
@@ -142,152 +513,408 @@ pub fn remember_op_id(state: &AppState, slug: &str, op_id: Uuid) -> bool {
     ro.insert(op_id)
 }
 
+pub fn recent_op_ids(state: &AppState, slug: &str) -> Vec<Uuid> {
+    state
+        .recent_ops
+        .read()
+        .get(slug)
+        .map(|ro| ro.ids().copied().collect())
+        .unwrap_or_default()
+}
+
+pub fn doc_exists(state: &AppState, slug: &str) -> anyhow::Result<bool> {
+    slug_to_rel_path(slug)?;
+    if state.docs.read().contains_key(slug) {
+        return Ok(true);
+    }
+    doc_exists_on_disk(state, slug)
+}
+
+/// Like [`get_or_load_doc`], but refuses to implicitly create documents when
+/// the server runs in invite-only mode.
+pub async fn get_existing_doc(
+    state: &AppState,
+    slug: &str,
+) -> anyhow::Result<Option<Arc<RwLock<Doc>>>> {
+    if state.invite_only && !doc_exists(state, slug)? {
+        return Ok(None);
+    }
+    get_or_load_doc(state, slug).await.map(Some)
+}
+
 pub async fn get_or_load_doc(state: &AppState, slug: &str) -> anyhow::Result<Arc<RwLock<Doc>>> {
     slug_to_rel_path(slug)?;
     if let Some(d) = state.docs.read().get(slug).cloned() {
         return Ok(d);
     }
+    // A delete holds this while it unloads the document and moves its
+    // files, so loading waits instead of reading them halfway.
+    let wal = wal_lock(state, slug);
+    let _wal = wal.lock();
     let mut docs = state.docs.write();
     if let Some(d) = docs.get(slug).cloned() {
         return Ok(d);
     }
 
+    let started = Instant::now();
+    let (doc, wal_edit_count) = read_doc(state, slug)?;
+    if needs_password(state, slug, &doc)? && !doc_exists_on_disk(state, slug)? {
+        return Err(password_required());
+    }
+    let d = Arc::new(RwLock::new(doc));
+    docs.insert(slug.to_string(), d.clone());
+    record_load(state, slug, started.elapsed(), wal_edit_count);
+    Ok(d)
+}
+
+/// Creates `slug`, refused with `exists` when it is loaded or on disk.
+/// `init` fills in the new document before anyone else can load it; it
+/// still has to be written out.
+pub fn create_new_doc(
+    state: &AppState,
+    slug: &str,
+    init: impl FnOnce(&mut Doc),
+) -> anyhow::Result<Arc<RwLock<Doc>>> {
+    slug_to_rel_path(slug)?;
+    let mut docs = state.docs.write();
+    if docs.contains_key(slug) || doc_exists_on_disk(state, slug)? {
+        return Err(Rejection::new("exists", "document already exists").into());
+    }
+    let (mut doc, _) = read_doc(state, slug)?;
+    init(&mut doc);
+    if needs_password(state, slug, &doc)? {
+        return Err(password_required());
+    }
+    let d = Arc::new(RwLock::new(doc));
+    docs.insert(slug.to_string(), d.clone());
+    Ok(d)
+}
+
+/// Whether `doc` lacks the password its workspace requires of documents.
+fn needs_password(state: &AppState, slug: &str, doc: &Doc) -> anyhow::Result<bool> {
+    Ok(required_password_hash(doc).is_none()
+        && workspace_settings_for(state, slug)?.is_some_and(|ws| ws.require_password))
+}
+
+fn password_required() -> anyhow::Error {
+    Rejection::new(
+        "password_required",
+        "documents in this workspace need a password",
+    )
+    .into()
+}
+
+/// Builds `slug` from its files on disk: snapshot, WAL replay, password and
+/// workspace settings. Returns the document and how many WAL edits it still
+/// has to flush.
+pub fn read_doc(state: &AppState, slug: &str) -> anyhow::Result<(Doc, usize)> {
     let mut doc = Doc::default();
+    match load_meta(state, slug) {
+        Ok(Some(meta)) => doc.meta = meta,
+        Ok(None) => {}
+        Err(err) => warn!("failed to read metadata for slug '{}': {:#}", slug, err),
+    }
+    let snapshot_rev = doc.meta.snapshot_rev;
+    let mut versions = doc.meta.versions.clone();
+    let keep_versions = !versions.is_empty();
     let mut wal_edit_count = 0usize;
     let mut wal_last_ts = 0u64;
-    let snap_path = snapshot_path(state, slug)?;
-    if let Ok(content) = fs::read_to_string(&snap_path) {
+    if let Some(content) = read_snapshot(state, slug)? {
         doc.content = content;
     }
-    let wal_path = wal_path(state, slug)?;
-    if let Ok(data) = fs::read_to_string(&wal_path) {
-        let mut seen: HashSet<Uuid> = HashSet::new();
-        for line in data.lines() {
-            let trimmed = line.trim();
-            if trimmed.is_empty() {
-                continue;
-            }
-            match serde_json::from_str::<WalLine>(trimmed) {
-                Ok(WalLine::V2(entry)) => match entry.event {
-                    DocEvent::Edit { mut edit } => {
-                        if edit.ts.is_none() {
-                            edit.ts = Some(entry.ts);
-                        }
-                        if let Some(id) = edit.op_id {
-                            if seen.contains(&id) {
-                                continue;
-                            } else {
-                                seen.insert(id);
-                            }
-                        }
-                        let ops2 = transform_ops(&doc, &edit);
-                        apply_ops(&mut doc, &ops2);
-                        doc.rev += 1;
-                        doc.log.push(ops2);
-                        wal_edit_count += 1;
-                        wal_last_ts = wal_last_ts.max(entry.ts);
-                    }
-                    DocEvent::Cursor { op_id, .. } | DocEvent::Ime { op_id, .. } => {
-                        if let Some(id) = op_id {
-                            seen.insert(id);
-                        }
-                    }
-                },
-                Ok(WalLine::V1(edit)) => {
-                    let legacy = edit;
-                    if let Some(id) = legacy.op_id {
-                        if seen.contains(&id) {
-                            continue;
-                        } else {
-                            seen.insert(id);
-                        }
-                    }
-                    let ops2 = transform_ops(&doc, &legacy);
-                    apply_ops(&mut doc, &ops2);
-                    doc.rev += 1;
-                    doc.log.push(ops2);
-                    wal_edit_count += 1;
-                    if let Some(ts) = legacy.ts {
-                        wal_last_ts = wal_last_ts.max(ts);
-                    }
-                }
-                Err(err) => {
-                    warn!("failed to parse wal entry for slug '{}': {:#}", slug, err);
+    let mut replayer = Replayer::new(doc, snapshot_rev);
+    if let Some(data) = read_wal(state, slug)? {
+        for replayed in replayer.replay_lines(&data) {
+            let replayed = match replayed {
+                Ok(replayed) => replayed,
+                Err((line, err)) => {
+                    warn!(
+                        "failed to parse wal line {} for slug '{}': {}",
+                        line, slug, err
+                    );
+                    continue;
                 }
+            };
+            // Edits up to `snapshot_rev` are already counted in the version
+            // vector unless the metadata predates it.
+            if replayed.step.applied || !keep_versions {
+                bump_version(&mut versions, replayed.edit.client_id);
+            }
+            // Compaction only squashes snapshotted edits, so those are never
+            // left to flush.
+            if replayed.step.applied {
+                wal_edit_count += 1;
+                wal_last_ts = wal_last_ts.max(replayed.server_ts.unwrap_or(replayed.ts));
             }
         }
         if wal_edit_count > 0 && wal_last_ts == 0 {
             wal_last_ts = now_millis();
         }
-        if !seen.is_empty() {
-            let mut map = state.recent_ops.write();
-            let ro = map
-                .entry(slug.to_string())
-                .or_insert_with(|| RecentOps::new(RECENT_OPS_CAP));
-            for id in seen {
-                ro.insert(id);
-            }
+    }
+    let Replayer { mut doc, seen, .. } = replayer;
+    doc.versions = versions;
+    // Ids of edits compacted out of the WAL only survive in the op id file.
+    // They seed the dedup set but never hold back WAL replay above.
+    let persisted = load_op_ids(state, slug).unwrap_or_else(|err| {
+        warn!("failed to read op ids for slug '{}': {:#}", slug, err);
+        Vec::new()
+    });
+    if !persisted.is_empty() || !seen.is_empty() {
+        let mut map = state.recent_ops.write();
+        let ro = map
+            .entry(slug.to_string())
+            .or_insert_with(|| RecentOps::new(RECENT_OPS_CAP));
+        for id in persisted.into_iter().chain(seen) {
+            ro.insert(id);
         }
     }
     if wal_edit_count > 0 {
         doc.since_flush = wal_edit_count;
         doc.last_edit_ts = wal_last_ts;
     }
-    let pwd_path = password_path(state, slug)?;
-    if let Ok(hash) = fs::read_to_string(&pwd_path) {
-        doc.password_hash = Some(hash.trim().to_string());
+    doc.password_hash = load_password_hash(state, slug)?;
+    match workspace_settings_for(state, slug) {
+        Ok(Some(ws)) => doc.inherited_password_hash = ws.default_password_hash,
+        Ok(None) => {}
+        Err(err) => warn!("failed to read workspace for slug '{}': {:#}", slug, err),
     }
-    let d = Arc::new(RwLock::new(doc));
-    docs.insert(slug.to_string(), d.clone());
-    Ok(d)
+    Ok((doc, wal_edit_count))
+}
+
+/// Drops a document from memory. Callers flush first; the next access
+/// rehydrates it from disk.
+pub fn unload_doc(state: &AppState, slug: &str, reason: &str) {
+    if state.docs.write().remove(slug).is_some() {
+        record_unload(state, slug, reason);
+    }
+    state.recent_ops.write().remove(slug);
 }
 
 pub async fn apply_edit(state: &AppState, slug: &str, mut edit: Edit) -> anyhow::Result<()> {
-    let ts = edit.ts.unwrap_or_else(now_millis);
+    let started = Instant::now();
+    let server_now = now_millis();
+    let ts = clamp_client_ts(state, slug, edit.client_id, edit.ts, server_now);
     edit.ts = Some(ts);
+    if !owns(state, slug) {
+        return Err(Rejection::new("not_owner", "document is owned by another node").into());
+    }
     let doc_arc = get_or_load_doc(state, slug).await?;
     if let Some(op_id) = edit.op_id
         && op_id_seen(state, slug, &op_id)
     {
-        let d = doc_arc.read();
-        broadcast(
-            state,
-            slug,
-            ServerMsg::Applied {
-                slug: slug.to_string(),
-                rev: d.rev,
-                ops: vec![],
-                client_id: edit.client_id,
-                op_id: Some(op_id),
-                ts,
-            },
-        );
+        let (rev, line_ops) = {
+            let d = doc_arc.read();
+            (d.rev, d.line_log.as_ref().map(|_| Vec::new()))
+        };
+        broadcast_applied(state, slug, rev, vec![], line_ops, None, None, &edit, ts);
         return Ok(());
     }
+    if doc_arc.read().meta.archived_at.is_some() {
+        return Err(Rejection::new("archived", "document is archived").into());
+    }
+    if doc_arc.read().meta.settings.read_only == Some(true) {
+        return Err(Rejection::new("read_only", "document is locked").into());
+    }
+    if is_expired(&doc_arc.read().meta, server_now) {
+        return Err(Rejection::new("expired", "document has expired").into());
+    }
+    check_wal_capacity(state)?;
 
-    let to_broadcast = {
-        let mut d = doc_arc.write();
-        let ops2 = transform_ops(&d, &edit);
-        if !ops2.is_empty() {
-            apply_ops(&mut d, &ops2);
-            d.rev += 1;
-            d.log.push(ops2.clone());
-            d.since_flush += 1;
-            d.last_edit_ts = ts;
-            (d.rev, ops2, edit.client_id)
-        } else {
-            (d.rev, vec![], edit.client_id)
-        }
+    let inserted: usize = edit
+        .ops
+        .iter()
+        .map(|op| match op {
+            OpKind::Insert { text, .. } => text.len(),
+            OpKind::Delete { .. } => 0,
+        })
+        .sum();
+    let creating = {
+        let d = doc_arc.read();
+        d.rev == 0 && d.content.is_empty()
     };
+    admit_write(state, slug, inserted as u64, creating)?;
+    if inserted > 0 {
+        check_quota(state, slug, inserted as u64)?;
+    }
+    let validated = has_checks(state, slug, RuleStage::Edit)?;
 
-    wal_append_event(state, slug, &DocEvent::Edit { edit: edit.clone() }, ts)?;
-    let _ = flush_snapshot_if_needed(state, slug).await?;
+    let mut warnings = Vec::new();
+    let cursor_after;
+    let wal = wal_lock(state, slug);
+    let to_broadcast = {
+        // Held until the edit is in the WAL, so edits land there in revision
+        // order and a delete cannot move the files away in between.
+        let _wal = wal.lock();
+        let to_broadcast = {
+            let mut d = doc_arc.write();
+            if d.deleted {
+                return Err(Rejection::new("deleted", "document was deleted").into());
+            }
+            if state.strict_ops
+                && let Err(invalid) = check_ops_strict(&d, &edit)
+            {
+                record_invalid_op(state, slug, edit.client_id, &invalid);
+                return Err(Rejection {
+                    code: INVALID_OP,
+                    message: invalid.to_string(),
+                    invalid_op: Some(invalid),
+                }
+                .into());
+            }
+            let ops2 = transform_ops(&d, &edit);
+            if let Err(message) = check_consistency(&d, &edit, &ops2) {
+                return Err(Rejection::new(DIVERGED, message).into());
+            }
+            if edit.base_rev < d.rev
+                && let Some(span) = conflict_span(&d, &edit)
+            {
+                record_conflict(state, slug, &d, span, server_now);
+            }
+            if let Some(claim) = claimed_by_other(&mut d, edit.client_id, &ops2, server_now) {
+                let message = format!("\"{}\" is claimed by another session", claim.heading);
+                if state.strict_ops {
+                    return Err(Rejection::new(SECTION_CLAIMED, message).into());
+                }
+                warnings.push(Violation {
+                    rule: SECTION_CLAIMED.to_string(),
+                    message,
+                    action: RuleAction::Warn,
+                });
+            }
+            cursor_after = edit
+                .cursor_after
+                .as_ref()
+                .map(|cursor| rebase_cursor(&d, &edit, cursor));
+            let max_bytes = d.meta.settings.max_bytes.filter(|_| inserted > 0);
+            if (validated || max_bytes.is_some()) && !ops2.is_empty() {
+                let mut candidate = Doc {
+                    content: d.content.clone(),
+                    ..Default::default()
+                };
+                apply_ops(&mut candidate, &ops2);
+                if let Some(max) = max_bytes
+                    && candidate.content.len() as u64 > max
+                {
+                    return Err(Rejection::new(
+                        "doc_too_large",
+                        format!(
+                            "document would be {} bytes, over its {} byte limit",
+                            candidate.content.len(),
+                            max
+                        ),
+                    )
+                    .into());
+                }
+                if validated {
+                    warnings.extend(validate(
+                        state,
+                        &Candidate {
+                            slug,
+                            content: &candidate.content,
+                            content_type: &d.meta.content_type.clone().unwrap_or_default(),
+                            stage: RuleStage::Edit,
+                        },
+                    )?);
+                    if let Some(rejection) = rejection(&warnings) {
+                        return Err(rejection.into());
+                    }
+                }
+            }
+            if !ops2.is_empty() {
+                let line_ops = apply_ops_tracking_lines(&mut d, &ops2);
+                shift_sections(&mut d, &ops2);
+                shift_diagnostics(&mut d.diagnostics, &ops2);
+                d.rev += 1;
+                d.log.push(shapes(&ops2));
+                bump_version(&mut d.versions, edit.client_id);
+                d.since_flush += 1;
+                // Idle flushing compares against our own clock.
+                d.last_edit_ts = server_now;
+                let hash = (state.hash_interval > 0 && d.rev % state.hash_interval == 0)
+                    .then(|| (content_hash(&d.content), d.content.chars().count()));
+                let stats = (state.stats_interval > 0 && d.rev % state.stats_interval == 0)
+                    .then(|| doc_stats(&d.content));
+                (d.rev, ops2, line_ops, hash, stats)
+            } else {
+                let line_ops = d.line_log.as_ref().map(|_| Vec::new());
+                (d.rev, vec![], line_ops, None, None)
+            }
+        };
+        append_or_hold(state, slug, &DocEvent::Edit { edit: edit.clone() }, ts)?;
+        to_broadcast
+    };
+    // Presence wants the cursor where it ended up, not where the client put it.
+    edit.cursor_after = cursor_after;
 
     if let Some(op_id) = edit.op_id {
         remember_op_id(state, slug, op_id);
     }
 
-    let (rev, ops, cid) = to_broadcast;
+    let (rev, ops, line_ops, hash, stats) = to_broadcast;
+    if !ops.is_empty() {
+        record_change(state, slug, &edit);
+        record_activity(state, slug, &edit);
+        let followers = doc_arc.read().meta.followers.clone();
+        notify_followers(state, slug, &followers, &edit, rev, ts);
+    }
+    record_edit(slug, edit.client_id, rev, started.elapsed());
+    broadcast_applied(state, slug, rev, ops, line_ops, hash, stats, &edit, ts);
+    broadcast_warnings(state, slug, &warnings, edit.op_id);
+
+    propagate_presence_after_edit(state, slug, &edit, ts);
+    // Everyone has the edit by now, so a slow snapshot only holds up this
+    // caller. The WAL already has it, so a failed flush does not fail it.
+    if !state.wal_health.is_degraded()
+        && let Err(err) = flush_snapshot_if_needed(state, slug).await
+    {
+        warn!(%slug, "snapshot flush failed: {:#}", err);
+    }
+    Ok(())
+}
+
+/// Tells everyone on `slug` about rule violations that did not block the
+/// edit or flush.
+pub fn broadcast_warnings(
+    state: &AppState,
+    slug: &str,
+    violations: &[Violation],
+    op_id: Option<Uuid>,
+) {
+    for violation in violations {
+        broadcast(
+            state,
+            slug,
+            ServerMsg::Warning {
+                slug: slug.to_string(),
+                code: violation.rule.clone(),
+                message: violation.message.clone(),
+                op_id,
+            },
+        );
+    }
+}
+
+/// Rebases a line edit onto the current revision and applies it like any
+/// other edit.
+pub async fn apply_line_edit(state: &AppState, slug: &str, edit: LineEdit) -> anyhow::Result<()> {
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let edit = line_edit_to_edit(&mut doc_arc.write(), edit)?;
+    apply_edit(state, slug, edit).await
+}
+
+/// Sends `Applied`, and `LineApplied` when the document keeps a line log.
+#[allow(clippy::too_many_arguments)]
+fn broadcast_applied(
+    state: &AppState,
+    slug: &str,
+    rev: u64,
+    ops: Vec<OpKind>,
+    line_ops: Option<Vec<LineOp>>,
+    hash: Option<(u32, usize)>,
+    stats: Option<DocStats>,
+    edit: &Edit,
+    ts: u64,
+) {
     broadcast(
         state,
         slug,
@@ -295,16 +922,138 @@ pub async fn apply_edit(state: &AppState, slug: &str, mut edit: Edit) -> anyhow:
             slug: slug.to_string(),
             rev,
             ops,
-            client_id: cid,
+            client_id: edit.client_id,
             op_id: edit.op_id,
             ts,
+            hash: hash.map(|(hash, _)| hash),
+            chars: hash.map(|(_, chars)| chars),
+            stats,
+            group_id: edit.group_id,
+            user_id: edit.user_id,
         },
     );
+    if let Some(ops) = line_ops {
+        broadcast(
+            state,
+            slug,
+            ServerMsg::LineApplied {
+                slug: slug.to_string(),
+                rev,
+                ops,
+                client_id: edit.client_id,
+                op_id: edit.op_id,
+                ts,
+                group_id: edit.group_id,
+                user_id: edit.user_id,
+            },
+        );
+    }
+}
 
-    propagate_presence_after_edit(state, slug, &edit, ts);
+pub enum OwnerClaim {
+    /// Only claim documents that have never been written to.
+    NewDocument,
+    /// Claim any document that has no owner yet.
+    Unowned,
+}
+
+/// Records the caller as the document owner and returns the freshly issued
+/// owner token, or `None` when the document is not claimable.
+pub async fn claim_ownership(
+    state: &AppState,
+    slug: &str,
+    mode: OwnerClaim,
+) -> anyhow::Result<Option<String>> {
+    let doc_arc = get_or_load_doc(state, slug).await?;
+    let (token, meta) = {
+        let mut d = doc_arc.write();
+        if d.meta.owner_hash.is_some() {
+            return Ok(None);
+        }
+        if matches!(mode, OwnerClaim::NewDocument)
+            && (d.rev > 0 || !d.content.is_empty() || d.password_hash.is_some())
+        {
+            return Ok(None);
+        }
+        let token = Uuid::new_v4().simple().to_string();
+        d.meta.owner_hash = Some(hash_password(&token));
+        (token, d.meta.clone())
+    };
+    persist_meta(state, slug, &meta)?;
+    Ok(Some(token))
+}
+
+/// Sets the password of `doc`, or removes it when `new` is empty. An owned
+/// document takes the owner token; otherwise `current` has to match the
+/// password in place, and an unowned document without one must be claimed
+/// first. Refusals are [`Rejection`]s. On success the document's sessions
+/// are told, with `by` as the client that made the change.
+pub fn change_password(
+    state: &AppState,
+    slug: &str,
+    doc: &RwLock<Doc>,
+    current: &str,
+    new: &str,
+    owner_token: Option<&str>,
+    by: Option<Uuid>,
+) -> anyhow::Result<()> {
+    let require_password = workspace_settings_for(state, slug)?
+        .is_some_and(|ws| ws.require_password && ws.default_password_hash.is_none());
+    if require_password && new.is_empty() {
+        return Err(Rejection::new(
+            "password_required",
+            "workspace requires a document password",
+        )
+        .into());
+    }
+    let new_hash = {
+        let mut d = doc.write();
+        if d.meta.owner_hash.is_some() {
+            if !is_owner(&d, owner_token) {
+                return Err(Rejection::new("owner_required", "owner credentials required").into());
+            }
+        } else if d.password_hash.is_none() && !new.is_empty() {
+            return Err(Rejection::new(
+                "claim_required",
+                "claim document ownership before setting a password",
+            )
+            .into());
+        } else if match &d.password_hash {
+            Some(expected) => hash_password(current) != *expected,
+            None => !current.is_empty(),
+        } {
+            return Err(Rejection::new("invalid_password", "invalid current password").into());
+        }
+        let new_hash = (!new.is_empty()).then(|| hash_password(new));
+        d.password_hash = new_hash.clone();
+        new_hash
+    };
+    persist_password_hash(state, slug, new_hash.as_deref())?;
+    publish_access_change(state, slug, doc, by);
     Ok(())
 }
 
+/// Bumps the access version of `doc` and sends `AccessChanged` to its
+/// sessions, each of which checks whether the credential it connected with
+/// still opens the document; watchers whose credential no longer does are
+/// dropped. `by` is the session that made the change.
+pub fn publish_access_change(state: &AppState, slug: &str, doc: &RwLock<Doc>, by: Option<Uuid>) {
+    let (msg, required) = {
+        let mut d = doc.write();
+        d.access_version += 1;
+        let msg = ServerMsg::AccessChanged {
+            slug: slug.to_string(),
+            version: d.access_version,
+            client_id: by,
+            protected: required_password_hash(&d).is_some(),
+            writable: d.meta.settings.read_only != Some(true) && !is_expired(&d.meta, now_millis()),
+        };
+        (msg, required_password_hash(&d).map(str::to_string))
+    };
+    recheck_watchers(state, slug, required.as_deref());
+    broadcast(state, slug, msg);
+}
+
 fn propagate_presence_after_edit(state: &AppState, slug: &str, edit: &Edit, ts: u64) {
     if let (Some(cid), Some(cursor_after)) = (edit.client_id, edit.cursor_after.clone()) {
         let server_now = now_millis();
@@ -339,7 +1088,11 @@ fn propagate_presence_after_edit(state: &AppState, slug: &str, edit: &Edit, ts:
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{CursorState, DocEvent, Edit, ImeEvent, OpKind, TextRange};
+    use crate::{
+        document::OpShape,
+        types::{CursorState, ImeEvent, TextRange, VersionVector},
+    };
+    use std::fs;
     use std::{io::Write, path::Path};
 
     fn mk_state(tmp: &Path) -> AppState {
@@ -350,6 +1103,246 @@ mod tests {
         AppState::new(wal_dir, snap_dir, 10_000, 1_000_000, true, Vec::new())
     }
 
+    #[tokio::test]
+    async fn watchers_receive_only_applied() {
+        let base = std::env::temp_dir().join(format!("srvtest-watch-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        add_watcher(&state, "w", &tx, None);
+        add_watcher(&state, "w", &tx, None);
+
+        broadcast(
+            &state,
+            "w",
+            ServerMsg::PresenceDiff {
+                slug: "w".into(),
+                added: vec![],
+                updated: vec![],
+                removed: vec![],
+            },
+        );
+        let edit = Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: "x".into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: Some(Uuid::nil()),
+            user_id: None,
+        };
+        apply_edit(&state, "w", edit.clone()).await.unwrap();
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            ServerMsg::Applied { rev: 1, group_id: Some(id), .. } if id.is_nil()
+        ));
+        assert!(rx.try_recv().is_err());
+
+        remove_watcher(&state, "w", &tx);
+        apply_edit(&state, "w", edit).await.unwrap();
+        assert!(rx.try_recv().is_err());
+        assert!(state.watchers.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn workspaces_requiring_passwords_refuse_implicit_creation() {
+        let base = std::env::temp_dir().join(format!("srvtest-require-pw-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        let settings = WorkspaceSettings {
+            require_password: true,
+            ..Default::default()
+        };
+        crate::workspace::save_workspace(&state, "team", &settings).unwrap();
+        let code = |err: anyhow::Error| err.downcast::<Rejection>().unwrap().code;
+
+        let err = get_or_load_doc(&state, "team/new").await.unwrap_err();
+        assert_eq!(code(err), "password_required");
+        assert!(state.docs.read().is_empty());
+        let err = create_new_doc(&state, "team/new", |_| {}).unwrap_err();
+        assert_eq!(code(err), "password_required");
+
+        create_new_doc(&state, "team/new", |d| {
+            d.password_hash = Some(hash_password("pw"));
+        })
+        .unwrap();
+        let err = create_new_doc(&state, "team/new", |_| {}).unwrap_err();
+        assert_eq!(code(err), "exists");
+        // Documents outside the workspace are created as before.
+        get_or_load_doc(&state, "loose").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn access_changes_drop_watchers_that_lost_access() {
+        let base = std::env::temp_dir().join(format!("srvtest-rewatch-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        let doc = get_or_load_doc(&state, "w").await.unwrap();
+        doc.write().password_hash = Some(hash_password("old"));
+        let (kept_tx, mut kept) = mpsc::unbounded_channel();
+        let (open_tx, mut open) = mpsc::unbounded_channel();
+        add_watcher(&state, "w", &kept_tx, Some(hash_password("old")));
+        add_watcher(&state, "w", &open_tx, None);
+
+        publish_access_change(&state, "w", &doc, None);
+        assert!(matches!(
+            open.try_recv().unwrap(),
+            ServerMsg::Error { code, .. } if code == "unauthorized"
+        ));
+        assert!(kept.try_recv().is_err());
+
+        doc.write().password_hash = Some(hash_password("new"));
+        publish_access_change(&state, "w", &doc, None);
+        assert!(matches!(
+            kept.try_recv().unwrap(),
+            ServerMsg::Error { code, .. } if code == "unauthorized"
+        ));
+        assert!(state.watchers.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn applied_carries_hash_and_stats_every_interval() {
+        let base = std::env::temp_dir().join(format!("srvtest-hash-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.hash_interval = 2;
+        state.stats_interval = 2;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        add_watcher(&state, "h", &tx, None);
+        for (rev, text) in ["é", "ab"].into_iter().enumerate() {
+            let edit = Edit {
+                base_rev: rev as u64,
+                ops: vec![OpKind::Insert {
+                    pos: 0,
+                    text: text.into(),
+                }],
+                client_id: None,
+                op_id: None,
+                cursor_before: None,
+                cursor_after: None,
+                ts: None,
+                group_id: None,
+                user_id: None,
+            };
+            apply_edit(&state, "h", edit).await.unwrap();
+        }
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            ServerMsg::Applied {
+                rev: 1,
+                hash: None,
+                chars: None,
+                stats: None,
+                ..
+            }
+        ));
+        let expected = content_hash("abé");
+        let stats = DocStats {
+            chars: 3,
+            words: 1,
+            lines: 1,
+        };
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            ServerMsg::Applied { rev: 2, hash: Some(hash), chars: Some(3), stats: Some(s), .. }
+                if hash == expected && s == stats
+        ));
+    }
+
+    #[tokio::test]
+    async fn broadcast_skips_classes_a_subscriber_left_out() {
+        let base = std::env::temp_dir().join(format!("srvtest-subscribe-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let (all_tx, mut all_rx) = mpsc::unbounded_channel();
+        let (edits_tx, mut edits_rx) = mpsc::unbounded_channel();
+        state
+            .subs
+            .write()
+            .insert("s".into(), vec![all_tx.into(), edits_tx.clone().into()]);
+        set_subscription(
+            &state,
+            "s",
+            &edits_tx,
+            Uuid::new_v4(),
+            Some(vec![MessageClass::Edits]),
+        );
+
+        broadcast(
+            &state,
+            "s",
+            ServerMsg::Cursor {
+                slug: "s".into(),
+                client_id: Uuid::new_v4(),
+                cursor: CursorState {
+                    position: 0,
+                    anchor: None,
+                    selection_direction: None,
+                },
+                op_id: None,
+                ts: 0,
+            },
+        );
+        let edit = Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: "x".into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(&state, "s", edit).await.unwrap();
+        assert!(matches!(all_rx.try_recv(), Ok(ServerMsg::Cursor { .. })));
+        assert!(matches!(all_rx.try_recv(), Ok(ServerMsg::Applied { .. })));
+        assert!(matches!(edits_rx.try_recv(), Ok(ServerMsg::Applied { .. })));
+        assert!(edits_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn lifecycle_metrics_track_load_flush_unload() {
+        let base = std::env::temp_dir().join(format!("srvtest-metrics-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "m";
+        let edit = Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: "x".into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(&state, slug, edit.clone()).await.unwrap();
+        unload_doc(&state, slug, "test");
+        get_or_load_doc(&state, slug).await.unwrap();
+        crate::storage::flush_snapshot_force(&state, slug)
+            .await
+            .unwrap();
+        unload_doc(&state, "never-loaded", "test");
+
+        let stats = state.metrics.snapshot();
+        assert_eq!(stats.loads, 2);
+        assert_eq!(stats.hydrated_edits, 1);
+        assert_eq!((stats.flushes, stats.flushed_edits), (1, 1));
+        assert_eq!(stats.unloads, 1);
+        assert!(stats.load_ms_p99 >= stats.load_ms_p50);
+    }
+
     #[tokio::test]
     async fn dedup_same_op_id_applies_once() {
         let base = std::env::temp_dir().join(format!("srvtest-{}", Uuid::new_v4()));
@@ -369,6 +1362,8 @@ mod tests {
             cursor_before: None,
             cursor_after: None,
             ts: None,
+            group_id: None,
+            user_id: None,
         };
         apply_edit(&state, slug, e.clone()).await.unwrap();
         let d = get_or_load_doc(&state, slug).await.unwrap();
@@ -391,6 +1386,8 @@ mod tests {
             cursor_before: None,
             cursor_after: None,
             ts: None,
+            group_id: None,
+            user_id: None,
         };
         apply_edit(&state, slug, e2).await.unwrap();
         let d = get_or_load_doc(&state, slug).await.unwrap();
@@ -417,6 +1414,8 @@ mod tests {
             cursor_before: None,
             cursor_after: None,
             ts: None,
+            group_id: None,
+            user_id: None,
         };
         let e2 = Edit {
             base_rev: 1,
@@ -429,6 +1428,8 @@ mod tests {
             cursor_before: None,
             cursor_after: None,
             ts: None,
+            group_id: None,
+            user_id: None,
         };
         let mut f = fs::OpenOptions::new()
             .create(true)
@@ -466,6 +1467,8 @@ mod tests {
             cursor_before: None,
             cursor_after: None,
             ts: None,
+            group_id: None,
+            user_id: None,
         };
 
         crate::storage::wal_append_event(&state, slug, &DocEvent::Edit { edit: mk_edit("a") }, 111)
@@ -483,8 +1486,8 @@ mod tests {
     async fn nested_slug_creates_nested_files() {
         let base = std::env::temp_dir().join(format!("srvtest-nested-{}", Uuid::new_v4()));
         fs::create_dir_all(&base).unwrap();
-        let mut state = mk_state(&base);
-        state.flush_max_ops = 1;
+        let state = mk_state(&base);
+        state.live.write().flush_max_ops = 1;
         let slug = "dir/sub/doc";
 
         let edit = Edit {
@@ -498,6 +1501,8 @@ mod tests {
             cursor_before: None,
             cursor_after: None,
             ts: None,
+            group_id: None,
+            user_id: None,
         };
         apply_edit(&state, slug, edit).await.unwrap();
 
@@ -531,6 +1536,8 @@ mod tests {
             cursor_before: None,
             cursor_after: None,
             ts: None,
+            group_id: None,
+            user_id: None,
         };
         apply_edit(&state, slug, edit).await.unwrap();
 
@@ -580,6 +1587,529 @@ mod tests {
         assert!(ro.contains(&ime_id));
     }
 
+    #[tokio::test]
+    async fn claim_ownership_only_grants_first_creator() {
+        let base = std::env::temp_dir().join(format!("srvtest-owner-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "owned";
+
+        let token = claim_ownership(&state, slug, OwnerClaim::NewDocument)
+            .await
+            .unwrap()
+            .expect("first creator owns the doc");
+        assert!(
+            claim_ownership(&state, slug, OwnerClaim::Unowned)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        state.docs.write().remove(slug);
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert!(crate::auth::is_owner(&doc.read(), Some(&token)));
+    }
+
+    #[tokio::test]
+    async fn change_password_checks_the_current_credentials() {
+        let base = std::env::temp_dir().join(format!("srvtest-chpw-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "guarded";
+        let code = |err: anyhow::Error| err.downcast_ref::<Rejection>().unwrap().code;
+
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        let err = change_password(&state, slug, &doc, "", "pw", None, None).unwrap_err();
+        assert_eq!(code(err), "claim_required");
+
+        doc.write().password_hash = Some(hash_password("old"));
+        let err = change_password(&state, slug, &doc, "wrong", "new", None, None).unwrap_err();
+        assert_eq!(code(err), "invalid_password");
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state.subs.write().insert(slug.into(), vec![tx.into()]);
+        change_password(&state, slug, &doc, "old", "new", None, None).unwrap();
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMsg::AccessChanged {
+                protected: true,
+                client_id: None,
+                ..
+            })
+        ));
+        assert_eq!(
+            load_password_hash(&state, slug).unwrap(),
+            Some(hash_password("new"))
+        );
+        change_password(&state, slug, &doc, "new", "", None, None).unwrap();
+        assert!(doc.read().password_hash.is_none());
+
+        let token = claim_ownership(&state, slug, OwnerClaim::Unowned)
+            .await
+            .unwrap()
+            .unwrap();
+        let err = change_password(&state, slug, &doc, "", "pw", Some("bogus"), None).unwrap_err();
+        assert_eq!(code(err), "owner_required");
+        change_password(&state, slug, &doc, "", "pw", Some(&token), None).unwrap();
+    }
+
+    #[tokio::test]
+    async fn claim_ownership_skips_existing_content_for_new_mode() {
+        let base = std::env::temp_dir().join(format!("srvtest-owner-old-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "legacy";
+        fs::write(
+            crate::storage::snapshot_path(&state, slug).unwrap(),
+            "existing",
+        )
+        .unwrap();
+
+        let granted = claim_ownership(&state, slug, OwnerClaim::NewDocument)
+            .await
+            .unwrap();
+        assert!(granted.is_none());
+        let granted = claim_ownership(&state, slug, OwnerClaim::Unowned)
+            .await
+            .unwrap();
+        assert!(granted.is_some());
+    }
+
+    #[tokio::test]
+    async fn invite_only_mode_does_not_create_docs_implicitly() {
+        let base = std::env::temp_dir().join(format!("srvtest-invite-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.invite_only = true;
+
+        assert!(get_existing_doc(&state, "missing").await.unwrap().is_none());
+        assert!(!state.docs.read().contains_key("missing"));
+
+        fs::write(
+            crate::storage::snapshot_path(&state, "present").unwrap(),
+            "hi",
+        )
+        .unwrap();
+        let doc = get_existing_doc(&state, "present").await.unwrap();
+        assert_eq!(doc.expect("existing doc").read().content, "hi");
+    }
+
+    #[tokio::test]
+    async fn apply_edit_rejects_inserts_over_quota() {
+        let base = std::env::temp_dir().join(format!("srvtest-quota-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        crate::workspace::save_workspace(
+            &state,
+            "team",
+            &WorkspaceSettings {
+                quota_bytes: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let edit = Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: "too much".into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+
+        let err = apply_edit(&state, "team/doc", edit).await.unwrap_err();
+        let rejection = err
+            .downcast_ref::<Rejection>()
+            .expect("structured rejection");
+        assert_eq!(rejection.code, "quota_exceeded");
+        let doc = get_or_load_doc(&state, "team/doc").await.unwrap();
+        assert_eq!(doc.read().rev, 0);
+    }
+
+    #[tokio::test]
+    async fn validation_rules_reject_edits_and_hold_back_flushes() {
+        use crate::validation::{RuleAction, RuleCheck, ValidationRule};
+
+        let base = std::env::temp_dir().join(format!("srvtest-validate-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        state.live.write().flush_max_ops = 1;
+        crate::workspace::save_workspace(
+            &state,
+            "team",
+            &WorkspaceSettings {
+                validation: vec![
+                    ValidationRule {
+                        check: RuleCheck::Forbidden {
+                            pattern: "SECRET".into(),
+                        },
+                        action: RuleAction::Reject,
+                        on: RuleStage::Edit,
+                    },
+                    ValidationRule {
+                        check: RuleCheck::MaxBytes { max_bytes: 4 },
+                        action: RuleAction::Warn,
+                        on: RuleStage::Edit,
+                    },
+                    ValidationRule {
+                        check: RuleCheck::FrontMatter {
+                            keys: vec!["title".into()],
+                        },
+                        action: RuleAction::Reject,
+                        on: RuleStage::Flush,
+                    },
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let slug = "team/doc";
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        state.subs.write().insert(slug.into(), vec![tx.into()]);
+        let insert = |base_rev: u64, pos: usize, text: &str| Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+
+        let err = apply_edit(&state, slug, insert(0, 0, "SECRET"))
+            .await
+            .unwrap_err();
+        let rejection = err.downcast_ref::<Rejection>().expect("rejection");
+        assert_eq!(rejection.code, "validation_failed");
+        assert!(rx.try_recv().is_err());
+
+        apply_edit(&state, slug, insert(0, 0, "hello"))
+            .await
+            .unwrap();
+        let mut codes = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            if let ServerMsg::Warning { code, .. } = msg {
+                codes.push(code);
+            }
+        }
+        // The edit's own warnings go out before the flush that follows it.
+        assert_eq!(codes, vec!["max_bytes", "front_matter"]);
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!(doc.read().since_flush, 1);
+        assert!(
+            !crate::storage::snapshot_path(&state, slug)
+                .unwrap()
+                .exists()
+        );
+
+        let front = "---\ntitle: t\n---\n";
+        apply_edit(&state, slug, insert(1, 0, front)).await.unwrap();
+        assert_eq!(doc.read().since_flush, 0);
+        let snap = crate::storage::snapshot_path(&state, slug).unwrap();
+        assert_eq!(fs::read_to_string(snap).unwrap(), format!("{}hello", front));
+    }
+
+    #[tokio::test]
+    async fn reload_after_flush_does_not_replay_snapshotted_edits() {
+        let base = std::env::temp_dir().join(format!("srvtest-reload-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        state.live.write().flush_max_ops = 1;
+        let slug = "reload";
+        let mk_edit = |base_rev: u64, pos: usize, text: &str| Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(&state, slug, mk_edit(0, 0, "abc"))
+            .await
+            .unwrap();
+        state.live.write().flush_max_ops = 1_000;
+        apply_edit(&state, slug, mk_edit(1, 3, "def"))
+            .await
+            .unwrap();
+
+        state.docs.write().clear();
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        let d = doc.read();
+        assert_eq!(d.content, "abcdef");
+        assert_eq!(d.rev, 2);
+        assert_eq!(d.log.len(), 2);
+        assert_eq!(d.since_flush, 1);
+    }
+
+    #[tokio::test]
+    async fn log_keeps_op_lengths_not_text() {
+        let base = std::env::temp_dir().join(format!("srvtest-logshape-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "logshape";
+        let mk_edit = |base_rev: u64, pos: usize, text: &str| Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        let big = "\u{e9}".repeat(50_000);
+        apply_edit(&state, slug, mk_edit(0, 0, "ab")).await.unwrap();
+        apply_edit(&state, slug, mk_edit(1, 1, &big)).await.unwrap();
+        // Made before the big insert, so rebased across it by length alone.
+        apply_edit(&state, slug, mk_edit(1, 2, "!")).await.unwrap();
+
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        let d = doc.read();
+        assert_eq!(d.content, format!("a{big}b!"));
+        assert_eq!(
+            d.log[1],
+            vec![OpShape::Insert {
+                pos: 1,
+                chars: 50_000
+            }]
+        );
+        assert_eq!(
+            d.log[2],
+            vec![OpShape::Insert {
+                pos: 50_002,
+                chars: 1
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn version_vector_counts_edits_per_client_across_reloads() {
+        let base = std::env::temp_dir().join(format!("srvtest-versions-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "versions";
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let mk_edit = |base_rev: u64, client_id: Option<Uuid>| Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: "x".into(),
+            }],
+            client_id,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        for (rev, client) in [
+            (0, Some(alice)),
+            (1, Some(bob)),
+            (2, Some(alice)),
+            (3, None),
+        ] {
+            apply_edit(&state, slug, mk_edit(rev, client))
+                .await
+                .unwrap();
+        }
+        crate::storage::flush_snapshot_force(&state, slug)
+            .await
+            .unwrap();
+        apply_edit(&state, slug, mk_edit(4, Some(bob)))
+            .await
+            .unwrap();
+        let expected = VersionVector::from([(alice, 2), (bob, 2)]);
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!(doc.read().versions, expected);
+
+        state.docs.write().clear();
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!(doc.read().meta.versions[&alice], 2);
+        assert_eq!(doc.read().versions, expected);
+
+        // Metadata written before version vectors existed: the whole WAL counts.
+        let mut meta = doc.read().meta.clone();
+        meta.versions.clear();
+        persist_meta(&state, slug, &meta).unwrap();
+        state.docs.write().clear();
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!(doc.read().versions, expected);
+    }
+
+    #[tokio::test]
+    async fn diverged_edits_are_rejected_and_cursors_rebased() {
+        let base = std::env::temp_dir().join(format!("srvtest-diverge-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "diverge";
+        let cursor = |position| CursorState {
+            position,
+            anchor: None,
+            selection_direction: None,
+        };
+        let mk_edit = |base_rev: u64, pos: usize, text: &str| Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos,
+                text: text.into(),
+            }],
+            client_id: Some(Uuid::new_v4()),
+            op_id: None,
+            cursor_before: None,
+            cursor_after: Some(cursor(pos + text.chars().count())),
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(&state, slug, mk_edit(0, 0, "abc"))
+            .await
+            .unwrap();
+
+        for edit in [
+            mk_edit(1, 9, "x"),
+            mk_edit(5, 0, "x"),
+            Edit {
+                cursor_before: Some(cursor(7)),
+                ..mk_edit(1, 0, "x")
+            },
+        ] {
+            let err = apply_edit(&state, slug, edit).await.unwrap_err();
+            assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, DIVERGED);
+        }
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!((doc.read().rev, doc.read().content.as_str()), (1, "abc"));
+
+        // Typed at the end against rev 1: the cursor follows the rebase.
+        apply_edit(&state, slug, mk_edit(1, 0, "XY")).await.unwrap();
+        let late = mk_edit(1, 3, "!");
+        let cid = late.client_id.unwrap();
+        crate::presence::register_presence(&state, slug, cid, None, None, None, 0);
+        apply_edit(&state, slug, late).await.unwrap();
+        assert_eq!(doc.read().content, "XYabc!");
+        let presence = state.presence.read();
+        assert_eq!(presence[slug].clients[&cid].cursor, Some(cursor(6)));
+    }
+
+    #[tokio::test]
+    async fn strict_mode_refuses_ops_that_did_not_fit_and_counts_them() {
+        let base = std::env::temp_dir().join(format!("srvtest-strict-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.strict_ops = true;
+        let slug = "strict";
+        let mk_edit = |base_rev: u64, ops: Vec<OpKind>| Edit {
+            base_rev,
+            ops,
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        let insert = |pos: usize, text: &str| OpKind::Insert {
+            pos,
+            text: text.into(),
+        };
+        apply_edit(&state, slug, mk_edit(0, vec![insert(0, "abc")]))
+            .await
+            .unwrap();
+        apply_edit(&state, slug, mk_edit(1, vec![insert(3, "defgh")]))
+            .await
+            .unwrap();
+
+        let late = mk_edit(1, vec![OpKind::Delete { pos: 2, len: 5 }]);
+        let err = apply_edit(&state, slug, late).await.unwrap_err();
+        let rejection = err.downcast_ref::<Rejection>().unwrap();
+        assert_eq!(rejection.code, INVALID_OP);
+        let invalid = rejection.invalid_op.as_ref().unwrap();
+        assert_eq!((invalid.index, invalid.reason), (0, "delete_out_of_range"));
+        let err = apply_edit(&state, slug, mk_edit(2, vec![insert(0, "")]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, INVALID_OP);
+        assert_eq!(state.metrics.snapshot().invalid_ops, 2);
+
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert_eq!(
+            (doc.read().rev, doc.read().content.as_str()),
+            (2, "abcdefgh")
+        );
+        apply_edit(&state, slug, mk_edit(1, vec![insert(3, "!")]))
+            .await
+            .unwrap();
+        assert_eq!(doc.read().content, "abc!defgh");
+    }
+
+    #[tokio::test]
+    async fn skewed_client_timestamps_give_way_to_the_server_clock() {
+        let base = std::env::temp_dir().join(format!("srvtest-skew-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "skew";
+        let mk_edit = |base_rev: u64, ts: u64| Edit {
+            base_rev,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: "x".into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: Some(ts),
+            group_id: None,
+            user_id: None,
+        };
+        let before = now_millis();
+        apply_edit(&state, slug, mk_edit(0, before - 1_000))
+            .await
+            .unwrap();
+        apply_edit(&state, slug, mk_edit(1, 42)).await.unwrap();
+        let after = now_millis();
+
+        let entries: Vec<_> = crate::integrity::wal_entries(&state, slug)
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(entries[0].ts, before - 1_000);
+        assert_eq!(entries[0].edit.ts, Some(before - 1_000));
+        let clamped = &entries[1];
+        assert!((before..=after).contains(&clamped.ts));
+        assert_eq!(clamped.edit.ts, Some(clamped.ts));
+        let wal = crate::storage::read_wal(&state, slug).unwrap().unwrap();
+        for line in wal.lines() {
+            let entry: crate::types::WalEntryV2 = serde_json::from_str(line).unwrap();
+            assert!((before..=after).contains(&entry.server_ts.unwrap()));
+        }
+        assert_eq!(state.metrics.snapshot().clock_skew, 1);
+        let doc = get_or_load_doc(&state, slug).await.unwrap();
+        assert!(doc.read().last_edit_ts >= before);
+    }
+
     #[tokio::test]
     async fn slug_with_parent_component_is_rejected() {
         let base = std::env::temp_dir().join(format!("srvtest-invalid-{}", Uuid::new_v4()));
@@ -1,16 +1,34 @@
 use std::{
+    cell::Cell,
+    collections::HashMap,
     fs,
-    fs::OpenOptions,
-    io::Write,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
     path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Instant,
 };
 
 use crate::{
-    state::{AppState, get_or_load_doc, now_millis},
-    types::{CURRENT_WAL_VERSION, DocEvent, WalEntryV2},
+    analysis::analyze_content,
+    cluster::owns,
+    doc_settings::{flush_idle_ms, flush_max_ops},
+    front_matter::update_derived_meta,
+    links::record_links,
+    mentions::notify_mentions,
+    metrics::record_flush,
+    quota::record_bytes,
+    state::{AppState, broadcast_warnings, get_or_load_doc, now_millis, recent_op_ids},
+    tags::record_tags,
+    types::{CURRENT_WAL_VERSION, DocEvent, DocMeta, WalEntryV2},
+    validation::{Candidate, RuleStage, rejection, validate},
 };
 use anyhow::bail;
+use parking_lot::{Mutex, ReentrantMutex};
+use serde::Deserialize;
 use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use uuid::Uuid;
 
 pub fn slug_to_rel_path(slug: &str) -> anyhow::Result<PathBuf> {
     let trimmed = slug.trim_matches('/');
@@ -38,34 +56,413 @@ pub fn snapshot_path(state: &AppState, slug: &str) -> anyhow::Result<PathBuf> {
 }
 
 pub fn password_path(state: &AppState, slug: &str) -> anyhow::Result<PathBuf> {
+    slug_path_with_extension(&state.secrets_dir, slug, "pwd")
+}
+
+/// Where password hashes lived before they moved to `secrets_dir`.
+pub fn legacy_password_path(state: &AppState, slug: &str) -> anyhow::Result<PathBuf> {
     slug_path_with_extension(&state.snap_dir, slug, "pwd")
 }
 
+pub fn meta_path(state: &AppState, slug: &str) -> anyhow::Result<PathBuf> {
+    slug_path_with_extension(&state.snap_dir, slug, "meta.json")
+}
+
+/// Op ids the document has seen, kept across restarts and WAL compaction.
+pub fn op_ids_path(state: &AppState, slug: &str) -> anyhow::Result<PathBuf> {
+    slug_path_with_extension(&state.snap_dir, slug, "ops")
+}
+
 pub fn wal_path(state: &AppState, slug: &str) -> anyhow::Result<PathBuf> {
     slug_path_with_extension(&state.wal_dir, slug, "jsonl")
 }
 
+pub const COMPRESSED_SUFFIX: &str = "zst";
+
+/// The zstd-compressed sibling of a plain storage file (`doc.md` ->
+/// `doc.md.zst`).
+pub fn compressed_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(COMPRESSED_SUFFIX);
+    path.with_file_name(name)
+}
+
+fn read_optional(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads the snapshot, preferring the compressed file when both exist.
+pub fn read_snapshot(state: &AppState, slug: &str) -> anyhow::Result<Option<String>> {
+    let path = snapshot_path(state, slug)?;
+    let data = match read_optional(&compressed_path(&path))? {
+        Some(raw) => zstd::decode_all(raw.as_slice())?,
+        None => match read_optional(&path)? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(String::from_utf8(data)?))
+}
+
+/// Writes the snapshot in the configured format and removes the stale file in
+/// the other format. Returns the on-disk size difference.
+pub fn write_snapshot(state: &AppState, slug: &str, content: &str) -> anyhow::Result<i64> {
+    let plain = snapshot_path(state, slug)?;
+    let compressed = compressed_path(&plain);
+    let (target, stale) = if state.compress_storage {
+        (&compressed, &plain)
+    } else {
+        (&plain, &compressed)
+    };
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let size_of = |p: &Path| fs::metadata(p).map(|m| m.len() as i64).unwrap_or(0);
+    let previous = size_of(target) + size_of(stale);
+    if state.compress_storage {
+        fs::write(target, zstd::encode_all(content.as_bytes(), 0)?)?;
+    } else {
+        fs::write(target, content)?;
+    }
+    if stale.exists() {
+        fs::remove_file(stale)?;
+    }
+    Ok(size_of(target) - previous)
+}
+
+/// Per-document locks past this many drop the ones nobody holds.
+const LOCKS_PRUNE_AT: usize = 1024;
+
+/// The lock that orders writes to the WAL of `slug`. Appends take it, and
+/// anything that replaces or moves the WAL holds it from the read it starts
+/// with until the files are in place, so an append cannot land in a file
+/// that is about to be unlinked. Taken before the document's own lock.
+pub fn wal_lock(state: &AppState, slug: &str) -> Arc<WalLock> {
+    lock_for(&state.wal_locks, slug)
+}
+
+/// The lock a flush of `slug` holds from copying the document until its
+/// snapshot and metadata are written, so flushes land in revision order.
+pub fn flush_lock(state: &AppState, slug: &str) -> Arc<tokio::sync::Mutex<()>> {
+    lock_for(&state.flush_locks, slug)
+}
+
+fn lock_for<L: Default>(locks: &Mutex<HashMap<String, Arc<L>>>, slug: &str) -> Arc<L> {
+    let mut locks = locks.lock();
+    if locks.len() >= LOCKS_PRUNE_AT {
+        // Only the map holds these, and handing out a clone needs the map.
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+    }
+    locks.entry(slug.to_string()).or_default().clone()
+}
+
+/// Uncompressed bytes a compressed WAL gathers before they are compressed
+/// together as one segment.
+const WAL_SEGMENT_BYTES: u64 = 256 * 1024;
+
+/// Guards the WAL of one document and remembers where its compressed file
+/// stands, so appends need not scan it.
+pub type WalLock = ReentrantMutex<Cell<Option<WalTail>>>;
+
+/// A compressed WAL is a run of zstd frames: whole segments, then one frame
+/// per line appended since the last segment was sealed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalTail {
+    /// Size of the file this was read from; a different size means the
+    /// file was replaced and has to be scanned again.
+    file_len: u64,
+    /// Bytes of sealed segments at the start of the file.
+    sealed_len: u64,
+    /// Uncompressed bytes in the frames after them.
+    open_bytes: u64,
+}
+
+fn scan_segments(raw: &[u8]) -> anyhow::Result<WalTail> {
+    let mut tail = WalTail {
+        file_len: raw.len() as u64,
+        ..Default::default()
+    };
+    let mut offset = 0;
+    while offset < raw.len() {
+        let size = zstd::zstd_safe::find_frame_compressed_size(&raw[offset..]).map_err(|code| {
+            anyhow::anyhow!(
+                "corrupt WAL frame: {}",
+                zstd::zstd_safe::get_error_name(code)
+            )
+        })?;
+        let frame = &raw[offset..offset + size];
+        let content = match zstd::zstd_safe::get_frame_content_size(frame) {
+            Ok(Some(len)) => len,
+            // Frames written by a streaming encoder leave the size out.
+            _ => zstd::decode_all(frame)?.len() as u64,
+        };
+        offset += size;
+        if content >= WAL_SEGMENT_BYTES {
+            tail.sealed_len = offset as u64;
+            tail.open_bytes = 0;
+        } else {
+            tail.open_bytes += content;
+        }
+    }
+    Ok(tail)
+}
+
+/// Time a WAL line was written, for ordering WALs split across formats.
+#[derive(Deserialize)]
+struct LineTime {
+    ts: Option<u64>,
+    server_ts: Option<u64>,
+}
+
+fn line_time(line: &str) -> u64 {
+    serde_json::from_str::<LineTime>(line)
+        .ok()
+        .and_then(|t| t.server_ts.or(t.ts))
+        .unwrap_or(0)
+}
+
+/// Merges the lines of a WAL left in both files by a version that wrote
+/// whichever format was configured at the time. Each file is in order on
+/// its own; ties keep `plain` first.
+fn interleave_by_time(plain: &str, compressed: &str) -> String {
+    let mut out = String::with_capacity(plain.len() + compressed.len());
+    let mut a = plain.lines().filter(|l| !l.trim().is_empty()).peekable();
+    let mut b = compressed
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .peekable();
+    loop {
+        let next = match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) if line_time(y) < line_time(x) => b.next(),
+            (Some(_), _) => a.next(),
+            (None, _) => b.next(),
+        };
+        let Some(line) = next else {
+            break;
+        };
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Reads the whole WAL. A WAL is kept in one format, compressed or not,
+/// and moved over whole when the setting changes; only WALs written before
+/// that can have both files, whose lines are put back in order by time.
+pub fn read_wal(state: &AppState, slug: &str) -> anyhow::Result<Option<String>> {
+    let path = wal_path(state, slug)?;
+    let plain = read_optional(&path)?.map(String::from_utf8).transpose()?;
+    let compressed = match read_optional(&compressed_path(&path))? {
+        Some(raw) => Some(String::from_utf8(zstd::decode_all(raw.as_slice())?)?),
+        None => None,
+    };
+    Ok(match (plain, compressed) {
+        (None, None) => None,
+        (Some(data), None) | (None, Some(data)) => Some(data),
+        (Some(plain), Some(compressed)) => Some(interleave_by_time(&plain, &compressed)),
+    })
+}
+
+fn open_optional(path: &Path) -> anyhow::Result<Option<File>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub type WalLines = Box<dyn Iterator<Item = std::io::Result<String>> + Send>;
+
+/// Streams the WAL line by line in the same order as [`read_wal`], without
+/// holding all of it in memory.
+pub fn wal_lines(state: &AppState, slug: &str) -> anyhow::Result<WalLines> {
+    let path = wal_path(state, slug)?;
+    let plain = open_optional(&path)?;
+    let compressed = open_optional(&compressed_path(&path))?;
+    Ok(match (plain, compressed) {
+        (Some(_), Some(_)) => {
+            let data = read_wal(state, slug)?.unwrap_or_default();
+            let lines: Vec<_> = data.lines().map(|line| Ok(line.to_string())).collect();
+            Box::new(lines.into_iter())
+        }
+        (Some(file), None) => Box::new(BufReader::new(file).lines()),
+        (None, Some(file)) => Box::new(BufReader::new(zstd::Decoder::new(file)?).lines()),
+        (None, None) => Box::new(std::iter::empty()),
+    })
+}
+
+/// Writes `data` aside and renames it over `path`.
+fn replace_file(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Replaces the whole WAL with `data` in the configured format. The new file
+/// is written aside and renamed into place. Callers hold [`wal_lock`] since
+/// reading the WAL `data` was made from.
+pub fn rewrite_wal(state: &AppState, slug: &str, data: &str) -> anyhow::Result<()> {
+    let plain = wal_path(state, slug)?;
+    let compressed = compressed_path(&plain);
+    let (target, stale) = if state.compress_storage {
+        (&compressed, &plain)
+    } else {
+        (&plain, &compressed)
+    };
+    let lock = wal_lock(state, slug);
+    let tail = lock.lock();
+    let size_of = |p: &Path| fs::metadata(p).map(|m| m.len() as i64).unwrap_or(0);
+    let previous = size_of(target) + size_of(stale);
+    let encoded = if state.compress_storage {
+        zstd::bulk::compress(data.as_bytes(), 0)?
+    } else {
+        data.as_bytes().to_vec()
+    };
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    replace_file(target, &encoded)?;
+    if stale.exists() {
+        fs::remove_file(stale)?;
+    }
+    tail.set(None);
+    record_bytes(state, slug, size_of(target) - previous);
+    Ok(())
+}
+
+pub fn doc_exists_on_disk(state: &AppState, slug: &str) -> anyhow::Result<bool> {
+    let snap = snapshot_path(state, slug)?;
+    let wal = wal_path(state, slug)?;
+    Ok(snap.exists()
+        || compressed_path(&snap).exists()
+        || wal.exists()
+        || compressed_path(&wal).exists()
+        || meta_path(state, slug)?.exists())
+}
+
 pub fn wal_append_event(
     state: &AppState,
     slug: &str,
     event: &DocEvent,
     ts: u64,
 ) -> anyhow::Result<()> {
-    let path = wal_path(state, slug)?;
-    if let Some(parent) = path.parent() {
+    let plain = wal_path(state, slug)?;
+    let compressed = compressed_path(&plain);
+    let (target, stale) = if state.compress_storage {
+        (&compressed, &plain)
+    } else {
+        (&plain, &compressed)
+    };
+    let lock = wal_lock(state, slug);
+    let tail = lock.lock();
+    if stale.exists() {
+        // Compression was switched on or off since this WAL was written.
+        let data = read_wal(state, slug)?.unwrap_or_default();
+        rewrite_wal(state, slug, &data)?;
+    }
+    if let Some(parent) = target.parent() {
         fs::create_dir_all(parent)?;
     }
-    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
     let entry = WalEntryV2 {
         version: CURRENT_WAL_VERSION,
         ts,
+        server_ts: Some(now_millis()),
         event: event.clone(),
     };
-    serde_json::to_writer(&mut f, &entry)?;
-    f.write_all(b"\n")?;
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    let written = if state.compress_storage {
+        append_compressed(target, &line, &tail)?
+    } else {
+        let mut f = OpenOptions::new().create(true).append(true).open(target)?;
+        f.write_all(&line)?;
+        line.len() as i64
+    };
+    record_bytes(state, slug, written);
     Ok(())
 }
 
+/// Appends `line` to a compressed WAL as a frame of its own, or, once the
+/// open frames hold a segment's worth, replaces them and `line` with one
+/// frame compressed as a whole. Returns how much the file grew.
+fn append_compressed(
+    path: &Path,
+    line: &[u8],
+    tail: &Cell<Option<WalTail>>,
+) -> anyhow::Result<i64> {
+    let file_len = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    let current = match tail.get() {
+        Some(known) if known.file_len == file_len => known,
+        _ => scan_segments(&read_optional(path)?.unwrap_or_default())?,
+    };
+    if current.open_bytes + (line.len() as u64) < WAL_SEGMENT_BYTES {
+        let frame = zstd::bulk::compress(line, 0)?;
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        f.write_all(&frame)?;
+        tail.set(Some(WalTail {
+            file_len: file_len + frame.len() as u64,
+            open_bytes: current.open_bytes + line.len() as u64,
+            ..current
+        }));
+        return Ok(frame.len() as i64);
+    }
+    let raw = read_optional(path)?.unwrap_or_default();
+    let sealed = current.sealed_len as usize;
+    let mut segment = zstd::decode_all(&raw[sealed..])?;
+    segment.extend_from_slice(line);
+    let mut data = raw[..sealed].to_vec();
+    data.extend(zstd::bulk::compress(&segment, 0)?);
+    replace_file(path, &data)?;
+    tail.set(Some(WalTail {
+        file_len: data.len() as u64,
+        sealed_len: data.len() as u64,
+        open_bytes: 0,
+    }));
+    Ok(data.len() as i64 - raw.len() as i64)
+}
+
+/// Runs file work on tokio's blocking pool so a large write does not hold up
+/// the sockets served by the same worker thread.
+pub async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    tokio::task::spawn_blocking(work).await?
+}
+
+/// Takes the lock that makes this process the only one writing the data
+/// directory the lock file `path` sits in, held until the file is dropped.
+/// With `wait` set this waits for the process holding it to exit, the way a
+/// process started next to a draining one with `REUSE_PORT` takes over;
+/// otherwise a held lock fails at once.
+pub async fn lock_data_dir(path: PathBuf, wait: bool) -> anyhow::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => return Ok(file),
+        Err(fs::TryLockError::WouldBlock) if wait => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            bail!("{} is held by another process", path.display())
+        }
+        Err(fs::TryLockError::Error(err)) => return Err(err.into()),
+    }
+    info!(lock = %path.display(), "waiting for the running process to release the data directory");
+    blocking(move || {
+        file.lock()?;
+        Ok(file)
+    })
+    .await
+}
+
 enum FlushMode {
     Opportunistic,
     Forced,
@@ -80,9 +477,10 @@ pub async fn flush_snapshot_force(state: &AppState, slug: &str) -> anyhow::Resul
 }
 
 pub async fn flush_all_wals_to_snapshots(state: &AppState) -> anyhow::Result<usize> {
-    let slugs = collect_pending_wal_slugs(&state.wal_dir)?;
+    let slugs = collect_slugs_with_extension(&state.wal_dir, "jsonl", true)?;
     let mut flushed = 0usize;
-    for slug in slugs {
+    // Other nodes of a cluster are still appending to theirs.
+    for slug in slugs.into_iter().filter(|slug| owns(state, slug)) {
         if flush_snapshot_force(state, &slug).await? {
             flushed += 1;
         }
@@ -97,10 +495,10 @@ async fn flush_snapshot(state: &AppState, slug: &str, mode: FlushMode) -> anyhow
         let d = doc_arc.read();
         match mode {
             FlushMode::Opportunistic => {
-                let due_to_ops = d.since_flush >= state.flush_max_ops;
+                let due_to_ops = d.since_flush >= flush_max_ops(state, &d.meta);
                 let due_to_idle = d.since_flush > 0
                     && d.last_edit_ts > 0
-                    && now.saturating_sub(d.last_edit_ts) >= state.flush_idle_ms;
+                    && now.saturating_sub(d.last_edit_ts) >= flush_idle_ms(state, &d.meta);
                 due_to_ops || due_to_idle
             }
             FlushMode::Forced => d.since_flush > 0,
@@ -110,39 +508,190 @@ async fn flush_snapshot(state: &AppState, slug: &str, mode: FlushMode) -> anyhow
         return Ok(false);
     }
 
-    let content;
+    // Only the copy is made under the lock; edits keep landing while the
+    // snapshot is checked and written, and stay counted for the next flush.
+    // Another flush of this document waits, so an older copy never lands
+    // on top of a newer one.
+    let flush = flush_lock(state, slug);
+    let flushing = flush.lock().await;
+    let (content, rev, edits, versions, mut derived) = {
+        let d = doc_arc.read();
+        if d.since_flush == 0 {
+            return Ok(false);
+        }
+        let derived = DocMeta {
+            content_type: d.meta.content_type.clone(),
+            ..Default::default()
+        };
+        (
+            d.content.clone(),
+            d.rev,
+            d.since_flush,
+            d.versions.clone(),
+            derived,
+        )
+    };
+    let violations = validate(
+        state,
+        &Candidate {
+            slug,
+            content: &content,
+            content_type: &derived.content_type.clone().unwrap_or_default(),
+            stage: RuleStage::Flush,
+        },
+    )?;
+    update_derived_meta(&mut derived, &content);
+
+    let meta;
+    let new_violations;
+    let mentioned;
     {
         let mut d = doc_arc.write();
-        if d.since_flush == 0 {
+        new_violations = if violations == d.flush_violations {
+            Vec::new()
+        } else {
+            violations.clone()
+        };
+        d.flush_violations = violations;
+        if let Some(rejection) = rejection(&d.flush_violations) {
+            // The WAL still has every edit; the snapshot waits until the
+            // content passes again.
+            drop(d);
+            if !new_violations.is_empty() {
+                warn!(%slug, "snapshot held back: {}", rejection.message);
+                broadcast_warnings(state, slug, &new_violations, None);
+            }
             return Ok(false);
         }
-        content = d.content.clone();
-        d.since_flush = 0;
+        // A flush that started later got here first.
+        if d.meta.snapshot_rev >= rev && d.meta.snapshot_rev > 0 {
+            return Ok(false);
+        }
+        // A first snapshot mentions nobody before it.
+        mentioned = match d.meta.snapshot_rev {
+            0 => Some(Vec::new()),
+            _ => d.meta.mentions.clone(),
+        };
+        // The document itself moves on once the snapshot is on disk.
+        meta = DocMeta {
+            snapshot_rev: rev,
+            versions,
+            mentions: derived.mentions,
+            front_matter: derived.front_matter,
+            tags: derived.tags,
+            ..d.meta.clone()
+        };
     }
-    let snap_path = snapshot_path(state, slug)?;
-    if let Some(parent) = snap_path.parent() {
-        fs::create_dir_all(parent)?;
+    let started = Instant::now();
+    let op_ids = recent_op_ids(state, slug);
+    let (content, meta, delta) = {
+        let (state, slug) = (state.clone(), slug.to_string());
+        blocking(move || {
+            let delta = write_snapshot(&state, &slug, &content)?;
+            if let Some(mirror) = &state.mirror
+                && let Err(err) = mirror.write_mirror(&slug, &content, rev)
+            {
+                warn!(%slug, "writing the mirrored file failed: {:#}", err);
+            }
+            if let Some(git) = &state.git {
+                let path = snapshot_path(&state, &slug)?;
+                if let Err(err) = git.commit(&slug, &path) {
+                    warn!(%slug, "committing the snapshot failed: {:#}", err);
+                }
+            }
+            persist_meta(&state, &slug, &meta)?;
+            if !op_ids.is_empty() {
+                persist_op_ids(&state, &slug, &op_ids)?;
+            }
+            Ok((content, meta, delta))
+        })
+        .await?
+    };
+    {
+        let mut d = doc_arc.write();
+        d.since_flush = d.since_flush.saturating_sub(edits);
+        d.meta.snapshot_rev = meta.snapshot_rev;
+        d.meta.versions = meta.versions.clone();
+        d.meta.mentions = meta.mentions.clone();
+        d.meta.front_matter = meta.front_matter.clone();
+        d.meta.tags = meta.tags.clone();
+    }
+    drop(flushing);
+    record_bytes(state, slug, delta);
+    record_links(
+        state,
+        slug,
+        &meta.content_type.clone().unwrap_or_default(),
+        &content,
+    );
+    record_tags(state, slug, meta.tags.as_deref().unwrap_or_default());
+    notify_mentions(state, slug, mentioned.as_deref(), &meta, &content);
+    record_flush(
+        state,
+        slug,
+        meta.snapshot_rev,
+        edits,
+        content.len(),
+        started.elapsed(),
+    );
+    for violation in &new_violations {
+        warn!(%slug, rule = %violation.rule, "flushed content: {}", violation.message);
+    }
+    broadcast_warnings(state, slug, &new_violations, None);
+    if !state.analyzers.is_empty() {
+        let content_type = meta.content_type.unwrap_or_default();
+        if let Err(err) = analyze_content(state, slug, rev, content, content_type).await {
+            warn!(%slug, "analyzing the flushed content failed: {:#}", err);
+        }
     }
-    fs::write(snap_path, content)?;
     Ok(true)
 }
 
-fn collect_pending_wal_slugs(base: &Path) -> anyhow::Result<Vec<String>> {
-    fn visit(base: &Path, dir: &Path, acc: &mut Vec<String>) -> anyhow::Result<()> {
+/// Every document on disk or in memory, sorted.
+pub fn list_all_slugs(state: &AppState) -> anyhow::Result<Vec<String>> {
+    let mut slugs = collect_slugs_with_extension(&state.snap_dir, "md", false)?;
+    slugs.extend(collect_slugs_with_extension(&state.wal_dir, "jsonl", true)?);
+    slugs.extend(state.docs.read().keys().cloned());
+    slugs.sort();
+    slugs.dedup();
+    Ok(slugs)
+}
+
+pub fn collect_slugs_with_extension(
+    base: &Path,
+    ext: &str,
+    skip_empty: bool,
+) -> anyhow::Result<Vec<String>> {
+    fn visit(
+        base: &Path,
+        dir: &Path,
+        ext: &str,
+        skip_empty: bool,
+        acc: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
             if entry.file_type()?.is_dir() {
-                visit(base, &path, acc)?;
-            } else if path.extension().and_then(|e| e.to_str()) == Some("jsonl") {
-                if fs::metadata(&path)?.len() == 0 {
+                visit(base, &path, ext, skip_empty, acc)?;
+                continue;
+            }
+            let logical = if path.extension().and_then(|e| e.to_str()) == Some(COMPRESSED_SUFFIX) {
+                path.with_extension("")
+            } else {
+                path.clone()
+            };
+            if logical.extension().and_then(|e| e.to_str()) == Some(ext) {
+                if skip_empty && fs::metadata(&path)?.len() == 0 {
                     continue;
                 }
-                let rel = path.strip_prefix(base)?;
+                let rel = logical.strip_prefix(base)?;
                 let mut rel_slug = rel.to_path_buf();
                 rel_slug.set_extension("");
                 let slug = rel_slug.to_string_lossy().replace('\\', "/");
-                acc.push(slug);
+                if !acc.contains(&slug) {
+                    acc.push(slug);
+                }
             }
         }
         Ok(())
@@ -150,7 +699,7 @@ fn collect_pending_wal_slugs(base: &Path) -> anyhow::Result<Vec<String>> {
 
     let mut slugs = Vec::new();
     if base.exists() {
-        visit(base, base, &mut slugs)?;
+        visit(base, base, ext, skip_empty, &mut slugs)?;
     }
     Ok(slugs)
 }
@@ -168,22 +717,132 @@ pub fn persist_password_hash(
 ) -> anyhow::Result<()> {
     let path = password_path(state, slug)?;
     match hash {
-        Some(h) => {
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent)?;
-            }
-            fs::write(path, h)?;
-        }
+        Some(h) => write_secret(&path, h)?,
         None => {
             if path.exists() {
                 fs::remove_file(path)?;
             }
         }
     }
+    let legacy = legacy_password_path(state, slug)?;
+    if legacy.exists() {
+        fs::remove_file(legacy)?;
+    }
     Ok(())
 }
 
+/// Reads the password hash of `slug`. One still in the snapshot tree is
+/// moved to `secrets_dir` on the way, except on a read replica.
+pub fn load_password_hash(state: &AppState, slug: &str) -> anyhow::Result<Option<String>> {
+    if let Some(hash) = read_optional(&password_path(state, slug)?)? {
+        return Ok(Some(String::from_utf8(hash)?.trim().to_string()));
+    }
+    let Some(hash) = read_optional(&legacy_password_path(state, slug)?)? else {
+        return Ok(None);
+    };
+    let hash = String::from_utf8(hash)?.trim().to_string();
+    if state.replica.is_none() {
+        persist_password_hash(state, slug, Some(&hash))?;
+    }
+    Ok(Some(hash))
+}
+
+/// Moves every password hash still in the snapshot tree to `secrets_dir`.
+/// Returns how many were moved.
+pub fn migrate_password_hashes(state: &AppState) -> anyhow::Result<usize> {
+    if !state.snap_dir.exists() {
+        return Ok(0);
+    }
+    let slugs = collect_slugs_with_extension(&state.snap_dir, "pwd", false)?;
+    for slug in &slugs {
+        load_password_hash(state, slug)?;
+    }
+    Ok(slugs.len())
+}
+
+/// Writes `data` readable by the server's user only. The file is written
+/// aside and renamed into place, so it is never seen half written, and a
+/// file left from before with looser permissions is replaced rather than
+/// reused.
+pub fn write_secret(path: &Path, data: &str) -> anyhow::Result<()> {
+    let mut dirs = fs::DirBuilder::new();
+    dirs.recursive(true);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+        dirs.mode(0o700);
+        options.mode(0o600);
+    }
+    let parent = path.parent().unwrap_or(Path::new("."));
+    dirs.create(parent)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(parent, fs::Permissions::from_mode(0o700))?;
+    }
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    match fs::remove_file(&tmp) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(data.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn persist_meta(state: &AppState, slug: &str, meta: &DocMeta) -> anyhow::Result<()> {
+    let path = meta_path(state, slug)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_vec_pretty(meta)?)?;
+    Ok(())
+}
+
+/// Writes `ids`, oldest first, as 16 raw bytes each.
+pub fn persist_op_ids(state: &AppState, slug: &str, ids: &[Uuid]) -> anyhow::Result<()> {
+    let path = op_ids_path(state, slug)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let data: Vec<u8> = ids.iter().flat_map(|id| *id.as_bytes()).collect();
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+pub fn load_op_ids(state: &AppState, slug: &str) -> anyhow::Result<Vec<Uuid>> {
+    let path = op_ids_path(state, slug)?;
+    match fs::read(&path) {
+        Ok(data) => Ok(data
+            .chunks_exact(16)
+            .map(|chunk| Uuid::from_slice(chunk).expect("16 byte chunk"))
+            .collect()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn load_meta(state: &AppState, slug: &str) -> anyhow::Result<Option<DocMeta>> {
+    let path = meta_path(state, slug)?;
+    match fs::read(&path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::field_reassign_with_default)]
 mod tests {
     use super::*;
     use crate::document::Doc;
@@ -236,6 +895,93 @@ mod tests {
         assert_eq!(doc_arc.read().since_flush, 0);
     }
 
+    #[tokio::test]
+    async fn one_process_at_a_time_holds_the_data_dir() {
+        let base = std::env::temp_dir().join(format!("storage-lock-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let path = base.join(".lock");
+        let held = lock_data_dir(path.clone(), false).await.unwrap();
+        assert!(lock_data_dir(path.clone(), false).await.is_err());
+
+        let waiting = tokio::spawn(lock_data_dir(path, true));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        drop(held);
+        waiting.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_snapshot_writes_leave_the_document_unflushed() {
+        let base = std::env::temp_dir().join(format!("storage-fail-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "doc";
+        let doc = Doc {
+            content: "hello".into(),
+            rev: 1,
+            since_flush: 1,
+            ..Default::default()
+        };
+        let doc_arc = Arc::new(RwLock::new(doc));
+        state.docs.write().insert(slug.into(), doc_arc.clone());
+        // A directory where the snapshot goes makes the write fail.
+        let path = snapshot_path(&state, slug).unwrap();
+        fs::create_dir_all(&path).unwrap();
+
+        assert!(flush_snapshot_force(&state, slug).await.is_err());
+        {
+            let d = doc_arc.read();
+            assert_eq!((d.since_flush, d.meta.snapshot_rev), (1, 0));
+        }
+
+        fs::remove_dir(&path).unwrap();
+        assert!(flush_snapshot_force(&state, slug).await.unwrap());
+        let d = doc_arc.read();
+        assert_eq!((d.since_flush, d.meta.snapshot_rev), (0, 1));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_flushes_leave_the_newest_snapshot() {
+        let base = std::env::temp_dir().join(format!("storage-race-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "racy";
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for _ in 0..25 {
+                        let rev = get_or_load_doc(&state, slug).await.unwrap().read().rev;
+                        let edit = Edit {
+                            base_rev: rev,
+                            ops: vec![OpKind::Insert {
+                                pos: 0,
+                                text: "x".into(),
+                            }],
+                            client_id: None,
+                            op_id: Some(Uuid::new_v4()),
+                            cursor_before: None,
+                            cursor_after: None,
+                            ts: None,
+                            group_id: None,
+                            user_id: None,
+                        };
+                        crate::state::apply_edit(&state, slug, edit).await.unwrap();
+                        flush_snapshot_force(&state, slug).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        flush_snapshot_force(&state, slug).await.unwrap();
+
+        let meta = load_meta(&state, slug).unwrap().unwrap();
+        let snapshot = read_snapshot(&state, slug).unwrap().unwrap();
+        assert_eq!((meta.snapshot_rev, snapshot.len()), (100, 100));
+    }
+
     #[tokio::test]
     async fn flush_snapshot_if_needed_respects_idle_time() {
         let base = std::env::temp_dir().join(format!("storage-idle-{}", Uuid::new_v4()));
@@ -246,7 +992,7 @@ mod tests {
         doc.content = "idle".into();
         doc.rev = 2;
         doc.since_flush = 1;
-        doc.last_edit_ts = now_millis().saturating_sub(state.flush_idle_ms + 5);
+        doc.last_edit_ts = now_millis().saturating_sub(state.live.read().flush_idle_ms + 5);
         state
             .docs
             .write()
@@ -256,6 +1002,86 @@ mod tests {
         assert!(flushed, "idle threshold should trigger flush");
     }
 
+    #[tokio::test]
+    async fn large_snapshot_writes_leave_the_runtime_free() {
+        let base = std::env::temp_dir().join(format!("storage-blocking-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        state.compress_storage = true;
+        let slug = "big-doc";
+        let doc = Doc {
+            content: (0..1_000_000).map(|i| format!("{i} ")).collect(),
+            rev: 1,
+            since_flush: 1,
+            ..Default::default()
+        };
+        state
+            .docs
+            .write()
+            .insert(slug.into(), Arc::new(RwLock::new(doc)));
+
+        // The test runtime has a single thread, so the ticker only gets to
+        // run while the flush is waiting on the write rather than doing it.
+        let ticks = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    ticks.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        tokio::task::yield_now().await;
+        let before = ticks.load(std::sync::atomic::Ordering::Relaxed);
+        assert!(flush_snapshot_force(&state, slug).await.unwrap());
+        let during = ticks.load(std::sync::atomic::Ordering::Relaxed) - before;
+        ticker.abort();
+        assert!(during > 0, "the runtime stalled for the whole write");
+        assert!(compressed_path(&snapshot_path(&state, slug).unwrap()).exists());
+    }
+
+    #[tokio::test]
+    async fn edits_land_while_a_flush_is_checked() {
+        use crate::validation::{Candidate, ValidationHook, Violation};
+
+        /// Types into the document the moment its flush is validated.
+        struct TypeDuringFlush(Arc<RwLock<Doc>>);
+        impl ValidationHook for TypeDuringFlush {
+            fn check(&self, candidate: &Candidate<'_>) -> Vec<Violation> {
+                if candidate.stage == RuleStage::Flush {
+                    let mut d = self.0.write();
+                    d.content.push('!');
+                    d.rev += 1;
+                    d.since_flush += 1;
+                }
+                Vec::new()
+            }
+        }
+
+        let base = std::env::temp_dir().join(format!("storage-during-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        let slug = "busy-doc";
+        let doc = Arc::new(RwLock::new(Doc {
+            content: "typing".into(),
+            rev: 2,
+            since_flush: 2,
+            ..Default::default()
+        }));
+        state.docs.write().insert(slug.into(), doc.clone());
+        state
+            .validation_hooks
+            .push(Arc::new(TypeDuringFlush(doc.clone())));
+
+        assert!(flush_snapshot_force(&state, slug).await.unwrap());
+        let snap = fs::read_to_string(snapshot_path(&state, slug).unwrap()).unwrap();
+        assert_eq!(snap, "typing");
+        let d = doc.read();
+        assert_eq!((d.content.as_str(), d.rev), ("typing!", 3));
+        assert_eq!((d.meta.snapshot_rev, d.since_flush), (2, 1));
+    }
+
     #[tokio::test]
     async fn flush_snapshot_force_ignores_idle_threshold() {
         let base = std::env::temp_dir().join(format!("storage-force-{}", Uuid::new_v4()));
@@ -295,6 +1121,8 @@ mod tests {
             cursor_before: None,
             cursor_after: None,
             ts: None,
+            group_id: None,
+            user_id: None,
         };
 
         wal_append_event(
@@ -382,4 +1210,264 @@ mod tests {
         persist_password_hash(&state, slug, None).unwrap();
         assert!(!path.exists());
     }
+
+    #[test]
+    fn password_hashes_move_out_of_the_snapshot_tree() {
+        let base = std::env::temp_dir().join(format!("storage-secrets-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "team/old";
+        let legacy = legacy_password_path(&state, slug).unwrap();
+        fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+        fs::write(&legacy, "abc\n").unwrap();
+
+        assert_eq!(
+            load_password_hash(&state, slug).unwrap().as_deref(),
+            Some("abc")
+        );
+        assert!(!legacy.exists());
+        let moved = password_path(&state, slug).unwrap();
+        assert!(moved.starts_with(&state.secrets_dir));
+        assert!(!moved.starts_with(&state.snap_dir));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&moved), 0o600);
+            assert_eq!(mode(moved.parent().unwrap()), 0o700);
+            // Rewriting replaces a file whose permissions were loosened.
+            fs::set_permissions(&moved, fs::Permissions::from_mode(0o644)).unwrap();
+            persist_password_hash(&state, slug, Some("def")).unwrap();
+            assert_eq!(mode(&moved), 0o600);
+            assert_eq!(fs::read_to_string(&moved).unwrap(), "def");
+            persist_password_hash(&state, slug, Some("abc")).unwrap();
+        }
+        assert_eq!(
+            load_password_hash(&state, slug).unwrap().as_deref(),
+            Some("abc")
+        );
+    }
+
+    #[test]
+    fn startup_moves_every_legacy_password_hash() {
+        let base = std::env::temp_dir().join(format!("storage-migrate-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        for slug in ["a", "team/b"] {
+            let legacy = legacy_password_path(&state, slug).unwrap();
+            fs::create_dir_all(legacy.parent().unwrap()).unwrap();
+            fs::write(&legacy, slug).unwrap();
+        }
+        assert_eq!(migrate_password_hashes(&state).unwrap(), 2);
+        for slug in ["a", "team/b"] {
+            assert!(!legacy_password_path(&state, slug).unwrap().exists());
+            let moved = fs::read_to_string(password_path(&state, slug).unwrap()).unwrap();
+            assert_eq!(moved, slug);
+        }
+        assert_eq!(migrate_password_hashes(&state).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn compressed_storage_roundtrips_and_reads_plain_files() {
+        let base = std::env::temp_dir().join(format!("storage-zstd-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let mut state = mk_state(&base);
+        let slug = "zipped/doc";
+        let mk_edit = |text: &str| Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: None,
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        wal_append_event(&state, slug, &DocEvent::Edit { edit: mk_edit("a") }, 1).unwrap();
+        state.compress_storage = true;
+        wal_append_event(&state, slug, &DocEvent::Edit { edit: mk_edit("b") }, 2).unwrap();
+        let wal = read_wal(&state, slug).unwrap().unwrap();
+        assert_eq!(wal.lines().count(), 2);
+        assert!(compressed_path(&wal_path(&state, slug).unwrap()).exists());
+
+        write_snapshot(&state, slug, "plain").unwrap();
+        assert!(!snapshot_path(&state, slug).unwrap().exists());
+        assert_eq!(
+            read_snapshot(&state, slug).unwrap().as_deref(),
+            Some("plain")
+        );
+
+        state.compress_storage = false;
+        write_snapshot(&state, slug, "again").unwrap();
+        assert!(!compressed_path(&snapshot_path(&state, slug).unwrap()).exists());
+        assert_eq!(
+            read_snapshot(&state, slug).unwrap().as_deref(),
+            Some("again")
+        );
+
+        let slugs = collect_slugs_with_extension(&state.wal_dir, "jsonl", true).unwrap();
+        assert_eq!(slugs, vec![slug.to_string()]);
+    }
+
+    fn insert_event(text: &str) -> DocEvent {
+        DocEvent::Edit {
+            edit: Edit {
+                base_rev: 0,
+                ops: vec![OpKind::Insert {
+                    pos: 0,
+                    text: text.into(),
+                }],
+                client_id: None,
+                op_id: None,
+                cursor_before: None,
+                cursor_after: None,
+                ts: None,
+                group_id: None,
+                user_id: None,
+            },
+        }
+    }
+
+    fn inserted_texts(wal: &str) -> Vec<String> {
+        wal.lines()
+            .map(|line| {
+                let entry: WalEntryV2 = serde_json::from_str(line).unwrap();
+                match entry.event {
+                    DocEvent::Edit { edit } => match &edit.ops[0] {
+                        OpKind::Insert { text, .. } => text.clone(),
+                        op => panic!("unexpected {op:?}"),
+                    },
+                    event => panic!("unexpected {event:?}"),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn switching_compression_moves_the_wal_over_in_order() {
+        let base = std::env::temp_dir().join(format!("storage-switch-{}", Uuid::new_v4()));
+        let mut state = mk_state(&base);
+        let slug = "switched";
+        let plain = wal_path(&state, slug).unwrap();
+        state.compress_storage = true;
+        wal_append_event(&state, slug, &insert_event("a"), 1).unwrap();
+        wal_append_event(&state, slug, &insert_event("b"), 2).unwrap();
+        state.compress_storage = false;
+        wal_append_event(&state, slug, &insert_event("c"), 3).unwrap();
+        assert!(!compressed_path(&plain).exists());
+        assert_eq!(
+            inserted_texts(&read_wal(&state, slug).unwrap().unwrap()),
+            ["a", "b", "c"]
+        );
+
+        state.compress_storage = true;
+        wal_append_event(&state, slug, &insert_event("d"), 4).unwrap();
+        assert!(!plain.exists());
+        let streamed: Vec<String> = wal_lines(&state, slug)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(inserted_texts(&streamed.join("\n")), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn wals_left_in_both_formats_read_back_by_time() {
+        let base = std::env::temp_dir().join(format!("storage-legacy-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        let slug = "legacy";
+        let line = |text: &str, ts: u64| {
+            let entry = WalEntryV2 {
+                version: CURRENT_WAL_VERSION,
+                ts,
+                server_ts: Some(ts),
+                event: insert_event(text),
+            };
+            format!("{}\n", serde_json::to_string(&entry).unwrap())
+        };
+        let plain = wal_path(&state, slug).unwrap();
+        fs::create_dir_all(plain.parent().unwrap()).unwrap();
+        fs::write(&plain, line("a", 1) + &line("c", 3)).unwrap();
+        let mut compressed = zstd::encode_all(line("b", 2).as_bytes(), 0).unwrap();
+        compressed.extend(zstd::encode_all(line("d", 4).as_bytes(), 0).unwrap());
+        fs::write(compressed_path(&plain), compressed).unwrap();
+
+        let wal = read_wal(&state, slug).unwrap().unwrap();
+        assert_eq!(inserted_texts(&wal), ["a", "b", "c", "d"]);
+        let streamed: Vec<String> = wal_lines(&state, slug)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(streamed.join("\n") + "\n", wal);
+    }
+
+    #[test]
+    fn compressed_wals_are_sealed_into_segments() {
+        let base = std::env::temp_dir().join(format!("storage-segments-{}", Uuid::new_v4()));
+        let mut state = mk_state(&base);
+        state.compress_storage = true;
+        let slug = "segments";
+        let path = compressed_path(&wal_path(&state, slug).unwrap());
+        let count = 3_000;
+        for i in 0..count {
+            let text = format!("line {i} of a fairly repetitive write-ahead log");
+            wal_append_event(&state, slug, &insert_event(&text), i).unwrap();
+        }
+        let raw = fs::read(&path).unwrap();
+        let tail = scan_segments(&raw).unwrap();
+        assert!(tail.sealed_len > 0);
+        let wal = read_wal(&state, slug).unwrap().unwrap();
+        assert_eq!(inserted_texts(&wal).len(), count as usize);
+        assert!(raw.len() * 5 < wal.len(), "{} of {}", raw.len(), wal.len());
+
+        // A fresh lock has to find the segments from the file itself.
+        state.wal_locks.lock().clear();
+        wal_append_event(&state, slug, &insert_event("last"), count).unwrap();
+        let wal = read_wal(&state, slug).unwrap().unwrap();
+        assert_eq!(inserted_texts(&wal).last().unwrap(), "last");
+        assert_eq!(
+            scan_segments(&fs::read(&path).unwrap()).unwrap().sealed_len,
+            tail.sealed_len
+        );
+    }
+
+    #[test]
+    fn persist_meta_roundtrips_sidecar() {
+        let base = std::env::temp_dir().join(format!("storage-meta-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let slug = "nested/meta";
+        assert!(load_meta(&state, slug).unwrap().is_none());
+
+        let meta = crate::types::DocMeta {
+            owner_hash: Some("owner".into()),
+            snapshot_rev: 3,
+            archived_at: None,
+            content_type: Some(crate::types::ContentType::Json),
+            scrubbed_rev: 0,
+            settings: crate::types::DocSettings {
+                flush_max_ops: Some(5),
+                ..Default::default()
+            },
+            versions: [(Uuid::new_v4(), 4)].into(),
+            tags: Some(vec!["plan".into()]),
+            front_matter: Some(crate::front_matter::FrontMatter {
+                title: Some("Plan".into()),
+                ..Default::default()
+            }),
+            mentions: Some(vec!["ann".into()]),
+            seen: [(Uuid::new_v4(), 2)].into(),
+            followers: [Uuid::new_v4()].into(),
+        };
+        persist_meta(&state, slug, &meta).unwrap();
+
+        assert_eq!(load_meta(&state, slug).unwrap(), Some(meta));
+        assert!(
+            meta_path(&state, slug)
+                .unwrap()
+                .ends_with("nested/meta.meta.json")
+        );
+    }
 }
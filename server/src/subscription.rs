@@ -0,0 +1,330 @@
+//! Per-socket broadcast filters and delivery lanes. A client names the
+//! message classes it wants in its `Hello`; [`broadcast`](crate::state::broadcast)
+//! skips the rest before they are queued. Cursor, IME and presence traffic
+//! waits in a [`PresenceLane`] so it never holds up edits.
+
+use std::{collections::VecDeque, sync::Arc};
+
+use parking_lot::Mutex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, mpsc};
+use uuid::Uuid;
+
+use crate::types::{ImeEvent, ServerMsg};
+
+/// Presence messages a lane holds before cursor and IME updates are dropped.
+pub const PRESENCE_LANE_CAP: usize = 256;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageClass {
+    /// `applied` and `line_applied`.
+    Edits,
+    Cursors,
+    Ime,
+    /// `presence_snapshot` and `presence_diff`.
+    Presence,
+}
+
+impl MessageClass {
+    /// The class `msg` is filtered by, or `None` for messages every
+    /// subscriber gets (errors, warnings, resyncs and the like).
+    pub fn of(msg: &ServerMsg) -> Option<Self> {
+        match msg {
+            ServerMsg::Applied { .. } | ServerMsg::LineApplied { .. } => Some(Self::Edits),
+            ServerMsg::Cursor { .. } => Some(Self::Cursors),
+            ServerMsg::Ime { .. } => Some(Self::Ime),
+            ServerMsg::PresenceSnapshot { .. } | ServerMsg::PresenceDiff { .. } => {
+                Some(Self::Presence)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether messages of this class wait in the presence lane.
+    pub fn is_presence(self) -> bool {
+        matches!(self, Self::Cursors | Self::Ime | Self::Presence)
+    }
+}
+
+/// Low-priority queue of one socket. The socket drains it only once its
+/// main channel is empty; a cursor replaces the queued one of the same
+/// client and IME updates replace the previous update, so a slow reader
+/// gets the latest state instead of the whole backlog.
+#[derive(Debug, Default)]
+pub struct PresenceLane {
+    queue: Mutex<VecDeque<ServerMsg>>,
+    ready: Notify,
+}
+
+impl PresenceLane {
+    pub fn push(&self, msg: ServerMsg) {
+        {
+            let mut queue = self.queue.lock();
+            if let Some(idx) = superseded(&queue, &msg) {
+                queue[idx] = msg;
+                return;
+            }
+            if queue.len() >= PRESENCE_LANE_CAP
+                && let Some(idx) = queue.iter().position(is_droppable)
+            {
+                queue.remove(idx);
+            }
+            queue.push_back(msg);
+        }
+        self.ready.notify_one();
+    }
+
+    /// Waits until something was pushed since the last wait.
+    pub async fn ready(&self) {
+        self.ready.notified().await
+    }
+
+    /// Messages waiting in the lane.
+    pub fn pending(&self) -> usize {
+        self.queue.lock().len()
+    }
+
+    pub fn drain(&self) -> Vec<ServerMsg> {
+        self.queue.lock().drain(..).collect()
+    }
+}
+
+/// Position of the queued message `msg` makes obsolete. Only the latest IME
+/// message of a client is looked at, so a start or commit is never
+/// overtaken.
+fn superseded(queue: &VecDeque<ServerMsg>, msg: &ServerMsg) -> Option<usize> {
+    match msg {
+        ServerMsg::Cursor { client_id, .. } => queue.iter().position(
+            |queued| matches!(queued, ServerMsg::Cursor { client_id: c, .. } if c == client_id),
+        ),
+        ServerMsg::Ime {
+            client_id,
+            ime: ImeEvent::Update { .. },
+            ..
+        } => queue
+            .iter()
+            .rposition(
+                |queued| matches!(queued, ServerMsg::Ime { client_id: c, .. } if c == client_id),
+            )
+            .filter(|&idx| {
+                matches!(
+                    queue[idx],
+                    ServerMsg::Ime {
+                        ime: ImeEvent::Update { .. },
+                        ..
+                    }
+                )
+            }),
+        _ => None,
+    }
+}
+
+fn is_droppable(msg: &ServerMsg) -> bool {
+    matches!(
+        msg,
+        ServerMsg::Cursor { .. }
+            | ServerMsg::Ime {
+                ime: ImeEvent::Update { .. },
+                ..
+            }
+    )
+}
+
+/// A socket joined to a document and the broadcasts it asked for.
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    pub tx: mpsc::UnboundedSender<ServerMsg>,
+    /// Set by `Hello`; until then the socket gets everything.
+    pub client_id: Option<Uuid>,
+    /// `None` subscribes to every class.
+    pub classes: Option<Vec<MessageClass>>,
+    /// Where presence classes go instead of `tx`, for sockets that drain
+    /// one.
+    pub lane: Option<Arc<PresenceLane>>,
+}
+
+impl Subscriber {
+    /// Whether `msg` should be queued for this socket. A client that left
+    /// out edits still sees its own, since they double as acknowledgements.
+    pub fn wants(&self, msg: &ServerMsg) -> bool {
+        let (Some(classes), Some(class)) = (&self.classes, MessageClass::of(msg)) else {
+            return true;
+        };
+        if classes.contains(&class) {
+            return true;
+        }
+        match msg {
+            ServerMsg::Applied { client_id, .. } | ServerMsg::LineApplied { client_id, .. } => {
+                client_id.is_some() && *client_id == self.client_id
+            }
+            _ => false,
+        }
+    }
+
+    /// Queues `msg` on the lane it belongs to. Returns `false` once the
+    /// socket is gone.
+    pub fn send(&self, msg: ServerMsg) -> bool {
+        match &self.lane {
+            Some(lane) if MessageClass::of(&msg).is_some_and(MessageClass::is_presence) => {
+                lane.push(msg);
+                !self.tx.is_closed()
+            }
+            _ => self.tx.send(msg).is_ok(),
+        }
+    }
+}
+
+impl From<mpsc::UnboundedSender<ServerMsg>> for Subscriber {
+    fn from(tx: mpsc::UnboundedSender<ServerMsg>) -> Self {
+        Self {
+            tx,
+            client_id: None,
+            classes: None,
+            lane: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(client_id: Option<Uuid>) -> ServerMsg {
+        ServerMsg::Applied {
+            slug: "doc".into(),
+            rev: 1,
+            ops: vec![],
+            client_id,
+            op_id: None,
+            ts: 0,
+            hash: None,
+            chars: None,
+            stats: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[test]
+    fn filters_by_class_but_keeps_own_edits_and_unclassified_messages() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let me = Uuid::new_v4();
+        let sub = Subscriber {
+            client_id: Some(me),
+            classes: Some(vec![MessageClass::Presence]),
+            ..Subscriber::from(tx)
+        };
+        let diff = ServerMsg::PresenceDiff {
+            slug: "doc".into(),
+            added: vec![],
+            updated: vec![],
+            removed: vec![],
+        };
+        let error = ServerMsg::Error {
+            slug: "doc".into(),
+            code: "x".into(),
+            message: "x".into(),
+            op_id: None,
+        };
+        assert!(sub.wants(&diff));
+        assert!(sub.wants(&error));
+        assert!(sub.wants(&applied(Some(me))));
+        assert!(!sub.wants(&applied(Some(Uuid::new_v4()))));
+        assert!(!sub.wants(&applied(None)));
+
+        let everything = Subscriber {
+            classes: None,
+            ..sub
+        };
+        assert!(everything.wants(&applied(None)));
+    }
+
+    fn cursor(client_id: Uuid, position: usize) -> ServerMsg {
+        ServerMsg::Cursor {
+            slug: "doc".into(),
+            client_id,
+            cursor: crate::types::CursorState {
+                position,
+                anchor: None,
+                selection_direction: None,
+            },
+            op_id: None,
+            ts: 0,
+        }
+    }
+
+    fn ime(client_id: Uuid, ime: ImeEvent) -> ServerMsg {
+        ServerMsg::Ime {
+            slug: "doc".into(),
+            client_id,
+            ime,
+            op_id: None,
+            ts: 0,
+        }
+    }
+
+    #[test]
+    fn presence_lane_coalesces_cursors_and_ime_updates() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let range = crate::types::TextRange { start: 0, end: 1 };
+        let update = |text: &str| ImeEvent::Update {
+            range: range.clone(),
+            text: text.into(),
+        };
+        let lane = PresenceLane::default();
+        lane.push(cursor(a, 1));
+        lane.push(cursor(b, 1));
+        lane.push(cursor(a, 2));
+        lane.push(ime(a, update("k")));
+        lane.push(ime(a, update("ka")));
+        lane.push(ime(
+            a,
+            ImeEvent::Commit {
+                replace_range: range.clone(),
+                text: "か".into(),
+            },
+        ));
+        lane.push(ime(a, update("n")));
+        assert_eq!(
+            lane.drain(),
+            vec![
+                cursor(a, 2),
+                cursor(b, 1),
+                ime(a, update("ka")),
+                ime(
+                    a,
+                    ImeEvent::Commit {
+                        replace_range: range.clone(),
+                        text: "か".into(),
+                    }
+                ),
+                ime(a, update("n")),
+            ]
+        );
+        assert!(lane.drain().is_empty());
+
+        for _ in 0..PRESENCE_LANE_CAP + 10 {
+            lane.push(cursor(Uuid::new_v4(), 0));
+        }
+        assert_eq!(lane.drain().len(), PRESENCE_LANE_CAP);
+    }
+
+    #[test]
+    fn subscriber_with_a_lane_keeps_presence_off_the_main_channel() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let lane = Arc::new(PresenceLane::default());
+        let sub = Subscriber {
+            lane: Some(lane.clone()),
+            ..Subscriber::from(tx)
+        };
+        assert!(sub.send(cursor(Uuid::new_v4(), 0)));
+        assert!(sub.send(applied(None)));
+        assert!(matches!(rx.try_recv(), Ok(ServerMsg::Applied { .. })));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(lane.drain().len(), 1);
+        drop(rx);
+        assert!(!sub.send(cursor(Uuid::new_v4(), 0)));
+    }
+}
@@ -0,0 +1,201 @@
+//! Document tags: `#hashtags` in the text and `tags` in the
+//! [front-matter](crate::front_matter). They are stored in the metadata
+//! sidecar on every flush, and the tag index is built from disk on first use
+//! and kept up to date by [`record_tags`] afterwards, like the link index.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::Serialize;
+
+use crate::{
+    front_matter::{FrontMatter, split_front_matter},
+    state::AppState,
+    storage::{collect_slugs_with_extension, load_meta, read_snapshot},
+    toc::fenced,
+    types::ContentType,
+};
+
+const MAX_TAG_CHARS: usize = 64;
+
+#[derive(Debug, Default)]
+pub struct TagIndex {
+    by_doc: HashMap<String, BTreeSet<String>>,
+    docs: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl TagIndex {
+    fn set(&mut self, slug: &str, tags: BTreeSet<String>) {
+        self.remove(slug);
+        for tag in &tags {
+            self.docs
+                .entry(tag.clone())
+                .or_default()
+                .insert(slug.to_string());
+        }
+        if !tags.is_empty() {
+            self.by_doc.insert(slug.to_string(), tags);
+        }
+    }
+
+    fn remove(&mut self, slug: &str) {
+        for tag in self.by_doc.remove(slug).unwrap_or_default() {
+            if let Some(docs) = self.docs.get_mut(&tag) {
+                docs.remove(slug);
+                if docs.is_empty() {
+                    self.docs.remove(&tag);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct TagCount {
+    pub tag: String,
+    pub docs: usize,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct TagsResp {
+    pub tags: Vec<TagCount>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct TaggedDocsResp {
+    pub tag: String,
+    pub docs: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub front_matter: BTreeMap<String, FrontMatter>,
+}
+
+/// Lowercased, without the `#`. Tags are letters, digits, `_`, `-` and `/`
+/// for nesting, and not only digits, so `#1` stays an issue number.
+pub fn normalize_tag(raw: &str) -> Option<String> {
+    let tag = raw.trim().trim_start_matches('#').to_lowercase();
+    let valid = !tag.is_empty()
+        && tag.chars().count() <= MAX_TAG_CHARS
+        && tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '/'))
+        && !tag.chars().all(|c| c.is_ascii_digit())
+        && !tag.starts_with('/')
+        && !tag.ends_with('/');
+    valid.then_some(tag)
+}
+
+/// Tags of a Markdown document: the front-matter `tags` and `#tag`s that
+/// start a word outside code.
+pub fn extract_tags(content: &str) -> BTreeSet<String> {
+    let (front, body) = split_front_matter(content);
+    let mut tags: BTreeSet<String> = front.into_iter().flat_map(|front| front.tags).collect();
+    let mut fence = None;
+    for line in content[body..].lines() {
+        if fenced(&mut fence, line.trim_start()) {
+            continue;
+        }
+        // Odd pieces are inside code spans.
+        for text in line.split('`').step_by(2) {
+            for word in text.split_whitespace() {
+                let Some(rest) = word.strip_prefix('#') else {
+                    continue;
+                };
+                let end = rest
+                    .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '/')))
+                    .unwrap_or(rest.len());
+                tags.extend(normalize_tag(rest[..end].trim_end_matches('/')));
+            }
+        }
+    }
+    tags
+}
+
+fn scan_disk(state: &AppState) -> anyhow::Result<TagIndex> {
+    let mut index = TagIndex::default();
+    for slug in collect_slugs_with_extension(&state.snap_dir, "md", false)? {
+        let meta = load_meta(state, &slug)?.unwrap_or_default();
+        let tags = match meta.tags {
+            Some(tags) => tags.into_iter().collect(),
+            // Flushed before tags were kept in the sidecar.
+            None if meta.content_type.unwrap_or_default() == ContentType::Markdown => {
+                extract_tags(&read_snapshot(state, &slug)?.unwrap_or_default())
+            }
+            None => BTreeSet::new(),
+        };
+        index.set(&slug, tags);
+    }
+    Ok(index)
+}
+
+fn with_index<T>(state: &AppState, read: impl FnOnce(&TagIndex) -> T) -> anyhow::Result<T> {
+    if state.tags.read().is_none() {
+        let index = scan_disk(state)?;
+        state.tags.write().get_or_insert(index);
+    }
+    let tags = state.tags.read();
+    Ok(read(tags.as_ref().expect("tag index was just built")))
+}
+
+/// Every tag with the documents carrying it, sorted by tag.
+pub fn all_tags(state: &AppState) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+    with_index(state, |index| {
+        index
+            .docs
+            .iter()
+            .map(|(tag, docs)| (tag.clone(), docs.iter().cloned().collect()))
+            .collect()
+    })
+}
+
+/// Documents tagged `tag` as of their last flush, sorted.
+pub fn tagged_docs(state: &AppState, tag: &str) -> anyhow::Result<Vec<String>> {
+    with_index(state, |index| {
+        index
+            .docs
+            .get(tag)
+            .map(|docs| docs.iter().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Updates the index with the tags a flush stored in the sidecar.
+pub fn record_tags(state: &AppState, slug: &str, tags: &[String]) {
+    if let Some(index) = state.tags.write().as_mut() {
+        index.set(slug, tags.iter().cloned().collect());
+    }
+}
+
+/// Drops the tags of a document that was deleted or archived.
+pub fn forget_tags(state: &AppState, slug: &str) {
+    if let Some(index) = state.tags.write().as_mut() {
+        index.remove(slug);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_front_matter_and_hashtags() {
+        let content = "---\ntitle: Plan\ntags: [Roadmap, \"q3\"]\nauthors:\n  - ann\n---\n\
+            # Heading\nShip it #launch/beta and #Launch, not #1 or a#b.\n\
+            See [x](#anchor) `#code`\n```\n#fenced\n```\n";
+        let tags: Vec<String> = extract_tags(content).into_iter().collect();
+        assert_eq!(tags, vec!["launch", "launch/beta", "q3", "roadmap"]);
+
+        let listed = "---\ntags:\n  - one\n  - Two\nother: - x\n---\nbody";
+        let tags: Vec<String> = extract_tags(listed).into_iter().collect();
+        assert_eq!(tags, vec!["one", "two"]);
+    }
+
+    #[test]
+    fn index_lists_docs_per_tag() {
+        let mut index = TagIndex::default();
+        index.set("a", ["x".to_string(), "y".to_string()].into());
+        index.set("b", ["x".to_string()].into());
+        index.set("a", ["y".to_string()].into());
+        assert_eq!(index.docs["x"], BTreeSet::from(["b".to_string()]));
+        index.remove("b");
+        assert!(!index.docs.contains_key("x"));
+    }
+}
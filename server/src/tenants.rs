@@ -0,0 +1,270 @@
+//! Several independent products on one instance. Each tenant has its own
+//! API keys, documents under `DATA_DIR/tenants/{id}`, a quota on the bytes
+//! stored there and its own allowed origins. A request carrying one of its
+//! keys, in the `X-Api-Key` header or, for browsers opening a WebSocket, the
+//! `api_key` query parameter, is served by the tenant's own [`AppState`], so
+//! the same slug names different documents for different tenants. Requests
+//! without a key are served by the instance itself, as before.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{Context, bail};
+use axum::{
+    Router,
+    extract::{Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tower::ServiceExt;
+
+use crate::{build_router, state::AppState, storage::slug_to_rel_path};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct Tenant {
+    /// Names the tenant's data directory.
+    pub id: String,
+    pub api_keys: Vec<String>,
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    /// Origins that may open WebSockets; empty keeps the instance's own.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+/// Reads the JSON list of tenants at `path`.
+pub fn load_tenants(path: &Path) -> anyhow::Result<Vec<Tenant>> {
+    let raw =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let tenants: Vec<Tenant> =
+        serde_json::from_str(&raw).with_context(|| format!("invalid {}", path.display()))?;
+    let mut ids = HashSet::new();
+    let mut keys = HashSet::new();
+    for tenant in &tenants {
+        if slug_to_rel_path(&tenant.id)?.components().count() != 1 {
+            bail!("tenant id '{}' must be a single path segment", tenant.id);
+        }
+        if !ids.insert(tenant.id.as_str()) {
+            bail!("tenant '{}' is listed twice", tenant.id);
+        }
+        if tenant.api_keys.is_empty() {
+            bail!("tenant '{}' has no API keys", tenant.id);
+        }
+        for key in &tenant.api_keys {
+            if key.trim().is_empty() || !keys.insert(key.as_str()) {
+                bail!("tenant '{}' has an empty or shared API key", tenant.id);
+            }
+        }
+    }
+    Ok(tenants)
+}
+
+/// A state for `tenant` with the settings of `base` and nothing else of it:
+/// its documents, sessions and counters start out empty.
+pub fn tenant_state(base: &AppState, data_dir: &Path, tenant: &Tenant) -> anyhow::Result<AppState> {
+    let dir = data_dir.join("tenants").join(&tenant.id);
+    let (wal_dir, snap_dir) = (dir.join("wal"), dir.join("snapshots"));
+    fs::create_dir_all(&wal_dir)?;
+    fs::create_dir_all(&snap_dir)?;
+    let live = base.live.read().clone();
+    let own_origins = Some(tenant.allowed_origins.clone()).filter(|o| !o.is_empty());
+    let mut state = AppState::new(
+        wal_dir,
+        snap_dir,
+        live.flush_idle_ms,
+        live.flush_max_ops,
+        base.app_env_dev,
+        own_origins.clone().unwrap_or(live.allowed_origins),
+    );
+    // Reloads read the instance's settings; the log filter is the
+    // instance's to set.
+    state.config_file = base.config_file.clone();
+    state.own_origins = own_origins;
+    state.quota_bytes = tenant.quota_bytes;
+    state.trash_retention_days = base.trash_retention_days;
+    state.archive_compress = base.archive_compress;
+    state.compress_storage = base.compress_storage;
+    state.invite_only = base.invite_only;
+    state.admin_token = base.admin_token.clone();
+    state.digest_target = base.digest_target.clone();
+    state.digest_interval_ms = base.digest_interval_ms;
+    state.validation_hooks = base.validation_hooks.clone();
+    state.analyzers = base.analyzers.clone();
+    state.assist = base.assist.clone();
+    state.transforms = base.transforms.clone();
+    state.retention = base.retention.clone();
+    state.retention_interval_ms = base.retention_interval_ms;
+    state.hash_interval = base.hash_interval;
+    state.stats_interval = base.stats_interval;
+    state.require_ws_ticket = base.require_ws_ticket;
+    state.replica = base.replica.clone();
+    state.strict_ops = base.strict_ops;
+    state.max_clock_skew_ms = base.max_clock_skew_ms;
+    state.ws_compress_threshold = base.ws_compress_threshold;
+    state.ws_ping_interval_ms = base.ws_ping_interval_ms;
+    state.ws_echo_protocol = base.ws_echo_protocol;
+    state.reauth_grace_ms = base.reauth_grace_ms;
+    state.wal_buffer_cap = base.wal_buffer_cap;
+    state.min_free_bytes = base.min_free_bytes;
+    state.alert_policy = base.alert_policy.clone();
+    state.alert_interval_ms = base.alert_interval_ms;
+    // Same disk, watched once.
+    state.disk = base.disk.clone();
+    Ok(state)
+}
+
+fn key_hash(key: &str) -> [u8; 32] {
+    Sha256::digest(key.trim().as_bytes()).into()
+}
+
+/// Tenant routers by the hash of each of their keys.
+#[derive(Clone, Default)]
+pub struct TenantRouters {
+    by_key: Arc<HashMap<[u8; 32], Router>>,
+}
+
+impl TenantRouters {
+    pub fn new(tenants: &[(Tenant, AppState)]) -> Self {
+        let mut by_key = HashMap::new();
+        for (tenant, state) in tenants {
+            let router = build_router(state);
+            for key in &tenant.api_keys {
+                by_key.insert(key_hash(key), router.clone());
+            }
+        }
+        Self {
+            by_key: Arc::new(by_key),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyParam {
+    api_key: Option<String>,
+}
+
+fn api_key(req: &Request) -> Option<String> {
+    req.headers()
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| {
+            Query::<KeyParam>::try_from_uri(req.uri())
+                .ok()
+                .and_then(|Query(param)| param.api_key)
+        })
+        .filter(|key| !key.trim().is_empty())
+}
+
+/// Hands requests with an API key to their tenant's router.
+pub async fn route_tenant(
+    State(routers): State<TenantRouters>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = api_key(&req) else {
+        return next.run(req).await;
+    };
+    let Some(router) = routers.by_key.get(&key_hash(&key)).cloned() else {
+        return (StatusCode::UNAUTHORIZED, "unknown API key").into_response();
+    };
+    match router.oneshot(req).await {
+        Ok(resp) => resp,
+        Err(never) => match never {},
+    }
+}
+
+/// `app` with requests that carry an API key served by their tenant.
+pub fn with_tenants(app: Router, routers: TenantRouters) -> Router {
+    app.layer(axum::middleware::from_fn_with_state(routers, route_tenant))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use uuid::Uuid;
+
+    fn create(slug: &str, key: Option<&str>) -> Request {
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/api/docs")
+            .header("content-type", "application/json")
+            .body(Body::from(format!(
+                r#"{{"slug":"{}","content":"hello"}}"#,
+                slug
+            )))
+            .unwrap();
+        if let Some(key) = key {
+            req.headers_mut()
+                .insert(API_KEY_HEADER, key.parse().unwrap());
+        }
+        req
+    }
+
+    fn snapshot(slug: &str, query_key: Option<&str>) -> Request {
+        let key = query_key
+            .map(|k| format!("&api_key={}", k))
+            .unwrap_or_default();
+        Request::builder()
+            .uri(format!("/api/snapshot?slug={}{}", slug, key))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn keys_pick_an_isolated_tenant() {
+        let base_dir = std::env::temp_dir().join(format!("tenants-{}", Uuid::new_v4()));
+        let base = AppState::new(
+            base_dir.join("wal"),
+            base_dir.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        );
+        let tenants: Vec<(Tenant, AppState)> =
+            [("acme", "k-acme", None), ("beta", "k-beta", Some(4))]
+                .into_iter()
+                .map(|(id, key, quota_bytes)| {
+                    let tenant = Tenant {
+                        id: id.into(),
+                        api_keys: vec![key.into()],
+                        quota_bytes,
+                        allowed_origins: Vec::new(),
+                    };
+                    let state = tenant_state(&base, &base_dir, &tenant).unwrap();
+                    (tenant, state)
+                })
+                .collect();
+        let app = with_tenants(build_router(&base), TenantRouters::new(&tenants));
+
+        let created = app.clone().oneshot(create("notes", Some("k-acme"))).await;
+        assert_eq!(created.unwrap().status(), StatusCode::CREATED);
+        assert!(base_dir.join("tenants/acme/snapshots/notes.md").exists());
+        assert!(!base_dir.join("snapshots/notes.md").exists());
+
+        let read = app.clone().oneshot(snapshot("notes", Some("k-acme"))).await;
+        let body = axum::body::to_bytes(read.unwrap().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("hello"));
+        // Neither the instance itself nor another tenant has it.
+        assert!(!base.docs.read().contains_key("notes"));
+        assert!(!tenants[1].1.docs.read().contains_key("notes"));
+
+        let over = app.clone().oneshot(create("notes", Some("k-beta"))).await;
+        assert_eq!(over.unwrap().status(), StatusCode::INSUFFICIENT_STORAGE);
+        let unknown = app.oneshot(snapshot("notes", Some("nope"))).await;
+        assert_eq!(unknown.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+}
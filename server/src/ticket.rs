@@ -0,0 +1,96 @@
+//! Short-lived, single-use tickets for the WebSocket upgrade. A browser
+//! fetches one from `POST /api/ws-ticket` with its credentials and presents
+//! it as `?ticket=` on `/api/ws`, so the password itself never has to ride
+//! on the WebSocket URL or in a `Join` message.
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::state::AppState;
+
+pub const TICKET_TTL_MS: u64 = 30_000;
+
+#[derive(Debug, Clone)]
+pub struct TicketEntry {
+    slug: String,
+    expires_at: u64,
+}
+
+pub type TicketStore = Arc<Mutex<HashMap<String, TicketEntry>>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WsTicket {
+    pub ticket: String,
+    /// Milliseconds since the epoch after which the ticket is refused.
+    pub expires_at: u64,
+}
+
+/// Issues a ticket good for one upgrade to `slug`. The caller has already
+/// checked the credentials.
+pub fn issue_ticket(state: &AppState, slug: &str, now: u64) -> WsTicket {
+    let ticket = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let expires_at = now + TICKET_TTL_MS;
+    let mut tickets = state.ws_tickets.lock();
+    tickets.retain(|_, entry| entry.expires_at > now);
+    tickets.insert(
+        ticket.clone(),
+        TicketEntry {
+            slug: slug.to_string(),
+            expires_at,
+        },
+    );
+    WsTicket { ticket, expires_at }
+}
+
+/// Consumes `ticket`; true when it was issued for `slug` and has not expired.
+/// A ticket presented for the wrong document is spent all the same.
+pub fn redeem_ticket(state: &AppState, ticket: &str, slug: &str, now: u64) -> bool {
+    state
+        .ws_tickets
+        .lock()
+        .remove(ticket)
+        .is_some_and(|entry| entry.slug == slug && entry.expires_at > now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("srvtest-ticket-{}", Uuid::new_v4()));
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            1_000,
+            100,
+            true,
+            vec![],
+        )
+    }
+
+    #[test]
+    fn tickets_are_single_use_and_bound_to_a_slug() {
+        let state = mk_state();
+        let now = 1_000;
+        let first = issue_ticket(&state, "doc", now);
+        assert_eq!(first.expires_at, now + TICKET_TTL_MS);
+        assert!(redeem_ticket(&state, &first.ticket, "doc", now + 1));
+        assert!(!redeem_ticket(&state, &first.ticket, "doc", now + 2));
+
+        let other = issue_ticket(&state, "doc", now);
+        assert!(!redeem_ticket(&state, &other.ticket, "elsewhere", now));
+        assert!(!redeem_ticket(&state, &other.ticket, "doc", now));
+
+        let stale = issue_ticket(&state, "doc", now);
+        assert!(!redeem_ticket(
+            &state,
+            &stale.ticket,
+            "doc",
+            now + TICKET_TTL_MS
+        ));
+        assert!(!redeem_ticket(&state, "made-up", "doc", now));
+    }
+}
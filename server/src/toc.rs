@@ -0,0 +1,180 @@
+//! Markdown table of contents. Offsets count characters, like op positions,
+//! so a sidebar can jump into the editor and move its entries along with
+//! `Applied` ops until it fetches the outline again.
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct TocResp {
+    pub slug: String,
+    /// The revision `headings` were taken from.
+    pub rev: u64,
+    pub headings: Vec<Heading>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Heading {
+    /// 1 for `#`, up to 6; setext headings are 1 (`===`) or 2 (`---`).
+    pub level: u8,
+    pub text: String,
+    /// Start of the heading line.
+    pub start: usize,
+    /// Where its section ends: the next heading of the same or a higher
+    /// level, or the end of the document.
+    pub end: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Heading>,
+}
+
+/// The heading tree of a Markdown document. Headings inside fenced code
+/// blocks are not headings; a heading deeper than the one before it nests
+/// under it even when levels are skipped.
+pub fn toc(content: &str) -> Vec<Heading> {
+    let mut flat = Vec::new();
+    let mut fence: Option<(char, usize)> = None;
+    // Char offset and text of the previous line, if it could carry a setext
+    // underline.
+    let mut paragraph: Option<(usize, &str)> = None;
+    let mut pos = 0;
+    for line in content.split_inclusive('\n') {
+        let start = pos;
+        pos += line.chars().count();
+        let line = line.trim_end_matches(['\n', '\r']);
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let body = &line[indent..];
+        if indent > 3 {
+            paragraph = None;
+            continue;
+        }
+        if fenced(&mut fence, body) {
+            paragraph = None;
+            continue;
+        }
+        if let Some((level, text)) = atx_heading(body) {
+            flat.push((level, text.to_string(), start));
+            paragraph = None;
+            continue;
+        }
+        if let (Some((at, text)), Some(level)) = (paragraph, setext_level(body)) {
+            flat.push((level, text.to_string(), at));
+            paragraph = None;
+            continue;
+        }
+        paragraph = (!body.trim().is_empty()).then_some((start, body.trim()));
+    }
+    nest(&flat, &mut 0, 0, pos)
+}
+
+/// Follows fenced code blocks line by line: whether `body`, a line without
+/// its indentation, opens, closes or sits inside one.
+pub(crate) fn fenced(fence: &mut Option<(char, usize)>, body: &str) -> bool {
+    if let Some((marker, len)) = *fence {
+        let closing = body.chars().take_while(|c| *c == marker).count();
+        if closing >= len && body[closing * marker.len_utf8()..].trim().is_empty() {
+            *fence = None;
+        }
+        return true;
+    }
+    *fence = fence_start(body);
+    fence.is_some()
+}
+
+fn fence_start(body: &str) -> Option<(char, usize)> {
+    let marker = body.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let len = body.chars().take_while(|c| *c == marker).count();
+    (len >= 3).then_some((marker, len))
+}
+
+fn atx_heading(body: &str) -> Option<(u8, &str)> {
+    let level = body.chars().take_while(|c| *c == '#').count();
+    let rest = &body[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+    let text = rest.trim();
+    // A closing run of `#` is decoration unless it is the whole text.
+    let stripped = text.trim_end_matches('#');
+    let text = if stripped.is_empty() || stripped.ends_with([' ', '\t']) {
+        stripped.trim_end()
+    } else {
+        text
+    };
+    Some((level as u8, text))
+}
+
+fn setext_level(body: &str) -> Option<u8> {
+    let underline = body.trim_end();
+    let level = match underline.chars().next()? {
+        '=' => 1,
+        '-' => 2,
+        _ => return None,
+    };
+    let marker = if level == 1 { '=' } else { '-' };
+    underline.chars().all(|c| c == marker).then_some(level)
+}
+
+/// Builds the tree from `flat[*idx..]`, taking headings deeper than
+/// `parent`; `doc_end` closes the sections that run to the end.
+fn nest(flat: &[(u8, String, usize)], idx: &mut usize, parent: u8, doc_end: usize) -> Vec<Heading> {
+    let mut out = Vec::new();
+    while let Some((level, text, start)) = flat.get(*idx) {
+        if *level <= parent {
+            break;
+        }
+        *idx += 1;
+        let children = nest(flat, idx, *level, doc_end);
+        let end = flat[*idx..]
+            .iter()
+            .find(|(next, ..)| next <= level)
+            .map_or(doc_end, |(_, _, next_start)| *next_start);
+        out.push(Heading {
+            level: *level,
+            text: text.clone(),
+            start: *start,
+            end,
+            children,
+        });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outline(headings: &[Heading]) -> Vec<(u8, &str, usize, usize)> {
+        let mut out = Vec::new();
+        for h in headings {
+            out.push((h.level, h.text.as_str(), h.start, h.end));
+            out.extend(outline(&h.children));
+        }
+        out
+    }
+
+    #[test]
+    fn nests_headings_with_char_offsets() {
+        let doc = "# Café ##\nintro\n### Deep\n## Two\ntext\n# Next\n";
+        let headings = toc(doc);
+        assert_eq!(headings.len(), 2);
+        assert_eq!(headings[0].children.len(), 2);
+        assert_eq!(
+            outline(&headings),
+            vec![
+                (1, "Café", 0, 37),
+                (3, "Deep", 16, 25),
+                (2, "Two", 25, 37),
+                (1, "Next", 37, 44),
+            ]
+        );
+        assert_eq!(doc.chars().count(), 44);
+    }
+
+    #[test]
+    fn skips_code_fences_and_reads_setext_headings() {
+        let doc = "Title\n=====\n```md\n# not a heading\n```\n#nope\n    # code\nSub\n---\n";
+        assert_eq!(
+            outline(&toc(doc)),
+            vec![(1, "Title", 0, 63), (2, "Sub", 55, 63)]
+        );
+    }
+}
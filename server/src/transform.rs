@@ -0,0 +1,303 @@
+//! Text transforms run by the server over a range of a document, or all of
+//! it: `uppercase` and `lowercase` are built in, and `TRANSFORMS_FILE` adds
+//! named ones served by an outside HTTP service (translation, say). The
+//! result is applied as one edit sharing a `group_id`, like a replace, or
+//! with `preview` only reported as the ops it would apply.
+
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    diff::diff_ops,
+    state::{AppState, Rejection, apply_edit, get_or_load_doc},
+    types::{ContentType, Edit, OpKind, TextRange},
+    webhook::post_json_reply,
+};
+
+const SERVICE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A transform served over HTTP; see [`ServiceQuery`] for what it is sent.
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TransformService {
+    pub name: String,
+    /// http:// only, like webhooks.
+    pub url: String,
+}
+
+/// Reads the `TRANSFORMS_FILE` list.
+pub fn load_transforms(path: &Path) -> anyhow::Result<Vec<TransformService>> {
+    let raw =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let services: Vec<TransformService> =
+        serde_json::from_str(&raw).with_context(|| format!("invalid {}", path.display()))?;
+    for service in &services {
+        if matches!(service.name.as_str(), "uppercase" | "lowercase") {
+            anyhow::bail!("transform '{}' is built in", service.name);
+        }
+        if !service.url.starts_with("http://") {
+            anyhow::bail!("transform '{}' needs an http:// URL", service.name);
+        }
+    }
+    Ok(services)
+}
+
+/// The body POSTed to a transform service, which answers `{"text": ...}`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ServiceQuery<'a> {
+    pub transform: &'a str,
+    pub slug: &'a str,
+    pub text: &'a str,
+    pub content_type: &'a ContentType,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ServiceReply {
+    text: String,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct TransformReport {
+    /// The revision `ops` apply to; after it when they were applied.
+    pub rev: u64,
+    pub range: TextRange,
+    pub text: String,
+    /// What changes in the document, as of the revision transformed.
+    pub ops: Vec<OpKind>,
+    pub applied: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<Uuid>,
+}
+
+async fn run_transform(
+    state: &AppState,
+    name: &str,
+    slug: &str,
+    text: &str,
+    content_type: &ContentType,
+) -> anyhow::Result<String> {
+    match name {
+        "uppercase" => return Ok(text.to_uppercase()),
+        "lowercase" => return Ok(text.to_lowercase()),
+        _ => {}
+    }
+    let Some(service) = state.transforms.iter().find(|t| t.name == name) else {
+        return Err(
+            Rejection::new("unknown_transform", format!("no transform named '{name}'")).into(),
+        );
+    };
+    let query = ServiceQuery {
+        transform: name,
+        slug,
+        text,
+        content_type,
+    };
+    match post_json_reply::<_, ServiceReply>(&service.url, &query, SERVICE_TIMEOUT).await {
+        Ok(reply) => Ok(reply.text),
+        Err(err) => {
+            warn!(
+                slug,
+                transform = name,
+                "transform service failed: {:#}",
+                err
+            );
+            Err(Rejection::new("transform_failed", format!("transform '{name}' failed")).into())
+        }
+    }
+}
+
+/// Runs the transform `name` over `range` of `slug`, or all of it, and
+/// applies the result as one edit unless `preview` is set. Edits that land
+/// while a service is working are transformed over it as usual.
+pub async fn transform_doc(
+    state: &AppState,
+    slug: &str,
+    name: &str,
+    range: Option<TextRange>,
+    preview: bool,
+) -> anyhow::Result<TransformReport> {
+    let doc = get_or_load_doc(state, slug).await?;
+    let (base_rev, content, content_type) = {
+        let d = doc.read();
+        (
+            d.rev,
+            d.content.clone(),
+            d.meta.content_type.clone().unwrap_or_default(),
+        )
+    };
+    let len = content.chars().count();
+    let range = range.unwrap_or(TextRange { start: 0, end: len });
+    if range.start > range.end || range.end > len {
+        return Err(Rejection::new(
+            "invalid_range",
+            format!("{}..{} is outside 0..{}", range.start, range.end, len),
+        )
+        .into());
+    }
+    let selected: String = content
+        .chars()
+        .skip(range.start)
+        .take(range.end - range.start)
+        .collect();
+    let text = run_transform(state, name, slug, &selected, &content_type).await?;
+    let mut ops = diff_ops(&selected, &text);
+    for op in &mut ops {
+        match op {
+            OpKind::Insert { pos, .. } | OpKind::Delete { pos, .. } => *pos += range.start,
+        }
+    }
+    let mut report = TransformReport {
+        rev: base_rev,
+        range,
+        text,
+        ops,
+        applied: false,
+        group_id: None,
+    };
+    if preview || report.ops.is_empty() {
+        return Ok(report);
+    }
+    let group_id = Uuid::new_v4();
+    let edit = Edit {
+        base_rev,
+        ops: report.ops.clone(),
+        client_id: None,
+        op_id: Some(Uuid::new_v4()),
+        cursor_before: None,
+        cursor_after: None,
+        ts: None,
+        group_id: Some(group_id),
+        user_id: None,
+    };
+    apply_edit(state, slug, edit).await?;
+    report.rev = doc.read().rev;
+    report.applied = true;
+    report.group_id = Some(group_id);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, routing::post};
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("transform-{}", Uuid::new_v4()));
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        )
+    }
+
+    async fn seed(state: &AppState, text: &str) {
+        let edit = Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        };
+        apply_edit(state, "notes", edit).await.unwrap();
+    }
+
+    fn code(err: anyhow::Error) -> &'static str {
+        err.downcast::<Rejection>().unwrap().code
+    }
+
+    #[tokio::test]
+    async fn previews_and_applies_a_range() {
+        let state = mk_state();
+        seed(&state, "hello wörld\nbye").await;
+        let range = TextRange { start: 6, end: 11 };
+
+        let preview = transform_doc(&state, "notes", "uppercase", Some(range.clone()), true)
+            .await
+            .unwrap();
+        assert_eq!(preview.text, "WÖRLD");
+        assert!(!preview.applied);
+        assert_eq!(preview.rev, 1);
+        let doc = get_or_load_doc(&state, "notes").await.unwrap();
+        assert_eq!(doc.read().content, "hello wörld\nbye");
+
+        let applied = transform_doc(&state, "notes", "uppercase", Some(range), false)
+            .await
+            .unwrap();
+        assert!(applied.applied && applied.group_id.is_some());
+        assert_eq!(applied.ops, preview.ops);
+        assert_eq!(doc.read().content, "hello WÖRLD\nbye");
+
+        let whole = transform_doc(&state, "notes", "lowercase", None, false)
+            .await
+            .unwrap();
+        assert_eq!(whole.range, TextRange { start: 0, end: 15 });
+        assert_eq!(doc.read().content, "hello wörld\nbye");
+
+        let unchanged = transform_doc(&state, "notes", "lowercase", None, false)
+            .await
+            .unwrap();
+        assert!(!unchanged.applied && unchanged.ops.is_empty());
+
+        let outside = TextRange { start: 3, end: 16 };
+        let err = transform_doc(&state, "notes", "uppercase", Some(outside), true).await;
+        assert_eq!(code(err.unwrap_err()), "invalid_range");
+        let err = transform_doc(&state, "notes", "translate", None, true).await;
+        assert_eq!(code(err.unwrap_err()), "unknown_transform");
+    }
+
+    #[tokio::test]
+    async fn services_transform_over_http() {
+        let service = Router::new().route(
+            "/reverse",
+            post(|Json(query): Json<serde_json::Value>| async move {
+                let text: String = query["text"].as_str().unwrap().chars().rev().collect();
+                Json(serde_json::json!({ "text": text }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        let mut state = mk_state();
+        state.transforms = vec![
+            TransformService {
+                name: "reverse".into(),
+                url: format!("http://{addr}/reverse"),
+            },
+            TransformService {
+                name: "broken".into(),
+                url: format!("http://{addr}/missing"),
+            },
+        ];
+        seed(&state, "abc def").await;
+        let report = transform_doc(
+            &state,
+            "notes",
+            "reverse",
+            Some(TextRange { start: 4, end: 7 }),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.text, "fed");
+        let doc = get_or_load_doc(&state, "notes").await.unwrap();
+        assert_eq!(doc.read().content, "abc fed");
+
+        let err = transform_doc(&state, "notes", "broken", None, false).await;
+        assert_eq!(code(err.unwrap_err()), "transform_failed");
+    }
+}
@@ -0,0 +1,376 @@
+//! The trash: documents deleted by retention or expiry are moved into `.trash/`, one
+//! folder per deletion, and can be restored until their window runs out.
+//! [`run_trash_purge_loop`] drops them for good afterwards. Like archived
+//! bytes, trashed ones do not count against quotas.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, time::sleep};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    expiry::forget_expiry,
+    links::{forget_links, record_links},
+    quota::record_bytes,
+    retention::DAY_MS,
+    state::{AppState, Rejection, broadcast, now_millis, unload_doc},
+    storage::{
+        compressed_path, doc_exists_on_disk, legacy_password_path, load_meta, meta_path,
+        op_ids_path, password_path, persist_meta, read_snapshot, slug_to_rel_path, snapshot_path,
+        wal_lock, wal_path,
+    },
+    tags::{forget_tags, record_tags},
+    types::ServerMsg,
+};
+
+pub const DEFAULT_TRASH_RETENTION_DAYS: u64 = 30;
+const ENTRY_FILE: &str = "entry.json";
+const PURGE_INTERVAL_MS: u64 = 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TrashEntry {
+    pub id: Uuid,
+    pub slug: String,
+    /// What deleted the document, like `unused`.
+    pub reason: String,
+    pub deleted_at: u64,
+    /// When the purge task removes it; the window is fixed at deletion.
+    pub expires_at: u64,
+    /// On-disk size of the trashed files.
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashResp {
+    /// Newest first.
+    pub items: Vec<TrashEntry>,
+}
+
+/// The storage files of `slug`, with the names they get in the trash.
+fn doc_files(state: &AppState, slug: &str) -> anyhow::Result<Vec<(&'static str, PathBuf)>> {
+    let snap = snapshot_path(state, slug)?;
+    let wal = wal_path(state, slug)?;
+    Ok(vec![
+        ("snapshot.md.zst", compressed_path(&snap)),
+        ("snapshot.md", snap),
+        ("wal.jsonl.zst", compressed_path(&wal)),
+        ("wal.jsonl", wal),
+        ("password.pwd", password_path(state, slug)?),
+        ("legacy.pwd", legacy_password_path(state, slug)?),
+        ("meta.json", meta_path(state, slug)?),
+        ("ops", op_ids_path(state, slug)?),
+    ])
+}
+
+fn file_size(path: &Path) -> anyhow::Result<Option<u64>> {
+    match fs::metadata(path) {
+        Ok(meta) => Ok(Some(meta.len())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn read_entry(dir: &Path) -> anyhow::Result<Option<TrashEntry>> {
+    match fs::read(dir.join(ENTRY_FILE)) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Deletes `slug` from storage and memory and tells its sessions. The files
+/// go to the trash unless `trash_retention_days` is 0.
+pub fn delete_doc(state: &AppState, slug: &str, reason: &str) -> anyhow::Result<()> {
+    // Held until the files are gone so no edit or load sees them halfway.
+    let wal = wal_lock(state, slug);
+    let _wal = wal.lock();
+    let loaded = state.docs.read().get(slug).cloned();
+    if let Some(doc) = loaded {
+        doc.write().deleted = true;
+    }
+    unload_doc(state, slug, "deleted");
+    let mut files = Vec::new();
+    for (name, path) in doc_files(state, slug)? {
+        if let Some(size) = file_size(&path)? {
+            files.push((name, path, size));
+        }
+    }
+    let freed: u64 = files.iter().map(|(.., size)| size).sum();
+    if state.trash_retention_days > 0 && !files.is_empty() {
+        let now = now_millis();
+        let entry = TrashEntry {
+            id: Uuid::new_v4(),
+            slug: slug.to_string(),
+            reason: reason.to_string(),
+            deleted_at: now,
+            expires_at: now.saturating_add(state.trash_retention_days.saturating_mul(DAY_MS)),
+            bytes: freed,
+        };
+        let dir = state.trash_dir.join(entry.id.to_string());
+        fs::create_dir_all(&dir)?;
+        // Written first so a partly moved document is still listed.
+        fs::write(dir.join(ENTRY_FILE), serde_json::to_vec_pretty(&entry)?)?;
+        for (name, path, _) in &files {
+            fs::rename(path, dir.join(name))?;
+        }
+    } else {
+        for (_, path, _) in &files {
+            fs::remove_file(path)?;
+        }
+    }
+    record_bytes(state, slug, -(freed as i64));
+    forget_links(state, slug);
+    forget_tags(state, slug);
+    forget_expiry(state, slug);
+    broadcast(
+        state,
+        slug,
+        ServerMsg::Error {
+            slug: slug.to_string(),
+            code: "deleted".to_string(),
+            message: "document was deleted".to_string(),
+            op_id: None,
+        },
+    );
+    Ok(())
+}
+
+/// Everything in the trash, newest first.
+pub fn list_trash(state: &AppState) -> anyhow::Result<Vec<TrashEntry>> {
+    let dirs = match fs::read_dir(&state.trash_dir) {
+        Ok(dirs) => dirs,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut entries = Vec::new();
+    for dir in dirs {
+        if let Some(entry) = read_entry(&dir?.path())? {
+            entries.push(entry);
+        }
+    }
+    entries.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then(a.slug.cmp(&b.slug)));
+    Ok(entries)
+}
+
+/// The decoded WALs of trashed documents, with the entry and the file each
+/// was read from.
+pub fn read_trashed_wals(state: &AppState) -> anyhow::Result<Vec<(TrashEntry, PathBuf, String)>> {
+    let mut wals = Vec::new();
+    for entry in list_trash(state)? {
+        let dir = state.trash_dir.join(entry.id.to_string());
+        for (name, compressed) in [("wal.jsonl.zst", true), ("wal.jsonl", false)] {
+            let path = dir.join(name);
+            if !path.exists() {
+                continue;
+            }
+            let raw = fs::read(&path)?;
+            let data = if compressed {
+                zstd::decode_all(raw.as_slice())?
+            } else {
+                raw
+            };
+            wals.push((entry.clone(), path, String::from_utf8(data)?));
+            break;
+        }
+    }
+    Ok(wals)
+}
+
+/// Moves trash item `id` back into live storage, under `slug` when given
+/// instead of the slug it was deleted from. Refuses to replace a document.
+pub fn restore_trashed(
+    state: &AppState,
+    id: Uuid,
+    slug: Option<&str>,
+) -> anyhow::Result<TrashEntry> {
+    let dir = state.trash_dir.join(id.to_string());
+    let entry = read_entry(&dir)?
+        .ok_or_else(|| Rejection::new("not_found", "no such item in the trash"))?;
+    let slug = slug.unwrap_or(&entry.slug).trim_matches('/').to_string();
+    slug_to_rel_path(&slug)?;
+    if state.docs.read().contains_key(&slug) || doc_exists_on_disk(state, &slug)? {
+        return Err(Rejection::new("exists", "a document with this slug exists").into());
+    }
+    let mut restored = 0u64;
+    for (name, path) in doc_files(state, &slug)? {
+        let source = dir.join(name);
+        let Some(size) = file_size(&source)? else {
+            continue;
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&source, &path)?;
+        restored += size;
+    }
+    fs::remove_dir_all(&dir)?;
+    record_bytes(state, &slug, restored as i64);
+    let mut meta = load_meta(state, &slug)?.unwrap_or_default();
+    // An expired document would be deleted again right away.
+    if meta.settings.expires_at.take().is_some() {
+        persist_meta(state, &slug, &meta)?;
+    }
+    if let Some(content) = read_snapshot(state, &slug)? {
+        let content_type = meta.content_type.clone().unwrap_or_default();
+        record_links(state, &slug, &content_type, &content);
+    }
+    if let Some(tags) = &meta.tags {
+        record_tags(state, &slug, tags);
+    }
+    Ok(TrashEntry { slug, ..entry })
+}
+
+/// Removes trash items whose window ended by `now` and returns them.
+pub fn purge_trash(state: &AppState, now: u64) -> anyhow::Result<Vec<TrashEntry>> {
+    let mut purged = Vec::new();
+    for entry in list_trash(state)? {
+        if entry.expires_at <= now {
+            fs::remove_dir_all(state.trash_dir.join(entry.id.to_string()))?;
+            purged.push(entry);
+        }
+    }
+    Ok(purged)
+}
+
+pub async fn run_trash_purge_loop(state: AppState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = sleep(Duration::from_millis(PURGE_INTERVAL_MS)) => {
+                match purge_trash(&state, now_millis()) {
+                    Ok(purged) if !purged.is_empty() => {
+                        info!(docs = purged.len(), "purged expired trash");
+                    }
+                    Ok(_) => {}
+                    Err(err) => error!("trash purge failed: {:#}", err),
+                }
+            }
+            changed = shutdown.changed() => {
+                if changed.is_ok() && *shutdown.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        state::{apply_edit, get_or_load_doc},
+        storage::flush_snapshot_force,
+        types::{Edit, OpKind},
+    };
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("trash-{}", Uuid::new_v4()));
+        let wal = base.join("wal");
+        let snap = base.join("snapshots");
+        fs::create_dir_all(&wal).unwrap();
+        fs::create_dir_all(&snap).unwrap();
+        AppState::new(wal, snap, 10_000, 1_000, true, vec![])
+    }
+
+    fn insert(text: &str) -> Edit {
+        Edit {
+            base_rev: 0,
+            ops: vec![OpKind::Insert {
+                pos: 0,
+                text: text.into(),
+            }],
+            client_id: None,
+            op_id: Some(Uuid::new_v4()),
+            cursor_before: None,
+            cursor_after: None,
+            ts: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_documents_can_be_restored_until_purged() {
+        let state = mk_state();
+        apply_edit(&state, "team/old", insert("#keep me"))
+            .await
+            .unwrap();
+        flush_snapshot_force(&state, "team/old").await.unwrap();
+        delete_doc(&state, "team/old", "unused").unwrap();
+        assert!(!doc_exists_on_disk(&state, "team/old").unwrap());
+
+        let items = list_trash(&state).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(
+            (items[0].slug.as_str(), items[0].reason.as_str()),
+            ("team/old", "unused")
+        );
+        assert!(items[0].bytes > 0);
+
+        apply_edit(&state, "team/old", insert("new")).await.unwrap();
+        let err = restore_trashed(&state, items[0].id, None).unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, "exists");
+        let restored = restore_trashed(&state, items[0].id, Some("team/older")).unwrap();
+        assert_eq!(restored.slug, "team/older");
+        assert!(list_trash(&state).unwrap().is_empty());
+        let doc = get_or_load_doc(&state, "team/older").await.unwrap();
+        assert_eq!(doc.read().content, "#keep me");
+        assert_eq!(doc.read().rev, 1);
+
+        delete_doc(&state, "team/older", "unused").unwrap();
+        let expires_at = list_trash(&state).unwrap()[0].expires_at;
+        assert!(purge_trash(&state, expires_at - 1).unwrap().is_empty());
+        assert_eq!(purge_trash(&state, expires_at).unwrap().len(), 1);
+        assert!(list_trash(&state).unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn edits_racing_a_delete_leave_no_ghost_behind() {
+        let state = mk_state();
+        apply_edit(&state, "busy", insert("start")).await.unwrap();
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for _ in 0..20 {
+                        let _ = apply_edit(&state, "busy", insert("x")).await;
+                    }
+                })
+            })
+            .collect();
+        tokio::task::yield_now().await;
+        delete_doc(&state, "busy", "unused").unwrap();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        // Whatever is on disk now was written by a document that is still
+        // loaded, and reads back as it.
+        let loaded = state.docs.read().get("busy").cloned();
+        assert_eq!(
+            doc_exists_on_disk(&state, "busy").unwrap(),
+            loaded.is_some()
+        );
+        if let Some(doc) = loaded {
+            let (on_disk, _) = crate::state::read_doc(&state, "busy").unwrap();
+            assert_eq!(on_disk.content, doc.read().content);
+        }
+    }
+
+    #[tokio::test]
+    async fn zero_retention_deletes_outright() {
+        let mut state = mk_state();
+        state.trash_retention_days = 0;
+        apply_edit(&state, "gone", insert("bye")).await.unwrap();
+        delete_doc(&state, "gone", "unused").unwrap();
+        assert!(!doc_exists_on_disk(&state, "gone").unwrap());
+        assert!(list_trash(&state).unwrap().is_empty());
+        let err = restore_trashed(&state, Uuid::new_v4(), None).unwrap_err();
+        assert_eq!(err.downcast_ref::<Rejection>().unwrap().code, "not_found");
+    }
+}
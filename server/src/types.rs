@@ -1,21 +1,26 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+use crate::{front_matter::FrontMatter, protocol::ProtocolInfo, subscription::MessageClass};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum OpKind {
     Insert { pos: usize, text: String },
     Delete { pos: usize, len: usize },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum SelectionDirection {
     Forward,
     Backward,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 pub struct CursorState {
     pub position: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -24,13 +29,13 @@ pub struct CursorState {
     pub selection_direction: Option<SelectionDirection>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 pub struct TextRange {
     pub start: usize,
     pub end: usize,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 pub struct Edit {
     pub base_rev: u64,
     pub ops: Vec<OpKind>,
@@ -42,6 +47,38 @@ pub struct Edit {
     pub cursor_after: Option<CursorState>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ts: Option<u64>,
+    /// Shared by the edits of one user action (a paste, a replace-all) so
+    /// undo, replay and blame can treat them as a unit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<Uuid>,
+    /// The person behind `client_id`, stable across tabs and reloads. Set by
+    /// the server from the session, never taken from the client's edit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
+}
+
+/// Ops for sessions that negotiated `line_ops`. Lines are counted from zero
+/// and never contain `\n`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LineOp {
+    InsertLines { line: usize, lines: Vec<String> },
+    DeleteLines { line: usize, count: usize },
+    ReplaceLine { line: usize, text: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct LineEdit {
+    pub base_rev: u64,
+    pub ops: Vec<LineOp>,
+    pub client_id: Option<Uuid>,
+    pub op_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -49,9 +86,114 @@ pub struct SnapshotResp {
     pub slug: String,
     pub rev: u64,
     pub content: String,
+    pub content_type: ContentType,
+    pub versions: VersionVector,
+    /// [`content_hash`](crate::document::content_hash) of `content`.
+    pub content_hash: u32,
+    /// Length of `content` in characters, the unit op positions count in.
+    pub chars: usize,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// How many edits from each client a document has applied. Two vectors for
+/// the same document tell which side is missing whose edits.
+pub type VersionVector = BTreeMap<Uuid, u64>;
+
+/// What a document holds. Documents created before content types existed
+/// are markdown.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ContentType {
+    #[default]
+    Markdown,
+    Plaintext,
+    Json,
+    Code {
+        lang: String,
+    },
+}
+
+/// Per-document overrides of the server-wide defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DocSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flush_idle_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flush_max_ops: Option<usize>,
+    /// Overrides the retention policy's `purge_history_days`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purge_history_days: Option<u64>,
+    /// Edits that insert text are refused once the content would exceed
+    /// this many bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    /// Edits are refused while set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_only: Option<bool>,
+    /// When the document locks and then deletes itself, in ms since the
+    /// epoch; see [`crate::expiry`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Where alerts about the document go instead of `ALERT_WEBHOOK_URL`;
+    /// see [`crate::alerts`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_webhook_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_max_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_max_conflicts_per_hour: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_max_unflushed_minutes: Option<u64>,
+    /// Lets sessions send the document to the assistant service; off
+    /// unless set. See [`crate::assist`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assist: Option<bool>,
+}
+
+impl DocSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DocMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner_hash: Option<String>,
+    #[serde(default)]
+    pub snapshot_rev: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<ContentType>,
+    /// Edits up to this revision had their inserted text scrubbed from the
+    /// WAL.
+    #[serde(default)]
+    pub scrubbed_rev: u64,
+    #[serde(default, skip_serializing_if = "DocSettings::is_empty")]
+    pub settings: DocSettings,
+    /// The document's version vector as of `snapshot_rev`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub versions: VersionVector,
+    /// Tags found in the snapshot at `snapshot_rev`; absent when it was
+    /// flushed before tags were kept here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub front_matter: Option<FrontMatter>,
+    /// Names `@mentioned` in the snapshot at `snapshot_rev`; absent when it
+    /// was flushed before mentions were kept here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mentions: Option<Vec<String>>,
+    /// The last revision each named user reported seeing.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub seen: BTreeMap<Uuid, u64>,
+    /// Named users who asked to be notified of changes.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub followers: BTreeSet<Uuid>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 pub struct ImeSnapshot {
     pub phase: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -60,7 +202,7 @@ pub struct ImeSnapshot {
     pub text: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(tag = "phase", rename_all = "snake_case")]
 pub enum ImeEvent {
     Start {
@@ -85,6 +227,8 @@ pub enum DocEvent {
     Edit {
         edit: Edit,
     },
+    /// Cursor and IME entries only appear in WALs from servers that still
+    /// logged presence; replay skips them apart from their op ids.
     Cursor {
         client_id: Uuid,
         op_id: Option<Uuid>,
@@ -95,12 +239,28 @@ pub enum DocEvent {
         op_id: Option<Uuid>,
         ime: ImeEvent,
     },
+    /// Retention dropped every edit up to `rev`; the WAL continues after it.
+    Purged {
+        rev: u64,
+    },
+    /// Consecutive edits by one author squashed by history compaction. The
+    /// revisions after the previous edit up to `edit.base_rev` keep their
+    /// numbers but log nothing; `edit` carries all of their ops and lands on
+    /// the revision after them.
+    Compacted {
+        edit: Edit,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct WalEntryV2 {
     pub version: u8,
+    /// When the event happened, as the client saw it if its clock agreed
+    /// with ours.
     pub ts: u64,
+    /// When this node wrote the entry, by its own clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_ts: Option<u64>,
     pub event: DocEvent,
 }
 
@@ -113,7 +273,25 @@ pub enum WalLine {
 
 pub const CURRENT_WAL_VERSION: u8 = 2;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// Changes to a followed document not yet read, kept as one entry per
+/// document until the user sees them.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct Notification {
+    pub slug: String,
+    /// The revision before the first change.
+    pub since_rev: u64,
+    pub rev: u64,
+    pub edits: u64,
+    /// Who made them, as named in presence.
+    pub editors: Vec<String>,
+    /// Lines that mention the user, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<String>,
+    pub first_at: u64,
+    pub last_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 pub struct PresenceState {
     pub client_id: Uuid,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -125,9 +303,75 @@ pub struct PresenceState {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ime: Option<ImeSnapshot>,
     pub last_seen: u64,
+    /// Set for named users; guests have only their per-tab `client_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
+    /// An `http(s)` image URL, at most 512 bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// A short free-text status, at most 80 characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// The section this session claimed, as of when it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section: Option<SectionClaim>,
+    /// Smoothed round-trip time of this session's socket, in milliseconds,
+    /// once the server has measured it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// A soft lock on the Markdown section under a heading. Offsets count
+/// characters like `/api/toc`; clients move them along with `Applied` ops.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct SectionClaim {
+    pub heading: String,
+    /// Start of the heading line.
+    pub start: usize,
+    pub end: usize,
+    /// When the claim lapses unless claimed again.
+    pub expires_at: u64,
+}
+
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+/// What an analyzer found about `start..end` of a document, in characters.
+/// Clients move the range along with `Applied` ops, as the server does.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub analyzer: String,
+    pub start: usize,
+    pub end: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// One labeled user in a workspace roster: the tabs they have open and the
+/// documents those tabs are in.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct RosterEntry {
+    pub label: String,
+    pub client_ids: Vec<Uuid>,
+    pub slugs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ViewportUnit {
+    #[default]
+    Char,
+    Line,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientMsg {
     Hello {
@@ -135,11 +379,29 @@ pub enum ClientMsg {
         client_id: Uuid,
         label: Option<String>,
         color: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<String>,
+        /// Broadcast classes this socket wants; all of them when absent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        subscribe: Option<Vec<MessageClass>>,
+        /// Stable identity of a named user; guests leave it out.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_id: Option<Uuid>,
+        /// Answered with `snapshot_current` when it is still the head, and
+        /// with a `resync` otherwise.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        known: Option<KnownState>,
     },
     Edit {
         slug: String,
         edit: Edit,
     },
+    LineEdit {
+        slug: String,
+        edit: LineEdit,
+    },
     Cursor {
         slug: String,
         cursor: CursorState,
@@ -152,10 +414,16 @@ pub enum ClientMsg {
         op_id: Option<Uuid>,
         ts: Option<u64>,
     },
+    /// Absent fields stay as they are; an empty `avatar_url` or `status`
+    /// clears it.
     Profile {
         slug: String,
         label: Option<String>,
         color: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        avatar_url: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<String>,
     },
     Join {
         session_id: String,
@@ -168,6 +436,15 @@ pub enum ClientMsg {
         password: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         token: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        capabilities: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_id: Option<Uuid>,
+        /// Skips the content of the `snapshot` when it is still the head.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        known: Option<KnownState>,
     },
     #[serde(rename = "op")]
     CompatOp {
@@ -180,9 +457,150 @@ pub enum ClientMsg {
         ts: Option<u64>,
     },
     Pong,
+    /// Follow `Applied` broadcasts of another document without joining its
+    /// presence or gaining edit rights. Carries a ticket for that document
+    /// instead of its password when the server requires those.
+    Watch {
+        slug: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ticket: Option<String>,
+    },
+    Unwatch {
+        slug: String,
+    },
+    /// Sent after the client saw a gap in `seq`; `last_seq` is the last
+    /// message it processed.
+    Resync {
+        last_seq: u64,
+    },
+    /// The client's copy did not match the `hash` of an `Applied` at `rev`;
+    /// answered with a `snapshot`.
+    StateMismatch {
+        slug: String,
+        rev: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hash: Option<u32>,
+    },
+    /// Only receive ops inside `[start, end)` of the document, counted in
+    /// `unit`s. Answered with `Viewport`.
+    SetViewport {
+        slug: String,
+        start: usize,
+        end: usize,
+        #[serde(default)]
+        unit: ViewportUnit,
+    },
+    /// Back to full updates; answered with `Resync`.
+    ClearViewport {
+        slug: String,
+    },
+    /// Asks for the counts of the current document; answered with `Stats`.
+    Stats {
+        slug: String,
+    },
+    /// The user has seen the document up to `rev`, for unread counts in
+    /// listings. Not answered; ignored for guests without a `user_id`.
+    SeenUpTo {
+        slug: String,
+        rev: u64,
+    },
+    /// Claims the section under the heading starting at `start`, or renews
+    /// the claim. Shown in presence; refused with `section_claimed` when
+    /// another session holds an overlapping one, or `no_section`.
+    ClaimSection {
+        slug: String,
+        start: usize,
+    },
+    ReleaseSection {
+        slug: String,
+    },
+    /// Notify the user of changes made while they are not on the document,
+    /// or stop with `follow: false`. Not answered; ignored for guests without
+    /// a `user_id`.
+    Follow {
+        slug: String,
+        follow: bool,
+    },
+    /// Find and replace over the whole document, applied by the server as
+    /// one grouped edit. Answered with `Replaced`.
+    Replace {
+        slug: String,
+        find: String,
+        #[serde(default)]
+        replace: String,
+        #[serde(default)]
+        regex: bool,
+        #[serde(default)]
+        case_insensitive: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        op_id: Option<Uuid>,
+    },
+    /// Where people are active across a workspace, the current document's
+    /// when `workspace` is absent; only the user with `label` when given.
+    /// Answered with `Roster`.
+    Roster {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        workspace: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        label: Option<String>,
+    },
+    /// Changes the document password from inside a session, under the same
+    /// rules as `/api/password`; an empty `new` removes it. Everyone on the
+    /// document is sent `AccessChanged`.
+    SetPassword {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        current: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        new: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner_token: Option<String>,
+    },
+    /// Asks the assistant service about `range` of the current document, for
+    /// a completion or rewrite depending on `prompt_kind`. Answered with
+    /// `AssistResult`, or an `Error` with `assist_disabled`, `rate_limited`,
+    /// `invalid_range` or `assist_failed`, tagged with `request_id`.
+    AssistRequest {
+        slug: String,
+        range: TextRange,
+        prompt_kind: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<Uuid>,
+    },
+    /// Answers `AuthRequired` with the new password, or a ticket from
+    /// `/api/ws-ticket` when the server requires those. Answered with
+    /// `Authenticated`.
+    Authenticate {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ticket: Option<String>,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// Counts over a document's content, so clients need not recount large
+/// documents themselves.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct DocStats {
+    /// Characters, the unit op positions count in.
+    pub chars: usize,
+    /// Runs of non-whitespace characters.
+    pub words: usize,
+    /// Line breaks plus one, as an editor numbers lines.
+    pub lines: usize,
+}
+
+/// A copy of the document a client kept from an earlier session, e.g. across
+/// a page restore.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+pub struct KnownState {
+    pub rev: u64,
+    /// [`content_hash`](crate::document::content_hash) of the copy.
+    pub content_hash: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 pub struct CompatOpContext {
     #[serde(rename = "baseVersion")]
     pub base_version: u64,
@@ -196,7 +614,7 @@ pub struct CompatOpContext {
     pub ts: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 pub struct CompatSelection {
     pub position: usize,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -225,7 +643,7 @@ impl From<CursorState> for CompatSelection {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMsg {
     Applied {
@@ -235,6 +653,34 @@ pub enum ServerMsg {
         client_id: Option<Uuid>,
         op_id: Option<Uuid>,
         ts: u64,
+        /// [`content_hash`](crate::document::content_hash) of the document at
+        /// `rev`, sent every `hash_interval` revisions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hash: Option<u32>,
+        /// Length of the document at `rev` in characters, sent with `hash`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chars: Option<usize>,
+        /// Counts for the document at `rev`, sent every `stats_interval`
+        /// revisions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stats: Option<DocStats>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group_id: Option<Uuid>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_id: Option<Uuid>,
+    },
+    /// `Applied` in line form, for sessions that negotiated `line_ops`.
+    LineApplied {
+        slug: String,
+        rev: u64,
+        ops: Vec<LineOp>,
+        client_id: Option<Uuid>,
+        op_id: Option<Uuid>,
+        ts: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group_id: Option<Uuid>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_id: Option<Uuid>,
     },
     Cursor {
         slug: String,
@@ -253,6 +699,8 @@ pub enum ServerMsg {
     PresenceSnapshot {
         slug: String,
         clients: Vec<PresenceState>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol: Option<ProtocolInfo>,
     },
     PresenceDiff {
         slug: String,
@@ -267,6 +715,48 @@ pub enum ServerMsg {
         content: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         presence: Option<Vec<PresenceState>>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        versions: VersionVector,
+        /// The content came ahead in `snapshot_chunk`s and is empty here.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        chunked: bool,
+    },
+    /// Stands in for `snapshot` at join for sessions that negotiated
+    /// `lazy_snapshot`: the content is fetched from `GET /api/snapshot`,
+    /// which may already be at a later revision; edits up to the revision it
+    /// returns are part of it. `length` counts UTF-8 bytes.
+    SnapshotRef {
+        session_id: String,
+        rev: u64,
+        content_hash: u32,
+        length: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        presence: Option<Vec<PresenceState>>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        versions: VersionVector,
+    },
+    /// Stands in for `snapshot` when the client joined with a `known` copy
+    /// that is still the head: the client keeps its own content.
+    SnapshotCurrent {
+        session_id: String,
+        rev: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        presence: Option<Vec<PresenceState>>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        versions: VersionVector,
+    },
+    /// Part of a `snapshot` too large for one frame, for sessions that
+    /// negotiated `snapshot_chunks`. `offset` and `total` count UTF-8 bytes;
+    /// the last chunk carries the [`content_hash`](crate::document::content_hash)
+    /// of the whole content.
+    SnapshotChunk {
+        session_id: String,
+        rev: u64,
+        offset: usize,
+        total: usize,
+        data: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        checksum: Option<u32>,
     },
     #[serde(rename = "op_broadcast")]
     CompatOpBroadcast {
@@ -285,9 +775,167 @@ pub enum ServerMsg {
         #[serde(skip_serializing_if = "Option::is_none")]
         ts: Option<u64>,
     },
+    Watching {
+        slug: String,
+        rev: u64,
+    },
+    /// Full document state for a client whose missed messages can no longer
+    /// be replayed.
+    Resync {
+        slug: String,
+        rev: u64,
+        content: String,
+    },
+    /// The text of a windowed subscription. `start`/`end` are char offsets
+    /// into the whole document at `rev`.
+    Viewport {
+        slug: String,
+        rev: u64,
+        start: usize,
+        end: usize,
+        content: String,
+    },
+    /// An `Applied` for a windowed subscription: `ops` are relative to the
+    /// window, `start`/`end` are the window after them.
+    ViewportApplied {
+        slug: String,
+        rev: u64,
+        start: usize,
+        end: usize,
+        ops: Vec<OpKind>,
+        client_id: Option<Uuid>,
+        op_id: Option<Uuid>,
+        ts: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group_id: Option<Uuid>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_id: Option<Uuid>,
+    },
+    /// Edits outside the window moved it or advanced the revision.
+    ViewportSync {
+        slug: String,
+        rev: u64,
+        start: usize,
+        end: usize,
+    },
+    OwnerGranted {
+        slug: String,
+        owner_token: String,
+    },
+    /// Answers `ClientMsg::Stats`.
+    Stats {
+        slug: String,
+        rev: u64,
+        stats: DocStats,
+    },
+    /// The assistant's suggestion for `range` as of `rev`, for the session
+    /// that asked alone. Nothing is applied; the client moves the range
+    /// along with later `Applied` ops and edits as usual if the user takes
+    /// it.
+    AssistResult {
+        slug: String,
+        rev: u64,
+        range: TextRange,
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<Uuid>,
+    },
+    /// Result of a `Replace`; the edit itself arrives as `Applied`.
+    Replaced {
+        slug: String,
+        rev: u64,
+        matches: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        op_id: Option<Uuid>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<Uuid>,
+    },
+    /// Password-protected documents other than this socket's are left out.
+    Roster {
+        slug: String,
+        workspace: String,
+        members: Vec<RosterEntry>,
+    },
+    /// Who may open or edit the document changed: its password, the
+    /// workspace default or the read-only lock. `client_id` made the change
+    /// when it came from a session. `version` moves with every change.
+    AccessChanged {
+        slug: String,
+        version: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_id: Option<Uuid>,
+        protected: bool,
+        writable: bool,
+    },
+    /// The password this session connected with no longer opens the
+    /// document. Until it sends `Authenticate` the session is read-only,
+    /// and after `grace_ms` it is closed.
+    AuthRequired {
+        slug: String,
+        grace_ms: u64,
+    },
+    /// The session presented the current password and is writable again.
+    Authenticated {
+        slug: String,
+    },
+    /// The document deletes itself at `expires_at`: edits stop then, and it
+    /// goes to the trash a minute later. Sent when the time is set, as it
+    /// draws near and on join; without `expires_at` it was cancelled.
+    Expiring {
+        slug: String,
+        expires_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        remaining_ms: Option<u64>,
+    },
+    /// A followed document changed while the user was not on it. Sent to
+    /// every socket of the user when the change is first queued; later
+    /// changes grow the queued entry silently.
+    Notification {
+        notification: Notification,
+    },
+    /// The server cannot write its log. While `degraded`, edits are still
+    /// applied but held in memory, and refused with `degraded` once too many
+    /// are waiting; `false` means writes go through again.
+    Degraded {
+        slug: String,
+        degraded: bool,
+    },
+    Error {
+        slug: String,
+        code: String,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        op_id: Option<Uuid>,
+    },
+    Warning {
+        slug: String,
+        code: String,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        op_id: Option<Uuid>,
+    },
+    /// Everything the analyzers found in the document, with ranges as of
+    /// `rev`; replaces what was sent before.
+    Diagnostics {
+        slug: String,
+        rev: u64,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// An edit refused by strict op validation. With `resync`, a `snapshot`
+    /// to start over from follows.
+    InvalidOp {
+        slug: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        op_id: Option<Uuid>,
+        index: usize,
+        reason: String,
+        doc_len: usize,
+        message: String,
+        resync: bool,
+    },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 pub struct CompatOpBroadcastContext {
     #[serde(rename = "serverSeq")]
     pub server_seq: u64,
@@ -0,0 +1,325 @@
+//! Content rules checked before an edit is applied or a snapshot is written.
+//! Workspaces configure [`ValidationRule`]s; embedders can add their own
+//! [`ValidationHook`]s to [`AppState::validation_hooks`].
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    content_type::content_problem,
+    state::{AppState, Rejection},
+    types::ContentType,
+    workspace::workspace_settings_for,
+};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    #[default]
+    Warn,
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleStage {
+    /// Checked against the content an edit would produce.
+    #[default]
+    Edit,
+    /// Checked against the content about to be written as a snapshot.
+    Flush,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum RuleCheck {
+    MaxBytes { max_bytes: u64 },
+    Forbidden { pattern: String },
+    FrontMatter { keys: Vec<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ValidationRule {
+    #[serde(flatten)]
+    pub check: RuleCheck,
+    #[serde(default)]
+    pub action: RuleAction,
+    #[serde(default)]
+    pub on: RuleStage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub rule: String,
+    pub message: String,
+    pub action: RuleAction,
+}
+
+/// What a hook gets to look at.
+pub struct Candidate<'a> {
+    pub slug: &'a str,
+    pub content: &'a str,
+    pub content_type: &'a ContentType,
+    pub stage: RuleStage,
+}
+
+/// A custom content check. Edits are checked while the document is locked,
+/// so hooks should not block.
+pub trait ValidationHook: Send + Sync {
+    fn check(&self, candidate: &Candidate<'_>) -> Vec<Violation>;
+}
+
+impl ValidationRule {
+    fn check(&self, content: &str) -> Option<Violation> {
+        let (rule, message) = match &self.check {
+            RuleCheck::MaxBytes { max_bytes } => {
+                if content.len() as u64 <= *max_bytes {
+                    return None;
+                }
+                (
+                    "max_bytes",
+                    format!(
+                        "document is {} bytes, over the {} byte limit",
+                        content.len(),
+                        max_bytes
+                    ),
+                )
+            }
+            RuleCheck::Forbidden { pattern } => {
+                if pattern.is_empty() || !content.contains(pattern.as_str()) {
+                    return None;
+                }
+                ("forbidden", format!("document contains '{}'", pattern))
+            }
+            RuleCheck::FrontMatter { keys } => {
+                let missing: Vec<&str> = match front_matter_keys(content) {
+                    Some(present) => keys
+                        .iter()
+                        .map(String::as_str)
+                        .filter(|key| !present.contains(key))
+                        .collect(),
+                    None => {
+                        return Some(Violation {
+                            rule: "front_matter".into(),
+                            message: "document has no front matter".into(),
+                            action: self.action,
+                        });
+                    }
+                };
+                if missing.is_empty() {
+                    return None;
+                }
+                (
+                    "front_matter",
+                    format!("front matter is missing {}", missing.join(", ")),
+                )
+            }
+        };
+        Some(Violation {
+            rule: rule.into(),
+            message,
+            action: self.action,
+        })
+    }
+}
+
+/// Keys of a leading `---` delimited front matter block.
+fn front_matter_keys(content: &str) -> Option<Vec<&str>> {
+    let mut lines = content.lines();
+    if lines.next()?.trim_end() != "---" {
+        return None;
+    }
+    let mut keys = Vec::new();
+    for line in lines {
+        if line.trim_end() == "---" {
+            return Some(keys);
+        }
+        if let Some((key, _)) = line.split_once(':')
+            && !key.starts_with(char::is_whitespace)
+        {
+            keys.push(key.trim());
+        }
+    }
+    None
+}
+
+/// Whether anything would look at `slug`'s content at `stage`, so callers
+/// can skip building a candidate.
+pub fn has_checks(state: &AppState, slug: &str, stage: RuleStage) -> anyhow::Result<bool> {
+    if !state.validation_hooks.is_empty() {
+        return Ok(true);
+    }
+    let rules = workspace_settings_for(state, slug)?
+        .map(|ws| ws.validation)
+        .unwrap_or_default();
+    Ok(rules.iter().any(|rule| rule.on == stage))
+}
+
+/// Runs the workspace rules and hooks for `stage` against `candidate`.
+/// Flushes also check the content against its content type.
+pub fn validate(state: &AppState, candidate: &Candidate<'_>) -> anyhow::Result<Vec<Violation>> {
+    let rules = workspace_settings_for(state, candidate.slug)?
+        .map(|ws| ws.validation)
+        .unwrap_or_default();
+    let mut violations: Vec<Violation> = rules
+        .iter()
+        .filter(|rule| rule.on == candidate.stage)
+        .filter_map(|rule| rule.check(candidate.content))
+        .collect();
+    if candidate.stage == RuleStage::Flush
+        && let Some(message) = content_problem(candidate.content_type, candidate.content)
+    {
+        violations.push(Violation {
+            rule: "content_type".into(),
+            message,
+            action: RuleAction::Warn,
+        });
+    }
+    for hook in &state.validation_hooks {
+        violations.extend(hook.check(candidate));
+    }
+    Ok(violations)
+}
+
+/// The rejection for the first rejecting violation, if any.
+pub fn rejection(violations: &[Violation]) -> Option<Rejection> {
+    violations
+        .iter()
+        .find(|v| v.action == RuleAction::Reject)
+        .map(|v| Rejection::new("validation_failed", format!("{}: {}", v.rule, v.message)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, sync::Arc};
+    use uuid::Uuid;
+
+    use crate::workspace::{WorkspaceSettings, save_workspace};
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("validation-{}", Uuid::new_v4()));
+        let wal = base.join("wal");
+        let snap = base.join("snapshots");
+        fs::create_dir_all(&wal).unwrap();
+        fs::create_dir_all(&snap).unwrap();
+        AppState::new(wal, snap, 1_000, 100, true, vec![])
+    }
+
+    fn rule(check: RuleCheck, action: RuleAction, on: RuleStage) -> ValidationRule {
+        ValidationRule { check, action, on }
+    }
+
+    fn candidate<'a>(slug: &'a str, content: &'a str, stage: RuleStage) -> Candidate<'a> {
+        Candidate {
+            slug,
+            content,
+            content_type: &ContentType::Markdown,
+            stage,
+        }
+    }
+
+    #[test]
+    fn rules_parse_from_workspace_json() {
+        let rules: Vec<ValidationRule> = serde_json::from_str(
+            r#"[{"rule":"max_bytes","max_bytes":10,"action":"reject"},
+                {"rule":"front_matter","keys":["title"],"on":"flush"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                rule(
+                    RuleCheck::MaxBytes { max_bytes: 10 },
+                    RuleAction::Reject,
+                    RuleStage::Edit
+                ),
+                rule(
+                    RuleCheck::FrontMatter {
+                        keys: vec!["title".into()]
+                    },
+                    RuleAction::Warn,
+                    RuleStage::Flush
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn workspace_rules_apply_by_stage() {
+        let state = mk_state();
+        let settings = WorkspaceSettings {
+            validation: vec![
+                rule(
+                    RuleCheck::Forbidden {
+                        pattern: "SECRET".into(),
+                    },
+                    RuleAction::Reject,
+                    RuleStage::Edit,
+                ),
+                rule(
+                    RuleCheck::FrontMatter {
+                        keys: vec!["title".into(), "owner".into()],
+                    },
+                    RuleAction::Warn,
+                    RuleStage::Flush,
+                ),
+            ],
+            ..Default::default()
+        };
+        save_workspace(&state, "team", &settings).unwrap();
+        assert!(has_checks(&state, "team/doc", RuleStage::Edit).unwrap());
+        assert!(!has_checks(&state, "solo", RuleStage::Edit).unwrap());
+
+        let content = "---\ntitle: x\n---\nSECRET";
+        let edit = validate(&state, &candidate("team/doc", content, RuleStage::Edit)).unwrap();
+        assert_eq!(edit.len(), 1);
+        assert_eq!(
+            rejection(&edit).unwrap().message,
+            "forbidden: document contains 'SECRET'"
+        );
+
+        let flush = validate(&state, &candidate("team/doc", content, RuleStage::Flush)).unwrap();
+        assert_eq!(flush.len(), 1);
+        assert_eq!(flush[0].message, "front matter is missing owner");
+        assert!(rejection(&flush).is_none());
+        let flush = validate(&state, &candidate("team/doc", "plain", RuleStage::Flush)).unwrap();
+        assert_eq!(flush[0].message, "document has no front matter");
+    }
+
+    struct NoTabs;
+
+    impl ValidationHook for NoTabs {
+        fn check(&self, candidate: &Candidate<'_>) -> Vec<Violation> {
+            if candidate.content.contains('\t') {
+                vec![Violation {
+                    rule: "no_tabs".into(),
+                    message: "tabs are not allowed".into(),
+                    action: RuleAction::Reject,
+                }]
+            } else {
+                Vec::new()
+            }
+        }
+    }
+
+    #[test]
+    fn hooks_and_content_types_are_checked() {
+        let mut state = mk_state();
+        state.validation_hooks.push(Arc::new(NoTabs));
+        assert!(has_checks(&state, "solo", RuleStage::Edit).unwrap());
+        let violations = validate(&state, &candidate("solo", "a\tb", RuleStage::Edit)).unwrap();
+        assert_eq!(violations[0].rule, "no_tabs");
+        assert!(rejection(&violations).is_some());
+
+        let json = Candidate {
+            slug: "solo",
+            content: "{",
+            content_type: &ContentType::Json,
+            stage: RuleStage::Flush,
+        };
+        let violations = validate(&state, &json).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].rule, "content_type");
+        assert_eq!(violations[0].action, RuleAction::Warn);
+    }
+}
@@ -0,0 +1,410 @@
+//! Windowed subscriptions: a connection that set a viewport only receives
+//! the ops that touch its window, rewritten relative to the window start.
+
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+use crate::{
+    document::{Doc, OpShape, ShapedOp},
+    types::{OpKind, ServerMsg, ViewportUnit},
+};
+
+/// How often a connection is told where its window moved while no visible
+/// ops reached it.
+pub const VIEWPORT_SYNC_MS: u64 = 1000;
+
+/// Out-of-order broadcasts held back before the window is rebuilt from the
+/// document log instead.
+const MAX_PENDING: usize = 256;
+
+/// Resolves a requested window into a `[start, end)` char range of `content`.
+/// Line windows cover lines `start..end`, counted from zero.
+pub fn resolve_window(
+    content: &str,
+    unit: ViewportUnit,
+    start: usize,
+    end: usize,
+) -> (usize, usize) {
+    let len = content.chars().count();
+    let (start, end) = match unit {
+        ViewportUnit::Char => (start, end),
+        ViewportUnit::Line => {
+            let mut line_starts = std::iter::once(0).chain(
+                content
+                    .chars()
+                    .enumerate()
+                    .filter(|(_, c)| *c == '\n')
+                    .map(|(idx, _)| idx + 1),
+            );
+            let from = line_starts.clone().nth(start).unwrap_or(len);
+            let to = line_starts.nth(end).unwrap_or(len);
+            (from, to)
+        }
+    };
+    let start = start.min(len);
+    (start, end.clamp(start, len))
+}
+
+/// Builds the `Viewport` message for `[start, end)` of `doc`.
+pub fn viewport_message(slug: &str, doc: &Doc, start: usize, end: usize) -> ServerMsg {
+    ServerMsg::Viewport {
+        slug: slug.to_string(),
+        rev: doc.rev,
+        start,
+        end,
+        content: doc.content.chars().skip(start).take(end - start).collect(),
+    }
+}
+
+/// Moves the window through `ops` and returns the parts that fall inside it,
+/// each relative to the window start at the point it applies.
+fn advance<T: ShapedOp>(start: &mut usize, end: &mut usize, ops: &[T]) -> Vec<T> {
+    let mut visible = Vec::new();
+    for op in ops {
+        match op.shape() {
+            OpShape::Insert { pos, chars: n } => {
+                if pos < *start {
+                    *start += n;
+                    *end += n;
+                } else if pos <= *end {
+                    visible.push(op.moved_to(pos - *start));
+                    *end += n;
+                }
+            }
+            OpShape::Delete { pos, len } => {
+                let del_end = pos.saturating_add(len);
+                let before_start = del_end.min(*start).saturating_sub(pos);
+                let before_end = del_end.min(*end).saturating_sub(pos);
+                if before_end > before_start {
+                    visible.push(T::delete(
+                        pos.max(*start) - *start,
+                        before_end - before_start,
+                    ));
+                }
+                *start -= before_start;
+                *end -= before_end;
+            }
+        }
+    }
+    visible
+}
+
+struct PendingApplied {
+    ops: Vec<OpKind>,
+    client_id: Option<Uuid>,
+    op_id: Option<Uuid>,
+    ts: u64,
+    group_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+}
+
+/// Per-connection window state. `start`/`end` are valid at `rev`, so a client
+/// editing inside its window sends absolute positions `start + offset` with
+/// `base_rev` set to the last `rev` it was told about.
+pub struct ViewportFilter {
+    slug: String,
+    /// The connection's own client, whose acks are forwarded even for edits
+    /// outside the window.
+    client_id: Option<Uuid>,
+    rev: u64,
+    start: usize,
+    end: usize,
+    synced_rev: u64,
+    pending: BTreeMap<u64, PendingApplied>,
+}
+
+impl ViewportFilter {
+    pub fn new(slug: &str, client_id: Option<Uuid>, rev: u64, start: usize, end: usize) -> Self {
+        Self {
+            slug: slug.to_string(),
+            client_id,
+            rev,
+            start,
+            end,
+            synced_rev: rev,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Feeds one message bound for the connection through the filter and
+    /// returns what should actually be sent.
+    pub fn route(
+        filter: &mut Option<Self>,
+        slug: &str,
+        client_id: Option<Uuid>,
+        msg: ServerMsg,
+    ) -> Vec<ServerMsg> {
+        match msg {
+            ServerMsg::Viewport {
+                slug: ref s,
+                rev,
+                start,
+                end,
+                ..
+            } if s == slug => {
+                *filter = Some(Self::new(slug, client_id, rev, start, end));
+                vec![msg]
+            }
+            ServerMsg::Resync { slug: ref s, .. } if s == slug => {
+                *filter = None;
+                vec![msg]
+            }
+            ServerMsg::Applied {
+                slug: ref s,
+                rev,
+                ops,
+                client_id,
+                op_id,
+                ts,
+                hash,
+                chars,
+                stats,
+                group_id,
+                user_id,
+            } if s == slug => match filter {
+                Some(f) => f.push(
+                    rev,
+                    PendingApplied {
+                        ops,
+                        client_id,
+                        op_id,
+                        ts,
+                        group_id,
+                        user_id,
+                    },
+                ),
+                None => vec![ServerMsg::Applied {
+                    slug: slug.to_string(),
+                    rev,
+                    ops,
+                    client_id,
+                    op_id,
+                    ts,
+                    hash,
+                    chars,
+                    stats,
+                    group_id,
+                    user_id,
+                }],
+            },
+            msg => vec![msg],
+        }
+    }
+
+    fn push(&mut self, rev: u64, applied: PendingApplied) -> Vec<ServerMsg> {
+        // Acks for edits that changed nothing carry no ops and do not move the
+        // window; report them at the window's own rev.
+        if applied.ops.is_empty() {
+            return vec![self.applied(Vec::new(), applied)];
+        }
+        if rev <= self.rev {
+            return Vec::new();
+        }
+        self.pending.insert(rev, applied);
+        let mut out = Vec::new();
+        while let Some(applied) = self.pending.remove(&(self.rev + 1)) {
+            self.rev += 1;
+            let visible = advance(&mut self.start, &mut self.end, &applied.ops);
+            let own = applied.client_id.is_some() && applied.client_id == self.client_id;
+            if !visible.is_empty() || own {
+                out.push(self.applied(visible, applied));
+            }
+        }
+        out
+    }
+
+    fn applied(&mut self, ops: Vec<OpKind>, applied: PendingApplied) -> ServerMsg {
+        self.synced_rev = self.rev;
+        ServerMsg::ViewportApplied {
+            slug: self.slug.clone(),
+            rev: self.rev,
+            start: self.start,
+            end: self.end,
+            ops,
+            client_id: applied.client_id,
+            op_id: applied.op_id,
+            ts: applied.ts,
+            group_id: applied.group_id,
+            user_id: applied.user_id,
+        }
+    }
+
+    /// A `ViewportSync` when ops outside the window advanced the revision
+    /// since the client last heard about it.
+    pub fn take_sync(&mut self) -> Option<ServerMsg> {
+        if self.synced_rev == self.rev {
+            return None;
+        }
+        self.synced_rev = self.rev;
+        Some(ServerMsg::ViewportSync {
+            slug: self.slug.clone(),
+            rev: self.rev,
+            start: self.start,
+            end: self.end,
+        })
+    }
+
+    /// Whether a missing broadcast has held back too many later ones.
+    pub fn is_stalled(&self) -> bool {
+        self.pending.len() > MAX_PENDING
+    }
+
+    /// Moves the window up to `doc.rev` through the op log and returns a
+    /// fresh `Viewport` for it. Held-back broadcasts are dropped.
+    pub fn rebuild(&mut self, doc: &Doc) -> ServerMsg {
+        for ops in doc.log.iter().skip(self.rev as usize) {
+            advance(&mut self.start, &mut self.end, ops);
+        }
+        self.rev = doc.rev;
+        self.synced_rev = doc.rev;
+        self.pending.clear();
+        viewport_message(&self.slug, doc, self.start, self.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{apply_ops, shapes};
+
+    fn insert(pos: usize, text: &str) -> OpKind {
+        OpKind::Insert {
+            pos,
+            text: text.into(),
+        }
+    }
+
+    fn applied(rev: u64, ops: Vec<OpKind>) -> ServerMsg {
+        ServerMsg::Applied {
+            slug: "big".into(),
+            rev,
+            ops,
+            client_id: None,
+            op_id: None,
+            ts: 0,
+            hash: None,
+            chars: None,
+            stats: None,
+            group_id: None,
+            user_id: None,
+        }
+    }
+
+    #[test]
+    fn resolves_line_windows() {
+        let content = "zero\none\ntwo\nthree";
+        assert_eq!(resolve_window(content, ViewportUnit::Line, 1, 3), (5, 13));
+        assert_eq!(resolve_window(content, ViewportUnit::Line, 3, 9), (13, 18));
+        assert_eq!(resolve_window(content, ViewportUnit::Char, 9, 2), (9, 9));
+        assert_eq!(
+            resolve_window(content, ViewportUnit::Char, 40, 50),
+            (18, 18)
+        );
+    }
+
+    #[test]
+    fn only_ops_touching_the_window_are_forwarded() {
+        let mut doc = Doc {
+            content: "aaaa|window|bbbb".into(),
+            ..Default::default()
+        };
+        let mut filter = None;
+        let out = ViewportFilter::route(
+            &mut filter,
+            "big",
+            None,
+            viewport_message("big", &doc, 5, 11),
+        );
+        assert!(matches!(&out[..], [ServerMsg::Viewport { content, .. }] if content == "window"));
+        let mut local = Doc {
+            content: "window".into(),
+            ..Default::default()
+        };
+
+        let steps = vec![
+            vec![insert(0, "xx")],
+            vec![OpKind::Delete { pos: 5, len: 3 }],
+            vec![insert(15, "tail")],
+            vec![insert(10, "!")],
+        ];
+        // Broadcasts may arrive out of order; the third is held back until
+        // the second shows up.
+        let order = [0usize, 2, 1, 3];
+        let mut received = Vec::new();
+        for ops in &steps {
+            apply_ops(&mut doc, ops);
+            doc.rev += 1;
+            doc.log.push(shapes(ops));
+        }
+        for idx in order {
+            received.extend(ViewportFilter::route(
+                &mut filter,
+                "big",
+                None,
+                applied(idx as u64 + 1, steps[idx].clone()),
+            ));
+        }
+        for msg in &received {
+            if let ServerMsg::ViewportApplied { ops, .. } = msg {
+                apply_ops(&mut local, ops);
+            }
+        }
+        assert_eq!(received.len(), 2);
+        let Some(ServerMsg::ViewportApplied {
+            rev, start, end, ..
+        }) = received.last()
+        else {
+            panic!("expected a viewport edit, got {:?}", received);
+        };
+        assert_eq!(*rev, 4);
+        let window: String = doc.content.chars().skip(*start).take(end - start).collect();
+        assert_eq!(window, local.content);
+        assert_eq!(local.content, "indow!");
+
+        let filter = filter.as_mut().unwrap();
+        assert!(filter.take_sync().is_none());
+        apply_ops(&mut doc, &[insert(0, ">")]);
+        doc.rev += 1;
+        doc.log.push(shapes(&[insert(0, ">")]));
+        let rebuilt = filter.rebuild(&doc);
+        assert!(
+            matches!(rebuilt, ServerMsg::Viewport { rev: 5, ref content, .. } if content == "indow!")
+        );
+    }
+
+    #[test]
+    fn outside_edits_are_reported_as_syncs() {
+        let me = Uuid::new_v4();
+        let mut filter = Some(ViewportFilter::new("big", Some(me), 0, 10, 20));
+        let out =
+            ViewportFilter::route(&mut filter, "big", None, applied(1, vec![insert(0, "abc")]));
+        assert!(out.is_empty());
+        // Other documents (watched ones) pass through untouched.
+        let out =
+            ViewportFilter::route(&mut filter, "other", None, applied(1, vec![insert(0, "x")]));
+        assert!(matches!(&out[..], [ServerMsg::Applied { .. }]));
+        let sync = filter.as_mut().unwrap().take_sync();
+        assert!(matches!(
+            sync,
+            Some(ServerMsg::ViewportSync {
+                rev: 1,
+                start: 13,
+                end: 23,
+                ..
+            })
+        ));
+        assert!(filter.as_mut().unwrap().take_sync().is_none());
+
+        // The connection's own edit is acked even though it is off-screen.
+        let mut own = applied(2, vec![insert(0, "z")]);
+        if let ServerMsg::Applied { client_id, .. } = &mut own {
+            *client_id = Some(me);
+        }
+        let out = ViewportFilter::route(&mut filter, "big", None, own);
+        assert!(matches!(
+            &out[..],
+            [ServerMsg::ViewportApplied { ops, rev: 2, start: 14, .. }] if ops.is_empty()
+        ));
+    }
+}
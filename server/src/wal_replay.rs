@@ -0,0 +1,286 @@
+//! Replays a document's WAL. The loader, the integrity check, history export,
+//! playback and retention all number revisions from the same replay, so an
+//! edit one of them skips as a duplicate or rebases across a purge is
+//! skipped or rebased the same way by the others.
+
+use std::collections::HashSet;
+
+use uuid::Uuid;
+
+use crate::{
+    document::{Doc, apply_ops, shapes, skip_purged, transform_ops},
+    types::{CURRENT_WAL_VERSION, DocEvent, Edit, OpKind, WalEntryV2, WalLine},
+};
+
+/// Decodes one WAL line, upgrading legacy lines. Blank lines yield `None`;
+/// a line that does not parse yields its 1-based number and the error.
+pub fn decode_line(idx: usize, line: &str) -> Option<Result<WalEntryV2, (usize, String)>> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+    Some(match serde_json::from_str::<WalLine>(trimmed) {
+        Ok(WalLine::V2(entry)) => Ok(entry),
+        Ok(WalLine::V1(edit)) => Ok(WalEntryV2 {
+            version: CURRENT_WAL_VERSION,
+            ts: edit.ts.unwrap_or(0),
+            server_ts: None,
+            event: DocEvent::Edit { edit },
+        }),
+        Err(err) => Err((idx + 1, err.to_string())),
+    })
+}
+
+/// The edit an entry carries, if any.
+pub fn edit_of(event: &DocEvent) -> Option<&Edit> {
+    match event {
+        DocEvent::Edit { edit } | DocEvent::Compacted { edit } => Some(edit),
+        _ => None,
+    }
+}
+
+/// What replaying one edit did.
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// Revision the edit made.
+    pub rev: u64,
+    /// Revisions skipped before it because compaction emptied them.
+    pub folded: u64,
+    /// The edit's ops rebased onto the revision before `rev`.
+    pub ops: Vec<OpKind>,
+    /// Whether the ops were applied to the content, i.e. the edit comes
+    /// after the revision the content started at.
+    pub applied: bool,
+}
+
+/// Replay state: the document being rebuilt and the op ids already seen.
+pub struct Replayer {
+    pub doc: Doc,
+    /// Edits before this revision only rebuild the log; their effect is
+    /// already part of `doc.content`.
+    pub apply_from: u64,
+    /// Op ids of every edit and presence event replayed. A later edit
+    /// carrying one of them is a retry and is skipped.
+    pub seen: HashSet<Uuid>,
+    /// Revision the WAL starts after when retention purged its head.
+    pub purged: u64,
+    /// Edit entries read, including duplicates and no-ops.
+    pub edits: usize,
+}
+
+impl Replayer {
+    pub fn new(doc: Doc, apply_from: u64) -> Self {
+        Self {
+            doc,
+            apply_from,
+            seen: HashSet::new(),
+            purged: 0,
+            edits: 0,
+        }
+    }
+
+    /// Replays one entry. Returns the step when it made a revision.
+    pub fn replay(&mut self, entry: &WalEntryV2) -> Option<Step> {
+        let edit = match &entry.event {
+            DocEvent::Edit { edit } | DocEvent::Compacted { edit } => edit,
+            DocEvent::Cursor { op_id, .. } | DocEvent::Ime { op_id, .. } => {
+                self.seen.extend(*op_id);
+                return None;
+            }
+            DocEvent::Purged { rev } => {
+                skip_purged(&mut self.doc, *rev);
+                self.purged = self.purged.max(*rev);
+                return None;
+            }
+        };
+        self.edits += 1;
+        if edit.op_id.is_some_and(|id| !self.seen.insert(id)) {
+            return None;
+        }
+        let from = self.doc.rev;
+        if matches!(entry.event, DocEvent::Compacted { .. }) {
+            skip_purged(&mut self.doc, edit.base_rev);
+        }
+        let ops = transform_ops(&self.doc, edit);
+        if ops.is_empty() {
+            return None;
+        }
+        let applied = self.doc.rev >= self.apply_from;
+        if applied {
+            apply_ops(&mut self.doc, &ops);
+        }
+        self.doc.rev += 1;
+        self.doc.log.push(shapes(&ops));
+        Some(Step {
+            rev: self.doc.rev,
+            folded: self.doc.rev - from - 1,
+            ops,
+            applied,
+        })
+    }
+
+    /// Replays the lines of a WAL read in full. Yields each edit that made a
+    /// revision, and lines that do not parse.
+    pub fn replay_lines<'a>(&'a mut self, data: &'a str) -> ReplayLines<'a> {
+        ReplayLines {
+            replayer: self,
+            lines: data.lines().enumerate(),
+        }
+    }
+}
+
+/// An edit replayed from the WAL.
+#[derive(Debug, Clone)]
+pub struct Replayed {
+    pub ts: u64,
+    pub server_ts: Option<u64>,
+    pub edit: Edit,
+    /// Written by history compaction: the revisions up to `edit.base_rev`
+    /// log nothing and this edit carries their ops.
+    pub compacted: bool,
+    pub step: Step,
+}
+
+pub struct ReplayLines<'a> {
+    replayer: &'a mut Replayer,
+    lines: std::iter::Enumerate<std::str::Lines<'a>>,
+}
+
+impl Iterator for ReplayLines<'_> {
+    type Item = Result<Replayed, (usize, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (idx, line) = self.lines.next()?;
+            let entry = match decode_line(idx, line) {
+                None => continue,
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return Some(Err(err)),
+            };
+            let Some(step) = self.replayer.replay(&entry) else {
+                continue;
+            };
+            let compacted = matches!(entry.event, DocEvent::Compacted { .. });
+            let (DocEvent::Edit { edit } | DocEvent::Compacted { edit }) = entry.event else {
+                unreachable!("only edits make revisions");
+            };
+            return Some(Ok(Replayed {
+                ts: entry.ts,
+                server_ts: entry.server_ts,
+                edit,
+                compacted,
+                step,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        history::export_history,
+        integrity::verify_doc,
+        replay::{ReplayItem, replay_history},
+        state::{AppState, read_doc},
+        storage::wal_append_event,
+        types::CursorState,
+    };
+
+    fn mk_state() -> AppState {
+        let base = std::env::temp_dir().join(format!("srvtest-walreplay-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(base.join("wal")).unwrap();
+        std::fs::create_dir_all(base.join("snapshots")).unwrap();
+        AppState::new(
+            base.join("wal"),
+            base.join("snapshots"),
+            10_000,
+            1_000,
+            true,
+            Vec::new(),
+        )
+    }
+
+    fn insert(base_rev: u64, text: &str, op_id: Option<Uuid>) -> DocEvent {
+        DocEvent::Edit {
+            edit: Edit {
+                base_rev,
+                ops: vec![OpKind::Insert {
+                    pos: 0,
+                    text: text.into(),
+                }],
+                client_id: None,
+                op_id,
+                cursor_before: None,
+                cursor_after: None,
+                ts: None,
+                group_id: None,
+                user_id: None,
+            },
+        }
+    }
+
+    fn entry(event: DocEvent) -> WalEntryV2 {
+        WalEntryV2 {
+            version: CURRENT_WAL_VERSION,
+            ts: 0,
+            server_ts: None,
+            event,
+        }
+    }
+
+    #[test]
+    fn edits_the_snapshot_holds_only_rebuild_the_log() {
+        // The snapshot was written at rev 1, so "a" is already in it.
+        let doc = Doc {
+            content: "a".into(),
+            ..Default::default()
+        };
+        let mut replayer = Replayer::new(doc, 1);
+        let first = replayer.replay(&entry(insert(0, "a", None))).unwrap();
+        let second = replayer.replay(&entry(insert(1, "b", None))).unwrap();
+        assert!(!first.applied);
+        assert!(second.applied);
+        assert_eq!(replayer.doc.content, "ba");
+        assert_eq!((replayer.doc.rev, replayer.doc.log.len()), (2, 2));
+    }
+
+    #[test]
+    fn every_reader_numbers_the_wal_the_same_way() {
+        let state = mk_state();
+        let retried = Uuid::new_v4();
+        let events = [
+            insert(0, "a", None),
+            DocEvent::Cursor {
+                client_id: Uuid::new_v4(),
+                op_id: Some(retried),
+                cursor: CursorState {
+                    position: 1,
+                    anchor: None,
+                    selection_direction: None,
+                },
+            },
+            // A retry of an op id already seen on a presence event.
+            insert(1, "x", Some(retried)),
+            insert(1, "b", Some(Uuid::new_v4())),
+        ];
+        for (idx, event) in events.iter().enumerate() {
+            wal_append_event(&state, "doc", event, idx as u64).unwrap();
+        }
+
+        let (doc, _) = read_doc(&state, "doc").unwrap();
+        assert_eq!((doc.rev, doc.content.as_str()), (2, "ba"));
+        assert_eq!(verify_doc(&state, "doc").rev, 2);
+        let archive = export_history(&state, "doc").unwrap();
+        assert_eq!((archive.rev, archive.content.as_str()), (2, "ba"));
+        let mut played = Vec::new();
+        replay_history(&state, "doc", |item| {
+            if let ReplayItem::Edit(edit) = item {
+                played.push(edit.rev);
+            }
+            true
+        })
+        .unwrap();
+        assert_eq!(played, [1, 2]);
+    }
+}
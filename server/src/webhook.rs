@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use axum::http::{Request, Uri, header};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper_util::rt::TokioIo;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{net::TcpStream, time::timeout};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest answer [`post_json_reply`] reads.
+const MAX_REPLY_BYTES: usize = 1024 * 1024;
+
+/// POSTs `body` as JSON over plain HTTP/1.1. TLS endpoints are expected to
+/// sit behind a local relay or reverse proxy.
+pub async fn post_json<T: Serialize>(url: &str, body: &T) -> anyhow::Result<()> {
+    post(url, body, WEBHOOK_TIMEOUT).await.map(drop)
+}
+
+/// Like [`post_json`], but waits up to `limit` for the endpoint and returns
+/// the JSON it answered with.
+pub async fn post_json_reply<T: Serialize, R: DeserializeOwned>(
+    url: &str,
+    body: &T,
+    limit: Duration,
+) -> anyhow::Result<R> {
+    let reply = post(url, body, limit).await?;
+    Ok(serde_json::from_slice(&reply)?)
+}
+
+async fn post<T: Serialize>(url: &str, body: &T, limit: Duration) -> anyhow::Result<Bytes> {
+    let uri: Uri = url.parse()?;
+    if uri.scheme_str() != Some("http") {
+        anyhow::bail!("only http:// webhook targets are supported: {}", url);
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow::anyhow!("webhook url has no host: {}", url))?;
+    let addr = format!("{}:{}", host, uri.port_u16().unwrap_or(80));
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let request = Request::post(path)
+        .header(
+            header::HOST,
+            uri.authority().map(|a| a.as_str()).unwrap_or(host),
+        )
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(bytes::Bytes::from(serde_json::to_vec(body)?)))?;
+
+    timeout(limit, async {
+        let stream = TcpStream::connect(addr).await?;
+        let (mut sender, conn) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(conn);
+        let response = sender.send_request(request).await?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("webhook {} answered {}", url, status);
+        }
+        let reply = Limited::new(response.into_body(), MAX_REPLY_BYTES)
+            .collect()
+            .await
+            .map_err(|err| anyhow::anyhow!("reading the answer of {} failed: {}", url, err))?;
+        Ok(reply.to_bytes())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Json, Router, routing::post};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn post_json_delivers_body() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/hook",
+            post(move |Json(v): Json<serde_json::Value>| async move {
+                tx.send(v).unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        post_json(
+            &format!("http://{}/hook", addr),
+            &serde_json::json!({"a": 1}),
+        )
+        .await
+        .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), serde_json::json!({"a": 1}));
+        let missing = post_json(&format!("http://{}/nope", addr), &1).await;
+        assert!(missing.is_err());
+        assert!(post_json("https://example.com/", &1).await.is_err());
+    }
+}
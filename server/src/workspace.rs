@@ -0,0 +1,329 @@
+use std::{collections::BTreeSet, fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::required_password_hash,
+    mentions::mention_key,
+    state::{AppState, publish_access_change},
+    storage::{collect_slugs_with_extension, hash_password, slug_to_rel_path, write_secret},
+    validation::ValidationRule,
+};
+
+const SETTINGS_FILE: &str = ".workspace.json";
+const SECRET_FILE: &str = ".workspace.secret";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WorkspaceSettings {
+    #[serde(default)]
+    pub require_password: bool,
+    /// Kept in `secrets_dir`, not in the settings file; read from one only
+    /// to move it there.
+    #[serde(default, skip_serializing)]
+    pub default_password_hash: Option<String>,
+    #[serde(default)]
+    pub members: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub validation: Vec<ValidationRule>,
+}
+
+impl WorkspaceSettings {
+    /// Whether Basic auth as `user` with `password` opens the workspace
+    /// itself (its settings and listing). The password has to match the
+    /// default password; with `members`, the user name also has to be one of
+    /// them, compared like mentions. A name alone proves nothing, so members
+    /// without a default password leave the workspace to the admin. A
+    /// workspace with neither is open.
+    pub fn admits(&self, user: Option<&str>, password: Option<&str>) -> bool {
+        let Some(expected) = self.default_password_hash.as_deref() else {
+            return self.members.is_empty();
+        };
+        if password.map(hash_password).as_deref() != Some(expected) {
+            return false;
+        }
+        if self.members.is_empty() {
+            return true;
+        }
+        let Some(user) = user.and_then(mention_key) else {
+            return false;
+        };
+        self.members
+            .iter()
+            .any(|m| mention_key(m).as_deref() == Some(user.as_str()))
+    }
+}
+
+/// Returns the workspace a slug belongs to: its first path segment, when the
+/// slug is nested at all.
+pub fn workspace_of(slug: &str) -> Option<&str> {
+    slug.trim_matches('/')
+        .split_once('/')
+        .map(|(ws, _)| ws)
+        .filter(|ws| !ws.is_empty())
+}
+
+fn settings_path(state: &AppState, ws: &str) -> anyhow::Result<PathBuf> {
+    Ok(state.snap_dir.join(workspace_rel(ws)?).join(SETTINGS_FILE))
+}
+
+/// Where the default password hash of `ws` is kept. Document hashes in
+/// `secrets_dir` end in `.pwd`, so this cannot be one of them.
+fn secret_path(state: &AppState, ws: &str) -> anyhow::Result<PathBuf> {
+    Ok(state.secrets_dir.join(workspace_rel(ws)?).join(SECRET_FILE))
+}
+
+fn workspace_rel(ws: &str) -> anyhow::Result<PathBuf> {
+    let rel = slug_to_rel_path(ws)?;
+    if rel.components().count() != 1 {
+        anyhow::bail!("workspace must be a single path segment");
+    }
+    Ok(rel)
+}
+
+fn read_settings(state: &AppState, ws: &str) -> anyhow::Result<WorkspaceSettings> {
+    let mut settings: WorkspaceSettings = match fs::read(settings_path(state, ws)?) {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => WorkspaceSettings::default(),
+        Err(err) => return Err(err.into()),
+    };
+    if settings.default_password_hash.is_some() {
+        // Written before hashes moved out of the snapshot tree.
+        if state.replica.is_none() {
+            write_settings(state, ws, &settings)?;
+        }
+        return Ok(settings);
+    }
+    settings.default_password_hash = match fs::read_to_string(secret_path(state, ws)?) {
+        Ok(hash) => Some(hash.trim().to_string()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    Ok(settings)
+}
+
+fn write_settings(state: &AppState, ws: &str, settings: &WorkspaceSettings) -> anyhow::Result<()> {
+    let secret = secret_path(state, ws)?;
+    match settings.default_password_hash.as_deref() {
+        Some(hash) => write_secret(&secret, hash)?,
+        None => match fs::remove_file(&secret) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        },
+    }
+    let path = settings_path(state, ws)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_vec_pretty(settings)?)?;
+    Ok(())
+}
+
+pub fn load_workspace(state: &AppState, ws: &str) -> anyhow::Result<WorkspaceSettings> {
+    if let Some(settings) = state.workspaces.read().get(ws).cloned() {
+        return Ok(settings);
+    }
+    let settings = read_settings(state, ws)?;
+    state
+        .workspaces
+        .write()
+        .insert(ws.to_string(), settings.clone());
+    Ok(settings)
+}
+
+/// Moves default password hashes still in workspace settings files to
+/// `secrets_dir`. Returns how many were moved.
+pub fn migrate_workspace_secrets(state: &AppState) -> anyhow::Result<usize> {
+    let dirs = match fs::read_dir(&state.snap_dir) {
+        Ok(dirs) => dirs,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let mut moved = 0;
+    for entry in dirs {
+        let entry = entry?;
+        let Ok(ws) = entry.file_name().into_string() else {
+            continue;
+        };
+        let Ok(data) = fs::read(entry.path().join(SETTINGS_FILE)) else {
+            continue;
+        };
+        let stored: WorkspaceSettings = serde_json::from_slice(&data)?;
+        if stored.default_password_hash.is_some() {
+            read_settings(state, &ws)?;
+            moved += 1;
+        }
+    }
+    Ok(moved)
+}
+
+pub fn save_workspace(
+    state: &AppState,
+    ws: &str,
+    settings: &WorkspaceSettings,
+) -> anyhow::Result<()> {
+    write_settings(state, ws, settings)?;
+    state
+        .workspaces
+        .write()
+        .insert(ws.to_string(), settings.clone());
+    let mut changed = Vec::new();
+    for (slug, doc) in state.docs.read().iter() {
+        if workspace_of(slug) == Some(ws) {
+            let mut d = doc.write();
+            let before = required_password_hash(&d).map(str::to_string);
+            d.inherited_password_hash = settings.default_password_hash.clone();
+            let after = required_password_hash(&d);
+            if after != before.as_deref() {
+                changed.push((slug.clone(), doc.clone()));
+            }
+        }
+    }
+    for (slug, doc) in changed {
+        publish_access_change(state, &slug, &doc, None);
+    }
+    Ok(())
+}
+
+/// Settings inherited by `slug` from its workspace, if it lives in one.
+pub fn workspace_settings_for(
+    state: &AppState,
+    slug: &str,
+) -> anyhow::Result<Option<WorkspaceSettings>> {
+    match workspace_of(slug) {
+        Some(ws) => load_workspace(state, ws).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn list_workspace_docs(state: &AppState, ws: &str) -> anyhow::Result<Vec<String>> {
+    let rel = slug_to_rel_path(ws)?;
+    let prefix = format!("{}/", ws);
+    let mut slugs = BTreeSet::new();
+    for (base, ext, skip_empty) in [
+        (&state.snap_dir, "md", false),
+        (&state.wal_dir, "jsonl", true),
+    ] {
+        let dir = base.join(&rel);
+        for slug in collect_slugs_with_extension(&dir, ext, skip_empty)? {
+            slugs.insert(format!("{}{}", prefix, slug));
+        }
+    }
+    for slug in state.docs.read().keys() {
+        if slug.starts_with(&prefix) {
+            slugs.insert(slug.clone());
+        }
+    }
+    Ok(slugs.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use uuid::Uuid;
+
+    fn mk_state(tmp: &Path) -> AppState {
+        let wal_dir = tmp.join("wal");
+        let snap_dir = tmp.join("snapshots");
+        fs::create_dir_all(&wal_dir).unwrap();
+        fs::create_dir_all(&snap_dir).unwrap();
+        AppState::new(wal_dir, snap_dir, 1_000, 128, true, Vec::new())
+    }
+
+    #[test]
+    fn workspace_of_uses_first_segment() {
+        assert_eq!(workspace_of("team/spec"), Some("team"));
+        assert_eq!(workspace_of("/team/a/b"), Some("team"));
+        assert_eq!(workspace_of("loose"), None);
+    }
+
+    #[test]
+    fn save_workspace_persists_and_lists_docs() {
+        let base = std::env::temp_dir().join(format!("workspace-{}", Uuid::new_v4()));
+        fs::create_dir_all(&base).unwrap();
+        let state = mk_state(&base);
+        let settings = WorkspaceSettings {
+            require_password: true,
+            members: vec!["alice".into()],
+            ..Default::default()
+        };
+        save_workspace(&state, "team", &settings).unwrap();
+        state.workspaces.write().clear();
+        assert_eq!(load_workspace(&state, "team").unwrap(), settings);
+
+        fs::create_dir_all(state.snap_dir.join("team/sub")).unwrap();
+        fs::write(state.snap_dir.join("team/a.md"), "a").unwrap();
+        fs::write(state.snap_dir.join("team/sub/b.md"), "b").unwrap();
+        fs::write(state.snap_dir.join("other.md"), "x").unwrap();
+
+        let docs = list_workspace_docs(&state, "team").unwrap();
+        assert_eq!(docs, vec!["team/a".to_string(), "team/sub/b".to_string()]);
+        assert!(save_workspace(&state, "a/b", &settings).is_err());
+    }
+
+    #[test]
+    fn default_passwords_are_kept_with_the_secrets() {
+        let base = std::env::temp_dir().join(format!("workspace-secret-{}", Uuid::new_v4()));
+        let state = mk_state(&base);
+        let settings = WorkspaceSettings {
+            default_password_hash: Some(hash_password("pw")),
+            ..Default::default()
+        };
+        save_workspace(&state, "team", &settings).unwrap();
+        let stored = fs::read_to_string(settings_path(&state, "team").unwrap()).unwrap();
+        assert!(!stored.contains("default_password_hash"));
+        assert!(
+            secret_path(&state, "team")
+                .unwrap()
+                .starts_with(&state.secrets_dir)
+        );
+        state.workspaces.write().clear();
+        assert_eq!(load_workspace(&state, "team").unwrap(), settings);
+
+        // Settings files from before carry the hash themselves.
+        let legacy = serde_json::json!({
+            "require_password": false,
+            "default_password_hash": hash_password("old"),
+            "members": [],
+        });
+        fs::create_dir_all(state.snap_dir.join("old")).unwrap();
+        fs::write(settings_path(&state, "old").unwrap(), legacy.to_string()).unwrap();
+        assert_eq!(migrate_workspace_secrets(&state).unwrap(), 1);
+        let stored = fs::read_to_string(settings_path(&state, "old").unwrap()).unwrap();
+        assert!(!stored.contains("default_password_hash"));
+        assert_eq!(
+            load_workspace(&state, "old").unwrap().default_password_hash,
+            Some(hash_password("old"))
+        );
+        assert_eq!(migrate_workspace_secrets(&state).unwrap(), 0);
+
+        save_workspace(&state, "team", &WorkspaceSettings::default()).unwrap();
+        assert!(!secret_path(&state, "team").unwrap().exists());
+    }
+
+    #[test]
+    fn members_and_default_password_gate_the_workspace() {
+        let open = WorkspaceSettings::default();
+        assert!(open.admits(None, None));
+
+        let members = WorkspaceSettings {
+            members: vec!["Ann Lee".into()],
+            ..Default::default()
+        };
+        // Anyone can send a member's name.
+        assert!(!members.admits(Some("ann_lee"), None));
+        assert!(!members.admits(Some("ann_lee"), Some("guess")));
+        assert!(!members.admits(None, None));
+
+        let locked = WorkspaceSettings {
+            default_password_hash: Some(hash_password("pw")),
+            ..members
+        };
+        assert!(locked.admits(Some("Ann Lee"), Some("pw")));
+        assert!(!locked.admits(Some("Ann Lee"), Some("nope")));
+        assert!(!locked.admits(Some("bob"), Some("pw")));
+    }
+}
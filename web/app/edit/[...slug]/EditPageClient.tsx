@@ -7,6 +7,7 @@ import {
   fetchSnapshot,
   setStoredPassword,
   getStoredPassword,
+  setStoredOwnerToken,
   updatePasswordOnServer,
   UnauthorizedError,
   type EditMsg,
@@ -21,6 +22,7 @@ import {
   type PresenceDiffMsg,
   type AckMsg,
   type OpBroadcastMsg,
+  type OwnerGrantedMsg,
   type TextRange,
 } from '../../lib/api'
 import { PendingQueue, useHeartbeat, useRealtimeChannel, type PendingEdit } from '../../lib/realtime'
@@ -483,6 +485,9 @@ export default function EditPageClient({ slugParts }: { slugParts: string[] }) {
 
   const socket = useRealtimeChannel(authState === 'authorized' ? slug : null, {
     reconnectDeps: [authNonce, activePassword],
+    onConnectError: err => {
+      if (err instanceof UnauthorizedError) setAuthState('needPassword')
+    },
   })
 
   useEffect(() => {
@@ -778,6 +783,12 @@ export default function EditPageClient({ slugParts }: { slugParts: string[] }) {
           })
           break
         }
+        case 'owner_granted': {
+          const msg = data as OwnerGrantedMsg
+          if (msg.slug !== slug) break
+          setStoredOwnerToken(slug, msg.owner_token)
+          break
+        }
         default:
           break
       }
@@ -1,10 +1,18 @@
-export type Snapshot = { slug: string; rev: number; content: string }
+import { v4 as uuidv4 } from 'uuid'
+
+export type ContentType =
+  | { kind: 'markdown' }
+  | { kind: 'plaintext' }
+  | { kind: 'json' }
+  | { kind: 'code'; lang: string }
+export type Snapshot = { slug: string; rev: number; content: string; content_type?: ContentType; content_hash?: number; chars?: number }
 export type Op =
   | { type: 'insert'; pos: number; text: string }
   | { type: 'delete'; pos: number; len: number }
 
 const STORAGE_PREFIX = process.env.NEXT_PUBLIC_STORAGE_PREFIX ?? 'coedit'
 const passwordKey = (slug: string) => `${STORAGE_PREFIX}:pwd:${slug}`
+const ownerTokenKey = (slug: string) => `${STORAGE_PREFIX}:owner:${slug}`
 
 export class UnauthorizedError extends Error {}
 
@@ -31,6 +39,25 @@ export function setStoredPassword(slug: string, password: string | null) {
   }
 }
 
+export function getStoredOwnerToken(slug: string): string | null {
+  if (typeof window === 'undefined') return null
+  try {
+    return localStorage.getItem(ownerTokenKey(slug))
+  } catch (error) {
+    console.warn('localStorage.getItemでオーナートークンの取得に失敗しました', error)
+    return null
+  }
+}
+
+export function setStoredOwnerToken(slug: string, token: string) {
+  if (typeof window === 'undefined') return
+  try {
+    localStorage.setItem(ownerTokenKey(slug), token)
+  } catch (error) {
+    console.warn('localStorageでオーナートークンの保存に失敗しました', error)
+  }
+}
+
 function buildBasicToken(slug: string, password: string): string {
   const payload = `${slug}:${password}`
   if (typeof window !== 'undefined' && typeof window.btoa === 'function') {
@@ -79,13 +106,36 @@ export type PresenceState = {
   cursor?: CursorState
   ime?: ImeSnapshot
   last_seen: number
+  user_id?: string
+  avatar_url?: string
+  status?: string
 }
 
-export type AppliedMsg = { type: 'applied'; slug: string; rev: number; ops: Op[]; client_id?: string; op_id?: string; ts: number }
+export type DocStats = { chars: number; words: number; lines: number }
+export type AppliedMsg = { type: 'applied'; slug: string; rev: number; ops: Op[]; client_id?: string; op_id?: string; ts: number; hash?: number; chars?: number; stats?: DocStats; group_id?: string; user_id?: string }
 export type CursorMsgInbound = { type: 'cursor'; slug: string; client_id: string; cursor: CursorState; op_id?: string; ts: number }
 export type ImeMsgInbound = { type: 'ime'; slug: string; client_id: string; ime: ImeEvent; op_id?: string; ts: number }
-export type PresenceSnapshotMsg = { type: 'presence_snapshot'; slug: string; clients: PresenceState[] }
+export type ProtocolInfo = { version: number; capabilities: string[]; coordinates: string }
+export type PresenceSnapshotMsg = {
+  type: 'presence_snapshot'
+  slug: string
+  clients: PresenceState[]
+  protocol?: ProtocolInfo
+}
 export type PresenceDiffMsg = { type: 'presence_diff'; slug: string; added: PresenceState[]; updated: PresenceState[]; removed: string[] }
+export type StatsMsg = { type: 'stats'; slug: string; rev: number; stats: DocStats }
+export type ReplacedMsg = { type: 'replaced'; slug: string; rev: number; matches: number; op_id?: string; group_id?: string }
+export type InvalidOpMsg = {
+  type: 'invalid_op'
+  slug: string
+  op_id?: string
+  index: number
+  reason: string
+  doc_len: number
+  message: string
+  resync: boolean
+}
+export type OwnerGrantedMsg = { type: 'owner_granted'; slug: string; owner_token: string }
 export type PingMsg = { type: 'ping' }
 export type PongMsg = { type: 'pong' }
 export type SnapshotMsg = { type: 'snapshot'; payload: Snapshot }
@@ -110,13 +160,31 @@ export type EditPayload = {
   cursor_before?: CursorState
   cursor_after?: CursorState
   ts?: number
+  group_id?: string
 }
 
 export type EditMsg = { type: 'edit'; slug: string; edit: EditPayload }
-export type HelloMsg = { type: 'hello'; slug: string; client_id: string; label?: string; color?: string }
+export type HelloMsg = { type: 'hello'; slug: string; client_id: string; label?: string; color?: string; user_id?: string }
 export type CursorMsgOutbound = { type: 'cursor'; slug: string; cursor: CursorState; op_id?: string; ts?: number }
 export type ImeMsgOutbound = { type: 'ime'; slug: string; ime: ImeEvent; op_id?: string; ts?: number }
-export type ProfileMsgOutbound = { type: 'profile'; slug: string; label?: string | null; color?: string | null }
+export type StatsMsgOutbound = { type: 'stats'; slug: string }
+export type ReplaceMsgOutbound = {
+  type: 'replace'
+  slug: string
+  find: string
+  replace: string
+  regex?: boolean
+  case_insensitive?: boolean
+  op_id?: string
+}
+export type ProfileMsgOutbound = {
+  type: 'profile'
+  slug: string
+  label?: string | null
+  color?: string | null
+  avatar_url?: string
+  status?: string
+}
 export type JoinMsgOutbound = {
   type: 'join'
   sessionId: string
@@ -146,6 +214,10 @@ export type WsInbound =
   | SnapshotMsg
   | OpBroadcastMsg
   | AckMsg
+  | OwnerGrantedMsg
+  | ReplacedMsg
+  | StatsMsg
+  | InvalidOpMsg
 export type WsOutbound =
   | EditMsg
   | PingMsg
@@ -156,28 +228,64 @@ export type WsOutbound =
   | PongMsg
   | JoinMsgOutbound
   | CompatOpMsg
+  | ReplaceMsgOutbound
+  | StatsMsgOutbound
+
+export type WsTicket = { ticket: string; expires_at: number }
+
+// パスワードをWebSocketのURLに載せないよう、接続ごとに使い捨てのチケットを取得する
+export async function fetchWsTicket(slug: string): Promise<WsTicket> {
+  const headers = new Headers({ 'Content-Type': 'application/json' })
+  const pwd = getStoredPassword(slug)
+  if (pwd) headers.set('Authorization', `Basic ${buildBasicToken(slug, pwd)}`)
+  const res = await fetch('/api/ws-ticket', {
+    method: 'POST',
+    cache: 'no-store',
+    credentials: 'same-origin',
+    headers,
+    body: JSON.stringify({ slug }),
+  })
+  if (res.status === 401) throw new UnauthorizedError('unauthorized')
+  if (!res.ok) throw new Error('failed to fetch ws ticket')
+  return res.json()
+}
 
-export function openWs(slug: string): WebSocket {
+export function openWs(slug: string, ticket?: string): WebSocket {
   const proto = location.protocol === 'https:' ? 'wss:' : 'ws:'
   const pwd = getStoredPassword(slug)
-  const token = pwd ? `&token=${encodeURIComponent(buildBasicToken(slug, pwd))}` : ''
-  const url = `${proto}//${location.host}/api/ws?slug=${encodeURIComponent(slug)}${token}`
+  let auth = ''
+  if (ticket) {
+    auth = `&ticket=${encodeURIComponent(ticket)}`
+  } else if (pwd) {
+    auth = `&token=${encodeURIComponent(buildBasicToken(slug, pwd))}`
+  }
+  const url = `${proto}//${location.host}/api/ws?slug=${encodeURIComponent(slug)}${auth}`
   return new WebSocket(url)
 }
 
+// チケットが取れなければそのまま失敗させる。パスワード入りのトークンを URL に載せて繋ぎ直すことはしない
+export async function openWsWithTicket(slug: string): Promise<WebSocket> {
+  const { ticket } = await fetchWsTicket(slug)
+  return openWs(slug, ticket)
+}
+
 export async function updatePasswordOnServer(slug: string, newPassword: string, currentPassword?: string): Promise<void> {
-  const headers: Record<string, string> = { 'Content-Type': 'application/json' }
+  // 同じキーで再送すれば、サーバは最初の結果を返すだけで二重に適用しない
+  const headers: Record<string, string> = { 'Content-Type': 'application/json', 'Idempotency-Key': uuidv4() }
   const existing = currentPassword ?? getStoredPassword(slug) ?? undefined
   if (existing) headers['Authorization'] = `Basic ${buildBasicToken(slug, existing)}`
-  const res = await fetch('/api/password', {
+  const request = () => fetch('/api/password', {
     method: 'POST',
     headers,
     body: JSON.stringify({
       slug,
       current_password: existing ?? null,
       new_password: newPassword,
+      owner_token: getStoredOwnerToken(slug),
     }),
   })
+  const res = await request().catch(() => request())
   if (res.status === 401) throw new UnauthorizedError('unauthorized')
+  if (res.status === 403) throw new Error('only the document owner can change the password')
   if (!res.ok) throw new Error('failed to update password')
 }
@@ -0,0 +1,620 @@
+// Generated from server/src/types.rs by `cargo run --bin coedit-schema -- ts`.
+// Do not edit by hand.
+
+export type ClientMsg =
+  | {
+      type: 'hello'
+      capabilities?: string[]
+      client_id: string
+      color?: string | null
+      /** Answered with `snapshot_current` when it is still the head, and with a `resync` otherwise. */
+      known?: KnownState | null
+      label?: string | null
+      slug: string
+      /** Broadcast classes this socket wants; all of them when absent. */
+      subscribe?: MessageClass[] | null
+      /** Stable identity of a named user; guests leave it out. */
+      user_id?: string | null
+      version?: number | null
+    }
+  | {
+      type: 'edit'
+      edit: Edit
+      slug: string
+    }
+  | {
+      type: 'line_edit'
+      edit: LineEdit
+      slug: string
+    }
+  | {
+      type: 'cursor'
+      cursor: CursorState
+      op_id?: string | null
+      slug: string
+      ts?: number | null
+    }
+  | {
+      type: 'ime'
+      ime: ImeEvent
+      op_id?: string | null
+      slug: string
+      ts?: number | null
+    }
+  | {
+      type: 'profile'
+      avatar_url?: string | null
+      color?: string | null
+      label?: string | null
+      slug: string
+      status?: string | null
+    }
+  | {
+      type: 'join'
+      capabilities?: string[]
+      client_id: string
+      color?: string | null
+      /** Skips the content of the `snapshot` when it is still the head. */
+      known?: KnownState | null
+      label?: string | null
+      password?: string | null
+      session_id: string
+      token?: string | null
+      user_id?: string | null
+      version?: number | null
+    }
+  | {
+      type: 'op'
+      context: CompatOpContext
+      operation: OpKind
+      session_id: string
+    }
+  | {
+      type: 'ping'
+      ts?: number | null
+    }
+  | {
+      type: 'pong'
+    }
+  | {
+      type: 'watch'
+      password?: string | null
+      slug: string
+      ticket?: string | null
+    }
+  | {
+      type: 'unwatch'
+      slug: string
+    }
+  | {
+      type: 'resync'
+      last_seq: number
+    }
+  | {
+      type: 'state_mismatch'
+      hash?: number | null
+      rev: number
+      slug: string
+    }
+  | {
+      type: 'set_viewport'
+      end: number
+      slug: string
+      start: number
+      unit?: ViewportUnit
+    }
+  | {
+      type: 'clear_viewport'
+      slug: string
+    }
+  | {
+      type: 'stats'
+      slug: string
+    }
+  | {
+      type: 'seen_up_to'
+      rev: number
+      slug: string
+    }
+  | {
+      type: 'claim_section'
+      slug: string
+      start: number
+    }
+  | {
+      type: 'release_section'
+      slug: string
+    }
+  | {
+      type: 'follow'
+      follow: boolean
+      slug: string
+    }
+  | {
+      type: 'replace'
+      case_insensitive?: boolean
+      find: string
+      op_id?: string | null
+      regex?: boolean
+      replace?: string
+      slug: string
+    }
+  | {
+      type: 'roster'
+      label?: string | null
+      workspace?: string | null
+    }
+  | {
+      type: 'set_password'
+      current?: string | null
+      new?: string | null
+      owner_token?: string | null
+    }
+  | {
+      type: 'assist_request'
+      prompt_kind: string
+      range: TextRange
+      request_id?: string | null
+      slug: string
+    }
+  | {
+      type: 'authenticate'
+      password?: string | null
+      ticket?: string | null
+    }
+
+export type CompatOpBroadcastContext = {
+  client_id?: string | null
+  op_id?: string | null
+  selection?: CompatSelection | null
+  serverSeq: number
+  ts?: number | null
+}
+
+export type CompatOpContext = {
+  baseVersion: number
+  client_id?: string | null
+  op_id?: string | null
+  selection?: CompatSelection | null
+  ts?: number | null
+}
+
+export type CompatSelection = {
+  anchor?: number | null
+  position: number
+  selection_direction?: SelectionDirection | null
+}
+
+export type CursorState = {
+  anchor?: number | null
+  position: number
+  selection_direction?: SelectionDirection | null
+}
+
+/** What an analyzer found about `start..end` of a document, in characters. Clients move the range along with `Applied` ops, as the server does. */
+export type Diagnostic = {
+  analyzer: string
+  end: number
+  message: string
+  severity: Severity
+  start: number
+}
+
+/** Counts over a document's content, so clients need not recount large documents themselves. */
+export type DocStats = {
+  /** Characters, the unit op positions count in. */
+  chars: number
+  /** Line breaks plus one, as an editor numbers lines. */
+  lines: number
+  /** Runs of non-whitespace characters. */
+  words: number
+}
+
+export type Edit = {
+  base_rev: number
+  client_id?: string | null
+  cursor_after?: CursorState | null
+  cursor_before?: CursorState | null
+  /** Shared by the edits of one user action (a paste, a replace-all) so undo, replay and blame can treat them as a unit. */
+  group_id?: string | null
+  op_id?: string | null
+  ops: OpKind[]
+  ts?: number | null
+  /** The person behind `client_id`, stable across tabs and reloads. Set by the server from the session, never taken from the client's edit. */
+  user_id?: string | null
+}
+
+export type ImeEvent =
+  | {
+      phase: 'start'
+      range: TextRange
+    }
+  | {
+      phase: 'update'
+      range: TextRange
+      text: string
+    }
+  | {
+      phase: 'commit'
+      replace_range: TextRange
+      text: string
+    }
+  | {
+      phase: 'cancel'
+      range: TextRange
+    }
+
+export type ImeSnapshot = {
+  phase: string
+  range?: TextRange | null
+  text?: string | null
+}
+
+/** A copy of the document a client kept from an earlier session, e.g. across a page restore. */
+export type KnownState = {
+  /** [`content_hash`](crate::document::content_hash) of the copy. */
+  content_hash: number
+  rev: number
+}
+
+export type LineEdit = {
+  base_rev: number
+  client_id?: string | null
+  group_id?: string | null
+  op_id?: string | null
+  ops: LineOp[]
+  ts?: number | null
+  user_id?: string | null
+}
+
+/** Ops for sessions that negotiated `line_ops`. Lines are counted from zero and never contain `\n`. */
+export type LineOp =
+  | {
+      type: 'insert_lines'
+      line: number
+      lines: string[]
+    }
+  | {
+      type: 'delete_lines'
+      count: number
+      line: number
+    }
+  | {
+      type: 'replace_line'
+      line: number
+      text: string
+    }
+
+export type MessageClass =
+  | 'cursors' | 'ime'
+  | 'edits'
+  | 'presence'
+
+/** Changes to a followed document not yet read, kept as one entry per document until the user sees them. */
+export type Notification = {
+  /** Who made them, as named in presence. */
+  editors: string[]
+  edits: number
+  first_at: number
+  last_at: number
+  /** Lines that mention the user, oldest first. */
+  mentions?: string[]
+  rev: number
+  /** The revision before the first change. */
+  since_rev: number
+  slug: string
+}
+
+export type OpKind =
+  | {
+      type: 'insert'
+      pos: number
+      text: string
+    }
+  | {
+      type: 'delete'
+      len: number
+      pos: number
+    }
+
+export type PresenceState = {
+  /** An `http(s)` image URL, at most 512 bytes. */
+  avatar_url?: string | null
+  client_id: string
+  color?: string | null
+  cursor?: CursorState | null
+  ime?: ImeSnapshot | null
+  label?: string | null
+  last_seen: number
+  /** Smoothed round-trip time of this session's socket, in milliseconds, once the server has measured it. */
+  rtt_ms?: number | null
+  /** The section this session claimed, as of when it did. */
+  section?: SectionClaim | null
+  /** A short free-text status, at most 80 characters. */
+  status?: string | null
+  /** Set for named users; guests have only their per-tab `client_id`. */
+  user_id?: string | null
+}
+
+export type ProtocolInfo = {
+  capabilities: string[]
+  coordinates: string
+  version: number
+}
+
+/** One labeled user in a workspace roster: the tabs they have open and the documents those tabs are in. */
+export type RosterEntry = {
+  client_ids: string[]
+  label: string
+  slugs: string[]
+}
+
+/** A soft lock on the Markdown section under a heading. Offsets count characters like `/api/toc`; clients move them along with `Applied` ops. */
+export type SectionClaim = {
+  end: number
+  /** When the claim lapses unless claimed again. */
+  expires_at: number
+  heading: string
+  /** Start of the heading line. */
+  start: number
+}
+
+export type SelectionDirection = 'forward' | 'backward'
+
+/** A `ServerMsg` as sent on the socket, numbered per connection. */
+export type ServerFrame = ServerMsg & {
+  seq: number
+}
+
+export type ServerMsg =
+  | {
+      type: 'applied'
+      /** Length of the document at `rev` in characters, sent with `hash`. */
+      chars?: number | null
+      client_id?: string | null
+      group_id?: string | null
+      /** [`content_hash`](crate::document::content_hash) of the document at `rev`, sent every `hash_interval` revisions. */
+      hash?: number | null
+      op_id?: string | null
+      ops: OpKind[]
+      rev: number
+      slug: string
+      /** Counts for the document at `rev`, sent every `stats_interval` revisions. */
+      stats?: DocStats | null
+      ts: number
+      user_id?: string | null
+    }
+  | {
+      type: 'line_applied'
+      client_id?: string | null
+      group_id?: string | null
+      op_id?: string | null
+      ops: LineOp[]
+      rev: number
+      slug: string
+      ts: number
+      user_id?: string | null
+    }
+  | {
+      type: 'cursor'
+      client_id: string
+      cursor: CursorState
+      op_id?: string | null
+      slug: string
+      ts: number
+    }
+  | {
+      type: 'ime'
+      client_id: string
+      ime: ImeEvent
+      op_id?: string | null
+      slug: string
+      ts: number
+    }
+  | {
+      type: 'presence_snapshot'
+      clients: PresenceState[]
+      protocol?: ProtocolInfo | null
+      slug: string
+    }
+  | {
+      type: 'presence_diff'
+      added: PresenceState[]
+      removed: string[]
+      slug: string
+      updated: PresenceState[]
+    }
+  | {
+      type: 'snapshot'
+      /** The content came ahead in `snapshot_chunk`s and is empty here. */
+      chunked?: boolean
+      content: string
+      presence?: PresenceState[] | null
+      rev: number
+      session_id: string
+      versions?: Record<string, unknown>
+    }
+  | {
+      type: 'snapshot_ref'
+      content_hash: number
+      length: number
+      presence?: PresenceState[] | null
+      rev: number
+      session_id: string
+      versions?: Record<string, unknown>
+    }
+  | {
+      type: 'snapshot_current'
+      presence?: PresenceState[] | null
+      rev: number
+      session_id: string
+      versions?: Record<string, unknown>
+    }
+  | {
+      type: 'snapshot_chunk'
+      checksum?: number | null
+      data: string
+      offset: number
+      rev: number
+      session_id: string
+      total: number
+    }
+  | {
+      type: 'op_broadcast'
+      context: CompatOpBroadcastContext
+      operation: OpKind
+      session_id: string
+    }
+  | {
+      type: 'ack'
+      op_id?: string | null
+      server_seq: number
+      session_id: string
+    }
+  | {
+      type: 'pong'
+      ts?: number | null
+    }
+  | {
+      type: 'watching'
+      rev: number
+      slug: string
+    }
+  | {
+      type: 'resync'
+      content: string
+      rev: number
+      slug: string
+    }
+  | {
+      type: 'viewport'
+      content: string
+      end: number
+      rev: number
+      slug: string
+      start: number
+    }
+  | {
+      type: 'viewport_applied'
+      client_id?: string | null
+      end: number
+      group_id?: string | null
+      op_id?: string | null
+      ops: OpKind[]
+      rev: number
+      slug: string
+      start: number
+      ts: number
+      user_id?: string | null
+    }
+  | {
+      type: 'viewport_sync'
+      end: number
+      rev: number
+      slug: string
+      start: number
+    }
+  | {
+      type: 'owner_granted'
+      owner_token: string
+      slug: string
+    }
+  | {
+      type: 'stats'
+      rev: number
+      slug: string
+      stats: DocStats
+    }
+  | {
+      type: 'assist_result'
+      range: TextRange
+      request_id?: string | null
+      rev: number
+      slug: string
+      text: string
+    }
+  | {
+      type: 'replaced'
+      group_id?: string | null
+      matches: number
+      op_id?: string | null
+      rev: number
+      slug: string
+    }
+  | {
+      type: 'roster'
+      members: RosterEntry[]
+      slug: string
+      workspace: string
+    }
+  | {
+      type: 'access_changed'
+      client_id?: string | null
+      protected: boolean
+      slug: string
+      version: number
+      writable: boolean
+    }
+  | {
+      type: 'auth_required'
+      grace_ms: number
+      slug: string
+    }
+  | {
+      type: 'authenticated'
+      slug: string
+    }
+  | {
+      type: 'expiring'
+      expires_at?: number | null
+      remaining_ms?: number | null
+      slug: string
+    }
+  | {
+      type: 'notification'
+      notification: Notification
+    }
+  | {
+      type: 'degraded'
+      degraded: boolean
+      slug: string
+    }
+  | {
+      type: 'error'
+      code: string
+      message: string
+      op_id?: string | null
+      slug: string
+    }
+  | {
+      type: 'warning'
+      code: string
+      message: string
+      op_id?: string | null
+      slug: string
+    }
+  | {
+      type: 'diagnostics'
+      diagnostics: Diagnostic[]
+      rev: number
+      slug: string
+    }
+  | {
+      type: 'invalid_op'
+      doc_len: number
+      index: number
+      message: string
+      op_id?: string | null
+      reason: string
+      resync: boolean
+      slug: string
+    }
+
+export type Severity = 'error' | 'warning' | 'info'
+
+export type TextRange = {
+  end: number
+  start: number
+}
+
+export type ViewportUnit = 'char' | 'line'
@@ -1,6 +1,6 @@
 import { useEffect, useRef, useState, type DependencyList } from 'react'
 
-import { openWs, type CursorState, type Op } from './api'
+import { openWsWithTicket, UnauthorizedError, type CursorState, type Op } from './api'
 
 export type PendingEdit = {
   op_id: string
@@ -91,12 +91,33 @@ export class PendingQueue {
   }
 }
 
+// Close codes the server ends a session with; see server/src/protocol.rs.
+export const WS_CLOSE = {
+  unauthorized: 4001,
+  rateLimited: 4002,
+  docGone: 4003,
+  draining: 4004,
+  moved: 4005,
+  protocolError: 4006,
+} as const
+
+// Application closures that only repeat on reconnect until the user acts.
+const FATAL_CLOSE_CODES: ReadonlySet<number> = new Set([
+  WS_CLOSE.unauthorized,
+  WS_CLOSE.docGone,
+  WS_CLOSE.protocolError,
+])
+
+export const isRetryableClose = (code: number) => !FATAL_CLOSE_CODES.has(code)
+
 type UseRealtimeChannelOptions = {
   reconnectDeps?: DependencyList
   createSocket?: () => WebSocket
   onOpen?: (socket: WebSocket) => void
   onClose?: (event: CloseEvent) => void
   onError?: (event: Event) => void
+  // チケットが取れず接続できなかったとき。401 以外は再試行する
+  onConnectError?: (error: unknown) => void
 }
 
 export const useRealtimeChannel = (
@@ -112,6 +133,7 @@ export const useRealtimeChannel = (
   const handleOpen = options?.onOpen
   const handleClose = options?.onClose
   const handleError = options?.onError
+  const handleConnectError = options?.onConnectError
 
   useEffect(() => {
     if (!sessionId) {
@@ -131,10 +153,31 @@ export const useRealtimeChannel = (
     let closed = false
     let ws: WebSocket | null = null
 
-    const connect = () => {
+    const scheduleRetry = () => {
+      const retry = Math.min(8, retryRef.current + 1)
+      retryRef.current = retry
+      const delay = Math.min(10_000, 500 * 2 ** retry)
+      if (timerRef.current) {
+        clearTimeout(timerRef.current)
+      }
+      timerRef.current = setTimeout(() => void connect(), delay)
+    }
+
+    const connect = async () => {
       if (closed) return
-      const socketFactory = createSocket ?? (() => openWs(sessionId))
-      const next = socketFactory()
+      let next: WebSocket
+      try {
+        next = createSocket ? createSocket() : await openWsWithTicket(sessionId)
+      } catch (openError) {
+        console.warn('WebSocketの接続準備に失敗しました', openError)
+        handleConnectError?.(openError)
+        if (!closed && !(openError instanceof UnauthorizedError)) scheduleRetry()
+        return
+      }
+      if (closed) {
+        next.close(1000, 'component unmounted')
+        return
+      }
       ws = next
       setSocket(next)
       next.addEventListener('open', () => {
@@ -143,14 +186,11 @@ export const useRealtimeChannel = (
       })
       next.addEventListener('close', event => {
         handleClose?.(event)
-        if (closed) return
-        const retry = Math.min(8, retryRef.current + 1)
-        retryRef.current = retry
-        const delay = Math.min(10_000, 500 * 2 ** retry)
-        if (timerRef.current) {
-          clearTimeout(timerRef.current)
+        if (closed || !isRetryableClose(event.code)) return
+        if (event.code === WS_CLOSE.moved) {
+          retryRef.current = 0
         }
-        timerRef.current = setTimeout(connect, delay)
+        scheduleRetry()
       })
       next.addEventListener('error', event => {
         handleError?.(event)
@@ -165,7 +205,7 @@ export const useRealtimeChannel = (
       })
     }
 
-    connect()
+    void connect()
 
     return () => {
       closed = true